@@ -0,0 +1,43 @@
+// Conserve backup system.
+// Copyright 2020 Martin Pool.
+
+//! Benchmark comparing and sorting apaths.
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+
+use conserve::*;
+
+mod common;
+
+fn apaths() -> Vec<Apath> {
+    let tf = common::generated_tree();
+    let lt = LiveTree::open(tf.path()).unwrap();
+    lt.iter_entries()
+        .unwrap()
+        .map(|entry| entry.apath().clone())
+        .collect()
+}
+
+fn apath(c: &mut Criterion) {
+    let sorted = apaths();
+    // A deterministic shuffle, so sorting has work to do.
+    let mut shuffled = sorted.clone();
+    let len = shuffled.len();
+    for i in 0..len {
+        shuffled.swap(i, (i * 7919 + 13) % len);
+    }
+
+    c.bench_function("apath/cmp_sorted", |b| {
+        b.iter(|| sorted.windows(2).filter(|w| w[0] < w[1]).count())
+    });
+    c.bench_function("apath/sort", |b| {
+        b.iter_batched(
+            || shuffled.clone(),
+            |mut apaths| apaths.sort(),
+            BatchSize::LargeInput,
+        )
+    });
+}
+
+criterion_group!(benches, apath);
+criterion_main!(benches);
@@ -0,0 +1,69 @@
+// Conserve backup system.
+// Copyright 2020 Martin Pool.
+
+//! Benchmark storing blocks: hashing, compressing, and writing them.
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use tempfile::TempDir;
+
+use conserve::*;
+
+const BLOCK_SIZE: usize = 1 << 20;
+const BATCH: usize = 16;
+
+/// Somewhat compressible data, different for each seed.
+fn block(seed: u64) -> Vec<u8> {
+    let mut x = seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1;
+    (0..BLOCK_SIZE)
+        .map(|i| {
+            if i % 4 == 0 {
+                x ^= x << 13;
+                x ^= x >> 7;
+                x ^= x << 17;
+            }
+            (x >> (8 * (i % 4))) as u8 & 0x3f
+        })
+        .collect()
+}
+
+fn blockdir(c: &mut Criterion) {
+    let blocks: Vec<Vec<u8>> = (0..BATCH as u64).map(block).collect();
+    let mut group = c.benchmark_group("blockdir");
+
+    group.throughput(Throughput::Bytes(BLOCK_SIZE as u64));
+    group.bench_function("store_block", |b| {
+        b.iter_batched(
+            || {
+                let dir = TempDir::new().unwrap();
+                let block_dir = BlockDir::create(&dir.path().join("d")).unwrap();
+                (dir, block_dir)
+            },
+            |(_dir, block_dir)| block_dir.store_block(&blocks[0]).unwrap(),
+            BatchSize::PerIteration,
+        )
+    });
+
+    let dir = TempDir::new().unwrap();
+    let block_dir = BlockDir::create(&dir.path().join("d")).unwrap();
+    block_dir.store_block(&blocks[0]).unwrap();
+    group.bench_function("store_existing_block", |b| {
+        b.iter(|| block_dir.store_block(&blocks[0]).unwrap())
+    });
+
+    group.throughput(Throughput::Bytes((BLOCK_SIZE * BATCH) as u64));
+    group.bench_function("store_blocks", |b| {
+        b.iter_batched(
+            || {
+                let dir = TempDir::new().unwrap();
+                let block_dir = BlockDir::create(&dir.path().join("d")).unwrap();
+                (dir, block_dir)
+            },
+            |(_dir, block_dir)| block_dir.store_blocks(&blocks).unwrap(),
+            BatchSize::PerIteration,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, blockdir);
+criterion_main!(benches);
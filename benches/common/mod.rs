@@ -0,0 +1,32 @@
+// Conserve backup system.
+// Copyright 2020 Martin Pool.
+
+//! Trees shared by the benchmarks.
+
+use std::env;
+
+use conserve::test_fixtures::{NameStyle, TreeFixture, TreeGenerator};
+
+/// The number of files in generated trees, from `CONSERVE_BENCH_FILES`, so
+/// that benchmarks can be run at larger scales.
+pub fn bench_files() -> usize {
+    env::var("CONSERVE_BENCH_FILES")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(10_000)
+}
+
+/// Make a tree with `bench_files` files, always the same for a given size.
+pub fn generated_tree() -> TreeFixture {
+    let files = bench_files();
+    let tf = TreeFixture::new();
+    TreeGenerator::new(0)
+        .files(files)
+        .dirs(files / 20)
+        .max_depth(6)
+        .max_file_size(16 << 10)
+        .symlinks(files / 100)
+        .names(NameStyle::Mixed)
+        .generate(&tf);
+    tf
+}
@@ -0,0 +1,56 @@
+// Conserve backup system.
+// Copyright 2020 Martin Pool.
+
+//! Benchmark writing and reading indexes, in each format.
+
+use std::path::Path;
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use tempfile::TempDir;
+
+use conserve::test_fixtures::ScratchArchive;
+use conserve::*;
+
+mod common;
+
+/// Back up a generated tree, to get realistic index entries.
+fn entries() -> Vec<IndexEntry> {
+    let tf = common::generated_tree();
+    let af = ScratchArchive::new();
+    BackupOptions::new(tf.path(), af.path()).run().unwrap();
+    StoredTree::open_last(&af)
+        .unwrap()
+        .iter_entries()
+        .unwrap()
+        .collect()
+}
+
+fn write_index(dir: &Path, format: IndexFormat, entries: &[IndexEntry]) {
+    let mut builder = IndexBuilder::new(dir).with_format(format);
+    for entry in entries {
+        builder.push_entry(entry.clone()).unwrap();
+    }
+    builder.finish().unwrap();
+}
+
+fn index(c: &mut Criterion) {
+    let entries = entries();
+    for &(name, format) in &[("json", IndexFormat::Json), ("binary", IndexFormat::Binary)] {
+        c.bench_function(&format!("index/write/{}", name), |b| {
+            b.iter_batched(
+                || TempDir::new().unwrap(),
+                |dir| write_index(dir.path(), format, &entries),
+                BatchSize::PerIteration,
+            )
+        });
+
+        let dir = TempDir::new().unwrap();
+        write_index(dir.path(), format, &entries);
+        c.bench_function(&format!("index/read/{}", name), |b| {
+            b.iter(|| ReadIndex::new(dir.path()).iter().unwrap().count())
+        });
+    }
+}
+
+criterion_group!(benches, index);
+criterion_main!(benches);
@@ -0,0 +1,22 @@
+// Conserve backup system.
+// Copyright 2020 Martin Pool.
+
+//! Benchmark walking a source tree.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+
+use conserve::*;
+
+mod common;
+
+fn live_tree(c: &mut Criterion) {
+    let tf = common::generated_tree();
+    let lt = LiveTree::open(tf.path()).unwrap();
+    c.bench_function("live_tree/walk", |b| {
+        b.iter(|| lt.iter_entries().unwrap().count())
+    });
+    c.bench_function("live_tree/size", |b| b.iter(|| lt.size().unwrap()));
+}
+
+criterion_group!(benches, live_tree);
+criterion_main!(benches);
@@ -44,6 +44,61 @@ impl Apath {
         }
         true
     }
+
+    /// True if this apath is `dir` or inside it.
+    pub fn is_in(&self, dir: &Apath) -> bool {
+        self.0 == dir.0
+            || dir.0 == "/"
+            || (self.0.starts_with(&dir.0) && self.0[dir.0.len()..].starts_with('/'))
+    }
+
+    /// If this apath is `from` or inside it, return the same apath with `from`
+    /// replaced by `to`.
+    pub fn replace_prefix(&self, from: &Apath, to: &Apath) -> Option<Apath> {
+        if !self.is_in(from) {
+            return None;
+        }
+        let rest = if from.0 == "/" {
+            &self.0
+        } else {
+            &self.0[from.0.len()..]
+        };
+        Some(Apath(match (to.0.as_str(), rest) {
+            (to, "") | (to, "/") => to.to_owned(),
+            ("/", rest) => rest.to_owned(),
+            (to, rest) => format!("{}{}", to, rest),
+        }))
+    }
+
+    /// The number of directories this apath is below the root: 0 for the root
+    /// itself, 1 for entries in the root, and so on.
+    pub fn depth(&self) -> usize {
+        if self.0 == "/" {
+            0
+        } else {
+            self.0.matches('/').count()
+        }
+    }
+
+    /// Compare this apath to the range of apaths strictly inside `dir`.
+    ///
+    /// In apath order, everything inside a directory sorts together, after the
+    /// directory itself and its siblings. So this returns `Equal` for apaths
+    /// inside `dir`, and otherwise says whether this apath sorts before or after
+    /// all of them.
+    pub fn cmp_to_contents_of(&self, dir: &Apath) -> Ordering {
+        if self != dir && self.is_in(dir) {
+            return Ordering::Equal;
+        }
+        // Everything outside the directory compares the same way to any
+        // apath inside it, so compare against an arbitrary child.
+        let child = if dir.0 == "/" {
+            Apath("/x".to_owned())
+        } else {
+            Apath(format!("{}/x", dir.0))
+        };
+        self.cmp(&child)
+    }
 }
 
 impl From<Apath> for String {
@@ -177,21 +232,97 @@ impl CheckOrder {
     }
 
     pub fn check(&mut self, a: &Apath) {
-        if let Some(ref last_apath) = self.last_apath {
-            assert!(
-                last_apath < a,
+        if let Err(last_apath) = self.try_check(a) {
+            panic!(
                 "apaths out of order: {:?} should be before {:?}",
-                last_apath,
-                a
+                last_apath, a
             );
         }
-        self.last_apath = Some(a.clone());
+    }
+
+    /// Like `check`, but rather than panicking, return the previous apath if
+    /// `a` doesn't sort strictly after it: either it's out of order or it's a
+    /// duplicate.
+    pub fn try_check(&mut self, a: &Apath) -> std::result::Result<(), Apath> {
+        match self.last_apath.replace(a.clone()) {
+            Some(last_apath) if last_apath >= *a => Err(last_apath),
+            _ => Ok(()),
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::Apath;
+    use super::{Apath, CheckOrder};
+
+    #[test]
+    pub fn is_in() {
+        let a = Apath::from("/a/b");
+        assert!(a.is_in(&"/".into()));
+        assert!(a.is_in(&"/a".into()));
+        assert!(a.is_in(&"/a/b".into()));
+        assert!(!a.is_in(&"/a/b/c".into()));
+        assert!(!a.is_in(&"/a/bb".into()));
+        assert!(!Apath::from("/a/bb").is_in(&a));
+    }
+
+    #[test]
+    pub fn replace_prefix() {
+        for (apath, from, to, expected) in &[
+            ("/a/b", "/a", "/c", Some("/c/b")),
+            ("/a", "/a", "/c/d", Some("/c/d")),
+            ("/a/b/c", "/a/b", "/", Some("/c")),
+            ("/a/b", "/", "/x", Some("/x/a/b")),
+            ("/", "/", "/x", Some("/x")),
+            ("/ab", "/a", "/c", None),
+            ("/b", "/a", "/c", None),
+        ] {
+            assert_eq!(
+                Apath::from(*apath).replace_prefix(&(*from).into(), &(*to).into()),
+                expected.map(Apath::from),
+                "{} {}={}",
+                apath,
+                from,
+                to
+            );
+        }
+    }
+
+    #[test]
+    pub fn depth() {
+        assert_eq!(Apath::from("/").depth(), 0);
+        assert_eq!(Apath::from("/a").depth(), 1);
+        assert_eq!(Apath::from("/a/b/c").depth(), 3);
+    }
+
+    #[test]
+    pub fn cmp_to_contents_of() {
+        use std::cmp::Ordering::*;
+        let b = Apath::from("/b");
+        for (a, expected) in &[
+            ("/", Less),
+            ("/b", Less),
+            ("/zzz", Less),
+            ("/a/zzz", Less),
+            ("/b/a", Equal),
+            ("/b/c/d", Equal),
+            ("/c/a", Greater),
+        ] {
+            assert_eq!(Apath::from(*a).cmp_to_contents_of(&b), *expected, "{}", a);
+        }
+        assert_eq!(Apath::from("/").cmp_to_contents_of(&"/".into()), Less);
+        assert_eq!(Apath::from("/a").cmp_to_contents_of(&"/".into()), Equal);
+    }
+
+    #[test]
+    pub fn try_check_order() {
+        let mut check_order = CheckOrder::new();
+        assert_eq!(check_order.try_check(&"/".into()), Ok(()));
+        assert_eq!(check_order.try_check(&"/b".into()), Ok(()));
+        assert_eq!(check_order.try_check(&"/b".into()), Err("/b".into()));
+        assert_eq!(check_order.try_check(&"/a".into()), Err("/b".into()));
+        assert_eq!(check_order.try_check(&"/a/c".into()), Ok(()));
+    }
 
     #[test]
     pub fn invalid() {
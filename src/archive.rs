@@ -3,23 +3,43 @@
 
 //! Archives holding backup material.
 
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fs::read_dir;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use serde::{Deserialize, Serialize};
 use snafu::{ensure, ResultExt};
+use tracing::{error, info};
 
+use super::deletion_guard::{DeletionGuard, DELETION_GUARD_FILENAME};
 use super::io::file_exists;
 use super::jsonio;
 use super::misc::remove_item;
 use super::*;
-use crate::stats::ValidateArchiveStats;
+use crate::stats::{BandHistory, BandSpace, SquashStats, ValidateArchiveStats};
 
 const HEADER_FILENAME: &str = "CONSERVE";
+const HEADER_SIGNATURE_FILENAME: &str = "CONSERVE.sig";
+
+/// Archive version written in the header of archives with binary indexes, so
+/// that versions of Conserve that can't read them refuse to open the archive.
+const BINARY_INDEX_ARCHIVE_VERSION: &str = "0.6.3";
 static BLOCK_DIR: &str = "d";
 
+/// Holds one subdirectory for each named tree.
+static TREES_DIR: &str = "trees";
+
+/// Holds bands that were removed, until the trash is emptied. Bands from
+/// named trees are in `trash/trees/NAME`.
+static TRASH_DIR: &str = "trash";
+
 /// An archive holding backup material.
+///
+/// An archive holds one default series of bands, and optionally any number of
+/// other independent series in named trees. All trees share the same block
+/// directory so that content is deduplicated between them.
 #[derive(Clone, Debug)]
 pub struct Archive {
     /// Top-level directory for the archive.
@@ -27,26 +47,234 @@ pub struct Archive {
 
     /// Holds body content for all file versions.
     block_dir: BlockDir,
+
+    /// The named tree whose bands are read and written, or None for the
+    /// default tree.
+    tree_name: Option<String>,
+
+    /// Encoding for index hunks in new bands.
+    index_format: IndexFormat,
+
+    /// Block and index thresholds for new bands.
+    tuning: Tuning,
+
+    /// If set, new bands are signed with this key.
+    pub(crate) signing_key: Option<Arc<SigningKey>>,
+
+    /// If set, bands are checked against this key when they're read.
+    pub(crate) public_key: Option<PublicKey>,
+
+    /// If true, new bands are written without timestamps or references to
+    /// earlier bands, so that the same tree always gives the same band.
+    deterministic: bool,
+
+    /// If true, new bands store only the index entries that changed from
+    /// the previous band.
+    layered_indexes: bool,
+
+    /// The secret that allows removing data, if the archive is guarded.
+    deletion_secret: Option<Secret>,
+}
+
+/// Options for validating an archive, for programs that embed Conserve.
+///
+/// ```no_run
+/// let stats = conserve::ValidateOptions::new("/backup/archive").run().unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct ValidateOptions {
+    archive: PathBuf,
+    band_id: Option<BandId>,
+    excludes: Vec<String>,
+    jobs: Option<usize>,
+    validation_cache: Option<PathBuf>,
+    since: Option<Duration>,
+    scrub_budget: Option<Duration>,
+    fix_index_order: bool,
+}
+
+impl ValidateOptions {
+    /// Validate the archive at `archive`.
+    pub fn new<P: AsRef<Path>>(archive: P) -> ValidateOptions {
+        ValidateOptions {
+            archive: archive.as_ref().to_path_buf(),
+            band_id: None,
+            excludes: Vec::new(),
+            jobs: None,
+            validation_cache: None,
+            since: None,
+            scrub_budget: None,
+            fix_index_order: false,
+        }
+    }
+
+    /// Check only this version, rather than the whole archive.
+    pub fn band_id(self, band_id: BandId) -> ValidateOptions {
+        ValidateOptions {
+            band_id: Some(band_id),
+            ..self
+        }
+    }
+
+    /// Don't check the content of stored files matching a glob pattern.
+    pub fn exclude(mut self, pattern: &str) -> ValidateOptions {
+        self.excludes.push(pattern.to_owned());
+        self
+    }
+
+    /// Check blocks and stored files on `jobs` worker threads, rather than
+    /// one per core.
+    pub fn jobs(self, jobs: usize) -> ValidateOptions {
+        ValidateOptions {
+            jobs: Some(jobs),
+            ..self
+        }
+    }
+
+    /// Remember which blocks were verified, and when, in this file rather
+    /// than the default `ValidationCache::sidecar_path`.
+    pub fn validation_cache<P: AsRef<Path>>(self, path: P) -> ValidateOptions {
+        ValidateOptions {
+            validation_cache: Some(path.as_ref().to_path_buf()),
+            ..self
+        }
+    }
+
+    /// Don't reread blocks that the validation cache says were verified
+    /// within this time.
+    pub fn since(self, since: Duration) -> ValidateOptions {
+        ValidateOptions {
+            since: Some(since),
+            ..self
+        }
+    }
+
+    /// Spend at most this long reading blocks, starting with those least
+    /// recently verified, so that successive runs cover the whole archive.
+    pub fn scrub_budget(self, scrub_budget: Duration) -> ValidateOptions {
+        ValidateOptions {
+            scrub_budget: Some(scrub_budget),
+            ..self
+        }
+    }
+
+    /// Rewrite, in order, any band index whose apaths are out of order or
+    /// duplicated.
+    pub fn fix_index_order(self, fix_index_order: bool) -> ValidateOptions {
+        ValidateOptions {
+            fix_index_order,
+            ..self
+        }
+    }
+
+    /// Check the archive, reporting problems through the ui module.
+    ///
+    /// The validation cache is used only when checking the whole archive.
+    pub fn run(&self) -> Result<ValidateArchiveStats> {
+        let archive = Archive::open(&self.archive)?;
+        let excludes = excludes::from_strings(&self.excludes)?;
+        let cache_path = match &self.validation_cache {
+            Some(path) => Some(path.clone()),
+            None if self.since.is_some() || self.scrub_budget.is_some() => {
+                Some(ValidationCache::sidecar_path(&self.archive))
+            }
+            None => None,
+        };
+        let mut stats = in_thread_pool(self.jobs, || match (&self.band_id, cache_path) {
+            (Some(band_id), _) => archive.validate_band(band_id, excludes),
+            (None, Some(cache_path)) => {
+                let mut cache = ValidationCache::open(&cache_path)?;
+                archive.validate_with_cache(excludes, &mut cache, self.since, self.scrub_budget)
+            }
+            (None, None) => archive.validate_with_excludes(excludes),
+        })??;
+        if self.fix_index_order && stats.index_order_errors > 0 {
+            stats.indexes_rewritten = archive.fix_index_order(self.band_id.as_ref())?;
+        }
+        Ok(stats)
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 struct ArchiveHeader {
     conserve_archive_version: String,
+
+    #[serde(default, skip_serializing_if = "IndexFormat::is_default")]
+    index_format: IndexFormat,
+
+    #[serde(default, skip_serializing_if = "Tuning::is_default")]
+    tuning: Tuning,
+}
+
+/// The archive version for an archive whose indexes are in `index_format`.
+fn archive_version(index_format: IndexFormat) -> &'static str {
+    match index_format {
+        IndexFormat::Json => ARCHIVE_VERSION,
+        IndexFormat::Binary => BINARY_INDEX_ARCHIVE_VERSION,
+    }
+}
+
+/// Largest file that's read to check whether it's an archive header.
+const MAX_HEADER_SIZE: u64 = 64 << 10;
+
+/// True if `path` looks like the top directory of an archive: it has a
+/// header file holding an archive version.
+///
+/// Unrelated files that happen to be called `CONSERVE` don't count.
+pub(crate) fn is_archive_dir(path: &Path) -> bool {
+    let header_path = path.join(HEADER_FILENAME);
+    match std::fs::metadata(&header_path) {
+        Ok(metadata) if metadata.is_file() && metadata.len() <= MAX_HEADER_SIZE => (),
+        _ => return false,
+    }
+    std::fs::read(&header_path)
+        .ok()
+        .and_then(|bytes| serde_json::from_slice::<ArchiveHeader>(&bytes).ok())
+        .is_some()
 }
 
 impl Archive {
     /// Make a new directory to hold an archive, and write the header.
     pub fn create<P: AsRef<Path>>(path: P) -> Result<Archive> {
+        Archive::create_with_index_format(path, IndexFormat::default())
+    }
+
+    /// Make a new archive whose bands will have indexes in the given format.
+    pub fn create_with_index_format<P: AsRef<Path>>(
+        path: P,
+        index_format: IndexFormat,
+    ) -> Result<Archive> {
+        Archive::create_with_tuning(path, index_format, Tuning::default())
+    }
+
+    /// Make a new archive whose bands will have indexes in the given format,
+    /// and be written with the given block and index thresholds.
+    pub fn create_with_tuning<P: AsRef<Path>>(
+        path: P,
+        index_format: IndexFormat,
+        tuning: Tuning,
+    ) -> Result<Archive> {
+        tuning.validate()?;
         let path = path.as_ref();
         std::fs::create_dir(&path).with_context(|| errors::CreateArchiveDirectory { path })?;
         let block_dir = BlockDir::create(&path.join(BLOCK_DIR))?;
         let header = ArchiveHeader {
-            conserve_archive_version: String::from(ARCHIVE_VERSION),
+            conserve_archive_version: String::from(archive_version(index_format)),
+            index_format,
+            tuning,
         };
         jsonio::write_json_metadata_file(&path.join(HEADER_FILENAME), &header)?;
         Ok(Archive {
             path: path.to_path_buf(),
             block_dir,
+            tree_name: None,
+            index_format,
+            tuning,
+            signing_key: None,
+            public_key: None,
+            deterministic: false,
+            layered_indexes: false,
+            deletion_secret: None,
         })
     }
 
@@ -62,7 +290,7 @@ impl Archive {
         );
         let header: ArchiveHeader = jsonio::read_json_metadata_file(&header_path)?;
         ensure!(
-            header.conserve_archive_version == ARCHIVE_VERSION,
+            header.conserve_archive_version == archive_version(header.index_format),
             errors::UnsupportedArchiveVersion {
                 version: header.conserve_archive_version,
                 path,
@@ -71,9 +299,280 @@ impl Archive {
         Ok(Archive {
             path: path.to_path_buf(),
             block_dir: BlockDir::new(&path.join(BLOCK_DIR)),
+            tree_name: None,
+            index_format: header.index_format,
+            tuning: header.tuning,
+            signing_key: None,
+            public_key: None,
+            deterministic: false,
+            layered_indexes: false,
+            deletion_secret: None,
+        })
+    }
+
+    /// Open an existing archive, and select the named tree if one is given.
+    pub fn open_tree<P: AsRef<Path>>(path: P, tree_name: Option<&str>) -> Result<Archive> {
+        let archive = Archive::open(path)?;
+        match tree_name {
+            Some(tree_name) => archive.select_tree(tree_name),
+            None => Ok(archive),
+        }
+    }
+
+    /// Return an Archive that reads and writes bands in the named tree.
+    ///
+    /// The tree need not exist yet: it's created when the first band is written.
+    pub fn select_tree(self, tree_name: &str) -> Result<Archive> {
+        ensure!(
+            tree_name_is_valid(tree_name),
+            errors::InvalidTreeName { tree_name }
+        );
+        Ok(Archive {
+            tree_name: Some(tree_name.to_owned()),
+            ..self
+        })
+    }
+
+    /// Sign new bands with this key, and check the signatures of the header
+    /// and of every complete band read, failing if they're missing or don't
+    /// match.
+    ///
+    /// The header is checked immediately.
+    pub fn with_signing_key(self, key: SigningKey) -> Result<Archive> {
+        let archive = self.with_public_key(key.public_key())?;
+        Ok(Archive {
+            signing_key: Some(Arc::new(key)),
+            ..archive
+        })
+    }
+
+    /// Check the signatures of the header and of every complete band read
+    /// against this public key, failing if they're missing or don't match.
+    ///
+    /// New bands can't be written without the secret key, given by
+    /// `with_signing_key`.
+    ///
+    /// The header is checked immediately.
+    pub fn with_public_key(self, key: PublicKey) -> Result<Archive> {
+        key.verify(
+            &self.path,
+            &[HEADER_FILENAME.to_owned()],
+            None,
+            &self.path.join(HEADER_SIGNATURE_FILENAME),
+        )?;
+        Ok(Archive {
+            public_key: Some(key),
+            ..self
         })
     }
 
+    /// Write new bands deterministically, so that backing up the same tree
+    /// always writes the same head, index and tail, for testing that backups
+    /// are reproducible.
+    ///
+    /// Start and end times are written as 0, and the previous band isn't
+    /// used as a basis, so every file is read and stored the same way
+    /// regardless of the archive's history.
+    pub fn with_deterministic(self, deterministic: bool) -> Archive {
+        Archive {
+            deterministic,
+            ..self
+        }
+    }
+
+    /// Give the secret needed to remove versions from an archive that's
+    /// guarded against deletion.
+    pub fn with_deletion_secret(self, secret: Secret) -> Archive {
+        Archive {
+            deletion_secret: Some(secret),
+            ..self
+        }
+    }
+
+    /// True if removing versions from this archive needs a deletion secret.
+    pub fn is_deletion_guarded(&self) -> Result<bool> {
+        Ok(DeletionGuard::load(&self.path)?.is_some())
+    }
+
+    /// Require `secret` to remove versions from this archive from now on, or
+    /// with None, stop requiring one.
+    ///
+    /// If the archive is already guarded, the current secret must have been
+    /// given with `with_deletion_secret`.
+    pub fn set_deletion_guard(&self, secret: Option<&Secret>) -> Result<()> {
+        self.authorize_deletion()?;
+        match secret {
+            Some(secret) => DeletionGuard::new(secret)?.save(&self.path),
+            None => {
+                let path = self.path.join(DELETION_GUARD_FILENAME);
+                match std::fs::remove_file(&path) {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                        Err(e).context(errors::WriteMetadata { path })
+                    }
+                    _ => Ok(()),
+                }
+            }
+        }
+    }
+
+    /// Check that the deletion secret was given, if the archive needs one.
+    fn authorize_deletion(&self) -> Result<()> {
+        if let Some(guard) = DeletionGuard::load(&self.path)? {
+            match &self.deletion_secret {
+                None => return errors::DeletionSecretRequired { path: &self.path }.fail(),
+                Some(secret) => ensure!(
+                    guard.accepts(secret),
+                    errors::WrongDeletionSecret { path: &self.path }
+                ),
+            }
+        }
+        Ok(())
+    }
+
+    /// True if new bands are written deterministically.
+    pub fn is_deterministic(&self) -> bool {
+        self.deterministic
+    }
+
+    /// Write new bands with layered indexes, holding only the entries that
+    /// were added or changed since the previous complete band, and markers
+    /// for those that were deleted.
+    ///
+    /// This makes backups of mostly-unchanged trees much smaller and faster
+    /// to write. Reading a layered band reads the bands below it too, so
+    /// after `MAX_INDEX_LAYERS` layers a whole index is written again.
+    pub fn with_layered_indexes(self, layered_indexes: bool) -> Archive {
+        Archive {
+            layered_indexes,
+            ..self
+        }
+    }
+
+    /// True if new bands are written with layered indexes.
+    pub fn layered_indexes(&self) -> bool {
+        self.layered_indexes
+    }
+
+    /// The key used to sign and check bands, if any.
+    pub fn signing_key(&self) -> Option<&SigningKey> {
+        self.signing_key.as_deref()
+    }
+
+    /// Sign the archive header, and every complete band in every tree that's
+    /// not signed yet, returning the number of bands signed.
+    ///
+    /// A signed band that follows a newly signed one is signed again, to
+    /// name it as the band before.
+    ///
+    /// This trusts that the existing bands haven't already been tampered with.
+    pub fn sign(&self, key: &SigningKey) -> Result<usize> {
+        key.sign(
+            &self.path,
+            &[HEADER_FILENAME.to_owned()],
+            &[],
+            None,
+            &self.path.join(HEADER_SIGNATURE_FILENAME),
+        )?;
+        let mut signed = 0;
+        for tree in self.all_trees()? {
+            let mut previous = None;
+            let mut sign_next = false;
+            for band_id in tree.list_bands()? {
+                let band = Band::open(&tree, &band_id)?;
+                if band.is_signed() {
+                    if sign_next {
+                        band.sign(key, previous.as_ref())?;
+                        sign_next = false;
+                    }
+                } else if band.is_closed()? {
+                    band.sign(key, previous.as_ref())?;
+                    signed += 1;
+                    sign_next = true;
+                } else {
+                    continue;
+                }
+                previous = Some(band_id);
+            }
+        }
+        Ok(signed)
+    }
+
+    /// If there's a public key, check the band's signature.
+    pub(crate) fn verify_band(&self, band: &Band) -> Result<()> {
+        match &self.public_key {
+            Some(key) => band.verify_signature(key, self.previous_signed_band(band.id())?.as_ref()),
+            None => Ok(()),
+        }
+    }
+
+    /// The last signed band of the selected tree before `band_id`, which
+    /// `band_id`'s signature names.
+    pub(crate) fn previous_signed_band(&self, band_id: &BandId) -> Result<Option<BandId>> {
+        Ok(self
+            .list_bands()?
+            .into_iter()
+            .rev()
+            .find(|id| id < band_id && self.is_band_signed(id)))
+    }
+
+    /// The first signed band of the selected tree after `band_id`.
+    fn next_signed_band(&self, band_id: &BandId) -> Result<Option<BandId>> {
+        Ok(self
+            .list_bands()?
+            .into_iter()
+            .find(|id| id > band_id && self.is_band_signed(id)))
+    }
+
+    /// True if the band has a signature, without opening the band.
+    fn is_band_signed(&self, band_id: &BandId) -> bool {
+        self.bands_path()
+            .join(band_id.to_string())
+            .join(band::SIGNATURE_FILENAME)
+            .is_file()
+    }
+
+    /// The default tree and all the named trees.
+    fn all_trees(&self) -> Result<Vec<Archive>> {
+        let mut trees = vec![Archive {
+            tree_name: None,
+            ..self.clone()
+        }];
+        for tree_name in self.list_trees()? {
+            trees.push(self.clone().select_tree(&tree_name)?);
+        }
+        Ok(trees)
+    }
+
+    /// The encoding used for index hunks in new bands.
+    pub fn index_format(&self) -> IndexFormat {
+        self.index_format
+    }
+
+    /// The format-compatibility version in this archive's header.
+    pub fn archive_version(&self) -> &'static str {
+        archive_version(self.index_format)
+    }
+
+    /// The block and index thresholds used when writing new bands.
+    pub fn tuning(&self) -> Tuning {
+        self.tuning
+    }
+
+    /// The name of the selected tree, or None for the default tree.
+    pub fn tree_name(&self) -> Option<&str> {
+        self.tree_name.as_deref()
+    }
+
+    /// Return the names of all named trees in the archive, in sorted order.
+    pub fn list_trees(&self) -> Result<Vec<String>> {
+        let path = self.path.join(TREES_DIR);
+        match list_dir(&path) {
+            Ok((_files, dirs)) => Ok(dirs),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e).context(errors::ListBands { path }),
+        }
+    }
+
     pub fn block_dir(&self) -> &BlockDir {
         &self.block_dir
     }
@@ -83,17 +582,32 @@ impl Archive {
         self.path.as_path()
     }
 
+    /// Returns the directory holding bands for the selected tree.
+    pub fn bands_path(&self) -> PathBuf {
+        match &self.tree_name {
+            None => self.path.clone(),
+            Some(name) => self.path.join(TREES_DIR).join(name),
+        }
+    }
+
     /// Returns a vector of band ids, in sorted order from first to last.
     pub fn list_bands(&self) -> Result<Vec<BandId>> {
         let mut band_ids = Vec::<BandId>::new();
-        for e in read_dir(self.path())
-            .with_context(|| errors::ListBands {
-                path: self.path.clone(),
-            })?
-            .filter_map(std::result::Result::ok)
-        {
+        let path = self.bands_path();
+        let dir_iter = match read_dir(&path) {
+            Ok(i) => i,
+            // A named tree with no bands yet need not have a directory.
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && self.tree_name.is_some() => {
+                return Ok(band_ids)
+            }
+            Err(e) => return Err(e).context(errors::ListBands { path }),
+        };
+        for e in dir_iter.filter_map(std::result::Result::ok) {
             if let Ok(n) = e.file_name().into_string() {
-                if e.file_type().map(|ft| ft.is_dir()).unwrap_or(false) && n != BLOCK_DIR {
+                if e.file_type().map(|ft| ft.is_dir()).unwrap_or(false)
+                    && (self.tree_name.is_some()
+                        || (n != BLOCK_DIR && n != TREES_DIR && n != TRASH_DIR))
+                {
                     band_ids.push(BandId::from_string(&n)?);
                 }
             }
@@ -122,100 +636,653 @@ impl Archive {
         Ok(None)
     }
 
+    /// Find the band named by `version`, which is either a band id like
+    /// `b0003`, or `latest` for the last complete band, or `latest~N` for the
+    /// Nth complete band before that.
+    ///
+    /// Band ids are returned even if there's no such band, so that opening
+    /// it reports the problem.
+    pub fn resolve_band_id(&self, version: &str) -> Result<BandId> {
+        let back = match version.strip_prefix("latest") {
+            None => return BandId::from_string(version),
+            Some("") => 0,
+            Some(back) => match back.strip_prefix('~').map(str::parse::<usize>) {
+                Some(Ok(back)) => back,
+                _ => {
+                    return Err(Error::InvalidVersion {
+                        version: version.to_owned(),
+                    })
+                }
+            },
+        };
+        let mut complete_versions = 0;
+        for band_id in self.list_bands()?.into_iter().rev() {
+            if Band::open(self, &band_id)?.is_closed()? {
+                if complete_versions == back {
+                    return Ok(band_id);
+                }
+                complete_versions += 1;
+            }
+        }
+        if complete_versions == 0 {
+            Err(Error::ArchiveEmpty)
+        } else {
+            Err(Error::NoSuchVersion {
+                version: version.to_owned(),
+                complete_versions,
+            })
+        }
+    }
+
     /// Return a sorted set containing all the blocks referenced by all bands.
+    ///
+    /// Since the block directory is shared, this includes the bands of all
+    /// trees, not only the selected tree.
     pub fn referenced_blocks(&self) -> Result<BTreeSet<String>> {
         let mut hs = BTreeSet::<String>::new();
-        for band_id in self.list_bands()? {
-            let band = Band::open(&self, &band_id)?;
-            for ie in band.iter_entries()? {
-                for a in ie.addrs {
-                    hs.insert(a.hash);
+        for tree in self.all_trees()? {
+            for band_id in tree.list_bands()? {
+                let band = Band::open(&tree, &band_id)?;
+                for ie in band.iter_entries()? {
+                    for a in ie.addrs {
+                        hs.insert(a.hash);
+                    }
                 }
             }
         }
         Ok(hs)
     }
 
+    /// Measure, for each band of the selected tree, the compressed size of
+    /// the blocks used only by that band, and of those it shares with other
+    /// bands of any tree.
+    pub fn band_space(&self) -> Result<BTreeMap<BandId, BandSpace>> {
+        let mut band_blocks = Vec::<(bool, BandId, BTreeSet<String>)>::new();
+        for tree in self.all_trees()? {
+            let selected = tree.tree_name == self.tree_name;
+            for band_id in tree.list_bands()? {
+                let band = Band::open(&tree, &band_id)?;
+                let mut hashes = BTreeSet::new();
+                for ie in StoredTree::new(&tree, band)?.iter_entries()? {
+                    hashes.extend(ie.addrs.into_iter().map(|a| a.hash));
+                }
+                band_blocks.push((selected, band_id, hashes));
+            }
+        }
+        let mut ref_counts = HashMap::<&str, usize>::new();
+        for (_, _, hashes) in &band_blocks {
+            for hash in hashes {
+                *ref_counts.entry(hash).or_default() += 1;
+            }
+        }
+        let block_sizes: HashMap<String, u64> = self.block_dir.block_names_and_sizes()?.collect();
+        let mut space = BTreeMap::new();
+        for (selected, band_id, hashes) in &band_blocks {
+            if !selected {
+                continue;
+            }
+            let mut band_space = BandSpace::default();
+            for hash in hashes {
+                let size = block_sizes.get(hash).copied().unwrap_or_default();
+                if ref_counts[hash.as_str()] == 1 {
+                    band_space.exclusive_bytes += size;
+                } else {
+                    band_space.shared_bytes += size;
+                }
+            }
+            space.insert(band_id.clone(), band_space);
+        }
+        Ok(space)
+    }
+
+    /// Describe each band of the selected tree, in order, with how it grew
+    /// and changed from the band before.
+    pub fn history(&self) -> Result<Vec<BandHistory>> {
+        let block_sizes: HashMap<String, u64> = self.block_dir.block_names_and_sizes()?.collect();
+        let mut seen_blocks = HashSet::<String>::new();
+        let mut total_block_bytes = 0;
+        let mut previous_files = Vec::<(Apath, Vec<blockdir::Address>)>::new();
+        let mut history = Vec::new();
+        for band_id in self.list_bands()? {
+            ui::set_progress_phase(&format!("Read {}", band_id));
+            let band = Band::open(self, &band_id)?;
+            let info = band.get_info()?;
+            let mut files = Vec::new();
+            let mut file_bytes = 0;
+            let mut new_block_bytes = 0;
+            for entry in StoredTree::new(self, band)?.iter_entries()? {
+                if entry.kind != Kind::File {
+                    continue;
+                }
+                file_bytes += entry.addrs.iter().map(|a| a.len).sum::<u64>();
+                for addr in &entry.addrs {
+                    if !seen_blocks.contains(&addr.hash) {
+                        new_block_bytes += block_sizes.get(&addr.hash).copied().unwrap_or_default();
+                        seen_blocks.insert(addr.hash.clone());
+                    }
+                }
+                files.push((entry.apath, entry.addrs));
+            }
+            total_block_bytes += new_block_bytes;
+            let (files_added, files_changed, files_removed) =
+                compare_files(&previous_files, &files);
+            history.push(BandHistory {
+                band_id,
+                start_time: info.start_time,
+                is_closed: info.is_closed,
+                files: files.len() as u64,
+                file_bytes,
+                files_added,
+                files_changed,
+                files_removed,
+                new_block_bytes,
+                total_block_bytes,
+            });
+            previous_files = files;
+        }
+        Ok(history)
+    }
+
+    /// Merge the versions from `start` to `end` into a single version.
+    ///
+    /// `end` keeps its id and tree, but if its index is layered it's
+    /// rewritten as a whole index, so that it no longer depends on earlier
+    /// bands. The other bands in the range are then moved to the trash,
+    /// where `undelete` can bring them back until the trash is emptied.
+    /// Blocks are reused, and blocks no longer referenced are left in place.
+    ///
+    /// It's an error if any band after `end` is layered on a band that
+    /// would be removed.
+    ///
+    /// If signed bands are removed, the first signed band from `end` on is
+    /// signed again to follow the last signed band before `start`, which
+    /// needs the key.
+    pub fn squash(&self, start: &BandId, end: &BandId) -> Result<SquashStats> {
+        ensure!(
+            start <= end,
+            errors::InvalidVersionRange {
+                range: format!("{}..{}", start, end)
+            }
+        );
+        self.authorize_deletion()?;
+        let tree = StoredTree::open_version(self, end)?;
+        let band_ids = self.list_bands()?;
+        let removed: Vec<&BandId> = band_ids
+            .iter()
+            .filter(|band_id| *band_id >= start && *band_id < end)
+            .collect();
+        for band_id in band_ids.iter().filter(|band_id| *band_id > end) {
+            if let Some(base) = Band::open(self, band_id)?.index_base_band_id()? {
+                ensure!(
+                    !removed.contains(&&base),
+                    errors::SquashBaseInUse {
+                        band_id: base,
+                        layered_band_id: band_id.clone(),
+                    }
+                );
+            }
+        }
+        let previous = self.previous_signed_band(start)?;
+        let resign = match removed.iter().find(|band_id| self.is_band_signed(band_id)) {
+            Some(removed_signed) => {
+                let resign = band_ids
+                    .iter()
+                    .find(|band_id| *band_id >= end && self.is_band_signed(band_id));
+                ensure!(
+                    resign.is_none() || self.signing_key.is_some(),
+                    errors::SquashSignedBand {
+                        band_id: (*removed_signed).clone()
+                    }
+                );
+                resign
+            }
+            None => None,
+        };
+        let mut stats = SquashStats::default();
+        if tree.layers() > 0 {
+            let band = tree.band();
+            ensure!(
+                !band.is_signed() || self.signing_key.is_some(),
+                errors::SquashSignedBand {
+                    band_id: end.clone()
+                }
+            );
+            ui::set_progress_phase(&format!("Rewrite index of {}", end));
+            stats.index_builder_stats = band.rewrite_whole_index(
+                &tree,
+                self.index_format,
+                self.tuning.index_hunk_entries,
+                self.signing_key(),
+                previous.as_ref(),
+            )?;
+        }
+        // Sign before removing, so that if this is interrupted, squashing
+        // again signs it again.
+        if let (Some(band_id), Some(key)) = (resign, self.signing_key()) {
+            Band::open(self, band_id)?.sign(key, previous.as_ref())?;
+        }
+        // Remove the newest first, so that if this is interrupted, no
+        // remaining band is layered on one that's gone.
+        for band_id in removed.into_iter().rev() {
+            self.trash_band(band_id)?;
+            stats.bands_removed += 1;
+        }
+        Ok(stats)
+    }
+
+    /// Returns the directory holding removed bands of the selected tree.
+    fn trash_path(&self) -> PathBuf {
+        let trash = self.path.join(TRASH_DIR);
+        match &self.tree_name {
+            None => trash,
+            Some(name) => trash.join(TREES_DIR).join(name),
+        }
+    }
+
+    /// Move a band into the trash, replacing any band there with the same id.
+    fn trash_band(&self, band_id: &BandId) -> Result<()> {
+        let trash_path = self.trash_path();
+        let dest = trash_path.join(band_id.to_string());
+        let ctx = || errors::DeleteBand {
+            band_id: band_id.clone(),
+        };
+        std::fs::create_dir_all(&trash_path).with_context(ctx)?;
+        if dest.exists() {
+            std::fs::remove_dir_all(&dest).with_context(ctx)?;
+        }
+        std::fs::rename(self.bands_path().join(band_id.to_string()), &dest).with_context(ctx)
+    }
+
+    /// Returns the ids of bands of the selected tree in the trash, in order.
+    pub fn list_trash(&self) -> Result<Vec<BandId>> {
+        let path = self.trash_path();
+        let mut dirs = match list_dir(&path) {
+            Ok((_files, dirs)) => dirs,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e).context(errors::ListBands { path }),
+        };
+        if self.tree_name.is_none() {
+            remove_item(&mut dirs, &TREES_DIR);
+        }
+        let mut band_ids = dirs
+            .iter()
+            .map(|d| BandId::from_string(d))
+            .collect::<Result<Vec<BandId>>>()?;
+        band_ids.sort_unstable();
+        Ok(band_ids)
+    }
+
+    /// Move a band back out of the trash.
+    ///
+    /// If the band's index is layered on another band, that band must be
+    /// present too, so undelete the oldest first.
+    ///
+    /// If the band is signed, the next signed band is signed again to
+    /// follow it, which needs the key.
+    pub fn undelete(&self, band_id: &BandId) -> Result<()> {
+        let source = self.trash_path().join(band_id.to_string());
+        ensure!(
+            source.is_dir(),
+            errors::BandNotInTrash {
+                band_id: band_id.clone()
+            }
+        );
+        let dest = self.bands_path().join(band_id.to_string());
+        ensure!(
+            !dest.exists(),
+            errors::UndeleteExistingBand {
+                band_id: band_id.clone()
+            }
+        );
+        let resign = if source.join(band::SIGNATURE_FILENAME).is_file() {
+            self.next_signed_band(band_id)?
+        } else {
+            None
+        };
+        ensure!(
+            resign.is_none() || self.signing_key.is_some(),
+            errors::UndeleteSignedBand {
+                band_id: band_id.clone()
+            }
+        );
+        std::fs::rename(&source, &dest).context(errors::UndeleteBand {
+            band_id: band_id.clone(),
+        })?;
+        if let (Some(next), Some(key)) = (resign, self.signing_key()) {
+            Band::open(self, &next)?.sign(key, Some(band_id))?;
+        }
+        Ok(())
+    }
+
+    /// Permanently remove the bands of the selected tree in the trash, and
+    /// return how many there were.
+    ///
+    /// If the archive is guarded against deletion, the deletion secret is
+    /// needed. Blocks that were only used by these bands are left in place.
+    pub fn empty_trash(&self) -> Result<usize> {
+        self.authorize_deletion()?;
+        let band_ids = self.list_trash()?;
+        for band_id in &band_ids {
+            std::fs::remove_dir_all(self.trash_path().join(band_id.to_string())).context(
+                errors::DeleteBand {
+                    band_id: band_id.clone(),
+                },
+            )?;
+        }
+        Ok(band_ids.len())
+    }
+
     pub fn validate(&self) -> Result<ValidateArchiveStats> {
+        self.validate_with_excludes(excludes::excludes_nothing())
+    }
+
+    /// Check the whole archive, but skip checking the content of stored files
+    /// that match `excludes`.
+    pub fn validate_with_excludes(&self, excludes: GlobSet) -> Result<ValidateArchiveStats> {
+        self.validate_archive(excludes, None, None, None)
+    }
+
+    /// Check the whole archive, remembering in `cache` which blocks were
+    /// found intact.
+    ///
+    /// If `since` is given, blocks that the cache says were verified more
+    /// recently than that are not read again, nor are stored files made
+    /// only of such blocks.
+    ///
+    /// If `scrub_budget` is given, blocks are checked least recently
+    /// verified first, and checking stops once the budget is spent, so that
+    /// successive runs work their way around the whole archive. Stored files
+    /// are then checked only for missing or damaged blocks, without reading
+    /// their content again.
+    pub fn validate_with_cache(
+        &self,
+        excludes: GlobSet,
+        cache: &mut ValidationCache,
+        since: Option<Duration>,
+        scrub_budget: Option<Duration>,
+    ) -> Result<ValidateArchiveStats> {
+        self.validate_archive(excludes, Some(cache), since, scrub_budget)
+    }
+
+    fn validate_archive(
+        &self,
+        excludes: GlobSet,
+        mut cache: Option<&mut ValidationCache>,
+        since: Option<Duration>,
+        scrub_budget: Option<Duration>,
+    ) -> Result<ValidateArchiveStats> {
         // Check there's no extra top-level contents.
         self.validate_archive_dir()?;
-        ui::println("Check blockdir...");
-        let block_dir_stats = self.block_dir.validate()?;
-        self.validate_bands()?;
+
+        // Blocks and stored files are checked under one progress phase, so
+        // that the bar moves steadily from start to finish.
+        ui::set_progress_phase("Measure blocks and stored trees");
+        let start = SystemTime::now();
+        let mut blocks: Vec<(String, u64)> = self.block_dir.block_names_and_sizes()?.collect();
+        let mut recent: Vec<String> = Vec::new();
+        if let Some(cache) = cache.as_deref_mut() {
+            cache.retain_present(&blocks);
+            if let Some(since) = since {
+                let cutoff = start.checked_sub(since).unwrap_or(UNIX_EPOCH);
+                let (skipped, to_check): (Vec<_>, Vec<_>) = blocks
+                    .into_iter()
+                    .partition(|(hash, _)| cache.verified_since(hash, cutoff));
+                info!("Skip {} recently verified blocks", skipped.len());
+                recent = skipped.into_iter().map(|(hash, _)| hash).collect();
+                blocks = to_check;
+            }
+            if scrub_budget.is_some() {
+                // Resume where earlier scrubs stopped: blocks never checked
+                // come first, then the longest unchecked.
+                blocks.sort_by_key(|(hash, _)| cache.last_verified(hash));
+            }
+        }
+        let block_bytes: u64 = blocks.iter().map(|(_, size)| size).sum();
+        let mut tree_bytes: u64 = 0;
+        for tree in self.all_trees()? {
+            for band_id in tree.list_bands()?.iter() {
+                tree_bytes += StoredTree::open_incomplete_version(&tree, band_id)?
+                    .size()?
+                    .file_bytes;
+            }
+        }
+        info!(
+            "Check {} in blocks and {} in stored files...",
+            crate::misc::bytes_to_human_mb(block_bytes),
+            crate::misc::bytes_to_human_mb(tree_bytes)
+        );
+        ui::set_progress_phase("Check archive");
+        ui::set_bytes_total(block_bytes + tree_bytes);
+        let deadline = scrub_budget.map(|budget| Instant::now() + budget);
+        let (mut block_dir_stats, good, bad) = self.block_dir.check_blocks(&blocks, deadline);
+        if block_dir_stats.block_skipped_count > 0 {
+            info!(
+                "Scrub budget spent: {} blocks left for the next run",
+                block_dir_stats.block_skipped_count
+            );
+        }
+        block_dir_stats.block_skipped_count += recent.len() as u64;
+        let known_good = match cache {
+            Some(cache) => {
+                cache.record(&good, start);
+                cache.save()?;
+                if scrub_budget.is_some() {
+                    // Blocks not reached yet are taken on trust until their
+                    // turn comes.
+                    let bad: HashSet<String> = bad.into_iter().collect();
+                    Some(
+                        self.block_dir
+                            .block_names()?
+                            .filter(|hash| !bad.contains(hash))
+                            .collect::<HashSet<String>>(),
+                    )
+                } else {
+                    Some(good.into_iter().chain(recent).collect::<HashSet<String>>())
+                }
+            }
+            None => None,
+        };
+        let index_order_errors = self.validate_bands(&excludes, known_good.as_ref())?;
 
         // TODO: Don't say "OK" if there were non-fatal problems.
-        ui::println("Archive is OK.");
-        Ok(ValidateArchiveStats { block_dir_stats })
+        if index_order_errors == 0 {
+            info!("Archive is OK.");
+        }
+        Ok(ValidateArchiveStats {
+            block_dir_stats,
+            index_order_errors,
+            ..ValidateArchiveStats::default()
+        })
+    }
+
+    /// Check one band and the content of its stored files, other than those
+    /// matching `excludes`, without checking the rest of the archive.
+    pub fn validate_band(
+        &self,
+        band_id: &BandId,
+        excludes: GlobSet,
+    ) -> Result<ValidateArchiveStats> {
+        let band = Band::open(self, band_id)?;
+        band.validate()?;
+        self.verify_band(&band)?;
+        let index_order_errors = band.check_index_order()?;
+        StoredTree::open_incomplete_version(self, band_id)?
+            .with_excludes(excludes)
+            .validate()?;
+        if index_order_errors == 0 {
+            info!("Version {} is OK.", band_id);
+        }
+        Ok(ValidateArchiveStats {
+            index_order_errors,
+            ..ValidateArchiveStats::default()
+        })
+    }
+
+    /// Rewrite the index of `band_id`, or of every band, if its apaths are
+    /// out of order or duplicated, as might have been written by older or
+    /// buggy versions. Returns the number of indexes rewritten.
+    ///
+    /// The first entry for a duplicated apath is kept. Signed bands are
+    /// signed again, which needs the key.
+    pub fn fix_index_order(&self, band_id: Option<&BandId>) -> Result<u64> {
+        let band_ids = match band_id {
+            Some(band_id) => vec![band_id.clone()],
+            None => self.list_bands()?,
+        };
+        let mut rewritten = 0;
+        for band_id in band_ids {
+            let band = Band::open(self, &band_id)?;
+            if band.check_index_order()? == 0 {
+                continue;
+            }
+            ensure!(
+                !band.is_signed() || self.signing_key.is_some(),
+                errors::RewriteSignedIndex { band_id }
+            );
+            ui::set_progress_phase(&format!("Rewrite index of {}", band_id));
+            band.rewrite_sorted_index(
+                self.index_format,
+                self.tuning.index_hunk_entries,
+                self.signing_key(),
+                self.previous_signed_band(&band_id)?.as_ref(),
+            )?;
+            info!("Rewrote index of {} in order", band_id);
+            rewritten += 1;
+        }
+        Ok(rewritten)
     }
 
     fn validate_archive_dir(&self) -> Result<()> {
-        ui::println("Check archive top-level directory...");
+        info!("Check archive top-level directory...");
         let (mut files, mut dirs) =
             list_dir(self.path()).context(errors::ReadMetadata { path: self.path() })?;
         remove_item(&mut files, &HEADER_FILENAME);
+        remove_item(&mut files, &HEADER_SIGNATURE_FILENAME);
+        remove_item(&mut files, &DELETION_GUARD_FILENAME);
         if !files.is_empty() {
-            ui::problem(&format!(
+            error!(
                 "Unexpected files in archive directory {:?}: {:?}",
                 self.path(),
                 files
-            ));
+            );
         }
 
         remove_item(&mut dirs, &BLOCK_DIR);
-        dirs.sort();
-        let mut bs = BTreeSet::<BandId>::new();
-        for d in dirs.iter() {
-            if let Ok(b) = BandId::from_string(&d) {
-                if bs.contains(&b) {
-                    ui::problem(&format!(
-                        "Duplicated band directory in {:?}: {:?}",
-                        self.path(),
-                        d
-                    ));
-                } else {
-                    bs.insert(b);
+        remove_item(&mut dirs, &TRASH_DIR);
+        if dirs.iter().any(|d| d == TREES_DIR) {
+            remove_item(&mut dirs, &TREES_DIR);
+            self.validate_trees_dir()?;
+        }
+        validate_band_dirs(self.path(), dirs);
+        Ok(())
+    }
+
+    /// Check that `trees/` holds only validly-named trees, each containing
+    /// only bands.
+    fn validate_trees_dir(&self) -> Result<()> {
+        let trees_path = self.path.join(TREES_DIR);
+        let (files, tree_names) =
+            list_dir(&trees_path).context(errors::ReadMetadata { path: &trees_path })?;
+        if !files.is_empty() {
+            error!(
+                "Unexpected files in trees directory {:?}: {:?}",
+                trees_path, files
+            );
+        }
+        for tree_name in tree_names {
+            let tree_path = trees_path.join(&tree_name);
+            if !tree_name_is_valid(&tree_name) {
+                error!("Unexpected directory in {:?}: {:?}", trees_path, tree_name);
+                continue;
+            }
+            let (files, dirs) =
+                list_dir(&tree_path).context(errors::ReadMetadata { path: &tree_path })?;
+            if !files.is_empty() {
+                error!(
+                    "Unexpected files in tree directory {:?}: {:?}",
+                    tree_path, files
+                );
+            }
+            validate_band_dirs(&tree_path, dirs);
+        }
+        Ok(())
+    }
+
+    /// Check each band, and return the number of apaths out of order or
+    /// duplicated in their indexes.
+    fn validate_bands(
+        &self,
+        excludes: &GlobSet,
+        known_good: Option<&HashSet<String>>,
+    ) -> Result<u64> {
+        let mut index_order_errors = 0;
+        for tree in self.all_trees()? {
+            for bid in tree.list_bands()?.iter() {
+                let b = Band::open(&tree, bid)?;
+                b.validate()?;
+                if b.is_closed()? {
+                    tree.verify_band(&b)?;
                 }
-            } else {
-                ui::problem(&format!(
-                    "Unexpected directory in {:?}: {:?}",
-                    self.path(),
-                    d
-                ));
+                index_order_errors += b.check_index_order()?;
+
+                let st = StoredTree::open_incomplete_version(&tree, bid)?
+                    .with_excludes(excludes.clone());
+                st.validate_entries(known_good)?;
             }
         }
+        Ok(index_order_errors)
+    }
+}
 
-        Ok(())
+/// Complain about directories in `path` that are not bands, or that name a
+/// band twice.
+fn validate_band_dirs(path: &Path, mut dirs: Vec<String>) {
+    dirs.sort();
+    let mut bs = BTreeSet::<BandId>::new();
+    for d in dirs.iter() {
+        if let Ok(b) = BandId::from_string(&d) {
+            if bs.contains(&b) {
+                error!("Duplicated band directory in {:?}: {:?}", path, d);
+            } else {
+                bs.insert(b);
+            }
+        } else {
+            error!("Unexpected directory in {:?}: {:?}", path, d);
+        }
     }
+}
+
+/// True if `tree_name` can be used as the name of a tree.
+///
+/// Names must be usable as a directory name on any platform.
+fn tree_name_is_valid(tree_name: &str) -> bool {
+    !tree_name.is_empty()
+        && !tree_name.starts_with('.')
+        && tree_name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
 
-    fn validate_bands(&self) -> Result<()> {
-        let mut ps = ProgressState::default();
-        use crate::ui::println;
-        println("Measure stored trees...");
-        ps.phase = "Measure stored trees...".into();
-        let mut total_size: u64 = 0;
-        for bid in self.list_bands()?.iter() {
-            let b = StoredTree::open_incomplete_version(self, bid)?
-                .size()?
-                .file_bytes;
-            total_size += b;
-            ps.bytes_done += b;
-            // lock_ui().show_progress(&ps);
-        }
-
-        // lock_ui().println(&format!(
-        //     "Check {} in stored files...",
-        //     crate::misc::bytes_to_human_mb(total_size)
-        // ));
-        ps.bytes_total = total_size;
-        for bid in self.list_bands()?.iter() {
-            let b = Band::open(self, bid)?;
-            b.validate()?;
-
-            let st = StoredTree::open_incomplete_version(self, bid)?;
-            st.validate()?;
+/// Count the files added, changed, and removed between two lists of files in
+/// apath order.
+fn compare_files(
+    before: &[(Apath, Vec<blockdir::Address>)],
+    after: &[(Apath, Vec<blockdir::Address>)],
+) -> (u64, u64, u64) {
+    let (mut added, mut changed, mut removed) = (0, 0, 0);
+    let mut before = before.iter().peekable();
+    for (apath, addrs) in after {
+        while before.next_if(|(old_apath, _)| old_apath < apath).is_some() {
+            removed += 1;
+        }
+        match before.next_if(|(old_apath, _)| old_apath == apath) {
+            Some((_, old_addrs)) if old_addrs != addrs => changed += 1,
+            Some(_) => (),
+            None => added += 1,
         }
-        Ok(())
     }
+    removed += before.count() as u64;
+    (added, changed, removed)
 }
 
 #[cfg(test)]
@@ -225,7 +1292,43 @@ mod tests {
     use tempfile::TempDir;
 
     use super::*;
-    use crate::test_fixtures::ScratchArchive;
+    use crate::test_fixtures::{ScratchArchive, TreeFixture};
+
+    #[test]
+    fn resolve_band_ids() {
+        let af = ScratchArchive::new();
+        assert!(matches!(
+            af.resolve_band_id("latest"),
+            Err(Error::ArchiveEmpty)
+        ));
+        af.store_two_versions();
+        af.setup_incomplete_empty_band();
+
+        let resolve = |version| af.resolve_band_id(version).unwrap().to_string();
+        // The incomplete band isn't counted.
+        assert_eq!(resolve("latest"), "b0001");
+        assert_eq!(resolve("latest~0"), "b0001");
+        assert_eq!(resolve("latest~1"), "b0000");
+        assert_eq!(resolve("b0002"), "b0002");
+        assert_eq!(resolve("b0007"), "b0007");
+        assert!(matches!(
+            af.resolve_band_id("latest~2"),
+            Err(Error::NoSuchVersion {
+                complete_versions: 2,
+                ..
+            })
+        ));
+        for invalid in &["latest~", "latest~x", "latest2", "latest~-1", "b"] {
+            assert!(
+                matches!(
+                    af.resolve_band_id(invalid),
+                    Err(Error::InvalidVersion { .. })
+                ),
+                "{}",
+                invalid
+            );
+        }
+    }
 
     #[test]
     fn create_then_open_archive() {
@@ -242,6 +1345,90 @@ mod tests {
         assert!(arch.last_complete_band().unwrap().is_none());
     }
 
+    #[test]
+    fn band_space() {
+        let af = ScratchArchive::new();
+        let srcdir = TreeFixture::new();
+        srcdir.create_file_with_contents("a", b"in every version");
+        let lt = LiveTree::open(srcdir.path()).unwrap();
+        copy_tree(&lt, BackupWriter::begin(&af).unwrap(), &COPY_DEFAULT).unwrap();
+        srcdir.create_file_with_contents("b", b"only in the second version");
+        copy_tree(&lt, BackupWriter::begin(&af).unwrap(), &COPY_DEFAULT).unwrap();
+
+        let space = af.band_space().unwrap();
+        assert_eq!(space.len(), 2);
+        let b0 = space[&BandId::new(&[0])];
+        let b1 = space[&BandId::new(&[1])];
+        assert_eq!(b0.exclusive_bytes, 0);
+        assert!(b0.shared_bytes > 0);
+        assert!(b1.exclusive_bytes > 0);
+        assert_eq!(b1.shared_bytes, b0.shared_bytes);
+        let total: u64 = af
+            .block_dir()
+            .block_names_and_sizes()
+            .unwrap()
+            .map(|(_, s)| s)
+            .sum();
+        assert_eq!(b1.exclusive_bytes + b1.shared_bytes, total);
+    }
+
+    #[test]
+    fn history() {
+        let af = ScratchArchive::new();
+        af.store_two_versions();
+        let history = af.history().unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].band_id, BandId::new(&[0]));
+        assert_eq!(
+            (
+                history[0].files,
+                history[0].files_added,
+                history[0].files_removed
+            ),
+            (2, 2, 0)
+        );
+        assert_eq!(history[0].file_bytes, 2 * 8);
+        assert!(history[0].new_block_bytes > 0);
+        assert_eq!(history[0].total_block_bytes, history[0].new_block_bytes);
+        assert_eq!(
+            (
+                history[1].files,
+                history[1].files_added,
+                history[1].files_changed
+            ),
+            (3, 1, 0)
+        );
+        assert_eq!(
+            history[1].total_block_bytes,
+            history[0].total_block_bytes + history[1].new_block_bytes
+        );
+        assert!(history.iter().all(|h| h.is_closed));
+    }
+
+    #[test]
+    fn compare_file_lists() {
+        let addr = |hash: &str| {
+            vec![blockdir::Address {
+                hash: hash.to_owned(),
+                start: 0,
+                len: 1,
+            }]
+        };
+        let before = vec![
+            (Apath::from("/a"), addr("1")),
+            (Apath::from("/b"), addr("2")),
+            (Apath::from("/d"), addr("4")),
+        ];
+        let after = vec![
+            (Apath::from("/b"), addr("2")),
+            (Apath::from("/c"), addr("3")),
+            (Apath::from("/d"), addr("5")),
+            (Apath::from("/e"), addr("6")),
+        ];
+        assert_eq!(compare_files(&before, &after), (2, 1, 1));
+        assert_eq!(compare_files(&after, &[]), (0, 0, 4));
+    }
+
     /// A new archive contains just one header file.
     /// The header is readable json containing only a version number.
     #[test]
@@ -292,4 +1479,418 @@ mod tests {
         assert!(af.referenced_blocks().unwrap().is_empty());
         assert_eq!(af.block_dir.block_names().unwrap().count(), 0);
     }
+
+    #[test]
+    fn named_trees_are_independent() {
+        let af = ScratchArchive::new();
+        assert!(af.list_trees().unwrap().is_empty());
+
+        let home = af.clone().select_tree("home").unwrap();
+        assert_eq!(home.tree_name(), Some("home"));
+        assert!(home.list_bands().unwrap().is_empty());
+        assert!(home.last_complete_band().unwrap().is_none());
+
+        Band::create(&home).unwrap();
+        Band::create(&home).unwrap();
+        assert_eq!(
+            home.list_bands().unwrap(),
+            vec![BandId::new(&[0]), BandId::new(&[1])]
+        );
+        assert!(home.path().join("trees/home/b0001").is_dir());
+        assert_eq!(af.list_trees().unwrap(), vec!["home"]);
+
+        // The default tree doesn't see bands from the named tree.
+        assert!(af.list_bands().unwrap().is_empty());
+        let _band = Band::create(&af).unwrap();
+        assert_eq!(af.list_bands().unwrap(), vec![BandId::new(&[0])]);
+
+        let etc = af.clone().select_tree("etc").unwrap();
+        assert!(etc.list_bands().unwrap().is_empty());
+    }
+
+    #[test]
+    fn invalid_tree_names() {
+        let af = ScratchArchive::new();
+        for name in &["", ".", "..", ".hidden", "a/b", "a\\b", "x y"] {
+            assert!(
+                af.clone().select_tree(name).is_err(),
+                "{:?} should be invalid",
+                name
+            );
+        }
+        af.clone().select_tree("etc-2020_01.x").unwrap();
+    }
+
+    #[test]
+    fn signed_bands() {
+        let af = ScratchArchive::new();
+        af.store_two_versions();
+        let key = SigningKey::generate().unwrap();
+        let key_path = af.path().with_extension("key");
+        key.save(&key_path).unwrap();
+        let load_key = || SigningKey::load(&key_path).unwrap();
+        assert!(matches!(
+            Archive::open(af.path())
+                .unwrap()
+                .with_signing_key(load_key()),
+            Err(Error::SignatureMissing { .. })
+        ));
+
+        assert_eq!(af.sign(&key).unwrap(), 2);
+        assert_eq!(af.sign(&key).unwrap(), 0);
+        let signed = Archive::open(af.path())
+            .unwrap()
+            .with_signing_key(load_key())
+            .unwrap();
+        signed.validate().unwrap();
+
+        // New bands are signed as they're closed.
+        let tf = crate::test_fixtures::TreeFixture::new();
+        tf.create_file("new");
+        copy_tree(
+            &tf.live_tree(),
+            BackupWriter::begin(&signed).unwrap(),
+            &COPY_DEFAULT,
+        )
+        .unwrap();
+        let last = StoredTree::open_last(&signed).unwrap();
+        assert!(last.band().is_signed());
+        signed.validate().unwrap();
+
+        // The public key checks signatures, but can't sign new bands.
+        let checked = Archive::open(af.path())
+            .unwrap()
+            .with_public_key(key.public_key())
+            .unwrap();
+        checked.validate().unwrap();
+        assert!(matches!(
+            BackupWriter::begin(&checked),
+            Err(Error::SigningKeyNeeded)
+        ));
+
+        // A different key is refused.
+        assert!(matches!(
+            Archive::open(af.path())
+                .unwrap()
+                .with_signing_key(SigningKey::generate().unwrap()),
+            Err(Error::BadSignature { .. })
+        ));
+
+        // Rewriting history is noticed.
+        let head_path = af.path().join("b0001").join("BANDHEAD");
+        let head = fs::read_to_string(&head_path).unwrap();
+        fs::write(
+            &head_path,
+            head.replace("\"start_time\":", "\"start_time\":1"),
+        )
+        .unwrap();
+        assert!(matches!(
+            StoredTree::open_version(&signed, &BandId::new(&[1])),
+            Err(Error::BadSignature { .. })
+        ));
+        assert!(signed.validate().is_err());
+        // But the archive can still be read without the key.
+        StoredTree::open_version(&af, &BandId::new(&[1])).unwrap();
+    }
+
+    #[test]
+    fn removing_a_signed_band_is_noticed() {
+        let af = ScratchArchive::new();
+        af.store_two_versions();
+        let key = SigningKey::generate().unwrap();
+        af.sign(&key).unwrap();
+        let checked = Archive::open(af.path())
+            .unwrap()
+            .with_public_key(key.public_key())
+            .unwrap();
+        checked.validate().unwrap();
+
+        fs::remove_dir_all(af.path().join("b0000")).unwrap();
+        let err = StoredTree::open_last(&checked).err().unwrap();
+        assert!(
+            err.to_string()
+                .contains("signed as following version b0000, but follows no signed version"),
+            "{}",
+            err
+        );
+        assert!(checked.validate().is_err());
+    }
+
+    #[test]
+    fn signing_a_band_signs_the_next_again() {
+        let af = ScratchArchive::new();
+        af.store_two_versions();
+        let key = SigningKey::generate().unwrap();
+        assert_eq!(af.sign(&key).unwrap(), 2);
+        let key_path = af.path().with_extension("key");
+        key.save(&key_path).unwrap();
+        let signed = Archive::open(af.path())
+            .unwrap()
+            .with_signing_key(SigningKey::load(&key_path).unwrap())
+            .unwrap();
+        let tf = TreeFixture::new();
+        tf.create_file("new");
+        copy_tree(
+            &tf.live_tree(),
+            BackupWriter::begin(&signed).unwrap(),
+            &COPY_DEFAULT,
+        )
+        .unwrap();
+
+        // As if b0001 had been written without the key.
+        fs::remove_file(af.path().join("b0001").join("BANDSIG")).unwrap();
+        assert!(signed.validate().is_err());
+        assert_eq!(af.sign(&key).unwrap(), 1);
+        signed.validate().unwrap();
+    }
+
+    /// Make three layered backups: b0001 is layered on b0000, and b0002 on
+    /// b0001.
+    fn store_layered_versions(af: &ScratchArchive, key_path: Option<&Path>) {
+        let tf = TreeFixture::new();
+        tf.create_file("a");
+        tf.create_file("b");
+        let backup = || {
+            let mut options = BackupOptions::new(tf.path(), af.path()).layered_indexes(true);
+            if let Some(key_path) = key_path {
+                options = options.signing_key_file(key_path);
+            }
+            options.run().unwrap();
+        };
+        backup();
+        tf.create_file_with_contents("a", b"new a");
+        backup();
+        fs::remove_file(tf.path().join("b")).unwrap();
+        tf.create_file("c");
+        backup();
+    }
+
+    #[test]
+    fn squash_layered_versions() {
+        let af = ScratchArchive::new();
+        store_layered_versions(&af, None);
+        let last = BandId::new(&[2]);
+        let before: Vec<IndexEntry> = StoredTree::open_last(&af)
+            .unwrap()
+            .iter_entries()
+            .unwrap()
+            .collect();
+        assert_eq!(StoredTree::open_last(&af).unwrap().layers(), 2);
+
+        let stats = af.squash(&BandId::zero(), &last).unwrap();
+        assert_eq!(stats.bands_removed, 2);
+        assert!(stats.index_builder_stats.index_hunks > 0);
+        assert_eq!(af.list_bands().unwrap(), [last.clone()]);
+        let st = StoredTree::open_last(&af).unwrap();
+        assert_eq!(st.layers(), 0);
+        assert_eq!(st.band().index_base_band_id().unwrap(), None);
+        assert_eq!(st.iter_entries().unwrap().collect::<Vec<_>>(), before);
+        let (_, dirs) = list_dir(st.band().path()).unwrap();
+        assert_eq!(dirs, ["i"]);
+        af.validate().unwrap();
+
+        // Squashing a version that's already whole does nothing.
+        let stats = af.squash(&last, &last).unwrap();
+        assert_eq!(stats, SquashStats::default());
+    }
+
+    #[test]
+    fn squashed_bands_go_to_trash() {
+        let af = ScratchArchive::new();
+        store_layered_versions(&af, None);
+        let last = BandId::new(&[2]);
+        af.squash(&BandId::zero(), &last).unwrap();
+        assert_eq!(af.list_bands().unwrap(), [last.clone()]);
+        assert_eq!(
+            af.list_trash().unwrap(),
+            [BandId::zero(), BandId::new(&[1])]
+        );
+        af.validate().unwrap();
+
+        af.undelete(&BandId::zero()).unwrap();
+        assert_eq!(af.list_bands().unwrap(), [BandId::zero(), last.clone()]);
+        assert!(matches!(
+            af.undelete(&BandId::zero()),
+            Err(Error::BandNotInTrash { .. })
+        ));
+        assert_eq!(
+            StoredTree::open_version(&af, &BandId::zero())
+                .unwrap()
+                .iter_entries()
+                .unwrap()
+                .count(),
+            3
+        );
+
+        assert_eq!(af.empty_trash().unwrap(), 1);
+        assert!(af.list_trash().unwrap().is_empty());
+        assert!(matches!(
+            af.undelete(&BandId::new(&[1])),
+            Err(Error::BandNotInTrash { .. })
+        ));
+        assert_eq!(af.empty_trash().unwrap(), 0);
+
+        // Each named tree has its own trash.
+        let tree = Archive::open_tree(af.path(), Some("other")).unwrap();
+        assert!(tree.list_trash().unwrap().is_empty());
+    }
+
+    #[test]
+    fn squash_refuses_to_remove_a_base_in_use() {
+        let af = ScratchArchive::new();
+        store_layered_versions(&af, None);
+        let band = Band::create_with_head(&af, None, None, Some(&BandId::zero())).unwrap();
+        band.index_builder().finish().unwrap();
+        band.close().unwrap();
+        assert!(matches!(
+            af.squash(&BandId::zero(), &BandId::new(&[2])),
+            Err(Error::SquashBaseInUse { band_id, layered_band_id })
+                if band_id == BandId::zero() && layered_band_id == BandId::new(&[3])
+        ));
+        assert!(matches!(
+            af.squash(&BandId::new(&[1]), &BandId::zero()),
+            Err(Error::InvalidVersionRange { .. })
+        ));
+        assert_eq!(af.list_bands().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn squash_signed_versions() {
+        let af = ScratchArchive::new();
+        let key = SigningKey::generate().unwrap();
+        let key_path = af.path().with_extension("key");
+        key.save(&key_path).unwrap();
+        af.sign(&key).unwrap();
+        store_layered_versions(&af, Some(&key_path));
+        let last = BandId::new(&[2]);
+
+        assert!(matches!(
+            af.squash(&BandId::new(&[1]), &last),
+            Err(Error::SquashSignedBand { .. })
+        ));
+        let signed = Archive::open(af.path())
+            .unwrap()
+            .with_signing_key(SigningKey::load(&key_path).unwrap())
+            .unwrap();
+        assert_eq!(
+            signed
+                .squash(&BandId::new(&[1]), &last)
+                .unwrap()
+                .bands_removed,
+            1
+        );
+        assert_eq!(StoredTree::open_last(&signed).unwrap().layers(), 0);
+        signed.validate().unwrap();
+
+        // Bringing the band back signs the band after it again.
+        assert!(matches!(
+            af.undelete(&BandId::new(&[1])),
+            Err(Error::UndeleteSignedBand { .. })
+        ));
+        signed.undelete(&BandId::new(&[1])).unwrap();
+        assert_eq!(signed.list_bands().unwrap().len(), 3);
+        signed.validate().unwrap();
+    }
+
+    #[test]
+    fn squash_needs_deletion_secret() {
+        let af = ScratchArchive::new();
+        store_layered_versions(&af, None);
+        let secret = Secret::new("two keys");
+        af.set_deletion_guard(Some(&secret)).unwrap();
+        assert!(af.is_deletion_guarded().unwrap());
+        let last = BandId::new(&[2]);
+
+        assert!(matches!(
+            af.squash(&BandId::zero(), &last),
+            Err(Error::DeletionSecretRequired { .. })
+        ));
+        let wrong = Archive::open(af.path())
+            .unwrap()
+            .with_deletion_secret(Secret::new("one key"));
+        assert!(matches!(
+            wrong.squash(&BandId::zero(), &last),
+            Err(Error::WrongDeletionSecret { .. })
+        ));
+        // Nor can the guard be removed without the secret.
+        assert!(matches!(
+            wrong.set_deletion_guard(None),
+            Err(Error::WrongDeletionSecret { .. })
+        ));
+        assert_eq!(af.list_bands().unwrap().len(), 3);
+
+        let authorized = Archive::open(af.path())
+            .unwrap()
+            .with_deletion_secret(secret);
+        assert_eq!(
+            authorized
+                .squash(&BandId::zero(), &last)
+                .unwrap()
+                .bands_removed,
+            2
+        );
+        authorized.validate().unwrap();
+        authorized.set_deletion_guard(None).unwrap();
+        assert!(!af.is_deletion_guarded().unwrap());
+    }
+
+    /// Overwrite the first index hunk of the first band with its entries
+    /// reversed, and the last one repeated, as a buggy writer might.
+    fn misorder_first_index(af: &Archive) -> Vec<IndexEntry> {
+        use crate::compress::snappy::Snappy;
+        use crate::compress::Compression;
+
+        let band = Band::open(af, &BandId::zero()).unwrap();
+        let entries: Vec<IndexEntry> = band.iter_entries().unwrap().collect();
+        let mut misordered = entries.clone();
+        misordered.reverse();
+        misordered.push(misordered.last().unwrap().clone());
+        let json = serde_json::to_vec(&misordered).unwrap();
+        let mut hunk =
+            fs::File::create(band.index_dir_path.join("00000").join("000000000")).unwrap();
+        Snappy::compress_and_write(&json, &mut hunk).unwrap();
+        entries
+    }
+
+    #[test]
+    fn validate_finds_misordered_index() {
+        let af = ScratchArchive::new();
+        af.store_two_versions();
+        let entries = misorder_first_index(&af);
+        let problems = entries.len() as u64;
+
+        let stats = af.validate().unwrap();
+        assert_eq!(stats.index_order_errors, problems);
+        assert_eq!(stats.indexes_rewritten, 0);
+        let stats = af
+            .validate_band(&BandId::zero(), excludes::excludes_nothing())
+            .unwrap();
+        assert_eq!(stats.index_order_errors, problems);
+        assert_eq!(
+            af.validate_band(&BandId::new(&[1]), excludes::excludes_nothing())
+                .unwrap()
+                .index_order_errors,
+            0
+        );
+    }
+
+    #[test]
+    fn validate_fixes_index_order() {
+        let af = ScratchArchive::new();
+        af.store_two_versions();
+        let entries = misorder_first_index(&af);
+
+        let stats = ValidateOptions::new(af.path())
+            .fix_index_order(true)
+            .run()
+            .unwrap();
+        assert_eq!(stats.index_order_errors, entries.len() as u64);
+        assert_eq!(stats.indexes_rewritten, 1);
+
+        let band = Band::open(&af, &BandId::zero()).unwrap();
+        assert_eq!(band.iter_entries().unwrap().collect::<Vec<_>>(), entries);
+        let stats = af.validate().unwrap();
+        assert_eq!(stats.index_order_errors, 0);
+        assert_eq!(af.fix_index_order(None).unwrap(), 0);
+    }
 }
@@ -0,0 +1,172 @@
+// Conserve backup system.
+// Copyright 2020 Martin Pool.
+
+//! Async wrappers for use from a tokio runtime, enabled by the `async` feature.
+//!
+//! Conserve's storage code does blocking filesystem IO, so each operation here
+//! runs the synchronous implementation on tokio's blocking thread pool. This
+//! lets a server run many concurrent restores or validations from async tasks
+//! without dedicating a thread to each operation while it's waiting, and
+//! without blocking the async executor.
+//!
+//! There is not yet a transport abstraction for remote archives: when there is,
+//! it should grow async variants here too.
+
+use tokio::task;
+
+use crate::blockdir::Address;
+use crate::index::IndexEntryIter;
+use crate::stats::{BackupStats, CopyStats, Sizes, ValidateArchiveStats};
+use crate::*;
+
+/// Run a blocking closure on the blocking thread pool and wait for its result.
+async fn blocking<F, T>(f: F) -> T
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    match task::spawn_blocking(f).await {
+        Ok(t) => t,
+        // The closure panicked: propagate it to the caller.
+        Err(join_error) => std::panic::resume_unwind(join_error.into_panic()),
+    }
+}
+
+/// Async access to a block directory.
+#[derive(Clone, Debug)]
+pub struct AsyncBlockDir {
+    block_dir: BlockDir,
+}
+
+impl AsyncBlockDir {
+    pub fn new(block_dir: BlockDir) -> AsyncBlockDir {
+        AsyncBlockDir { block_dir }
+    }
+
+    /// True if the named block is present.
+    pub async fn contains(&self, hash: &str) -> Result<bool> {
+        let block_dir = self.block_dir.clone();
+        let hash = hash.to_owned();
+        blocking(move || block_dir.contains(&hash)).await
+    }
+
+    /// Read back the contents of the block at an address.
+    pub async fn get(&self, addr: &Address) -> Result<(Vec<u8>, Sizes)> {
+        let block_dir = self.block_dir.clone();
+        let addr = addr.clone();
+        blocking(move || block_dir.get(&addr)).await
+    }
+
+    /// Store one block of data, if it's not already present, and return its address.
+    pub async fn store_block(&self, block_data: Vec<u8>) -> Result<Address> {
+        let block_dir = self.block_dir.clone();
+        blocking(move || block_dir.store_block(&block_data)).await
+    }
+}
+
+/// Reads entries from a stored index in apath order, in batches.
+#[derive(Debug)]
+pub struct AsyncIndexEntries {
+    /// The underlying iterator, or None once it's exhausted.
+    iter: Option<IndexEntryIter>,
+}
+
+impl AsyncIndexEntries {
+    pub fn new(iter: IndexEntryIter) -> AsyncIndexEntries {
+        AsyncIndexEntries { iter: Some(iter) }
+    }
+
+    /// Read all the entries of a band's index.
+    pub fn for_band(band: &Band) -> Result<AsyncIndexEntries> {
+        Ok(AsyncIndexEntries::new(band.iter_entries()?))
+    }
+
+    /// Return up to `max` more entries, or an empty vec at the end of the index.
+    pub async fn next_batch(&mut self, max: usize) -> Vec<IndexEntry> {
+        let mut iter = match self.iter.take() {
+            Some(iter) => iter,
+            None => return Vec::new(),
+        };
+        let (batch, iter) = blocking(move || {
+            let batch: Vec<IndexEntry> = iter.by_ref().take(max).collect();
+            (batch, iter)
+        })
+        .await;
+        if batch.len() == max {
+            self.iter = Some(iter);
+        }
+        batch
+    }
+}
+
+/// Make a backup, as `BackupOptions::run`.
+pub async fn backup(options: BackupOptions) -> Result<BackupStats> {
+    blocking(move || options.run()).await
+}
+
+/// Restore a version, as `RestoreOptions::run`.
+pub async fn restore(options: RestoreOptions) -> Result<CopyStats> {
+    blocking(move || options.run()).await
+}
+
+/// Validate an archive, as `ValidateOptions::run`.
+pub async fn validate(options: ValidateOptions) -> Result<ValidateArchiveStats> {
+    blocking(move || options.run()).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::Future;
+
+    use super::*;
+    use crate::test_fixtures::{ScratchArchive, TreeFixture};
+
+    fn block_on<F: Future>(f: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(f)
+    }
+
+    #[test]
+    fn store_and_get_block() {
+        let af = ScratchArchive::new();
+        let block_dir = AsyncBlockDir::new(af.block_dir().clone());
+        block_on(async {
+            let addr = block_dir.store_block(b"hello".to_vec()).await.unwrap();
+            assert!(block_dir.contains(&addr.hash).await.unwrap());
+            assert_eq!(block_dir.get(&addr).await.unwrap().0, b"hello");
+        });
+    }
+
+    #[test]
+    fn backup_and_read_index_in_batches() {
+        let af = ScratchArchive::new();
+        let srcdir = TreeFixture::new();
+        srcdir.create_file("a");
+        srcdir.create_file("b");
+        srcdir.create_file("c");
+        block_on(async {
+            let stats = backup(BackupOptions::new(srcdir.path(), af.path()))
+                .await
+                .unwrap();
+            assert_eq!(stats.files, 3);
+            validate(ValidateOptions::new(af.path())).await.unwrap();
+        });
+
+        let band = af.last_complete_band().unwrap().unwrap();
+        let mut entries = AsyncIndexEntries::for_band(&band).unwrap();
+        let apaths: Vec<Vec<String>> = block_on(async {
+            let mut batches = Vec::new();
+            loop {
+                let batch = entries.next_batch(2).await;
+                if batch.is_empty() {
+                    break;
+                }
+                batches.push(batch.into_iter().map(|e| e.apath.into()).collect());
+            }
+            batches
+        });
+        assert_eq!(apaths, [vec!["/", "/a"], vec!["/b", "/c"]]);
+    }
+}
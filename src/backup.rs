@@ -4,13 +4,433 @@
 //! Make a backup by walking a source directory and copying the contents
 //! into an archive.
 
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+
 #[allow(unused_imports)]
-use snafu::ResultExt;
+use snafu::{ensure, ResultExt};
+use tracing::{info_span, warn, Span};
 
 use super::blockdir::StoreFiles;
 use super::*;
+use crate::content_index::{
+    hash_stored_content, read_hashes_by_apath, ContentIndexBuilder, HashingReader,
+};
 use crate::index::IndexEntryIter;
-use crate::stats::CopyStats;
+use crate::scan_cache::{ScanCache, ScanCacheWriter};
+use crate::stats::{BackupStats, CopyStats};
+
+/// The most bands below a band with a layered index, whose indexes must also
+/// be read to make its tree. After that, a whole index is written again.
+pub const MAX_INDEX_LAYERS: usize = 10;
+
+/// Options for making a backup, for programs that embed Conserve.
+///
+/// ```no_run
+/// let stats = conserve::BackupOptions::new("/home/me", "/backup/archive")
+///     .exclude("/.cache")
+///     .run()
+///     .unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct BackupOptions {
+    source: PathBuf,
+    archive: PathBuf,
+    tree_name: Option<String>,
+    excludes: Vec<String>,
+    filter: Option<Filter>,
+    print_filenames: bool,
+    small_file_size: Option<u64>,
+    tuning: Option<Tuning>,
+    paranoid: bool,
+    deterministic: bool,
+    layered_indexes: bool,
+    include_archives: bool,
+    exclude_if_present: Vec<String>,
+    signing_key_file: Option<PathBuf>,
+    snapshot: bool,
+    ntfs_metadata: bool,
+    statx_metadata: bool,
+    dereference: bool,
+    max_depth: Option<usize>,
+    warn_free_space: Option<u64>,
+    min_free_space: Option<u64>,
+    observer: Option<Arc<dyn Observer>>,
+    content_index: bool,
+    trust_dir_mtimes: bool,
+    stop: Option<Arc<AtomicBool>>,
+    scan_cache: Option<PathBuf>,
+}
+
+impl BackupOptions {
+    /// Back up `source` into the existing archive at `archive`.
+    pub fn new<P: AsRef<Path>, Q: AsRef<Path>>(source: P, archive: Q) -> BackupOptions {
+        BackupOptions {
+            source: source.as_ref().to_path_buf(),
+            archive: archive.as_ref().to_path_buf(),
+            tree_name: None,
+            excludes: Vec::new(),
+            filter: None,
+            print_filenames: false,
+            small_file_size: None,
+            tuning: None,
+            paranoid: false,
+            deterministic: false,
+            layered_indexes: false,
+            include_archives: false,
+            exclude_if_present: Vec::new(),
+            signing_key_file: None,
+            snapshot: false,
+            ntfs_metadata: false,
+            statx_metadata: false,
+            dereference: false,
+            max_depth: None,
+            warn_free_space: None,
+            min_free_space: None,
+            observer: None,
+            content_index: false,
+            trust_dir_mtimes: false,
+            stop: None,
+            scan_cache: None,
+        }
+    }
+
+    /// Exclude source files matching a glob pattern.
+    pub fn exclude(mut self, pattern: &str) -> BackupOptions {
+        self.excludes.push(pattern.to_owned());
+        self
+    }
+
+    /// Back up only directories and the entries matching a filter
+    /// expression.
+    pub fn filter(self, filter: Filter) -> BackupOptions {
+        BackupOptions {
+            filter: Some(filter),
+            ..self
+        }
+    }
+
+    /// Exclude source directories containing a file with this name.
+    pub fn exclude_if_present(mut self, name: &str) -> BackupOptions {
+        self.exclude_if_present.push(name.to_owned());
+        self
+    }
+
+    /// Write into a named tree within the archive.
+    pub fn tree(self, tree_name: &str) -> BackupOptions {
+        BackupOptions {
+            tree_name: Some(tree_name.to_owned()),
+            ..self
+        }
+    }
+
+    /// Print the name of each file as it's stored.
+    pub fn print_filenames(self, print_filenames: bool) -> BackupOptions {
+        BackupOptions {
+            print_filenames,
+            ..self
+        }
+    }
+
+    /// Combine files up to this many bytes into shared blocks, or store each
+    /// file separately if it's 0.
+    ///
+    /// By default this comes from the archive's tuning.
+    pub fn small_file_size(self, small_file_size: u64) -> BackupOptions {
+        BackupOptions {
+            small_file_size: Some(small_file_size),
+            ..self
+        }
+    }
+
+    /// Use these block and index thresholds rather than the ones configured
+    /// in the archive.
+    pub fn tuning(self, tuning: Tuning) -> BackupOptions {
+        BackupOptions {
+            tuning: Some(tuning),
+            ..self
+        }
+    }
+
+    /// Read back and check every block after it's written.
+    pub fn paranoid(self, paranoid: bool) -> BackupOptions {
+        BackupOptions { paranoid, ..self }
+    }
+
+    /// Write only the index entries that changed since the previous version,
+    /// layered over its tree.
+    pub fn layered_indexes(self, layered_indexes: bool) -> BackupOptions {
+        BackupOptions {
+            layered_indexes,
+            ..self
+        }
+    }
+
+    /// Write the version without timestamps, and without using the previous
+    /// version as a basis, so that backing up the same tree always gives the
+    /// same index.
+    pub fn deterministic(self, deterministic: bool) -> BackupOptions {
+        BackupOptions {
+            deterministic,
+            ..self
+        }
+    }
+
+    /// Include Conserve archives found in the source, rather than skipping
+    /// them.
+    pub fn include_archives(self, include_archives: bool) -> BackupOptions {
+        BackupOptions {
+            include_archives,
+            ..self
+        }
+    }
+
+    /// Sign the new version with the key in this file, after checking the
+    /// archive is signed with the same key.
+    pub fn signing_key_file<P: AsRef<Path>>(self, path: P) -> BackupOptions {
+        BackupOptions {
+            signing_key_file: Some(path.as_ref().to_path_buf()),
+            ..self
+        }
+    }
+
+    /// Read the source from a snapshot of its volume, so that it's
+    /// consistent. This is only supported on Windows, using Volume Shadow
+    /// Copy, and on macOS, using an APFS local snapshot.
+    pub fn snapshot(self, snapshot: bool) -> BackupOptions {
+        BackupOptions { snapshot, ..self }
+    }
+
+    /// Store the NTFS security descriptor and named streams of each file and
+    /// directory. This is only supported on Windows.
+    pub fn ntfs_metadata(self, ntfs_metadata: bool) -> BackupOptions {
+        BackupOptions {
+            ntfs_metadata,
+            ..self
+        }
+    }
+
+    /// Store the birth time, mount ID, and attributes of each entry, from
+    /// `statx`. This is only supported on Linux.
+    pub fn statx_metadata(self, statx_metadata: bool) -> BackupOptions {
+        BackupOptions {
+            statx_metadata,
+            ..self
+        }
+    }
+
+    /// Follow symlinks in the source, storing the files and directories they
+    /// point to. Symlinks that would loop back to a directory already visited
+    /// are skipped, and reported as problems.
+    pub fn dereference(self, dereference: bool) -> BackupOptions {
+        BackupOptions {
+            dereference,
+            ..self
+        }
+    }
+
+    /// Don't descend more than this many directories below the source, for
+    /// a quick snapshot of its top levels.
+    pub fn max_depth(self, max_depth: Option<usize>) -> BackupOptions {
+        BackupOptions { max_depth, ..self }
+    }
+
+    /// Warn if the filesystem holding the archive has less than this many
+    /// bytes free, before or during the backup.
+    pub fn warn_free_space(self, bytes: u64) -> BackupOptions {
+        BackupOptions {
+            warn_free_space: Some(bytes),
+            ..self
+        }
+    }
+
+    /// Don't start the backup if the filesystem holding the archive has less
+    /// than this many bytes free, and stop it if the space falls below this
+    /// while it's running.
+    ///
+    /// A stopped backup leaves an incomplete version holding the files
+    /// stored so far, so keep enough space to write its index.
+    pub fn min_free_space(self, bytes: u64) -> BackupOptions {
+        BackupOptions {
+            min_free_space: Some(bytes),
+            ..self
+        }
+    }
+
+    /// Tell `observer` about entries and blocks as they're stored.
+    pub fn observer(self, observer: Arc<dyn Observer>) -> BackupOptions {
+        BackupOptions {
+            observer: Some(observer),
+            ..self
+        }
+    }
+
+    /// Write a content index listing the hash of each file's whole content,
+    /// so that `find_content` can search this version.
+    pub fn content_index(self, content_index: bool) -> BackupOptions {
+        BackupOptions {
+            content_index,
+            ..self
+        }
+    }
+
+    /// Take the files and symlinks of directories whose mtime and names are
+    /// unchanged from the previous version, without reading their metadata.
+    ///
+    /// This is much faster for trees where files are only added, but misses
+    /// files whose contents are rewritten in place. See
+    /// `LiveTree::with_trusted_dir_mtimes`.
+    pub fn trust_dir_mtimes(self, trust_dir_mtimes: bool) -> BackupOptions {
+        BackupOptions {
+            trust_dir_mtimes,
+            ..self
+        }
+    }
+
+    /// Once `stop` is set, as by `interrupt::stop_on_signals`, stop before
+    /// the next entry, leaving an incomplete version holding what was
+    /// stored so far, and fail with `Error::Interrupted`.
+    pub fn stop(self, stop: Arc<AtomicBool>) -> BackupOptions {
+        BackupOptions {
+            stop: Some(stop),
+            ..self
+        }
+    }
+
+    /// Keep a local record of the new version's entries in this file, and
+    /// take unchanged files from it rather than from the previous version's
+    /// index, when it was written for that version.
+    ///
+    /// This is faster when reading the index from the archive is slow, as it
+    /// may be from remote storage.
+    pub fn scan_cache<P: AsRef<Path>>(self, path: P) -> BackupOptions {
+        BackupOptions {
+            scan_cache: Some(path.as_ref().to_path_buf()),
+            ..self
+        }
+    }
+
+    /// Make the backup, writing a new version into the archive.
+    pub fn run(&self) -> Result<BackupStats> {
+        let _span = backup_span(&self.source, &self.archive).entered();
+        let mut archive = Archive::open_tree(&self.archive, self.tree_name.as_deref())?
+            .with_deterministic(self.deterministic)
+            .with_layered_indexes(self.layered_indexes);
+        if let Some(path) = &self.signing_key_file {
+            archive = archive.with_signing_key(SigningKey::load(path)?)?;
+        }
+        let snapshot = if self.snapshot {
+            Some(Snapshot::create(&self.source)?)
+        } else {
+            None
+        };
+        let mut tuning = self.tuning.unwrap_or_else(|| archive.tuning());
+        if let Some(small_file_size) = self.small_file_size {
+            tuning.small_file_size = small_file_size;
+        }
+        tuning.validate()?;
+        let mut lt = self.live_tree_at(snapshot.as_ref().map_or(&self.source, |s| s.path()))?;
+        if self.trust_dir_mtimes {
+            lt = lt.with_trusted_dir_mtimes(&archive)?;
+        }
+        let warned = check_free_space(archive.path(), self.warn_free_space, self.min_free_space)?;
+        let bw = BackupWriter::begin_with_source_path(&archive, Some(&self.source))?
+            .with_tuning(tuning)
+            .with_paranoid(self.paranoid)
+            .with_content_index(self.content_index)
+            .with_free_space(
+                self.warn_free_space.filter(|_| !warned),
+                self.min_free_space,
+            );
+        let bw = match &self.observer {
+            Some(observer) => bw.with_observer(observer.clone()),
+            None => bw,
+        };
+        let bw = match &self.scan_cache {
+            Some(path) => bw.with_scan_cache(path)?,
+            None => bw,
+        };
+        copy_tree(
+            &lt,
+            bw,
+            &CopyOptions {
+                print_filenames: self.print_filenames,
+                filter: self.filter.clone(),
+                stop: self.stop.clone(),
+                ..CopyOptions::default()
+            },
+        )
+    }
+
+    /// Open the source tree, with these exclusions.
+    pub(crate) fn live_tree(&self) -> Result<LiveTree> {
+        self.live_tree_at(&self.source)
+    }
+
+    /// Open the source tree, with these exclusions, reading from `path`.
+    fn live_tree_at(&self, path: &Path) -> Result<LiveTree> {
+        Ok(LiveTree::open(path)?
+            .with_excludes(excludes::from_strings(&self.excludes)?)
+            .with_archives_included(self.include_archives)
+            .with_exclude_if_present(&self.exclude_if_present)
+            .with_ntfs_metadata(self.ntfs_metadata)
+            .with_statx_metadata(self.statx_metadata)
+            .with_dereference(self.dereference)
+            .with_max_depth(self.max_depth))
+    }
+}
+
+/// The span holding the events of a backup from `source` into `archive`.
+///
+/// Programs that drive a `BackupWriter` themselves can enter it so their
+/// events look the same as those from `BackupOptions::run`.
+pub fn backup_span(source: &Path, archive: &Path) -> Span {
+    info_span!("backup", source = %source.display(), archive = %archive.display())
+}
+
+/// Check the space free on the filesystem holding `path`, failing if it's
+/// less than `min_free_space` and warning if it's less than
+/// `warn_free_space`.
+///
+/// Returns true if it warned. Where the free space can't be found, nothing is
+/// checked.
+pub fn check_free_space(
+    path: &Path,
+    warn_free_space: Option<u64>,
+    min_free_space: Option<u64>,
+) -> Result<bool> {
+    if warn_free_space.is_none() && min_free_space.is_none() {
+        return Ok(false);
+    }
+    let available =
+        match crate::io::available_space(path).context(errors::CheckFreeSpace { path })? {
+            Some(available) => available,
+            None => return Ok(false),
+        };
+    if let Some(required) = min_free_space {
+        ensure!(
+            available >= required,
+            errors::LowFreeSpace {
+                path,
+                available,
+                required
+            }
+        );
+    }
+    match warn_free_space {
+        Some(warn_free_space) if available < warn_free_space => {
+            warn!(
+                "Only {} free on the filesystem holding {:?}",
+                bytes_to_human(available),
+                path
+            );
+            Ok(true)
+        }
+        _ => Ok(false),
+    }
+}
 
 /// Accepts files to write in the archive (in apath order.)
 pub struct BackupWriter {
@@ -21,6 +441,54 @@ pub struct BackupWriter {
     /// The index for the last stored band, used as hints for whether newly
     /// stored files have changed.
     basis_index: Option<IndexEntryIter>,
+
+    /// If true, the band's index is a layer over the basis, so entries that
+    /// are unchanged from the basis aren't written, and deleted entries are
+    /// marked.
+    layered: bool,
+
+    /// In a layered band, the next basis entry, read but not yet matched.
+    basis_next: Option<IndexEntry>,
+
+    /// If the basis entries were read from a scan cache, the inodes it
+    /// recorded for source files.
+    basis_inodes: Option<BTreeMap<Apath, u64>>,
+
+    /// If set, collects the entries of the new tree, to be written as a scan
+    /// cache when the band is finished.
+    scan_cache: Option<ScanCacheWriter>,
+
+    /// Sign the band with this key when it's finished.
+    signing_key: Option<Arc<SigningKey>>,
+
+    /// The signed band before this one, which its signature names.
+    previous_signed_band: Option<BandId>,
+
+    /// Where to check the free space before storing each file.
+    archive_path: PathBuf,
+
+    /// Warn once if the free space falls below this.
+    warn_free_space: Option<u64>,
+
+    /// Stop if the free space falls below this.
+    min_free_space: Option<u64>,
+
+    /// Told about entries and blocks as they're stored.
+    observer: Option<Arc<dyn Observer>>,
+
+    /// If set, collects the content hash of each file, to be written
+    /// beside the index.
+    content_index: Option<ContentIndexBuilder>,
+
+    /// The directory of the basis band, whose content index gives the
+    /// hashes of unchanged files.
+    basis_band_path: Option<PathBuf>,
+
+    /// The content hashes of files in the basis band, loaded when first
+    /// needed, and empty if it has no content index.
+    basis_hashes: Option<BTreeMap<Apath, String>>,
+
+    block_dir: BlockDir,
 }
 
 impl BackupWriter {
@@ -28,41 +496,354 @@ impl BackupWriter {
     ///
     /// This currently makes a new top-level band.
     pub fn begin(archive: &Archive) -> Result<BackupWriter> {
-        let basis_index = archive
-            .last_complete_band()?
-            .map(|b| b.iter_entries())
+        BackupWriter::begin_with_source_path(archive, None)
+    }
+
+    /// Create a new BackupWriter, recording in the band the path of the source
+    /// directory being backed up.
+    pub fn begin_with_source_path(
+        archive: &Archive,
+        source_path: Option<&Path>,
+    ) -> Result<BackupWriter> {
+        let source_path = source_path.map(|p| {
+            p.canonicalize()
+                .unwrap_or_else(|_| p.to_path_buf())
+                .to_string_lossy()
+                .into_owned()
+        });
+        archive.tuning().validate()?;
+        ensure!(
+            archive.public_key.is_none() || archive.signing_key.is_some(),
+            errors::SigningKeyNeeded
+        );
+        let basis_band = if archive.is_deterministic() {
+            None
+        } else {
+            archive.last_complete_band()?
+        };
+        let basis_band_id = basis_band.as_ref().map(|b| b.id().clone());
+        let basis_band_path = basis_band.as_ref().map(|b| b.path().to_owned());
+        let basis_tree = basis_band
+            .map(|b| StoredTree::new(archive, b))
             .transpose()?;
+        let layered = match &basis_tree {
+            Some(basis_tree) => archive.layered_indexes() && basis_tree.layers() < MAX_INDEX_LAYERS,
+            None => false,
+        };
+        let basis_index = basis_tree.as_ref().map(|t| t.iter_entries()).transpose()?;
         // Create the new band only after finding the basis band!
-        let band = Band::create(archive)?;
-        let index_builder = band.index_builder();
+        let band = Band::create_with_head(
+            archive,
+            basis_band_id.as_ref(),
+            source_path.as_deref(),
+            basis_band_id.as_ref().filter(|_| layered),
+        )?;
+        let previous_signed_band = match archive.signing_key {
+            Some(_) => archive.previous_signed_band(band.id())?,
+            None => None,
+        };
+        let index_builder = band.index_builder().with_format(archive.index_format());
         Ok(BackupWriter {
             band,
             index_builder,
             store_files: StoreFiles::new(archive.block_dir().clone()),
             basis_index,
+            layered,
+            basis_next: None,
+            basis_inodes: None,
+            scan_cache: None,
+            signing_key: archive.signing_key.clone(),
+            previous_signed_band,
+            archive_path: archive.path().to_owned(),
+            warn_free_space: None,
+            min_free_space: None,
+            observer: None,
+            content_index: None,
+            basis_band_path,
+            basis_hashes: None,
+            block_dir: archive.block_dir().clone(),
+        }
+        .with_tuning(archive.tuning()))
+    }
+
+    /// Use these block and index thresholds, rather than the ones configured
+    /// in the archive.
+    ///
+    /// The tuning should already be validated.
+    pub fn with_tuning(self, tuning: Tuning) -> BackupWriter {
+        BackupWriter {
+            index_builder: self
+                .index_builder
+                .with_hunk_entries(tuning.index_hunk_entries),
+            store_files: self.store_files.with_tuning(&tuning),
+            ..self
+        }
+    }
+
+    /// Combine files up to this many bytes into shared blocks, or store each
+    /// file separately if it's 0.
+    pub fn with_small_file_size(self, small_file_size: u64) -> BackupWriter {
+        BackupWriter {
+            store_files: self.store_files.with_small_file_size(small_file_size),
+            ..self
+        }
+    }
+
+    /// Read back and check each block after it's written, before any index
+    /// entries referring to it are written.
+    pub fn with_paranoid(self, paranoid: bool) -> BackupWriter {
+        BackupWriter {
+            store_files: self.store_files.with_paranoid(paranoid),
+            ..self
+        }
+    }
+
+    /// Check the free space before storing each file, warning once if it's
+    /// below `warn_free_space`, and stopping if it's below `min_free_space`.
+    pub fn with_free_space(
+        self,
+        warn_free_space: Option<u64>,
+        min_free_space: Option<u64>,
+    ) -> BackupWriter {
+        BackupWriter {
+            warn_free_space,
+            min_free_space,
+            ..self
+        }
+    }
+
+    /// Write a content index beside the band's index, listing the hash of
+    /// each file's whole content.
+    pub fn with_content_index(self, content_index: bool) -> BackupWriter {
+        BackupWriter {
+            content_index: if content_index {
+                Some(ContentIndexBuilder::default())
+            } else {
+                None
+            },
+            ..self
+        }
+    }
+
+    /// Take the basis entries from the scan cache at `path`, if it was written
+    /// for the basis band, and when the band is finished, replace the cache
+    /// with the entries of the new tree.
+    pub fn with_scan_cache(self, path: &Path) -> Result<BackupWriter> {
+        let cache = match self.band.get_info()?.basis_band_id {
+            Some(basis_band_id) => ScanCache::read(path, &self.archive_path, &basis_band_id)?,
+            None => None,
+        };
+        let (basis_index, basis_inodes) = match cache {
+            Some(cache) => (
+                Some(IndexEntryIter::from_entries(cache.entries)),
+                Some(cache.inodes),
+            ),
+            None => (self.basis_index, None),
+        };
+        Ok(BackupWriter {
+            scan_cache: Some(ScanCacheWriter::new(
+                path,
+                &self.archive_path,
+                self.band.id(),
+            )),
+            basis_index,
+            basis_inodes,
+            ..self
         })
     }
 
+    /// False if the scan cache recorded a different inode for this source
+    /// file, as when it's been replaced by another with the same size and
+    /// mtime.
+    fn same_inode<E: Entry>(&self, source_entry: &E) -> bool {
+        let cached = self
+            .basis_inodes
+            .as_ref()
+            .and_then(|inodes| inodes.get(source_entry.apath()));
+        match (cached, source_entry.inode()) {
+            (Some(cached), Some(inode)) => *cached == inode,
+            _ => true,
+        }
+    }
+
+    /// Remember an entry of the new tree for the scan cache, if there is one.
+    fn cache_entry(&mut self, index_entry: &IndexEntry) {
+        if let Some(scan_cache) = &mut self.scan_cache {
+            scan_cache.push(index_entry.clone());
+        }
+    }
+
+    /// Return the content hash and size of a file that's unchanged from the
+    /// basis, from the basis band's content index if it has one, or else by
+    /// reading its stored content.
+    fn unchanged_content_hash(&mut self, basis_entry: &IndexEntry) -> Result<(String, u64)> {
+        if self.basis_hashes.is_none() {
+            self.basis_hashes = Some(match &self.basis_band_path {
+                Some(path) => read_hashes_by_apath(path)?.unwrap_or_default(),
+                None => BTreeMap::new(),
+            });
+        }
+        match self.basis_hashes.as_ref().unwrap().get(&basis_entry.apath) {
+            Some(hash) => Ok((hash.clone(), basis_entry.size().unwrap_or(0))),
+            None => hash_stored_content(&self.block_dir, &basis_entry.addrs),
+        }
+    }
+
+    /// Tell `observer` about entries as they're copied and stored, blocks as
+    /// they're written, and the band when it's closed.
+    pub fn with_observer(self, observer: Arc<dyn Observer>) -> BackupWriter {
+        BackupWriter {
+            store_files: self.store_files.with_observer(Some(observer.clone())),
+            observer: Some(observer),
+            ..self
+        }
+    }
+
+    /// Turn errors from running out of space while writing into `DiskFull`,
+    /// so that the backup stops rather than failing on every remaining file.
+    fn check_disk_full(&self, error: Error) -> Error {
+        match &error {
+            Error::StoreBlock { source, .. } | Error::WriteIndex { source, .. }
+                if crate::io::is_disk_full(source) =>
+            {
+                Error::DiskFull {
+                    path: self.archive_path.clone(),
+                }
+            }
+            _ => error,
+        }
+    }
+
+    /// Return the basis entry for `apath`, if there is one.
+    ///
+    /// Basis entries before `apath` are skipped. In a layered band they're
+    /// no longer in the tree, so they're marked as deleted.
+    fn basis_entry(&mut self, apath: &Apath) -> Result<Option<IndexEntry>> {
+        let basis_index = match self.basis_index.as_mut() {
+            Some(basis_index) => basis_index,
+            None => return Ok(None),
+        };
+        if !self.layered {
+            return Ok(basis_index.advance_to(apath));
+        }
+        let mut deleted = Vec::new();
+        let found = loop {
+            match self.basis_next.take().or_else(|| basis_index.next()) {
+                Some(entry) if entry.apath < *apath => deleted.push(entry.apath),
+                Some(entry) if entry.apath == *apath => break Some(entry),
+                other => {
+                    self.basis_next = other;
+                    break None;
+                }
+            }
+        };
+        for apath in deleted {
+            self.push_deleted(apath)?;
+        }
+        Ok(found)
+    }
+
+    fn entry_started<E: Entry>(&self, source_entry: &E) {
+        if let Some(observer) = &self.observer {
+            observer.entry_started(source_entry.apath(), source_entry.kind());
+        }
+    }
+
+    fn push_deleted(&mut self, apath: Apath) -> Result<()> {
+        self.push_entry(IndexEntry::tombstone(apath))
+    }
+
+    /// Write an entry for a directory or symlink, unless this is a layered
+    /// band and it's unchanged from the basis.
+    fn push_metadata_entry(&mut self, index_entry: IndexEntry) -> Result<()> {
+        if self.layered && self.basis_entry(&index_entry.apath)?.as_ref() == Some(&index_entry) {
+            self.cache_entry(&index_entry);
+            Ok(())
+        } else {
+            self.push_entry(index_entry)
+        }
+    }
+
     fn push_entry(&mut self, index_entry: IndexEntry) -> Result<()> {
+        self.store_files.queue_entry(index_entry)?;
+        self.write_ready_entries()
+    }
+
+    /// Write entries whose content is stored into the index, in order.
+    fn write_ready_entries(&mut self) -> Result<()> {
         // TODO: Return or accumulate index sizes.
-        self.index_builder.push_entry(index_entry)?;
+        for index_entry in self.store_files.take_ready() {
+            if let Some(observer) = &self.observer {
+                observer.entry_stored(&index_entry);
+            }
+            if index_entry.kind != Kind::Deleted {
+                self.cache_entry(&index_entry);
+            }
+            self.index_builder.push_entry(index_entry)?;
+        }
         Ok(())
     }
 }
 
 impl tree::WriteTree for BackupWriter {
-    fn finish(self) -> Result<CopyStats> {
+    fn finish(mut self) -> Result<CopyStats> {
+        if self.layered {
+            // Everything left in the basis is gone from the source.
+            let rest: Vec<Apath> = self
+                .basis_next
+                .take()
+                .into_iter()
+                .chain(self.basis_index.take().into_iter().flatten())
+                .map(|entry| entry.apath)
+                .collect();
+            for apath in rest {
+                self.push_deleted(apath)?;
+            }
+        }
+        self.store_files.flush()?;
+        self.write_ready_entries()?;
+        let stats = self.store_files.take_stats();
+        let index_builder_stats = self.index_builder.finish()?;
+        if let Some(content_index) = self.content_index.take() {
+            content_index.write(self.band.path())?;
+        }
+        self.band.close_signed(
+            self.signing_key.as_deref(),
+            self.previous_signed_band.as_ref(),
+        )?;
+        if let Some(observer) = &self.observer {
+            observer.band_closed(self.band.id());
+        }
+        if let Some(scan_cache) = self.scan_cache.take() {
+            // The band is complete, so don't fail the backup: the next one
+            // will just read the index.
+            if let Err(e) = scan_cache.write() {
+                warn!("{}", ui::format_error(&e));
+            }
+        }
+        Ok(CopyStats {
+            index_builder_stats,
+            ..stats
+        })
+    }
+
+    /// Store the files queued so far and write the index, leaving the band
+    /// incomplete.
+    fn checkpoint(mut self) -> Result<CopyStats> {
+        self.store_files.flush()?;
+        self.write_ready_entries()?;
+        let stats = self.store_files.take_stats();
         let index_builder_stats = self.index_builder.finish()?;
-        self.band.close()?;
         Ok(CopyStats {
             index_builder_stats,
-            ..CopyStats::default()
+            ..stats
         })
     }
 
     fn copy_dir<E: Entry>(&mut self, source_entry: &E) -> Result<()> {
+        self.entry_started(source_entry);
         // TODO: Pass back index sizes
-        self.push_entry(IndexEntry::metadata_from(source_entry))
+        self.push_metadata_entry(IndexEntry::metadata_from(source_entry))
     }
 
     /// Copy in the contents of a file from another tree.
@@ -71,15 +852,14 @@ impl tree::WriteTree for BackupWriter {
         source_entry: &R::Entry,
         from_tree: &R,
     ) -> Result<CopyStats> {
+        self.entry_started(source_entry);
         let mut stats = CopyStats::default();
         let apath = source_entry.apath();
-        if let Some(basis_entry) = self
-            .basis_index
-            .as_mut()
-            .map(|bi| bi.advance_to(&apath))
-            .flatten()
-        {
-            if source_entry.is_unchanged_from(&basis_entry) {
+        if let Some(scan_cache) = &mut self.scan_cache {
+            scan_cache.source_inode(apath, source_entry.inode());
+        }
+        if let Some(mut basis_entry) = self.basis_entry(apath)? {
+            if source_entry.is_unchanged_from(&basis_entry) && self.same_inode(source_entry) {
                 // TODO: In verbose mode, say if the file is changed, unchanged,
                 // etc, but without duplicating the filenames.
                 //
@@ -89,7 +869,23 @@ impl tree::WriteTree for BackupWriter {
                 // with the archive invariants, which include that all the
                 // blocks referenced by the index, are actually present.
                 stats.unmodified_files += 1;
-                self.push_entry(basis_entry)?;
+                ui::increment_bytes_deduplicated(source_entry.size().unwrap_or(0));
+                // Permissions, streams, and attributes can change without
+                // changing the mtime, so always take them from the source.
+                if self.content_index.is_some() {
+                    let (hash, size) = self.unchanged_content_hash(&basis_entry)?;
+                    if let Some(content_index) = &mut self.content_index {
+                        content_index.push(apath.clone(), hash, size);
+                    }
+                }
+                let unchanged_entry = basis_entry.clone();
+                basis_entry.ntfs = source_entry.ntfs_metadata().cloned();
+                basis_entry.statx = source_entry.statx_metadata().cloned();
+                if self.layered && basis_entry == unchanged_entry {
+                    self.cache_entry(&basis_entry);
+                } else {
+                    self.push_entry(basis_entry)?;
+                }
                 return Ok(stats);
             } else {
                 stats.modified_files += 1;
@@ -97,22 +893,40 @@ impl tree::WriteTree for BackupWriter {
         } else {
             stats.new_files += 1;
         }
+        if check_free_space(
+            &self.archive_path,
+            self.warn_free_space,
+            self.min_free_space,
+        )? {
+            self.warn_free_space = None;
+        }
         let content = &mut from_tree.file_contents(&source_entry)?;
-        // TODO: Don't read the whole file into memory, but especially don't do that and
-        // then downcast it to Read.
-        let (addrs, file_stats) = self.store_files.store_file_content(&apath, content)?;
-        stats += file_stats;
-        self.push_entry(IndexEntry {
-            addrs,
-            ..IndexEntry::metadata_from(source_entry)
-        })?;
+        let index_entry = IndexEntry::metadata_from(source_entry);
+        // The entry is written to the index later, once its blocks are stored.
+        let queued = match &mut self.content_index {
+            Some(content_index) => {
+                let mut hashing = HashingReader::new(content);
+                let queued = self.store_files.queue_file(index_entry, &mut hashing);
+                let (hash, size) = hashing.finish();
+                if queued.is_ok() {
+                    content_index.push(apath.clone(), hash, size);
+                }
+                queued
+            }
+            None => self.store_files.queue_file(index_entry, content),
+        };
+        queued
+            .and_then(|()| self.write_ready_entries())
+            .map_err(|e| self.check_disk_full(e))?;
+        stats += self.store_files.take_stats();
         Ok(stats)
     }
 
     fn copy_symlink<E: Entry>(&mut self, source_entry: &E) -> Result<()> {
+        self.entry_started(source_entry);
         let target = source_entry.symlink_target().clone();
         assert!(target.is_some());
-        self.push_entry(IndexEntry::metadata_from(source_entry))
+        self.push_metadata_entry(IndexEntry::metadata_from(source_entry))
     }
 }
 
@@ -241,6 +1055,24 @@ mod tests {
         assert_eq!(stats.modified_files, 1);
     }
 
+    #[test]
+    pub fn record_source_path() {
+        let af = ScratchArchive::new();
+        let srcdir = TreeFixture::new();
+        let bw = BackupWriter::begin_with_source_path(&af, Some(srcdir.path())).unwrap();
+        copy_tree(&srcdir.live_tree(), bw, &COPY_DEFAULT).unwrap();
+        let info = af
+            .last_complete_band()
+            .unwrap()
+            .unwrap()
+            .get_info()
+            .unwrap();
+        assert_eq!(
+            info.source_path.unwrap(),
+            srcdir.path().canonicalize().unwrap().to_string_lossy()
+        );
+    }
+
     #[test]
     pub fn detect_minimal_mtime_change() {
         let af = ScratchArchive::new();
@@ -276,4 +1108,190 @@ mod tests {
         assert_eq!(stats.files, 2);
         assert_eq!(stats.unmodified_files, 1);
     }
+
+    #[test]
+    pub fn trust_dir_mtimes() {
+        let af = ScratchArchive::new();
+        let srcdir = TreeFixture::new();
+        srcdir.create_file("aaa");
+        srcdir.create_dir("sub");
+        srcdir.create_file("sub/bbb");
+        BackupOptions::new(srcdir.path(), af.path()).run().unwrap();
+
+        // Rewriting a file in place doesn't change its directory's mtime, so
+        // the change is missed.
+        srcdir.create_file_with_contents("aaa", b"rewritten content");
+        // Adding a file does, so that directory is read again.
+        srcdir.create_file("sub/ccc");
+        let stats = BackupOptions::new(srcdir.path(), af.path())
+            .trust_dir_mtimes(true)
+            .run()
+            .unwrap();
+        assert_eq!(stats.files, 3);
+        assert_eq!(stats.new_files, 1);
+        assert_eq!(stats.unmodified_files, 2);
+        assert_eq!(stats.modified_files, 0);
+
+        let stats = BackupOptions::new(srcdir.path(), af.path()).run().unwrap();
+        assert_eq!(stats.unmodified_files, 2);
+        assert_eq!(stats.modified_files, 1);
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    #[test]
+    pub fn stop_cleanly_when_free_space_is_low() {
+        let af = ScratchArchive::new();
+        let srcdir = TreeFixture::new();
+        srcdir.create_file("aaa");
+
+        // Nothing is written if there's too little space to start.
+        let err = BackupOptions::new(srcdir.path(), af.path())
+            .min_free_space(u64::MAX)
+            .run()
+            .unwrap_err();
+        assert!(matches!(err, Error::LowFreeSpace { required, .. } if required == u64::MAX));
+        assert!(af.list_bands().unwrap().is_empty());
+
+        // A backup that runs short of space stops before storing the next
+        // file, leaving an incomplete band with what was copied so far.
+        let bw = BackupWriter::begin(&af)
+            .unwrap()
+            .with_free_space(None, Some(u64::MAX));
+        let err = copy_tree(&srcdir.live_tree(), bw, &COPY_DEFAULT).unwrap_err();
+        assert!(matches!(err, Error::LowFreeSpace { .. }));
+        let band = Band::open(&af, &BandId::zero()).unwrap();
+        assert!(!band.is_closed().unwrap());
+        let entries: Vec<String> = StoredTree::open_incomplete_version(&af, &BandId::zero())
+            .unwrap()
+            .iter_entries()
+            .unwrap()
+            .map(|e| e.apath.to_string())
+            .collect();
+        assert_eq!(entries, ["/"]);
+
+        // Warning about low space doesn't stop the backup.
+        let stats = BackupOptions::new(srcdir.path(), af.path())
+            .warn_free_space(u64::MAX)
+            .run()
+            .unwrap();
+        assert_eq!(stats.new_files, 1);
+    }
+
+    #[test]
+    fn stop_when_interrupted() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Arc;
+
+        /// Asks to stop once a given entry starts.
+        struct StopAt(Apath, Arc<AtomicBool>);
+
+        impl Observer for StopAt {
+            fn entry_started(&self, apath: &Apath, _kind: Kind) {
+                if *apath == self.0 {
+                    self.1.store(true, Ordering::SeqCst);
+                }
+            }
+        }
+
+        let af = ScratchArchive::new();
+        let srcdir = TreeFixture::new();
+        for name in &["a", "b", "c"] {
+            srcdir.create_file(name);
+        }
+        let stop = Arc::new(AtomicBool::new(false));
+        let err = BackupOptions::new(srcdir.path(), af.path())
+            .observer(Arc::new(StopAt("/b".into(), stop.clone())))
+            .stop(stop)
+            .run()
+            .unwrap_err();
+        assert!(matches!(err, Error::Interrupted));
+
+        // The entry being copied is finished, and the index written, but the
+        // band is left incomplete.
+        let band = Band::open(&af, &BandId::zero()).unwrap();
+        assert!(!band.is_closed().unwrap());
+        let st = StoredTree::open_incomplete_version(&af, &BandId::zero()).unwrap();
+        let entries: Vec<String> = st
+            .iter_entries()
+            .unwrap()
+            .map(|e| e.apath.to_string())
+            .collect();
+        assert_eq!(entries, ["/", "/a", "/b"]);
+        st.validate().unwrap();
+
+        // The next backup is complete.
+        BackupOptions::new(srcdir.path(), af.path()).run().unwrap();
+        assert_eq!(
+            StoredTree::open_last(&af)
+                .unwrap()
+                .iter_entries()
+                .unwrap()
+                .count(),
+            4
+        );
+    }
+
+    #[test]
+    fn scan_cache_replaces_basis_index() {
+        let af = ScratchArchive::new();
+        let srcdir = TreeFixture::new();
+        for name in &["a", "b", "c"] {
+            srcdir.create_file(name);
+        }
+        let cache_dir = TreeFixture::new();
+        let cache_path = cache_dir.path().join("scan");
+        for &layered in &[false, true] {
+            let backup = || {
+                BackupOptions::new(srcdir.path(), af.path())
+                    .layered_indexes(layered)
+                    .scan_cache(&cache_path)
+                    .run()
+                    .unwrap()
+            };
+            backup();
+            // Without the basis index, unchanged files are found from the cache.
+            let basis = archive_last_band(&af);
+            std::fs::remove_dir_all(&basis.index_dir_path).unwrap();
+            std::fs::create_dir(&basis.index_dir_path).unwrap();
+            let stats = backup();
+            assert_eq!(stats.unmodified_files, 3, "layered={}", layered);
+            assert_eq!(stats.new_files, 0);
+        }
+        let st = StoredTree::open_last(&af).unwrap();
+        assert_eq!(st.iter_entries().unwrap().count(), 4);
+        st.validate().unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn scan_cache_notices_replaced_files() {
+        let af = ScratchArchive::new();
+        let srcdir = TreeFixture::new();
+        srcdir.create_file_with_contents("a", b"old");
+        let a = srcdir.path().join("a");
+        utime::set_file_times(&a, 1_500_000_000, 1_500_000_000).unwrap();
+        let cache_dir = TreeFixture::new();
+        let cache_path = cache_dir.path().join("scan");
+        let backup = || {
+            BackupOptions::new(srcdir.path(), af.path())
+                .scan_cache(&cache_path)
+                .run()
+                .unwrap()
+        };
+        backup();
+
+        // Another file with the same size and mtime is renamed over it.
+        srcdir.create_file_with_contents("a.new", b"new");
+        let a_new = srcdir.path().join("a.new");
+        utime::set_file_times(&a_new, 1_500_000_000, 1_500_000_000).unwrap();
+        std::fs::rename(&a_new, &a).unwrap();
+        let stats = backup();
+        assert_eq!(stats.modified_files, 1);
+        assert_eq!(stats.unmodified_files, 0);
+    }
+
+    fn archive_last_band(archive: &Archive) -> Band {
+        let band_id = archive.last_band_id().unwrap().unwrap();
+        Band::open(archive, &band_id).unwrap()
+    }
 }
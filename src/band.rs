@@ -16,23 +16,38 @@ use std::path::{Path, PathBuf};
 
 use chrono::{DateTime, TimeZone, Utc};
 use serde::{Deserialize, Serialize};
-use snafu::ResultExt;
+use snafu::{ensure, ResultExt};
+use tracing::error;
 
 use super::io::file_exists;
 use super::jsonio;
 use super::misc::remove_item;
+use super::stats::IndexBuilderStats;
 use super::*;
 
 static INDEX_DIR: &str = "i";
 static HEAD_FILENAME: &str = "BANDHEAD";
 static TAIL_FILENAME: &str = "BANDTAIL";
+pub(crate) static SIGNATURE_FILENAME: &str = "BANDSIG";
+
+/// While an index is rewritten, the new index, head and signature are
+/// written into this directory, and the old index is moved into the other
+/// before it's removed.
+static REPLACEMENT_DIR: &str = "replacement";
+static REPLACED_DIR: &str = "replaced";
 
 /// Band format-compatibility. Bands written out by this program, can only be
-/// read correctly by versions equal or later than the stated version.
+/// read correctly by versions equal or later than the stated version, and
+/// this program can read bands marked with this or any earlier version.
+///
+/// This is recorded in each band's head as the minimum version to read it,
+/// and is only raised when bands gain something that older versions would
+/// misread. Readers ignore fields they don't know in the head, tail, and
+/// index entries, so new optional metadata doesn't need a new version.
 pub const BAND_FORMAT_VERSION: &str = "0.6.3";
 
 fn band_version_requirement() -> semver::VersionReq {
-    semver::VersionReq::parse("<=0.6.3").unwrap()
+    semver::VersionReq::parse(&format!("<={}", BAND_FORMAT_VERSION)).unwrap()
 }
 
 fn band_version_supported(version: &str) -> bool {
@@ -47,6 +62,9 @@ pub struct Band {
     id: BandId,
     path_buf: PathBuf,
     pub index_dir_path: PathBuf,
+
+    /// If true, the head and tail are written with zero times.
+    deterministic: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -57,6 +75,24 @@ struct Head {
     /// Semver string for the minimum Conserve version to read this band
     /// correctly.
     band_format_version: Option<String>,
+
+    /// The band used as a basis for change detection when this band was written,
+    /// if any.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    basis_band_id: Option<String>,
+
+    /// The source directory from which this band was written, if known.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source_path: Option<String>,
+
+    /// If set, this band's index holds only the entries that changed from
+    /// the tree of this earlier band, which must be read to get the whole
+    /// tree.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    index_base_band_id: Option<String>,
 }
 
 /// Format of the on-disk tail file.
@@ -75,6 +111,15 @@ pub struct Info {
 
     /// Time this band was completed, if it is complete.
     pub end_time: Option<DateTime<Utc>>,
+
+    /// The earlier band this band was based on, if it was recorded.
+    pub basis_band_id: Option<BandId>,
+
+    /// The source directory this band was written from, if it was recorded.
+    pub source_path: Option<String>,
+
+    /// If the band's index is a layer over an earlier band's tree, that band.
+    pub index_base_band_id: Option<BandId>,
 }
 
 // TODO: Maybe merge this with StoredTree? The distinction seems small.
@@ -83,32 +128,148 @@ impl Band {
     ///
     /// The Band gets the next id after those that already exist.
     pub fn create(archive: &Archive) -> Result<Band> {
+        Band::create_with_metadata(archive, None, None)
+    }
+
+    /// Make a new band, recording in its head the earlier band it's based on
+    /// and the source directory it's written from.
+    pub fn create_with_metadata(
+        archive: &Archive,
+        basis_band_id: Option<&BandId>,
+        source_path: Option<&str>,
+    ) -> Result<Band> {
+        Band::create_with_head(archive, basis_band_id, source_path, None)
+    }
+
+    /// Make a new band, and if `index_base_band_id` is given, record that its
+    /// index is a layer over that band's tree.
+    pub(crate) fn create_with_head(
+        archive: &Archive,
+        basis_band_id: Option<&BandId>,
+        source_path: Option<&str>,
+        index_base_band_id: Option<&BandId>,
+    ) -> Result<Band> {
         let new_band_id = archive
             .last_band_id()?
             .map_or_else(BandId::zero, |b| b.next_sibling());
-        let archive_dir = archive.path();
-        let new = Band::new(archive_dir, new_band_id);
+        let bands_path = archive.bands_path();
+        if archive.tree_name().is_some() {
+            std::fs::create_dir_all(&bands_path).context(errors::CreateBand)?;
+        }
+        let new = Band {
+            deterministic: archive.is_deterministic(),
+            ..Band::new(&bands_path, new_band_id)
+        };
         fs::create_dir(&new.path_buf).context(errors::CreateBand)?;
         fs::create_dir(&new.index_dir_path).context(errors::CreateBand)?;
         let head = Head {
-            start_time: Utc::now().timestamp(),
+            start_time: new.now(),
             band_format_version: Some(BAND_FORMAT_VERSION.to_owned()),
+            basis_band_id: basis_band_id.map(BandId::to_string),
+            source_path: source_path.map(str::to_owned),
+            index_base_band_id: index_base_band_id.map(BandId::to_string),
         };
-        jsonio::write_json_metadata_file(&new.head_path(), &head)?;
+        if let Err(e) = jsonio::write_json_metadata_file(&new.head_path(), &head) {
+            // A band without a head can't be opened, so don't leave one
+            // behind.
+            let _ = fs::remove_dir_all(&new.path_buf);
+            return Err(e);
+        }
         Ok(new)
     }
 
     /// Mark this band closed: no more blocks should be written after this.
     pub fn close(&self) -> Result<()> {
+        self.close_signed(None, None)
+    }
+
+    /// Close the band, first signing it if there's a key, so that it never
+    /// appears complete without its signature.
+    ///
+    /// `previous` is the signed band before this one.
+    pub(crate) fn close_signed(
+        &self,
+        key: Option<&SigningKey>,
+        previous: Option<&BandId>,
+    ) -> Result<()> {
         let tail = Tail {
-            end_time: Utc::now().timestamp(),
+            end_time: self.now(),
         };
+        if let Some(key) = key {
+            // Sign the tail exactly as it'll be written.
+            let path = self.tail_path();
+            let mut tail_json =
+                serde_json::to_string(&tail).context(errors::SerializeJson { path })?;
+            tail_json.push('\n');
+            key.sign(
+                self.path(),
+                &self.signed_files(false)?,
+                &[(TAIL_FILENAME, tail_json.as_bytes())],
+                previous.map(BandId::to_string).as_deref(),
+                &self.signature_path(),
+            )?;
+        }
         jsonio::write_json_metadata_file(&self.tail_path(), &tail)
     }
 
+    /// Sign a band that's already complete, following the signed band
+    /// `previous`.
+    pub(crate) fn sign(&self, key: &SigningKey, previous: Option<&BandId>) -> Result<()> {
+        key.sign(
+            self.path(),
+            &self.signed_files(true)?,
+            &[],
+            previous.map(BandId::to_string).as_deref(),
+            &self.signature_path(),
+        )
+    }
+
+    /// True if the band has a signature, without checking it.
+    pub fn is_signed(&self) -> bool {
+        self.signature_path().is_file()
+    }
+
+    /// Check that the band is complete, and its signature matches its head,
+    /// tail and index, and names `previous` as the signed band before it.
+    pub fn verify_signature(&self, key: &PublicKey, previous: Option<&BandId>) -> Result<()> {
+        if !self.is_closed()? {
+            return Err(Error::BandIncomplete {
+                band_id: self.id.clone(),
+            });
+        }
+        key.verify(
+            self.path(),
+            &self.signed_files(true)?,
+            previous.map(BandId::to_string).as_deref(),
+            &self.signature_path(),
+        )
+    }
+
+    /// Names of the files covered by a signature, relative to the band
+    /// directory.
+    fn signed_files(&self, include_tail: bool) -> Result<Vec<String>> {
+        let mut names = vec![HEAD_FILENAME.to_owned()];
+        if include_tail {
+            names.push(TAIL_FILENAME.to_owned());
+        }
+        names.extend(index_files(&self.path_buf)?);
+        Ok(names)
+    }
+
     /// Open the band with the given id.
+    ///
+    /// If rewriting its index was interrupted after the new index was
+    /// complete, it's moved into place first.
     pub fn open(archive: &Archive, band_id: &BandId) -> Result<Band> {
-        let new = Band::new(archive.path(), band_id.clone());
+        let new = Band::new(&archive.bands_path(), band_id.clone());
+        if new
+            .path_buf
+            .join(REPLACEMENT_DIR)
+            .join(HEAD_FILENAME)
+            .is_file()
+        {
+            new.finish_replacing_index()?;
+        }
         let head = new.read_head()?;
         if let Some(version) = head.band_format_version {
             if !band_version_supported(&version) {
@@ -128,8 +289,8 @@ impl Band {
     ///
     /// Instead of creating the in-memory object you typically should either
     /// `create` or `open` the band corresponding to in-archive directory.
-    fn new(archive_dir: &Path, id: BandId) -> Band {
-        let mut path_buf = archive_dir.to_path_buf();
+    fn new(bands_path: &Path, id: BandId) -> Band {
+        let mut path_buf = bands_path.to_path_buf();
         path_buf.push(id.to_string());
         let mut index_dir_path = path_buf.clone();
         index_dir_path.push(INDEX_DIR);
@@ -137,6 +298,16 @@ impl Band {
             id,
             path_buf,
             index_dir_path,
+            deterministic: false,
+        }
+    }
+
+    /// The time to record in the head or tail, in seconds since the epoch.
+    fn now(&self) -> i64 {
+        if self.deterministic {
+            0
+        } else {
+            Utc::now().timestamp()
         }
     }
 
@@ -161,6 +332,10 @@ impl Band {
         self.path_buf.join(TAIL_FILENAME)
     }
 
+    fn signature_path(&self) -> PathBuf {
+        self.path_buf.join(SIGNATURE_FILENAME)
+    }
+
     pub fn index_builder(&self) -> IndexBuilder {
         IndexBuilder::new(&self.index_dir_path)
     }
@@ -172,7 +347,7 @@ impl Band {
 
     /// Return an iterator through entries in this band.
     pub fn iter_entries(&self) -> Result<index::IndexEntryIter> {
-        index::IndexEntryIter::open(&self.index_dir_path)
+        self.index().iter()
     }
 
     fn read_head(&self) -> Result<Head> {
@@ -192,42 +367,279 @@ impl Band {
         } else {
             None
         };
+        let basis_band_id = match head.basis_band_id {
+            Some(b) => Some(BandId::from_string(&b)?),
+            None => None,
+        };
         Ok(Info {
             id: self.id.clone(),
             is_closed,
             start_time: Utc.timestamp(head.start_time, 0),
             end_time,
+            basis_band_id,
+            source_path: head.source_path,
+            index_base_band_id: self.index_base_band_id()?,
         })
     }
 
+    /// If this band's index is a layer over an earlier band's tree, return
+    /// that band.
+    pub fn index_base_band_id(&self) -> Result<Option<BandId>> {
+        match self.read_head()?.index_base_band_id {
+            Some(b) => {
+                let base = BandId::from_string(&b)?;
+                ensure!(
+                    base < self.id,
+                    errors::InvalidIndexBase {
+                        band_id: self.id.clone(),
+                        base_band_id: base,
+                    }
+                );
+                Ok(Some(base))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Replace this band's index by a whole index of `tree`, which should be
+    /// this band's own tree, so that it no longer depends on any band below
+    /// it.
+    ///
+    /// The new index is written alongside the old one and swapped in, and
+    /// then the band is signed again if there's a key, following the signed
+    /// band `previous`.
+    pub(crate) fn rewrite_whole_index(
+        &self,
+        tree: &StoredTree,
+        format: IndexFormat,
+        hunk_entries: usize,
+        key: Option<&SigningKey>,
+        previous: Option<&BandId>,
+    ) -> Result<IndexBuilderStats> {
+        let head = Head {
+            index_base_band_id: None,
+            ..self.read_head()?
+        };
+        self.replace_index(
+            tree.iter_entries()?,
+            head,
+            format,
+            hunk_entries,
+            key,
+            previous,
+        )
+    }
+
+    /// Check that the apaths in this band's own index are in order with no
+    /// duplicates, reporting each problem, and return the number found.
+    pub fn check_index_order(&self) -> Result<u64> {
+        let mut check_order = apath::CheckOrder::new();
+        let mut problems = 0;
+        for entry in self.iter_entries()? {
+            if let Err(last_apath) = check_order.try_check(&entry.apath) {
+                if last_apath == entry.apath {
+                    error!(
+                        "Duplicate apath {:?} in index of band {}",
+                        entry.apath, self.id
+                    );
+                } else {
+                    error!(
+                        "Apath {:?} out of order after {:?} in index of band {}",
+                        entry.apath, last_apath, self.id
+                    );
+                }
+                problems += 1;
+            }
+        }
+        Ok(problems)
+    }
+
+    /// Rewrite this band's own index in apath order, keeping only the first
+    /// entry for any duplicated apath.
+    ///
+    /// A layered index stays layered over the same base. If there's a key,
+    /// the band is signed again, following the signed band `previous`.
+    pub(crate) fn rewrite_sorted_index(
+        &self,
+        format: IndexFormat,
+        hunk_entries: usize,
+        key: Option<&SigningKey>,
+        previous: Option<&BandId>,
+    ) -> Result<IndexBuilderStats> {
+        let mut entries: Vec<IndexEntry> = self.iter_entries()?.collect();
+        // The sort is stable, so the first of any duplicates is kept.
+        entries.sort_by(|a, b| a.apath.cmp(&b.apath));
+        entries.dedup_by(|a, b| a.apath == b.apath);
+        self.replace_index(
+            entries,
+            self.read_head()?,
+            format,
+            hunk_entries,
+            key,
+            previous,
+        )
+    }
+
+    /// Write `entries` as a new index, with `head`, and then swap them in.
+    ///
+    /// The new index, head and signature are written into a replacement
+    /// directory, the head last, so that once it's there the replacement is
+    /// complete. If this is interrupted before then, the old index is
+    /// untouched and the replacement is discarded when the index is next
+    /// rewritten; after then, the replacement is moved into place when the
+    /// band is next opened.
+    fn replace_index<I: IntoIterator<Item = IndexEntry>>(
+        &self,
+        entries: I,
+        head: Head,
+        format: IndexFormat,
+        hunk_entries: usize,
+        key: Option<&SigningKey>,
+        previous: Option<&BandId>,
+    ) -> Result<IndexBuilderStats> {
+        let stats =
+            self.write_replacement_index(entries, &head, format, hunk_entries, key, previous)?;
+        self.finish_replacing_index()?;
+        Ok(stats)
+    }
+
+    fn write_replacement_index<I: IntoIterator<Item = IndexEntry>>(
+        &self,
+        entries: I,
+        head: &Head,
+        format: IndexFormat,
+        hunk_entries: usize,
+        key: Option<&SigningKey>,
+        previous: Option<&BandId>,
+    ) -> Result<IndexBuilderStats> {
+        let replacement = self.path_buf.join(REPLACEMENT_DIR);
+        let replaced = self.path_buf.join(REPLACED_DIR);
+        for path in &[&replacement, &replaced] {
+            if path.exists() {
+                fs::remove_dir_all(path).context(errors::WriteBandFile { path: *path })?;
+            }
+        }
+        let index_dir = replacement.join(INDEX_DIR);
+        fs::create_dir_all(&index_dir).context(errors::WriteBandFile { path: &index_dir })?;
+        let mut builder = IndexBuilder::new(&index_dir)
+            .with_format(format)
+            .with_hunk_entries(hunk_entries);
+        for entry in entries {
+            builder.push_entry(entry)?;
+        }
+        let stats = builder.finish()?;
+        let head_path = replacement.join(HEAD_FILENAME);
+        if let Some(key) = key {
+            // Sign the head exactly as it'll be written, and the tail as it
+            // is.
+            let mut head_json =
+                serde_json::to_string(head).context(errors::SerializeJson { path: &head_path })?;
+            head_json.push('\n');
+            let tail_path = self.tail_path();
+            let tail = fs::read(&tail_path).context(errors::ReadMetadata { path: &tail_path })?;
+            key.sign(
+                &replacement,
+                &index_files(&replacement)?,
+                &[
+                    (HEAD_FILENAME, head_json.as_bytes()),
+                    (TAIL_FILENAME, &tail),
+                ],
+                previous.map(BandId::to_string).as_deref(),
+                &replacement.join(SIGNATURE_FILENAME),
+            )?;
+        }
+        jsonio::write_json_metadata_file(&head_path, head)?;
+        Ok(stats)
+    }
+
+    /// Move a complete replacement index, head and signature into place.
+    ///
+    /// Each step is skipped if it's already done, so this can finish a
+    /// replacement that was interrupted part way through.
+    fn finish_replacing_index(&self) -> Result<()> {
+        let replacement = self.path_buf.join(REPLACEMENT_DIR);
+        let replaced = self.path_buf.join(REPLACED_DIR);
+        let rename = |from: &Path, to: &Path| {
+            fs::rename(from, to).context(errors::WriteBandFile { path: to })
+        };
+        let new_index_dir = replacement.join(INDEX_DIR);
+        if new_index_dir.exists() {
+            if self.index_dir_path.exists() {
+                fs::create_dir_all(&replaced).context(errors::WriteBandFile { path: &replaced })?;
+                rename(&self.index_dir_path, &replaced.join(INDEX_DIR))?;
+            }
+            rename(&new_index_dir, &self.index_dir_path)?;
+        }
+        let new_signature = replacement.join(SIGNATURE_FILENAME);
+        if new_signature.exists() {
+            rename(&new_signature, &self.signature_path())?;
+        }
+        // The head goes last, since it marks the replacement as complete.
+        rename(&replacement.join(HEAD_FILENAME), &self.head_path())?;
+        for path in &[&replacement, &replaced] {
+            if path.exists() {
+                fs::remove_dir_all(path).context(errors::WriteBandFile { path: *path })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Scan the index and summarize its contents.
+    pub fn index_summary(&self) -> Result<index::IndexSummary> {
+        let mut summary = index::IndexSummary::default();
+        for entry in self.iter_entries()? {
+            summary.add(&entry);
+        }
+        summary.index_hunks = self.index().count_hunks()?.into();
+        Ok(summary)
+    }
+
     pub fn validate(&self) -> Result<()> {
         let (mut files, dirs) =
             list_dir(self.path()).context(errors::ReadMetadata { path: self.path() })?;
         if !files.contains(&HEAD_FILENAME.to_string()) {
-            ui::problem(&format!("No band head file in {:?}", self.path()));
+            error!("No band head file in {:?}", self.path());
         }
         remove_item(&mut files, &HEAD_FILENAME);
         remove_item(&mut files, &TAIL_FILENAME);
+        remove_item(&mut files, &SIGNATURE_FILENAME);
+        remove_item(&mut files, &crate::content_index::CONTENT_INDEX_FILENAME);
         if !files.is_empty() {
-            ui::problem(&format!(
-                "Unexpected files in {:?}: {:?}",
-                self.path(),
-                files
-            ));
+            error!("Unexpected files in {:?}: {:?}", self.path(), files);
         }
 
         if dirs != [INDEX_DIR.to_string()] {
-            ui::problem(&format!(
-                "Incongruous directories in {:?}: {:?}",
-                self.path(),
-                dirs
-            ));
+            error!("Incongruous directories in {:?}: {:?}", self.path(), dirs);
         }
 
         Ok(())
     }
 }
 
+/// Names of the files in the index directory under `band_dir`, relative to
+/// `band_dir`.
+fn index_files(band_dir: &Path) -> Result<Vec<String>> {
+    let index_dir = band_dir.join(INDEX_DIR);
+    let mut names = Vec::new();
+    for entry in walkdir::WalkDir::new(&index_dir) {
+        let entry = entry.map_err(|e| Error::ReadIndex {
+            path: index_dir.clone(),
+            source: e.into(),
+        })?;
+        if entry.file_type().is_file() {
+            let relative: Vec<String> = entry
+                .path()
+                .strip_prefix(band_dir)
+                .unwrap()
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy().into_owned())
+                .collect();
+            names.push(relative.join("/"));
+        }
+    }
+    Ok(names)
+}
+
 #[cfg(test)]
 mod tests {
     use std::fs;
@@ -268,6 +680,130 @@ mod tests {
         // Test should have taken (much) less than 5s between starting and finishing
         // the band.  (It might fail if you set a breakpoint right there.)
         assert!(dur < Duration::seconds(5));
+        assert_eq!(info.basis_band_id, None);
+    }
+
+    #[test]
+    fn record_basis_band() {
+        let af = ScratchArchive::new();
+        af.store_two_versions();
+        let band = Band::open(&af, &BandId::new(&[1])).unwrap();
+        let info = band.get_info().unwrap();
+        assert_eq!(info.basis_band_id, Some(BandId::zero()));
+
+        let head: serde_json::Value = jsonio::read_json_metadata_file(&band.head_path()).unwrap();
+        assert_eq!(head["basis_band_id"], "b0000");
+    }
+
+    #[test]
+    fn deterministic_times() {
+        let af = ScratchArchive::new();
+        let archive = Archive::open(af.path()).unwrap().with_deterministic(true);
+        let band = Band::create(&archive).unwrap();
+        band.close().unwrap();
+        let info = band.get_info().unwrap();
+        assert_eq!(info.start_time.timestamp(), 0);
+        assert_eq!(info.end_time.unwrap().timestamp(), 0);
+    }
+
+    #[test]
+    fn index_summary() {
+        let af = ScratchArchive::new();
+        af.store_two_versions();
+        let band = Band::open(&af, &BandId::new(&[1])).unwrap();
+        let summary = band.index_summary().unwrap();
+        assert_eq!(summary.files, 3);
+        assert_eq!(summary.dirs, 2);
+        assert_eq!(summary.file_bytes, 3 * 8);
+        assert_eq!(summary.index_hunks, 1);
+        assert_eq!(summary.last_apath, Some(Apath::from("/subdir/subfile")));
+        if SYMLINKS_SUPPORTED {
+            assert_eq!(summary.symlinks, 1);
+            assert_eq!(summary.entries, 6);
+        } else {
+            assert_eq!(summary.entries, 5);
+        }
+    }
+
+    /// Write a replacement index of `band` without its first entry, but
+    /// don't move it into place.
+    fn write_shorter_replacement(af: &ScratchArchive, band: &Band) {
+        let entries: Vec<IndexEntry> = band.iter_entries().unwrap().skip(1).collect();
+        band.write_replacement_index(
+            entries,
+            &band.read_head().unwrap(),
+            af.index_format(),
+            af.tuning().index_hunk_entries,
+            None,
+            None,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn interrupted_index_replacement_is_finished_on_open() {
+        let af = ScratchArchive::new();
+        af.store_two_versions();
+        let band_id = BandId::new(&[1]);
+        let band = Band::open(&af, &band_id).unwrap();
+        let entries = band.index_summary().unwrap().entries;
+        write_shorter_replacement(&af, &band);
+        // As if interrupted after moving the old index aside.
+        fs::create_dir(band.path().join(REPLACED_DIR)).unwrap();
+        fs::rename(
+            band.path().join(INDEX_DIR),
+            band.path().join(REPLACED_DIR).join(INDEX_DIR),
+        )
+        .unwrap();
+
+        let band = Band::open(&af, &band_id).unwrap();
+        assert_eq!(band.index_summary().unwrap().entries, entries - 1);
+        let (_files, dirs) = list_dir(band.path()).unwrap();
+        assert_eq!(dirs, [INDEX_DIR]);
+    }
+
+    #[test]
+    fn incomplete_index_replacement_is_discarded() {
+        let af = ScratchArchive::new();
+        af.store_two_versions();
+        let band_id = BandId::new(&[1]);
+        let band = Band::open(&af, &band_id).unwrap();
+        let entries = band.index_summary().unwrap().entries;
+        write_shorter_replacement(&af, &band);
+        // As if interrupted before the head was written.
+        fs::remove_file(band.path().join(REPLACEMENT_DIR).join(HEAD_FILENAME)).unwrap();
+
+        let band = Band::open(&af, &band_id).unwrap();
+        assert_eq!(band.index_summary().unwrap().entries, entries);
+        band.rewrite_sorted_index(
+            af.index_format(),
+            af.tuning().index_hunk_entries,
+            None,
+            None,
+        )
+        .unwrap();
+        assert_eq!(band.index_summary().unwrap().entries, entries);
+        let (_files, dirs) = list_dir(band.path()).unwrap();
+        assert_eq!(dirs, [INDEX_DIR]);
+    }
+
+    #[test]
+    fn read_head_with_unknown_fields() {
+        let af = ScratchArchive::new();
+        fs::create_dir(af.path().join("b0000")).unwrap();
+        let head = json!({
+            "start_time": 0,
+            "band_format_version": "0.6.0",
+            "added_later": {"a": 1},
+        });
+        fs::write(
+            af.path().join("b0000").join(HEAD_FILENAME),
+            head.to_string(),
+        )
+        .unwrap();
+
+        let band = Band::open(&af, &BandId::zero()).unwrap();
+        assert_eq!(band.get_info().unwrap().start_time.timestamp(), 0);
     }
 
     #[test]
@@ -3,7 +3,9 @@
 
 //! Command-line entry point for Conserve backups.
 
-use std::path::Path;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
 
 use clap::{crate_authors, App, AppSettings, Arg, ArgMatches, SubCommand};
 
@@ -11,26 +13,123 @@ use conserve::*;
 
 fn main() -> conserve::Result<()> {
     let matches = make_clap().get_matches();
-    ui::enable_progress(true);
-
     let (n, sm) = rollup_subcommands(&matches);
+    let log_file = match sm.value_of("log-file") {
+        Some(path) => {
+            match conserve::log_file::LogFileLayer::open(
+                Path::new(path),
+                conserve::log_file::DEFAULT_MAX_LOG_SIZE,
+            ) {
+                Ok(layer) => Some(layer),
+                Err(e) => {
+                    // The subscriber isn't installed yet, so show this directly.
+                    ui::problem(&ui::format_error(&e));
+                    std::process::exit(1);
+                }
+            }
+        }
+        None => None,
+    };
+    ui::install_tracing_subscriber(log_file);
+    tracing::info!(
+        target: ui::LOG_ONLY_TARGET,
+        "Start conserve {} with arguments {:?}",
+        conserve::version(),
+        std::env::args()
+            .skip(1)
+            .map(|arg| redact_url(&arg))
+            .collect::<Vec<_>>()
+    );
+
+    let verbosity = if sm.is_present("quiet") {
+        ui::Verbosity::Quiet
+    } else {
+        match sm.occurrences_of("v") {
+            0 => ui::Verbosity::Normal,
+            1 => ui::Verbosity::Verbose,
+            _ => ui::Verbosity::Debug,
+        }
+    };
+    ui::set_verbosity(verbosity);
+    // The older --ui option is used only if --color isn't given.
+    let color = match (sm.occurrences_of("color"), matches.value_of("ui")) {
+        (0, Some("plain")) => Some("never"),
+        (0, Some("color")) => Some("always"),
+        _ => sm.value_of("color"),
+    };
+    ui::set_color(match color {
+        Some("always") => ui::ColorChoice::Always,
+        Some("never") => ui::ColorChoice::Never,
+        _ => ui::ColorChoice::Auto,
+    });
+    // Progress bars are also only drawn when stdout is a terminal.
+    ui::enable_progress(verbosity != ui::Verbosity::Quiet && !sm.is_present("no-progress"));
+    if let Some(fd) = sm.value_of("progress-json") {
+        let file = progress_json_file(fd);
+        ui::set_progress_callback(Some(Box::new(move |state: &ProgressState| {
+            // Keep going if the reader goes away.
+            let _ = state.write_json(&mut &file);
+        })));
+    }
+    let _watchdog = sm.value_of("stall-timeout").map(|s| {
+        ui::start_stall_watchdog(
+            Duration::from_secs_f64(s.parse().expect("seconds were validated")),
+            sm.is_present("abandon-stalled"),
+        )
+    });
+    // Problems while copying trees are summarized at the end, rather than
+    // scrolling past.
+    ui::defer_problems(matches!(
+        n.as_str(),
+        "backup" | "cp" | "import-tar" | "restore" | "watch"
+    ));
     let c = match n.as_str() {
         "backup" => backup,
+        "band-info" => band_info,
+        "blockdir-stats" => blockdir_stats,
+        "compare-archives" => compare_archives,
+        "content-index" => content_index,
+        "cp" => cp,
         "debug block list" => debug_block_list,
         "debug block referenced" => debug_block_referenced,
         "debug index dump" => debug_index_dump,
         "diff" => diff,
+        "doctor" => doctor,
+        "du" => du,
+        "dupes" => dupes,
+        "explain-excludes" => explain_excludes,
+        "find" => find,
+        "guard-deletion" => guard_deletion,
+        "import-tar" => import_tar,
         "init" => init,
+        "keygen" => keygen,
         "ls" => ls,
+        "push" => push,
+        "replicate" => replicate,
         "restore" => restore,
+        "serve" => serve,
+        "sign" => sign,
+        "source helper" => source_helper,
         "source ls" => source_ls,
         "source size" => source_size,
+        "squash" => squash,
+        "stats" => archive_stats,
+        "trash empty" => trash_empty,
+        "trash ls" => trash_ls,
         "tree size" => tree_size,
+        "trees" => trees,
+        "undelete" => undelete,
         "validate" => validate,
         "versions" => versions,
+        "watch" => watch,
         _ => panic!("unimplemented command"),
     };
-    let result = c(sm);
+    set_memory_limit(sm.value_of("memory-limit").map(|s| s.parse().unwrap()));
+    let result = match sm.value_of("threads") {
+        Some(n) => set_threads(Threads::all(n.parse().unwrap())),
+        None => Ok(()),
+    }
+    .and_then(|()| c(sm));
     ui::clear_progress();
     if let Err(ref e) = result {
         ui::show_error(e);
@@ -40,9 +139,14 @@ fn main() -> conserve::Result<()> {
                 println!("{}", bt);
             }
         }
+        tracing::info!(target: ui::LOG_ONLY_TARGET, "Failed");
         // Avoid Rust redundantly printing the error.
-        std::process::exit(1);
+        std::process::exit(match e {
+            Error::Interrupted => conserve::interrupt::INTERRUPTED_EXIT_CODE,
+            _ => 1,
+        });
     }
+    tracing::info!(target: ui::LOG_ONLY_TARGET, "Finished successfully");
     // TODO: If the operation had >0 non-fatal errors, return a non-zero exit code.
     result
 }
@@ -69,7 +173,10 @@ fn make_clap<'a, 'b>() -> clap::App<'a, 'b> {
 
     fn backup_arg<'a, 'b>() -> Arg<'a, 'b> {
         Arg::with_name("backup")
-            .help("Backup version number")
+            .help(
+                "Backup version number, or `latest~N` for the Nth complete version \
+                 before the latest",
+            )
             .short("b")
             .long("backup")
             .takes_value(true)
@@ -87,6 +194,102 @@ fn make_clap<'a, 'b>() -> clap::App<'a, 'b> {
             .help("Exclude files that match the provided glob pattern")
     };
 
+    fn filter_arg<'a, 'b>() -> Arg<'a, 'b> {
+        Arg::with_name("filter")
+            .long("filter")
+            .takes_value(true)
+            .value_name("EXPR")
+            .help(
+                "Include only entries matching an expression like \
+                 'size > 1MB and path ~ \"*.log\"' (directories are always included \
+                 when copying)",
+            )
+    }
+
+    fn exclude_preset_arg<'a, 'b>() -> Arg<'a, 'b> {
+        Arg::with_name("exclude-preset")
+            .long("exclude-preset")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("NAMES")
+            .help(
+                "Exclude files matching the named presets: \
+                 os-caches, node_modules, build-artifacts",
+            )
+    }
+
+    fn include_archives_arg<'a, 'b>() -> Arg<'a, 'b> {
+        Arg::with_name("include-archives")
+            .long("include-archives")
+            .help("Include Conserve archives found in the source, rather than skipping them")
+    }
+
+    fn exclude_if_present_arg<'a, 'b>() -> Arg<'a, 'b> {
+        Arg::with_name("exclude-if-present")
+            .long("exclude-if-present")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("NAME")
+            .help("Exclude directories containing a file with this name")
+    }
+
+    fn tree_arg<'a, 'b>() -> Arg<'a, 'b> {
+        Arg::with_name("tree")
+            .help("Named tree within the archive")
+            .long("tree")
+            .short("t")
+            .takes_value(true)
+            .value_name("NAME")
+    }
+
+    fn deletion_secret_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+        vec![
+            Arg::with_name("deletion-secret-file")
+                .long("deletion-secret-file")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("Allow removing versions with the deletion secret in this file"),
+            Arg::with_name("deletion-secret-env")
+                .long("deletion-secret-env")
+                .takes_value(true)
+                .value_name("VAR")
+                .conflicts_with_all(&["deletion-secret-file", "ask-deletion-secret"])
+                .help("Allow removing versions with the deletion secret in this variable"),
+            Arg::with_name("deletion-secret-command")
+                .long("deletion-secret-command")
+                .takes_value(true)
+                .value_name("COMMAND")
+                .conflicts_with_all(&[
+                    "deletion-secret-file",
+                    "deletion-secret-env",
+                    "ask-deletion-secret",
+                ])
+                .help("Allow removing versions with the deletion secret printed by this command"),
+            Arg::with_name("ask-deletion-secret")
+                .long("ask-deletion-secret")
+                .conflicts_with("deletion-secret-file")
+                .help("Ask for the deletion secret on the terminal"),
+        ]
+    }
+
+    fn key_arg<'a, 'b>() -> Arg<'a, 'b> {
+        Arg::with_name("key")
+            .long("key")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("Sign new versions with, and check signatures against, the key in this file")
+    }
+
+    fn public_key_arg<'a, 'b>() -> Arg<'a, 'b> {
+        Arg::with_name("public-key")
+            .long("public-key")
+            .takes_value(true)
+            .value_name("FILE")
+            .conflicts_with("key")
+            .help("Check signatures against the public key in this file")
+    }
 
     fn incomplete_arg<'a, 'b>() -> Arg<'a, 'b> {
         Arg::with_name("incomplete")
@@ -95,9 +298,139 @@ fn make_clap<'a, 'b>() -> clap::App<'a, 'b> {
     };
 
     fn verbose_arg<'a, 'b>() -> Arg<'a, 'b> {
-        Arg::with_name("v").short("v").help("Print filenames")
+        Arg::with_name("v")
+            .short("v")
+            .multiple(true)
+            .help("Print filenames; repeat to also show debug messages")
     };
 
+    fn seconds_arg<'a, 'b>(name: &'a str, help: &'a str) -> Arg<'a, 'b> {
+        Arg::with_name(name)
+            .long(name)
+            .takes_value(true)
+            .value_name("SECONDS")
+            .validator(|s| s.parse::<f64>().map(|_| ()).map_err(|e| e.to_string()))
+            .help(help)
+    }
+
+    fn number_arg<'a, 'b>(name: &'a str, value_name: &'a str, help: &'a str) -> Arg<'a, 'b> {
+        Arg::with_name(name)
+            .long(name)
+            .takes_value(true)
+            .value_name(value_name)
+            .validator(|s| s.parse::<u64>().map(|_| ()).map_err(|e| e.to_string()))
+            .help(help)
+    }
+
+    fn max_depth_arg<'a, 'b>() -> Arg<'a, 'b> {
+        number_arg(
+            "max-depth",
+            "N",
+            "Don't descend more than N directories below the root",
+        )
+    }
+
+    fn stats_json_arg<'a, 'b>() -> Arg<'a, 'b> {
+        Arg::with_name("stats-json")
+            .long("stats-json")
+            .value_name("FILE")
+            .help("Write stats as JSON to this file")
+            .takes_value(true)
+    }
+
+    #[cfg(feature = "notify")]
+    fn notify_arg<'a, 'b>() -> Arg<'a, 'b> {
+        Arg::with_name("notify")
+            .long("notify")
+            .help("Show a desktop notification when the backup finishes or fails")
+    }
+
+    // Without notification support, accept the option so that shared scripts
+    // still work, but hide it and warn when it's used.
+    #[cfg(not(feature = "notify"))]
+    fn notify_arg<'a, 'b>() -> Arg<'a, 'b> {
+        Arg::with_name("notify").long("notify").hidden(true)
+    }
+
+    fn webhook_arg<'a, 'b>() -> Arg<'a, 'b> {
+        Arg::with_name("webhook")
+            .long("webhook")
+            .value_name("URL")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("When finished, post a JSON summary of the result to this http:// URL")
+    }
+
+    fn notify_command_arg<'a, 'b>() -> Arg<'a, 'b> {
+        Arg::with_name("notify-command")
+            .long("notify-command")
+            .value_name("COMMAND")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("When finished, run this shell command with a JSON summary on stdin")
+    }
+
+    fn state_file_arg<'a, 'b>() -> Arg<'a, 'b> {
+        Arg::with_name("state-file")
+            .long("state-file")
+            .value_name("FILE")
+            .takes_value(true)
+            .help(
+                "When finished, record the time, result, latest version, and error \
+                 count in this JSON file, keeping the time of the last success",
+            )
+    }
+
+    fn replicate_arg<'a, 'b>() -> Arg<'a, 'b> {
+        Arg::with_name("replicate")
+            .long("replicate")
+            .value_name("CONFIG")
+            .takes_value(true)
+            .help("After a successful backup, replicate to the mirrors in this config file")
+    }
+
+    fn snapshot_arg<'a, 'b>() -> Arg<'a, 'b> {
+        Arg::with_name("snapshot").long("snapshot").help(
+            "Back up from a snapshot of the source volume, so files are read \
+             consistently (Windows and macOS only)",
+        )
+    }
+
+    fn ntfs_metadata_arg<'a, 'b>() -> Arg<'a, 'b> {
+        Arg::with_name("ntfs-metadata")
+            .long("ntfs-metadata")
+            .help("Store NTFS security descriptors and alternate data streams (Windows only)")
+    }
+
+    fn statx_metadata_arg<'a, 'b>() -> Arg<'a, 'b> {
+        Arg::with_name("statx-metadata")
+            .long("statx-metadata")
+            .help("Store the birth time, mount ID, attributes, and owner of each file (Linux only)")
+    }
+
+    fn dereference_arg<'a, 'b>() -> Arg<'a, 'b> {
+        Arg::with_name("dereference")
+            .long("dereference")
+            .short("L")
+            .help(
+                "Follow symlinks, storing what they point to; symlinks looping \
+                 back to a directory already visited are skipped",
+            )
+    }
+
+    fn escalate_command_arg<'a, 'b>() -> Arg<'a, 'b> {
+        Arg::with_name("escalate-command")
+            .long("escalate-command")
+            .takes_value(true)
+            .value_name("COMMAND")
+            .help(
+                "Read directories and files that can't be read for lack of permission \
+                 through conserve run under this command, such as 'sudo -n'",
+            )
+    }
+
     App::new("conserve")
         .about("A robust backup tool <https://github.com/sourcefrog/conserve/>")
         .author(crate_authors!())
@@ -107,15 +440,102 @@ fn make_clap<'a, 'b>() -> clap::App<'a, 'b> {
             Arg::with_name("ui")
                 .long("ui")
                 .short("u")
-                .help("UI for progress and messages")
+                .help("Same as --color: plain is never, and color is always")
                 .takes_value(true)
                 .possible_values(&["auto", "plain", "color"]),
         )
+        .arg(
+            Arg::with_name("color")
+                .long("color")
+                .value_name("WHEN")
+                .global(true)
+                .takes_value(true)
+                .possible_values(&["auto", "always", "never"])
+                .default_value("auto")
+                .help("Color output: auto colors on a terminal unless NO_COLOR is set"),
+        )
+        .arg(
+            Arg::with_name("quiet")
+                .long("quiet")
+                .short("q")
+                .global(true)
+                .help("Show only errors and requested output, not progress or summaries"),
+        )
+        .arg(
+            Arg::with_name("log-file")
+                .long("log-file")
+                .value_name("FILE")
+                .global(true)
+                .takes_value(true)
+                .help("Append a log of this run to a file, which is rotated when it grows large"),
+        )
         .arg(
             Arg::with_name("no-progress")
                 .long("no-progress")
+                .global(true)
                 .help("Hide progress bar"),
         )
+        .arg(
+            Arg::with_name("progress-json")
+                .long("progress-json")
+                .value_name("FD")
+                .global(true)
+                .takes_value(true)
+                .validator(validate_progress_fd)
+                .help(
+                    "Write progress as lines of JSON to this open file descriptor, \
+                     for programs that show their own progress",
+                ),
+        )
+        .arg(
+            number_arg(
+                "threads",
+                "N",
+                "Use at most N threads each for blocks and for pushing [default: one per core]",
+            )
+            .global(true),
+        )
+        .arg(
+            number_arg(
+                "memory-limit",
+                "BYTES",
+                "Keep the biggest buffers within about this much memory, spilling \
+                 large directory listings to temporary files",
+            )
+            .global(true),
+        )
+        .arg(
+            seconds_arg(
+                "stall-timeout",
+                "Warn which file is stuck when no progress is made for this long",
+            )
+            .global(true),
+        )
+        .arg(
+            Arg::with_name("abandon-stalled")
+                .long("abandon-stalled")
+                .global(true)
+                .requires("stall-timeout")
+                .help("Skip a source file that makes no progress for the stall timeout"),
+        )
+        .subcommand(
+            SubCommand::with_name("band-info")
+                .about("Show whether a version is complete, and summarize its index")
+                .arg(archive_arg())
+                .arg(tree_arg())
+                .arg(backup_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("blockdir-stats")
+                .about("Measure the blocks in an archive, and look for unexpected files")
+                .arg(archive_arg())
+                .arg(
+                    Arg::with_name("subdirs")
+                        .long("subdirs")
+                        .help("Show the blocks and compression in each subdirectory"),
+                )
+                .arg(stats_json_arg()),
+        )
         .subcommand(
             SubCommand::with_name("debug")
                 .about("Show developer-oriented information")
@@ -140,6 +560,7 @@ fn make_clap<'a, 'b>() -> clap::App<'a, 'b> {
                             SubCommand::with_name("dump")
                                 .about("Show the stored index for the given band")
                                 .arg(backup_arg())
+                                .arg(tree_arg())
                                 .arg(Arg::with_name("archive").required(true)),
                         ),
                 ),
@@ -147,7 +568,81 @@ fn make_clap<'a, 'b>() -> clap::App<'a, 'b> {
         .subcommand(
             SubCommand::with_name("validate")
                 .about("Check whether an archive is internally consistent")
-                .arg(archive_arg()),
+                .arg(archive_arg())
+                .arg(tree_arg())
+                .arg(key_arg())
+                .arg(public_key_arg())
+                .arg(backup_arg().help("Check only this version, not the whole archive"))
+                .arg(exclude_arg())
+                .arg(exclude_preset_arg())
+                .arg(number_arg(
+                    "jobs",
+                    "N",
+                    "Check blocks and files on this many threads [default: one per core]",
+                ))
+                .arg(
+                    Arg::with_name("since")
+                        .long("since")
+                        .value_name("AGE")
+                        .conflicts_with("backup")
+                        .help(
+                            "Skip blocks verified within this age, like 30d, \
+                             according to the validation cache",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("validation-cache")
+                        .long("validation-cache")
+                        .value_name("FILE")
+                        .conflicts_with("backup")
+                        .help(
+                            "Remember when blocks were verified in this file \
+                             [default: ARCHIVE.validated, beside the archive, \
+                             when --since or --scrub-budget is given]",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("scrub-budget")
+                        .long("scrub-budget")
+                        .value_name("AGE")
+                        .conflicts_with("backup")
+                        .help(
+                            "Spend at most this long, like 1h, reading blocks, \
+                             least recently verified first",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("fix-index-order")
+                        .long("fix-index-order")
+                        .help("Rewrite indexes whose entries are out of order or duplicated"),
+                )
+                .arg(stats_json_arg())
+                .arg(webhook_arg())
+                .arg(notify_command_arg())
+                .arg(state_file_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("doctor")
+                .about("Check for common problems with an archive and its environment")
+                .after_help(
+                    "Checks the archive can be read and written, that each version \
+                     can be read, whether a backup is in progress, the space free, \
+                     the clock, and, if given, that the source can be read. \
+                     Problems and warnings are shown with what to do about them.",
+                )
+                .arg(archive_arg())
+                .arg(Arg::with_name("source").help("Also check this source directory"))
+                .arg(number_arg(
+                    "warn-free-space",
+                    "BYTES",
+                    "Warn if the archive's filesystem has less than this much space free \
+                     [default: 1 GiB]",
+                ))
+                .arg(
+                    Arg::with_name("json")
+                        .long("json")
+                        .help("Print findings as json, one per line"),
+                ),
         )
         .subcommand(
             SubCommand::with_name("init")
@@ -160,6 +655,160 @@ fn make_clap<'a, 'b>() -> clap::App<'a, 'b> {
                              should either not exist or be an empty directory",
                         )
                         .required(true),
+                )
+                .arg(
+                    Arg::with_name("index-format")
+                        .long("index-format")
+                        .value_name("FORMAT")
+                        .possible_values(&["json", "binary"])
+                        .default_value("json")
+                        .help(
+                            "Encoding for index hunks: binary is smaller and faster, \
+                             but can't be read by Conserve before 0.6.3",
+                        ),
+                )
+                .arg(number_arg(
+                    "block-size",
+                    "BYTES",
+                    "Break file content into blocks of this size [default: 1048576]",
+                ))
+                .arg(number_arg(
+                    "small-file-size",
+                    "BYTES",
+                    "Combine files up to this size into shared blocks, \
+                     or 0 to store each file in its own blocks [default: 100000]",
+                ))
+                .arg(number_arg(
+                    "index-hunk-entries",
+                    "N",
+                    "Write index hunks of this many entries [default: 1000]",
+                ))
+                .arg(number_arg(
+                    "flush-bytes",
+                    "BYTES",
+                    "Store blocks in batches of this many bytes, trading memory \
+                     for parallelism [default: 16777216]",
+                ))
+                .arg(key_arg().help("Sign the archive with the key in this file")),
+        )
+        .subcommand(
+            SubCommand::with_name("keygen")
+                .about("Make a new secret key for signing archives")
+                .after_help(
+                    "The public key, which checks signatures but can't make them, \
+                     is written to the same name with .pub added.",
+                )
+                .arg(
+                    Arg::with_name("key-file")
+                        .help("Write the key to this new file")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("sign")
+                .about("Sign an archive and its complete versions with a key")
+                .after_help(
+                    "Once an archive is signed, giving --key to commands that read \
+                     or write it checks that the archive and each complete version \
+                     are signed with that key, so changes by anyone without the key \
+                     are noticed. Commands that only read the archive can be given \
+                     --public-key instead. Existing versions are trusted as they are \
+                     when they're signed.",
+
+                )
+                .arg(archive_arg())
+                .arg(
+                    Arg::with_name("key")
+                        .long("key")
+                        .takes_value(true)
+                        .value_name("FILE")
+                        .required(true)
+                        .help("Sign with the key in this file"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("guard-deletion")
+                .about("Require a second secret to remove versions from an archive")
+                .after_help(
+                    "Once an archive is guarded, commands that remove data from it, \
+                     such as squash, fail unless they're given the deletion secret. \
+                     Keep the secret off the backup host, so that a compromised \
+                     host can add backups but not destroy old ones.\n\n\
+                     Changing or removing the guard needs the current secret.",
+                )
+                .arg(archive_arg())
+                .args(&deletion_secret_args())
+                .arg(
+                    Arg::with_name("new-secret-file")
+                        .long("new-secret-file")
+                        .takes_value(true)
+                        .value_name("FILE")
+                        .help("Guard the archive with the secret in this file"),
+                )
+                .arg(
+                    Arg::with_name("new-secret-env")
+                        .long("new-secret-env")
+                        .takes_value(true)
+                        .value_name("VAR")
+                        .conflicts_with_all(&["new-secret-file", "ask-new-secret"])
+                        .help("Guard the archive with the secret in this environment variable"),
+                )
+                .arg(
+                    Arg::with_name("new-secret-command")
+                        .long("new-secret-command")
+                        .takes_value(true)
+                        .value_name("COMMAND")
+                        .conflicts_with_all(&["new-secret-file", "new-secret-env", "ask-new-secret"])
+                        .help("Guard the archive with the secret printed by this shell command"),
+                )
+                .arg(
+                    Arg::with_name("ask-new-secret")
+                        .long("ask-new-secret")
+                        .conflicts_with("new-secret-file")
+                        .help("Ask for the new secret on the terminal"),
+                )
+                .arg(
+                    Arg::with_name("remove")
+                        .long("remove")
+                        .conflicts_with_all(&[
+                            "new-secret-file",
+                            "new-secret-env",
+                            "new-secret-command",
+                            "ask-new-secret",
+                        ])
+                        .required_unless_one(&[
+                            "new-secret-file",
+                            "new-secret-env",
+                            "new-secret-command",
+                            "ask-new-secret",
+                        ])
+                        .help("Stop requiring a secret to remove versions"),
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("squash")
+                .about("Merge a range of versions into the last of them")
+                .after_help(
+                    "The last version in the range keeps its name and contents, and \
+                     if its index is layered over earlier versions it's rewritten as \
+                     a whole index. The other versions in the range are then moved to \
+                     the trash, from where `conserve undelete` can bring them back. \
+                     The blocks of the archive are reused as they are.\n\n\
+                     Squashing fails if a later version is layered on one that would \
+                     be removed.",
+                )
+                .arg(archive_arg())
+                .arg(tree_arg())
+                .arg(key_arg())
+                .args(&deletion_secret_args())
+                .arg(
+                    Arg::with_name("backup")
+                        .short("b")
+                        .long("backup")
+                        .takes_value(true)
+                        .value_name("START..END")
+                        .required(true)
+                        .help("Versions to squash, like b0001..b0005 or b0001..latest~1"),
                 ),
         )
         .subcommand(
@@ -167,41 +816,134 @@ fn make_clap<'a, 'b>() -> clap::App<'a, 'b> {
                 .display_order(2)
                 .about("Copy source directory into an archive")
                 .arg(archive_arg())
+                .arg(tree_arg())
+                .arg(key_arg())
                 .arg(
                     Arg::with_name("source")
                         .help("Backup from this directory")
                         .required(true),
                 )
+                .arg(Arg::with_name("rsync-slash").long("rsync-slash").help(
+                    "Like rsync, store the source directory itself unless \
+                             the source path ends with a slash",
+                ))
                 .arg(exclude_arg())
-                .arg(verbose_arg()),
+                .arg(exclude_preset_arg())
+                .arg(filter_arg())
+                .arg(max_depth_arg())
+                .arg(include_archives_arg())
+                .arg(exclude_if_present_arg())
+                .arg(verbose_arg())
+                .arg(stats_json_arg())
+                .arg(notify_arg())
+                .arg(webhook_arg())
+                .arg(notify_command_arg())
+                .arg(state_file_arg())
+                .arg(replicate_arg())
+                .arg(snapshot_arg())
+                .arg(ntfs_metadata_arg())
+                .arg(statx_metadata_arg())
+                .arg(dereference_arg())
+                .arg(escalate_command_arg())
+                .arg(number_arg(
+                    "profile-entries",
+                    "N",
+                    "Time each entry, and afterwards list the N slowest files and directories",
+                ))
+                .arg(Arg::with_name("paranoid").long("paranoid").help(
+                    "Read back and check every block after it's written: \
+                     slower, but catches corruption while writing",
+                ))
+                .arg(Arg::with_name("layered").long("layered").help(
+                    "Write only the index entries that changed since the previous \
+                     version, so backups of mostly-unchanged trees are smaller and faster",
+                ))
+                .arg(Arg::with_name("content-index").long("content-index").help(
+                    "Write a content index of the hash of each file, so that \
+                     `conserve find --hash` can search this version",
+                ))
+                .arg(
+                    Arg::with_name("trust-dir-mtimes")
+                        .long("trust-dir-mtimes")
+                        .help(
+                            "Take files from the previous version without reading their \
+                             metadata when their directory's mtime and names are unchanged; \
+                             much faster for trees where files are only added, but misses \
+                             files rewritten in place",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("scan-cache")
+                        .long("scan-cache")
+                        .value_name("FILE")
+                        .help(
+                            "Record the new version's entries in this local file, and find \
+                             unchanged files from it rather than the previous version's \
+                             index, if it was written for that version",
+                        ),
+                )
+                .arg(Arg::with_name("deterministic").long("deterministic").help(
+                    "Write the version with zero timestamps and without using \
+                     the previous version as a basis, so that backing up the same \
+                     tree always writes identical metadata and index",
+                ))
+                .arg(number_arg(
+                    "small-file-size",
+                    "BYTES",
+                    "Combine files up to this size into shared blocks, \
+                     or 0 to store each file in its own blocks \
+                     [default: as set when the archive was created]",
+                ))
+                .arg(number_arg(
+                    "warn-free-space",
+                    "BYTES",
+                    "Warn if the archive's filesystem has less than this much space free",
+                ))
+                .arg(number_arg(
+                    "min-free-space",
+                    "BYTES",
+                    "Don't start, or stop cleanly leaving an incomplete version, \
+                     if the archive's filesystem has less than this much space free",
+                ))
+                .arg(
+                    Arg::with_name("metrics-textfile")
+                        .long("metrics-textfile")
+                        .value_name("FILE")
+                        .help("After a successful backup, write metrics in Prometheus textfile format")
+                        .takes_value(true),
+                ),
         )
         .subcommand(
-            SubCommand::with_name("diff")
-                .about("Diff source against a stored tree")
+            SubCommand::with_name("import-tar")
+                .about("Copy the contents of a tar file into a new version in an archive")
                 .arg(archive_arg())
+                .arg(tree_arg())
                 .arg(
-                    Arg::with_name("source")
-                        .help("Diff against this source")
+                    Arg::with_name("tarfile")
+                        .help("Uncompressed tar file to import, not a pipe")
                         .required(true),
-                ),
+                )
+                .arg(exclude_arg())
+                .arg(exclude_preset_arg())
+                .arg(verbose_arg())
+                .arg(stats_json_arg()),
         )
         .subcommand(
-            SubCommand::with_name("restore")
-                .display_order(3)
-                .about("Copy a backup tree out of an archive")
-                .arg(archive_arg())
-                .arg(backup_arg())
-                .arg(incomplete_arg())
+            SubCommand::with_name("cp")
+                .about("Copy a source directory to a new directory, without an archive")
                 .after_help(
                     "\
-                     Conserve will by default refuse to restore incomplete versions, \
-                     to prevent you thinking you restored the whole tree when it may \
-                     be truncated.  You can override this with --incomplete, or \
-                     select an older version with --backup.",
+                     Files are copied in the same order, with the same excludes \
+                     and error handling, as a backup followed by a restore.",
+                )
+                .arg(
+                    Arg::with_name("source")
+                        .help("Copy from this directory")
+                        .required(true),
                 )
                 .arg(
                     Arg::with_name("destination")
-                        .help("Restore to this new directory")
+                        .help("Copy to this new directory")
                         .required(true),
                 )
                 .arg(
@@ -210,95 +952,892 @@ fn make_clap<'a, 'b>() -> clap::App<'a, 'b> {
                         .help("Overwrite existing destination directory"),
                 )
                 .arg(exclude_arg())
-                .arg(verbose_arg()),
+                .arg(exclude_preset_arg())
+                .arg(include_archives_arg())
+                .arg(exclude_if_present_arg())
+                .arg(verbose_arg())
+                .arg(stats_json_arg()),
         )
         .subcommand(
-            SubCommand::with_name("versions")
-                .display_order(4)
-                .about("List backup versions in an archive")
+            SubCommand::with_name("diff")
+                .about("Diff source against a stored tree")
                 .after_help(
-                    "`conserve versions` shows one version per \
-                     line.  For each version the output shows the version name, \
-                     whether it is complete, when it started, and (if complete) \
-                     how much time elapsed.",
-                )
-                .arg(
-                    Arg::with_name("sizes")
-                        .help("Show tree sizes")
-                        .long("sizes"),
+                    "Without a source, show the entries that the version added, \
+                     changed, and deleted, compared to the version it was written over.",
                 )
                 .arg(archive_arg())
+                .arg(tree_arg())
+                .arg(key_arg())
+                .arg(public_key_arg())
+                .arg(backup_arg())
+                .arg(Arg::with_name("source").help("Diff against this source"))
+                .arg(exclude_arg())
+                .arg(exclude_preset_arg())
+                .arg(exclude_if_present_arg())
                 .arg(
-                    Arg::with_name("short")
-                        .help("List just version name without details")
-                        .long("short")
-                        .short("s"),
+                    Arg::with_name("ignore-dir-mtimes")
+                        .long("ignore-dir-mtimes")
+                        .help("Don't show directories whose only change is their mtime"),
                 ),
         )
         .subcommand(
-            SubCommand::with_name("ls")
-                .display_order(5)
-                .about("List files in a backup version")
-                .arg(archive_arg())
-                .arg(backup_arg())
+            SubCommand::with_name("explain-excludes")
+                .about("Show which files would be excluded from a backup, and why")
+                .arg(
+                    Arg::with_name("source")
+                        .help("Source directory")
+                        .required(true),
+                )
                 .arg(exclude_arg())
-                .arg(incomplete_arg()),
+                .arg(exclude_preset_arg())
+                .arg(include_archives_arg())
+                .arg(exclude_if_present_arg()),
         )
         .subcommand(
-            SubCommand::with_name("source")
-                .about("Operate on source directories")
-                .subcommand(
-                    SubCommand::with_name("ls")
-                        .about("Recursive list files from source directory")
-                        .arg(
-                            Arg::with_name("source")
-                                .help("Source directory")
-                                .required(true),
-                        )
-                        .arg(exclude_arg()),
+            SubCommand::with_name("find")
+                .about("Find which versions hold a file with the given content")
+                .after_help(
+                    "The hash is the BLAKE2b-512 of the file's whole content, as \
+                     printed by `b2sum`, or a prefix of it. Only versions backed up \
+                     with --content-index, or indexed later with \
+                     `conserve content-index`, are searched.",
                 )
-                .subcommand(
-                    SubCommand::with_name("size")
-                        .about("Show the size of a source directory")
-                        .arg(
-                            Arg::with_name("source")
-                                .help("Source directory")
-                                .required(true),
-                        ),
+                .arg(archive_arg())
+                .arg(tree_arg())
+                .arg(
+                    Arg::with_name("hash")
+                        .long("hash")
+                        .takes_value(true)
+                        .value_name("HASH")
+                        .required(true)
+                        .validator(|s| {
+                            if !s.is_empty() && s.chars().all(|c| c.is_ascii_hexdigit()) {
+                                Ok(())
+                            } else {
+                                Err("expected a hex hash".to_owned())
+                            }
+                        })
+                        .help("Hex content hash, or a prefix of it"),
                 ),
         )
         .subcommand(
-            SubCommand::with_name("tree")
+            SubCommand::with_name("content-index")
+                .about("Write content indexes for versions that were backed up without one")
+                .arg(archive_arg())
+                .arg(tree_arg())
+                .arg(backup_arg().help("Index only this version [default: all without one]")),
+        )
+        .subcommand(
+            SubCommand::with_name("dupes")
+                .about("List files in a version with identical content")
+                .after_help(
+                    "Uses the version's content index, so it must have been backed up \
+                     with --content-index, or indexed later with `conserve content-index`. \
+                     Sets whose content is already stored only once in the archive are \
+                     marked as shared.",
+                )
+                .arg(archive_arg())
+                .arg(tree_arg())
+                .arg(backup_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("compare-archives")
+                .about("Check that two archives have the same versions and blocks")
+                .after_help(
+                    "Blocks are compared by their hashes, so their content isn't read. \
+                     Use this to confirm that a replica is complete: it fails if either \
+                     archive has versions or blocks the other lacks.",
+                )
+                .arg(
+                    Arg::with_name("left")
+                        .help("Path of the first archive")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("right")
+                        .help("Path of the second archive")
+                        .required(true),
+                )
+                .arg(tree_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("push")
+                .about("Copy new versions to a conserve server")
+                .arg(archive_arg())
+                .arg(tree_arg())
+                .arg(
+                    Arg::with_name("url")
+                        .help("URL of the server, like http://HOST:PORT/, or path of another archive")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("token-file")
+                        .long("token-file")
+                        .takes_value(true)
+                        .value_name("FILE")
+                        .help("Authenticate with the token in this file"),
+                )
+                .arg(
+                    Arg::with_name("token-env")
+                        .long("token-env")
+                        .takes_value(true)
+                        .value_name("VAR")
+                        .conflicts_with_all(&["token-file", "token-command", "ask-token"])
+                        .help("Authenticate with the token in this environment variable"),
+                )
+                .arg(
+                    Arg::with_name("token-command")
+                        .long("token-command")
+                        .alias("password-command")
+                        .takes_value(true)
+                        .value_name("COMMAND")
+                        .conflicts_with_all(&["token-file", "ask-token"])
+                        .help("Authenticate with the token printed by this shell command"),
+                )
+                .arg(
+                    Arg::with_name("ask-token")
+                        .long("ask-token")
+                        .conflicts_with("token-file")
+                        .help("Ask for the token on the terminal"),
+                )
+                .arg(
+                    Arg::with_name("bandwidth-limit")
+                        .long("bandwidth-limit")
+                        .takes_value(true)
+                        .value_name("BYTES")
+                        .validator(|s| s.parse::<u64>().map(|_| ()).map_err(|e| e.to_string()))
+                        .help("Send at most this many bytes per second"),
+                )
+                .arg(
+                    Arg::with_name("batch-size")
+                        .long("batch-size")
+                        .takes_value(true)
+                        .value_name("N")
+                        .validator(|s| s.parse::<usize>().map(|_| ()).map_err(|e| e.to_string()))
+                        .help("Send up to this many index files at once [default: 16]"),
+                )
+                .arg(
+                    Arg::with_name("block-cache")
+                        .long("block-cache")
+                        .takes_value(true)
+                        .value_name("FILE")
+                        .help("Remember in this file which blocks the destination has"),
+                )
+                .arg(stats_json_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("replicate")
+                .about("Copy new versions to each mirror listed in a config file")
+                .after_help(
+                    "The config file is json listing mirrors, each either a conserve \
+                     server or another archive, with optional priorities, intervals, \
+                     and bandwidth limits. What's been replicated to each mirror is \
+                     recorded in a state file, by default next to the config file.",
+                )
+                .arg(archive_arg())
+                .arg(tree_arg())
+                .arg(
+                    Arg::with_name("config")
+                        .help("Mirror config file")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("state-file")
+                        .long("state-file")
+                        .takes_value(true)
+                        .value_name("FILE")
+                        .help("Record what's been replicated in this file [default: CONFIG.state]"),
+                )
+                .arg(
+                    Arg::with_name("force")
+                        .long("force")
+                        .help("Replicate to every mirror, even if it's not due or seems up to date"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("restore")
+                .display_order(3)
+                .about("Copy a backup tree out of an archive")
+                .arg(archive_arg())
+                .arg(tree_arg())
+                .arg(key_arg())
+                .arg(public_key_arg())
+                .arg(backup_arg())
+                .arg(incomplete_arg())
+                .after_help(
+                    "\
+                     Conserve will by default refuse to restore incomplete versions, \
+                     to prevent you thinking you restored the whole tree when it may \
+                     be truncated.  You can override this with --incomplete, or \
+                     select an older version with --backup.  With --incomplete \
+                     and no --backup, the most recent version is restored even if \
+                     it was interrupted.\n\n\
+                     If the owners of entries were stored, with --statx-metadata, \
+                     they're restored, after applying any --map-user and --map-group \
+                     maps. Restoring other users' ownership usually needs root.\n\n\
+                     Symlinks whose stored target is empty, too long, or contains a \
+                     NUL are never restored.",
+                )
+                .arg(Arg::with_name("destination").help("Restore to this new directory"))
+                .arg(
+                    Arg::with_name("force-overwrite")
+                        .long("force-overwrite")
+                        .help("Overwrite existing destination directory"),
+                )
+                .arg(
+                    Arg::with_name("map")
+                        .long("map")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .value_name("OLD=NEW")
+                        .help("Restore entries in the apath OLD into NEW instead, like /home/alice=/home/bob"),
+                )
+                .arg(
+                    Arg::with_name("map-user")
+                        .long("map-user")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .value_name("OLD:NEW")
+                        .help("Restore entries stored as owned by user ID OLD as owned by NEW"),
+                )
+                .arg(
+                    Arg::with_name("map-group")
+                        .long("map-group")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .value_name("OLD:NEW")
+                        .help("Restore entries stored as owned by group ID OLD as owned by NEW"),
+                )
+                .arg(
+                    Arg::with_name("escaping-symlinks")
+                        .long("escaping-symlinks")
+                        .takes_value(true)
+                        .value_name("WHAT")
+                        .possible_values(&["allow", "warn", "refuse"])
+                        .default_value("allow")
+                        .help("What to do with symlinks pointing outside the destination"),
+                )
+                .arg(exclude_arg())
+                .arg(exclude_preset_arg())
+                .arg(filter_arg())
+                .arg(max_depth_arg())
+                .arg(verbose_arg())
+                .arg(stats_json_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("watch")
+                .about("Back up a source directory whenever it changes")
+                .after_help(
+                    "`conserve watch` makes a backup, and then rescans the source \
+                     for changes, when notified of one on Linux or else every poll \
+                     interval. Once it changes, another backup starts when it's \
+                     been unchanged for the quiet period, or after the maximum \
+                     delay if that's set.",
+                )
+                .arg(archive_arg())
+                .arg(tree_arg())
+                .arg(key_arg())
+                .arg(
+                    Arg::with_name("source")
+                        .help("Backup from this directory")
+                        .required(true),
+                )
+                .arg(exclude_arg())
+                .arg(exclude_preset_arg())
+                .arg(include_archives_arg())
+                .arg(exclude_if_present_arg())
+                .arg(verbose_arg())
+                .arg(webhook_arg())
+                .arg(notify_command_arg())
+                .arg(state_file_arg())
+                .arg(replicate_arg())
+                .arg(snapshot_arg())
+                .arg(ntfs_metadata_arg())
+                .arg(statx_metadata_arg())
+                .arg(dereference_arg())
+                .arg(escalate_command_arg())
+                .arg(seconds_arg(
+                    "poll-interval",
+                    "Scan for changes at least this often [default: 10]",
+                ))
+                .arg(seconds_arg(
+                    "quiet-period",
+                    "Back up once the source has been unchanged this long [default: 30]",
+                ))
+                .arg(seconds_arg(
+                    "max-delay",
+                    "Back up at most this long after a change, even if the source is still changing",
+                ))
+                .arg(
+                    Arg::with_name("max-backups")
+                        .long("max-backups")
+                        .takes_value(true)
+                        .value_name("N")
+                        .validator(|s| s.parse::<usize>().map(|_| ()).map_err(|e| e.to_string()))
+                        .help("Stop after this many backups"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("versions")
+                .display_order(4)
+                .about("List backup versions in an archive")
+                .after_help(
+                    "`conserve versions` shows one version per \
+                     line.  For each version the output shows the version name, \
+                     whether it is complete, when it started, and (if complete) \
+                     how much time elapsed.\n\n\
+                     With --sizes, it also shows the size of the files in the version, \
+                     the compressed size of the blocks used only by that version, \
+                     which deleting it would free, and the compressed size of the \
+                     blocks it shares with other versions.\n\n\
+                     With --changes, it also shows how many entries the version \
+                     added (+), changed (~), and deleted (-), compared to the \
+                     version it was written over.",
+                )
+                .arg(
+                    Arg::with_name("sizes")
+                        .help("Show tree sizes, and exclusive and shared block sizes")
+                        .long("sizes"),
+                )
+                .arg(
+                    Arg::with_name("changes")
+                        .help("Show how many entries were added, changed, and deleted")
+                        .long("changes"),
+                )
+                .arg(archive_arg())
+                .arg(tree_arg())
+                .arg(
+                    Arg::with_name("short")
+                        .help("List just version name without details")
+                        .long("short")
+                        .short("s"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("du")
+                .about("Show the biggest files and directories in a backup version")
+                .after_help(
+                    "Sizes are the uncompressed size of the files, counting everything \
+                     inside each directory, read from the index without reading any \
+                     file content.",
+                )
+                .arg(archive_arg())
+                .arg(
+                    Arg::with_name("subtree")
+                        .help("Measure only this apath and its contents, like /home/me"),
+                )
+                .arg(tree_arg())
+                .arg(key_arg())
+                .arg(public_key_arg())
+                .arg(backup_arg())
+                .arg(exclude_arg())
+                .arg(exclude_preset_arg())
+                .arg(incomplete_arg())
+                .arg(number_arg("top", "N", "Show this many files and directories").default_value("20")),
+        )
+        .subcommand(
+            SubCommand::with_name("ls")
+                .display_order(5)
+                .about("List files in a backup version")
+                .arg(archive_arg())
+                .arg(
+                    Arg::with_name("subtree")
+                        .help("List only this apath and its contents, like /home/me"),
+                )
+                .arg(
+                    Arg::with_name("tree-view")
+                        .long("tree-view")
+                        .conflicts_with("filter")
+                        .help("Show an indented tree, with file and directory sizes"),
+                )
+                .arg(tree_arg())
+                .arg(key_arg())
+                .arg(public_key_arg())
+                .arg(backup_arg())
+                .arg(exclude_arg())
+                .arg(exclude_preset_arg())
+                .arg(filter_arg())
+                .arg(max_depth_arg())
+                .arg(incomplete_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("serve")
+                .about("Serve an archive over HTTP, accepting pushes from clients with a push token")
+                .arg(archive_arg())
+                .arg(tree_arg())
+                .arg(
+                    Arg::with_name("listen")
+                        .long("listen")
+                        .takes_value(true)
+                        .value_name("ADDRESS")
+                        .default_value("127.0.0.1:8080")
+                        .help("Address and port to listen on"),
+                )
+                .arg(
+                    Arg::with_name("push-token-file")
+                        .long("push-token-file")
+                        .takes_value(true)
+                        .value_name("FILE")
+                        .help("Only serve, and accept pushes from, clients presenting the token in this file"),
+                )
+                .arg(
+                    Arg::with_name("push-token-env")
+                        .long("push-token-env")
+                        .takes_value(true)
+                        .value_name("VAR")
+                        .conflicts_with_all(&["push-token-file", "push-token-command"])
+                        .help("Only serve clients with the token in this environment variable"),
+                )
+                .arg(
+                    Arg::with_name("push-token-command")
+                        .long("push-token-command")
+                        .takes_value(true)
+                        .value_name("COMMAND")
+                        .conflicts_with("push-token-file")
+                        .help("Only serve clients with the token printed by this shell command"),
+                )
+                .arg(
+                    Arg::with_name("quota")
+                        .long("quota")
+                        .takes_value(true)
+                        .value_name("BYTES")
+                        .validator(|s| s.parse::<u64>().map(|_| ()).map_err(|e| e.to_string()))
+                        .help("Refuse pushed blocks once the archive holds this many compressed bytes"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("source")
+                .about("Operate on source directories")
+                .subcommand(
+                    SubCommand::with_name("ls")
+                        .about("Recursive list files from source directory")
+                        .arg(
+                            Arg::with_name("source")
+                                .help("Source directory")
+                                .required(true),
+                        )
+                        .arg(exclude_arg())
+                        .arg(exclude_preset_arg())
+                        .arg(include_archives_arg())
+                        .arg(exclude_if_present_arg()),
+                )
+                .subcommand(
+                    SubCommand::with_name("size")
+                        .about("Show the size of a source directory")
+                        .arg(
+                            Arg::with_name("source")
+                                .help("Source directory")
+                                .required(true),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("helper")
+                        .about("List a directory or write a file, for --escalate-command")
+                        .setting(AppSettings::Hidden)
+                        .arg(
+                            Arg::with_name("operation")
+                                .possible_values(&["list", "cat"])
+                                .required(true),
+                        )
+                        .arg(Arg::with_name("path").required(true)),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("stats")
+                .about("Show how an archive has grown over time")
+                .after_help(
+                    "With --history, each version is shown with its number of files, \
+                     the size of the files, how many files were added, changed, and \
+                     removed since the previous version, the compressed size of the \
+                     blocks it first stored, and the total size of the blocks stored \
+                     by it and all earlier versions.",
+                )
+                .arg(archive_arg())
+                .arg(tree_arg())
+                .arg(
+                    Arg::with_name("history")
+                        .long("history")
+                        .help("Show every version, rather than only the latest"),
+                )
+                .arg(
+                    Arg::with_name("csv")
+                        .long("csv")
+                        .help("Show the history as CSV, with sizes in bytes"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("trees")
+                .about("List the named trees in an archive")
+                .arg(
+                    Arg::with_name("archive")
+                        .help("Archive directory")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("tree")
                 .about("Operate on stored trees")
                 .subcommand(
                     SubCommand::with_name("size")
                         .about("Show the size of a stored tree (as it would be when restored)")
                         .arg(archive_arg())
+                        .arg(tree_arg())
                         .arg(backup_arg()),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("trash")
+                .about("Operate on versions removed from an archive")
+                .subcommand(
+                    SubCommand::with_name("ls")
+                        .about("List removed versions that can be undeleted")
+                        .arg(archive_arg())
+                        .arg(tree_arg()),
+                )
+                .subcommand(
+                    SubCommand::with_name("empty")
+                        .about("Permanently remove the versions in the trash")
+                        .arg(archive_arg())
+                        .arg(tree_arg())
+                        .args(&deletion_secret_args()),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("undelete")
+                .about("Bring back a removed version from the trash")
+                .arg(archive_arg())
+                .arg(tree_arg())
+                .arg(backup_arg().required(true)),
+        )
 }
 
 fn init(subm: &ArgMatches) -> Result<()> {
     let archive_path = subm.value_of("archive").expect("'archive' arg not found");
-    Archive::create(archive_path).and(Ok(()))?;
+    let index_format = match subm.value_of("index-format") {
+        Some("binary") => IndexFormat::Binary,
+        _ => IndexFormat::Json,
+    };
+    let mut tuning = Tuning::default();
+    if let Some(s) = subm.value_of("block-size") {
+        tuning.block_size = s.parse().expect("block-size was validated");
+    }
+    if let Some(s) = subm.value_of("small-file-size") {
+        tuning.small_file_size = s.parse().expect("small-file-size was validated");
+    }
+    if let Some(s) = subm.value_of("index-hunk-entries") {
+        tuning.index_hunk_entries = s.parse().expect("index-hunk-entries was validated");
+    }
+    if let Some(s) = subm.value_of("flush-bytes") {
+        tuning.flush_bytes = s.parse().expect("flush-bytes was validated");
+    }
+    let archive = Archive::create_with_tuning(archive_path, index_format, tuning)?;
+    if let Some(key_path) = subm.value_of("key") {
+        archive.sign(&SigningKey::load(Path::new(key_path))?)?;
+    }
     ui::println(&format!("Created new archive in {}", archive_path));
     Ok(())
 }
 
-fn backup(subm: &ArgMatches) -> Result<()> {
+fn keygen(subm: &ArgMatches) -> Result<()> {
+    let key_file = subm.value_of("key-file").unwrap();
+    let key = SigningKey::generate()?;
+    key.save(Path::new(key_file))?;
+    key.public_key()
+        .save(Path::new(&format!("{}.pub", key_file)))?;
+    ui::println(&format!("Created key {}", key.key_id()));
+    Ok(())
+}
+
+fn sign(subm: &ArgMatches) -> Result<()> {
     let archive = Archive::open(subm.value_of("archive").unwrap())?;
-    let lt = live_tree_from_options(subm)?;
+    let key = SigningKey::load(Path::new(subm.value_of("key").unwrap()))?;
+    let signed = archive.sign(&key)?;
+    ui::println(&format!(
+        "Signed archive and {} versions with key {}",
+        signed,
+        key.key_id()
+    ));
+    Ok(())
+}
+
+fn guard_deletion(subm: &ArgMatches) -> Result<()> {
+    let archive = archive_with_deletion_secret(subm)?;
+    if subm.is_present("remove") {
+        archive.set_deletion_guard(None)?;
+        ui::println("Versions can now be removed without a deletion secret");
+    } else {
+        let source = credential_source(subm, "new-secret", Some("New deletion secret"))
+            .expect("clap requires a new secret");
+        archive.set_deletion_guard(Some(&source.get()?))?;
+        ui::println("Removing versions now needs the deletion secret");
+    }
+    Ok(())
+}
+
+/// Open the archive, with the deletion secret if one was given.
+fn archive_with_deletion_secret(subm: &ArgMatches) -> Result<Archive> {
+    let archive = archive_from_options(subm)?;
+    Ok(
+        match credential_source(subm, "deletion-secret", Some("Deletion secret")) {
+            Some(source) => archive.with_deletion_secret(source.get()?),
+            None => archive,
+        },
+    )
+}
+
+fn squash(subm: &ArgMatches) -> Result<()> {
+    let archive = archive_with_deletion_secret(subm)?;
+    let range = subm.value_of("backup").unwrap();
+    let (start, end) = match range.split_once("..") {
+        Some((start, end)) => (
+            archive.resolve_band_id(start)?,
+            archive.resolve_band_id(end)?,
+        ),
+        None => {
+            return Err(Error::InvalidVersionRange {
+                range: range.to_owned(),
+            })
+        }
+    };
+    let stats = archive.squash(&start, &end)?;
+    ui::println(&format!(
+        "Squashed {} versions into {}",
+        stats.bands_removed, end
+    ));
+    Ok(())
+}
+
+fn backup(subm: &ArgMatches) -> Result<()> {
+    let result = backup_to_archive(subm);
+    if subm.is_present("notify") {
+        #[cfg(feature = "notify")]
+        notify_backup_result(&result);
+        #[cfg(not(feature = "notify"))]
+        tracing::warn!("This build of Conserve can't show desktop notifications");
+    }
+    send_report(subm, "backup", &result);
+    update_state_file(
+        subm,
+        "backup",
+        &result,
+        result.as_ref().map_or(0, |s| s.problems.len() as u64),
+    );
+    if result.is_ok() {
+        replicate_after_backup(subm);
+    }
+    result.map(|_| ())
+}
+
+fn backup_to_archive(subm: &ArgMatches) -> Result<stats::BackupStats> {
+    let source = subm.value_of("source").unwrap();
+    let archive_path = subm.value_of("archive").unwrap();
+    let _span = backup_span(Path::new(source), Path::new(archive_path)).entered();
+    let start = Instant::now();
+    let archive = archive_from_options(subm)?
+        .with_deterministic(subm.is_present("deterministic"))
+        .with_layered_indexes(subm.is_present("layered"));
+    let snapshot = if subm.is_present("snapshot") {
+        Some(Snapshot::create(Path::new(source))?)
+    } else {
+        None
+    };
+    let mut lt = match &snapshot {
+        Some(snapshot) => live_tree_at(subm, snapshot.path())?,
+        None => live_tree_from_options(subm)?,
+    };
+    if subm.is_present("rsync-slash") && !source.ends_with('/') {
+        // Store the source directory itself, under its own name.
+        let source_path = Path::new(source);
+        let name = source_path
+            .canonicalize()
+            .ok()
+            .and_then(|p| p.file_name().map(|n| n.to_string_lossy().into_owned()))
+            .ok_or_else(|| Error::NoSourceDirName {
+                path: source_path.to_path_buf(),
+            })?;
+        lt = lt.with_source_dir_name(&name);
+    }
+    if subm.is_present("trust-dir-mtimes") {
+        lt = lt.with_trusted_dir_mtimes(&archive)?;
+    }
+    let source_path = if snapshot.is_some() {
+        Path::new(source)
+    } else {
+        lt.path()
+    };
+    let warn_free_space = subm
+        .value_of("warn-free-space")
+        .map(|s| s.parse().expect("warn-free-space was validated"));
+    let min_free_space = subm
+        .value_of("min-free-space")
+        .map(|s| s.parse().expect("min-free-space was validated"));
+    let warned = check_free_space(archive.path(), warn_free_space, min_free_space)?;
+    let mut bw = BackupWriter::begin_with_source_path(&archive, Some(source_path))?
+        .with_paranoid(subm.is_present("paranoid"))
+        .with_content_index(subm.is_present("content-index"))
+        .with_free_space(warn_free_space.filter(|_| !warned), min_free_space);
+    if let Some(s) = subm.value_of("small-file-size") {
+        bw = bw.with_small_file_size(s.parse().expect("small-file-size was validated"));
+    }
+    if let Some(path) = subm.value_of_os("scan-cache") {
+        bw = bw.with_scan_cache(Path::new(path))?;
+    }
+    let opts = CopyOptions {
+        print_filenames: subm.is_present("v"),
+        filter: filter_from_option(subm)?,
+        stop: Some(conserve::interrupt::stop_on_signals()?),
+        profile_entries: subm
+            .value_of("profile-entries")
+            .map(|s| s.parse().expect("profile-entries was validated")),
+        ..CopyOptions::default()
+    };
+    let copy_stats =
+        copy_tree(&lt, bw, &opts).map_err(|e| explain_interrupted_backup(&archive, e))?;
+    tracing::info!("Backup complete.");
+    if !subm.is_present("escalate-command")
+        && !copy_stats.problems.permission_denied_summary().is_empty()
+    {
+        tracing::warn!(
+            "To back up entries that aren't readable by this user, \
+             use for example --escalate-command 'sudo -n'"
+        );
+    }
+    if ui::verbosity() > ui::Verbosity::Quiet {
+        copy_stats.summarize_backup(&mut std::io::stdout());
+    }
+    if subm.is_present("profile-entries") {
+        copy_stats.entry_profile.summarize(&mut std::io::stdout())?;
+    }
+    summarize_problems(subm, &copy_stats.problems)?;
+    if let Some(path) = subm.value_of("metrics-textfile") {
+        // Write atomically so the collector never sees a partial file.
+        let path = Path::new(path);
+        AtomicFile::new(path)
+            .and_then(|mut f| {
+                stats::write_backup_metrics(
+                    &copy_stats,
+                    start.elapsed(),
+                    SystemTime::now(),
+                    &mut f,
+                )?;
+                f.close()
+            })
+            .map_err(|source| Error::WriteMetrics {
+                path: path.to_path_buf(),
+                source,
+            })?;
+    }
+    record_stats(subm, &copy_stats)?;
+    Ok(copy_stats)
+}
+
+/// Post a desktop notification summarizing the result of a backup.
+///
+/// Failing to notify is only a warning, because the backup itself is done.
+#[cfg(feature = "notify")]
+fn notify_backup_result(result: &Result<stats::BackupStats>) {
+    let (summary, body) = match result {
+        Ok(stats) if stats.errors > 0 => (
+            "Backup completed with errors",
+            format!("{} files, {} errors", stats.files, stats.errors),
+        ),
+        Ok(stats) => (
+            "Backup complete",
+            format!(
+                "{} files, {} new or modified",
+                stats.files,
+                stats.new_files + stats.modified_files
+            ),
+        ),
+        Err(e) => ("Backup failed", e.to_string()),
+    };
+    if let Err(e) = notify_rust::Notification::new()
+        .appname("Conserve")
+        .summary(summary)
+        .body(&body)
+        .show()
+    {
+        tracing::warn!("Failed to show notification: {}", e);
+    }
+}
+
+fn band_info(subm: &ArgMatches) -> Result<()> {
+    use conserve::output::ShowArchive;
+    let archive = archive_from_options(subm)?;
+    let band_id = match band_id_from_option(&archive, subm)? {
+        Some(b) => b,
+        None => archive.last_band_id()?.ok_or(Error::ArchiveEmpty)?,
+    };
+    let band = Band::open(&archive, &band_id)?;
+    output::BandInfo::new(&band).show_archive(&archive)
+}
+
+fn blockdir_stats(subm: &ArgMatches) -> Result<()> {
+    let archive = archive_from_options(subm)?;
+    let stats = archive.block_dir().stats()?;
+    ui::clear_progress();
+    stats.summarize(&mut std::io::stdout(), subm.is_present("subdirs"))?;
+    if stats.has_anomalies() {
+        tracing::warn!("The block directory contains unexpected files");
+    }
+    record_stats(subm, &stats)
+}
+
+/// After a backup is interrupted, say what was kept.
+fn explain_interrupted_backup(archive: &Archive, error: Error) -> Error {
+    if let (Error::Interrupted, Ok(Some(band_id))) = (&error, archive.last_band_id()) {
+        tracing::warn!(
+            "Version {} is incomplete, holding what was stored before the interruption. \
+             Its files can be restored with --incomplete, and the next backup will reuse \
+             the blocks already stored.",
+            band_id
+        );
+    }
+    error
+}
+
+fn import_tar(subm: &ArgMatches) -> Result<()> {
+    let archive = archive_from_options(subm)?;
+    let tar_tree = TarTree::open(subm.value_of("tarfile").unwrap())?
+        .with_excludes(excludes_from_option(subm)?);
     let bw = BackupWriter::begin(&archive)?;
     let opts = CopyOptions {
         print_filenames: subm.is_present("v"),
+        stop: Some(conserve::interrupt::stop_on_signals()?),
         ..CopyOptions::default()
     };
-    let copy_stats = copy_tree(&lt, bw, &opts)?;
-    ui::println("Backup complete.");
-    copy_stats.summarize_backup(&mut std::io::stdout());
-    // ui::println(&format!("{:#?}", copy_stats));
-    Ok(())
+    let copy_stats =
+        copy_tree(&tar_tree, bw, &opts).map_err(|e| explain_interrupted_backup(&archive, e))?;
+    tracing::info!("Import complete.");
+    if ui::verbosity() > ui::Verbosity::Quiet {
+        copy_stats.summarize_backup(&mut std::io::stdout());
+    }
+    summarize_problems(subm, &copy_stats.problems)?;
+    record_stats(subm, &copy_stats)
+}
+
+fn cp(subm: &ArgMatches) -> Result<()> {
+    let lt = live_tree_from_options(subm)?;
+    let dest = Path::new(subm.value_of("destination").unwrap());
+    let rt = if subm.is_present("force-overwrite") {
+        RestoreTree::create_overwrite(dest)
+    } else {
+        RestoreTree::create(dest)
+    }?;
+    let opts = CopyOptions {
+        print_filenames: subm.is_present("v"),
+        ..CopyOptions::default()
+    };
+    let copy_stats = copy_tree(&lt, rt, &opts)?;
+    tracing::info!("Copy complete.");
+    if ui::verbosity() > ui::Verbosity::Quiet {
+        copy_stats.summarize_restore(&mut std::io::stdout())?;
+    }
+    summarize_problems(subm, &copy_stats.problems)?;
+    record_stats(subm, &copy_stats)
 }
 
 fn diff(subm: &ArgMatches) -> Result<()> {
@@ -306,47 +1845,503 @@ fn diff(subm: &ArgMatches) -> Result<()> {
     // TODO: Consider whether the actual files have changed.
     // TODO: Summarize diff.
     // TODO: Optionally include unchanged files.
-    let st = stored_tree_from_options(subm)?;
+    let st =
+        stored_tree_from_options(subm)?.ignore_dir_mtimes(subm.is_present("ignore-dir-mtimes"));
+    if !subm.is_present("source") {
+        for change in st.changes()? {
+            let line = format!("{:<8} {}", change.kind.name(), change.apath);
+            ui::println(&match change.kind {
+                ChangeKind::Added => ui::paint(ui::Highlight::Added, &line),
+                ChangeKind::Changed => line,
+                ChangeKind::Deleted => ui::paint(ui::Highlight::Removed, &line),
+            });
+        }
+        return Ok(());
+    }
     let lt = live_tree_from_options(subm)?;
     for e in conserve::iter_merged_entries(&st, &lt)? {
         use MergedEntryKind::*;
-        let ks = match e.kind {
-            LeftOnly => "left",
-            RightOnly => "right",
-            Both => "both",
+        let line = match e.kind() {
+            LeftOnly => ui::paint(
+                ui::Highlight::Removed,
+                &format!("{:<8} {}", "left", e.apath()),
+            ),
+            RightOnly => ui::paint(
+                ui::Highlight::Added,
+                &format!("{:<8} {}", "right", e.apath()),
+            ),
+            Both => format!("{:<8} {}", "both", e.apath()),
         };
-        ui::println(&format!("{:<8} {}", ks, e.apath));
+        ui::println(&line);
     }
     // TODO: Show stats.
     Ok(())
 }
 
+fn doctor(subm: &ArgMatches) -> Result<()> {
+    use conserve::doctor::{DoctorOptions, Severity};
+    let mut options = DoctorOptions::new(subm.value_of_os("archive").unwrap());
+    if let Some(source) = subm.value_of_os("source") {
+        options = options.source(source);
+    }
+    if let Some(bytes) = subm.value_of("warn-free-space") {
+        options = options.warn_free_space(bytes.parse().expect("bytes were validated"));
+    }
+    let findings = options.run();
+    for finding in &findings {
+        if subm.is_present("json") {
+            ui::println(&serde_json::to_string(finding).expect("serialize finding"));
+            continue;
+        }
+        let severity = format!("{:<8}", finding.severity);
+        let severity = match finding.severity {
+            Severity::Ok => severity,
+            Severity::Warning => ui::paint(ui::Highlight::Warning, &severity),
+            Severity::Problem => ui::paint(ui::Highlight::Error, &severity),
+        };
+        ui::println(&format!(
+            "{}{:<12} {}",
+            severity, finding.check, finding.message
+        ));
+        if let Some(advice) = &finding.advice {
+            ui::println(&format!("{:20} {}", "", advice));
+        }
+    }
+    match findings
+        .iter()
+        .filter(|f| f.severity == Severity::Problem)
+        .count()
+    {
+        0 => Ok(()),
+        count => Err(Error::DoctorFoundProblems { count }),
+    }
+}
+
 fn validate(subm: &ArgMatches) -> Result<()> {
-    let archive = Archive::open(subm.value_of("archive").unwrap())?;
-    let validate_stats = archive.validate()?;
-    // ui::println(&format!("{:#?}", validate_stats));
+    let result = validate_archive(subm);
+    send_report(subm, "validate", &result);
+    update_state_file(
+        subm,
+        "validate",
+        &result,
+        result.as_ref().map_or(0, |s| {
+            s.block_dir_stats.block_error_count + s.index_order_errors
+        }),
+    );
+    result.map(|_| ())
+}
+
+fn validate_archive(subm: &ArgMatches) -> Result<stats::ValidateArchiveStats> {
+    let archive = archive_from_options(subm)?;
+    let excludes = excludes_from_option(subm)?;
+    let band_id = band_id_from_option(&archive, subm)?;
+    let jobs = subm.value_of("jobs").map(|s| s.parse().unwrap());
+    let since = subm
+        .value_of("since")
+        .map(conserve::parse_age)
+        .transpose()?;
+    let scrub_budget = subm
+        .value_of("scrub-budget")
+        .map(conserve::parse_age)
+        .transpose()?;
+    let cache_path = match subm.value_of_os("validation-cache") {
+        Some(path) => Some(PathBuf::from(path)),
+        None if since.is_some() || scrub_budget.is_some() => {
+            Some(ValidationCache::sidecar_path(archive.path()))
+        }
+        None => None,
+    };
+    let mut validate_stats = conserve::in_thread_pool(jobs, || match (&band_id, cache_path) {
+        (Some(band_id), _) => archive.validate_band(band_id, excludes),
+        (None, Some(cache_path)) => {
+            let mut cache = ValidationCache::open(&cache_path)?;
+            archive.validate_with_cache(excludes, &mut cache, since, scrub_budget)
+        }
+        (None, None) => archive.validate_with_excludes(excludes),
+    })??;
+    if subm.is_present("fix-index-order") && validate_stats.index_order_errors > 0 {
+        validate_stats.indexes_rewritten = archive.fix_index_order(band_id.as_ref())?;
+    }
     validate_stats.summarize(&mut std::io::stdout())?;
-    Ok(())
+    record_stats(subm, &validate_stats)?;
+    Ok(validate_stats)
+}
+
+fn archive_stats(subm: &ArgMatches) -> Result<()> {
+    use conserve::output::ShowArchive;
+    output::ArchiveStats::default()
+        .history(subm.is_present("history"))
+        .csv(subm.is_present("csv"))
+        .show_archive(&archive_from_options(subm)?)
 }
 
 fn versions(subm: &ArgMatches) -> Result<()> {
     use conserve::output::ShowArchive;
-    let archive = Archive::open(subm.value_of("archive").unwrap())?;
+    let archive = archive_from_options(subm)?;
     if subm.is_present("short") {
         output::ShortVersionList::default().show_archive(&archive)
     } else {
         output::VerboseVersionList::default()
             .show_sizes(subm.is_present("sizes"))
+            .show_changes(subm.is_present("changes"))
             .show_archive(&archive)
     }
 }
 
+fn watch(subm: &ArgMatches) -> Result<()> {
+    let mut backup = BackupOptions::new(
+        subm.value_of("source").unwrap(),
+        subm.value_of("archive").unwrap(),
+    )
+    .print_filenames(subm.is_present("v"))
+    .include_archives(subm.is_present("include-archives"))
+    .snapshot(subm.is_present("snapshot"))
+    .ntfs_metadata(subm.is_present("ntfs-metadata"))
+    .statx_metadata(subm.is_present("statx-metadata"))
+    .dereference(subm.is_present("dereference"));
+    if let Some(tree) = subm.value_of("tree") {
+        backup = backup.tree(tree);
+    }
+    if let Some(path) = subm.value_of("key") {
+        backup = backup.signing_key_file(path);
+    }
+    for pattern in exclude_patterns_from_option(subm)? {
+        backup = backup.exclude(&pattern);
+    }
+    for name in subm.values_of("exclude-if-present").into_iter().flatten() {
+        backup = backup.exclude_if_present(name);
+    }
+    let seconds = |name| {
+        subm.value_of(name)
+            .map(|s| Duration::from_secs_f64(s.parse().expect("seconds were validated")))
+    };
+    let mut options = WatchOptions::new(backup);
+    if let Some(poll_interval) = seconds("poll-interval") {
+        options = options.poll_interval(poll_interval);
+    }
+    if let Some(quiet_period) = seconds("quiet-period") {
+        options = options.quiet_period(quiet_period);
+    }
+    if let Some(max_delay) = seconds("max-delay") {
+        options = options.max_delay(max_delay);
+    }
+    if let Some(max_backups) = subm.value_of("max-backups") {
+        options = options.max_backups(max_backups.parse().unwrap());
+    }
+    options.run(|result| {
+        match &result {
+            Ok(stats) => {
+                tracing::info!("Backup complete.");
+                if ui::verbosity() > ui::Verbosity::Quiet {
+                    stats.summarize_backup(&mut std::io::stdout());
+                }
+                if let Err(err) = summarize_problems(subm, &stats.problems) {
+                    ui::show_error(&err);
+                }
+            }
+            Err(err) => ui::show_error(err),
+        }
+        send_report(subm, "backup", &result);
+        update_state_file(
+            subm,
+            "backup",
+            &result,
+            result.as_ref().map_or(0, |s| s.problems.len() as u64),
+        );
+        if result.is_ok() {
+            replicate_after_backup(subm);
+        }
+    })
+}
+
+fn explain_excludes(subm: &ArgMatches) -> Result<()> {
+    let patterns = exclude_patterns_from_option(subm)?;
+    let markers: Vec<&str> = subm
+        .values_of("exclude-if-present")
+        .into_iter()
+        .flatten()
+        .collect();
+    let lt = live_tree_from_options(subm)?.with_exclusions_recorded();
+    ui::set_progress_phase(&"Scanning".to_string());
+    lt.iter_entries()?.for_each(drop);
+    ui::clear_progress();
+    for exclusion in lt.take_exclusions() {
+        let reason = match exclusion.reason {
+            ExclusionReason::Pattern(i) => format!("excluded by {}", patterns[i]),
+            ExclusionReason::Archive => "Conserve archive".to_owned(),
+            ExclusionReason::Marker(i) => format!("contains {}", markers[i]),
+        };
+        ui::println(&format!("{:<40} {}", exclusion.apath.to_string(), reason));
+    }
+    Ok(())
+}
+
+fn find(subm: &ArgMatches) -> Result<()> {
+    let archive = archive_from_options(subm)?;
+    let found = find_content(&archive, subm.value_of("hash").unwrap())?;
+    for (band_id, entry) in &found.matches {
+        ui::println(&format!("{} {}", band_id, entry.apath));
+    }
+    if !found.unindexed_bands.is_empty() {
+        tracing::warn!(
+            "{} versions have no content index and weren't searched; \
+             index them with `conserve content-index`",
+            found.unindexed_bands.len()
+        );
+    }
+    Ok(())
+}
+
+fn content_index(subm: &ArgMatches) -> Result<()> {
+    let archive = archive_from_options(subm)?;
+    let band_ids = match band_id_from_option(&archive, subm)? {
+        Some(band_id) => vec![band_id],
+        None => unindexed_bands(&archive)?,
+    };
+    for band_id in band_ids {
+        let files = build_content_index(&archive, &band_id)?;
+        ui::println(&format!("Indexed {} files in {}", files, band_id));
+    }
+    Ok(())
+}
+
+fn dupes(subm: &ArgMatches) -> Result<()> {
+    let st = stored_tree_from_options(subm)?;
+    let sets = find_duplicates(&st)?;
+    let mut total = 0;
+    for set in &sets {
+        ui::println(&format!(
+            "{} files of {} each, {} redundant{}: {}",
+            set.apaths.len(),
+            bytes_to_human(set.size),
+            bytes_to_human(set.redundant_bytes()),
+            if set.stored_once { ", shared" } else { "" },
+            set.hash,
+        ));
+        for apath in &set.apaths {
+            ui::println(&format!("    {}", apath));
+        }
+        total += set.redundant_bytes();
+    }
+    ui::println(&format!(
+        "{} sets of identical files, {} redundant",
+        sets.len(),
+        bytes_to_human(total)
+    ));
+    Ok(())
+}
+
+fn compare_archives(subm: &ArgMatches) -> Result<()> {
+    let tree = subm.value_of("tree");
+    let left = Archive::open_tree(subm.value_of("left").unwrap(), tree)?;
+    let right = Archive::open_tree(subm.value_of("right").unwrap(), tree)?;
+    let comparison = conserve::compare_archives(&left, &right)?;
+    let mut lines = Vec::new();
+    lines.extend(comparison.left_only_bands.iter().map(|b| {
+        ui::paint(
+            ui::Highlight::Removed,
+            &format!("{:<10} band {}", "left", b),
+        )
+    }));
+    lines.extend(
+        comparison
+            .right_only_bands
+            .iter()
+            .map(|b| ui::paint(ui::Highlight::Added, &format!("{:<10} band {}", "right", b))),
+    );
+    lines.extend(
+        comparison
+            .unfinished_bands
+            .iter()
+            .map(|b| format!("{:<10} band {}", "unfinished", b)),
+    );
+    lines.extend(comparison.left_only_blocks.iter().map(|h| {
+        ui::paint(
+            ui::Highlight::Removed,
+            &format!("{:<10} block {}", "left", h),
+        )
+    }));
+    lines.extend(comparison.right_only_blocks.iter().map(|h| {
+        ui::paint(
+            ui::Highlight::Added,
+            &format!("{:<10} block {}", "right", h),
+        )
+    }));
+    for line in lines {
+        ui::println(&line);
+    }
+    if !comparison.is_same() {
+        return Err(Error::ArchivesDiffer {
+            differences: comparison.difference_count(),
+        });
+    }
+    if ui::verbosity() > ui::Verbosity::Quiet {
+        ui::println(&format!(
+            "Archives match: {} versions and {} blocks.",
+            comparison.common_bands, comparison.common_blocks
+        ));
+    }
+    Ok(())
+}
+
+fn push(subm: &ArgMatches) -> Result<()> {
+    let mut options = PushOptions::new(
+        subm.value_of("archive").unwrap(),
+        subm.value_of("url").unwrap(),
+    );
+    if let Some(tree) = subm.value_of("tree") {
+        options = options.tree(tree);
+    }
+    if let Some(source) = credential_source(subm, "token", Some("Token")) {
+        options = options.token(source.get()?.expose());
+    }
+    if let Some(limit) = subm.value_of("bandwidth-limit") {
+        options = options.bandwidth_limit(limit.parse().unwrap());
+    }
+    if let Some(batch_size) = subm.value_of("batch-size") {
+        options = options.batch_size(batch_size.parse().unwrap());
+    }
+    if let Some(path) = subm.value_of("block-cache") {
+        options = options.block_cache(path);
+    }
+    let stats = options.run()?;
+    if ui::verbosity() > ui::Verbosity::Quiet {
+        stats.summarize(&mut std::io::stdout())?;
+    }
+    record_stats(subm, &stats)
+}
+
+fn replicate(subm: &ArgMatches) -> Result<()> {
+    let results = replicate_options(
+        subm,
+        subm.value_of("config").unwrap(),
+        subm.value_of("state-file"),
+    )?
+    .force(subm.is_present("force"))
+    .run()?;
+    show_mirror_results(&results);
+    let failed: Vec<String> = results
+        .into_iter()
+        .filter(|result| matches!(result.outcome, MirrorOutcome::Failed(_)))
+        .map(|result| result.name)
+        .collect();
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::Replicate { mirrors: failed })
+    }
+}
+
+/// Replicate to the mirrors given by `--replicate`, if any.
+///
+/// Failures are only warnings, because the backup itself succeeded. What's
+/// been replicated is recorded in the default `CONFIG.state`, since
+/// `--state-file` here is the monitoring state of the backup.
+fn replicate_after_backup(subm: &ArgMatches) {
+    if let Some(config) = subm.value_of("replicate") {
+        match replicate_options(subm, config, None).and_then(|options| options.run()) {
+            Ok(results) => show_mirror_results(&results),
+            Err(e) => tracing::warn!("Failed to replicate: {}", e),
+        }
+    }
+}
+
+fn replicate_options(
+    subm: &ArgMatches,
+    config_path: &str,
+    state_file: Option<&str>,
+) -> Result<ReplicateOptions> {
+    let config = ReplicateConfig::load(Path::new(config_path))?;
+    let state_file = match state_file {
+        Some(path) => path.to_owned(),
+        None => format!("{}.state", config_path),
+    };
+    let mut options =
+        ReplicateOptions::new(subm.value_of("archive").unwrap(), config).state_file(state_file);
+    if let Some(tree) = subm.value_of("tree") {
+        options = options.tree(tree);
+    }
+    Ok(options)
+}
+
+fn show_mirror_results(results: &[MirrorResult]) {
+    for result in results {
+        let description = match &result.outcome {
+            MirrorOutcome::Pushed(stats) => format!(
+                "pushed {} bands and {} MB in {} blocks",
+                stats.bands,
+                bytes_to_human_mb(stats.block_bytes),
+                stats.blocks
+            ),
+            MirrorOutcome::UpToDate => "up to date".to_owned(),
+            MirrorOutcome::NotDue => "not due".to_owned(),
+            MirrorOutcome::Failed(err) => format!("failed: {}", err),
+        };
+        ui::println(&format!("{:<20} {}", result.name, description));
+    }
+}
+
+/// Where to get a secret from the `--{name}-file`, `--{name}-env`,
+/// `--{name}-command` and, if there's a prompt, `--ask-{name}` options.
+fn credential_source(
+    subm: &ArgMatches,
+    name: &str,
+    prompt: Option<&str>,
+) -> Option<CredentialSource> {
+    let value = |suffix: &str| subm.value_of(format!("{}-{}", name, suffix));
+    if let Some(path) = value("file") {
+        Some(CredentialSource::File(path.into()))
+    } else if let Some(var) = value("env") {
+        Some(CredentialSource::Env(var.to_owned()))
+    } else if let Some(command) = value("command") {
+        Some(CredentialSource::Command(command.to_owned()))
+    } else {
+        prompt
+            .filter(|_| subm.is_present(format!("ask-{}", name)))
+            .map(|prompt| CredentialSource::Prompt(prompt.to_owned()))
+    }
+}
+
+fn serve(subm: &ArgMatches) -> Result<()> {
+    let archive = archive_from_options(subm)?;
+    let mut server = Server::bind(archive, subm.value_of("listen").unwrap())?;
+    if let Some(source) = credential_source(subm, "push-token", None) {
+        server = server.with_push_token(source.get()?.expose());
+    }
+    if let Some(quota) = subm.value_of("quota") {
+        server = server.with_quota(quota.parse().unwrap())?;
+    }
+    tracing::info!("Serving archive on http://{}/", server.local_addr()?);
+    server.run()
+}
+
 fn source_ls(subm: &ArgMatches) -> Result<()> {
     let lt = live_tree_from_options(subm)?;
-    list_tree_contents(&lt)?;
+    list_entries(lt.iter_entries()?, None);
     Ok(())
 }
 
+fn source_helper(subm: &ArgMatches) -> Result<()> {
+    let path = Path::new(subm.value_of("path").unwrap());
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+    match subm.value_of("operation").unwrap() {
+        "list" => conserve::source_helper::serve_list(path, &mut out).map_err(|source| {
+            Error::ListSourceTree {
+                path: path.to_path_buf(),
+                source,
+            }
+        }),
+        "cat" => conserve::source_helper::serve_cat(path, &mut out).map_err(|source| {
+            Error::ReadSourceFile {
+                path: path.to_path_buf(),
+                source,
+            }
+        }),
+        _ => unreachable!("operation was validated"),
+    }
+}
+
 fn source_size(subm: &ArgMatches) -> Result<()> {
     let source = live_tree_from_options(subm)?;
     ui::set_progress_phase(&"Measuring".to_string());
@@ -354,44 +2349,187 @@ fn source_size(subm: &ArgMatches) -> Result<()> {
     Ok(())
 }
 
-fn ls(subm: &ArgMatches) -> Result<()> {
+fn du(subm: &ArgMatches) -> Result<()> {
     let st = stored_tree_from_options(subm)?;
-    list_tree_contents(&st)?;
+    let top = subm.value_of("top").unwrap().parse().unwrap();
+    ui::set_progress_phase(&"Measuring".to_owned());
+    let usage = match subm.value_of("subtree") {
+        Some(subtree) => {
+            if !Apath::is_valid(subtree) {
+                return Err(Error::InvalidApath {
+                    apath: subtree.to_owned(),
+                });
+            }
+            DiskUsage::measure(st.iter_subtree(&subtree.into())?, top)
+        }
+        None => DiskUsage::measure(st.iter_entries()?, top),
+    };
+    for (title, sizes) in &[
+        ("Largest files:", usage.files),
+        ("Largest directories:", usage.dirs),
+    ] {
+        ui::println(title);
+        for (apath, size) in sizes {
+            ui::println(&format!("{:>10}  {}", bytes_to_human(*size), apath));
+        }
+    }
+    if !st.is_closed()? {
+        ui::eprintln(&format!(
+            "Version {} is incomplete: some entries may be missing",
+            st.band().id()
+        ));
+    }
     Ok(())
 }
 
-fn list_tree_contents<T: ReadTree>(tree: &T) -> Result<()> {
+fn ls(subm: &ArgMatches) -> Result<()> {
+    let st = stored_tree_from_options(subm)?;
+    let filter = filter_from_option(subm)?;
+    if subm.is_present("tree-view") {
+        use conserve::output::ShowArchive;
+        let subtree = subm.value_of("subtree").unwrap_or("/");
+        if !Apath::is_valid(subtree) {
+            return Err(Error::InvalidApath {
+                apath: subtree.to_owned(),
+            });
+        }
+        output::TreeListing::new(&st, subtree.into()).show_archive(st.archive())?;
+        if !st.is_closed()? {
+            ui::eprintln(&format!(
+                "Version {} is incomplete: some entries may be missing",
+                st.band().id()
+            ));
+        }
+        return Ok(());
+    }
+    match subm.value_of("subtree") {
+        Some(subtree) => {
+            if !Apath::is_valid(subtree) {
+                return Err(Error::InvalidApath {
+                    apath: subtree.to_owned(),
+                });
+            }
+            list_entries(st.iter_subtree(&subtree.into())?, filter.as_ref());
+            if !st.is_closed()? {
+                ui::eprintln(&format!(
+                    "Version {} is incomplete: some entries may be missing",
+                    st.band().id()
+                ));
+            }
+            Ok(())
+        }
+        None => {
+            let last_apath = list_entries(st.iter_entries()?, filter.as_ref());
+            show_incomplete_marker(&st, last_apath)
+        }
+    }
+}
+
+/// List entries matching the filter, if any, and return the last apath read.
+fn list_entries<E: Entry>(
+    entries: impl Iterator<Item = E>,
+    filter: Option<&Filter>,
+) -> Option<Apath> {
     // TODO: Maybe should be a specific concept in the UI.
     // TODO: Perhaps writing them one at a time causes too much locking
     // or bad buffering. Perhaps we can write to a BufferedWriter, making
     // sure that the progress bar is disabled.
-    for entry in tree.iter_entries()? {
-        ui::println(&entry.apath());
+    let mut last_apath = None;
+    for entry in entries {
+        if !filter.map_or(true, |f| f.matches(&entry)) {
+            last_apath = Some(entry.apath().clone());
+            continue;
+        }
+        let apath: &str = entry.apath();
+        match entry.kind() {
+            Kind::Dir => ui::println(&ui::paint(ui::Highlight::Directory, apath)),
+            Kind::Symlink => ui::println(&ui::paint(ui::Highlight::Symlink, apath)),
+            _ => ui::println(apath),
+        }
+        last_apath = Some(entry.apath().clone());
+    }
+    last_apath
+}
+
+/// If the stored tree is incomplete, say on stderr where its index stops.
+fn show_incomplete_marker(st: &StoredTree, last_apath: Option<Apath>) -> Result<()> {
+    if !st.is_closed()? {
+        match last_apath {
+            Some(apath) => ui::eprintln(&format!(
+                "Version {} is incomplete: index stops after {}",
+                st.band().id(),
+                apath
+            )),
+            None => ui::eprintln(&format!(
+                "Version {} is incomplete: index is empty",
+                st.band().id()
+            )),
+        }
     }
     Ok(())
 }
 
 fn restore(subm: &ArgMatches) -> Result<()> {
-    let dest = Path::new(subm.value_of("destination").unwrap());
     let st = stored_tree_from_options(subm)?;
+    let dest = match subm.value_of("destination") {
+        Some(d) => Path::new(d),
+        None => {
+            return Err(Error::NoRestoreDestination {
+                source_path: st.band().get_info()?.source_path,
+            })
+        }
+    };
+    let _span = restore_span(st.archive().path(), dest).entered();
+    let mut path_maps = Vec::new();
+    for map in subm.values_of("map").into_iter().flatten() {
+        path_maps.push(parse_path_map(map)?);
+    }
+    let id_maps = |name| -> Result<HashMap<u32, u32>> {
+        subm.values_of(name)
+            .into_iter()
+            .flatten()
+            .map(parse_id_map)
+            .collect()
+    };
     let rt = if subm.is_present("force-overwrite") {
         RestoreTree::create_overwrite(dest)
     } else {
         RestoreTree::create(dest)
-    }?;
+    }?
+    .with_path_maps(path_maps)
+    .with_user_map(id_maps("map-user")?)
+    .with_group_map(id_maps("map-group")?)
+    .with_escaping_symlinks(match subm.value_of("escaping-symlinks") {
+        Some("warn") => EscapingSymlinks::Warn,
+        Some("refuse") => EscapingSymlinks::Refuse,
+        _ => EscapingSymlinks::Allow,
+    });
     let opts = CopyOptions {
         print_filenames: subm.is_present("v"),
+        // Measuring a stored tree only reads its index, and gives the
+        // progress bar a total.
+        measure_first: true,
+        filter: filter_from_option(subm)?,
+        stop: Some(conserve::interrupt::stop_on_signals()?),
         ..CopyOptions::default()
     };
     let copy_stats = copy_tree(&st, rt, &opts)?;
-    ui::println("Restore complete.");
-    copy_stats.summarize_restore(&mut std::io::stdout())?;
-    // ui::println(&format!("{:#?}", copy_stats));
-    Ok(())
+    if !st.is_closed()? {
+        tracing::warn!(
+            "Restored from incomplete version {}: some files may be missing",
+            st.band().id()
+        );
+    }
+    tracing::info!("Restore complete.");
+    if ui::verbosity() > ui::Verbosity::Quiet {
+        copy_stats.summarize_restore(&mut std::io::stdout())?;
+    }
+    summarize_problems(subm, &copy_stats.problems)?;
+    record_stats(subm, &copy_stats)
 }
 
 fn debug_block_list(subm: &ArgMatches) -> Result<()> {
-    let archive = Archive::open(subm.value_of("archive").unwrap())?;
+    let archive = archive_from_options(subm)?;
     for b in archive.block_dir().block_names()? {
         println!("{}", b);
     }
@@ -399,7 +2537,7 @@ fn debug_block_list(subm: &ArgMatches) -> Result<()> {
 }
 
 fn debug_block_referenced(subm: &ArgMatches) -> Result<()> {
-    let archive = Archive::open(subm.value_of("archive").unwrap())?;
+    let archive = archive_from_options(subm)?;
     for h in archive.referenced_blocks()? {
         ui::println(&h);
     }
@@ -408,7 +2546,7 @@ fn debug_block_referenced(subm: &ArgMatches) -> Result<()> {
 
 fn debug_index_dump(subm: &ArgMatches) -> Result<()> {
     use conserve::output::ShowArchive;
-    let archive = Archive::open(subm.value_of("archive").unwrap())?;
+    let archive = archive_from_options(subm)?;
     let st = stored_tree_from_options(subm)?;
     output::IndexDump::new(st.band()).show_archive(&archive)
 }
@@ -420,10 +2558,44 @@ fn tree_size(subm: &ArgMatches) -> Result<()> {
     Ok(())
 }
 
-fn stored_tree_from_options(subm: &ArgMatches) -> Result<StoredTree> {
+fn trash_empty(subm: &ArgMatches) -> Result<()> {
+    let removed = archive_with_deletion_secret(subm)?.empty_trash()?;
+    ui::println(&format!("Removed {} versions from the trash", removed));
+    Ok(())
+}
+
+fn trash_ls(subm: &ArgMatches) -> Result<()> {
+    for band_id in archive_from_options(subm)?.list_trash()? {
+        ui::println(&band_id.to_string());
+    }
+    Ok(())
+}
+
+fn trees(subm: &ArgMatches) -> Result<()> {
     let archive = Archive::open(subm.value_of("archive").unwrap())?;
-    let st = match band_id_from_option(subm)? {
-        None => StoredTree::open_last(&archive),
+    for tree_name in archive.list_trees()? {
+        ui::println(&tree_name);
+    }
+    Ok(())
+}
+
+fn undelete(subm: &ArgMatches) -> Result<()> {
+    let band_id = BandId::from_string(subm.value_of("backup").unwrap())?;
+    archive_from_options(subm)?.undelete(&band_id)?;
+    ui::println(&format!("Undeleted {}", band_id));
+    Ok(())
+}
+
+fn stored_tree_from_options(subm: &ArgMatches) -> Result<StoredTree> {
+    let archive = archive_from_options(subm)?;
+    let st = match band_id_from_option(&archive, subm)? {
+        None => {
+            if subm.is_present("incomplete") {
+                StoredTree::open_last_incomplete(&archive)
+            } else {
+                StoredTree::open_last(&archive)
+            }
+        }
         Some(ref b) => {
             if subm.is_present("incomplete") {
                 StoredTree::open_incomplete_version(&archive, b)
@@ -432,25 +2604,192 @@ fn stored_tree_from_options(subm: &ArgMatches) -> Result<StoredTree> {
             }
         }
     }?;
-    Ok(st.with_excludes(excludes_from_option(subm)?))
+    Ok(st
+        .with_excludes(excludes_from_option(subm)?)
+        .with_max_depth(max_depth_from_option(subm)))
+}
+
+#[cfg(unix)]
+fn validate_progress_fd(fd: String) -> std::result::Result<(), String> {
+    fd.parse::<i32>().map(|_| ()).map_err(|e| e.to_string())
+}
+
+#[cfg(not(unix))]
+fn validate_progress_fd(_fd: String) -> std::result::Result<(), String> {
+    Err("--progress-json is only supported on Unix".to_owned())
+}
+
+/// Take ownership of the file descriptor given to `--progress-json`, which
+/// the program running Conserve left open for writing.
+#[cfg(unix)]
+fn progress_json_file(fd: &str) -> std::fs::File {
+    use std::os::unix::io::FromRawFd;
+    let fd = fd.parse().expect("progress-json was validated");
+    // The descriptor isn't otherwise used by this process.
+    unsafe { std::fs::File::from_raw_fd(fd) }
+}
+
+#[cfg(not(unix))]
+fn progress_json_file(_fd: &str) -> std::fs::File {
+    unreachable!("progress-json was validated")
+}
+
+/// Open the archive, and select the tree named by `--tree`, if any.
+fn archive_from_options(subm: &ArgMatches) -> Result<Archive> {
+    let archive = Archive::open_tree(subm.value_of("archive").unwrap(), subm.value_of("tree"))?;
+    if let Some(path) = subm.value_of("key") {
+        archive.with_signing_key(SigningKey::load(Path::new(path))?)
+    } else if let Some(path) = subm.value_of("public-key") {
+        archive.with_public_key(PublicKey::load(Path::new(path))?)
+    } else {
+        Ok(archive)
+    }
 }
 
 fn live_tree_from_options(subm: &ArgMatches) -> Result<LiveTree> {
-    Ok(LiveTree::open(&subm.value_of("source").unwrap())?
-        .with_excludes(excludes_from_option(subm)?))
+    live_tree_at(subm, Path::new(subm.value_of("source").unwrap()))
 }
 
-fn band_id_from_option(subm: &ArgMatches) -> Result<Option<BandId>> {
-    match subm.value_of("backup") {
-        Some(b) => Ok(Some(BandId::from_string(b)?)),
-        None => Ok(None),
+/// Open the source tree at `path`, with exclusions from the options.
+fn live_tree_at(subm: &ArgMatches, path: &Path) -> Result<LiveTree> {
+    Ok(LiveTree::open(path)?
+        .with_excludes(excludes_from_option(subm)?)
+        .with_archives_included(subm.is_present("include-archives"))
+        .with_exclude_if_present(subm.values_of("exclude-if-present").into_iter().flatten())
+        .with_ntfs_metadata(subm.is_present("ntfs-metadata"))
+        .with_statx_metadata(subm.is_present("statx-metadata"))
+        .with_dereference(subm.is_present("dereference"))
+        .with_max_depth(max_depth_from_option(subm))
+        .with_source_helper(source_helper_from_options(subm)))
+}
+
+/// Make a source helper that runs this program under `--escalate-command`.
+fn source_helper_from_options(subm: &ArgMatches) -> Option<SourceHelper> {
+    let command = subm.value_of("escalate-command")?;
+    let exe = std::env::current_exe().expect("Failed to find the conserve executable");
+    let mut argv: Vec<std::ffi::OsString> = command.split_whitespace().map(Into::into).collect();
+    argv.extend([exe.into_os_string(), "source".into(), "helper".into()]);
+    Some(SourceHelper::new(argv))
+}
+
+/// Show a summary of the problems found while copying a tree, since they
+/// weren't shown as they happened.
+fn summarize_problems(subm: &ArgMatches, problems: &Problems) -> Result<()> {
+    const FIRST_PROBLEMS: usize = 10;
+    const TOP_DIRS: usize = 10;
+    if problems.is_empty() {
+        return Ok(());
+    }
+    problems.summarize(&mut std::io::stdout(), FIRST_PROBLEMS, TOP_DIRS)?;
+    if subm.is_present("log-file") {
+        tracing::info!("Every problem is listed in the log file");
+    } else {
+        tracing::info!("Use --log-file to record every problem");
+    }
+    Ok(())
+}
+
+/// Write stats to the log file, and to the file named by `--stats-json`, if any.
+fn record_stats<S: serde::Serialize>(subm: &ArgMatches, stats: &S) -> Result<()> {
+    if let Ok(json) = serde_json::to_string(stats) {
+        tracing::info!(target: ui::LOG_ONLY_TARGET, "Stats: {}", json);
+    }
+    if let Some(path) = subm.value_of("stats-json") {
+        let mut file =
+            std::fs::File::create(path).map_err(|source| Error::WriteStats { source })?;
+        stats::write_json(stats, &mut file)?;
+    }
+    Ok(())
+}
+
+/// Send a report of the result to any webhooks and notification commands.
+///
+/// Failing to send a report is only a warning, because the command itself is
+/// done.
+fn send_report<S: serde::Serialize>(subm: &ArgMatches, command: &str, result: &Result<S>) {
+    let webhooks: Vec<&str> = subm.values_of("webhook").into_iter().flatten().collect();
+    let commands: Vec<&str> = subm
+        .values_of("notify-command")
+        .into_iter()
+        .flatten()
+        .collect();
+    if webhooks.is_empty() && commands.is_empty() {
+        return;
+    }
+    let report = Report::new(command, subm.value_of("archive").unwrap(), result);
+    for url in webhooks {
+        if let Err(e) = report.send_webhook(url) {
+            tracing::warn!("Failed to send report: {}", e);
+        }
+    }
+    for notify_command in commands {
+        if let Err(e) = report.run_command(notify_command) {
+            tracing::warn!("Failed to send report: {}", e);
+        }
+    }
+}
+
+/// Record the result in the `--state-file`, if one was given.
+///
+/// Failing to write it is only a warning, because the command itself is done.
+fn update_state_file<S: serde::Serialize>(
+    subm: &ArgMatches,
+    command: &str,
+    result: &Result<S>,
+    error_count: u64,
+) {
+    let path = match subm.value_of_os("state-file") {
+        Some(path) => Path::new(path),
+        None => return,
+    };
+    let report = Report::new(command, subm.value_of("archive").unwrap(), result);
+    let band_id = archive_from_options(subm)
+        .and_then(|archive| archive.last_complete_band())
+        .ok()
+        .flatten()
+        .map(|band| band.id().clone());
+    if let Err(e) = RunState::update(path, &report, band_id.as_ref(), error_count) {
+        tracing::warn!("Failed to update state file: {}", ui::format_error(&e));
     }
 }
 
-/// Make an exclusion globset from the `--exclude` option.
+/// Find the band named by `--backup`, which may be like `latest~1`.
+fn band_id_from_option(archive: &Archive, subm: &ArgMatches) -> Result<Option<BandId>> {
+    subm.value_of("backup")
+        .map(|b| archive.resolve_band_id(b))
+        .transpose()
+}
+
+/// Make an exclusion globset from the `--exclude` and `--exclude-preset` options.
 fn excludes_from_option(subm: &ArgMatches) -> Result<globset::GlobSet> {
-    match subm.values_of("exclude") {
-        Some(excludes) => excludes::from_strings(excludes),
-        None => Ok(excludes::excludes_nothing()),
+    excludes::from_strings(exclude_patterns_from_option(subm)?)
+}
+
+fn filter_from_option(subm: &ArgMatches) -> Result<Option<Filter>> {
+    subm.value_of("filter").map(Filter::parse).transpose()
+}
+
+fn max_depth_from_option(subm: &ArgMatches) -> Option<usize> {
+    subm.value_of("max-depth")
+        .map(|s| s.parse().expect("max-depth was validated"))
+}
+
+/// List the patterns given by `--exclude`, followed by those in the presets
+/// named by `--exclude-preset`, which may be separated by commas.
+fn exclude_patterns_from_option(subm: &ArgMatches) -> Result<Vec<String>> {
+    let mut patterns: Vec<String> = subm
+        .values_of("exclude")
+        .into_iter()
+        .flatten()
+        .map(str::to_owned)
+        .collect();
+    for name in subm
+        .values_of("exclude-preset")
+        .into_iter()
+        .flatten()
+        .flat_map(|names| names.split(','))
+    {
+        patterns.extend(excludes::preset(name)?.iter().map(|p| (*p).to_owned()));
     }
+    Ok(patterns)
 }
@@ -0,0 +1,450 @@
+// Conserve backup system.
+// Copyright 2020 Martin Pool.
+
+//! A compact binary encoding for index hunks, as an alternative to json.
+//!
+//! Apaths are delta-encoded against the previous entry, integers are
+//! variable-length, and block hashes are stored as raw bytes rather than hex.
+//! Encoded hunks start with a magic prefix that can't begin a json hunk, so
+//! readers can tell the formats apart without any other configuration.
+//!
+//! The layout is described in `doc/format.md`.
+
+use std::convert::TryFrom;
+
+use serde::{Deserialize, Serialize};
+
+use crate::blockdir::Address;
+use crate::*;
+
+/// The start of every binary index hunk, followed by a version byte.
+pub(crate) const MAGIC: &[u8] = b"\0CBI";
+
+const VERSION: u8 = 1;
+
+/// The version of hunks where entries are followed by extra metadata, such as
+/// NTFS or `statx` metadata. Hunks without any are written as version 1, so
+/// that older readers can read them.
+const VERSION_WITH_EXTRAS: u8 = 2;
+
+/// Extra metadata that's rarely present, so stored as json.
+///
+/// Readers ignore fields they don't know, so new optional metadata can be
+/// added here without changing the hunk version or stopping older versions
+/// reading the index.
+#[derive(Default, Deserialize, Serialize)]
+struct Extras {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ntfs: Option<NtfsMetadata>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    statx: Option<StatxMetadata>,
+}
+
+/// True if this (decompressed) hunk is binary-encoded.
+pub(crate) fn is_binary(hunk: &[u8]) -> bool {
+    hunk.starts_with(MAGIC)
+}
+
+/// Encode a hunk of entries.
+pub(crate) fn encode(entries: &[IndexEntry]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(entries.len() * 32);
+    buf.extend_from_slice(MAGIC);
+    let with_extras = entries
+        .iter()
+        .any(|entry| entry.ntfs.is_some() || entry.statx.is_some());
+    buf.push(if with_extras {
+        VERSION_WITH_EXTRAS
+    } else {
+        VERSION
+    });
+    put_varint(&mut buf, entries.len() as u64);
+    let mut prev_apath: &[u8] = b"";
+    for entry in entries {
+        let apath = entry.apath.as_bytes();
+        let shared = prev_apath
+            .iter()
+            .zip(apath)
+            .take_while(|(a, b)| a == b)
+            .count();
+        put_varint(&mut buf, shared as u64);
+        put_bytes(&mut buf, &apath[shared..]);
+        prev_apath = apath;
+
+        buf.push(match entry.kind {
+            Kind::File => 0,
+            Kind::Dir => 1,
+            Kind::Symlink => 2,
+            Kind::Unknown => 3,
+            Kind::Deleted => 4,
+        });
+        put_varint(&mut buf, zigzag(entry.mtime));
+        put_varint(&mut buf, entry.mtime_nanos.into());
+
+        put_varint(&mut buf, entry.addrs.len() as u64);
+        for addr in &entry.addrs {
+            // Hashes are normally lowercase hex and are packed into bytes;
+            // anything else is stored literally. The low bit says which.
+            match hex::decode(&addr.hash) {
+                Ok(packed) if hex::encode(&packed) == addr.hash => {
+                    put_varint(&mut buf, (packed.len() as u64) << 1);
+                    buf.extend_from_slice(&packed);
+                }
+                _ => {
+                    put_varint(&mut buf, (addr.hash.len() as u64) << 1 | 1);
+                    buf.extend_from_slice(addr.hash.as_bytes());
+                }
+            }
+            put_varint(&mut buf, addr.start);
+            put_varint(&mut buf, addr.len);
+        }
+
+        match &entry.target {
+            None => put_varint(&mut buf, 0),
+            Some(target) => {
+                put_varint(&mut buf, target.len() as u64 + 1);
+                buf.extend_from_slice(target.as_bytes());
+            }
+        }
+
+        if with_extras {
+            if entry.ntfs.is_none() && entry.statx.is_none() {
+                put_varint(&mut buf, 0);
+            } else {
+                let extras = Extras {
+                    ntfs: entry.ntfs.clone(),
+                    statx: entry.statx.clone(),
+                };
+                let json = serde_json::to_vec(&extras).expect("metadata can be serialized");
+                put_varint(&mut buf, json.len() as u64 + 1);
+                buf.extend_from_slice(&json);
+            }
+        }
+    }
+    buf
+}
+
+/// Decode a binary hunk, or describe why it can't be decoded.
+pub(crate) fn decode(buf: &[u8]) -> std::result::Result<Vec<IndexEntry>, String> {
+    if !is_binary(buf) {
+        return Err("missing binary index header".to_owned());
+    }
+    let mut r = Reader {
+        buf: &buf[MAGIC.len()..],
+    };
+    let version = r.take(1)?[0];
+    if version != VERSION && version != VERSION_WITH_EXTRAS {
+        return Err(format!("unsupported binary index version {}", version));
+    }
+    let count = r.varint()?;
+    let mut entries: Vec<IndexEntry> = Vec::new();
+    let mut prev_apath = String::new();
+    for _ in 0..count {
+        let shared = r.varint_usize()?;
+        let suffix = r.bytes()?;
+        if shared > prev_apath.len() {
+            return Err("apath prefix is longer than the previous apath".to_owned());
+        }
+        let mut apath_bytes = prev_apath.as_bytes()[..shared].to_vec();
+        apath_bytes.extend_from_slice(suffix);
+        let apath = String::from_utf8(apath_bytes).map_err(|_| "apath is not UTF-8")?;
+        if !Apath::is_valid(&apath) {
+            return Err(format!("invalid apath {:?}", apath));
+        }
+        prev_apath = apath.clone();
+
+        let kind = match r.take(1)?[0] {
+            0 => Kind::File,
+            1 => Kind::Dir,
+            2 => Kind::Symlink,
+            3 => Kind::Unknown,
+            4 => Kind::Deleted,
+            // Added by a later version.
+            _ => Kind::Unknown,
+        };
+        let mtime = unzigzag(r.varint()?);
+        let mtime_nanos = u32::try_from(r.varint()?).map_err(|_| "mtime_nanos out of range")?;
+
+        let n_addrs = r.varint_usize()?;
+        let mut addrs = Vec::new();
+        for _ in 0..n_addrs {
+            let tagged_len = r.varint()?;
+            let hash_bytes = r.take(usize::try_from(tagged_len >> 1).map_err(|_| "bad length")?)?;
+            let hash = if tagged_len & 1 == 0 {
+                hex::encode(hash_bytes)
+            } else {
+                String::from_utf8(hash_bytes.to_vec()).map_err(|_| "hash is not UTF-8")?
+            };
+            addrs.push(Address {
+                hash,
+                start: r.varint()?,
+                len: r.varint()?,
+            });
+        }
+
+        let target = match r.varint_usize()? {
+            0 => None,
+            n => Some(
+                String::from_utf8(r.take(n - 1)?.to_vec())
+                    .map_err(|_| "symlink target is not UTF-8")?,
+            ),
+        };
+
+        let extras: Extras = if version == VERSION_WITH_EXTRAS {
+            match r.varint_usize()? {
+                0 => Extras::default(),
+                n => serde_json::from_slice(r.take(n - 1)?)
+                    .map_err(|e| format!("invalid extra metadata: {}", e))?,
+            }
+        } else {
+            Extras::default()
+        };
+
+        entries.push(IndexEntry {
+            apath: apath.into(),
+            kind,
+            mtime,
+            mtime_nanos,
+            addrs,
+            target,
+            ntfs: extras.ntfs,
+            statx: extras.statx,
+        });
+    }
+    if !r.buf.is_empty() {
+        return Err("unexpected data after the last entry".to_owned());
+    }
+    Ok(entries)
+}
+
+fn put_varint(buf: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        buf.push((v as u8) | 0x80);
+        v >>= 7;
+    }
+    buf.push(v as u8);
+}
+
+fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    put_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+fn zigzag(v: i64) -> u64 {
+    ((v << 1) ^ (v >> 63)) as u64
+}
+
+fn unzigzag(v: u64) -> i64 {
+    ((v >> 1) as i64) ^ -((v & 1) as i64)
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> std::result::Result<&'a [u8], String> {
+        if n > self.buf.len() {
+            return Err("truncated binary index hunk".to_owned());
+        }
+        let (head, tail) = self.buf.split_at(n);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    fn varint(&mut self) -> std::result::Result<u64, String> {
+        let mut v = 0u64;
+        for shift in (0..64).step_by(7) {
+            let b = self.take(1)?[0];
+            v |= u64::from(b & 0x7f) << shift;
+            if b & 0x80 == 0 {
+                return Ok(v);
+            }
+        }
+        Err("varint is too long".to_owned())
+    }
+
+    fn varint_usize(&mut self) -> std::result::Result<usize, String> {
+        usize::try_from(self.varint()?).map_err(|_| "value out of range".to_owned())
+    }
+
+    fn bytes(&mut self) -> std::result::Result<&'a [u8], String> {
+        let len = self.varint_usize()?;
+        self.take(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_entries() -> Vec<IndexEntry> {
+        vec![
+            IndexEntry {
+                apath: "/".into(),
+                kind: Kind::Dir,
+                mtime: -12,
+                mtime_nanos: 0,
+                addrs: vec![],
+                target: None,
+                ntfs: None,
+                statx: None,
+            },
+            IndexEntry {
+                apath: "/añejo".into(),
+                kind: Kind::File,
+                mtime: 1_592_266_523,
+                mtime_nanos: 999_999_999,
+                addrs: vec![
+                    Address {
+                        hash: "9063990e5c5b2184877f92adace7c801a549b00c39cd7549877f06d5dd0d3a6c"
+                            .to_owned(),
+                        start: 0,
+                        len: 1 << 20,
+                    },
+                    Address {
+                        hash: "NotHex".to_owned(),
+                        start: 300,
+                        len: 7,
+                    },
+                ],
+                target: None,
+                ntfs: None,
+                statx: None,
+            },
+            IndexEntry {
+                apath: "/añejo2".into(),
+                kind: Kind::Symlink,
+                mtime: 0,
+                mtime_nanos: 1,
+                addrs: vec![],
+                target: Some("añejo".to_owned()),
+                ntfs: None,
+                statx: None,
+            },
+            IndexEntry {
+                apath: "/a/empty-target".into(),
+                kind: Kind::Symlink,
+                mtime: i64::MIN,
+                mtime_nanos: 0,
+                addrs: vec![],
+                target: Some(String::new()),
+                ntfs: None,
+                statx: None,
+            },
+        ]
+    }
+
+    #[test]
+    fn round_trip() {
+        let entries = sample_entries();
+        let encoded = encode(&entries);
+        assert!(is_binary(&encoded));
+        assert_eq!(decode(&encoded).unwrap(), entries);
+        assert_eq!(decode(&encode(&[])).unwrap(), []);
+    }
+
+    #[test]
+    fn round_trip_ntfs_metadata() {
+        let mut entries = sample_entries();
+        assert_eq!(encode(&entries)[MAGIC.len()], VERSION);
+        entries[1].ntfs = Some(NtfsMetadata {
+            security_descriptor: Some("01000480".to_owned()),
+            streams: vec![NamedStream {
+                name: "Zone.Identifier".to_owned(),
+                content: hex::encode("[ZoneTransfer]"),
+            }],
+        });
+        let encoded = encode(&entries);
+        assert_eq!(encoded[MAGIC.len()], VERSION_WITH_EXTRAS);
+        assert_eq!(decode(&encoded).unwrap(), entries);
+    }
+
+    #[test]
+    fn round_trip_statx_metadata() {
+        let mut entries = sample_entries();
+        entries[0].statx = Some(StatxMetadata {
+            btime: Some(1_592_266_523),
+            btime_nanos: 12_345,
+            mnt_id: Some(29),
+            attributes: 0,
+            uid: Some(1000),
+            gid: Some(100),
+        });
+        entries[2].statx = Some(StatxMetadata::default());
+        let encoded = encode(&entries);
+        assert_eq!(encoded[MAGIC.len()], VERSION_WITH_EXTRAS);
+        assert_eq!(decode(&encoded).unwrap(), entries);
+    }
+
+    #[test]
+    fn read_entries_from_later_versions() {
+        let mut entries = sample_entries();
+        entries[0].statx = Some(StatxMetadata::default());
+        let mut encoded = encode(&entries);
+        // The kind of the root, after the version, entry count, shared
+        // prefix length, and apath.
+        let kind_pos = MAGIC.len() + 1 + 1 + 1 + 2;
+        assert_eq!(encoded[kind_pos], 1);
+        encoded[kind_pos] = 99;
+        // Unknown extra metadata, of the same length as the empty statx.
+        let extras = br#"{"statx":{}}"#;
+        let pos = encoded
+            .windows(extras.len())
+            .position(|w| w == extras)
+            .unwrap();
+        encoded[pos..(pos + extras.len())].copy_from_slice(br#"{"xattr":{}}"#);
+
+        let decoded = decode(&encoded).unwrap();
+        assert_eq!(decoded[0].kind, Kind::Unknown);
+        assert_eq!(decoded[0].statx, None);
+        assert_eq!(decoded[1..], entries[1..]);
+    }
+
+    #[test]
+    fn smaller_than_json() {
+        let entries: Vec<IndexEntry> = (0..1000)
+            .map(|i| IndexEntry {
+                apath: format!("/home/user/src/project/file{:04}.rs", i).into(),
+                kind: Kind::File,
+                mtime: 1_592_266_523 + i,
+                mtime_nanos: 123_456_789,
+                addrs: vec![Address {
+                    hash: hex::encode([i as u8; 64]),
+                    start: 0,
+                    len: 4000,
+                }],
+                target: None,
+                ntfs: None,
+                statx: None,
+            })
+            .collect();
+        let json_len = serde_json::to_vec(&entries).unwrap().len();
+        let binary_len = encode(&entries).len();
+        assert!(
+            binary_len * 2 < json_len,
+            "binary {} json {}",
+            binary_len,
+            json_len
+        );
+    }
+
+    #[test]
+    fn reject_damaged_hunks() {
+        let encoded = encode(&sample_entries());
+        for len in 0..encoded.len() {
+            assert!(decode(&encoded[..len]).is_err(), "truncated to {}", len);
+        }
+        let mut extra = encoded.clone();
+        extra.push(0);
+        assert!(decode(&extra).is_err());
+        assert!(decode(b"[]").is_err());
+        assert!(!is_binary(b"[]"));
+    }
+
+    #[test]
+    fn zigzag_round_trip() {
+        for v in &[0, 1, -1, 12345, -12345, i64::MAX, i64::MIN] {
+            assert_eq!(unzigzag(zigzag(*v)), *v);
+        }
+    }
+}
@@ -11,20 +11,26 @@
 //!
 //! The structure is: archive > blockdir > subdir > file.
 
+use std::collections::{BTreeMap, HashSet, VecDeque};
 use std::convert::TryInto;
 use std::fs;
 use std::io;
 use std::io::prelude::*;
+use std::ops::Range;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
 use blake2_rfc::blake2b;
 use blake2_rfc::blake2b::Blake2b;
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 use snafu::ResultExt;
+use tracing::{debug_span, error, info};
 
 use crate::compress::snappy;
-use crate::stats::{CopyStats, Sizes, ValidateBlockDirStats};
+use crate::stats::{BlockDirStats, CopyStats, Sizes, SubdirStats, ValidateBlockDirStats};
 use crate::*;
 
 /// Use the maximum 64-byte hash.
@@ -37,6 +43,23 @@ const SUBDIR_NAME_CHARS: usize = 3;
 
 const TMP_PREFIX: &str = "tmp";
 
+/// Holds an identifier that's changed whenever blocks may have been removed.
+const GENERATION_FILENAME: &str = "GENERATION";
+
+/// Store queued blocks once this many entries are waiting for them.
+const MAX_QUEUED_ENTRIES: usize = 1000;
+
+/// When validating against a deadline, check this many blocks between
+/// looking at the clock.
+const SCRUB_BATCH_BLOCKS: usize = 64;
+
+/// By default, files up to this size are combined into shared blocks.
+pub const DEFAULT_SMALL_FILE_SIZE: u64 = 100_000;
+
+/// Keep this many recently read shared blocks decompressed, so that reading
+/// the small files combined into them doesn't read the block again for each.
+const CACHED_BLOCKS: usize = 4;
+
 /// The unique identifier for a block: its hexadecimal `BLAKE2b` hash.
 pub type BlockHash = String;
 
@@ -44,7 +67,7 @@ pub type BlockHash = String;
 ///
 /// Identifiers are: which file contains it, at what (pre-compression) offset,
 /// and what (pre-compression) length.
-#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct Address {
     /// ID of the block storing this info (in future, salted.)
     pub hash: String,
@@ -62,6 +85,30 @@ pub struct Address {
 #[derive(Clone, Debug)]
 pub struct BlockDir {
     pub path: PathBuf,
+
+    /// Blocks known to be present, because this process stored them or saw
+    /// them, shared between clones.
+    ///
+    /// This is cleared by `new_generation`, but not if another process
+    /// removes blocks, so a BlockDir shouldn't be kept open across that.
+    known_blocks: Arc<Mutex<HashSet<BlockHash>>>,
+
+    /// Recently read blocks holding more than one address, most recent
+    /// last, shared between clones.
+    cached_blocks: Arc<Mutex<VecDeque<CachedBlock>>>,
+
+    /// The number of blocks read and decompressed, shared between clones.
+    blocks_read: Arc<AtomicU64>,
+
+    /// Read back and check each block after it's written.
+    verify_writes: bool,
+}
+
+#[derive(Debug)]
+struct CachedBlock {
+    hash: BlockHash,
+    content: Arc<Vec<u8>>,
+    sizes: Sizes,
 }
 
 fn block_name_to_subdirectory(block_hash: &str) -> &str {
@@ -73,6 +120,19 @@ impl BlockDir {
     pub fn new(path: &Path) -> BlockDir {
         BlockDir {
             path: path.to_path_buf(),
+            known_blocks: Arc::default(),
+            cached_blocks: Arc::default(),
+            blocks_read: Arc::default(),
+            verify_writes: false,
+        }
+    }
+
+    /// Return a BlockDir that reads back each block it writes and checks its
+    /// hash, to catch corruption on write, at some cost in speed.
+    pub fn with_verify_writes(self, verify_writes: bool) -> BlockDir {
+        BlockDir {
+            verify_writes,
+            ..self
         }
     }
 
@@ -93,6 +153,7 @@ impl BlockDir {
     }
 
     fn compress_and_store(&self, in_buf: &[u8], hex_hash: &str) -> std::io::Result<u64> {
+        let _span = debug_span!("store_block", hash = %hex_hash).entered();
         // Note: When we come to support cloud storage, we should do one atomic write rather than
         // a write and rename.
         let path = self.path_for_file(&hex_hash);
@@ -104,56 +165,245 @@ impl BlockDir {
         let comp_len = Snappy::compress_and_write(&in_buf, &mut tempf)?
             .try_into()
             .unwrap();
+        crate::faults::before_write(&path)?;
         // Use plain `persist` not `persist_noclobber` to avoid
         // calling `link` on Unix, which won't work on all filesystems.
         if let Err(e) = tempf.persist(&path) {
             if e.error.kind() == io::ErrorKind::AlreadyExists {
                 // Perhaps it was simultaneously created by another thread or process.
                 // This isn't really an error.
-                ui::problem(&format!(
-                    "Unexpected late detection of existing block {:?}",
-                    hex_hash
-                ));
+                error!("Unexpected late detection of existing block {:?}", hex_hash);
                 e.file.close()?;
             } else {
                 return Err(e.error);
             }
         }
+        self.known_blocks
+            .lock()
+            .unwrap()
+            .insert(hex_hash.to_owned());
         Ok(comp_len)
     }
 
+    /// Store one block of data, unless an identical block is already present,
+    /// and return its address.
+    pub fn store_block(&self, block_data: &[u8]) -> Result<Address> {
+        let block_hash = hash_bytes(block_data)?;
+        if !self.contains(&block_hash)? {
+            self.compress_and_store(block_data, &block_hash)
+                .with_context(|| errors::StoreBlock {
+                    block_hash: block_hash.clone(),
+                })?;
+            self.verify_write(&block_hash)?;
+        }
+        Ok(Address {
+            hash: block_hash,
+            start: 0,
+            len: block_data.len() as u64,
+        })
+    }
+
+    /// Store a batch of blocks, skipping any that are already present, and
+    /// return their addresses in the same order.
+    ///
+    /// Blocks are hashed, compressed and written concurrently on the pool for
+    /// blocks. Blocks repeated within the batch are only written once.
+    pub fn store_blocks(&self, blocks: &[Vec<u8>]) -> Result<(Vec<Address>, CopyStats)> {
+        self.store_blocks_observed(blocks, None)
+    }
+
+    /// Store a batch of blocks like `store_blocks`, telling `observer` about
+    /// each block that's written.
+    pub(crate) fn store_blocks_observed(
+        &self,
+        blocks: &[Vec<u8>],
+        observer: Option<&dyn Observer>,
+    ) -> Result<(Vec<Address>, CopyStats)> {
+        let mut stats = CopyStats::default();
+        let hashes = threads::in_block_pool(|| {
+            blocks
+                .par_iter()
+                .map(|block| {
+                    let hash = hash_bytes(block)?;
+                    let present = self.contains(&hash)?;
+                    Ok((hash, present))
+                })
+                .collect::<Result<Vec<(BlockHash, bool)>>>()
+        })?;
+        let mut new_hashes = HashSet::new();
+        let mut to_write = Vec::new();
+        for (i, (hash, present)) in hashes.iter().enumerate() {
+            if *present || !new_hashes.insert(hash) {
+                // TODO: Separate counter for size of the already-present blocks?
+                let len = blocks[i].len() as u64;
+                stats.deduplicated_blocks += 1;
+                stats.deduplicated_bytes += len;
+                ui::increment_bytes_deduplicated(len);
+            } else {
+                to_write.push(i);
+            }
+        }
+        let compressed_lens = threads::in_block_pool(|| {
+            to_write
+                .par_iter()
+                .map(|&i| {
+                    let block_hash = &hashes[i].0;
+                    let comp_len = self
+                        .compress_and_store(&blocks[i], block_hash)
+                        .with_context(|| errors::StoreBlock {
+                            block_hash: block_hash.clone(),
+                        })?;
+                    self.verify_write(block_hash)?;
+                    if let Some(observer) = observer {
+                        observer.block_written(block_hash, blocks[i].len() as u64, comp_len);
+                    }
+                    Ok(comp_len)
+                })
+                .collect::<Result<Vec<u64>>>()
+        })?;
+        stats.written_blocks += compressed_lens.len();
+        stats.compressed_bytes += compressed_lens.iter().sum::<u64>();
+        let addrs = hashes
+            .into_iter()
+            .zip(blocks)
+            .map(|((hash, _present), block)| Address {
+                hash,
+                start: 0,
+                len: block.len() as u64,
+            })
+            .collect();
+        Ok((addrs, stats))
+    }
+
+    /// If writes are verified, check that a newly written block can be read
+    /// back and has the right hash.
+    fn verify_write(&self, hash: &str) -> Result<()> {
+        if self.verify_writes {
+            self.get_block_content(hash)?;
+        }
+        Ok(())
+    }
+
+    /// An identifier for the current generation of blocks in this
+    /// directory, made when it's first asked for.
+    ///
+    /// It's changed by `new_generation` whenever blocks may have been
+    /// removed, so that anything remembering which blocks are present, such as
+    /// the block cache of a push, knows to forget them.
+    pub fn generation(&self) -> Result<String> {
+        let path = self.path.join(GENERATION_FILENAME);
+        match fs::read_to_string(&path) {
+            Ok(generation) => return Ok(generation.trim().to_owned()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e).context(errors::ReadMetadata { path }),
+        }
+        let generation = random_generation()?;
+        let written = AtomicFile::new(&path).and_then(|mut f| {
+            writeln!(f, "{}", generation)?;
+            f.close_noclobber()
+        });
+        match written {
+            Ok(()) => Ok(generation),
+            // Another process made one first.
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => fs::read_to_string(&path)
+                .map(|generation| generation.trim().to_owned())
+                .context(errors::ReadMetadata { path }),
+            Err(e) => Err(e).context(errors::WriteMetadata { path }),
+        }
+    }
+
+    /// Start a new generation, which must be done by anything that removes
+    /// blocks, and return its identifier.
+    pub fn new_generation(&self) -> Result<String> {
+        let path = self.path.join(GENERATION_FILENAME);
+        let generation = random_generation()?;
+        AtomicFile::new(&path)
+            .and_then(|mut f| {
+                writeln!(f, "{}", generation)?;
+                f.close()
+            })
+            .context(errors::WriteMetadata { path })?;
+        self.known_blocks.lock().unwrap().clear();
+        Ok(generation)
+    }
+
     /// True if the named block is present in this directory.
+    ///
+    /// Blocks already known to be present are not checked again.
     pub fn contains(&self, hash: &str) -> Result<bool> {
+        if self.known_blocks.lock().unwrap().contains(hash) {
+            return Ok(true);
+        }
         let path = self.path_for_file(hash);
         match fs::metadata(&path) {
             Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
-            Ok(_) => Ok(true),
+            Ok(_) => {
+                self.known_blocks.lock().unwrap().insert(hash.to_owned());
+                Ok(true)
+            }
             Err(e) => Err(e).context(errors::ReadBlock { path }),
         }
     }
 
     /// Read back the contents of a block, as a byte array.
     ///
+    /// Blocks that hold more than this address, such as those shared by
+    /// combined small files, are kept decompressed for a while in case
+    /// their other addresses are read next.
+    ///
     /// To read a whole file, use StoredFile instead.
     pub fn get(&self, addr: &Address) -> Result<(Vec<u8>, Sizes)> {
-        let (mut decompressed, sizes) = self.get_block_content(&addr.hash)?;
         let len = addr.len as usize;
         let start = addr.start as usize;
-        if (start + len) > decompressed.len() {
-            // TODO: Error, not panic.
-            panic!(
-                "address {:?} extends beyond decompressed length {}",
-                addr,
-                decompressed.len(),
-            );
+        let check_len = |block_len: usize| {
+            if (start + len) > block_len {
+                // TODO: Error, not panic.
+                panic!(
+                    "address {:?} extends beyond decompressed length {}",
+                    addr, block_len,
+                );
+            }
+        };
+        if let Some((content, sizes)) = self.cached_block(&addr.hash) {
+            check_len(content.len());
+            return Ok((content[start..(start + len)].to_owned(), sizes));
         }
-        if addr.start != 0 {
-            let trimmed = decompressed[start..(start + len)].to_owned();
-            Ok((trimmed, sizes))
-        } else {
-            decompressed.truncate(len);
-            Ok((decompressed, sizes))
+        let (decompressed, sizes) = self.get_block_content(&addr.hash)?;
+        check_len(decompressed.len());
+        if start == 0 && len == decompressed.len() {
+            return Ok((decompressed, sizes));
         }
+        let trimmed = decompressed[start..(start + len)].to_owned();
+        self.cache_block(&addr.hash, decompressed, sizes);
+        Ok((trimmed, sizes))
+    }
+
+    /// Return a recently read block, if it's still cached.
+    fn cached_block(&self, hash: &str) -> Option<(Arc<Vec<u8>>, Sizes)> {
+        let mut cached_blocks = self.cached_blocks.lock().unwrap();
+        let i = cached_blocks.iter().position(|c| c.hash == hash)?;
+        let cached = cached_blocks.remove(i).unwrap();
+        let found = (cached.content.clone(), cached.sizes);
+        cached_blocks.push_back(cached);
+        Some(found)
+    }
+
+    fn cache_block(&self, hash: &str, content: Vec<u8>, sizes: Sizes) {
+        let mut cached_blocks = self.cached_blocks.lock().unwrap();
+        if cached_blocks.len() >= CACHED_BLOCKS {
+            cached_blocks.pop_front();
+        }
+        cached_blocks.push_back(CachedBlock {
+            hash: hash.to_owned(),
+            content: Arc::new(content),
+            sizes,
+        });
+    }
+
+    /// The number of blocks read and decompressed by this BlockDir and its
+    /// clones.
+    pub fn blocks_read(&self) -> u64 {
+        self.blocks_read.load(Ordering::Relaxed)
     }
 
     /// Return a sorted vec of prefix subdirectories.
@@ -163,10 +413,7 @@ impl BlockDir {
         let (_fs, mut ds) = list_dir(&self.path)?;
         ds.retain(|dd| {
             if dd.len() != SUBDIR_NAME_CHARS {
-                ui::problem(&format!(
-                    "unexpected subdirectory in blockdir {:?}: {:?}",
-                    self, dd
-                ));
+                error!("unexpected subdirectory in blockdir {:?}: {:?}", self, dd);
                 false
             } else {
                 true
@@ -203,7 +450,7 @@ impl BlockDir {
     }
 
     /// Return an iterator of block names and sizes.
-    fn block_names_and_sizes(&self) -> Result<impl Iterator<Item = (String, u64)>> {
+    pub(crate) fn block_names_and_sizes(&self) -> Result<impl Iterator<Item = (String, u64)>> {
         Ok(self.iter_block_dir_entries()?.map(|de| {
             (
                 de.file_name().into_string().unwrap(),
@@ -212,42 +459,180 @@ impl BlockDir {
         }))
     }
 
+    /// Measure the blocks in this directory, and look for files that
+    /// shouldn't be here.
+    ///
+    /// This reads only the header of each block, not its whole content: to
+    /// check the content, use `validate`.
+    pub fn stats(&self) -> Result<BlockDirStats> {
+        ui::set_progress_phase("Measure blocks");
+        let path = &self.path;
+        let mut stats = BlockDirStats::default();
+        let mut subdirs = Vec::new();
+        for entry in fs::read_dir(path).context(errors::ListBlocks { path })? {
+            let entry = entry.context(errors::ListBlocks { path })?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let is_dir = entry
+                .file_type()
+                .context(errors::ListBlocks { path })?
+                .is_dir();
+            if is_dir && name.len() == SUBDIR_NAME_CHARS {
+                subdirs.push(name);
+            } else {
+                stats.unexpected_files.push(entry.path());
+            }
+        }
+        subdirs.sort_unstable();
+        let subdir_stats = threads::in_block_pool(|| {
+            subdirs
+                .par_iter()
+                .map(|subdir| self.subdir_stats(subdir))
+                .collect::<Result<Vec<(BlockDirStats, Vec<String>)>>>()
+        })?;
+        let mut hash_counts = BTreeMap::<String, usize>::new();
+        for (subdir, (s, hashes)) in subdirs.into_iter().zip(subdir_stats) {
+            stats.block_count += s.block_count;
+            stats.sizes += s.sizes;
+            for (upper, count) in s.size_histogram {
+                *stats.size_histogram.entry(upper).or_default() += count;
+            }
+            for hash in hashes {
+                *hash_counts.entry(hash).or_default() += 1;
+            }
+            stats.misplaced_blocks.extend(s.misplaced_blocks);
+            stats.unreadable_blocks.extend(s.unreadable_blocks);
+            stats.unexpected_files.extend(s.unexpected_files);
+            stats.temp_files += s.temp_files;
+            stats.subdirs.insert(
+                subdir,
+                SubdirStats {
+                    block_count: s.block_count,
+                    sizes: s.sizes,
+                },
+            );
+        }
+        stats.duplicate_blocks = hash_counts
+            .into_iter()
+            .filter(|(_, count)| *count > 1)
+            .map(|(hash, _)| hash)
+            .collect();
+        Ok(stats)
+    }
+
+    /// Measure one subdirectory, and return the names of the blocks in it.
+    fn subdir_stats(&self, subdir: &str) -> Result<(BlockDirStats, Vec<String>)> {
+        let path = &self.path.join(subdir);
+        let mut stats = BlockDirStats::default();
+        let mut hashes = Vec::new();
+        for entry in fs::read_dir(path).context(errors::ListBlocks { path })? {
+            let entry = entry.context(errors::ListBlocks { path })?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let metadata = entry.metadata().context(errors::ListBlocks { path })?;
+            if name.starts_with(TMP_PREFIX) {
+                stats.temp_files += 1;
+                continue;
+            } else if !metadata.is_file()
+                || name.len() != BLOCKDIR_FILE_NAME_LEN
+                || !name.bytes().all(|b| b.is_ascii_hexdigit())
+            {
+                stats.unexpected_files.push(entry.path());
+                continue;
+            } else if block_name_to_subdirectory(&name) != subdir {
+                stats.misplaced_blocks.push(entry.path());
+            }
+            let compressed = metadata.len();
+            match snappy::uncompressed_len_of_file(entry.path()) {
+                Ok(uncompressed) => {
+                    stats.sizes += Sizes {
+                        compressed,
+                        uncompressed,
+                    }
+                }
+                Err(_) => stats.unreadable_blocks.push(entry.path()),
+            }
+            stats.block_count += 1;
+            *stats
+                .size_histogram
+                .entry(compressed.next_power_of_two())
+                .or_default() += 1;
+            ui::increment_bytes_done(compressed);
+            hashes.push(name);
+        }
+        Ok((stats, hashes))
+    }
+
     /// Check format invariants of the BlockDir.
     pub fn validate(&self) -> Result<ValidateBlockDirStats> {
         // TODO: In the top-level directory, no files or directories other than prefix
         // directories of the right length.
         // TODO: Provide a progress bar that just works on counts, not bytes:
         // then we don't need to count the sizes in advance.
-        ui::println("Count blocks...");
+        info!("Count blocks...");
         let bns: Vec<(String, u64)> = self.block_names_and_sizes()?.collect();
         let tot = bns.iter().map(|a| a.1).sum();
         ui::set_progress_phase(&"Count blocks");
         ui::set_bytes_total(tot);
-        crate::ui::println(&format!(
-            "Check {} in blocks...",
-            crate::misc::bytes_to_human_mb(tot)
-        ));
+        info!("Check {} in blocks...", crate::misc::bytes_to_human_mb(tot));
         ui::set_progress_phase(&"Check block hashes");
-        // TODO: Accumulate counts from validation of individual blocks,
-        // and count the total number that were unreadable or had the wrong hash.
-        let block_error_count = bns
-            .par_iter()
-            .filter(|(block_hash, bsize)| {
-                ui::increment_bytes_done(*bsize);
-                self.get_block_content(&block_hash).is_err()
-            })
-            .count()
-            .try_into()
-            .unwrap();
-        let block_read_count = bns.len().try_into().unwrap();
-        Ok(ValidateBlockDirStats {
-            block_error_count,
-            block_read_count,
-        })
+        Ok(self.check_blocks(&bns, None).0)
+    }
+
+    /// Check the hashes of these blocks, spread across the current thread
+    /// pool, counting their bytes towards the current progress phase.
+    ///
+    /// If there's a `deadline`, blocks are checked in order, a batch at a
+    /// time, and those not reached by the deadline are skipped.
+    ///
+    /// Returns stats along with the hashes of the blocks that were intact,
+    /// and of those that weren't.
+    pub(crate) fn check_blocks(
+        &self,
+        bns: &[(String, u64)],
+        deadline: Option<Instant>,
+    ) -> (ValidateBlockDirStats, Vec<String>, Vec<String>) {
+        // TODO: Count the number that were unreadable separately from those
+        // with the wrong hash.
+        let batch_size = match deadline {
+            Some(_) => SCRUB_BATCH_BLOCKS,
+            None => bns.len().max(1),
+        };
+        let mut good = Vec::new();
+        let mut bad = Vec::new();
+        let mut block_read_count: u64 = 0;
+        for batch in bns.chunks(batch_size) {
+            if matches!(deadline, Some(deadline) if Instant::now() >= deadline) {
+                break;
+            }
+            let (batch_good, batch_bad): (Vec<_>, Vec<_>) = threads::in_block_pool(|| {
+                batch
+                    .par_iter()
+                    .map(|(block_hash, bsize)| {
+                        ui::increment_bytes_done(*bsize);
+                        (
+                            block_hash.clone(),
+                            self.get_block_content(block_hash).is_ok(),
+                        )
+                    })
+                    .partition(|(_, ok)| *ok)
+            });
+            good.extend(batch_good.into_iter().map(|(hash, _)| hash));
+            bad.extend(batch_bad.into_iter().map(|(hash, _)| hash));
+            block_read_count += batch.len() as u64;
+        }
+        (
+            ValidateBlockDirStats {
+                block_error_count: bad.len().try_into().unwrap(),
+                block_read_count,
+                block_skipped_count: bns.len() as u64 - block_read_count,
+            },
+            good,
+            bad,
+        )
     }
 
     /// Return the entire contents of the block.
     pub fn get_block_content(&self, hash: &str) -> Result<(Vec<u8>, Sizes)> {
+        self.blocks_read.fetch_add(1, Ordering::Relaxed);
         let path = self.path_for_file(hash);
         let (compressed_len, decompressed_bytes) = snappy::decompress_file(&path)
             .context(errors::ReadBlock { path: path.clone() })
@@ -259,10 +644,10 @@ impl BlockDir {
             blake2b::blake2b(BLAKE_HASH_SIZE_BYTES, &[], &decompressed_bytes).as_bytes(),
         );
         if actual_hash != *hash {
-            ui::problem(&format!(
+            error!(
                 "Block file {:?} has actual decompressed hash {:?}",
                 &path, actual_hash
-            ));
+            );
             return Err(Error::BlockCorrupt { path, actual_hash });
         }
         let sizes = Sizes {
@@ -281,79 +666,334 @@ impl BlockDir {
     }
 }
 
-/// Manages storage into the BlockDir of any number of files.
+/// Queue files and other index entries to be written to a band, storing the
+/// content of files into the BlockDir.
 ///
-/// At present this just holds a reusable input buffer.
+/// Blocks from one or more files are queued, and then hashed, compressed and
+/// written concurrently by [BlockDir::store_blocks]. Entries come out of
+/// the queue in the order they went in, once all their blocks are stored.
 ///
-/// In future it will combine small files into aggregate blocks,
-/// and perhaps compress them in parallel.
+/// Small files are packed together into shared blocks, each addressed by its
+/// offset and length, so that they compress better and there are fewer
+/// block files.
 pub(crate) struct StoreFiles {
-    // TODO: Rename to FileWriter or similar? Perhaps doesn't need to be
-    // separate from BackupWriter.
     block_dir: BlockDir,
-    input_buf: Vec<u8>,
+
+    /// Blocks read but not yet stored.
+    blocks: Vec<Vec<u8>>,
+
+    /// Total size of `blocks`.
+    queued_bytes: usize,
+
+    /// Reused to read each block, so that queued blocks only take as much
+    /// memory as was read into them.
+    read_buf: Vec<u8>,
+
+    /// Break file content into blocks of this many bytes.
+    block_size: usize,
+
+    /// Store queued blocks once there are this many bytes of them, so that
+    /// big batches can be compressed concurrently but memory use is bounded.
+    flush_bytes: usize,
+
+    /// Entries waiting for blocks to be stored, each with the range of
+    /// `blocks` holding the rest of its content.
+    queue: Vec<(IndexEntry, QueuedContent)>,
+
+    /// The queued block that small files are being added to, if any.
+    combined_block: Option<usize>,
+
+    /// Files no bigger than this are combined into shared blocks.
+    small_file_size: u64,
+
+    /// Entries whose content is all stored, ready to be written to the index.
+    ready: Vec<IndexEntry>,
+
+    /// Stats for stored blocks, not yet collected by `take_stats`.
+    stats: CopyStats,
+
+    /// Told about each block that's written.
+    observer: Option<Arc<dyn Observer>>,
 }
 
 impl StoreFiles {
     pub(crate) fn new(block_dir: BlockDir) -> StoreFiles {
         StoreFiles {
             block_dir,
-            input_buf: vec![0; MAX_BLOCK_SIZE],
+            blocks: Vec::new(),
+            queued_bytes: 0,
+            read_buf: Vec::new(),
+            block_size: MAX_BLOCK_SIZE,
+            flush_bytes: Tuning::default().flush_bytes,
+            queue: Vec::new(),
+            combined_block: None,
+            small_file_size: DEFAULT_SMALL_FILE_SIZE,
+            ready: Vec::new(),
+            stats: CopyStats::default(),
+            observer: None,
         }
     }
 
-    pub(crate) fn store_file_content(
+    /// Tell `observer` about each block that's written.
+    pub(crate) fn with_observer(self, observer: Option<Arc<dyn Observer>>) -> StoreFiles {
+        StoreFiles { observer, ..self }
+    }
+
+    /// Combine files up to this many bytes into shared blocks, or don't
+    /// combine them if it's 0.
+    pub(crate) fn with_small_file_size(self, small_file_size: u64) -> StoreFiles {
+        StoreFiles {
+            small_file_size,
+            ..self
+        }
+    }
+
+    /// Use the block size, flush threshold and small file size from `tuning`.
+    pub(crate) fn with_tuning(self, tuning: &Tuning) -> StoreFiles {
+        StoreFiles {
+            block_size: tuning.block_size,
+            flush_bytes: tuning.flush_bytes,
+            small_file_size: tuning.small_file_size,
+            ..self
+        }
+    }
+
+    /// Read back and check each block after it's written.
+    pub(crate) fn with_paranoid(self, paranoid: bool) -> StoreFiles {
+        StoreFiles {
+            block_dir: self.block_dir.with_verify_writes(paranoid),
+            ..self
+        }
+    }
+
+    /// Queue an entry that has no content to store, behind any earlier
+    /// entries.
+    pub(crate) fn queue_entry(&mut self, entry: IndexEntry) -> Result<()> {
+        if self.queue.is_empty() {
+            self.ready.push(entry);
+            Ok(())
+        } else {
+            let n = self.blocks.len();
+            self.queue.push((entry, QueuedContent::Blocks(n..n)));
+            self.store_if_full()
+        }
+    }
+
+    /// Read the content of a file, and queue its entry to be released once
+    /// the content is stored.
+    ///
+    /// Large files are stored in several batches as they're read, so that
+    /// the queue has a bounded size.
+    pub(crate) fn queue_file(
         &mut self,
-        apath: &Apath,
+        mut entry: IndexEntry,
         from_file: &mut dyn Read,
-    ) -> Result<(Vec<Address>, CopyStats)> {
-        let mut addresses = Vec::<Address>::with_capacity(1);
-        let mut stats = CopyStats::default();
+    ) -> Result<()> {
+        let mut first_block = self.blocks.len();
+        let mut n_blocks = 0;
+        let mut file_bytes = 0;
         loop {
-            // TODO: Possibly read repeatedly in case we get a short read and have room for more,
-            // so that short reads don't lead to short blocks being stored.
-            let read_len =
-                from_file
-                    .read(&mut self.input_buf)
-                    .with_context(|| errors::StoreFile {
-                        apath: apath.clone(),
-                    })?;
+            let read_start = Instant::now();
+            self.read_buf.resize(self.block_size, 0);
+            let read_result = read_full(from_file, &mut self.read_buf);
+            self.stats.read_time += read_start.elapsed();
+            let read_len = match read_result {
+                Ok(read_len) => read_len,
+                Err(source) => {
+                    self.queued_bytes -= self.blocks[first_block..]
+                        .iter()
+                        .map(Vec::len)
+                        .sum::<usize>();
+                    self.blocks.truncate(first_block);
+                    return Err(Error::StoreFile {
+                        apath: entry.apath,
+                        source,
+                    });
+                }
+            };
             if read_len == 0 {
                 break;
             }
-            stats.uncompressed_bytes += read_len as u64;
-            let block_data = &self.input_buf[..read_len];
-            let block_hash: String = hash_bytes(block_data).unwrap();
-            if self.block_dir.contains(&block_hash)? {
-                // TODO: Separate counter for size of the already-present blocks?
-                stats.deduplicated_blocks += 1;
-                stats.deduplicated_bytes += read_len as u64;
-            } else {
-                let comp_len = self
-                    .block_dir
-                    .compress_and_store(block_data, &block_hash)
-                    .with_context(|| errors::StoreBlock {
-                        block_hash: block_hash.clone(),
-                    })?;
-                stats.written_blocks += 1;
-                stats.compressed_bytes += comp_len;
+            file_bytes += read_len as u64;
+            self.queued_bytes += read_len;
+            self.blocks.push(self.read_buf[..read_len].to_vec());
+            n_blocks += 1;
+            if self.is_full() {
+                // Store everything so far, including the start of this file.
+                let addrs = self.store_queued()?;
+                entry.addrs.extend_from_slice(&addrs[first_block..]);
+                first_block = 0;
             }
-            addresses.push(Address {
-                hash: block_hash,
-                start: 0,
-                len: read_len as u64,
-            });
         }
-        match addresses.len() {
-            0 => stats.empty_files += 1,
-            1 => stats.single_block_files += 1,
-            _ => stats.multi_block_files += 1,
+        self.stats.uncompressed_bytes += file_bytes;
+        match n_blocks {
+            0 => self.stats.empty_files += 1,
+            1 => self.stats.single_block_files += 1,
+            _ => self.stats.multi_block_files += 1,
+        }
+        if n_blocks == 1
+            && self.blocks.len() == first_block + 1
+            && file_bytes <= self.small_file_size
+        {
+            let data = self.blocks.pop().unwrap();
+            let content = self.combine(data);
+            self.stats.combined_files += 1;
+            self.queue.push((entry, content));
+        } else if self.blocks.is_empty() && self.queue.is_empty() {
+            self.ready.push(entry);
+        } else {
+            let content = QueuedContent::Blocks(first_block..self.blocks.len());
+            self.queue.push((entry, content));
         }
-        Ok((addresses, stats))
+        self.store_if_full()
     }
+
+    /// Store the content of all queued entries, so that they're all ready.
+    pub(crate) fn flush(&mut self) -> Result<()> {
+        if !self.queue.is_empty() {
+            self.store_queued()?;
+        }
+        Ok(())
+    }
+
+    /// Return entries whose content is stored, in the order they were
+    /// queued.
+    pub(crate) fn take_ready(&mut self) -> Vec<IndexEntry> {
+        std::mem::take(&mut self.ready)
+    }
+
+    /// Return stats for content stored since the last call.
+    pub(crate) fn take_stats(&mut self) -> CopyStats {
+        std::mem::take(&mut self.stats)
+    }
+
+    /// Add the content of a small file to a shared block.
+    fn combine(&mut self, data: Vec<u8>) -> QueuedContent {
+        let len = data.len() as u64;
+        match self.combined_block {
+            Some(block) if self.blocks[block].len() + data.len() <= self.block_size => {
+                let start = self.blocks[block].len() as u64;
+                self.blocks[block].extend_from_slice(&data);
+                QueuedContent::Combined { block, start, len }
+            }
+            _ => {
+                let block = self.blocks.len();
+                self.blocks.push(data);
+                self.combined_block = Some(block);
+                QueuedContent::Combined {
+                    block,
+                    start: 0,
+                    len,
+                }
+            }
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        // Under a memory limit, store blocks sooner, but still at least one
+        // block at a time.
+        let flush_bytes = match spill::block_queue_budget() {
+            Some(budget) => self.flush_bytes.min(budget as usize).max(self.block_size),
+            None => self.flush_bytes,
+        };
+        self.queued_bytes >= flush_bytes || self.queue.len() >= MAX_QUEUED_ENTRIES
+    }
+
+    fn store_if_full(&mut self) -> Result<()> {
+        if self.is_full() {
+            self.store_queued()?;
+        }
+        Ok(())
+    }
+
+    /// Store all queued blocks, and release all queued entries.
+    ///
+    /// Returns the addresses of the blocks, in order.
+    fn store_queued(&mut self) -> Result<Vec<Address>> {
+        let store_start = Instant::now();
+        let (addrs, stats) = self
+            .block_dir
+            .store_blocks_observed(&self.blocks, self.observer.as_deref())?;
+        self.stats += stats;
+        self.stats.store_time += store_start.elapsed();
+        self.blocks.clear();
+        self.queued_bytes = 0;
+        self.combined_block = None;
+        for (mut entry, content) in self.queue.drain(..) {
+            match content {
+                QueuedContent::Blocks(range) => entry.addrs.extend_from_slice(&addrs[range]),
+                QueuedContent::Combined { block, start, len } => entry.addrs.push(Address {
+                    hash: addrs[block].hash.clone(),
+                    start,
+                    len,
+                }),
+            }
+            self.ready.push(entry);
+        }
+        Ok(addrs)
+    }
+
+    /// Store the content of one file, and return its addresses.
+    #[cfg(test)]
+    pub(crate) fn store_file_content(
+        &mut self,
+        apath: &Apath,
+        from_file: &mut dyn Read,
+    ) -> Result<(Vec<Address>, CopyStats)> {
+        assert!(self.queue.is_empty() && self.ready.is_empty());
+        let entry = IndexEntry {
+            apath: apath.clone(),
+            kind: Kind::File,
+            mtime: 0,
+            mtime_nanos: 0,
+            addrs: Vec::new(),
+            target: None,
+            ntfs: None,
+            statx: None,
+        };
+        self.queue_file(entry, from_file)?;
+        self.flush()?;
+        let entry = self.ready.pop().unwrap();
+        Ok((entry.addrs, self.take_stats()))
+    }
+}
+
+fn random_generation() -> Result<String> {
+    let mut bytes = [0; 16];
+    getrandom::getrandom(&mut bytes).map_err(|e| Error::GenerateKey {
+        message: e.to_string(),
+    })?;
+    Ok(hex::encode(bytes))
 }
 
-fn hash_bytes(in_buf: &[u8]) -> Result<BlockHash> {
+/// Read until `buf` is full or the file ends, so that short reads from pipes
+/// or network filesystems don't make the blocks depend on timing.
+///
+/// Returns the number of bytes read, which is less than the length of `buf`
+/// only at the end of the file.
+fn read_full(from_file: &mut dyn Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match from_file.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(len)
+}
+
+/// Where the content of a queued entry is, among the queued blocks.
+enum QueuedContent {
+    /// The whole of these blocks, in order.
+    Blocks(Range<usize>),
+
+    /// Part of one block, shared with other small files.
+    Combined { block: usize, start: u64, len: u64 },
+}
+
+pub(crate) fn hash_bytes(in_buf: &[u8]) -> Result<BlockHash> {
     let mut hasher = Blake2b::new(BLAKE_HASH_SIZE_BYTES);
     hasher.update(in_buf);
     Ok(hex::encode(hasher.finalize().as_bytes()))
@@ -470,6 +1110,17 @@ mod tests {
         assert_eq!(second_half_content, "89abcdef".as_bytes());
     }
 
+    #[test]
+    pub fn store_block() {
+        let (_testdir, block_dir) = setup();
+        let addr = block_dir.store_block(EXAMPLE_TEXT).unwrap();
+        assert_eq!(addr.hash, EXAMPLE_BLOCK_HASH);
+        assert_eq!(addr.len, EXAMPLE_TEXT.len() as u64);
+        assert_eq!(block_dir.get(&addr).unwrap().0, EXAMPLE_TEXT);
+        // Storing it again is harmless.
+        assert_eq!(block_dir.store_block(EXAMPLE_TEXT).unwrap(), addr);
+    }
+
     #[test]
     pub fn write_same_data_again() {
         let (_testdir, block_dir) = setup();
@@ -537,4 +1188,352 @@ mod tests {
             assert_eq!(block_sizes.uncompressed, MAX_BLOCK_SIZE as u64);
         }
     }
+
+    #[test]
+    pub fn store_blocks_in_order() {
+        let (_testdir, block_dir) = setup();
+        block_dir.store_block(b"old").unwrap();
+        let blocks: Vec<Vec<u8>> = vec![
+            b"one".to_vec(),
+            b"old".to_vec(),
+            b"two".to_vec(),
+            b"one".to_vec(),
+        ];
+        let (addrs, stats) = block_dir.store_blocks(&blocks).unwrap();
+        assert_eq!(addrs.len(), 4);
+        for (addr, block) in addrs.iter().zip(&blocks) {
+            assert_eq!(&block_dir.get(addr).unwrap().0, block);
+        }
+        assert_eq!(addrs[0], addrs[3]);
+        assert_eq!(stats.written_blocks, 2);
+        assert_eq!(stats.deduplicated_blocks, 2);
+        assert_eq!(stats.deduplicated_bytes, 6);
+    }
+
+    #[test]
+    pub fn queue_keeps_entries_in_order() {
+        let (_testdir, block_dir) = setup();
+        let mut store = StoreFiles::new(block_dir.clone());
+        let entry = |apath: &str, kind| IndexEntry {
+            apath: apath.into(),
+            kind,
+            mtime: 0,
+            mtime_nanos: 0,
+            addrs: Vec::new(),
+            target: None,
+            ntfs: None,
+            statx: None,
+        };
+        store.queue_entry(entry("/", Kind::Dir)).unwrap();
+        store
+            .queue_file(entry("/a", Kind::File), &mut io::Cursor::new(b"aaa"))
+            .unwrap();
+        store.queue_entry(entry("/b", Kind::Dir)).unwrap();
+        // Big enough to fill the queue part way through, so that the entries
+        // before it are released.
+        let big: Vec<u8> = (0..(Tuning::default().flush_bytes + MAX_BLOCK_SIZE / 2))
+            .map(|i| (i / MAX_BLOCK_SIZE) as u8)
+            .collect();
+        store
+            .queue_file(entry("/c", Kind::File), &mut io::Cursor::new(&big))
+            .unwrap();
+        let ready: Vec<String> = store
+            .take_ready()
+            .into_iter()
+            .map(|e| e.apath.into())
+            .collect();
+        assert_eq!(ready, ["/", "/a", "/b"]);
+        store
+            .queue_file(entry("/d", Kind::File), &mut io::Cursor::new(b"ddd"))
+            .unwrap();
+        store.flush().unwrap();
+        let ready = store.take_ready();
+        assert_eq!(ready.len(), 2);
+        assert_eq!(ready[0].apath, "/c");
+        assert_eq!(ready[0].addrs.len(), 17);
+        let mut content = Vec::new();
+        for addr in &ready[0].addrs {
+            content.extend(block_dir.get(addr).unwrap().0);
+        }
+        assert_eq!(content, big);
+        assert_eq!(ready[1].apath, "/d");
+
+        let stats = store.take_stats();
+        assert_eq!(stats.written_blocks, 19);
+        assert_eq!(stats.single_block_files, 2);
+        assert_eq!(stats.multi_block_files, 1);
+        assert_eq!(stats.uncompressed_bytes, big.len() as u64 + 6);
+    }
+
+    #[test]
+    pub fn combine_small_files() {
+        let (_testdir, block_dir) = setup();
+        let mut store = StoreFiles::new(block_dir.clone()).with_small_file_size(5);
+        let contents: [&[u8]; 4] = [b"one", b"two", b"toolong", b"three"];
+        for (i, content) in contents.iter().enumerate() {
+            let entry = IndexEntry {
+                apath: format!("/{}", i).into(),
+                kind: Kind::File,
+                mtime: 0,
+                mtime_nanos: 0,
+                addrs: Vec::new(),
+                target: None,
+                ntfs: None,
+                statx: None,
+            };
+            store
+                .queue_file(entry, &mut io::Cursor::new(content))
+                .unwrap();
+        }
+        store.flush().unwrap();
+        let ready = store.take_ready();
+        let addrs: Vec<&Address> = ready.iter().map(|e| &e.addrs[0]).collect();
+        assert_eq!(addrs[0].hash, addrs[1].hash);
+        assert_eq!(addrs[0].hash, addrs[3].hash);
+        assert_ne!(addrs[0].hash, addrs[2].hash);
+        assert_eq!((addrs[1].start, addrs[1].len), (3, 3));
+        assert_eq!((addrs[3].start, addrs[3].len), (6, 5));
+        for (addr, content) in addrs.iter().zip(&contents) {
+            assert_eq!(&block_dir.get(addr).unwrap().0, content);
+        }
+        let stats = store.take_stats();
+        assert_eq!(stats.combined_files, 3);
+        assert_eq!(stats.written_blocks, 2);
+
+        // With combining turned off, every file has its own block.
+        let mut store = StoreFiles::new(block_dir).with_small_file_size(0);
+        let (addrs, stats) = store
+            .store_file_content(&"/new".into(), &mut io::Cursor::new(b"new"))
+            .unwrap();
+        assert_eq!(addrs[0].start, 0);
+        assert_eq!(stats.combined_files, 0);
+    }
+
+    #[test]
+    pub fn shared_blocks_are_decompressed_once() {
+        let (_testdir, block_dir) = setup();
+        let mut store = StoreFiles::new(block_dir.clone()).with_small_file_size(5);
+        for (i, content) in [b"one", b"two", b"six"].iter().enumerate() {
+            let entry = IndexEntry {
+                apath: format!("/{}", i).into(),
+                kind: Kind::File,
+                mtime: 0,
+                mtime_nanos: 0,
+                addrs: Vec::new(),
+                target: None,
+                ntfs: None,
+                statx: None,
+            };
+            store
+                .queue_file(entry, &mut io::Cursor::new(content))
+                .unwrap();
+        }
+        store.flush().unwrap();
+        let addrs: Vec<Address> = store
+            .take_ready()
+            .into_iter()
+            .map(|e| e.addrs[0].clone())
+            .collect();
+        let (whole, _stats) = store
+            .store_file_content(&"/big".into(), &mut io::Cursor::new(b"too big"))
+            .unwrap();
+        assert!(addrs.iter().all(|a| a.hash == addrs[0].hash));
+
+        let reader = block_dir.clone();
+        let before = reader.blocks_read();
+        for _ in 0..2 {
+            for (addr, content) in addrs.iter().zip(&[b"one", b"two", b"six"]) {
+                assert_eq!(&reader.get(addr).unwrap().0, content);
+            }
+        }
+        assert_eq!(reader.blocks_read() - before, 1);
+
+        // Blocks that hold only one address aren't kept.
+        for _ in 0..2 {
+            assert_eq!(reader.get(&whole[0]).unwrap().0, b"too big");
+        }
+        assert_eq!(block_dir.blocks_read() - before, 3);
+    }
+
+    #[test]
+    pub fn tuned_block_size() {
+        let (_testdir, block_dir) = setup();
+        let tuning = Tuning {
+            block_size: 4096,
+            small_file_size: 0,
+            flush_bytes: 8192,
+            ..Tuning::default()
+        };
+        let mut store = StoreFiles::new(block_dir.clone()).with_tuning(&tuning);
+        let content: Vec<u8> = (0..10_000).map(|i| (i / 4096) as u8).collect();
+        let (addrs, stats) = store
+            .store_file_content(&"/big".into(), &mut io::Cursor::new(&content))
+            .unwrap();
+        assert_eq!(
+            addrs.iter().map(|a| a.len).collect::<Vec<u64>>(),
+            [4096, 4096, 1808]
+        );
+        assert_eq!(stats.written_blocks, 3);
+        let mut stored = Vec::new();
+        for addr in &addrs {
+            stored.extend(block_dir.get(addr).unwrap().0);
+        }
+        assert_eq!(stored, content);
+    }
+
+    /// Returns at most `chunk` bytes from each read.
+    struct ShortReads<'a> {
+        data: &'a [u8],
+        chunk: usize,
+    }
+
+    impl Read for ShortReads<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = self.chunk.min(buf.len()).min(self.data.len());
+            buf[..len].copy_from_slice(&self.data[..len]);
+            self.data = &self.data[len..];
+            Ok(len)
+        }
+    }
+
+    #[test]
+    pub fn short_reads_fill_blocks() {
+        let (_testdir, block_dir) = setup();
+        let content: Vec<u8> = (0..MAX_BLOCK_SIZE + 10).map(|i| i as u8).collect();
+        let mut store = StoreFiles::new(block_dir);
+        let (addrs, _stats) = store
+            .store_file_content(
+                &"/f".into(),
+                &mut ShortReads {
+                    data: &content,
+                    chunk: 1000,
+                },
+            )
+            .unwrap();
+        assert_eq!(
+            addrs.iter().map(|a| a.len).collect::<Vec<u64>>(),
+            [MAX_BLOCK_SIZE as u64, 10]
+        );
+    }
+
+    #[test]
+    pub fn queued_blocks_hold_only_what_was_read() {
+        let (_testdir, block_dir) = setup();
+        let mut store = StoreFiles::new(block_dir).with_small_file_size(0);
+        for i in 0..3 {
+            store
+                .queue_file(
+                    IndexEntry {
+                        apath: format!("/{}", i).into(),
+                        kind: Kind::File,
+                        mtime: 0,
+                        mtime_nanos: 0,
+                        addrs: Vec::new(),
+                        target: None,
+                        ntfs: None,
+                        statx: None,
+                    },
+                    &mut io::Cursor::new(b"small"),
+                )
+                .unwrap();
+        }
+        assert_eq!(store.queued_bytes, 15);
+        for block in &store.blocks {
+            assert_eq!(block.capacity(), 5);
+        }
+    }
+
+    #[test]
+    pub fn new_generation_forgets_present_blocks() {
+        let (testdir, block_dir) = setup();
+        let generation = block_dir.generation().unwrap();
+        assert_eq!(generation.len(), 32);
+        assert_eq!(
+            BlockDir::new(testdir.path()).generation().unwrap(),
+            generation
+        );
+        let addr = block_dir.store_block(EXAMPLE_TEXT).unwrap();
+        fs::remove_file(testdir.path().join("66a").join(EXAMPLE_BLOCK_HASH)).unwrap();
+        assert!(block_dir.contains(&addr.hash).unwrap());
+
+        let new_generation = block_dir.clone().new_generation().unwrap();
+        assert_ne!(new_generation, generation);
+        assert_eq!(block_dir.generation().unwrap(), new_generation);
+        assert!(!block_dir.contains(&addr.hash).unwrap());
+    }
+
+    #[test]
+    pub fn remember_present_blocks() {
+        let (testdir, block_dir) = setup();
+        let addr = block_dir.store_block(EXAMPLE_TEXT).unwrap();
+        let clone = block_dir.clone();
+        // Blocks found or stored by this process aren't looked up again.
+        fs::remove_file(testdir.path().join("66a").join(EXAMPLE_BLOCK_HASH)).unwrap();
+        assert!(clone.contains(&addr.hash).unwrap());
+        assert!(!BlockDir::new(testdir.path()).contains(&addr.hash).unwrap());
+    }
+
+    #[test]
+    pub fn verify_writes() {
+        let (testdir, block_dir) = setup();
+        let block_dir = block_dir.with_verify_writes(true);
+        let addr = block_dir.store_block(EXAMPLE_TEXT).unwrap();
+        assert_eq!(block_dir.get(&addr).unwrap().0, EXAMPLE_TEXT);
+
+        // Damage the block as if it had been written wrongly.
+        let mut damaged = Vec::new();
+        Snappy::compress_and_write(b"hellO!", &mut damaged).unwrap();
+        fs::write(testdir.path().join("66a").join(EXAMPLE_BLOCK_HASH), damaged).unwrap();
+        assert!(matches!(
+            block_dir.verify_write(EXAMPLE_BLOCK_HASH),
+            Err(Error::BlockCorrupt { .. })
+        ));
+        assert!(block_dir
+            .with_verify_writes(false)
+            .verify_write(EXAMPLE_BLOCK_HASH)
+            .is_ok());
+    }
+
+    #[test]
+    pub fn blockdir_stats() {
+        let (testdir, block_dir) = setup();
+        let addr = block_dir.store_block(EXAMPLE_TEXT).unwrap();
+        block_dir.store_block(&[b'x'; 5000]).unwrap();
+        fs::write(testdir.path().join("66a").join("tmp12345"), b"").unwrap();
+        fs::write(testdir.path().join("junk"), b"").unwrap();
+        // A copy of the block in the wrong place.
+        fs::create_dir(testdir.path().join("000")).unwrap();
+        fs::copy(
+            block_dir.path_for_file(&addr.hash),
+            testdir.path().join("000").join(&addr.hash),
+        )
+        .unwrap();
+
+        let stats = block_dir.stats().unwrap();
+        assert_eq!(stats.block_count, 3);
+        assert_eq!(stats.sizes.uncompressed, 5000 + 6 + 6);
+        assert_eq!(stats.subdirs.len(), 3);
+        assert_eq!(stats.subdirs["66a"].block_count, 1);
+        assert_eq!(stats.subdirs["66a"].sizes.compressed, 8);
+        assert_eq!(stats.size_histogram[&8], 2);
+        assert_eq!(stats.temp_files, 1);
+        assert_eq!(stats.unexpected_files, [testdir.path().join("junk")]);
+        assert_eq!(
+            stats.misplaced_blocks,
+            [testdir.path().join("000").join(&addr.hash)]
+        );
+        assert_eq!(stats.duplicate_blocks, [addr.hash]);
+        assert!(stats.unreadable_blocks.is_empty());
+        assert!(stats.has_anomalies());
+
+        let mut out = Vec::new();
+        stats.summarize(&mut out, true).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("           3      blocks\n"), "{}", out);
+        assert!(
+            out.contains("         66a               1 blocks"),
+            "{}",
+            out
+        );
+    }
 }
@@ -0,0 +1,106 @@
+// Conserve backup system.
+// Copyright 2020 Martin Pool.
+
+//! Compare two archives, such as an archive and its replica, by their lists
+//! of bands and block hashes, without reading any block content.
+
+use std::collections::BTreeSet;
+
+use crate::*;
+
+/// The differences between two archives, called left and right.
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct ArchiveComparison {
+    /// Bands only in the left archive.
+    pub left_only_bands: Vec<BandId>,
+    /// Bands only in the right archive.
+    pub right_only_bands: Vec<BandId>,
+    /// Bands in both archives, but complete in only one of them.
+    pub unfinished_bands: Vec<BandId>,
+    /// Hashes of blocks only in the left archive.
+    pub left_only_blocks: Vec<String>,
+    /// Hashes of blocks only in the right archive.
+    pub right_only_blocks: Vec<String>,
+    /// The number of bands in both archives.
+    pub common_bands: usize,
+    /// The number of blocks in both archives.
+    pub common_blocks: usize,
+}
+
+impl ArchiveComparison {
+    /// True if the archives have the same bands, equally complete, and the
+    /// same blocks.
+    pub fn is_same(&self) -> bool {
+        self.difference_count() == 0
+    }
+
+    /// The number of bands and blocks that differ.
+    pub fn difference_count(&self) -> usize {
+        self.left_only_bands.len()
+            + self.right_only_bands.len()
+            + self.unfinished_bands.len()
+            + self.left_only_blocks.len()
+            + self.right_only_blocks.len()
+    }
+}
+
+/// Compare the bands and blocks of two archives.
+///
+/// Blocks are compared by their hashes, which are their names, so neither
+/// archive's block content is read.
+pub fn compare_archives(left: &Archive, right: &Archive) -> Result<ArchiveComparison> {
+    let mut comparison = ArchiveComparison::default();
+
+    let left_bands: BTreeSet<BandId> = left.list_bands()?.into_iter().collect();
+    let right_bands: BTreeSet<BandId> = right.list_bands()?.into_iter().collect();
+    comparison.left_only_bands = left_bands.difference(&right_bands).cloned().collect();
+    comparison.right_only_bands = right_bands.difference(&left_bands).cloned().collect();
+    for band_id in left_bands.intersection(&right_bands) {
+        comparison.common_bands += 1;
+        if Band::open(left, band_id)?.is_closed()? != Band::open(right, band_id)?.is_closed()? {
+            comparison.unfinished_bands.push(band_id.clone());
+        }
+    }
+
+    let left_blocks: BTreeSet<String> = left.block_dir().block_names()?.collect();
+    let right_blocks: BTreeSet<String> = right.block_dir().block_names()?.collect();
+    comparison.left_only_blocks = left_blocks.difference(&right_blocks).cloned().collect();
+    comparison.right_only_blocks = right_blocks.difference(&left_blocks).cloned().collect();
+    comparison.common_blocks = left_blocks.intersection(&right_blocks).count();
+    Ok(comparison)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{ScratchArchive, TreeFixture};
+
+    #[test]
+    fn compare_archive_and_pushed_copy() {
+        let af = ScratchArchive::new();
+        af.store_two_versions();
+        let copy = ScratchArchive::new();
+        PushOptions::new(af.path(), copy.archive_dir_str())
+            .run()
+            .unwrap();
+
+        let comparison = compare_archives(&af, &copy).unwrap();
+        assert!(comparison.is_same(), "{:?}", comparison);
+        assert_eq!(comparison.common_bands, 2);
+        assert!(comparison.common_blocks > 0);
+
+        // A newer backup is only in the original, until it's pushed again.
+        let srcdir = TreeFixture::new();
+        srcdir.create_file_with_contents("new", b"new content");
+        BackupOptions::new(srcdir.path(), af.path()).run().unwrap();
+        let comparison = compare_archives(&af, &copy).unwrap();
+        assert_eq!(comparison.left_only_bands, [BandId::new(&[2])]);
+        assert!(comparison.right_only_bands.is_empty());
+        assert_eq!(comparison.left_only_blocks.len(), 1);
+        assert!(comparison.right_only_blocks.is_empty());
+        assert_eq!(comparison.difference_count(), 2);
+
+        let comparison = compare_archives(&copy, &af).unwrap();
+        assert_eq!(comparison.right_only_bands, [BandId::new(&[2])]);
+    }
+}
@@ -16,11 +16,31 @@ impl super::Compression for Snappy {
     }
 }
 
+/// Return the uncompressed length of a compressed file, reading only its
+/// header.
+pub fn uncompressed_len_of_file<P: AsRef<Path>>(p: P) -> io::Result<u64> {
+    use std::io::Read;
+
+    // The length is a varint of at most 10 bytes.
+    let mut header = Vec::with_capacity(10);
+    std::fs::File::open(p.as_ref())?
+        .take(10)
+        .read_to_end(&mut header)?;
+    snap::decompress_len(&header)
+        .map(|len| len as u64)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
 pub fn decompress_file<P: AsRef<Path>>(p: P) -> io::Result<(usize, Vec<u8>)> {
+    crate::faults::before_read(p.as_ref())?;
     let buf = std::fs::read(p.as_ref())?;
-    // TODO: Pass back error from snap decoder.
-    Ok((
-        buf.len(),
-        snap::Decoder::new().decompress_vec(&buf).unwrap(),
-    ))
+    let decompressed = decompress(&buf)?;
+    Ok((buf.len(), decompressed))
+}
+
+/// Decompress a buffer, failing rather than panicking if it's corrupt.
+pub fn decompress(buf: &[u8]) -> io::Result<Vec<u8>> {
+    snap::Decoder::new()
+        .decompress_vec(buf)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
 }
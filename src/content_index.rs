@@ -0,0 +1,373 @@
+// Conserve backup system.
+// Copyright 2020 Martin Pool.
+
+//! An optional sidecar in each band listing the hash of every file's whole
+//! content, so that `find` can say which versions hold some content without
+//! reading their indexes or blocks.
+//!
+//! The sidecar is a `CONTENTS` file in the band directory, holding one json
+//! object per line, sorted by hash. Hashes are the hex BLAKE2b-512 of the
+//! whole file, as printed by `b2sum`.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use blake2_rfc::blake2b::Blake2b;
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+
+use crate::blockdir::{Address, BLAKE_HASH_SIZE_BYTES};
+use crate::io::AtomicFile;
+use crate::*;
+
+pub(crate) const CONTENT_INDEX_FILENAME: &str = "CONTENTS";
+
+/// One file in a content index.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ContentEntry {
+    /// Hex BLAKE2b-512 hash of the file's whole content.
+    pub hash: String,
+    /// Size of the file in bytes.
+    pub size: u64,
+    pub apath: Apath,
+}
+
+/// Files found by `find_content`.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct ContentMatches {
+    /// Each file with matching content, with the band holding it, in band
+    /// order.
+    pub matches: Vec<(BandId, ContentEntry)>,
+    /// Bands that have no content index, and so weren't searched.
+    pub unindexed_bands: Vec<BandId>,
+}
+
+/// Files in one tree with identical content.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DuplicateSet {
+    /// Hex content hash shared by the files.
+    pub hash: String,
+    /// Size of each file.
+    pub size: u64,
+    /// The identical files, in apath order.
+    pub apaths: Vec<Apath>,
+    /// True if every file refers to the same stored blocks, so the content
+    /// is only stored once in the archive.
+    pub stored_once: bool,
+}
+
+impl DuplicateSet {
+    /// The space that would be freed if the files shared one copy.
+    pub fn redundant_bytes(&self) -> u64 {
+        self.size * (self.apaths.len() as u64 - 1)
+    }
+}
+
+/// Collects the content hashes of files in a band being written.
+#[derive(Debug, Default)]
+pub(crate) struct ContentIndexBuilder {
+    entries: Vec<ContentEntry>,
+}
+
+impl ContentIndexBuilder {
+    pub fn push(&mut self, apath: Apath, hash: String, size: u64) {
+        self.entries.push(ContentEntry { hash, size, apath });
+    }
+
+    /// Write the content index into the band directory.
+    pub fn write(mut self, band_path: &Path) -> Result<()> {
+        self.entries
+            .sort_unstable_by(|a, b| (&a.hash, &a.apath).cmp(&(&b.hash, &b.apath)));
+        let path = band_path.join(CONTENT_INDEX_FILENAME);
+        let ctx = || errors::WriteMetadata { path: path.clone() };
+        let mut af = AtomicFile::new(&path).with_context(ctx)?;
+        {
+            let mut w = BufWriter::new(&mut af);
+            for entry in &self.entries {
+                serde_json::to_writer(&mut w, entry)
+                    .context(errors::SerializeJson { path: path.clone() })?;
+                w.write_all(b"\n").with_context(ctx)?;
+            }
+            w.flush().with_context(ctx)?;
+        }
+        af.close().with_context(ctx)
+    }
+}
+
+/// Read the content index of a band, or None if it doesn't have one.
+fn read_content_index(band_path: &Path) -> Result<Option<Vec<ContentEntry>>> {
+    let path = band_path.join(CONTENT_INDEX_FILENAME);
+    let f = match fs::File::open(&path) {
+        Ok(f) => f,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).context(errors::ReadMetadata { path }),
+    };
+    let mut entries = Vec::new();
+    for line in BufReader::new(f).lines() {
+        let line = line.context(errors::ReadMetadata { path: path.clone() })?;
+        entries.push(
+            serde_json::from_str(&line).context(errors::DeserializeJson { path: path.clone() })?,
+        );
+    }
+    Ok(Some(entries))
+}
+
+/// Read the content hashes of a band by apath, if it has a content index.
+pub(crate) fn read_hashes_by_apath(band_path: &Path) -> Result<Option<BTreeMap<Apath, String>>> {
+    Ok(read_content_index(band_path)?.map(|entries| {
+        entries
+            .into_iter()
+            .map(|entry| (entry.apath, entry.hash))
+            .collect()
+    }))
+}
+
+/// Passes through reads while hashing everything read.
+pub(crate) struct HashingReader<'a> {
+    inner: &'a mut dyn Read,
+    hasher: Blake2b,
+    len: u64,
+}
+
+impl<'a> HashingReader<'a> {
+    pub fn new(inner: &'a mut dyn Read) -> HashingReader<'a> {
+        HashingReader {
+            inner,
+            hasher: Blake2b::new(BLAKE_HASH_SIZE_BYTES),
+            len: 0,
+        }
+    }
+
+    /// The hex hash and length of everything read.
+    pub fn finish(self) -> (String, u64) {
+        (hex::encode(self.hasher.finalize().as_bytes()), self.len)
+    }
+}
+
+impl Read for HashingReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.len += n as u64;
+        Ok(n)
+    }
+}
+
+/// Hash the whole content of a stored file, reading it from its blocks.
+pub(crate) fn hash_stored_content(
+    block_dir: &BlockDir,
+    addrs: &[Address],
+) -> Result<(String, u64)> {
+    let mut hasher = Blake2b::new(BLAKE_HASH_SIZE_BYTES);
+    let mut len = 0;
+    for addr in addrs {
+        let (content, _sizes) = block_dir.get(addr)?;
+        hasher.update(&content);
+        len += content.len() as u64;
+    }
+    Ok((hex::encode(hasher.finalize().as_bytes()), len))
+}
+
+/// Write a content index for a band that's already stored, by reading the
+/// content of all its files, and return the number of files.
+///
+/// Any existing content index is replaced.
+pub fn build_content_index(archive: &Archive, band_id: &BandId) -> Result<usize> {
+    let band = Band::open(archive, band_id)?;
+    let tree = StoredTree::open_version(archive, band_id)?;
+    let mut builder = ContentIndexBuilder::default();
+    ui::set_progress_phase(&format!("Hash files in {}", band_id));
+    for entry in tree.iter_entries()? {
+        if entry.kind() == Kind::File {
+            let (hash, size) = hash_stored_content(archive.block_dir(), &entry.addrs)?;
+            ui::increment_bytes_done(size);
+            builder.push(entry.apath, hash, size);
+        }
+    }
+    let count = builder.entries.len();
+    builder.write(band.path())?;
+    Ok(count)
+}
+
+/// Return the complete bands of the archive's selected tree that have no
+/// content index.
+pub fn unindexed_bands(archive: &Archive) -> Result<Vec<BandId>> {
+    let mut band_ids = Vec::new();
+    for band_id in archive.list_bands()? {
+        let band = Band::open(archive, &band_id)?;
+        if band.is_closed()? && !band.path().join(CONTENT_INDEX_FILENAME).is_file() {
+            band_ids.push(band_id);
+        }
+    }
+    Ok(band_ids)
+}
+
+/// Find the files, in any band of the archive's selected tree, whose content
+/// hash starts with `hash_prefix`.
+///
+/// Only bands with content indexes are searched.
+pub fn find_content(archive: &Archive, hash_prefix: &str) -> Result<ContentMatches> {
+    let hash_prefix = hash_prefix.to_ascii_lowercase();
+    let mut found = ContentMatches::default();
+    for band_id in archive.list_bands()? {
+        let band = Band::open(archive, &band_id)?;
+        match read_content_index(band.path())? {
+            None => found.unindexed_bands.push(band_id),
+            Some(entries) => {
+                // Entries are sorted by hash, so the matches are together.
+                let first = entries.partition_point(|entry| entry.hash < hash_prefix);
+                found.matches.extend(
+                    entries[first..]
+                        .iter()
+                        .take_while(|entry| entry.hash.starts_with(&hash_prefix))
+                        .map(|entry| (band_id.clone(), entry.clone())),
+                );
+            }
+        }
+    }
+    Ok(found)
+}
+
+/// Find sets of non-empty files in a stored tree with identical content,
+/// using the band's content index.
+///
+/// Sets are returned with those that waste the most space first.
+pub fn find_duplicates(tree: &StoredTree) -> Result<Vec<DuplicateSet>> {
+    let band = tree.band();
+    let entries = match read_content_index(band.path())? {
+        Some(entries) => entries,
+        None => {
+            return errors::NoContentIndex {
+                band_id: band.id().clone(),
+            }
+            .fail()
+        }
+    };
+    // Entries are sorted by hash, so identical files are together.
+    let mut sets: Vec<DuplicateSet> = Vec::new();
+    for group in entries.chunk_by(|a, b| a.hash == b.hash) {
+        if group.len() > 1 && group[0].size > 0 {
+            sets.push(DuplicateSet {
+                hash: group[0].hash.clone(),
+                size: group[0].size,
+                apaths: group.iter().map(|entry| entry.apath.clone()).collect(),
+                stored_once: false,
+            });
+        }
+    }
+
+    // Look up where each duplicate is stored, to see whether the archive
+    // already shares its content.
+    let mut addrs: BTreeMap<Apath, Vec<Address>> = sets
+        .iter()
+        .flat_map(|set| set.apaths.iter().map(|apath| (apath.clone(), Vec::new())))
+        .collect();
+    if !addrs.is_empty() {
+        for entry in tree.iter_entries()? {
+            if let Some(entry_addrs) = addrs.get_mut(&entry.apath) {
+                *entry_addrs = entry.addrs;
+            }
+        }
+    }
+    for set in &mut sets {
+        let first = &addrs[&set.apaths[0]];
+        set.stored_once = set.apaths[1..].iter().all(|apath| addrs[apath] == *first);
+    }
+    sets.sort_by(|a, b| {
+        b.redundant_bytes()
+            .cmp(&a.redundant_bytes())
+            .then_with(|| a.apaths.cmp(&b.apaths))
+    });
+    Ok(sets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{ScratchArchive, TreeFixture};
+
+    #[test]
+    fn hashing_reader_matches_blockdir_hash() {
+        let mut content: &[u8] = b"hello world";
+        let mut reader = HashingReader::new(&mut content);
+        io::copy(&mut reader, &mut io::sink()).unwrap();
+        let (hash, len) = reader.finish();
+        assert_eq!(len, 11);
+        assert_eq!(hash, crate::blockdir::hash_bytes(b"hello world").unwrap());
+    }
+
+    #[test]
+    fn build_and_find() {
+        let af = ScratchArchive::new();
+        let srcdir = TreeFixture::new();
+        srcdir.create_file_with_contents("a", b"same");
+        srcdir.create_dir("sub");
+        srcdir.create_file_with_contents("sub/b", b"same");
+        srcdir.create_file_with_contents("c", b"different");
+        BackupOptions::new(srcdir.path(), af.path()).run().unwrap();
+        let hash = crate::blockdir::hash_bytes(b"same").unwrap();
+
+        let found = find_content(&af, &hash).unwrap();
+        assert!(found.matches.is_empty());
+        assert_eq!(found.unindexed_bands, [BandId::zero()]);
+
+        assert_eq!(unindexed_bands(&af).unwrap(), [BandId::zero()]);
+        assert_eq!(build_content_index(&af, &BandId::zero()).unwrap(), 3);
+        assert!(unindexed_bands(&af).unwrap().is_empty());
+        let found = find_content(&af, &hash[..12].to_ascii_uppercase()).unwrap();
+        assert!(found.unindexed_bands.is_empty());
+        let apaths: Vec<String> = found
+            .matches
+            .iter()
+            .map(|(band_id, entry)| format!("{} {} {}", band_id, entry.apath, entry.size))
+            .collect();
+        assert_eq!(apaths, ["b0000 /a 4", "b0000 /sub/b 4"]);
+        assert!(find_content(&af, "0000").unwrap().matches.is_empty());
+        af.validate().unwrap();
+    }
+
+    #[test]
+    fn duplicates() {
+        let af = ScratchArchive::new();
+        let srcdir = TreeFixture::new();
+        srcdir.create_file_with_contents("a", b"same");
+        srcdir.create_dir("sub");
+        srcdir.create_file_with_contents("sub/b", b"same");
+        srcdir.create_file_with_contents("sub/c", b"same");
+        srcdir.create_file_with_contents("big1", &[7; 20000]);
+        srcdir.create_file_with_contents("big2", &[7; 20000]);
+        srcdir.create_file_with_contents("unique", b"unique");
+        srcdir.create_file_with_contents("empty1", b"");
+        srcdir.create_file_with_contents("empty2", b"");
+        BackupOptions::new(srcdir.path(), af.path())
+            .small_file_size(10)
+            .run()
+            .unwrap();
+
+        let tree = StoredTree::open_last(&af).unwrap();
+        assert!(matches!(
+            find_duplicates(&tree),
+            Err(Error::NoContentIndex { .. })
+        ));
+        build_content_index(&af, &BandId::zero()).unwrap();
+        let sets = find_duplicates(&tree).unwrap();
+        assert_eq!(sets.len(), 2);
+        assert_eq!(sets[0].apaths, [Apath::from("/big1"), Apath::from("/big2")]);
+        assert_eq!(sets[0].redundant_bytes(), 20000);
+        // Big files are stored as separate blocks, so identical ones share.
+        assert!(sets[0].stored_once);
+        assert_eq!(
+            sets[1].apaths,
+            [
+                Apath::from("/a"),
+                Apath::from("/sub/b"),
+                Apath::from("/sub/c")
+            ]
+        );
+        assert_eq!(sets[1].redundant_bytes(), 8);
+        // Small files are combined into one block, each at its own offset.
+        assert!(!sets[1].stored_once);
+    }
+}
@@ -3,21 +3,38 @@
 
 //! Copy tree contents.
 
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
 #[allow(unused_imports)]
 use snafu::ResultExt;
+use tracing::{info_span, warn};
 
-use crate::stats::CopyStats;
+use crate::stats::{CopyStats, EntryProfile, EntryTime};
 use crate::*;
 
 #[derive(Default, Clone, Debug)]
 pub struct CopyOptions {
     pub print_filenames: bool,
     pub measure_first: bool,
+    /// Copy only directories and the entries matching this filter.
+    pub filter: Option<Filter>,
+    /// Once this is set, as by `interrupt::stop_on_signals`, stop before the
+    /// next entry, keeping what's been copied so far, and fail with
+    /// `Error::Interrupted`.
+    pub stop: Option<Arc<AtomicBool>>,
+    /// If set, time each entry, and keep the times of this many of the
+    /// slowest files and directories in `CopyStats::entry_profile`.
+    pub profile_entries: Option<usize>,
 }
 
 pub const COPY_DEFAULT: CopyOptions = CopyOptions {
     print_filenames: false,
     measure_first: false,
+    filter: None,
+    stop: None,
+    profile_entries: None,
 };
 
 /// Copy files and other entries from one tree to another.
@@ -26,6 +43,7 @@ pub fn copy_tree<ST: ReadTree, DT: WriteTree>(
     mut dest: DT,
     options: &CopyOptions,
 ) -> Result<CopyStats> {
+    let _span = info_span!("copy_tree").entered();
     let mut stats = CopyStats::default();
     // This causes us to walk the source tree twice, which is probably an acceptable option
     // since it's nice to see realistic overall progress. We could keep all the entries
@@ -39,7 +57,26 @@ pub fn copy_tree<ST: ReadTree, DT: WriteTree>(
         ui::set_bytes_total(source.size()?.file_bytes);
     }
     ui::set_progress_phase("Copying");
-    for entry in source.iter_entries()? {
+    let mut profile = options.profile_entries.map(EntryProfile::new);
+    let mut entries = source.iter_entries()?;
+    loop {
+        // Time reading the entry from the source, which may include listing
+        // its directory, as well as copying it.
+        let started = Instant::now();
+        let entry = match entries.next() {
+            Some(entry) => entry,
+            None => break,
+        };
+        let mut times = (Duration::default(), Duration::default());
+        if matches!(&options.stop, Some(stop) if stop.load(Ordering::SeqCst)) {
+            stop_early(dest);
+            return Err(Error::Interrupted);
+        }
+        if let Some(filter) = &options.filter {
+            if !filter.selects(&entry) {
+                continue;
+            }
+        }
         if options.print_filenames {
             crate::ui::println(entry.apath());
         }
@@ -51,12 +88,17 @@ pub fn copy_tree<ST: ReadTree, DT: WriteTree>(
             }
             Kind::File => {
                 stats.files += 1;
-                dest.copy_file(&entry, source).map(|s| stats += s)
+                dest.copy_file(&entry, source).map(|s| {
+                    times = (s.read_time, s.store_time);
+                    stats += s;
+                    ui::increment_files_done();
+                })
             }
             Kind::Symlink => {
                 stats.symlinks += 1;
                 dest.copy_symlink(&entry)
             }
+            Kind::Deleted => continue,
             Kind::Unknown => {
                 stats.unknown_kind += 1;
                 // TODO: Perhaps eventually we could backup and restore pipes,
@@ -65,14 +107,71 @@ pub fn copy_tree<ST: ReadTree, DT: WriteTree>(
                 continue;
             }
         } {
-            ui::show_error(&e);
+            if matches!(e, Error::LowFreeSpace { .. } | Error::DiskFull { .. }) {
+                // Keep what's been copied so far, rather than failing on
+                // every remaining entry.
+                stop_early(dest);
+                return Err(e);
+            }
+            let problem = match &e {
+                Error::ReadSourceFile { source, .. }
+                    if source.kind() == std::io::ErrorKind::PermissionDenied =>
+                {
+                    Problem::PermissionDenied {
+                        apath: entry.apath().to_string(),
+                        entry_kind: entry.kind(),
+                    }
+                }
+                _ => Problem::CopyEntry {
+                    apath: entry.apath().clone(),
+                    entry_kind: entry.kind(),
+                    message: ui::format_error(&e),
+                },
+            };
+            stats.problems.push(problem.emit());
             stats.errors += 1;
-            continue;
+        } else if !(entry.kind() == Kind::File && dest.reports_file_progress()) {
+            ui::increment_bytes_done(entry.size().unwrap_or(0));
+        }
+        if let Some(profile) = &mut profile {
+            profile.record(EntryTime {
+                apath: entry.apath().clone(),
+                kind: entry.kind(),
+                elapsed: started.elapsed(),
+                read_time: times.0,
+                store_time: times.1,
+            });
         }
-        ui::increment_bytes_done(entry.size().unwrap_or(0));
     }
     ui::clear_progress();
+    // Problems found while listing the source aren't counted as they're
+    // found, so count them now.
+    let problems = source.take_problems(&mut entries);
+    stats.errors += problems.len();
+    stats.problems += problems;
+    for (apath, count) in stats.problems.permission_denied_summary() {
+        warn!(
+            "{} {} under {:?} skipped because of their permissions",
+            count,
+            if count == 1 { "entry" } else { "entries" },
+            apath
+        );
+    }
     stats += dest.finish()?;
+    if let Some(profile) = profile {
+        stats.entry_profile = profile;
+    }
     // TODO: Merge in stats from the tree iter and maybe the source tree?
     Ok(stats)
 }
+
+/// Save what's been copied so far, when copying stops before the end.
+fn stop_early<DT: WriteTree>(dest: DT) {
+    ui::clear_progress();
+    if let Err(checkpoint_error) = dest.checkpoint() {
+        warn!(
+            "Failed to save what was copied before stopping: {}",
+            ui::format_error(&checkpoint_error)
+        );
+    }
+}
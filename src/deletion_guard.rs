@@ -0,0 +1,91 @@
+// Conserve backup system.
+// Copyright 2020 Martin Pool.
+
+//! A second secret, kept away from the backup client, that's required to
+//! remove anything from an archive.
+//!
+//! The archive stores only a random salt and the keyed hash of the secret,
+//! in the `DELETION_GUARD` file, so a host that can read and write backups
+//! can't learn the secret from the archive.
+
+use std::path::Path;
+
+use blake2_rfc::blake2b::Blake2b;
+use serde::{Deserialize, Serialize};
+
+use crate::jsonio::{read_json_metadata_file, write_json_metadata_file};
+use crate::*;
+
+pub(crate) const DELETION_GUARD_FILENAME: &str = "DELETION_GUARD";
+
+const SALT_LEN: usize = 32;
+const HASH_LEN: usize = 32;
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub(crate) struct DeletionGuard {
+    /// Hex random salt, used as the hash key.
+    salt: String,
+    /// Hex Blake2b hash of the secret, keyed by the salt.
+    hash: String,
+}
+
+impl DeletionGuard {
+    /// Make a guard for a new secret, with a fresh salt.
+    pub fn new(secret: &Secret) -> Result<DeletionGuard> {
+        let mut salt = [0; SALT_LEN];
+        getrandom::getrandom(&mut salt).map_err(|e| Error::GenerateKey {
+            message: e.to_string(),
+        })?;
+        Ok(DeletionGuard {
+            hash: keyed_hash(&salt, secret),
+            salt: hex::encode(salt),
+        })
+    }
+
+    /// Read the guard of the archive in `archive_path`, if it has one.
+    pub fn load(archive_path: &Path) -> Result<Option<DeletionGuard>> {
+        let path = archive_path.join(DELETION_GUARD_FILENAME);
+        if path.is_file() {
+            read_json_metadata_file(&path).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn save(&self, archive_path: &Path) -> Result<()> {
+        write_json_metadata_file(&archive_path.join(DELETION_GUARD_FILENAME), self)
+    }
+
+    /// True if `secret` is the one this guard was made from.
+    pub fn accepts(&self, secret: &Secret) -> bool {
+        match hex::decode(&self.salt) {
+            Ok(salt) => keyed_hash(&salt, secret) == self.hash,
+            Err(_) => false,
+        }
+    }
+}
+
+fn keyed_hash(salt: &[u8], secret: &Secret) -> String {
+    let mut hasher = Blake2b::with_key(HASH_LEN, salt);
+    hasher.update(secret.expose().as_bytes());
+    hex::encode(hasher.finalize().as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_only_its_secret() {
+        let secret = Secret::new("correct horse");
+        let guard = DeletionGuard::new(&secret).unwrap();
+        assert!(guard.accepts(&secret));
+        assert!(!guard.accepts(&Secret::new("battery staple")));
+        assert!(!guard.hash.contains("horse"));
+
+        // The same secret gets a different salt and hash each time.
+        let other = DeletionGuard::new(&secret).unwrap();
+        assert_ne!(guard, other);
+        assert!(other.accepts(&secret));
+    }
+}
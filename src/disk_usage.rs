@@ -0,0 +1,119 @@
+// Conserve backup system.
+// Copyright 2020 Martin Pool.
+
+//! Find the biggest files and directories in a tree, from the sizes in its
+//! entries, without reading any file content.
+
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+
+use crate::*;
+
+/// The biggest files and directories in a tree, each biggest first.
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct DiskUsage {
+    /// The biggest files, and their sizes.
+    pub files: Vec<(Apath, u64)>,
+
+    /// The directories holding the most file bytes, counting everything
+    /// inside them, and those totals.
+    pub dirs: Vec<(Apath, u64)>,
+}
+
+impl DiskUsage {
+    /// Measure the entries of a tree, and keep the `top` biggest files and
+    /// directories.
+    ///
+    /// Sizes are the uncompressed lengths of file content, so space shared
+    /// with other files or versions is counted in each of them.
+    pub fn measure<E: Entry>(entries: impl Iterator<Item = E>, top: usize) -> DiskUsage {
+        // Smallest first, so the smallest can be dropped once there are more
+        // than `top`.
+        let mut files = BinaryHeap::<Reverse<(u64, Reverse<Apath>)>>::new();
+        let mut dirs = HashMap::<String, u64>::new();
+        for entry in entries {
+            let apath = entry.apath();
+            match entry.kind() {
+                Kind::Dir => {
+                    dirs.entry(apath.to_string()).or_default();
+                }
+                Kind::File => {
+                    let size = entry.size().unwrap_or_default();
+                    let mut dir: &str = apath;
+                    while let Some(slash) = dir.rfind('/') {
+                        dir = if slash == 0 { "/" } else { &dir[..slash] };
+                        if let Some(total) = dirs.get_mut(dir) {
+                            *total += size;
+                        }
+                        if slash == 0 {
+                            break;
+                        }
+                    }
+                    files.push(Reverse((size, Reverse(apath.clone()))));
+                    if files.len() > top {
+                        files.pop();
+                    }
+                }
+                _ => (),
+            }
+        }
+        let mut dirs: Vec<(Apath, u64)> = dirs
+            .into_iter()
+            .map(|(apath, size)| (Apath::from(apath.as_str()), size))
+            .collect();
+        dirs.sort_by(|(a, a_size), (b, b_size)| b_size.cmp(a_size).then_with(|| a.cmp(b)));
+        dirs.truncate(top);
+        DiskUsage {
+            files: files
+                .into_sorted_vec()
+                .into_iter()
+                .map(|Reverse((size, Reverse(apath)))| (apath, size))
+                .collect(),
+            dirs,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{ScratchArchive, TreeFixture};
+
+    #[test]
+    fn biggest_files_and_dirs() {
+        let tf = TreeFixture::new();
+        tf.create_dir("a");
+        tf.create_dir("a/deep");
+        tf.create_dir("b");
+        tf.create_file_with_contents("top", &[0; 10]);
+        tf.create_file_with_contents("a/one", &[0; 100]);
+        tf.create_file_with_contents("a/deep/two", &[0; 1000]);
+        tf.create_file_with_contents("b/three", &[0; 50]);
+        tf.create_file_with_contents("b/four", &[0; 50]);
+        let af = ScratchArchive::new();
+        BackupOptions::new(tf.path(), af.path()).run().unwrap();
+        let st = StoredTree::open_last(&af).unwrap();
+
+        let usage = DiskUsage::measure(st.iter_entries().unwrap(), 3);
+        assert_eq!(
+            usage.files,
+            [
+                ("/a/deep/two".into(), 1000),
+                ("/a/one".into(), 100),
+                ("/b/four".into(), 50),
+            ]
+        );
+        assert_eq!(
+            usage.dirs,
+            [
+                ("/".into(), 1210),
+                ("/a".into(), 1100),
+                ("/a/deep".into(), 1000),
+            ]
+        );
+
+        let usage = DiskUsage::measure(st.iter_subtree(&"/b".into()).unwrap(), 10);
+        assert_eq!(usage.files.len(), 2);
+        assert_eq!(usage.dirs, [("/b".into(), 100)]);
+    }
+}
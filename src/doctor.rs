@@ -0,0 +1,364 @@
+// Conserve backup system.
+// Copyright 2020 Martin Pool.
+
+//! Diagnose common problems with an archive and the environment it's used
+//! in, for `conserve doctor`.
+//!
+//! Each check produces a `Finding`, which for warnings and problems says what
+//! to do about it. Checks don't change the archive, and one failing doesn't
+//! stop the others.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use chrono::{Duration, Utc};
+use serde::Serialize;
+
+use crate::misc::bytes_to_human;
+use crate::*;
+
+/// By default, warn if the archive's filesystem has less than this free.
+pub const DEFAULT_WARN_FREE_SPACE: u64 = 1 << 30;
+
+/// How far in the future a version may seem to have started before the clock
+/// is reported as wrong, allowing for small differences between machines.
+const CLOCK_SKEW_SECS: i64 = 300;
+
+/// How serious a finding is.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Serialize)]
+pub enum Severity {
+    Ok,
+    Warning,
+    Problem,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(match self {
+            Severity::Ok => "ok",
+            Severity::Warning => "warning",
+            Severity::Problem => "problem",
+        })
+    }
+}
+
+/// The result of one check.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct Finding {
+    pub severity: Severity,
+    /// The name of the check, like `free-space`.
+    pub check: &'static str,
+    pub message: String,
+    /// For warnings and problems, what to do about it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub advice: Option<String>,
+}
+
+impl Finding {
+    fn ok(check: &'static str, message: String) -> Finding {
+        Finding {
+            severity: Severity::Ok,
+            check,
+            message,
+            advice: None,
+        }
+    }
+
+    fn warning(check: &'static str, message: String, advice: &str) -> Finding {
+        Finding {
+            severity: Severity::Warning,
+            check,
+            message,
+            advice: Some(advice.to_owned()),
+        }
+    }
+
+    fn problem(check: &'static str, message: String, advice: &str) -> Finding {
+        Finding {
+            severity: Severity::Problem,
+            check,
+            message,
+            advice: Some(advice.to_owned()),
+        }
+    }
+}
+
+/// Checks to run on an archive, and optionally a source directory.
+#[derive(Debug, Clone)]
+pub struct DoctorOptions {
+    archive: PathBuf,
+    source: Option<PathBuf>,
+    warn_free_space: u64,
+}
+
+impl DoctorOptions {
+    /// Check the archive at `archive`.
+    pub fn new<P: AsRef<Path>>(archive: P) -> DoctorOptions {
+        DoctorOptions {
+            archive: archive.as_ref().to_path_buf(),
+            source: None,
+            warn_free_space: DEFAULT_WARN_FREE_SPACE,
+        }
+    }
+
+    /// Also check that this source directory can be read.
+    pub fn source<P: AsRef<Path>>(self, source: P) -> DoctorOptions {
+        DoctorOptions {
+            source: Some(source.as_ref().to_path_buf()),
+            ..self
+        }
+    }
+
+    /// Warn if the archive's filesystem has less than this many bytes free.
+    pub fn warn_free_space(self, warn_free_space: u64) -> DoctorOptions {
+        DoctorOptions {
+            warn_free_space,
+            ..self
+        }
+    }
+
+    /// Run all the checks, returning what they found.
+    ///
+    /// If the archive can't be opened, the checks that read it are skipped.
+    pub fn run(&self) -> Vec<Finding> {
+        let mut findings = Vec::new();
+        match Archive::open(&self.archive) {
+            Ok(archive) => {
+                findings.push(Finding::ok(
+                    "archive",
+                    format!(
+                        "Archive {:?} is readable, in format {}",
+                        self.archive,
+                        archive.archive_version()
+                    ),
+                ));
+                findings.push(check_writable(&self.archive));
+                findings.extend(check_versions(&archive));
+                findings.extend(check_free_space(&self.archive, self.warn_free_space));
+            }
+            Err(err) => findings.push(archive_problem(&err)),
+        }
+        if let Some(source) = &self.source {
+            findings.push(check_source(source));
+        }
+        findings
+    }
+}
+
+fn archive_problem(err: &Error) -> Finding {
+    let advice = match err {
+        Error::NotAnArchive { .. } => {
+            "Check the path, or make a new archive there with `conserve init`."
+        }
+        Error::UnsupportedArchiveVersion { .. } => {
+            "Use a version of Conserve that supports this archive's format."
+        }
+        _ => "Check the archive's filesystem is mounted and readable by this user.",
+    };
+    Finding::problem("archive", ui::format_error(err), advice)
+}
+
+/// Check that new files can be made in the archive, by making and removing a
+/// temporary file.
+fn check_writable(archive: &Path) -> Finding {
+    match tempfile::NamedTempFile::new_in(archive) {
+        Ok(_) => Finding::ok("writable", "Archive is writable".to_owned()),
+        Err(err) => Finding::problem(
+            "writable",
+            format!("Can't write in archive {:?}: {}", archive, err),
+            "Backups need to write to the archive: check its permissions, and that \
+             its filesystem isn't mounted read-only.",
+        ),
+    }
+}
+
+/// Check every version can be read, that the latest is complete, and that
+/// none seems to have started in the future.
+fn check_versions(archive: &Archive) -> Vec<Finding> {
+    let band_ids = match archive.list_bands() {
+        Ok(band_ids) => band_ids,
+        Err(err) => {
+            return vec![Finding::problem(
+                "versions",
+                ui::format_error(&err),
+                "Check the archive directory is readable by this user.",
+            )]
+        }
+    };
+    let mut findings = Vec::new();
+    let mut infos = Vec::new();
+    for band_id in &band_ids {
+        match Band::open(archive, band_id).and_then(|band| band.get_info()) {
+            Ok(info) => infos.push(info),
+            Err(err) => findings.push(Finding::problem(
+                "versions",
+                format!("Can't read version {}: {}", band_id, ui::format_error(&err)),
+                "Run `conserve validate` to check the archive; a newer version of \
+                 Conserve may be needed to read it.",
+            )),
+        }
+    }
+    if findings.is_empty() {
+        findings.push(Finding::ok(
+            "versions",
+            format!("All {} versions are readable", band_ids.len()),
+        ));
+    }
+
+    match infos.last() {
+        Some(info) if !info.is_closed => findings.push(Finding::warning(
+            "in-progress",
+            format!(
+                "Latest version {} is incomplete: a backup is still running, or was \
+                 interrupted",
+                info.id
+            ),
+            "If no backup is running, the next backup will start a new version and \
+             reuse the blocks already stored.",
+        )),
+        _ => findings.push(Finding::ok(
+            "in-progress",
+            "No backup seems to be in progress".to_owned(),
+        )),
+    }
+
+    let now = Utc::now();
+    match infos
+        .iter()
+        .map(|info| info.end_time.unwrap_or(info.start_time))
+        .max()
+    {
+        Some(latest) if latest > now + Duration::seconds(CLOCK_SKEW_SECS) => {
+            findings.push(Finding::problem(
+                "clock",
+                format!(
+                    "The clock says {}, before the latest version was written at {}",
+                    now.to_rfc3339(),
+                    latest.to_rfc3339()
+                ),
+                "Check the system clock and time zone: versions are ordered and chosen \
+                 by time, and a slow clock makes new versions look older.",
+            ))
+        }
+        _ => findings.push(Finding::ok(
+            "clock",
+            "The clock is later than every version".to_owned(),
+        )),
+    }
+    findings
+}
+
+fn check_free_space(archive: &Path, warn_free_space: u64) -> Option<Finding> {
+    match crate::io::available_space(archive) {
+        Ok(Some(available)) if available < warn_free_space => Some(Finding::warning(
+            "free-space",
+            format!(
+                "Only {} free on the filesystem holding the archive",
+                bytes_to_human(available)
+            ),
+            "Backups stop cleanly when space runs out, but may be incomplete: make \
+             more space, or move the archive to a bigger filesystem.",
+        )),
+        Ok(Some(available)) => Some(Finding::ok(
+            "free-space",
+            format!("{} free for the archive", bytes_to_human(available)),
+        )),
+        // Not measurable on this platform.
+        Ok(None) => None,
+        Err(err) => Some(Finding::warning(
+            "free-space",
+            format!("Can't check free space: {}", err),
+            "Check the archive's filesystem is mounted.",
+        )),
+    }
+}
+
+/// Check the source directory can be listed.
+fn check_source(source: &Path) -> Finding {
+    match fs::read_dir(source) {
+        Ok(_) => Finding::ok("source", format!("Source {:?} is readable", source)),
+        Err(err) => {
+            let advice = match err.kind() {
+                io::ErrorKind::NotFound => "Check the source path, and that it's mounted.",
+                io::ErrorKind::PermissionDenied => {
+                    "Run the backup as a user that can read the source, or use \
+                     --escalate-command 'sudo -n'."
+                }
+                _ => "Check the source is a directory readable by this user.",
+            };
+            Finding::problem(
+                "source",
+                format!("Can't read source {:?}: {}", source, err),
+                advice,
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{ScratchArchive, TreeFixture};
+
+    fn checks(findings: &[Finding], severity: Severity) -> Vec<&'static str> {
+        findings
+            .iter()
+            .filter(|f| f.severity == severity)
+            .map(|f| f.check)
+            .collect()
+    }
+
+    #[test]
+    fn healthy_archive() {
+        let af = ScratchArchive::new();
+        af.store_two_versions();
+        let source = TreeFixture::new();
+        let findings = DoctorOptions::new(af.path())
+            .source(source.path())
+            .warn_free_space(0)
+            .run();
+        assert!(checks(&findings, Severity::Problem).is_empty());
+        assert!(checks(&findings, Severity::Warning).is_empty());
+        for check in &[
+            "archive",
+            "writable",
+            "versions",
+            "in-progress",
+            "clock",
+            "source",
+        ] {
+            assert!(checks(&findings, Severity::Ok).contains(check), "{}", check);
+        }
+    }
+
+    #[test]
+    fn incomplete_version_and_low_space() {
+        let af = ScratchArchive::new();
+        af.setup_incomplete_empty_band();
+        let findings = DoctorOptions::new(af.path())
+            .warn_free_space(u64::MAX)
+            .run();
+        let warnings = checks(&findings, Severity::Warning);
+        assert!(warnings.contains(&"in-progress"));
+        if cfg!(any(target_os = "linux", target_os = "macos")) {
+            assert!(warnings.contains(&"free-space"));
+        }
+        assert!(checks(&findings, Severity::Problem).is_empty());
+    }
+
+    #[test]
+    fn missing_archive_and_source() {
+        let dir = TreeFixture::new();
+        let findings = DoctorOptions::new(dir.path())
+            .source(dir.path().join("nothing"))
+            .run();
+        assert_eq!(checks(&findings, Severity::Problem), ["archive", "source"]);
+        assert!(findings[0]
+            .advice
+            .as_ref()
+            .unwrap()
+            .contains("conserve init"));
+    }
+}
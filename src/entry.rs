@@ -17,7 +17,14 @@ pub enum Kind {
     File,
     Dir,
     Symlink,
+    /// In a layered index, marks that an entry of the band below is no
+    /// longer present. Trees read from the archive never return these.
+    Deleted,
     /// Unknown file observed in local tree. Shouldn't be stored.
+    ///
+    /// Kinds added to the index by later versions are also read as this, so
+    /// that they're skipped rather than making the whole index unreadable.
+    #[serde(other)]
     Unknown,
 }
 
@@ -28,6 +35,27 @@ pub trait Entry: Debug + Eq + PartialEq {
     fn size(&self) -> Option<u64>;
     fn symlink_target(&self) -> &Option<String>;
 
+    /// NTFS security descriptor and named streams, if they were captured.
+    fn ntfs_metadata(&self) -> Option<&NtfsMetadata> {
+        None
+    }
+
+    /// Birth time, mount ID, and attributes from Linux `statx`, if they were
+    /// captured.
+    fn statx_metadata(&self) -> Option<&StatxMetadata> {
+        None
+    }
+
+    /// For source files on Unix, the inode number.
+    fn inode(&self) -> Option<u64> {
+        None
+    }
+
+    /// For files stored in an archive, the blocks holding their content.
+    fn stored_addrs(&self) -> Option<&[blockdir::Address]> {
+        None
+    }
+
     /// True if the metadata supports an assumption the file contents have
     /// not changed.
     fn is_unchanged_from<O: Entry>(&self, basis_entry: &O) -> bool {
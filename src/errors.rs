@@ -59,9 +59,37 @@ pub enum Error {
     #[snafu(display("Archive has no bands"))]
     ArchiveEmpty,
 
+    #[snafu(display("Can't determine the directory name of source {:?}", path))]
+    NoSourceDirName { path: PathBuf },
+
+    #[snafu(display(
+        "No restore destination given{}",
+        match source_path {
+            Some(p) => format!("; this version was backed up from {:?}", p),
+            None => String::new(),
+        }
+    ))]
+    NoRestoreDestination { source_path: Option<String> },
+
+    #[snafu(display("Invalid tree name {:?}", tree_name))]
+    InvalidTreeName { tree_name: String },
+
+    #[snafu(display("Invalid tuning: {}", message))]
+    InvalidTuning { message: String },
+
     #[snafu(display("Invalid backup version number {:?}", version))]
     InvalidVersion { version: String },
 
+    #[snafu(display(
+        "No version {:?}: the archive has {} complete versions",
+        version,
+        complete_versions
+    ))]
+    NoSuchVersion {
+        version: String,
+        complete_versions: usize,
+    },
+
     #[snafu(display("Failed to create band"))]
     CreateBand { source: std::io::Error },
 
@@ -77,12 +105,75 @@ pub enum Error {
     #[snafu(display("Band {} is incomplete", band_id))]
     BandIncomplete { band_id: BandId },
 
+    #[snafu(display(
+        "Band {} is layered over {}, which isn't an earlier band",
+        band_id,
+        base_band_id
+    ))]
+    InvalidIndexBase {
+        band_id: BandId,
+        base_band_id: BandId,
+    },
+
+    #[snafu(display("Invalid version range {:?}; expected START..END", range))]
+    InvalidVersionRange { range: String },
+
+    #[snafu(display(
+        "Can't squash away {} because {} is layered on it",
+        band_id,
+        layered_band_id
+    ))]
+    SquashBaseInUse {
+        band_id: BandId,
+        layered_band_id: BandId,
+    },
+
+    #[snafu(display("Band {} is signed, so it can't be squashed without the key", band_id))]
+    SquashSignedBand { band_id: BandId },
+
+    #[snafu(display(
+        "Band {} is signed, so its index can't be rewritten without the key",
+        band_id
+    ))]
+    RewriteSignedIndex { band_id: BandId },
+
+    #[snafu(display("Band {} is signed, so it can't be undeleted without the key", band_id))]
+    UndeleteSignedBand { band_id: BandId },
+
+    #[snafu(display("Failed to delete band {}", band_id))]
+    DeleteBand { band_id: BandId, source: IOError },
+
+    #[snafu(display(
+        "Version {} has no content index; write one with `conserve content-index`",
+        band_id
+    ))]
+    NoContentIndex { band_id: BandId },
+
+    #[snafu(display("Band {} is not in the trash", band_id))]
+    BandNotInTrash { band_id: BandId },
+
+    #[snafu(display("Band {} already exists, so it can't be undeleted", band_id))]
+    UndeleteExistingBand { band_id: BandId },
+
+    #[snafu(display("Failed to undelete band {}", band_id))]
+    UndeleteBand { band_id: BandId, source: IOError },
+
     #[snafu(display("Failed to parse glob {:?}", glob))]
     ParseGlob {
         glob: String,
         source: globset::Error,
     },
 
+    #[snafu(display(
+        "Unknown exclude preset {:?}; the presets are {}",
+        name,
+        crate::excludes::PRESETS.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(", ")
+    ))]
+    UnknownExcludePreset { name: String },
+
+    #[snafu(display("Failed to listen on {}", address))]
+    Listen { address: String, source: IOError },
+
     #[snafu(display("Failed to write index hunk {:?}", path))]
     WriteIndex { path: PathBuf, source: IOError },
 
@@ -101,12 +192,72 @@ pub enum Error {
         source: serde_json::Error,
     },
 
+    #[snafu(display("Failed to decode binary index hunk {:?}: {}", path, message))]
+    DecodeIndex { path: PathBuf, message: String },
+
     #[snafu(display("Failed to read metadata file {:?}", path))]
     ReadMetadata {
         path: PathBuf,
         source: std::io::Error,
     },
 
+    #[snafu(display("Failed to write band file {:?}", path))]
+    WriteBandFile { path: PathBuf, source: IOError },
+
+    #[snafu(display("HTTP request to {} failed: {}", url, message))]
+    Http { url: String, message: String },
+
+    #[snafu(display("Invalid filter {:?}: {}", filter, message))]
+    InvalidFilter { filter: String, message: String },
+
+    #[snafu(display("Archives differ in {} bands or blocks", differences))]
+    ArchivesDiffer { differences: usize },
+
+    #[snafu(display("Failed to replicate to {}", mirrors.join(", ")))]
+    Replicate { mirrors: Vec<String> },
+
+    #[snafu(display("Notification command {:?} failed: {}", command, message))]
+    NotifyCommand { command: String, message: String },
+
+    #[snafu(display("Failed to get credential from {}: {}", source_name, message))]
+    Credential {
+        source_name: String,
+        message: String,
+    },
+
+    #[snafu(display("Failed to make a snapshot: {}", message))]
+    Snapshot { message: String },
+
+    #[snafu(display(
+        "Archive {:?} is guarded against deletion; give its deletion secret",
+        path
+    ))]
+    DeletionSecretRequired { path: PathBuf },
+
+    #[snafu(display("Wrong deletion secret for archive {:?}", path))]
+    WrongDeletionSecret { path: PathBuf },
+
+    #[snafu(display("Failed to generate a key: {}", message))]
+    GenerateKey { message: String },
+
+    #[snafu(display("Failed to read key from {:?}", path))]
+    ReadKey { path: PathBuf, source: IOError },
+
+    #[snafu(display("Failed to write key to {:?}", path))]
+    WriteKey { path: PathBuf, source: IOError },
+
+    #[snafu(display("Invalid key in {:?}", path))]
+    InvalidKey { path: PathBuf },
+
+    #[snafu(display("New versions can't be signed with only the public key"))]
+    SigningKeyNeeded,
+
+    #[snafu(display("{:?} is not signed", path))]
+    SignatureMissing { path: PathBuf },
+
+    #[snafu(display("Bad signature in {:?}: {}", path, message))]
+    BadSignature { path: PathBuf, message: String },
+
     #[snafu(display("Failed to write metadata file {:?}", path))]
     WriteMetadata {
         path: PathBuf,
@@ -125,6 +276,15 @@ pub enum Error {
         source: serde_json::Error,
     },
 
+    #[snafu(display("Failed to write stats"))]
+    WriteStats { source: IOError },
+
+    #[snafu(display("Failed to write log file {}", path.display()))]
+    WriteLog { path: PathBuf, source: IOError },
+
+    #[snafu(display("Failed to write metrics to {}", path.display()))]
+    WriteMetrics { path: PathBuf, source: IOError },
+
     #[snafu(display("Failed to list bands in {:?}", path))]
     ListBands {
         path: PathBuf,
@@ -137,14 +297,107 @@ pub enum Error {
         source: std::io::Error,
     },
 
+    #[snafu(display("Failed to read tar file {}", path.display()))]
+    ReadTar { path: PathBuf, source: IOError },
+
+    #[snafu(display(
+        "Tar file {} is not a regular file: streams such as pipes can't be imported",
+        path.display()
+    ))]
+    TarNotSeekable { path: PathBuf },
+
     #[snafu(display("Failed to read source tree {}", path.display()))]
     ListSourceTree { path: PathBuf, source: IOError },
 
+    #[snafu(display(
+        "Only {} bytes are free on the filesystem holding {:?}, less than the {} required",
+        available,
+        path,
+        required
+    ))]
+    LowFreeSpace {
+        path: PathBuf,
+        available: u64,
+        required: u64,
+    },
+
+    #[snafu(display("The filesystem holding {:?} is full", path))]
+    DiskFull { path: PathBuf },
+
+    #[snafu(display("Failed to check free space on the filesystem holding {:?}", path))]
+    CheckFreeSpace { path: PathBuf, source: IOError },
+
     #[snafu(display("Failed to store file {}", apath))]
     StoreFile { apath: Apath, source: IOError },
 
     #[snafu(display("Failed to restore {}", path.display()))]
     Restore { path: PathBuf, source: IOError },
+
+    #[snafu(display("Refusing to restore symlink {}: {}", path.display(), reason))]
+    InvalidSymlinkTarget { path: PathBuf, reason: String },
+
+    #[snafu(display(
+        "Invalid apath {:?}: apaths start with / and have no . or .. parts",
+        apath
+    ))]
+    InvalidApath { apath: String },
+
+    #[snafu(display(
+        "Invalid path map {:?}: expected OLD=NEW, where both are apaths like /home/me",
+        map
+    ))]
+    InvalidPathMap { map: String },
+
+    #[snafu(display("Invalid ID map {:?}: expected OLD:NEW, like 1000:1001", map))]
+    InvalidIdMap { map: String },
+
+    #[snafu(display(
+        "Invalid age {:?}: expected a number with a unit of s, m, h, d, or w, like 30d",
+        age
+    ))]
+    InvalidAge { age: String },
+
+    #[snafu(display("Failed to read scan cache {:?}", path))]
+    ReadScanCache {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Failed to write scan cache {:?}", path))]
+    WriteScanCache {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Failed to read validation cache {:?}", path))]
+    ReadValidationCache {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Failed to write validation cache {:?}", path))]
+    WriteValidationCache {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Failed to start worker threads: {}", source))]
+    ThreadPool { source: rayon::ThreadPoolBuildError },
+
+    #[snafu(display("Failed to spill to a temporary file: {}", source))]
+    WriteSpill { source: IOError },
+
+    #[snafu(display("Failed to read back a temporary spill file: {}", source))]
+    ReadSpill { source: serde_json::Error },
+
+    #[snafu(display("Interrupted"))]
+    Interrupted,
+
+    #[snafu(display("Found {} problems", count))]
+    DoctorFoundProblems { count: usize },
+
+    #[snafu(display("Failed to install signal handler: {}", source))]
+    InstallSignalHandler { source: ctrlc::Error },
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
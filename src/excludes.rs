@@ -5,7 +5,7 @@
 
 use globset::{Glob, GlobSet, GlobSetBuilder};
 
-use snafu::ResultExt;
+use snafu::{OptionExt, ResultExt};
 
 use super::*;
 
@@ -23,6 +23,50 @@ pub fn excludes_nothing() -> GlobSet {
     GlobSetBuilder::new().build().unwrap()
 }
 
+/// Named sets of exclude patterns for files that are commonly not worth
+/// backing up, chosen by `--exclude-preset`.
+pub const PRESETS: &[(&str, &[&str])] = &[
+    (
+        "os-caches",
+        &[
+            "/**/.DS_Store",
+            "/**/.Spotlight-V100",
+            "/**/.fseventsd",
+            "/**/.Trash-*",
+            "/**/$RECYCLE.BIN",
+            "/**/Thumbs.db",
+            "/**/ehthumbs.db",
+            "/**/desktop.ini",
+            "/**/.cache",
+        ],
+    ),
+    ("node_modules", &["/**/node_modules"]),
+    (
+        "build-artifacts",
+        &[
+            "/**/__pycache__",
+            "/**/*.py[co]",
+            "/**/.mypy_cache",
+            "/**/.pytest_cache",
+            "/**/.tox",
+            "/**/*.o",
+            "/**/*.obj",
+            "/**/.gradle",
+            "/**/target/debug",
+            "/**/target/release",
+        ],
+    ),
+];
+
+/// Return the patterns in a named preset.
+pub fn preset(name: &str) -> Result<&'static [&'static str]> {
+    PRESETS
+        .iter()
+        .find(|(preset_name, _)| *preset_name == name)
+        .map(|(_, patterns)| *patterns)
+        .context(errors::UnknownExcludePreset { name })
+}
+
 #[cfg(test)]
 mod tests {
     use super::super::*;
@@ -53,6 +97,25 @@ mod tests {
         assert_eq!(excludes.matches("a").len(), 0);
     }
 
+    #[test]
+    pub fn presets() {
+        let excludes = excludes::from_strings(excludes::preset("os-caches").unwrap()).unwrap();
+        assert!(excludes.is_match("/.DS_Store"));
+        assert!(excludes.is_match("/home/me/.cache"));
+        assert!(!excludes.is_match("/home/me/cache"));
+
+        let excludes =
+            excludes::from_strings(excludes::preset("build-artifacts").unwrap()).unwrap();
+        assert!(excludes.is_match("/src/conserve/target/debug"));
+        assert!(excludes.is_match("/src/app/__pycache__"));
+        assert!(!excludes.is_match("/src/conserve/target"));
+
+        for (name, patterns) in excludes::PRESETS {
+            assert!(excludes::from_strings(*patterns).is_ok(), "{}", name);
+        }
+        assert!(excludes::preset("junk").is_err());
+    }
+
     #[test]
     pub fn nothing_parse() {
         let excludes = excludes::excludes_nothing();
@@ -0,0 +1,227 @@
+// Conserve backup system.
+// Copyright 2020 Martin Pool.
+
+//! Injected failures in reading and writing files, so that tests can
+//! deterministically exercise interrupted backups, retries, and repair.
+//!
+//! A `FaultPlan` applies only to files under its root directory, so that tests
+//! running concurrently on other directories aren't affected. It's active
+//! until the `FaultGuard` returned by `FaultPlan::install` is dropped.
+//!
+//! Writes are counted as each file in the archive is committed: an index
+//! hunk, a block, or a metadata file. Reads are counted as each block or
+//! index hunk is read from the archive, or each source file is read, including
+//! those prefetched through io_uring.
+//!
+//! ```
+//! use conserve::faults::FaultPlan;
+//!
+//! // The third file written under this directory fails with EIO.
+//! let guard = FaultPlan::new("/backup/archive").fail_write(3).install();
+//! // ... run a backup ...
+//! drop(guard);
+//! ```
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::sleep;
+use std::time::Duration;
+
+use lazy_static::lazy_static;
+
+lazy_static! {
+    static ref PLANS: Mutex<Vec<Arc<FaultPlan>>> = Mutex::default();
+}
+
+/// The number of installed plans, checked before taking the lock so that
+/// normal operation isn't slowed.
+static INSTALLED: AtomicUsize = AtomicUsize::new(0);
+
+/// Failures to inject into operations on files under one directory.
+#[derive(Debug)]
+pub struct FaultPlan {
+    root: PathBuf,
+    fail_writes: Vec<usize>,
+    fail_reads: Vec<usize>,
+    short_reads: Option<usize>,
+    delay: Option<Duration>,
+    writes: AtomicUsize,
+    reads: AtomicUsize,
+}
+
+impl FaultPlan {
+    /// Plan failures for files under `root`, which should be given as the
+    /// archive or source path is given to Conserve.
+    pub fn new<P: AsRef<Path>>(root: P) -> FaultPlan {
+        FaultPlan {
+            root: root.as_ref().to_owned(),
+            fail_writes: Vec::new(),
+            fail_reads: Vec::new(),
+            short_reads: None,
+            delay: None,
+            writes: AtomicUsize::new(0),
+            reads: AtomicUsize::new(0),
+        }
+    }
+
+    /// Fail the `n`th write, counting from 1, with EIO, so that the file
+    /// isn't written.
+    ///
+    /// This may be given several times to fail several writes.
+    pub fn fail_write(mut self, n: usize) -> FaultPlan {
+        self.fail_writes.push(n);
+        self
+    }
+
+    /// Fail the `n`th read, counting from 1, with EIO.
+    pub fn fail_read(mut self, n: usize) -> FaultPlan {
+        self.fail_reads.push(n);
+        self
+    }
+
+    /// Return at most `max_len` bytes from each read of a source file.
+    pub fn short_reads(self, max_len: usize) -> FaultPlan {
+        assert!(max_len > 0);
+        FaultPlan {
+            short_reads: Some(max_len),
+            ..self
+        }
+    }
+
+    /// Wait this long before each read and write.
+    pub fn delay(self, delay: Duration) -> FaultPlan {
+        FaultPlan {
+            delay: Some(delay),
+            ..self
+        }
+    }
+
+    /// Start injecting these failures, until the guard is dropped.
+    pub fn install(self) -> FaultGuard {
+        let plan = Arc::new(self);
+        PLANS.lock().unwrap().push(plan.clone());
+        INSTALLED.fetch_add(1, Ordering::SeqCst);
+        FaultGuard { plan }
+    }
+}
+
+/// Keeps a `FaultPlan` active, and counts the operations it's seen.
+#[derive(Debug)]
+pub struct FaultGuard {
+    plan: Arc<FaultPlan>,
+}
+
+impl FaultGuard {
+    /// The number of writes under the root so far, including failed ones.
+    pub fn writes(&self) -> usize {
+        self.plan.writes.load(Ordering::SeqCst)
+    }
+
+    /// The number of reads under the root so far, including failed ones.
+    pub fn reads(&self) -> usize {
+        self.plan.reads.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for FaultGuard {
+    fn drop(&mut self) {
+        PLANS
+            .lock()
+            .unwrap()
+            .retain(|plan| !Arc::ptr_eq(plan, &self.plan));
+        INSTALLED.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// The installed plans covering `path`.
+fn plans_for(path: &Path) -> Vec<Arc<FaultPlan>> {
+    if INSTALLED.load(Ordering::Relaxed) == 0 {
+        return Vec::new();
+    }
+    PLANS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|plan| path.starts_with(&plan.root))
+        .cloned()
+        .collect()
+}
+
+fn injected_error() -> io::Error {
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    {
+        io::Error::from_raw_os_error(libc::EIO)
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    {
+        io::Error::new(io::ErrorKind::Other, "injected I/O error")
+    }
+}
+
+/// Count a write of `path`, just before it's committed, and fail if it's
+/// planned to.
+pub(crate) fn before_write(path: &Path) -> io::Result<()> {
+    for plan in plans_for(path) {
+        if let Some(delay) = plan.delay {
+            sleep(delay);
+        }
+        let n = plan.writes.fetch_add(1, Ordering::SeqCst) + 1;
+        if plan.fail_writes.contains(&n) {
+            return Err(injected_error());
+        }
+    }
+    Ok(())
+}
+
+/// Count a read of `path`, and fail if it's planned to.
+pub(crate) fn before_read(path: &Path) -> io::Result<()> {
+    for plan in plans_for(path) {
+        if let Some(delay) = plan.delay {
+            sleep(delay);
+        }
+        let n = plan.reads.fetch_add(1, Ordering::SeqCst) + 1;
+        if plan.fail_reads.contains(&n) {
+            return Err(injected_error());
+        }
+    }
+    Ok(())
+}
+
+/// The most bytes each read of `path` should return, if it's limited.
+pub(crate) fn short_read_limit(path: &Path) -> Option<usize> {
+    plans_for(path)
+        .iter()
+        .filter_map(|plan| plan.short_reads)
+        .min()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plans_apply_under_their_root() {
+        let guard = FaultPlan::new("/faults/a")
+            .fail_write(2)
+            .fail_read(1)
+            .short_reads(10)
+            .install();
+        assert!(before_write(Path::new("/faults/a/x")).is_ok());
+        assert!(before_write(Path::new("/faults/b/x")).is_ok());
+        assert!(before_write(Path::new("/faults/a/y")).is_err());
+        assert!(before_write(Path::new("/faults/a/z")).is_ok());
+        assert_eq!(guard.writes(), 3);
+
+        assert!(before_read(Path::new("/faults/ab")).is_ok());
+        assert!(before_read(Path::new("/faults/a/x")).is_err());
+        assert_eq!(guard.reads(), 1);
+        assert_eq!(short_read_limit(Path::new("/faults/a/x")), Some(10));
+        assert_eq!(short_read_limit(Path::new("/faults/b/x")), None);
+
+        drop(guard);
+        assert!(before_read(Path::new("/faults/a/x")).is_ok());
+        assert_eq!(short_read_limit(Path::new("/faults/a/x")), None);
+    }
+}
@@ -0,0 +1,350 @@
+// Conserve backup system.
+// Copyright 2020 Martin Pool.
+
+//! A minimal C ABI, enabled by the `ffi` feature, so that programs in other
+//! languages can link against Conserve.
+//!
+//! Build a shared library with
+//! `cargo rustc --release --lib --features ffi --crate-type cdylib`.
+//!
+//! The C declarations are:
+//!
+//! ```c
+//! typedef void (*conserve_progress_cb)(const char *phase, uint64_t bytes_done,
+//!                                      uint64_t bytes_total, void *user_data);
+//! typedef void (*conserve_version_cb)(const char *band_id, bool complete,
+//!                                     void *user_data);
+//!
+//! int conserve_init_archive(const char *archive);
+//! int conserve_backup(const char *archive, const char *source,
+//!                     conserve_progress_cb progress, void *user_data);
+//! int conserve_restore(const char *archive, const char *destination,
+//!                      conserve_progress_cb progress, void *user_data);
+//! int conserve_list_versions(const char *archive, conserve_version_cb version,
+//!                            void *user_data);
+//! const char *conserve_last_error(void);
+//! ```
+//!
+//! Functions return 0 on success and -1 on failure, in which case
+//! `conserve_last_error` describes the error. Strings are UTF-8 and
+//! NUL-terminated. A panic inside Conserve is returned as an error rather
+//! than unwinding into the caller.
+//!
+//! Progress callbacks may be null. They're called on the thread that called
+//! in, before the function returns, so different threads can each run an
+//! operation with their own callback.
+
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_void};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::ptr;
+
+use crate::*;
+
+/// Called as an operation makes progress.
+pub type ProgressCallback = Option<
+    extern "C" fn(phase: *const c_char, bytes_done: u64, bytes_total: u64, user_data: *mut c_void),
+>;
+
+/// Called once for each version in an archive.
+pub type VersionCallback =
+    extern "C" fn(band_id: *const c_char, complete: bool, user_data: *mut c_void);
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(message: String) {
+    let message = CString::new(message.replace('\0', " ")).unwrap();
+    LAST_ERROR.with(|e| *e.borrow_mut() = Some(message));
+}
+
+/// Convert a Result into a C return code, remembering any error.
+fn to_return_code<T>(result: Result<T>) -> c_int {
+    match result {
+        Ok(_) => 0,
+        Err(e) => {
+            set_last_error(e.to_string());
+            -1
+        }
+    }
+}
+
+/// Read a C string argument.
+unsafe fn str_arg<'a>(name: &str, s: *const c_char) -> std::result::Result<&'a str, ()> {
+    if s.is_null() {
+        set_last_error(format!("{} is null", name));
+        return Err(());
+    }
+    CStr::from_ptr(s).to_str().map_err(|_| {
+        set_last_error(format!("{} is not UTF-8", name));
+    })
+}
+
+/// Run the body of an entry point, returning -1 rather than letting a panic
+/// unwind into the caller.
+fn catch_panic(f: impl FnOnce() -> c_int) -> c_int {
+    catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|_| {
+        set_last_error("Conserve panicked; see its log for details".to_owned());
+        -1
+    })
+}
+
+/// Removes this thread's progress callback when dropped, even after a panic.
+struct ClearProgressCallback;
+
+impl Drop for ClearProgressCallback {
+    fn drop(&mut self) {
+        ui::set_thread_progress_callback(None);
+    }
+}
+
+/// Run an operation with the progress callback installed for this thread.
+fn with_progress<T>(
+    progress: ProgressCallback,
+    user_data: *mut c_void,
+    f: impl FnOnce() -> Result<T>,
+) -> Result<T> {
+    let _clear = ClearProgressCallback;
+    ui::set_thread_progress_callback(progress.map(|progress| {
+        Box::new(move |state: &ProgressState| {
+            let phase = CString::new(state.phase.replace('\0', " ")).unwrap();
+            progress(
+                phase.as_ptr(),
+                state.bytes_done,
+                state.bytes_total,
+                user_data,
+            )
+        }) as ui::ThreadProgressCallback
+    }));
+    f()
+}
+
+/// Describe the last error on this thread, or return null if there was none.
+///
+/// The string is valid until the next call into this library on this thread.
+#[no_mangle]
+pub extern "C" fn conserve_last_error() -> *const c_char {
+    catch_unwind(|| {
+        LAST_ERROR.with(|e| match &*e.borrow() {
+            Some(message) => message.as_ptr(),
+            None => ptr::null(),
+        })
+    })
+    .unwrap_or(ptr::null())
+}
+
+/// Create a new archive directory.
+///
+/// # Safety
+///
+/// `archive` must be null or a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn conserve_init_archive(archive: *const c_char) -> c_int {
+    catch_panic(|| match str_arg("archive", archive) {
+        Ok(archive) => to_return_code(Archive::create(archive)),
+        Err(()) => -1,
+    })
+}
+
+/// Back up `source` into a new version in `archive`.
+///
+/// # Safety
+///
+/// `archive` and `source` must be null or NUL-terminated strings, and
+/// `progress`, if not null, must be safe to call with `user_data` on the
+/// calling thread until this returns.
+#[no_mangle]
+pub unsafe extern "C" fn conserve_backup(
+    archive: *const c_char,
+    source: *const c_char,
+    progress: ProgressCallback,
+    user_data: *mut c_void,
+) -> c_int {
+    catch_panic(|| {
+        let (archive, source) = match (str_arg("archive", archive), str_arg("source", source)) {
+            (Ok(a), Ok(s)) => (a, s),
+            _ => return -1,
+        };
+        to_return_code(with_progress(progress, user_data, || {
+            BackupOptions::new(source, archive).run()
+        }))
+    })
+}
+
+/// Restore the latest complete version in `archive` into the new directory
+/// `destination`.
+///
+/// # Safety
+///
+/// `archive` and `destination` must be null or NUL-terminated strings, and
+/// `progress`, if not null, must be safe to call with `user_data` on the
+/// calling thread until this returns.
+#[no_mangle]
+pub unsafe extern "C" fn conserve_restore(
+    archive: *const c_char,
+    destination: *const c_char,
+    progress: ProgressCallback,
+    user_data: *mut c_void,
+) -> c_int {
+    catch_panic(|| {
+        let (archive, destination) = match (
+            str_arg("archive", archive),
+            str_arg("destination", destination),
+        ) {
+            (Ok(a), Ok(d)) => (a, d),
+            _ => return -1,
+        };
+        to_return_code(with_progress(progress, user_data, || {
+            RestoreOptions::new(archive, destination).run()
+        }))
+    })
+}
+
+/// Call `version` once for each version in `archive`, in order.
+///
+/// # Safety
+///
+/// `archive` must be null or a NUL-terminated string, and `version` must be
+/// safe to call with `user_data`.
+#[no_mangle]
+pub unsafe extern "C" fn conserve_list_versions(
+    archive: *const c_char,
+    version: VersionCallback,
+    user_data: *mut c_void,
+) -> c_int {
+    catch_panic(|| {
+        let archive = match str_arg("archive", archive) {
+            Ok(a) => a,
+            Err(()) => return -1,
+        };
+        to_return_code((|| -> Result<()> {
+            let archive = Archive::open(archive)?;
+            for band_id in archive.list_bands()? {
+                let complete = Band::open(&archive, &band_id)?.is_closed()?;
+                let name = CString::new(band_id.to_string()).unwrap();
+                version(name.as_ptr(), complete, user_data);
+            }
+            Ok(())
+        })())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::TreeFixture;
+
+    fn c(s: &std::path::Path) -> CString {
+        CString::new(s.to_str().unwrap()).unwrap()
+    }
+
+    extern "C" fn count_versions(band_id: *const c_char, complete: bool, user_data: *mut c_void) {
+        let name = unsafe { CStr::from_ptr(band_id) }.to_str().unwrap();
+        assert_eq!(name, "b0000");
+        assert!(complete);
+        unsafe { *(user_data as *mut u32) += 1 };
+    }
+
+    #[test]
+    fn backup_list_restore() {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let archive = c(&tmp.path().join("archive"));
+        let srcdir = TreeFixture::new();
+        srcdir.create_file("hello");
+        let dest = c(&tmp.path().join("restored"));
+
+        unsafe {
+            assert_eq!(conserve_init_archive(archive.as_ptr()), 0);
+            assert_eq!(
+                conserve_backup(
+                    archive.as_ptr(),
+                    c(srcdir.path()).as_ptr(),
+                    None,
+                    ptr::null_mut()
+                ),
+                0
+            );
+            let mut count: u32 = 0;
+            assert_eq!(
+                conserve_list_versions(
+                    archive.as_ptr(),
+                    count_versions,
+                    &mut count as *mut u32 as *mut c_void
+                ),
+                0
+            );
+            assert_eq!(count, 1);
+            assert_eq!(
+                conserve_restore(archive.as_ptr(), dest.as_ptr(), None, ptr::null_mut()),
+                0
+            );
+        }
+        assert!(tmp.path().join("restored").join("hello").is_file());
+    }
+
+    #[test]
+    fn error_is_reported() {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let archive = c(&tmp.path().join("nothing"));
+        unsafe {
+            assert_eq!(
+                conserve_backup(archive.as_ptr(), archive.as_ptr(), None, ptr::null_mut()),
+                -1
+            );
+            assert_eq!(conserve_init_archive(ptr::null()), -1);
+        }
+        let message = unsafe { CStr::from_ptr(conserve_last_error()) };
+        assert_eq!(message.to_str().unwrap(), "archive is null");
+    }
+
+    extern "C" fn count_progress(
+        _phase: *const c_char,
+        _bytes_done: u64,
+        _bytes_total: u64,
+        user_data: *mut c_void,
+    ) {
+        // Using the UI from a callback mustn't deadlock.
+        ui::increment_bytes_deduplicated(0);
+        unsafe { *(user_data as *mut u32) += 1 };
+    }
+
+    #[test]
+    fn concurrent_backups_report_to_their_own_callbacks() {
+        let threads: Vec<_> = (0..2)
+            .map(|_| {
+                std::thread::spawn(|| {
+                    let tmp = tempfile::TempDir::new().unwrap();
+                    let archive = c(&tmp.path().join("archive"));
+                    let srcdir = TreeFixture::new();
+                    srcdir.create_file("hello");
+                    let mut count: u32 = 0;
+                    unsafe {
+                        assert_eq!(conserve_init_archive(archive.as_ptr()), 0);
+                        assert_eq!(
+                            conserve_backup(
+                                archive.as_ptr(),
+                                c(srcdir.path()).as_ptr(),
+                                Some(count_progress),
+                                &mut count as *mut u32 as *mut c_void
+                            ),
+                            0
+                        );
+                    }
+                    count
+                })
+            })
+            .collect();
+        for thread in threads {
+            assert!(thread.join().unwrap() > 0);
+        }
+    }
+
+    #[test]
+    fn panics_are_errors() {
+        assert_eq!(catch_panic(|| panic!("oops")), -1);
+        let message = unsafe { CStr::from_ptr(conserve_last_error()) };
+        assert!(message.to_str().unwrap().contains("panicked"));
+    }
+}
@@ -0,0 +1,474 @@
+// Conserve backup system.
+// Copyright 2020 Martin Pool.
+
+//! Filter expressions that choose entries by their path, name, kind, size,
+//! and modification time.
+//!
+//! For example:
+//!
+//! ```text
+//! size > 1MB and path ~ "*.log"
+//! mtime < 2019-01-01 or not (kind = file)
+//! name !~ "*.tmp" and size <= 64KiB
+//! ```
+//!
+//! Fields are `path` (the apath), `name` (its last component), `kind`
+//! (`file`, `dir`, or `symlink`), `size` (in bytes, for files), and `mtime`.
+//!
+//! Comparisons are `=`, `!=`, `<`, `<=`, `>`, and `>=`, and for `path` and
+//! `name` also `~` and `!~`, which match a glob pattern as in `--exclude`.
+//! Sizes may have a unit of `B`, `KB`, `MB`, `GB`, or `TB`, in powers of
+//! 1000, or `KiB`, `MiB`, `GiB`, or `TiB`, in powers of 1024. Times are
+//! dates like `2019-01-01`, optionally followed by a time like `T12:30:00`,
+//! in UTC.
+//!
+//! Comparisons are combined with `and`, `or`, and `not`, in that order of
+//! precedence from weakest to strongest, and grouped with parentheses.
+//! Comparisons on a field an entry doesn't have, such as the size of a
+//! directory, are false.
+
+use std::cmp::Ordering;
+use std::fmt;
+
+use chrono::{NaiveDate, NaiveDateTime};
+use globset::{Glob, GlobMatcher};
+
+use crate::*;
+
+/// A parsed filter expression, which can be tested against entries.
+#[derive(Clone)]
+pub struct Filter {
+    source: String,
+    expr: Expr,
+}
+
+impl fmt::Debug for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Filter").field(&self.source).finish()
+    }
+}
+
+impl Filter {
+    /// Parse a filter expression.
+    pub fn parse(source: &str) -> Result<Filter> {
+        let fail = |message: String| Error::InvalidFilter {
+            filter: source.to_owned(),
+            message,
+        };
+        let tokens = tokenize(source).map_err(fail)?;
+        let mut parser = Parser { tokens, pos: 0 };
+        let expr = parser.parse_or().map_err(fail)?;
+        if let Some(token) = parser.tokens.get(parser.pos) {
+            return Err(fail(format!("unexpected {}", token)));
+        }
+        Ok(Filter {
+            source: source.to_owned(),
+            expr,
+        })
+    }
+
+    /// True if the entry matches the expression.
+    pub fn matches<E: Entry>(&self, entry: &E) -> bool {
+        self.expr.matches(entry)
+    }
+
+    /// True if the entry should be copied: directories always are, so that
+    /// matching entries inside them can be, and other entries if they match.
+    pub fn selects<E: Entry>(&self, entry: &E) -> bool {
+        entry.kind() == Kind::Dir || self.matches(entry)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Op {
+    fn holds(self, ordering: Ordering) -> bool {
+        match self {
+            Op::Eq => ordering == Ordering::Equal,
+            Op::Ne => ordering != Ordering::Equal,
+            Op::Lt => ordering == Ordering::Less,
+            Op::Le => ordering != Ordering::Greater,
+            Op::Gt => ordering == Ordering::Greater,
+            Op::Ge => ordering != Ordering::Less,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum TextField {
+    Path,
+    Name,
+}
+
+impl TextField {
+    fn get<E: Entry>(self, entry: &E) -> &str {
+        let apath: &str = entry.apath();
+        match self {
+            TextField::Path => apath,
+            TextField::Name => apath.rsplit('/').next().unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Text(TextField, Op, String),
+    Glob(TextField, Box<GlobMatcher>, bool),
+    Kind(Op, Kind),
+    Size(Op, u64),
+    Mtime(Op, i64),
+}
+
+impl Expr {
+    fn matches<E: Entry>(&self, entry: &E) -> bool {
+        match self {
+            Expr::And(a, b) => a.matches(entry) && b.matches(entry),
+            Expr::Or(a, b) => a.matches(entry) || b.matches(entry),
+            Expr::Not(a) => !a.matches(entry),
+            Expr::Text(field, op, value) => op.holds(field.get(entry).cmp(value.as_str())),
+            Expr::Glob(field, matcher, negate) => matcher.is_match(field.get(entry)) != *negate,
+            Expr::Kind(op, kind) => (entry.kind() == *kind) == (*op == Op::Eq),
+            Expr::Size(op, size) => match (entry.kind(), entry.size()) {
+                (Kind::File, Some(actual)) => op.holds(actual.cmp(size)),
+                _ => false,
+            },
+            Expr::Mtime(op, secs) => op.holds(entry.mtime().secs.cmp(secs)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Token {
+    Open,
+    Close,
+    Op(&'static str),
+    Word(String),
+    Quoted(String),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Open => write!(f, "\"(\""),
+            Token::Close => write!(f, "\")\""),
+            Token::Op(op) => write!(f, "{:?}", op),
+            Token::Word(word) => write!(f, "{:?}", word),
+            Token::Quoted(text) => write!(f, "string {:?}", text),
+        }
+    }
+}
+
+const OPS: &[&str] = &["!=", "!~", "<=", ">=", "==", "=", "<", ">", "~"];
+
+fn tokenize(source: &str) -> std::result::Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = source.trim_start();
+    while let Some(c) = rest.chars().next() {
+        if c == '(' {
+            tokens.push(Token::Open);
+            rest = &rest[1..];
+        } else if c == ')' {
+            tokens.push(Token::Close);
+            rest = &rest[1..];
+        } else if let Some(op) = OPS.iter().find(|op| rest.starts_with(*op)) {
+            tokens.push(Token::Op(if *op == "==" { "=" } else { op }));
+            rest = &rest[op.len()..];
+        } else if c == '"' {
+            let mut text = String::new();
+            let mut chars = rest[1..].char_indices();
+            let end = loop {
+                match chars.next() {
+                    Some((i, '"')) => break i + 2,
+                    Some((_, '\\')) => match chars.next() {
+                        Some((_, escaped)) => text.push(escaped),
+                        None => return Err("unterminated string".to_owned()),
+                    },
+                    Some((_, c)) => text.push(c),
+                    None => return Err("unterminated string".to_owned()),
+                }
+            };
+            tokens.push(Token::Quoted(text));
+            rest = &rest[end..];
+        } else {
+            let end = rest
+                .find(|c: char| c.is_whitespace() || "()\"!=<>~".contains(c))
+                .unwrap_or(rest.len());
+            if end == 0 {
+                return Err(format!("unexpected {:?}", c));
+            }
+            tokens.push(Token::Word(rest[..end].to_owned()));
+            rest = &rest[end..];
+        }
+        rest = rest.trim_start();
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn next_is_keyword(&self, keyword: &str) -> bool {
+        matches!(self.tokens.get(self.pos), Some(Token::Word(w)) if w.eq_ignore_ascii_case(keyword))
+    }
+
+    fn parse_or(&mut self) -> std::result::Result<Expr, String> {
+        let mut expr = self.parse_and()?;
+        while self.next_is_keyword("or") {
+            self.pos += 1;
+            expr = Expr::Or(Box::new(expr), Box::new(self.parse_and()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_and(&mut self) -> std::result::Result<Expr, String> {
+        let mut expr = self.parse_not()?;
+        while self.next_is_keyword("and") {
+            self.pos += 1;
+            expr = Expr::And(Box::new(expr), Box::new(self.parse_not()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_not(&mut self) -> std::result::Result<Expr, String> {
+        if self.next_is_keyword("not") {
+            self.pos += 1;
+            return Ok(Expr::Not(Box::new(self.parse_not()?)));
+        }
+        match self.next() {
+            Some(Token::Open) => {
+                let expr = self.parse_or()?;
+                match self.next() {
+                    Some(Token::Close) => Ok(expr),
+                    Some(token) => Err(format!("expected \")\" but found {}", token)),
+                    None => Err("expected \")\"".to_owned()),
+                }
+            }
+            Some(Token::Word(field)) => self.parse_comparison(&field),
+            Some(token) => Err(format!("expected a field name but found {}", token)),
+            None => Err("expected a comparison".to_owned()),
+        }
+    }
+
+    fn parse_comparison(&mut self, field: &str) -> std::result::Result<Expr, String> {
+        let op = match self.next() {
+            Some(Token::Op(op)) => op,
+            Some(token) => return Err(format!("expected a comparison but found {}", token)),
+            None => return Err(format!("expected a comparison after {:?}", field)),
+        };
+        let value = match self.next() {
+            Some(Token::Word(value)) | Some(Token::Quoted(value)) => value,
+            Some(token) => return Err(format!("expected a value but found {}", token)),
+            None => return Err(format!("expected a value after {:?}", op)),
+        };
+        let text_field = match field.to_ascii_lowercase().as_str() {
+            "path" => Some(TextField::Path),
+            "name" => Some(TextField::Name),
+            _ => None,
+        };
+        if let Some(text_field) = text_field {
+            if op == "~" || op == "!~" {
+                let matcher = Glob::new(&value)
+                    .map_err(|e| format!("bad glob {:?}: {}", value, e))?
+                    .compile_matcher();
+                return Ok(Expr::Glob(text_field, Box::new(matcher), op == "!~"));
+            }
+            return Ok(Expr::Text(text_field, ordering_op(op, field)?, value));
+        }
+        let op = ordering_op(op, field)?;
+        match field.to_ascii_lowercase().as_str() {
+            "kind" | "type" => {
+                if op != Op::Eq && op != Op::Ne {
+                    return Err(format!("{:?} can only be compared with = or !=", field));
+                }
+                let kind = match value.to_ascii_lowercase().as_str() {
+                    "file" => Kind::File,
+                    "dir" => Kind::Dir,
+                    "symlink" => Kind::Symlink,
+                    _ => return Err(format!("unknown kind {:?}", value)),
+                };
+                Ok(Expr::Kind(op, kind))
+            }
+            "size" => Ok(Expr::Size(op, parse_size(&value)?)),
+            "mtime" => Ok(Expr::Mtime(op, parse_time(&value)?)),
+            _ => Err(format!("unknown field {:?}", field)),
+        }
+    }
+}
+
+fn ordering_op(op: &str, field: &str) -> std::result::Result<Op, String> {
+    Ok(match op {
+        "=" => Op::Eq,
+        "!=" => Op::Ne,
+        "<" => Op::Lt,
+        "<=" => Op::Le,
+        ">" => Op::Gt,
+        ">=" => Op::Ge,
+        _ => return Err(format!("{:?} can't be used with {:?}", op, field)),
+    })
+}
+
+fn parse_size(value: &str) -> std::result::Result<u64, String> {
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let scale: u64 = match unit.to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "kb" | "k" => 1_000,
+        "mb" | "m" => 1_000_000,
+        "gb" | "g" => 1_000_000_000,
+        "tb" | "t" => 1_000_000_000_000,
+        "kib" => 1 << 10,
+        "mib" => 1 << 20,
+        "gib" => 1 << 30,
+        "tib" => 1 << 40,
+        _ => return Err(format!("unknown size unit {:?}", unit)),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(scale))
+        .ok_or_else(|| format!("bad size {:?}", value))
+}
+
+fn parse_time(value: &str) -> std::result::Result<i64, String> {
+    if let Ok(time) = NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S") {
+        return Ok(time.timestamp());
+    }
+    NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map(|date| date.and_hms(0, 0, 0).timestamp())
+        .map_err(|_| format!("bad time {:?}: expected a date like 2019-01-01", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(apath: &str, kind: Kind, size: Option<u64>, mtime: i64) -> IndexEntry {
+        IndexEntry {
+            apath: apath.into(),
+            kind,
+            mtime,
+            mtime_nanos: 0,
+            addrs: size
+                .map(|len| {
+                    vec![blockdir::Address {
+                        hash: "00".to_owned(),
+                        start: 0,
+                        len,
+                    }]
+                })
+                .unwrap_or_default(),
+            target: None,
+            ntfs: None,
+            statx: None,
+        }
+    }
+
+    #[test]
+    fn match_fields() {
+        let log = entry(
+            "/var/log/big.log",
+            Kind::File,
+            Some(2_000_000),
+            1_600_000_000,
+        );
+        let small_log = entry("/var/log/small.log", Kind::File, Some(10), 1_500_000_000);
+        let dir = entry("/var/log", Kind::Dir, None, 1_600_000_000);
+
+        let filter = Filter::parse(r#"size > 1MB and path ~ "*.log""#).unwrap();
+        assert!(filter.matches(&log));
+        assert!(!filter.matches(&small_log));
+        assert!(!filter.matches(&dir));
+        assert!(filter.selects(&dir));
+
+        let filter = Filter::parse("mtime < 2019-01-01").unwrap();
+        assert!(filter.matches(&small_log));
+        assert!(!filter.matches(&log));
+
+        let filter = Filter::parse("not (kind = file) or name = small.log").unwrap();
+        assert!(filter.matches(&dir));
+        assert!(filter.matches(&small_log));
+        assert!(!filter.matches(&log));
+
+        let filter = Filter::parse(r#"NAME !~ "big*" AND size <= 1KiB"#).unwrap();
+        assert!(filter.matches(&small_log));
+        assert!(!filter.matches(&log));
+        assert!(!filter.matches(&dir));
+
+        let filter = Filter::parse("path = /var/log or size >= 2MB").unwrap();
+        assert!(filter.matches(&dir));
+        assert!(filter.matches(&log));
+        assert!(!filter.matches(&small_log));
+    }
+
+    #[test]
+    fn precedence() {
+        let log = entry("/a.log", Kind::File, Some(5), 0);
+        // `and` binds tighter than `or`.
+        assert!(Filter::parse("size = 5 or size = 6 and size = 7")
+            .unwrap()
+            .matches(&log));
+        assert!(!Filter::parse("(size = 5 or size = 6) and size = 7")
+            .unwrap()
+            .matches(&log));
+        assert!(Filter::parse("not not size = 5").unwrap().matches(&log));
+    }
+
+    #[test]
+    fn parse_sizes_and_times() {
+        assert_eq!(parse_size("10"), Ok(10));
+        assert_eq!(parse_size("1MB"), Ok(1_000_000));
+        assert_eq!(parse_size("2kib"), Ok(2048));
+        assert!(parse_size("1XB").is_err());
+        assert_eq!(parse_time("1970-01-02"), Ok(86400));
+        assert_eq!(parse_time("1970-01-01T00:01:00"), Ok(60));
+        assert!(parse_time("yesterday").is_err());
+    }
+
+    #[test]
+    fn reject_bad_filters() {
+        for bad in &[
+            "",
+            "size",
+            "size >",
+            "size > 1MB and",
+            "(size > 1",
+            "size > 1)",
+            "colour = red",
+            "size ~ 10",
+            "kind < file",
+            "kind = socket",
+            r#"path = "unterminated"#,
+            "path ~ \"[\"",
+        ] {
+            let err = Filter::parse(bad).unwrap_err();
+            assert!(
+                err.to_string().starts_with("Invalid filter"),
+                "{:?}: {}",
+                bad,
+                err
+            );
+        }
+    }
+}
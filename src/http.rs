@@ -0,0 +1,231 @@
+// Conserve backup system.
+// Copyright 2020 Martin Pool.
+
+//! A minimal HTTP client, for pushing to a `conserve serve` server and
+//! calling webhooks.
+//!
+//! Only plain `http://` URLs are supported. Each request uses a new
+//! connection.
+//!
+//! Requests time out rather than waiting forever for a server that doesn't
+//! answer, and responses are limited in size.
+
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use crate::*;
+
+/// Give up connecting after this long.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// By default, give up on a connection that doesn't send or receive anything
+/// for this long.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Don't read responses larger than this.
+const MAX_RESPONSE_SIZE: u64 = 64 << 20;
+
+pub(crate) struct HttpClient {
+    /// The `host:port` to connect to.
+    address: String,
+    /// The path within the server, without a trailing slash, prefixed to
+    /// every request.
+    base_path: String,
+    token: Option<Secret>,
+    /// Time limit for connecting, and for each read or write.
+    timeout: Duration,
+}
+
+impl HttpClient {
+    /// Make a client for URLs like `http://HOST[:PORT][/PATH]`.
+    ///
+    /// If a token is given, it's sent as a bearer token with every request.
+    pub fn new(url: &str, token: Option<Secret>) -> Result<HttpClient> {
+        let rest = url
+            .strip_prefix("http://")
+            .filter(|rest| !rest.is_empty() && !rest.starts_with('/'))
+            .filter(|rest| !rest.split('/').next().unwrap().contains('@'))
+            .ok_or_else(|| Error::Http {
+                url: redact_url(url),
+                message: "the URL should be like http://HOST:PORT/".to_owned(),
+            })?;
+        let (host, base_path) = match rest.find('/') {
+            Some(i) => rest.split_at(i),
+            None => (rest, ""),
+        };
+        let address = if host.contains(':') {
+            host.to_owned()
+        } else {
+            format!("{}:80", host)
+        };
+        Ok(HttpClient {
+            address,
+            base_path: base_path.trim_end_matches('/').to_owned(),
+            token,
+            timeout: DEFAULT_TIMEOUT,
+        })
+    }
+
+    /// Give up on connections that don't send or receive anything for this
+    /// long, and on connecting after at most this long.
+    pub fn timeout(self, timeout: Duration) -> HttpClient {
+        HttpClient { timeout, ..self }
+    }
+
+    fn connect(&self) -> std::io::Result<TcpStream> {
+        let mut last_error = None;
+        for address in self.address.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&address, self.timeout.min(CONNECT_TIMEOUT)) {
+                Ok(stream) => {
+                    stream.set_read_timeout(Some(self.timeout))?;
+                    stream.set_write_timeout(Some(self.timeout))?;
+                    return Ok(stream);
+                }
+                Err(err) => last_error = Some(err),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| std::io::ErrorKind::NotFound.into()))
+    }
+
+    pub fn put(&self, path: &str, body: &[u8]) -> Result<()> {
+        self.request("PUT", path, None, body).map(|_| ())
+    }
+
+    /// Post json to the URL itself.
+    pub fn post_json(&self, json: &[u8]) -> Result<()> {
+        self.request("POST", "", Some("application/json"), json)
+            .map(|_| ())
+    }
+
+    /// Make a request, and return the status code and body if it succeeded.
+    pub fn request(
+        &self,
+        method: &str,
+        path: &str,
+        content_type: Option<&str>,
+        body: &[u8],
+    ) -> Result<(u16, Vec<u8>)> {
+        let (status, reason, body) = self.send(method, path, content_type, body)?;
+        if (200..300).contains(&status) {
+            Ok((status, body))
+        } else {
+            Err(self.error(path, reason))
+        }
+    }
+
+    /// Make a request, and return the status code, its reason phrase, and the
+    /// body, whatever the status.
+    pub fn send(
+        &self,
+        method: &str,
+        path: &str,
+        content_type: Option<&str>,
+        body: &[u8],
+    ) -> Result<(u16, String, Vec<u8>)> {
+        let full_path = self.full_path(path);
+        let fail = |message: String| self.error(path, message);
+        let mut stream = self.connect().map_err(|e| fail(e.to_string()))?;
+        let mut request = format!(
+            "{} {} HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+            method,
+            full_path,
+            self.address,
+            body.len()
+        );
+        if let Some(content_type) = content_type {
+            request.push_str(&format!("Content-Type: {}\r\n", content_type));
+        }
+        if let Some(token) = &self.token {
+            request.push_str(&format!("Authorization: Bearer {}\r\n", token.expose()));
+        }
+        request.push_str("\r\n");
+        let mut response = Vec::new();
+        stream
+            .write_all(request.as_bytes())
+            .and_then(|()| stream.write_all(body))
+            .and_then(|()| {
+                (&mut stream)
+                    .take(MAX_RESPONSE_SIZE + 1)
+                    .read_to_end(&mut response)
+            })
+            .map_err(|e| fail(e.to_string()))?;
+        if response.len() as u64 > MAX_RESPONSE_SIZE {
+            return Err(fail("response is too large".to_owned()));
+        }
+        let split = response
+            .windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .ok_or_else(|| fail("incomplete response".to_owned()))?;
+        let status_line = String::from_utf8_lossy(&response[..split])
+            .lines()
+            .next()
+            .unwrap_or_default()
+            .to_owned();
+        let status: u16 = status_line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|s| s.parse().ok())
+            .ok_or_else(|| fail(format!("bad status line {:?}", status_line)))?;
+        let reason = status_line
+            .split_once(' ')
+            .map_or(status_line.as_str(), |(_, status)| status)
+            .to_owned();
+        Ok((status, reason, response[split + 4..].to_vec()))
+    }
+
+    /// An error about a request to `path`.
+    pub fn error(&self, path: &str, message: String) -> Error {
+        Error::Http {
+            url: format!("http://{}{}", self.address, self.full_path(path)),
+            message,
+        }
+    }
+
+    fn full_path(&self, path: &str) -> String {
+        let full_path = format!("{}{}", self.base_path, path);
+        if full_path.is_empty() {
+            "/".to_owned()
+        } else {
+            full_path
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_urls() {
+        let client = HttpClient::new("http://backup:8080/", None).unwrap();
+        assert_eq!(client.address, "backup:8080");
+        assert_eq!(client.base_path, "");
+        let client = HttpClient::new("http://hooks.example.com/conserve/done", None).unwrap();
+        assert_eq!(client.address, "hooks.example.com:80");
+        assert_eq!(client.base_path, "/conserve/done");
+
+        for bad in &[
+            "https://example.com/",
+            "http://",
+            "http:///path",
+            "backup:8080",
+        ] {
+            assert!(HttpClient::new(bad, None).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn silent_server_times_out() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let client = HttpClient::new(&url, None)
+            .unwrap()
+            .timeout(Duration::from_millis(200));
+        // The connection is accepted by the OS, but nothing is ever sent back.
+        let start = std::time::Instant::now();
+        assert!(client.post_json(b"{}").is_err());
+        assert!(start.elapsed() < Duration::from_secs(10));
+        drop(listener);
+    }
+}
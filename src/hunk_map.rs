@@ -0,0 +1,202 @@
+// Conserve backup system.
+// Copyright 2020 Martin Pool.
+
+//! A summary of the index hunks in a band, written as a sidecar when the
+//! index is finished.
+//!
+//! For each hunk it records the first and last apath, and optionally a Bloom
+//! filter of all the apaths in the hunk. This lets readers find which hunk
+//! could hold an apath, and often tell that it's not there at all, without
+//! reading any hunks.
+//!
+//! The summary is only an optimization: bands written by older versions, and
+//! incomplete bands, don't have one, and readers then fall back to reading
+//! the hunks.
+
+use std::convert::TryInto;
+use std::path::{Path, PathBuf};
+
+use blake2_rfc::blake2b;
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+
+use crate::*;
+
+/// Name of the sidecar file within the index directory.
+pub(crate) const HUNK_MAP_FILENAME: &str = "HUNKMAP";
+
+/// Bloom filter bits for each entry: with 5 hashes, about 2% false positives.
+const BLOOM_BITS_PER_ENTRY: usize = 8;
+
+const BLOOM_HASHES: u64 = 5;
+
+/// Summary of all the hunks in an index, in order.
+#[derive(Debug, Default, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct HunkMap {
+    pub hunks: Vec<HunkSummary>,
+}
+
+/// The range of apaths in one hunk.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct HunkSummary {
+    pub first: Apath,
+    pub last: Apath,
+
+    /// Hex-encoded Bloom filter of the apaths in this hunk.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bloom: Option<String>,
+}
+
+impl HunkSummary {
+    /// Summarize a non-empty, sorted hunk.
+    pub(crate) fn new(entries: &[IndexEntry]) -> HunkSummary {
+        let mut bloom = vec![0u8; entries.len() * BLOOM_BITS_PER_ENTRY / 8 + 1];
+        for entry in entries {
+            for bit in bloom_bits(&entry.apath, bloom.len()) {
+                bloom[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+        HunkSummary {
+            first: entries[0].apath.clone(),
+            last: entries[entries.len() - 1].apath.clone(),
+            bloom: Some(hex::encode(bloom)),
+        }
+    }
+
+    /// False if the apath is definitely not in this hunk.
+    pub fn may_contain(&self, apath: &Apath) -> bool {
+        if *apath < self.first || *apath > self.last {
+            return false;
+        }
+        match self.bloom.as_ref().and_then(|b| hex::decode(b).ok()) {
+            Some(bloom) if !bloom.is_empty() => {
+                bloom_bits(apath, bloom.len()).all(|bit| bloom[bit / 8] & (1 << (bit % 8)) != 0)
+            }
+            _ => true,
+        }
+    }
+}
+
+/// The bits set for an apath in a Bloom filter of `len` bytes.
+fn bloom_bits(apath: &Apath, len: usize) -> impl Iterator<Item = usize> {
+    let hash = blake2b::blake2b(16, &[], apath.as_bytes());
+    let hash = hash.as_bytes();
+    let h1 = u64::from_le_bytes(hash[..8].try_into().unwrap());
+    // Odd, so that successive bits differ.
+    let h2 = u64::from_le_bytes(hash[8..].try_into().unwrap()) | 1;
+    let nbits = len as u64 * 8;
+    (0..BLOOM_HASHES).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % nbits) as usize)
+}
+
+impl HunkMap {
+    fn path(index_dir: &Path) -> PathBuf {
+        index_dir.join(HUNK_MAP_FILENAME)
+    }
+
+    /// Read the hunk map for an index, or return None if there is none.
+    pub fn read(index_dir: &Path) -> Result<Option<HunkMap>> {
+        let path = &HunkMap::path(index_dir);
+        let (_len, json) = match crate::compress::snappy::decompress_file(path) {
+            Ok(x) => x,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).context(errors::ReadIndex { path }),
+        };
+        serde_json::from_slice(&json)
+            .context(errors::DeserializeIndex { path })
+            .map(Some)
+    }
+
+    pub(crate) fn write(&self, index_dir: &Path) -> Result<()> {
+        let path = &HunkMap::path(index_dir);
+        let json = serde_json::to_vec(self).context(errors::SerializeJson { path })?;
+        let mut af = AtomicFile::new(path).context(errors::WriteIndex { path })?;
+        Snappy::compress_and_write(&json, &mut af).context(errors::WriteIndex { path })?;
+        af.close().context(errors::WriteIndex { path })
+    }
+
+    /// Return the number of the only hunk that could contain `apath`, or
+    /// None if it's definitely not present.
+    pub fn hunk_for(&self, apath: &Apath) -> Option<u32> {
+        let i = self.first_hunk_not_before(apath);
+        match self.hunks.get(i as usize) {
+            Some(hunk) if hunk.may_contain(apath) => Some(i),
+            _ => None,
+        }
+    }
+
+    /// Return the number of the first hunk that doesn't end before `apath`,
+    /// or the number of hunks if they all do.
+    pub fn first_hunk_not_before(&self, apath: &Apath) -> u32 {
+        self.hunks.partition_point(|h| h.last < *apath) as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn entries(apaths: &[&str]) -> Vec<IndexEntry> {
+        apaths
+            .iter()
+            .map(|a| IndexEntry {
+                apath: (*a).into(),
+                mtime: 0,
+                mtime_nanos: 0,
+                kind: Kind::File,
+                addrs: vec![],
+                target: None,
+                ntfs: None,
+                statx: None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn bloom_has_no_false_negatives() {
+        let apaths: Vec<String> = (0..1000).map(|i| format!("/f{:04}", i)).collect();
+        let apath_strs: Vec<&str> = apaths.iter().map(String::as_str).collect();
+        let hunk = HunkSummary::new(&entries(&apath_strs));
+        assert_eq!(hunk.first, "/f0000");
+        assert_eq!(hunk.last, "/f0999");
+        assert!(apath_strs.iter().all(|a| hunk.may_contain(&(*a).into())));
+        let false_positives = (0..1000)
+            .filter(|i| hunk.may_contain(&format!("/f{:04}x", i).into()))
+            .count();
+        assert!(false_positives < 60, "{} false positives", false_positives);
+    }
+
+    #[test]
+    fn find_hunks() {
+        let map = HunkMap {
+            hunks: vec![
+                HunkSummary::new(&entries(&["/", "/a", "/c"])),
+                HunkSummary::new(&entries(&["/e", "/a/1"])),
+                HunkSummary {
+                    bloom: None,
+                    ..HunkSummary::new(&entries(&["/a/3", "/c/1"]))
+                },
+            ],
+        };
+        assert_eq!(map.hunk_for(&"/".into()), Some(0));
+        assert_eq!(map.hunk_for(&"/c".into()), Some(0));
+        assert_eq!(map.hunk_for(&"/a/1".into()), Some(1));
+        // Without a Bloom filter, anything in the range might be present.
+        assert_eq!(map.hunk_for(&"/a/4".into()), Some(2));
+        assert_eq!(map.hunk_for(&"/zz/1".into()), None);
+        assert_eq!(map.first_hunk_not_before(&"/d".into()), 1);
+        assert_eq!(map.first_hunk_not_before(&"/zz/1".into()), 3);
+    }
+
+    #[test]
+    fn write_and_read() {
+        let tmp = TempDir::new().unwrap();
+        assert_eq!(HunkMap::read(tmp.path()).unwrap(), None);
+        let map = HunkMap {
+            hunks: vec![HunkSummary::new(&entries(&["/", "/a"]))],
+        };
+        map.write(tmp.path()).unwrap();
+        assert_eq!(HunkMap::read(tmp.path()).unwrap(), Some(map));
+    }
+}
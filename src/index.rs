@@ -4,24 +4,52 @@
 //! Index lists the files in a band in the archive.
 
 use std::cmp::Ordering;
+use std::collections::VecDeque;
 use std::fmt;
 use std::io;
 use std::iter::Peekable;
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 use std::vec;
 
 use globset::GlobSet;
 use snafu::ResultExt;
+use tracing::error;
 
 use super::io::file_exists;
 use super::stats::{IndexBuilderStats, IndexEntryIterStats};
 use super::*;
+use crate::hunk_map::{HunkMap, HunkSummary};
 use crate::unix_time::UnixTime;
 
 pub const MAX_ENTRIES_PER_HUNK: usize = 1000;
 
 pub const HUNKS_PER_SUBDIR: u32 = 10_000;
 
+/// Number of decoded hunks kept in memory by each `ReadIndex`, for repeated
+/// lookups of nearby apaths.
+const HUNK_CACHE_SIZE: usize = 4;
+
+/// How index hunks are encoded, chosen when an archive is created.
+///
+/// Readers accept hunks in either format, whatever the archive's setting.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IndexFormat {
+    /// A json array of entries, readable by all versions of Conserve.
+    #[default]
+    Json,
+
+    /// A compact binary encoding, readable by Conserve 0.6.3 and later.
+    Binary,
+}
+
+impl IndexFormat {
+    pub fn is_default(&self) -> bool {
+        *self == IndexFormat::default()
+    }
+}
+
 /// Description of one archived file.
 ///
 /// This struct is directly encoded/decoded to the json index file, and also can be constructed by
@@ -60,6 +88,18 @@ pub struct IndexEntry {
     #[serde(default)]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub target: Option<String>,
+
+    /// On Windows, the NTFS security descriptor and named streams, if they
+    /// were captured.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ntfs: Option<NtfsMetadata>,
+
+    /// On Linux, the birth time, mount ID, and attributes from `statx`, if
+    /// they were captured.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub statx: Option<StatxMetadata>,
 }
 
 impl Entry for IndexEntry {
@@ -91,9 +131,41 @@ impl Entry for IndexEntry {
     fn symlink_target(&self) -> &Option<String> {
         &self.target
     }
+
+    fn ntfs_metadata(&self) -> Option<&NtfsMetadata> {
+        self.ntfs.as_ref()
+    }
+
+    fn statx_metadata(&self) -> Option<&StatxMetadata> {
+        self.statx.as_ref()
+    }
+
+    fn stored_addrs(&self) -> Option<&[blockdir::Address]> {
+        Some(&self.addrs)
+    }
 }
 
 impl IndexEntry {
+    /// A tombstone, which in a layered index hides the entry for `apath` in
+    /// the band below.
+    pub fn tombstone(apath: Apath) -> IndexEntry {
+        IndexEntry {
+            apath,
+            kind: Kind::Deleted,
+            mtime: 0,
+            mtime_nanos: 0,
+            addrs: Vec::new(),
+            target: None,
+            ntfs: None,
+            statx: None,
+        }
+    }
+
+    /// True if this is a tombstone for an entry deleted since the band below.
+    pub fn is_tombstone(&self) -> bool {
+        self.kind == Kind::Deleted
+    }
+
     /// Copy the metadata, but not the body content, from another entry.
     pub(crate) fn metadata_from<E: Entry>(source: &E) -> IndexEntry {
         let mtime = source.mtime();
@@ -106,12 +178,50 @@ impl IndexEntry {
             kind: source.kind(),
             addrs: Vec::new(),
             target: source.symlink_target().clone(),
+            ntfs: source.ntfs_metadata().cloned(),
+            statx: source.statx_metadata().cloned(),
             mtime: mtime.secs,
             mtime_nanos: mtime.nanosecs,
         }
     }
 }
 
+/// Summary of the contents of an index, from a scan of all its entries.
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct IndexSummary {
+    pub entries: u64,
+    pub files: u64,
+    pub dirs: u64,
+    pub symlinks: u64,
+    /// Markers for entries deleted from the band below, in a layered index.
+    pub deleted: u64,
+    /// Total uncompressed size of all files.
+    pub file_bytes: u64,
+    pub index_hunks: u64,
+    /// The last apath in the index, if it is not empty.
+    pub last_apath: Option<Apath>,
+}
+
+impl IndexSummary {
+    /// Count one entry into the summary.
+    ///
+    /// Entries should be added in index order, so that `last_apath` is correct.
+    pub fn add(&mut self, entry: &IndexEntry) {
+        self.entries += 1;
+        match entry.kind {
+            Kind::File => {
+                self.files += 1;
+                self.file_bytes += entry.size().unwrap_or(0);
+            }
+            Kind::Dir => self.dirs += 1,
+            Kind::Symlink => self.symlinks += 1,
+            Kind::Deleted => self.deleted += 1,
+            Kind::Unknown => (),
+        }
+        self.last_apath = Some(entry.apath.clone());
+    }
+}
+
 /// Accumulates ordered changes to the index and streams them out to index files.
 #[derive(Debug)]
 pub struct IndexBuilder {
@@ -129,6 +239,15 @@ pub struct IndexBuilder {
     /// hunk, and otherwise it's the last path from `entries`.
     check_order: apath::CheckOrder,
 
+    /// Summary of the hunks written so far.
+    hunk_map: HunkMap,
+
+    /// Encoding for new hunks.
+    format: IndexFormat,
+
+    /// Write a hunk once it has this many entries.
+    hunk_entries: usize,
+
     /// Statistics about work done while writing this index.
     pub stats: IndexBuilderStats,
 }
@@ -142,24 +261,43 @@ impl IndexBuilder {
             entries: Vec::<IndexEntry>::with_capacity(MAX_ENTRIES_PER_HUNK),
             sequence: 0,
             check_order: apath::CheckOrder::new(),
+            hunk_map: HunkMap::default(),
+            format: IndexFormat::default(),
+            hunk_entries: MAX_ENTRIES_PER_HUNK,
             stats: IndexBuilderStats::default(),
         }
     }
 
+    /// Return a builder that encodes hunks in the given format.
+    pub fn with_format(self, format: IndexFormat) -> IndexBuilder {
+        IndexBuilder { format, ..self }
+    }
+
+    /// Return a builder that writes a hunk every `hunk_entries` entries.
+    pub fn with_hunk_entries(self, hunk_entries: usize) -> IndexBuilder {
+        assert!(hunk_entries > 0);
+        IndexBuilder {
+            hunk_entries,
+            ..self
+        }
+    }
+
+    /// Write out any remaining entries, and then the hunk map.
     pub fn finish(mut self) -> Result<IndexBuilderStats> {
         self.finish_hunk()?;
+        self.hunk_map.write(&self.dir)?;
         Ok(self.stats)
     }
 
     /// Append an entry to the index.
     ///
     /// The new entry must sort after everything already written to the index.
-    pub(crate) fn push_entry(&mut self, entry: IndexEntry) -> Result<()> {
+    pub fn push_entry(&mut self, entry: IndexEntry) -> Result<()> {
         // We do this check here rather than the Index constructor so that we
         // can still read invalid apaths...
         self.check_order.check(&entry.apath);
         self.entries.push(entry);
-        if self.entries.len() >= MAX_ENTRIES_PER_HUNK {
+        if self.entries.len() >= self.hunk_entries {
             self.finish_hunk()
         } else {
             Ok(())
@@ -182,16 +320,22 @@ impl IndexBuilder {
                 .context(errors::WriteIndex { path })?;
         }
 
-        let json = serde_json::to_vec(&self.entries).context(errors::SerializeJson { path })?;
-        let uncompressed_len = json.len() as u64;
+        let encoded = match self.format {
+            IndexFormat::Json => {
+                serde_json::to_vec(&self.entries).context(errors::SerializeJson { path })?
+            }
+            IndexFormat::Binary => binary_index::encode(&self.entries),
+        };
+        let uncompressed_len = encoded.len() as u64;
         let mut af = AtomicFile::new(path).context(errors::WriteIndex { path })?;
         let compressed_len =
-            Snappy::compress_and_write(&json, &mut af).context(errors::WriteIndex { path })?;
+            Snappy::compress_and_write(&encoded, &mut af).context(errors::WriteIndex { path })?;
         af.close().context(errors::WriteIndex { path })?;
 
         self.stats.index_hunks += 1;
         self.stats.compressed_index_bytes += compressed_len as u64;
         self.stats.uncompressed_index_bytes += uncompressed_len as u64;
+        self.hunk_map.hunks.push(HunkSummary::new(&self.entries));
         // Ready for the next hunk.
         self.entries.clear();
         self.sequence += 1;
@@ -213,18 +357,84 @@ fn path_for_hunk(dir: &Path, hunk_number: u32) -> PathBuf {
     buf
 }
 
+/// Read a stored index.
+///
+/// Clones share a small cache of recently decoded hunks, used by `find_entry`
+/// and `iter_subtree`, so memory use is bounded however big the index is.
 #[derive(Debug, Clone)]
 pub struct ReadIndex {
     dir: PathBuf,
+    hunk_cache: Arc<Mutex<HunkCache>>,
+}
+
+/// Recently decoded hunks, least recently used first, and the hunk map once
+/// it's been read.
+#[derive(Debug, Default)]
+struct HunkCache {
+    hunks: VecDeque<(u32, Arc<Vec<IndexEntry>>)>,
+    hunk_map: Option<Option<Arc<HunkMap>>>,
+
+    /// The number of hunks read from disk, rather than found in the cache.
+    hunks_read: u64,
 }
 
 impl ReadIndex {
     pub fn new(dir: &Path) -> ReadIndex {
         ReadIndex {
             dir: dir.to_path_buf(),
+            hunk_cache: Arc::default(),
         }
     }
 
+    /// Return the hunk map, if this index has one.
+    ///
+    /// The map is only an optimization, so if it can't be read, the hunks are
+    /// read instead.
+    pub fn hunk_map(&self) -> Option<Arc<HunkMap>> {
+        let mut cache = self.hunk_cache.lock().unwrap();
+        cache
+            .hunk_map
+            .get_or_insert_with(|| match HunkMap::read(&self.dir) {
+                Ok(map) => map.map(Arc::new),
+                Err(e) => {
+                    ui::show_error(&e);
+                    None
+                }
+            })
+            .clone()
+    }
+
+    /// Read one hunk, through the cache, or return None if it doesn't exist.
+    fn cached_hunk(&self, hunk_number: u32) -> Result<Option<Arc<Vec<IndexEntry>>>> {
+        {
+            let mut cache = self.hunk_cache.lock().unwrap();
+            if let Some(pos) = cache.hunks.iter().position(|(n, _)| *n == hunk_number) {
+                let hit = cache.hunks.remove(pos).unwrap();
+                let entries = hit.1.clone();
+                cache.hunks.push_back(hit);
+                return Ok(Some(entries));
+            }
+        }
+        let entries = match read_hunk(&self.dir, hunk_number, &mut IndexEntryIterStats::default())?
+        {
+            Some(entries) => Arc::new(entries),
+            None => return Ok(None),
+        };
+        let mut cache = self.hunk_cache.lock().unwrap();
+        cache.hunks_read += 1;
+        if cache.hunks.len() >= HUNK_CACHE_SIZE {
+            cache.hunks.pop_front();
+        }
+        cache.hunks.push_back((hunk_number, entries.clone()));
+        Ok(Some(entries))
+    }
+
+    /// The number of hunks read from disk by `find_entry` and `iter_subtree`,
+    /// in this index and its clones.
+    pub fn hunks_read(&self) -> u64 {
+        self.hunk_cache.lock().unwrap().hunks_read
+    }
+
     /// Return the (1-based) number of index hunks in an index directory.
     pub fn count_hunks(&self) -> Result<u32> {
         for i in 0.. {
@@ -245,8 +455,126 @@ impl ReadIndex {
 
     /// Make an iterator that will return all entries in this band.
     pub fn iter(&self) -> Result<IndexEntryIter> {
-        IndexEntryIter::open(&self.dir)
+        let mut iter = IndexEntryIter::open(&self.dir)?;
+        iter.hunk_map = self.hunk_map();
+        Ok(iter)
     }
+
+    /// Return the entry for an apath, if it's present.
+    ///
+    /// If there's a hunk map this reads at most one hunk. Otherwise, since
+    /// hunks are in apath order, it binary-searches them, reading only about
+    /// log2 of the number of hunks, rather than scanning the whole index.
+    pub fn find_entry(&self, apath: &Apath) -> Result<Option<IndexEntry>> {
+        if let Some(hunk_map) = self.hunk_map() {
+            return Ok(match hunk_map.hunk_for(apath) {
+                Some(hunk_number) => self.cached_hunk(hunk_number)?.and_then(|entries| {
+                    entries
+                        .binary_search_by(|e| e.apath.cmp(apath))
+                        .ok()
+                        .map(|i| entries[i].clone())
+                }),
+                None => None,
+            });
+        }
+        let (mut lo, mut hi) = (0, self.count_hunks()?);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            let entries = self.cached_hunk(mid)?.unwrap_or_default();
+            match (entries.first(), entries.last()) {
+                (Some(first), _) if *apath < first.apath => hi = mid,
+                (_, Some(last)) if *apath > last.apath => lo = mid + 1,
+                (Some(_), Some(_)) => {
+                    return Ok(entries
+                        .binary_search_by(|e| e.apath.cmp(apath))
+                        .ok()
+                        .map(|i| entries[i].clone()));
+                }
+                // An empty hunk gives no hint which way to go, so fall back
+                // to a scan.
+                _ => return Ok(self.iter()?.advance_to(apath)),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Make an iterator that returns only `apath`, if present, and everything
+    /// inside it.
+    ///
+    /// The contents of a directory are contiguous in the index, so this
+    /// binary-searches for the first hunk that might hold them, and stops
+    /// after the last, without reading the hunks outside the subtree.
+    pub fn iter_subtree(&self, apath: &Apath) -> Result<IndexEntryIter> {
+        let (mut lo, mut hi) = (0, self.count_hunks()?);
+        if let Some(hunk_map) = self.hunk_map() {
+            lo = hunk_map
+                .hunks
+                .partition_point(|h| h.last.cmp_to_contents_of(apath) == Ordering::Less)
+                as u32;
+            hi = lo;
+        }
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            let entries = self.cached_hunk(mid)?.unwrap_or_default();
+            match entries.last() {
+                Some(last) if last.apath.cmp_to_contents_of(apath) == Ordering::Less => {
+                    lo = mid + 1
+                }
+                // The contents start in this hunk or earlier. An empty hunk
+                // gives no hint, so conservatively look earlier.
+                _ => hi = mid,
+            }
+        }
+        let mut iter = self.iter()?;
+        iter.subtree_root = self.find_entry(apath)?;
+        iter.skip_to_hunk(lo);
+        iter.subtree = Some(apath.clone());
+        Ok(iter)
+    }
+}
+
+/// Read and deserialize one index hunk, or return None if it doesn't exist.
+fn read_hunk(
+    dir: &Path,
+    hunk_number: u32,
+    stats: &mut IndexEntryIterStats,
+) -> Result<Option<Vec<IndexEntry>>> {
+    let path = &path_for_hunk(dir, hunk_number);
+    stats.index_hunks += 1;
+    let (comp_len, index_bytes) = match crate::compress::snappy::decompress_file(path) {
+        Ok(x) => x,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| errors::ReadIndex { path }),
+    };
+    stats.uncompressed_index_bytes += index_bytes.len() as u64;
+    stats.compressed_index_bytes += comp_len as u64;
+    let entries: Vec<IndexEntry> = if binary_index::is_binary(&index_bytes) {
+        binary_index::decode(&index_bytes).map_err(|message| Error::DecodeIndex {
+            path: path.clone(),
+            message,
+        })?
+    } else {
+        serde_json::from_slice(&index_bytes).with_context(|| errors::DeserializeIndex { path })?
+    };
+    if entries.is_empty() {
+        error!("Index hunk {:?} is empty", path);
+    }
+    Ok(Some(entries))
+}
+
+/// Check that the compressed content of a hunk, such as one pushed to a
+/// server, decodes to some entries, or describe why not.
+pub(crate) fn check_hunk(compressed: &[u8]) -> std::result::Result<(), String> {
+    let index_bytes = crate::compress::snappy::decompress(compressed).map_err(|e| e.to_string())?;
+    let entries: Vec<IndexEntry> = if binary_index::is_binary(&index_bytes) {
+        binary_index::decode(&index_bytes)?
+    } else {
+        serde_json::from_slice(&index_bytes).map_err(|e| e.to_string())?
+    };
+    if entries.is_empty() {
+        return Err("hunk is empty".to_owned());
+    }
+    Ok(())
 }
 
 /// Read out all the entries from a stored index, in apath order.
@@ -259,6 +587,36 @@ pub struct IndexEntryIter {
     next_hunk_number: u32,
     excludes: GlobSet,
 
+    /// If set, entries deeper than this are skipped.
+    max_depth: Option<usize>,
+
+    /// Excluded directories whose contents may still be ahead in the index,
+    /// so that their contents are excluded too, as they are from a live tree.
+    excluded_dirs: Vec<Apath>,
+
+    /// If set, only return the contents of this directory, after
+    /// `subtree_root`.
+    subtree: Option<Apath>,
+
+    /// The entry for the subtree itself, if it's yet to be returned.
+    subtree_root: Option<IndexEntry>,
+
+    /// True once there can be no more entries to return.
+    finished: bool,
+
+    /// If known, used to skip hunks that can't contain an apath.
+    hunk_map: Option<Arc<HunkMap>>,
+
+    /// For a layered index, the entries of the tree below, which are
+    /// returned unless this index has an entry for the same apath.
+    base: Option<Box<IndexEntryIter>>,
+
+    /// The next entry read from this index but not yet merged with the base.
+    own_next: Option<IndexEntry>,
+
+    /// The next entry read from the base but not yet merged.
+    base_next: Option<IndexEntry>,
+
     pub stats: IndexEntryIterStats,
 }
 
@@ -277,13 +635,13 @@ impl Iterator for IndexEntryIter {
 
     fn next(&mut self) -> Option<IndexEntry> {
         loop {
-            while let Some(entry) = self.buffered_entries.next() {
-                if !self.excludes.is_match(&entry.apath) {
-                    return Some(entry);
-                }
-            }
-            if !self.refill_entry_buffer_or_warn() {
-                return None;
+            let entry = if self.base.is_some() {
+                self.next_merged()?
+            } else {
+                self.next_own()?
+            };
+            if !self.is_excluded(&entry) {
+                return Some(entry);
             }
         }
     }
@@ -299,15 +657,156 @@ impl IndexEntryIter {
             buffered_entries: Vec::<IndexEntry>::new().into_iter().peekable(),
             next_hunk_number: 0,
             excludes: excludes::excludes_nothing(),
+            max_depth: None,
+            excluded_dirs: Vec::new(),
+            subtree: None,
+            subtree_root: None,
+            finished: false,
+            hunk_map: None,
+            base: None,
+            own_next: None,
+            base_next: None,
             stats: IndexEntryIterStats::default(),
         })
     }
 
+    /// Return an iterator over entries already in memory, which must be in
+    /// apath order.
+    pub(crate) fn from_entries(entries: Vec<IndexEntry>) -> IndexEntryIter {
+        IndexEntryIter {
+            buffered_entries: entries.into_iter().peekable(),
+            finished: true,
+            ..IndexEntryIter::open(Path::new("")).unwrap()
+        }
+    }
+
+    /// Consume this iterator and return one that reads this index as a layer
+    /// over `base`: entries in this index replace those for the same apath
+    /// in the base, and tombstones hide them.
+    ///
+    /// The base should have no exclusions of its own: they're applied to the
+    /// merged entries.
+    pub fn with_base(self, base: IndexEntryIter) -> IndexEntryIter {
+        IndexEntryIter {
+            base: Some(Box::new(base)),
+            ..self
+        }
+    }
+
+    /// Return the next entry from this index, ignoring any base and
+    /// exclusions.
+    fn next_own(&mut self) -> Option<IndexEntry> {
+        if let Some(entry) = self.subtree_root.take() {
+            return Some(entry);
+        }
+        loop {
+            if let Some(entry) = self.buffered_entries.next() {
+                if let Some(subtree) = &self.subtree {
+                    match entry.apath.cmp_to_contents_of(subtree) {
+                        Ordering::Less => continue,
+                        Ordering::Equal => (),
+                        Ordering::Greater => {
+                            self.finished = true;
+                            return None;
+                        }
+                    }
+                }
+                return Some(entry);
+            }
+            if self.finished || !self.refill_entry_buffer_or_warn() {
+                self.finished = true;
+                return None;
+            }
+        }
+    }
+
+    /// Return the next entry of this index merged over its base.
+    fn next_merged(&mut self) -> Option<IndexEntry> {
+        loop {
+            if self.own_next.is_none() {
+                self.own_next = self.next_own();
+            }
+            let base = self.base.as_mut().expect("layered iterator has a base");
+            if self.base_next.is_none() {
+                self.base_next = base.next();
+            }
+            let take_own = match (&self.own_next, &self.base_next) {
+                (None, None) => return None,
+                (Some(_), None) => true,
+                (None, Some(_)) => false,
+                (Some(own), Some(below)) => match own.apath.cmp(&below.apath) {
+                    Ordering::Less => true,
+                    Ordering::Equal => {
+                        self.base_next = None;
+                        true
+                    }
+                    Ordering::Greater => false,
+                },
+            };
+            let entry = if take_own {
+                self.own_next.take()
+            } else {
+                self.base_next.take()
+            }
+            .unwrap();
+            if !entry.is_tombstone() {
+                return Some(entry);
+            }
+        }
+    }
+
     /// Consume this iterator and return a new one with exclusions.
+    ///
+    /// The contents of excluded directories are excluded too.
     pub fn with_excludes(self, excludes: globset::GlobSet) -> IndexEntryIter {
         IndexEntryIter { excludes, ..self }
     }
 
+    /// Consume this iterator and return one that skips entries more than
+    /// `max_depth` directories below the root, or below the subtree being
+    /// iterated. With a depth of 0, only the root itself is returned.
+    pub fn with_max_depth(self, max_depth: Option<usize>) -> IndexEntryIter {
+        let subtree_depth = self.subtree.as_ref().map_or(0, Apath::depth);
+        IndexEntryIter {
+            max_depth: max_depth.map(|depth| depth + subtree_depth),
+            ..self
+        }
+    }
+
+    fn is_excluded(&mut self, entry: &IndexEntry) -> bool {
+        let apath = &entry.apath;
+        if matches!(self.max_depth, Some(max_depth) if apath.depth() > max_depth) {
+            return true;
+        }
+        self.excluded_dirs
+            .retain(|dir| apath.cmp_to_contents_of(dir) != Ordering::Greater);
+        if self.excluded_dirs.iter().any(|dir| apath.is_in(dir)) {
+            true
+        } else if self.excludes.is_match(apath) {
+            if entry.kind == Kind::Dir {
+                self.excluded_dirs.push(apath.clone());
+            }
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Discard any buffered entries and continue reading from the start of
+    /// the given hunk, without reading the hunks in between.
+    ///
+    /// Only one hunk is decoded and held in memory at a time, so skipping
+    /// hunks that aren't needed saves both time and memory.
+    pub fn skip_to_hunk(&mut self, hunk_number: u32) {
+        self.buffered_entries = Vec::new().into_iter().peekable();
+        self.next_hunk_number = hunk_number;
+    }
+
+    /// The number of the next hunk to be read.
+    pub fn next_hunk_number(&self) -> u32 {
+        self.next_hunk_number
+    }
+
     /// Return the entry for given apath, if it is present, otherwise None.
     /// It follows this will also return None at the end of the index.
     ///
@@ -315,6 +814,37 @@ impl IndexEntryIter {
     /// discarding entries for any earlier files. However, even if the apath
     /// is not present, other entries coming after it can still be read.
     pub fn advance_to(&mut self, apath: &Apath) -> Option<IndexEntry> {
+        debug_assert!(
+            self.subtree.is_none(),
+            "advance_to isn't supported on subtree iterators"
+        );
+        if self.base.is_none() {
+            return self.advance_own_to(apath);
+        }
+        let own = match self.own_next.take() {
+            Some(e) if e.apath < *apath => self.advance_own_to(apath),
+            Some(e) if e.apath == *apath => Some(e),
+            Some(e) => {
+                self.own_next = Some(e);
+                None
+            }
+            None => self.advance_own_to(apath),
+        };
+        let base = self.base.as_mut().expect("layered iterator has a base");
+        let below = match self.base_next.take() {
+            Some(e) if e.apath < *apath => base.advance_to(apath),
+            Some(e) if e.apath == *apath => Some(e),
+            Some(e) => {
+                self.base_next = Some(e);
+                None
+            }
+            None => base.advance_to(apath),
+        };
+        own.or(below).filter(|e| !e.is_tombstone())
+    }
+
+    /// Advance within this index, ignoring any base.
+    fn advance_own_to(&mut self, apath: &Apath) -> Option<IndexEntry> {
         // This takes some care because we don't want to consume the entry
         // that tells us we went too far.
         loop {
@@ -332,8 +862,17 @@ impl IndexEntryIter {
                         return None;
                     }
                 }
-            } else if !self.refill_entry_buffer_or_warn() {
-                return None;
+            } else {
+                if let Some(hunk_map) = &self.hunk_map {
+                    // Don't read hunks that end before this apath.
+                    let skip_to = hunk_map.first_hunk_not_before(apath);
+                    if skip_to > self.next_hunk_number {
+                        self.next_hunk_number = skip_to;
+                    }
+                }
+                if !self.refill_entry_buffer_or_warn() {
+                    return None;
+                }
             }
         }
     }
@@ -356,30 +895,20 @@ impl IndexEntryIter {
             self.buffered_entries.next().is_none(),
             "refill_entry_buffer called with non-empty buffer"
         );
-        let path = &path_for_hunk(&self.dir, self.next_hunk_number);
+        let hunk_number = self.next_hunk_number;
         // Whether we succeed or fail, don't try to read this hunk again.
         self.next_hunk_number += 1;
-        self.stats.index_hunks += 1;
-        let (comp_len, index_bytes) = match crate::compress::snappy::decompress_file(&path) {
-            Ok(x) => x,
-            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
-                // TODO: Cope with one hunk being missing, while there are still
-                // later-numbered hunks. This would require reading the whole
-                // list of hunks first.
-                return Ok(false);
+        match read_hunk(&self.dir, hunk_number, &mut self.stats)? {
+            Some(entries) => {
+                // NOTE: Not updating 'skipped' counters; here. Questionable value.
+                self.buffered_entries = entries.into_iter().peekable();
+                Ok(true)
             }
-            Err(e) => return Err(e).with_context(|| errors::ReadIndex { path }),
-        };
-        self.stats.uncompressed_index_bytes += index_bytes.len() as u64;
-        self.stats.compressed_index_bytes += comp_len as u64;
-        let entries: Vec<IndexEntry> = serde_json::from_slice(&index_bytes)
-            .with_context(|| errors::DeserializeIndex { path })?;
-        if entries.is_empty() {
-            ui::problem(&format!("Index hunk {:?} is empty", path));
+            // TODO: Cope with one hunk being missing, while there are still
+            // later-numbered hunks. This would require reading the whole
+            // list of hunks first.
+            None => Ok(false),
         }
-        // NOTE: Not updating 'skipped' counters; here. Questionable value.
-        self.buffered_entries = entries.into_iter().peekable();
-        Ok(true)
     }
 }
 
@@ -387,9 +916,12 @@ impl IndexEntryIter {
 mod tests {
     use std::path::Path;
 
+    use proptest::prelude::*;
     use tempfile::TempDir;
 
     use super::*;
+    use crate::compress::snappy::Snappy;
+    use crate::compress::Compression;
 
     pub fn scratch_indexbuilder() -> (TempDir, IndexBuilder) {
         let testdir = TempDir::new().unwrap();
@@ -405,10 +937,77 @@ mod tests {
             kind: Kind::File,
             addrs: vec![],
             target: None,
+            ntfs: None,
+            statx: None,
         })
         .unwrap();
     }
 
+    /// Write an index with entries of the given apaths, kinds, and mtimes.
+    fn write_index(dir: &Path, entries: &[(&str, Kind, i64)]) -> ReadIndex {
+        let mut ib = IndexBuilder::new(dir);
+        for (apath, kind, mtime) in entries {
+            ib.push_entry(IndexEntry {
+                apath: (*apath).into(),
+                mtime: *mtime,
+                mtime_nanos: 0,
+                kind: *kind,
+                addrs: vec![],
+                target: None,
+                ntfs: None,
+                statx: None,
+            })
+            .unwrap();
+        }
+        ib.finish().unwrap();
+        ReadIndex::new(dir)
+    }
+
+    #[test]
+    fn layered_index() {
+        let testdir = TempDir::new().unwrap();
+        let base = write_index(
+            testdir.path(),
+            &[
+                ("/", Kind::Dir, 1),
+                ("/a", Kind::File, 1),
+                ("/b", Kind::File, 1),
+                ("/c", Kind::File, 1),
+            ],
+        );
+        let layer_dir = TempDir::new().unwrap();
+        let layer = write_index(
+            layer_dir.path(),
+            &[
+                ("/a", Kind::File, 2),
+                ("/b", Kind::Deleted, 0),
+                ("/d", Kind::File, 2),
+            ],
+        );
+        let merged: Vec<(String, i64)> = layer
+            .iter()
+            .unwrap()
+            .with_base(base.iter().unwrap())
+            .map(|e| (e.apath.into(), e.mtime))
+            .collect();
+        assert_eq!(
+            merged,
+            [
+                ("/".to_owned(), 1),
+                ("/a".to_owned(), 2),
+                ("/c".to_owned(), 1),
+                ("/d".to_owned(), 2)
+            ]
+        );
+
+        let mut it = layer.iter().unwrap().with_base(base.iter().unwrap());
+        assert_eq!(it.advance_to(&"/a".into()).unwrap().mtime, 2);
+        assert_eq!(it.advance_to(&"/b".into()), None);
+        assert_eq!(it.advance_to(&"/c".into()).unwrap().mtime, 1);
+        assert_eq!(it.next().unwrap().apath, "/d");
+        assert_eq!(it.next(), None);
+    }
+
     #[test]
     fn serialize_index() {
         let entries = [IndexEntry {
@@ -418,6 +1017,8 @@ mod tests {
             kind: Kind::File,
             addrs: vec![],
             target: None,
+            ntfs: None,
+            statx: None,
         }];
         let index_json = serde_json::to_string(&entries).unwrap();
         println!("{}", index_json);
@@ -429,6 +1030,20 @@ mod tests {
         );
     }
 
+    #[test]
+    fn deserialize_entries_from_later_versions() {
+        // Fields and kinds this version doesn't know are ignored.
+        let index_json = r#"[
+            {"apath":"/","kind":"Dir","mtime":1,"xattrs":{"user.a":"b"}},
+            {"apath":"/fifo","kind":"Fifo","mtime":2,"mode":4516}
+        ]"#;
+        let entries: Vec<IndexEntry> = serde_json::from_str(index_json).unwrap();
+        assert_eq!(entries[0].kind, Kind::Dir);
+        assert_eq!(entries[0].mtime, 1);
+        assert_eq!(entries[1].apath, "/fifo");
+        assert_eq!(entries[1].kind, Kind::Unknown);
+    }
+
     #[test]
     #[should_panic]
     fn index_builder_checks_order() {
@@ -441,6 +1056,8 @@ mod tests {
             kind: Kind::File,
             addrs: vec![],
             target: None,
+            ntfs: None,
+            statx: None,
         })
         .unwrap();
         ib.push_entry(IndexEntry {
@@ -450,6 +1067,8 @@ mod tests {
             kind: Kind::File,
             addrs: vec![],
             target: None,
+            ntfs: None,
+            statx: None,
         })
         .unwrap();
     }
@@ -465,6 +1084,8 @@ mod tests {
             addrs: vec![],
             mtime_nanos: 0,
             target: None,
+            ntfs: None,
+            statx: None,
         })
         .unwrap();
     }
@@ -534,6 +1155,172 @@ mod tests {
         assert_eq!(names, &["/1.1", "/1.2", "/2.1", "/2.2"]);
     }
 
+    #[test]
+    fn find_entry_in_hunks() {
+        let (_testdir, mut ib) = scratch_indexbuilder();
+        for hunk in 1..=5 {
+            add_an_entry(&mut ib, &format!("/{}.1", hunk));
+            add_an_entry(&mut ib, &format!("/{}.3", hunk));
+            ib.finish_hunk().unwrap();
+        }
+        let index = ReadIndex::new(&ib.dir);
+        let find = |apath: &str| {
+            index
+                .find_entry(&apath.into())
+                .unwrap()
+                .map(|e| String::from(e.apath))
+        };
+        for present in &["/1.1", "/1.3", "/3.1", "/4.3", "/5.3"] {
+            assert_eq!(find(present).as_deref(), Some(*present));
+        }
+        for absent in &["/0", "/1.2", "/2.5", "/5.2", "/6", "/a/1.1"] {
+            assert_eq!(find(absent), None);
+        }
+    }
+
+    #[test]
+    fn find_entry_uses_hunk_cache() {
+        let (_testdir, mut ib) = scratch_indexbuilder();
+        for hunk in 1..=3 {
+            add_an_entry(&mut ib, &format!("/{}", hunk));
+            ib.finish_hunk().unwrap();
+        }
+        let index = ReadIndex::new(&ib.dir);
+        assert!(index.find_entry(&"/3".into()).unwrap().is_some());
+        let hunks_read = index.hunks_read();
+        assert!(hunks_read > 0);
+        // The hunks read while searching are cached, so finding the entry
+        // again, even through a clone of the index, reads nothing more.
+        assert!(index.find_entry(&"/3".into()).unwrap().is_some());
+        assert!(index.clone().find_entry(&"/3".into()).unwrap().is_some());
+        assert_eq!(index.hunks_read(), hunks_read);
+        assert_eq!(ReadIndex::new(&ib.dir).hunks_read(), 0);
+    }
+
+    /// Write hunks of two entries each, `/{hunk}.1` and `/{hunk}.2`, and
+    /// finish the index.
+    fn finished_index(hunks: u32) -> TempDir {
+        let (testdir, mut ib) = scratch_indexbuilder();
+        for hunk in 0..hunks {
+            add_an_entry(&mut ib, &format!("/{}.1", hunk));
+            add_an_entry(&mut ib, &format!("/{}.2", hunk));
+            ib.finish_hunk().unwrap();
+        }
+        ib.finish().unwrap();
+        testdir
+    }
+
+    fn damage_hunk(dir: &Path, hunk_number: u32) {
+        let mut damaged = std::fs::File::create(super::path_for_hunk(dir, hunk_number)).unwrap();
+        Snappy::compress_and_write(b"garbage", &mut damaged).unwrap();
+    }
+
+    #[test]
+    fn lookups_use_hunk_map() {
+        let testdir = finished_index(5);
+        let index = ReadIndex::new(testdir.path());
+        let hunk_map = index.hunk_map().unwrap();
+        assert_eq!(hunk_map.hunks.len(), 5);
+        assert_eq!(hunk_map.hunks[2].first, "/2.1");
+        assert_eq!(hunk_map.hunks[2].last, "/2.2");
+
+        // Only the hunk holding the apath is read.
+        for hunk_number in &[0, 1, 2, 4] {
+            damage_hunk(testdir.path(), *hunk_number);
+        }
+        let found = index.find_entry(&"/3.2".into()).unwrap().unwrap();
+        assert_eq!(found.apath, "/3.2");
+        assert!(index.find_entry(&"/3.3".into()).unwrap().is_none());
+        assert!(index.find_entry(&"/9".into()).unwrap().is_none());
+    }
+
+    #[test]
+    fn advance_to_skips_hunks_with_map() {
+        let testdir = finished_index(5);
+        let mut it = ReadIndex::new(testdir.path()).iter().unwrap();
+        for hunk_number in 0..3 {
+            damage_hunk(testdir.path(), hunk_number);
+        }
+        assert_eq!(it.advance_to(&"/3.1".into()).unwrap().apath, "/3.1");
+        assert_eq!(it.advance_to(&"/4.2".into()).unwrap().apath, "/4.2");
+        assert_eq!(it.stats.index_hunks, 2);
+    }
+
+    #[test]
+    fn iter_subtree_with_map() {
+        let (testdir, mut ib) = scratch_indexbuilder();
+        for hunk in &[&["/", "/a", "/b"][..], &["/a/1", "/a/2"], &["/b/1"]] {
+            for apath in hunk.iter() {
+                add_an_entry(&mut ib, apath);
+            }
+            ib.finish_hunk().unwrap();
+        }
+        ib.finish().unwrap();
+        damage_hunk(testdir.path(), 1);
+        let names: Vec<String> = ReadIndex::new(testdir.path())
+            .iter_subtree(&"/b".into())
+            .unwrap()
+            .map(|e| e.apath.into())
+            .collect();
+        assert_eq!(names, ["/b", "/b/1"]);
+    }
+
+    #[test]
+    fn skip_to_hunk() {
+        let (_testdir, mut ib) = scratch_indexbuilder();
+        for hunk in 0..3 {
+            add_an_entry(&mut ib, &format!("/{}", hunk));
+            ib.finish_hunk().unwrap();
+        }
+        let mut it = IndexEntryIter::open(&ib.dir).unwrap();
+        assert_eq!(it.next().unwrap().apath, "/0");
+        it.skip_to_hunk(2);
+        let rest: Vec<String> = it.map(|e| e.apath.into()).collect();
+        assert_eq!(rest, ["/2"]);
+    }
+
+    #[test]
+    fn iter_subtree_in_hunks() {
+        let (_testdir, mut ib) = scratch_indexbuilder();
+        for hunk in &[
+            &["/", "/a", "/b"][..],
+            &["/c", "/a/1", "/a/2"],
+            &["/b/1", "/b/2"],
+            &["/b/3", "/b/x/1"],
+            &["/c/1"],
+        ] {
+            for apath in hunk.iter() {
+                add_an_entry(&mut ib, apath);
+            }
+            ib.finish_hunk().unwrap();
+        }
+        let index = ReadIndex::new(&ib.dir);
+        let subtree = |apath: &str| {
+            let mut it = index.iter_subtree(&apath.into()).unwrap();
+            let names: Vec<String> = it.by_ref().map(|e| e.apath.into()).collect();
+            (names, it.next_hunk_number)
+        };
+        assert_eq!(
+            subtree("/b"),
+            (
+                vec![
+                    "/b".to_owned(),
+                    "/b/1".to_owned(),
+                    "/b/2".to_owned(),
+                    "/b/3".to_owned(),
+                    "/b/x/1".to_owned()
+                ],
+                // Stopped after reading the hunk containing /c/1.
+                5
+            )
+        );
+        assert_eq!(subtree("/a").0, ["/a", "/a/1", "/a/2"]);
+        assert_eq!(subtree("/b/x").0, ["/b/x/1"]);
+        assert_eq!(subtree("/b/2").0, ["/b/2"]);
+        assert_eq!(subtree("/nothing").0, Vec::<String>::new());
+        assert_eq!(subtree("/").0.len(), 11);
+    }
+
     #[test]
     #[should_panic]
     fn no_duplicate_paths() {
@@ -578,6 +1365,42 @@ mod tests {
         assert_eq!(names, &["/bar"]);
     }
 
+    #[test]
+    fn excluded_dir_contents() {
+        let (_testdir, mut ib) = scratch_indexbuilder();
+        for (apath, kind) in &[
+            ("/", Kind::Dir),
+            ("/a", Kind::Dir),
+            ("/b", Kind::Dir),
+            ("/c", Kind::File),
+            ("/a/x", Kind::Dir),
+            ("/a/y", Kind::File),
+            ("/a/x/z", Kind::File),
+            ("/b/x", Kind::File),
+        ] {
+            ib.push_entry(IndexEntry {
+                apath: (*apath).into(),
+                kind: *kind,
+                mtime: 0,
+                mtime_nanos: 0,
+                addrs: vec![],
+                target: None,
+                ntfs: None,
+                statx: None,
+            })
+            .unwrap();
+        }
+        ib.finish_hunk().unwrap();
+
+        let excludes = excludes::from_strings(&["/a"]).unwrap();
+        let names: Vec<String> = IndexEntryIter::open(&ib.dir)
+            .unwrap()
+            .with_excludes(excludes)
+            .map(|x| x.apath.into())
+            .collect();
+        assert_eq!(names, &["/", "/b", "/c", "/b/x"]);
+    }
+
     #[test]
     fn advance() {
         let (_testdir, mut ib) = scratch_indexbuilder();
@@ -632,4 +1455,219 @@ mod tests {
         assert_eq!(read_index.count_hunks()?, 1);
         Ok(())
     }
+
+    #[test]
+    fn smaller_hunks() -> Result<()> {
+        let (testdir, ib) = scratch_indexbuilder();
+        let mut ib = ib.with_hunk_entries(10);
+        for i in 0..25 {
+            add_an_entry(&mut ib, &format!("/{:0>10}", i));
+        }
+        ib.finish()?;
+        let read_index = ReadIndex::new(&testdir.path());
+        assert_eq!(read_index.count_hunks()?, 3);
+        assert_eq!(read_index.iter()?.count(), 25);
+        Ok(())
+    }
+
+    /// Generators of arbitrary index entries, for property tests of the
+    /// index encodings.
+    mod strategies {
+        use super::*;
+
+        fn component() -> impl Strategy<Value = String> {
+            "[^/\u{0}]{1,8}".prop_filter("not . or ..", |c| c != "." && c != "..")
+        }
+
+        pub fn apath() -> impl Strategy<Value = Apath> {
+            prop::collection::vec(component(), 0..4)
+                .prop_map(|components| Apath::from(format!("/{}", components.join("/"))))
+        }
+
+        fn kind() -> impl Strategy<Value = Kind> {
+            prop_oneof![
+                Just(Kind::File),
+                Just(Kind::Dir),
+                Just(Kind::Symlink),
+                Just(Kind::Unknown),
+                Just(Kind::Deleted),
+            ]
+        }
+
+        /// Hashes are usually lowercase hex, which the binary format packs,
+        /// but others must survive too.
+        fn hash() -> impl Strategy<Value = String> {
+            prop_oneof![
+                "[0-9a-f]{128}",
+                "([0-9a-f]{2}){0,4}",
+                "[0-9A-Fa-z]{0,9}",
+                any::<String>(),
+            ]
+        }
+
+        fn address() -> impl Strategy<Value = blockdir::Address> {
+            (hash(), any::<u64>(), any::<u64>()).prop_map(|(hash, start, len)| blockdir::Address {
+                hash,
+                start,
+                len,
+            })
+        }
+
+        fn ntfs() -> impl Strategy<Value = NtfsMetadata> {
+            (
+                prop::option::of("[0-9a-f]{0,40}"),
+                prop::collection::vec(
+                    (any::<String>(), "[0-9a-f]{0,20}")
+                        .prop_map(|(name, content)| NamedStream { name, content }),
+                    0..3,
+                ),
+            )
+                .prop_map(|(security_descriptor, streams)| NtfsMetadata {
+                    security_descriptor,
+                    streams,
+                })
+        }
+
+        fn statx() -> impl Strategy<Value = StatxMetadata> {
+            (
+                prop::option::of(any::<i64>()),
+                any::<u32>(),
+                prop::option::of(any::<u64>()),
+                any::<u64>(),
+                prop::option::of(any::<u32>()),
+                prop::option::of(any::<u32>()),
+            )
+                .prop_map(|(btime, btime_nanos, mnt_id, attributes, uid, gid)| {
+                    StatxMetadata {
+                        btime,
+                        btime_nanos,
+                        mnt_id,
+                        attributes,
+                        uid,
+                        gid,
+                    }
+                })
+        }
+
+        /// Times before the epoch, far in the past and future, and with
+        /// out-of-range nanoseconds are all possible.
+        fn mtime() -> impl Strategy<Value = (i64, u32)> {
+            (
+                prop_oneof![
+                    any::<i64>(),
+                    -86_400i64..86_400,
+                    Just(i64::MIN),
+                    Just(i64::MAX),
+                ],
+                prop_oneof![0u32..1_000_000_000, Just(0), any::<u32>()],
+            )
+        }
+
+        pub fn entry() -> impl Strategy<Value = IndexEntry> {
+            (
+                apath(),
+                kind(),
+                mtime(),
+                prop::collection::vec(address(), 0..4),
+                prop::option::of(any::<String>()),
+                prop::option::of(ntfs()),
+                prop::option::of(statx()),
+            )
+                .prop_map(
+                    |(apath, kind, (mtime, mtime_nanos), addrs, target, ntfs, statx)| IndexEntry {
+                        apath,
+                        kind,
+                        mtime,
+                        mtime_nanos,
+                        addrs,
+                        target,
+                        ntfs,
+                        statx,
+                    },
+                )
+        }
+
+        /// Entries with distinct apaths, in the order they're stored in an
+        /// index.
+        pub fn sorted_entries() -> impl Strategy<Value = Vec<IndexEntry>> {
+            prop::collection::vec(entry(), 0..40).prop_map(|mut entries| {
+                entries.sort_by(|a, b| a.apath.cmp(&b.apath));
+                entries.dedup_by(|a, b| a.apath == b.apath);
+                entries
+            })
+        }
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn json_entries_round_trip(entries in prop::collection::vec(strategies::entry(), 0..20)) {
+            let json = serde_json::to_vec(&entries).unwrap();
+            let decoded: Vec<IndexEntry> = serde_json::from_slice(&json).unwrap();
+            prop_assert_eq!(decoded, entries);
+        }
+
+        #[test]
+        fn binary_entries_round_trip(entries in prop::collection::vec(strategies::entry(), 0..20)) {
+            let buf = binary_index::encode(&entries);
+            prop_assert!(binary_index::is_binary(&buf));
+            prop_assert_eq!(binary_index::decode(&buf).unwrap(), entries);
+        }
+
+        #[test]
+        fn written_index_round_trips(
+            entries in strategies::sorted_entries(),
+            binary in any::<bool>(),
+            hunk_entries in 1usize..8,
+        ) {
+            let testdir = TempDir::new().unwrap();
+            let format = if binary { IndexFormat::Binary } else { IndexFormat::Json };
+            let mut ib = IndexBuilder::new(testdir.path())
+                .with_format(format)
+                .with_hunk_entries(hunk_entries);
+            for entry in &entries {
+                ib.push_entry(entry.clone()).unwrap();
+            }
+            ib.finish().unwrap();
+
+            let read_index = ReadIndex::new(testdir.path());
+            prop_assert_eq!(read_index.iter().unwrap().collect::<Vec<_>>(), entries.clone());
+            for entry in &entries {
+                prop_assert_eq!(read_index.find_entry(&entry.apath).unwrap(), Some(entry.clone()));
+            }
+        }
+
+        #[test]
+        fn apath_order_is_total(a in strategies::apath(), b in strategies::apath()) {
+            prop_assert_eq!(a.cmp(&b), b.cmp(&a).reverse());
+            prop_assert_eq!(a.cmp(&b) == Ordering::Equal, a == b);
+        }
+
+        /// In index order, the contents of every directory are contiguous
+        /// and come after the directory itself, so that subtrees can be
+        /// read by seeking.
+        #[test]
+        fn apath_order_keeps_dirs_together(
+            mut apaths in prop::collection::vec(strategies::apath(), 1..40),
+        ) {
+            apaths.sort();
+            for dir in &apaths {
+                let contents: Vec<usize> = apaths
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, a)| a.cmp_to_contents_of(dir) == Ordering::Equal)
+                    .map(|(i, _)| i)
+                    .collect();
+                if let (Some(first), Some(last)) = (contents.first(), contents.last()) {
+                    prop_assert_eq!(last - first + 1, contents.len());
+                    prop_assert!(apaths[*first] > *dir);
+                    for a in &apaths[..*first] {
+                        prop_assert_eq!(a.cmp_to_contents_of(dir), Ordering::Less);
+                    }
+                    for a in &apaths[last + 1..] {
+                        prop_assert_eq!(a.cmp_to_contents_of(dir), Ordering::Greater);
+                    }
+                }
+            }
+        }
+    }
 }
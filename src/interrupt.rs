@@ -0,0 +1,43 @@
+// Conserve backup system.
+// Copyright 2020 Martin Pool.
+
+//! Stop cleanly when the user interrupts with Ctrl-C, or the process is
+//! asked to terminate.
+//!
+//! The first signal sets a flag, which `copy_tree` checks before each entry:
+//! it then saves what's been copied so far, leaving an incomplete version,
+//! and fails with `Error::Interrupted`. A second signal exits immediately.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use lazy_static::lazy_static;
+use snafu::ResultExt;
+use tracing::warn;
+
+use crate::*;
+
+/// The exit status after being interrupted, by the shell's convention for
+/// SIGINT.
+pub const INTERRUPTED_EXIT_CODE: i32 = 130;
+
+lazy_static! {
+    static ref INTERRUPTED: Arc<AtomicBool> = Arc::default();
+}
+
+/// Handle SIGINT and SIGTERM, or Ctrl-C on Windows, by setting the returned
+/// flag, which can be given to `CopyOptions::stop` or
+/// `BackupOptions::stop`.
+///
+/// This can only be called once in a process.
+pub fn stop_on_signals() -> Result<Arc<AtomicBool>> {
+    ctrlc::set_handler(|| {
+        if INTERRUPTED.swap(true, Ordering::SeqCst) {
+            ui::clear_progress();
+            std::process::exit(INTERRUPTED_EXIT_CODE);
+        }
+        warn!("Interrupted: stopping after the current file (interrupt again to stop now)");
+    })
+    .context(errors::InstallSignalHandler)?;
+    Ok(INTERRUPTED.clone())
+}
@@ -16,7 +16,10 @@ pub struct AtomicFile {
 
 impl AtomicFile {
     pub fn new(path: &Path) -> std::io::Result<AtomicFile> {
-        let dir = path.parent().unwrap();
+        let dir = match path.parent() {
+            Some(dir) if dir != Path::new("") => dir,
+            _ => Path::new("."),
+        };
         Ok(AtomicFile {
             path: path.to_path_buf(),
             f: tempfile::Builder::new().prefix("tmp").tempfile_in(dir)?,
@@ -28,11 +31,25 @@ impl AtomicFile {
         // `link` on Unix, and some filesystems don't support it.  That's probably fine
         // because the files being updated by this should never already exist, though
         // it does mean we won't detect unexpected cases where it does.
+        crate::faults::before_write(&self.path)?;
         self.f
             .persist(&self.path)
             .and(Ok(()))
             .or_else(|e| Err(e.error))
     }
+
+    /// Move the file into place, failing with `AlreadyExists` if something
+    /// else already created it.
+    ///
+    /// Unlike checking beforehand, this can't race with another writer, but it
+    /// needs a filesystem that supports hard links.
+    pub fn close_noclobber(self) -> std::io::Result<()> {
+        crate::faults::before_write(&self.path)?;
+        self.f
+            .persist_noclobber(&self.path)
+            .map(|_| ())
+            .map_err(|e| e.error)
+    }
 }
 
 impl Write for AtomicFile {
@@ -109,8 +126,220 @@ pub fn list_dir(path: &Path) -> std::io::Result<(Vec<String>, Vec<String>)> {
     Ok((file_names, dir_names))
 }
 
+/// Convert a path to the form used to read or write files in a tree.
+///
+/// On Windows, this is the absolute extended-length form, like
+/// `\\?\C:\dir`, so that paths deep in the tree aren't limited to 260
+/// characters. Elsewhere, the path is unchanged.
+#[cfg(windows)]
+pub(crate) fn long_path(path: &Path) -> PathBuf {
+    let absolute = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        match std::env::current_dir() {
+            Ok(cwd) => cwd.join(path),
+            Err(_) => return path.to_path_buf(),
+        }
+    };
+    extended_length(&absolute.to_string_lossy()).map_or(absolute, PathBuf::from)
+}
+
+/// Convert a path to the form used to read or write files in a tree.
+///
+/// On Windows, this is the absolute extended-length form, like
+/// `\\?\C:\dir`, so that paths deep in the tree aren't limited to 260
+/// characters. Elsewhere, the path is unchanged.
+#[cfg(not(windows))]
+pub(crate) fn long_path(path: &Path) -> PathBuf {
+    path.to_path_buf()
+}
+
+/// Convert an absolute Windows path like `C:\dir` or `\\server\share\dir`
+/// to extended-length form, or None if it's not absolute.
+///
+/// Windows doesn't interpret `/`, `.` or `..` in extended-length paths, so
+/// they're resolved here.
+#[cfg_attr(not(windows), allow(dead_code))]
+fn extended_length(path: &str) -> Option<String> {
+    if path.starts_with(r"\\?\") {
+        return Some(path.to_owned());
+    }
+    let path = path.replace('/', r"\");
+    let (prefix, rest, root_parts) = if let Some(rest) = path.strip_prefix(r"\\") {
+        (r"\\?\UNC\", rest, 2)
+    } else {
+        let bytes = path.as_bytes();
+        if bytes.len() >= 3 && bytes[0].is_ascii_alphabetic() && &bytes[1..3] == b":\\" {
+            (r"\\?\", path.as_str(), 1)
+        } else {
+            return None;
+        }
+    };
+    let mut parts: Vec<&str> = Vec::new();
+    for part in rest.split('\\') {
+        match part {
+            "" | "." => (),
+            ".." => {
+                if parts.len() > root_parts {
+                    parts.pop();
+                }
+            }
+            part => parts.push(part),
+        }
+    }
+    let mut result = format!("{}{}", prefix, parts.join(r"\"));
+    if parts.len() == root_parts {
+        result.push('\\');
+    }
+    Some(result)
+}
+
+/// The path of `apath` within the directory `root`.
+///
+/// Each component is added separately, because `/` isn't a separator in
+/// Windows extended-length paths.
+pub(crate) fn apath_path(root: &Path, apath: &str) -> PathBuf {
+    let mut path = root.to_path_buf();
+    for part in apath.split('/').filter(|p| !p.is_empty()) {
+        path.push(part);
+    }
+    path
+}
+
+/// Open a source file for reading, without updating its access time if
+/// that's permitted.
+#[cfg(target_os = "linux")]
+pub(crate) fn open_source_file(path: &Path) -> io::Result<fs::File> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    match fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOATIME)
+        .open(path)
+    {
+        // Only the file's owner, or root, can use O_NOATIME.
+        Err(e) if e.raw_os_error() == Some(libc::EPERM) => fs::File::open(path),
+        result => result,
+    }
+}
+
+/// Open a source file for reading, without updating its access time if
+/// that's permitted.
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn open_source_file(path: &Path) -> io::Result<fs::File> {
+    fs::File::open(path)
+}
+
+/// Tell the kernel that a source file that's been read won't be read again
+/// soon, so that a large backup doesn't push everything else out of the page
+/// cache.
+#[cfg(target_os = "linux")]
+pub(crate) fn drop_from_cache(file: &fs::File) {
+    use std::os::unix::io::AsRawFd;
+
+    unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED) };
+}
+
+/// Tell the kernel that a source file that's been read won't be read again
+/// soon, so that a large backup doesn't push everything else out of the page
+/// cache.
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn drop_from_cache(_file: &fs::File) {}
+
+/// The number of bytes available to unprivileged users on the filesystem
+/// holding `path`, or None if it can't be found on this platform.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub(crate) fn available_space(path: &Path) -> io::Result<Option<u64>> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut buf: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut buf) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    #[allow(clippy::useless_conversion)]
+    Ok(Some(u64::from(buf.f_bavail) * u64::from(buf.f_frsize)))
+}
+
+/// The number of bytes available to unprivileged users on the filesystem
+/// holding `path`, or None if it can't be found on this platform.
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub(crate) fn available_space(_path: &Path) -> io::Result<Option<u64>> {
+    Ok(None)
+}
+
+/// True if an IO error means the filesystem is full.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub(crate) fn is_disk_full(error: &io::Error) -> bool {
+    error.raw_os_error() == Some(libc::ENOSPC)
+}
+
+/// True if an IO error means the filesystem is full.
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub(crate) fn is_disk_full(_error: &io::Error) -> bool {
+    false
+}
+
 #[cfg(test)]
 mod tests {
     // TODO: Somehow test the error cases.
     // TODO: Specific test for write_compressed_bytes.
+
+    use super::*;
+
+    #[test]
+    fn extended_length_paths() {
+        assert_eq!(
+            extended_length(r"C:\Users\me\..\you\.\src/conserve").as_deref(),
+            Some(r"\\?\C:\Users\you\src\conserve")
+        );
+        assert_eq!(extended_length(r"d:\").as_deref(), Some(r"\\?\d:\"));
+        assert_eq!(
+            extended_length(r"\\server\share\..\dir").as_deref(),
+            Some(r"\\?\UNC\server\share\dir")
+        );
+        assert_eq!(
+            extended_length(r"\\?\C:\long").as_deref(),
+            Some(r"\\?\C:\long")
+        );
+        assert_eq!(extended_length(r"relative\dir"), None);
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    #[test]
+    fn space_is_available() {
+        let tf = crate::test_fixtures::TreeFixture::new();
+        assert!(available_space(tf.path()).unwrap().unwrap() > 0);
+        assert!(available_space(&tf.path().join("nonexistent")).is_err());
+        assert!(is_disk_full(&io::Error::from_raw_os_error(libc::ENOSPC)));
+        assert!(!is_disk_full(&io::Error::from(io::ErrorKind::NotFound)));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn source_files_keep_atime() {
+        let tf = crate::test_fixtures::TreeFixture::new();
+        let path = tf.create_file("hello");
+        // Older than the mtime, so that even relatime would update it.
+        utime::set_file_times(&path, 1_000_000, 2_000_000).unwrap();
+        let mut file = open_source_file(&path).unwrap();
+        let mut content = String::new();
+        file.read_to_string(&mut content).unwrap();
+        drop_from_cache(&file);
+        assert_eq!(content, "contents");
+        let atime = fs::metadata(&path).unwrap().accessed().unwrap();
+        assert_eq!(
+            atime,
+            std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000)
+        );
+    }
+
+    #[test]
+    fn apath_paths() {
+        let root = Path::new("root");
+        assert_eq!(apath_path(root, "/"), root);
+        assert_eq!(apath_path(root, "/a/b"), root.join("a").join("b"));
+    }
 }
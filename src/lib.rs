@@ -5,51 +5,121 @@
 // Conserve implementation modules.
 mod apath;
 mod archive;
+#[cfg(feature = "async")]
+pub mod async_api;
 mod backup;
 mod band;
 mod bandid;
+mod binary_index;
 mod blockdir;
+mod compare_archives;
 pub mod compress;
+mod content_index;
 mod copy_tree;
+mod credentials;
+mod deletion_guard;
+mod disk_usage;
+pub mod doctor;
 mod entry;
 pub mod errors;
 pub mod excludes;
+pub mod faults;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+mod filter;
+mod http;
+pub mod hunk_map;
 pub mod index;
+pub mod interrupt;
 mod io;
 mod jsonio;
 pub mod live_tree;
+pub mod log_file;
 mod merge;
 pub(crate) mod misc;
+mod ntfs;
+mod observer;
 pub mod output;
+mod problem;
+mod push;
+mod reflink;
+mod replicate;
+mod report;
 mod restore;
+mod scan_cache;
+pub mod server;
+mod signing;
+mod snapshot;
+pub mod source_helper;
+mod spill;
 pub mod stats;
+mod statx;
 mod stored_file;
 mod stored_tree;
+mod tar_tree;
 pub mod test_fixtures;
+mod threads;
 mod tree;
+mod tuning;
 pub mod ui;
 pub mod unix_time;
+mod uring;
+mod validation_cache;
+mod watch;
 
 pub use crate::apath::Apath;
-pub use crate::archive::Archive;
-pub use crate::backup::BackupWriter;
+pub use crate::archive::{Archive, ValidateOptions};
+pub use crate::backup::{
+    backup_span, check_free_space, BackupOptions, BackupWriter, MAX_INDEX_LAYERS,
+};
 pub use crate::band::Band;
 pub use crate::bandid::BandId;
-pub use crate::blockdir::BlockDir;
+pub use crate::blockdir::{BlockDir, DEFAULT_SMALL_FILE_SIZE};
+pub use crate::compare_archives::{compare_archives, ArchiveComparison};
 pub use crate::compress::snappy::Snappy;
 pub use crate::compress::Compression;
+pub use crate::content_index::{
+    build_content_index, find_content, find_duplicates, unindexed_bands, ContentEntry,
+    ContentMatches, DuplicateSet,
+};
 pub use crate::copy_tree::{copy_tree, CopyOptions, COPY_DEFAULT};
+pub use crate::credentials::{redact_url, CredentialSource, Secret};
+pub use crate::disk_usage::DiskUsage;
 pub use crate::entry::{Entry, Kind};
 pub use crate::errors::*;
-pub use crate::index::{IndexBuilder, IndexEntry, ReadIndex};
+pub use crate::filter::Filter;
+pub use crate::index::{IndexBuilder, IndexEntry, IndexFormat, ReadIndex};
 pub use crate::io::{ensure_dir_exists, list_dir, AtomicFile};
-pub use crate::live_tree::{LiveEntry, LiveTree};
-pub use crate::merge::{iter_merged_entries, MergedEntryKind};
-pub use crate::misc::bytes_to_human_mb;
-pub use crate::restore::RestoreTree;
-pub use crate::stored_tree::StoredTree;
+pub use crate::live_tree::{Exclusion, ExclusionReason, LiveEntry, LiveFile, LiveTree};
+pub use crate::merge::{
+    iter_merged_entries, merge_trees, MergeTrees, MergedEntry, MergedEntryKind,
+};
+pub use crate::misc::{bytes_to_human, bytes_to_human_mb, in_thread_pool};
+pub use crate::ntfs::{NamedStream, NtfsMetadata, MAX_STREAM_SIZE};
+pub use crate::observer::Observer;
+pub use crate::problem::{Problem, Problems};
+pub use crate::push::{PushOptions, PushStats};
+pub use crate::replicate::{
+    MirrorConfig, MirrorOutcome, MirrorResult, MirrorState, ReplicateConfig, ReplicateOptions,
+};
+pub use crate::report::{Report, RunState};
+pub use crate::restore::{
+    parse_id_map, parse_path_map, restore_span, EscapingSymlinks, RestoreOptions, RestoreTree,
+};
+pub use crate::server::Server;
+pub use crate::signing::{PublicKey, SigningKey};
+pub use crate::snapshot::Snapshot;
+pub use crate::source_helper::{HelperEntry, HelperFile, SourceHelper};
+pub use crate::spill::{memory_limit, set_memory_limit};
+pub use crate::statx::StatxMetadata;
+pub use crate::stored_tree::{Change, ChangeKind, StoredTree};
+pub use crate::tar_tree::{TarEntry, TarTree};
+pub use crate::threads::{set_threads, threads, Threads};
 pub use crate::tree::{ReadBlocks, ReadTree, TreeSize, WriteTree};
+pub use crate::tuning::Tuning;
 pub use crate::ui::ProgressState;
+pub use crate::validation_cache::{parse_age, ValidationCache};
+pub use crate::watch::WatchOptions;
 
 // Commonly-used external types.
 pub use globset::GlobSet;
@@ -3,68 +3,309 @@
 
 //! Find source files within a source directory, in apath order.
 
+use std::cmp::Ordering;
 use std::collections::vec_deque::VecDeque;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt;
 use std::fs;
-use std::io::ErrorKind;
+use std::io::{self, ErrorKind, Read};
+use std::iter::Peekable;
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 
 use snafu::ResultExt;
+use tracing::{debug, debug_span, warn};
 
 use globset::GlobSet;
+use serde::{Deserialize, Serialize};
 
 use super::*;
+use crate::index::IndexEntryIter;
+use crate::io::{apath_path, drop_from_cache, long_path, open_source_file};
+use crate::source_helper::{HelperFile, SourceHelper};
+use crate::spill::{self, Merge, SortBuffer, Sorted};
 use crate::stats::LiveTreeIterStats;
+use crate::statx::{self, SourceMetadata};
 use crate::unix_time::UnixTime;
+use crate::uring::{self, Prefetch};
 
 /// A real tree on the filesystem, for use as a backup source or restore destination.
 #[derive(Clone)]
 pub struct LiveTree {
     path: PathBuf,
     excludes: GlobSet,
+
+    /// If set, the source directory itself appears as a directory of this name
+    /// in the root of the tree, rather than its contents being at the root.
+    source_dir_name: Option<String>,
+
+    /// If true, Conserve archives within the tree are included rather than
+    /// skipped.
+    include_archives: bool,
+
+    /// Directories containing a file with any of these names are skipped.
+    exclude_if_present: Vec<String>,
+
+    /// If set, entries skipped while iterating are recorded here.
+    exclusions: Option<Arc<Mutex<Vec<Exclusion>>>>,
+
+    /// If true, read NTFS security descriptors and named streams.
+    ntfs_metadata: bool,
+
+    /// If true, keep the birth time, mount ID, and attributes from `statx`.
+    statx_metadata: bool,
+
+    /// If set, small files are read in batches through io_uring.
+    prefetch: Option<Arc<Mutex<Prefetch>>>,
+
+    /// If set, directories and files that can't be read for lack of
+    /// permission are read through this helper.
+    source_helper: Option<SourceHelper>,
+
+    /// If set, the entries of directories whose mtime and names are unchanged
+    /// from this tree are taken from it, without reading their metadata.
+    trusted_basis: Option<Arc<StoredTree>>,
+
+    /// If true, symlinks are followed and stored as what they point to.
+    dereference: bool,
+
+    /// If set, directories this many levels below the root are listed
+    /// without their contents.
+    max_depth: Option<usize>,
+}
+
+/// An entry that was skipped while listing a live tree, and why.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Exclusion {
+    /// The apath of the skipped entry, relative to the source directory.
+    pub apath: Apath,
+    pub reason: ExclusionReason,
+}
+
+/// Why an entry was skipped.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ExclusionReason {
+    /// Matched the exclude pattern at this index in the list used to build the
+    /// `GlobSet`.
+    Pattern(usize),
+    /// The directory is a Conserve archive.
+    Archive,
+    /// The directory contains the marker file at this index in the names
+    /// given to `with_exclude_if_present`.
+    Marker(usize),
 }
 
 impl LiveTree {
     pub fn open<P: AsRef<Path>>(path: P) -> Result<LiveTree> {
         // TODO: Maybe fail here if the root doesn't exist or isn't a directory?
         Ok(LiveTree {
-            path: path.as_ref().to_path_buf(),
+            path: long_path(path.as_ref()),
             excludes: excludes::excludes_nothing(),
+            source_dir_name: None,
+            include_archives: false,
+            exclude_if_present: Vec::new(),
+            exclusions: None,
+            ntfs_metadata: false,
+            statx_metadata: false,
+            prefetch: Prefetch::new().map(|p| Arc::new(Mutex::new(p))),
+            source_helper: None,
+            trusted_basis: None,
+            dereference: false,
+            max_depth: None,
         })
     }
 
     /// Return a new LiveTree which when listed will ignore certain files.
     ///
     /// This replaces any previous exclusions.
+    ///
+    /// Exclusions match apaths relative to the source directory, even if it's
+    /// stored with `with_source_dir_name`.
     pub fn with_excludes(self, excludes: GlobSet) -> LiveTree {
         LiveTree { excludes, ..self }
     }
 
-    fn relative_path(&self, apath: &Apath) -> PathBuf {
-        relative_path(&self.path, apath)
+    /// Return a new LiveTree in which the source directory itself is a
+    /// directory called `name` in the root, like `rsync` copying a source
+    /// without a trailing slash.
+    pub fn with_source_dir_name(self, name: &str) -> LiveTree {
+        assert!(
+            Apath::is_valid(&format!("/{}", name)) && !name.contains('/'),
+            "invalid source dir name {:?}",
+            name
+        );
+        LiveTree {
+            source_dir_name: Some(name.to_owned()),
+            ..self
+        }
+    }
+
+    /// Return a new LiveTree which includes Conserve archives found within it.
+    ///
+    /// By default they're skipped with a warning, since backing up an archive
+    /// into itself would grow without limit, and backing up another archive
+    /// is rarely wanted.
+    pub fn with_archives_included(self, include_archives: bool) -> LiveTree {
+        LiveTree {
+            include_archives,
+            ..self
+        }
+    }
+
+    /// Return a new LiveTree which skips any directory containing a file or
+    /// directory with one of these names, such as `.nobackup`.
+    ///
+    /// This replaces any previous marker names.
+    pub fn with_exclude_if_present<I: IntoIterator<Item = S>, S: AsRef<str>>(
+        self,
+        names: I,
+    ) -> LiveTree {
+        LiveTree {
+            exclude_if_present: names.into_iter().map(|n| n.as_ref().to_owned()).collect(),
+            ..self
+        }
+    }
+
+    /// Return a new LiveTree which reads the NTFS security descriptor and
+    /// named streams of files and directories, on Windows.
+    pub fn with_ntfs_metadata(self, ntfs_metadata: bool) -> LiveTree {
+        LiveTree {
+            ntfs_metadata,
+            ..self
+        }
+    }
+
+    /// Return a new LiveTree which keeps the birth time, mount ID, and
+    /// attributes of entries, from `statx`, on Linux.
+    pub fn with_statx_metadata(self, statx_metadata: bool) -> LiveTree {
+        LiveTree {
+            statx_metadata,
+            ..self
+        }
+    }
+
+    /// Return a new LiveTree which reads directories and files it isn't
+    /// permitted to read through a helper with more privileges, rather than
+    /// skipping them.
+    pub fn with_source_helper(self, source_helper: Option<SourceHelper>) -> LiveTree {
+        LiveTree {
+            source_helper,
+            ..self
+        }
+    }
+
+    /// Return a new LiveTree which follows symlinks, storing the files and
+    /// directories they point to in place of the symlinks.
+    ///
+    /// A symlink to a directory that's already been visited, such as one
+    /// pointing to its own parent, is skipped and reported as a
+    /// `SymlinkLoop` problem, rather than being followed forever. Symlinks
+    /// whose target doesn't exist are stored as symlinks.
+    pub fn with_dereference(self, dereference: bool) -> LiveTree {
+        LiveTree {
+            dereference,
+            ..self
+        }
+    }
+
+    /// Return a new LiveTree which doesn't descend more than `max_depth`
+    /// directories below the source directory. With a depth of 1, only the
+    /// entries in the source directory itself are returned.
+    pub fn with_max_depth(self, max_depth: Option<usize>) -> LiveTree {
+        LiveTree { max_depth, ..self }
+    }
+
+    /// Return a new LiveTree which trusts that files are unchanged from the
+    /// last complete version in `archive` if their directory's mtime, and the
+    /// names of the files and symlinks in it, are unchanged.
+    ///
+    /// The metadata of files and symlinks in those directories is taken from
+    /// the archive without reading it from the source, which is much faster
+    /// for trees where files are only ever added. But a file whose content is
+    /// rewritten in place doesn't change its directory's mtime, so the
+    /// change will be missed: only use this where files are never modified.
+    ///
+    /// Subdirectories are still read, and checked in the same way.
+    pub fn with_trusted_dir_mtimes(self, archive: &Archive) -> Result<LiveTree> {
+        let trusted_basis = if archive.is_deterministic() {
+            None
+        } else {
+            archive
+                .last_complete_band()?
+                .map(|band| StoredTree::new(archive, band).map(Arc::new))
+                .transpose()?
+        };
+        Ok(LiveTree {
+            trusted_basis,
+            ..self
+        })
+    }
+
+    /// Return a new LiveTree that remembers the entries skipped while
+    /// iterating it, to be returned by `take_exclusions`.
+    ///
+    /// The contents of skipped directories aren't read, so only the top
+    /// excluded directory is recorded. Skipped archives are recorded rather
+    /// than warned about.
+    pub fn with_exclusions_recorded(self) -> LiveTree {
+        LiveTree {
+            exclusions: Some(Arc::default()),
+            ..self
+        }
+    }
+
+    /// Return and forget the entries skipped so far, in apath order.
+    ///
+    /// This is empty unless the tree was made with `with_exclusions_recorded`.
+    pub fn take_exclusions(&self) -> Vec<Exclusion> {
+        let mut exclusions = match &self.exclusions {
+            Some(exclusions) => std::mem::take(&mut *exclusions.lock().unwrap()),
+            None => Vec::new(),
+        };
+        exclusions.sort_unstable_by(|a, b| a.apath.cmp(&b.apath));
+        exclusions
+    }
+
+    /// Return the path of the source directory.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The path of an entry, or None if the tree has a source directory name
+    /// and the apath isn't within it.
+    pub(crate) fn relative_path(&self, apath: &Apath) -> Option<PathBuf> {
+        match &self.source_dir_name {
+            None => Some(relative_path(&self.path, apath)),
+            Some(name) => apath
+                .strip_prefix('/')
+                .and_then(|rest| rest.strip_prefix(name.as_str()))
+                .filter(|rest| rest.is_empty() || rest.starts_with('/'))
+                .map(|rest| apath_path(&self.path, rest)),
+        }
     }
 }
 
 /// An in-memory Entry describing a file/dir/symlink in a live tree.
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub struct LiveEntry {
     apath: Apath,
     kind: Kind,
     mtime: UnixTime,
     size: Option<u64>,
     symlink_target: Option<String>,
+    ntfs: Option<NtfsMetadata>,
+    statx: Option<StatxMetadata>,
+    inode: Option<u64>,
 }
 
-fn relative_path(root: &PathBuf, apath: &Apath) -> PathBuf {
-    let mut path = root.clone();
-    path.push(&apath[1..]);
-    path
+fn relative_path(root: &Path, apath: &Apath) -> PathBuf {
+    apath_path(root, apath)
 }
 
 impl tree::ReadTree for LiveTree {
     type Entry = LiveEntry;
     type I = Iter;
-    type R = std::fs::File;
+    type R = LiveFile;
 
     /// Iterate source files descending through a source directory.
     ///
@@ -73,20 +314,126 @@ impl tree::ReadTree for LiveTree {
     /// child directories, visit them according to a sorted comparison by their UTF-8
     /// name.
     fn iter_entries(&self) -> Result<Self::I> {
-        Iter::new(&self.path, &self.excludes)
+        let mut iter = Iter::new(
+            &self.path,
+            &self.excludes,
+            self.source_dir_name.as_deref(),
+            self.include_archives,
+            &self.exclude_if_present,
+            self.exclusions.clone(),
+        )?;
+        if self.ntfs_metadata {
+            iter.enable_ntfs_metadata();
+        }
+        if self.statx_metadata {
+            iter.enable_statx_metadata();
+        }
+        if self.dereference {
+            iter.enable_dereference();
+        }
+        iter.max_depth = self.max_depth;
+        iter.prefetch = self.prefetch.clone();
+        iter.source_helper = self.source_helper.clone();
+        if let Some(basis) = &self.trusted_basis {
+            iter.basis = Some(basis.iter_entries()?.peekable());
+        }
+        Ok(iter)
     }
 
     fn file_contents(&self, entry: &LiveEntry) -> Result<Self::R> {
         assert_eq!(entry.kind(), Kind::File);
-        let path = self.relative_path(&entry.apath);
-        fs::File::open(&path).context(errors::ReadSourceFile { path })
+        let path = self
+            .relative_path(&entry.apath)
+            .ok_or_else(|| Error::ReadSourceFile {
+                path: relative_path(&self.path, &entry.apath),
+                source: ErrorKind::NotFound.into(),
+            })?;
+        // Injected faults apply whether or not the file was prefetched.
+        crate::faults::before_read(&path).context(errors::ReadSourceFile { path: &path })?;
+        if let Some(max_len) = crate::faults::short_read_limit(&path) {
+            return open_source_file(&path)
+                .map(|file| LiveFile::Short(file, max_len))
+                .context(errors::ReadSourceFile { path });
+        }
+        if let Some(prefetch) = &self.prefetch {
+            if let Some(content) = prefetch.lock().unwrap().take(&entry.apath) {
+                return Ok(LiveFile::Read(io::Cursor::new(content)));
+            }
+        }
+        match (open_source_file(&path), &self.source_helper) {
+            (Err(e), Some(helper)) if e.kind() == ErrorKind::PermissionDenied => helper
+                .read_file(&path)
+                .map(LiveFile::Helper)
+                .context(errors::ReadSourceFile { path }),
+            (file, _) => file
+                .map(LiveFile::Open)
+                .context(errors::ReadSourceFile { path }),
+        }
     }
 
     fn estimate_count(&self) -> Result<u64> {
         // TODO: This stats the file and builds an entry about them, just to
         // throw it away. We could perhaps change the iter to optionally do
         // less work.
-        Ok(self.iter_entries()?.count() as u64)
+        let mut iter = self.iter_entries()?.quiet();
+        iter.prefetch = None;
+        Ok(iter.count() as u64)
+    }
+
+    fn take_problems(&self, entries: &mut Iter) -> Problems {
+        std::mem::take(&mut *entries.problems.lock().unwrap()).into()
+    }
+
+    fn size(&self) -> Result<TreeSize> {
+        // Problems are reported when the tree is copied, not while it's
+        // measured beforehand.
+        Ok(tree::measure_entries(self.iter_entries()?.quiet()))
+    }
+}
+
+/// The contents of a source file, either open, already read, or being read
+/// by the source helper.
+#[derive(Debug)]
+pub enum LiveFile {
+    Open(fs::File),
+    Read(io::Cursor<Vec<u8>>),
+    Helper(HelperFile),
+    /// Open, but each read returns at most this many bytes, as injected by
+    /// a `faults::FaultPlan`.
+    Short(fs::File, usize),
+}
+
+impl Read for LiveFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            LiveFile::Open(file) => {
+                let len = file.read(buf)?;
+                if len == 0 && !buf.is_empty() {
+                    drop_from_cache(file);
+                }
+                check_not_abandoned(len)
+            }
+            LiveFile::Read(cursor) => cursor.read(buf),
+            LiveFile::Helper(file) => check_not_abandoned(file.read(buf)?),
+            LiveFile::Short(file, max_len) => {
+                let len = buf.len().min(*max_len);
+                check_not_abandoned(file.read(&mut buf[..len])?)
+            }
+        }
+    }
+}
+
+/// Fail a read if the stall watchdog gave up on this file while it was
+/// being read, or otherwise note that it's making progress.
+fn check_not_abandoned(len: usize) -> io::Result<usize> {
+    if ui::should_abandon_entry() {
+        Err(io::Error::new(
+            ErrorKind::TimedOut,
+            "abandoned after making no progress",
+        ))
+    } else {
+        ui::heartbeat();
+        Ok(len)
     }
 }
 
@@ -118,39 +465,38 @@ impl Entry for LiveEntry {
     fn symlink_target(&self) -> &Option<String> {
         &self.symlink_target
     }
+
+    fn ntfs_metadata(&self) -> Option<&NtfsMetadata> {
+        self.ntfs.as_ref()
+    }
+
+    fn statx_metadata(&self) -> Option<&StatxMetadata> {
+        self.statx.as_ref()
+    }
+
+    fn inode(&self) -> Option<u64> {
+        self.inode
+    }
 }
 
 impl LiveEntry {
-    fn from_fs_metadata(
+    /// Make an entry from source metadata, dropping any `statx` metadata,
+    /// which is only kept if the iterator asks for it.
+    fn from_source_metadata(
         apath: Apath,
-        metadata: &fs::Metadata,
+        metadata: &SourceMetadata,
         symlink_target: Option<String>,
     ) -> LiveEntry {
         // TODO: Could we read the symlink target here, rather than in the caller?
-        let kind = if metadata.is_file() {
-            Kind::File
-        } else if metadata.is_dir() {
-            Kind::Dir
-        } else if metadata.file_type().is_symlink() {
-            Kind::Symlink
-        } else {
-            Kind::Unknown
-        };
-        let mtime = metadata
-            .modified()
-            .expect("Failed to get file mtime")
-            .into();
-        let size = if metadata.is_file() {
-            Some(metadata.len())
-        } else {
-            None
-        };
         LiveEntry {
             apath,
-            kind,
-            mtime,
+            kind: metadata.kind,
+            mtime: metadata.mtime,
             symlink_target,
-            size,
+            size: metadata.size,
+            ntfs: None,
+            statx: None,
+            inode: metadata.inode,
         }
     }
 }
@@ -174,14 +520,84 @@ pub struct Iter {
     /// glob pattern to skip in iterator
     excludes: GlobSet,
 
+    /// If set, returned apaths are moved into a top-level directory of this name.
+    source_dir_name: Option<String>,
+
+    /// A synthetic root entry to return first, when `source_dir_name` is set.
+    synthetic_root: Option<LiveEntry>,
+
+    /// If false, skip directories holding Conserve archives.
+    include_archives: bool,
+
+    /// Skip directories containing a file with any of these names.
+    exclude_if_present: Vec<String>,
+
+    /// Problems found by this iterator, not yet taken.
+    problems: Mutex<Vec<Problem>>,
+
+    /// If true, problems are remembered but not reported as they're found.
+    quiet: bool,
+
+    /// If set, skipped entries are recorded here, shared with the LiveTree.
+    exclusions: Option<Arc<Mutex<Vec<Exclusion>>>>,
+
+    /// If true, read NTFS metadata of files and directories.
+    ntfs_metadata: bool,
+
+    /// If true, keep `statx` metadata of entries.
+    statx_metadata: bool,
+
+    /// If set, small files of each directory are registered here to be read
+    /// in batches.
+    prefetch: Option<Arc<Mutex<Prefetch>>>,
+
+    /// If set, directories that can't be listed for lack of permission are
+    /// listed through this helper.
+    source_helper: Option<SourceHelper>,
+
+    /// If set, entries of the trusted basis tree not yet reached, in step
+    /// with the directories being visited.
+    basis: Option<Peekable<IndexEntryIter>>,
+
+    /// The mtimes of basis directories that are yet to be visited.
+    basis_dir_mtimes: BTreeMap<Apath, UnixTime>,
+
+    /// If true, follow symlinks.
+    dereference: bool,
+
+    /// The device and inode of every directory found so far, when following
+    /// symlinks, so that symlinks back to them aren't followed again.
+    visited_dirs: HashSet<(u64, u64)>,
+
+    /// If set, directories at this depth aren't visited.
+    max_depth: Option<usize>,
+
+    /// Spill the children of a directory past this many bytes.
+    directory_budget: Option<u64>,
+
+    /// Children of a directory too big to sort in memory, still to be
+    /// returned, and the path of the directory.
+    spilled: Option<(PathBuf, Merge<LiveEntry>)>,
+
+    /// Subdirectories read back from `spilled`, to be visited once it's
+    /// finished.
+    spilled_dirs: Vec<Apath>,
+
     stats: LiveTreeIterStats,
 }
 
 impl Iter {
     /// Construct a new iter that will visit everything below this root path,
     /// subject to some exclusions
-    fn new(root_path: &Path, excludes: &GlobSet) -> Result<Iter> {
-        let root_metadata = fs::symlink_metadata(&root_path)
+    fn new(
+        root_path: &Path,
+        excludes: &GlobSet,
+        source_dir_name: Option<&str>,
+        include_archives: bool,
+        exclude_if_present: &[String],
+        exclusions: Option<Arc<Mutex<Vec<Exclusion>>>>,
+    ) -> Result<Iter> {
+        let root_metadata = statx::read(root_path)
             .with_context(|| errors::ListSourceTree {
                 path: root_path.to_path_buf(),
             })
@@ -191,7 +607,7 @@ impl Iter {
             })?;
         // Preload iter to return the root and then recurse into it.
         let mut entry_deque = VecDeque::<LiveEntry>::new();
-        entry_deque.push_back(LiveEntry::from_fs_metadata(
+        entry_deque.push_back(LiveEntry::from_source_metadata(
             Apath::from("/"),
             &root_metadata,
             None,
@@ -200,14 +616,155 @@ impl Iter {
         // Should that be supported?
         let mut dir_deque = VecDeque::<Apath>::new();
         dir_deque.push_back("/".into());
-        Ok(Iter {
+        let synthetic_root = source_dir_name
+            .map(|_| LiveEntry::from_source_metadata(Apath::from("/"), &root_metadata, None));
+        let root_is_archive = !include_archives && archive::is_archive_dir(root_path);
+        if root_is_archive {
+            // Return only the root, as for an archive inside the tree.
+            dir_deque.clear();
+            if exclusions.is_none() {
+                warn!(
+                    "Skipping Conserve archive {:?}, which is the source",
+                    root_path
+                );
+            }
+        }
+        let mut iter = Iter {
             root_path: root_path.to_path_buf(),
             entry_deque,
             dir_deque,
             check_order: apath::CheckOrder::new(),
             excludes: excludes.clone(),
+            source_dir_name: source_dir_name.map(str::to_owned),
+            synthetic_root,
+            include_archives,
+            exclude_if_present: exclude_if_present.to_vec(),
+            problems: Mutex::default(),
+            quiet: false,
+            exclusions,
+            ntfs_metadata: false,
+            statx_metadata: false,
+            prefetch: None,
+            source_helper: None,
+            basis: None,
+            basis_dir_mtimes: BTreeMap::new(),
+            dereference: false,
+            visited_dirs: HashSet::new(),
+            max_depth: None,
+            directory_budget: spill::directory_budget(),
+            spilled: None,
+            spilled_dirs: Vec::new(),
             stats: LiveTreeIterStats::default(),
-        })
+        };
+        if root_is_archive {
+            iter.stats.exclusions += 1;
+            iter.exclusion("/", ExclusionReason::Archive);
+        }
+        Ok(iter)
+    }
+
+    /// Read NTFS metadata of every file and directory, starting with the
+    /// root.
+    fn enable_ntfs_metadata(&mut self) {
+        if !ntfs::SUPPORTED {
+            warn!("NTFS metadata can only be captured on Windows");
+            return;
+        }
+        self.ntfs_metadata = true;
+        let ntfs = self.read_ntfs_metadata(&self.root_path, "/");
+        if let Some(root) = self.entry_deque.front_mut() {
+            root.ntfs = ntfs.clone();
+        }
+        if let Some(root) = &mut self.synthetic_root {
+            root.ntfs = ntfs;
+        }
+    }
+
+    /// Keep `statx` metadata of every entry, starting with the root.
+    fn enable_statx_metadata(&mut self) {
+        if !statx::SUPPORTED {
+            warn!("statx metadata can only be captured on Linux");
+            return;
+        }
+        self.statx_metadata = true;
+        let statx = statx::read(&self.root_path)
+            .ok()
+            .and_then(|metadata| metadata.statx);
+        if let Some(root) = self.entry_deque.front_mut() {
+            root.statx = statx.clone();
+        }
+        if let Some(root) = &mut self.synthetic_root {
+            root.statx = statx;
+        }
+    }
+
+    /// Follow symlinks, starting from the root.
+    fn enable_dereference(&mut self) {
+        self.dereference = true;
+        self.visited_dirs.extend(dir_id(&self.root_path));
+    }
+
+    /// Read NTFS metadata, reporting any problems.
+    fn read_ntfs_metadata(&self, path: &Path, apath: &str) -> Option<NtfsMetadata> {
+        match ntfs::read(path) {
+            Ok((metadata, too_large)) => {
+                for name in too_large {
+                    self.problem(Problem::NtfsMetadata {
+                        apath: apath.to_owned(),
+                        message: format!(
+                            "stream {:?} is larger than {} bytes, so isn't stored",
+                            name,
+                            ntfs::MAX_STREAM_SIZE
+                        ),
+                    });
+                }
+                Some(metadata)
+            }
+            Err(e) => {
+                self.problem(Problem::NtfsMetadata {
+                    apath: apath.to_owned(),
+                    message: e.to_string(),
+                });
+                None
+            }
+        }
+    }
+
+    /// Remember a problem, and report it unless this iterator is quiet.
+    fn problem(&self, problem: Problem) {
+        let problem = if self.quiet { problem } else { problem.emit() };
+        self.problems.lock().unwrap().push(problem);
+    }
+
+    /// Don't report problems as they're found, for example because the tree
+    /// is only being scanned, and they'll be reported when it's copied.
+    pub(crate) fn quiet(self) -> Iter {
+        Iter {
+            quiet: true,
+            ..self
+        }
+    }
+
+    /// Remember a skipped entry, if exclusions are being recorded.
+    fn exclusion(&self, apath: &str, reason: ExclusionReason) {
+        if let Some(exclusions) = &self.exclusions {
+            exclusions.lock().unwrap().push(Exclusion {
+                apath: apath.into(),
+                reason,
+            });
+        }
+    }
+
+    /// Move an entry into the source dir, if one is set.
+    fn rename_into_source_dir(&self, mut entry: LiveEntry) -> LiveEntry {
+        if let Some(name) = &self.source_dir_name {
+            entry.apath = if entry.apath == "/" {
+                Apath::from(format!("/{}", name))
+            } else {
+                Apath::from(format!("/{}{}", name, entry.apath))
+            };
+        }
+        entry
     }
 
     /// Visit the next directory.
@@ -221,29 +778,47 @@ impl Iter {
         // TODO: Perhaps reuse the child buffer in the Iter, which we know will
         // now be empty? We have to be able to sort it, but perhaps a Vec in
         // reverse order from which we pop would work well.
+        let _span = debug_span!("visit_dir", apath = %parent_apath).entered();
         self.stats.directories_visited += 1;
-        let mut children = Vec::<(String, LiveEntry)>::new();
+        let mut children = SortBuffer::new(self.directory_budget);
         let dir_path = relative_path(&self.root_path, parent_apath);
-        let dir_iter = match fs::read_dir(&dir_path).with_context(|| errors::ListSourceTree {
-            path: dir_path.clone(),
-        }) {
+        let dir_iter = match fs::read_dir(&dir_path) {
             Ok(i) => i,
-            Err(e) => {
-                ui::problem(&format!("Error reading directory {:?}: {}", &dir_path, e));
+            Err(e) if e.kind() == ErrorKind::PermissionDenied => {
+                if self.source_helper.is_some() {
+                    self.visit_directory_through_helper(parent_apath, dir_path);
+                } else {
+                    self.problem(Problem::PermissionDenied {
+                        apath: parent_apath.to_string(),
+                        entry_kind: Kind::Dir,
+                    });
+                }
+                return;
+            }
+            Err(source) => {
+                let e = Error::ListSourceTree {
+                    path: dir_path.clone(),
+                    source,
+                };
+                self.problem(Problem::ListDirectory {
+                    path: dir_path,
+                    message: ui::format_error(&e),
+                });
                 return;
             }
         };
+        let mut dir_entries = Vec::new();
         for dir_entry in dir_iter {
-            let dir_entry = match dir_entry {
-                Ok(dir_entry) => dir_entry,
-                Err(e) => {
-                    ui::problem(&format!(
-                        "Error reading next entry from directory {:?}: {}",
-                        &dir_path, e
-                    ));
-                    continue;
-                }
-            };
+            match dir_entry {
+                Ok(dir_entry) => dir_entries.push(dir_entry),
+                Err(e) => self.problem(Problem::ListDirectory {
+                    path: dir_path.clone(),
+                    message: e.to_string(),
+                }),
+            }
+        }
+        let mut unchanged = self.unchanged_children(parent_apath, &dir_path, &dir_entries);
+        for dir_entry in dir_entries {
             let mut child_apath_str = parent_apath.to_string();
             // TODO: Specific Apath join method?
             if child_apath_str != "/" {
@@ -253,10 +828,10 @@ impl Iter {
             let child_name = match child_osstr.to_str() {
                 Some(c) => c,
                 None => {
-                    ui::problem(&format!(
-                        "Can't decode filename {:?} in {:?}",
-                        child_osstr, dir_path,
-                    ));
+                    self.problem(Problem::UndecodableName {
+                        dir: dir_path.clone(),
+                        name: child_osstr.to_string_lossy().into_owned(),
+                    });
                     continue;
                 }
             };
@@ -264,35 +839,102 @@ impl Iter {
             let ft = match dir_entry.file_type() {
                 Ok(ft) => ft,
                 Err(e) => {
-                    ui::problem(&format!(
-                        "Error getting type of {:?} during iteration: {}",
-                        child_apath_str, e
-                    ));
+                    self.problem(Problem::MetadataError {
+                        apath: child_apath_str,
+                        message: e.to_string(),
+                    });
                     continue;
                 }
             };
 
-            if self.excludes.is_match(&child_apath_str) {
+            if let Some(&pattern) = self.excludes.matches(&child_apath_str).first() {
+                self.stats.exclusions += 1;
+                self.exclusion(&child_apath_str, ExclusionReason::Pattern(pattern));
+                continue;
+            }
+            if let Some(entry) = unchanged.as_mut().and_then(|u| u.remove(child_name)) {
+                self.buffer_child(&mut children, &dir_path, child_name.to_string(), entry);
+                continue;
+            }
+            // The metadata of what a symlink points to, if it's followed and
+            // the target exists.
+            let followed = if self.dereference && ft.is_symlink() {
+                fs::metadata(dir_path.join(child_name))
+                    .ok()
+                    .map(|m| SourceMetadata::from(&m))
+            } else {
+                None
+            };
+            let is_dir = ft.is_dir() || followed.as_ref().map(|m| m.kind) == Some(Kind::Dir);
+            if is_dir && self.dereference {
+                if let Some(id) = dir_id(&dir_path.join(child_name)) {
+                    if !self.visited_dirs.insert(id) && !ft.is_dir() {
+                        self.stats.symlink_loops += 1;
+                        self.problem(Problem::SymlinkLoop {
+                            apath: child_apath_str,
+                        });
+                        continue;
+                    }
+                }
+            }
+            if is_dir
+                && !self.include_archives
+                && archive::is_archive_dir(&dir_path.join(child_name))
+            {
+                if self.exclusions.is_none() {
+                    warn!(
+                        "Skipping Conserve archive {:?} inside the source tree",
+                        dir_path.join(child_name)
+                    );
+                }
                 self.stats.exclusions += 1;
+                self.exclusion(&child_apath_str, ExclusionReason::Archive);
                 continue;
             }
-            let metadata = match dir_entry.metadata() {
+            if is_dir {
+                let child_path = dir_path.join(child_name);
+                if let Some(marker) = self
+                    .exclude_if_present
+                    .iter()
+                    .position(|name| fs::symlink_metadata(child_path.join(name)).is_ok())
+                {
+                    self.stats.exclusions += 1;
+                    self.exclusion(&child_apath_str, ExclusionReason::Marker(marker));
+                    continue;
+                }
+            }
+            let metadata = match followed {
+                Some(metadata) => Ok(metadata),
+                None => statx::read(&dir_path.join(child_name)),
+            };
+            let metadata = match metadata {
                 Ok(metadata) => metadata,
                 Err(e) => {
                     match e.kind() {
                         ErrorKind::NotFound => {
                             // Fairly harmless, and maybe not even worth logging. Just a race
                             // between listing the directory and looking at the contents.
-                            ui::problem(&format!(
-                                "File disappeared during iteration: {:?}: {}",
-                                child_apath_str, e
-                            ));
+                            self.problem(Problem::FileDisappeared {
+                                apath: child_apath_str,
+                            });
+                        }
+                        ErrorKind::PermissionDenied => {
+                            self.problem(Problem::PermissionDenied {
+                                apath: child_apath_str,
+                                entry_kind: if ft.is_dir() {
+                                    Kind::Dir
+                                } else if ft.is_symlink() {
+                                    Kind::Symlink
+                                } else {
+                                    Kind::File
+                                },
+                            });
                         }
                         _ => {
-                            ui::problem(&format!(
-                                "Failed to read source metadata from {:?}: {}",
-                                child_apath_str, e
-                            ));
+                            self.problem(Problem::MetadataError {
+                                apath: child_apath_str,
+                                message: e.to_string(),
+                            });
                             self.stats.metadata_error += 1;
                         }
                     };
@@ -300,38 +942,225 @@ impl Iter {
                 }
             };
 
-            // TODO: Move this into LiveEntry::from_fs_metadata, once there's a
+            // TODO: Move this into LiveEntry::from_source_metadata, once there's a
             // global way for it to complain about errors.
-            let target: Option<String> = if ft.is_symlink() {
+            let target: Option<String> = if metadata.kind == Kind::Symlink {
                 let t = match dir_path.join(dir_entry.file_name()).read_link() {
                     Ok(t) => t,
                     Err(e) => {
-                        ui::problem(&format!(
-                            "Failed to read target of symlink {:?}: {}",
-                            child_apath_str, e
-                        ));
+                        self.problem(Problem::UnreadableSymlink {
+                            apath: child_apath_str,
+                            message: e.to_string(),
+                        });
                         continue;
                     }
                 };
                 match t.into_os_string().into_string() {
                     Ok(t) => Some(t),
                     Err(e) => {
-                        ui::problem(&format!(
-                            "Failed to decode target of symlink {:?}: {:?}",
-                            child_apath_str, e
-                        ));
+                        self.problem(Problem::UnreadableSymlink {
+                            apath: child_apath_str,
+                            message: format!("can't decode {:?}", e),
+                        });
                         continue;
                     }
                 }
             } else {
                 None
             };
-            children.push((
-                child_name.to_string(),
-                LiveEntry::from_fs_metadata(child_apath_str.into(), &metadata, target),
-            ));
+            let mut entry =
+                LiveEntry::from_source_metadata(child_apath_str.into(), &metadata, target);
+            if self.statx_metadata {
+                entry.statx = metadata.statx;
+            }
+            if self.ntfs_metadata && (ft.is_file() || ft.is_dir()) {
+                entry.ntfs = self.read_ntfs_metadata(&dir_path.join(child_name), &entry.apath);
+            }
+            self.buffer_child(&mut children, &dir_path, child_name.to_string(), entry);
+        }
+        self.add_children(&dir_path, children);
+    }
+
+    /// If the directory is unchanged from the trusted basis, return entries
+    /// for its files and symlinks, taken from the basis and keyed by name.
+    ///
+    /// The directory is unchanged if its mtime is the same, and it has files
+    /// and symlinks of the same names, not counting excluded names.
+    ///
+    /// Basis entries up to the end of the directory's own children are
+    /// consumed, whether or not it's unchanged.
+    fn unchanged_children(
+        &mut self,
+        parent_apath: &Apath,
+        dir_path: &Path,
+        dir_entries: &[fs::DirEntry],
+    ) -> Option<HashMap<String, LiveEntry>> {
+        let basis = self.basis.as_mut()?;
+        let mut basis_children = HashMap::new();
+        while let Some(next) = basis.peek() {
+            let apath = match basis_apath(&next.apath, self.source_dir_name.as_deref()) {
+                Some(apath) => apath,
+                None => {
+                    basis.next();
+                    continue;
+                }
+            };
+            let is_child = match apath.cmp_to_contents_of(parent_apath) {
+                Ordering::Less => false,
+                Ordering::Equal if is_child_of(&apath, parent_apath) => true,
+                _ => break,
+            };
+            let entry = basis.next().expect("peeked entry is present");
+            if entry.kind == Kind::Dir {
+                self.basis_dir_mtimes.insert(apath.clone(), entry.mtime());
+            } else if is_child {
+                let name = apath[apath.rfind('/').unwrap() + 1..].to_owned();
+                basis_children.insert(name, (apath, entry));
+            }
+        }
+        let basis_mtime = self.basis_dir_mtimes.remove(parent_apath)?;
+        // Read the mtime after listing the directory, so that any change
+        // made while it's listed shows as a changed mtime.
+        if statx::read(dir_path).ok()?.mtime != basis_mtime {
+            return None;
+        }
+        let mut names = 0;
+        for dir_entry in dir_entries {
+            let ft = dir_entry.file_type().ok()?;
+            if ft.is_dir() || !(ft.is_file() || ft.is_symlink()) {
+                continue;
+            }
+            let name = dir_entry.file_name().into_string().ok()?;
+            let child_apath = if *parent_apath == "/" {
+                format!("/{}", name)
+            } else {
+                format!("{}/{}", parent_apath, name)
+            };
+            if !self.excludes.matches(&child_apath).is_empty() {
+                continue;
+            }
+            match basis_children.get(&name) {
+                Some((_, entry)) if (entry.kind == Kind::Symlink) == ft.is_symlink() => names += 1,
+                _ => return None,
+            }
+        }
+        if names != basis_children.len() {
+            return None;
+        }
+        self.stats.unchanged_dirs += 1;
+        Some(
+            basis_children
+                .into_iter()
+                .map(|(name, (apath, index_entry))| {
+                    let mut entry = LiveEntry {
+                        apath,
+                        kind: index_entry.kind,
+                        mtime: index_entry.mtime(),
+                        size: None,
+                        symlink_target: index_entry.target.clone(),
+                        ntfs: None,
+                        statx: None,
+                        inode: None,
+                    };
+                    if entry.kind == Kind::File {
+                        entry.size = index_entry.size();
+                    }
+                    if self.ntfs_metadata {
+                        entry.ntfs = index_entry.ntfs;
+                    }
+                    if self.statx_metadata {
+                        entry.statx = index_entry.statx;
+                    }
+                    (name, entry)
+                })
+                .collect(),
+        )
+    }
+
+    /// List a directory through the source helper, after it couldn't be read
+    /// directly.
+    ///
+    /// Only exclude patterns are applied to the children, since checking for
+    /// archives or marker files would need to read the directory again.
+    fn visit_directory_through_helper(&mut self, parent_apath: &Apath, dir_path: PathBuf) {
+        let helper = self.source_helper.as_ref().expect("source helper is set");
+        let helper_entries = match helper.list_dir(&dir_path) {
+            Ok(entries) => entries,
+            Err(e) => {
+                self.problem(Problem::PermissionDenied {
+                    apath: parent_apath.to_string(),
+                    entry_kind: Kind::Dir,
+                });
+                debug!("Source helper failed to list {:?}: {}", dir_path, e);
+                return;
+            }
+        };
+        let mut children = SortBuffer::new(self.directory_budget);
+        for helper_entry in helper_entries {
+            let child_apath_str = if *parent_apath == "/" {
+                format!("/{}", helper_entry.name)
+            } else {
+                format!("{}/{}", parent_apath, helper_entry.name)
+            };
+            if let Some(&pattern) = self.excludes.matches(&child_apath_str).first() {
+                self.stats.exclusions += 1;
+                self.exclusion(&child_apath_str, ExclusionReason::Pattern(pattern));
+                continue;
+            }
+            let metadata = SourceMetadata {
+                kind: helper_entry.kind,
+                mtime: helper_entry.mtime(),
+                size: helper_entry.size,
+                statx: None,
+                inode: None,
+            };
+            let entry = LiveEntry::from_source_metadata(
+                child_apath_str.into(),
+                &metadata,
+                helper_entry.target,
+            );
+            self.buffer_child(&mut children, &dir_path, helper_entry.name, entry);
+        }
+        self.add_children(&dir_path, children);
+    }
+
+    /// Queue the children of a directory to be returned, and its
+    /// subdirectories to be visited.
+    ///
+    /// Children of a directory that was spilled to disk are read back as
+    /// they're returned, and aren't prefetched.
+    fn add_children(&mut self, dir_path: &Path, children: SortBuffer<LiveEntry>) {
+        let children = match children.into_sorted() {
+            Ok(Sorted::Memory(children)) => children,
+            Ok(Sorted::Spilled(merge)) => {
+                self.stats.spilled_dirs += 1;
+                self.spilled = Some((dir_path.to_owned(), merge));
+                return;
+            }
+            Err(e) => {
+                self.problem(Problem::ListDirectory {
+                    path: dir_path.to_owned(),
+                    message: ui::format_error(&e),
+                });
+                return;
+            }
+        };
+        if let Some(prefetch) = &self.prefetch {
+            let small_files = children
+                .iter()
+                .filter(|(_, entry)| {
+                    entry.kind == Kind::File && entry.size.unwrap_or(0) <= uring::MAX_PREFETCH_SIZE
+                })
+                .map(|(name, entry)| {
+                    (
+                        self.rename_into_source_dir(entry.clone()).apath,
+                        dir_path.join(name),
+                        entry.size.unwrap_or(0),
+                    )
+                })
+                .collect();
+            prefetch.lock().unwrap().start_directory(small_files);
         }
-        children.sort_unstable_by(|a, b| a.0.cmp(&b.0));
         // To get the right overall tree ordering, any new subdirectories
         // discovered here should be visited together in apath order, but before
         // any previously pending directories. In other words, in reverse order
@@ -342,6 +1171,86 @@ impl Iter {
         self.entry_deque.reserve(children.len());
         self.entry_deque.extend(children.into_iter().map(|x| x.1));
     }
+
+    /// Add a child of the directory being visited, to be sorted with its
+    /// siblings.
+    fn buffer_child(
+        &mut self,
+        children: &mut SortBuffer<LiveEntry>,
+        dir_path: &Path,
+        name: String,
+        entry: LiveEntry,
+    ) {
+        // Roughly the memory used by the buffered entry.
+        let size = std::mem::size_of::<(String, LiveEntry)>()
+            + name.len()
+            + entry.apath.len()
+            + entry.symlink_target.as_ref().map_or(0, String::len);
+        if let Err(e) = children.push(name, entry, size as u64) {
+            self.problem(Problem::ListDirectory {
+                path: dir_path.to_owned(),
+                message: ui::format_error(&e),
+            });
+        }
+    }
+
+    /// Queue the next child of a spilled directory, or once they're all
+    /// returned, its subdirectories.
+    fn refill_from_spill(&mut self) {
+        let (dir_path, merge) = self.spilled.as_mut().expect("spilled directory is set");
+        match merge.next() {
+            Some(Ok((_name, entry))) => {
+                if entry.kind == Kind::Dir {
+                    self.spilled_dirs.push(entry.apath.clone());
+                }
+                self.entry_deque.push_back(entry);
+            }
+            Some(Err(e)) => {
+                let path = dir_path.clone();
+                self.problem(Problem::ListDirectory {
+                    path,
+                    message: ui::format_error(&e),
+                });
+            }
+            None => {
+                self.spilled = None;
+                for apath in self.spilled_dirs.drain(..).rev() {
+                    self.dir_deque.push_front(apath);
+                }
+            }
+        }
+    }
+}
+
+/// The device and inode of a directory, following symlinks, to recognize it
+/// when it's reached again through a symlink.
+#[cfg(unix)]
+fn dir_id(path: &Path) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    fs::metadata(path).ok().map(|m| (m.dev(), m.ino()))
+}
+
+#[cfg(not(unix))]
+fn dir_id(_path: &Path) -> Option<(u64, u64)> {
+    None
+}
+
+/// The apath in the source tree of an apath from the basis, or None if it's
+/// outside the source directory.
+fn basis_apath(apath: &Apath, source_dir_name: Option<&str>) -> Option<Apath> {
+    match source_dir_name {
+        None => Some(apath.clone()),
+        Some(name) => apath.replace_prefix(&Apath::from(format!("/{}", name)), &Apath::from("/")),
+    }
+}
+
+/// True if `apath` is directly inside `dir`.
+fn is_child_of(apath: &Apath, dir: &Apath) -> bool {
+    match apath.rfind('/') {
+        Some(0) => *dir == "/" && apath.len() > 1,
+        Some(i) => apath[..i] == dir[..],
+        None => false,
+    }
 }
 
 // The source iterator yields one path at a time as it walks through the source directories.
@@ -356,16 +1265,25 @@ impl Iterator for Iter {
     type Item = LiveEntry;
 
     fn next(&mut self) -> Option<LiveEntry> {
+        if let Some(entry) = self.synthetic_root.take() {
+            self.check_order.check(&entry.apath);
+            return Some(entry);
+        }
         loop {
             if let Some(entry) = self.entry_deque.pop_front() {
+                let entry = self.rename_into_source_dir(entry);
                 // Have already found some entries, so just return the first.
                 self.stats.entries_returned += 1;
                 // Sanity check that all the returned paths are in correct order.
                 self.check_order.check(&entry.apath);
                 return Some(entry);
+            } else if self.spilled.is_some() {
+                self.refill_from_spill();
             } else if let Some(entry) = self.dir_deque.pop_front() {
                 // No entries already queued, visit a new directory to try to refill the queue.
-                self.visit_next_directory(&entry)
+                if !matches!(self.max_depth, Some(max_depth) if entry.depth() >= max_depth) {
+                    self.visit_next_directory(&entry)
+                }
             } else {
                 // No entries queued and no more directories to visit.
                 return None;
@@ -377,7 +1295,8 @@ impl Iterator for Iter {
 #[cfg(test)]
 mod tests {
     use super::super::*;
-    use crate::test_fixtures::TreeFixture;
+    use crate::io::long_path;
+    use crate::test_fixtures::{ScratchArchive, TreeFixture};
 
     use regex::Regex;
 
@@ -387,7 +1306,7 @@ mod tests {
         let lt = LiveTree::open(tf.path()).unwrap();
         assert_eq!(
             format!("{:?}", &lt),
-            format!("LiveTree {{ path: {:?} }}", tf.path())
+            format!("LiveTree {{ path: {:?} }}", long_path(tf.path()))
         );
     }
 
@@ -414,13 +1333,30 @@ mod tests {
         assert_eq!(result.len(), 7);
 
         let repr = format!("{:?}", &result[6]);
-        let re = Regex::new(r#"LiveEntry \{ apath: Apath\("/jam/apricot"\), kind: File, mtime: UnixTime \{ [^)]* \}, size: Some\(8\), symlink_target: None \}"#).unwrap();
+        let re = Regex::new(r#"LiveEntry \{ apath: Apath\("/jam/apricot"\), kind: File, mtime: UnixTime \{ [^)]* \}, size: Some\(8\), symlink_target: None, ntfs: None, statx: None, inode: [^}]* \}"#).unwrap();
         assert!(re.is_match(&repr), repr);
 
         assert_eq!(source_iter.stats.directories_visited, 4);
         assert_eq!(source_iter.stats.entries_returned, 7);
     }
 
+    #[test]
+    fn statx_metadata() {
+        let tf = TreeFixture::new();
+        tf.create_file("hello");
+        let lt = LiveTree::open(tf.path()).unwrap();
+        assert!(lt
+            .iter_entries()
+            .unwrap()
+            .all(|entry| entry.statx.is_none()));
+        let lt = lt.with_statx_metadata(true).with_source_dir_name("src");
+        let entries = lt.iter_entries().unwrap().collect::<Vec<_>>();
+        assert_eq!(entries.len(), 3);
+        for entry in entries {
+            assert_eq!(entry.statx.is_some(), statx::SUPPORTED, "{:?}", entry);
+        }
+    }
+
     #[test]
     fn exclude_entries_directory() {
         let tf = TreeFixture::new();
@@ -449,6 +1385,194 @@ mod tests {
         assert_eq!(source_iter.stats.exclusions, 5);
     }
 
+    #[test]
+    fn skip_archives() {
+        let tf = TreeFixture::new();
+        tf.create_file("hello");
+        Archive::create(tf.path().join("archive")).unwrap();
+        let lt = LiveTree::open(tf.path()).unwrap();
+        let apaths: Vec<String> = lt.iter_entries().unwrap().map(|e| e.apath.into()).collect();
+        assert_eq!(apaths, ["/", "/hello"]);
+
+        let apaths: Vec<String> = lt
+            .with_archives_included(true)
+            .iter_entries()
+            .unwrap()
+            .map(|e| e.apath.into())
+            .collect();
+        assert_eq!(
+            apaths,
+            ["/", "/archive", "/hello", "/archive/CONSERVE", "/archive/d"]
+        );
+    }
+
+    #[test]
+    fn skip_source_that_is_an_archive() {
+        let tf = TreeFixture::new();
+        Archive::create(tf.path().join("archive")).unwrap();
+        let lt = LiveTree::open(tf.path().join("archive")).unwrap();
+        let apaths: Vec<String> = lt.iter_entries().unwrap().map(|e| e.apath.into()).collect();
+        assert_eq!(apaths, ["/"]);
+
+        let apaths: Vec<String> = lt
+            .with_archives_included(true)
+            .iter_entries()
+            .unwrap()
+            .map(|e| e.apath.into())
+            .collect();
+        assert_eq!(apaths, ["/", "/CONSERVE", "/d"]);
+    }
+
+    #[test]
+    fn unrelated_conserve_file_is_not_an_archive() {
+        let tf = TreeFixture::new();
+        tf.create_dir("notes");
+        tf.create_file_with_contents("notes/CONSERVE", b"Remember to back up");
+        let apaths: Vec<String> = LiveTree::open(tf.path())
+            .unwrap()
+            .iter_entries()
+            .unwrap()
+            .map(|e| e.apath.into())
+            .collect();
+        assert_eq!(apaths, ["/", "/notes", "/notes/CONSERVE"]);
+    }
+
+    #[test]
+    fn record_exclusions() {
+        let tf = TreeFixture::new();
+        tf.create_file("hello");
+        tf.create_dir("junk");
+        tf.create_file("junk/more");
+        tf.create_file("sub.tmp");
+        Archive::create(tf.path().join("archive")).unwrap();
+        let lt = LiveTree::open(tf.path())
+            .unwrap()
+            .with_excludes(excludes::from_strings(&["/junk", "*.tmp"]).unwrap())
+            .with_exclusions_recorded();
+        assert_eq!(lt.iter_entries().unwrap().count(), 2);
+        assert_eq!(
+            lt.take_exclusions(),
+            [
+                Exclusion {
+                    apath: "/archive".into(),
+                    reason: ExclusionReason::Archive,
+                },
+                Exclusion {
+                    apath: "/junk".into(),
+                    reason: ExclusionReason::Pattern(0),
+                },
+                Exclusion {
+                    apath: "/sub.tmp".into(),
+                    reason: ExclusionReason::Pattern(1),
+                },
+            ]
+        );
+        assert_eq!(lt.take_exclusions(), []);
+    }
+
+    #[test]
+    fn exclude_if_present() {
+        let tf = TreeFixture::new();
+        tf.create_file("hello");
+        tf.create_dir("keep");
+        tf.create_file("keep/a");
+        tf.create_dir("skip");
+        tf.create_file("skip/.nobackup");
+        tf.create_file("skip/b");
+        tf.create_dir("keep/skip");
+        tf.create_file("keep/skip/CACHEDIR.TAG");
+        let lt = LiveTree::open(tf.path())
+            .unwrap()
+            .with_exclude_if_present(&[".nobackup", "CACHEDIR.TAG"])
+            .with_exclusions_recorded();
+        let apaths: Vec<String> = lt.iter_entries().unwrap().map(|e| e.apath.into()).collect();
+        assert_eq!(apaths, ["/", "/hello", "/keep", "/keep/a"]);
+        assert_eq!(
+            lt.take_exclusions(),
+            [
+                Exclusion {
+                    apath: "/skip".into(),
+                    reason: ExclusionReason::Marker(0),
+                },
+                Exclusion {
+                    apath: "/keep/skip".into(),
+                    reason: ExclusionReason::Marker(1),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn with_source_dir_name() {
+        let tf = TreeFixture::new();
+        tf.create_file("bba");
+        tf.create_dir("jam");
+        tf.create_file("jam/apricot");
+        tf.create_file("excluded");
+        let lt = LiveTree::open(tf.path())
+            .unwrap()
+            .with_source_dir_name("src")
+            .with_excludes(excludes::from_strings(&["/excluded"]).unwrap());
+        let names: Vec<String> = lt.iter_entries().unwrap().map(|e| e.apath.into()).collect();
+        assert_eq!(
+            names,
+            ["/", "/src", "/src/bba", "/src/jam", "/src/jam/apricot"]
+        );
+
+        let entry = lt.iter_entries().unwrap().nth(4).unwrap();
+        let mut content = String::new();
+        std::io::Read::read_to_string(&mut lt.file_contents(&entry).unwrap(), &mut content)
+            .unwrap();
+        assert_eq!(content, "contents");
+        assert_eq!(lt.relative_path(&"/src".into()), Some(long_path(tf.path())));
+        assert_eq!(lt.relative_path(&"/".into()), None);
+        assert_eq!(lt.relative_path(&"/other/jam".into()), None);
+        assert_eq!(lt.relative_path(&"/srcjam".into()), None);
+    }
+
+    #[test]
+    fn trusted_dir_mtimes() {
+        let af = ScratchArchive::new();
+        let tf = TreeFixture::new();
+        tf.create_file("bba");
+        tf.create_dir("jam");
+        tf.create_file("jam/apricot");
+        tf.create_dir("jelly");
+        tf.create_file("jelly/grape");
+        let lt = LiveTree::open(tf.path())
+            .unwrap()
+            .with_source_dir_name("src");
+        copy_tree(&lt, BackupWriter::begin(&af).unwrap(), &COPY_DEFAULT).unwrap();
+        tf.create_file("jelly/lime");
+
+        let trusting = lt.clone().with_trusted_dir_mtimes(&af).unwrap();
+        let mut iter = trusting.iter_entries().unwrap();
+        let entries: Vec<LiveEntry> = iter.by_ref().collect();
+        // Inodes aren't known without reading the files' metadata.
+        let without_inodes = |entries: Vec<LiveEntry>| -> Vec<LiveEntry> {
+            entries
+                .into_iter()
+                .map(|e| LiveEntry { inode: None, ..e })
+                .collect()
+        };
+        assert_eq!(
+            without_inodes(entries),
+            without_inodes(lt.iter_entries().unwrap().collect())
+        );
+        // The root and /jam are unchanged, but /jelly has a new file.
+        assert_eq!(iter.stats.unchanged_dirs, 2);
+    }
+
+    #[test]
+    fn is_child_of() {
+        use super::is_child_of;
+        assert!(is_child_of(&"/a".into(), &"/".into()));
+        assert!(is_child_of(&"/a/b".into(), &"/a".into()));
+        assert!(!is_child_of(&"/a/b/c".into(), &"/a".into()));
+        assert!(!is_child_of(&"/ab/c".into(), &"/a".into()));
+        assert!(!is_child_of(&"/".into(), &"/".into()));
+    }
+
     #[cfg(unix)]
     #[test]
     fn symlinks() {
@@ -461,4 +1585,81 @@ mod tests {
         assert_eq!(&result[0].apath, "/");
         assert_eq!(&result[1].apath, "/from");
     }
+
+    #[test]
+    fn max_depth() {
+        let tf = TreeFixture::new();
+        tf.create_file("a");
+        tf.create_dir("b");
+        tf.create_file("b/c");
+        tf.create_dir("b/d");
+        tf.create_file("b/d/e");
+        let apaths = |max_depth| -> Vec<String> {
+            let lt = tf.live_tree().with_max_depth(max_depth);
+            lt.iter_entries().unwrap().map(|e| e.apath.into()).collect()
+        };
+        assert_eq!(apaths(Some(0)), ["/"]);
+        assert_eq!(apaths(Some(1)), ["/", "/a", "/b"]);
+        assert_eq!(apaths(Some(2)), ["/", "/a", "/b", "/b/c", "/b/d"]);
+        assert_eq!(apaths(None).len(), 6);
+    }
+
+    #[test]
+    fn spill_big_directory() {
+        let tf = TreeFixture::new();
+        for i in (0..50).rev() {
+            tf.create_file(&format!("f{:02}", i));
+        }
+        tf.create_dir("d");
+        tf.create_file("d/inner");
+        tf.create_dir("e");
+        let lt = tf.live_tree();
+        let expected: Vec<Apath> = lt.iter_entries().unwrap().map(|e| e.apath).collect();
+        assert_eq!(expected.len(), 54);
+
+        let mut iter = lt.iter_entries().unwrap();
+        iter.directory_budget = Some(1000);
+        let apaths: Vec<Apath> = iter.by_ref().map(|e| e.apath).collect();
+        assert_eq!(apaths, expected);
+        assert_eq!(iter.stats.spilled_dirs, 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn dereference_skips_symlink_loops() {
+        let tf = TreeFixture::new();
+        tf.create_dir("sub");
+        tf.create_file("sub/file");
+        tf.create_symlink("sub/parent", "..");
+        tf.create_symlink("sub/self", ".");
+        tf.create_symlink("link", "sub/file");
+        tf.create_symlink("dangling", "nowhere");
+
+        let lt = LiveTree::open(tf.path()).unwrap().with_dereference(true);
+        let mut iter = lt.iter_entries().unwrap();
+        let result = iter
+            .by_ref()
+            .map(|e| (e.apath.to_string(), e.kind))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            result,
+            [
+                ("/".to_owned(), Kind::Dir),
+                ("/dangling".to_owned(), Kind::Symlink),
+                ("/link".to_owned(), Kind::File),
+                ("/sub".to_owned(), Kind::Dir),
+                ("/sub/file".to_owned(), Kind::File),
+            ]
+        );
+        assert_eq!(iter.stats.symlink_loops, 2);
+        let problems = format!("{:?}", lt.take_problems(&mut iter));
+        assert!(problems.contains("SymlinkLoop"), "{}", problems);
+        assert!(problems.contains("/sub/parent"), "{}", problems);
+
+        // Each iterator has its own problems.
+        let mut iter = lt.iter_entries().unwrap();
+        iter.by_ref().count();
+        assert_eq!(lt.take_problems(&mut iter).len(), 2);
+        assert_eq!(lt.take_problems(&mut iter).len(), 0);
+    }
 }
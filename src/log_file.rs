@@ -0,0 +1,168 @@
+// Conserve backup system.
+// Copyright 2020 Martin Pool.
+
+//! Append a log of each run to a file, independent of what's shown on the
+//! terminal, for unattended machines.
+//!
+//! Each line has a timestamp, the level, the enclosing spans, the message, and
+//! then any other fields of the event, such as the apath of a problem.
+//! When the file grows beyond a size limit it's rotated to `PATH.1`, `PATH.2`,
+//! and so on, and the oldest is deleted.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use chrono::Local;
+use snafu::ResultExt;
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::ui::MessageVisitor;
+use crate::*;
+
+/// Rotate the log when it's bigger than this many bytes.
+pub const DEFAULT_MAX_LOG_SIZE: u64 = 10 << 20;
+
+/// Keep this many rotated old logs.
+const ROTATED_LOGS_KEPT: usize = 3;
+
+/// A tracing layer that appends events to a log file.
+#[derive(Debug)]
+pub struct LogFileLayer {
+    file: Mutex<File>,
+}
+
+impl LogFileLayer {
+    /// Open a log file for appending, first rotating it if it's bigger than
+    /// `max_size`.
+    pub fn open(path: &Path, max_size: u64) -> Result<LogFileLayer> {
+        let ctx = || errors::WriteLog {
+            path: path.to_path_buf(),
+        };
+        match fs::metadata(path) {
+            Ok(metadata) if metadata.len() > max_size => rotate(path).with_context(ctx)?,
+            _ => (),
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(ctx)?;
+        Ok(LogFileLayer {
+            file: Mutex::new(file),
+        })
+    }
+}
+
+fn rotated_path(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+/// Move `path` to `path.1`, shifting older logs up and deleting the oldest.
+fn rotate(path: &Path) -> io::Result<()> {
+    for n in (1..ROTATED_LOGS_KEPT).rev() {
+        let from = rotated_path(path, n);
+        if from.exists() {
+            fs::rename(&from, rotated_path(path, n + 1))?;
+        }
+    }
+    fs::rename(path, rotated_path(path, 1))
+}
+
+impl<S> Layer<S> for LogFileLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_event(&self, event: &Event, ctx: Context<S>) {
+        let level = *event.metadata().level();
+        if level > Level::INFO && event.metadata().target() != ui::LOG_ONLY_TARGET {
+            return;
+        }
+        let mut visitor = MessageVisitor::default();
+        event.record(&mut visitor);
+        let mut line = format!(
+            "{} {:<5} ",
+            Local::now().format("%Y-%m-%dT%H:%M:%S%.3f%z"),
+            level
+        );
+        if let Some(scope) = ctx.event_scope(event) {
+            let names: Vec<&str> = scope.from_root().map(|span| span.name()).collect();
+            if !names.is_empty() {
+                line.push_str(&names.join(":"));
+                line.push_str(": ");
+            }
+        }
+        line.push_str(&visitor.message);
+        line.push_str(&visitor.fields);
+        line.push('\n');
+        // Failing to write the log shouldn't stop the backup, and there's
+        // nowhere better to report it.
+        let _ = self.file.lock().unwrap().write_all(line.as_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::*;
+
+    #[test]
+    fn log_events_with_spans() {
+        let tmp = TempDir::new().unwrap();
+        let path = tmp.path().join("conserve.log");
+        let layer = LogFileLayer::open(&path, DEFAULT_MAX_LOG_SIZE).unwrap();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            let _span = tracing::info_span!("backup").entered();
+            tracing::info!("Backup complete.");
+            tracing::debug!("not logged");
+            tracing::debug!(target: ui::LOG_ONLY_TARGET, "logged anyhow");
+            Problem::FileDisappeared {
+                apath: "/gone".to_owned(),
+            }
+            .emit();
+        });
+        let log = fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = log.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].ends_with(" INFO  backup: Backup complete."));
+        assert!(lines[1].ends_with(" DEBUG backup: logged anyhow"));
+        assert!(lines[2].ends_with(
+            " ERROR backup: File disappeared during iteration: \"/gone\" \
+             kind=\"FileDisappeared\" apath=\"/gone\""
+        ));
+    }
+
+    #[test]
+    fn rotate_big_logs() {
+        let tmp = TempDir::new().unwrap();
+        let path = tmp.path().join("conserve.log");
+        for i in 0..5 {
+            fs::write(&path, format!("log {}\n", i)).unwrap();
+            LogFileLayer::open(&path, 2).unwrap();
+        }
+        // The current log was just rotated, and only three old logs are kept.
+        assert_eq!(fs::read_to_string(&path).unwrap(), "");
+        assert_eq!(
+            fs::read_to_string(tmp.path().join("conserve.log.1")).unwrap(),
+            "log 4\n"
+        );
+        assert_eq!(
+            fs::read_to_string(tmp.path().join("conserve.log.3")).unwrap(),
+            "log 2\n"
+        );
+        assert!(!tmp.path().join("conserve.log.4").exists());
+
+        // Small logs are appended to.
+        fs::write(&path, "x\n").unwrap();
+        LogFileLayer::open(&path, 100).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "x\n");
+    }
+}
@@ -6,117 +6,118 @@
 //! live tree, or storing an incremental backup.
 
 use std::cmp::Ordering;
+use std::iter::Peekable;
 
 use crate::*;
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum MergedEntryKind {
     LeftOnly,
     RightOnly,
     Both,
-    // TODO: Perhaps also include the tree-specific entry kind?
 }
 
 use self::MergedEntryKind::*;
 
-#[derive(Debug, PartialEq, Eq)]
-pub struct MergedEntry {
-    // TODO: Add accessors rather than making these public?
-    // TODO: Include the original entries from either side?
-    pub apath: Apath,
-    pub kind: MergedEntryKind,
+/// An apath present in either or both of two merged trees, with the entries
+/// for it from each side.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MergedEntry<AE, BE> {
+    Left(AE),
+    Right(BE),
+    Both(AE, BE),
 }
 
-/// Zip together entries from two trees, into an iterator of MergedEntryKind.
+impl<AE: Entry, BE: Entry> MergedEntry<AE, BE> {
+    pub fn apath(&self) -> &Apath {
+        match self {
+            MergedEntry::Left(a) | MergedEntry::Both(a, _) => a.apath(),
+            MergedEntry::Right(b) => b.apath(),
+        }
+    }
+
+    /// Which sides have this apath.
+    ///
+    /// Note that this doesn't say whether the entries differ when both
+    /// sides have it.
+    pub fn kind(&self) -> MergedEntryKind {
+        match self {
+            MergedEntry::Left(_) => LeftOnly,
+            MergedEntry::Right(_) => RightOnly,
+            MergedEntry::Both(..) => Both,
+        }
+    }
+}
+
+/// Zip together two iterators of entries, each in apath order, into one
+/// iterator of `MergedEntry` in apath order.
 ///
-/// Note that at present this only says whether files are absent from either
-/// side, not whether there is a content difference.
-pub fn iter_merged_entries<AT, BT>(a: &AT, b: &BT) -> Result<MergeTrees<AT, BT>>
+/// This works on the entries of any two `ReadTree`s, or any other sources of
+/// entries, such as a subtree or a filtered iterator.
+///
+/// ```
+/// use conserve::*;
+///
+/// # let source = tempfile::tempdir().unwrap();
+/// let a = LiveTree::open(source.path())?;
+/// let b = LiveTree::open(source.path())?;
+/// for merged in merge_trees(a.iter_entries()?, b.iter_entries()?) {
+///     assert_eq!(merged.kind(), MergedEntryKind::Both);
+/// }
+/// # Ok::<(), conserve::Error>(())
+/// ```
+pub fn merge_trees<AI, BI>(a: AI, b: BI) -> MergeTrees<AI::IntoIter, BI::IntoIter>
+where
+    AI: IntoIterator,
+    BI: IntoIterator,
+    AI::Item: Entry,
+    BI::Item: Entry,
+{
+    MergeTrees {
+        ait: a.into_iter().peekable(),
+        bit: b.into_iter().peekable(),
+    }
+}
+
+/// Zip together entries from two trees, into an iterator of `MergedEntry`.
+pub fn iter_merged_entries<AT, BT>(a: &AT, b: &BT) -> Result<MergeTrees<AT::I, BT::I>>
 where
     AT: ReadTree,
     BT: ReadTree,
 {
-    Ok(MergeTrees {
-        ait: a.iter_entries()?,
-        bit: b.iter_entries()?,
-        na: None,
-        nb: None,
-    })
+    Ok(merge_trees(a.iter_entries()?, b.iter_entries()?))
 }
 
-pub struct MergeTrees<AT: ReadTree, BT: ReadTree> {
-    ait: AT::I,
-    bit: BT::I,
-
-    // Read in advance entries from A and B.
-    na: Option<AT::Entry>,
-    nb: Option<BT::Entry>,
+/// Iterator returned by `merge_trees`.
+pub struct MergeTrees<AI: Iterator, BI: Iterator> {
+    ait: Peekable<AI>,
+    bit: Peekable<BI>,
 }
 
-impl<AT, BT> Iterator for MergeTrees<AT, BT>
+impl<AI, BI> Iterator for MergeTrees<AI, BI>
 where
-    AT: ReadTree,
-    BT: ReadTree,
+    AI: Iterator,
+    BI: Iterator,
+    AI::Item: Entry,
+    BI::Item: Entry,
 {
-    type Item = MergedEntry;
+    type Item = MergedEntry<AI::Item, BI::Item>;
 
     fn next(&mut self) -> Option<Self::Item> {
         // TODO: Stats about the merge.
-        let ait = &mut self.ait;
-        let bit = &mut self.bit;
-        // Preload next-A and next-B, if they're not already
-        // loaded.
-        //
-        // TODO: Perhaps use <https://doc.rust-lang.org/stable/core/iter/struct.Peekable.html> instead of keeping a
-        // readahead here?
-        if self.na.is_none() {
-            self.na = ait.next();
-        }
-        if self.nb.is_none() {
-            self.nb = bit.next();
-        }
-        if self.na.is_none() {
-            if self.nb.is_none() {
-                None
-            } else {
-                let tb = self.nb.take().unwrap();
-                Some(MergedEntry {
-                    apath: tb.apath().clone(),
-                    kind: RightOnly,
-                })
-            }
-        } else if self.nb.is_none() {
-            Some(MergedEntry {
-                apath: self.na.take().unwrap().apath().clone(),
-                kind: LeftOnly,
-            })
-        } else {
-            let pa = self.na.as_ref().unwrap().apath().clone();
-            let pb = self.nb.as_ref().unwrap().apath().clone();
-            match pa.cmp(&pb) {
-                Ordering::Equal => {
-                    self.na.take();
-                    self.nb.take();
-                    Some(MergedEntry {
-                        apath: pa,
-                        kind: Both,
-                    })
-                }
-                Ordering::Less => {
-                    self.na.take().unwrap();
-                    Some(MergedEntry {
-                        apath: pa,
-                        kind: LeftOnly,
-                    })
-                }
-                Ordering::Greater => {
-                    self.nb.take().unwrap();
-                    Some(MergedEntry {
-                        apath: pb,
-                        kind: RightOnly,
-                    })
-                }
-            }
+        let ordering = match (self.ait.peek(), self.bit.peek()) {
+            (None, None) => return None,
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (Some(a), Some(b)) => a.apath().cmp(b.apath()),
+        };
+        match ordering {
+            Ordering::Equal => Some(MergedEntry::Both(
+                self.ait.next().unwrap(),
+                self.bit.next().unwrap(),
+            )),
+            Ordering::Less => self.ait.next().map(MergedEntry::Left),
+            Ordering::Greater => self.bit.next().map(MergedEntry::Right),
         }
     }
 }
@@ -136,14 +137,52 @@ mod tests {
             .unwrap()
             .collect::<Vec<_>>();
         assert_eq!(di.len(), 1);
-        assert_eq!(
-            di[0],
-            MergedEntry {
-                apath: "/".into(),
-                kind: Both,
-            }
-        );
+        assert_eq!(di[0].apath(), "/");
+        assert_eq!(di[0].kind(), Both);
+    }
+
+    #[test]
+    fn merge_different_trees() {
+        let af = ScratchArchive::new();
+        af.store_two_versions();
+        let first = StoredTree::open_version(&af, &BandId::zero()).unwrap();
+        let last = StoredTree::open_last(&af).unwrap();
+        let merged: Vec<(String, MergedEntryKind)> =
+            merge_trees(last.iter_entries().unwrap(), first.iter_entries().unwrap())
+                .map(|e| (e.apath().to_string(), e.kind()))
+                .collect();
+        let mut expected = vec![
+            ("/".to_owned(), Both),
+            ("/hello".to_owned(), Both),
+            ("/hello2".to_owned(), LeftOnly),
+        ];
+        if SYMLINKS_SUPPORTED {
+            expected.push(("/link".to_owned(), Both));
+        }
+        expected.push(("/subdir".to_owned(), Both));
+        expected.push(("/subdir/subfile".to_owned(), Both));
+        assert_eq!(merged, expected);
+
+        // Both entries are returned, so their contents can be compared.
+        match merge_trees(first.iter_entries().unwrap(), last.iter_entries().unwrap())
+            .nth(1)
+            .unwrap()
+        {
+            MergedEntry::Both(a, b) => assert_eq!(a, b),
+            other => panic!("unexpected {:?}", other),
+        }
     }
 
-    // TODO: More tests of various diff situations.
+    #[test]
+    fn merge_with_empty() {
+        let tf = TreeFixture::new();
+        tf.create_file("a");
+        let merged: Vec<MergedEntryKind> = merge_trees(
+            Vec::<IndexEntry>::new(),
+            tf.live_tree().iter_entries().unwrap(),
+        )
+        .map(|e| e.kind())
+        .collect();
+        assert_eq!(merged, [RightOnly, RightOnly]);
+    }
 }
@@ -3,6 +3,12 @@
 
 //! Generally useful functions.
 
+use std::process::Command;
+
+use snafu::ResultExt;
+
+use crate::errors::{self, Result};
+
 /// Remove and return an item from a vec, if it's present.
 pub(crate) fn remove_item<T, U: PartialEq<T>>(v: &mut Vec<T>, item: &U) {
     if let Some(pos) = v.iter().position(|x| *item == *x) {
@@ -17,6 +23,41 @@ pub fn bytes_to_human_mb(s: u64) -> String {
     s
 }
 
+/// Format a byte count with a unit that keeps it short, like "512 B" or
+/// "1.5 MB".
+pub fn bytes_to_human(s: u64) -> String {
+    const UNITS: [&str; 5] = ["KB", "MB", "GB", "TB", "PB"];
+    if s < 1000 {
+        return format!("{} B", s);
+    }
+    let mut value = s as f64 / 1000.0;
+    let mut unit = 0;
+    while value >= 999.95 && unit + 1 < UNITS.len() {
+        value /= 1000.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+/// Run `f` on a pool of `jobs` worker threads, which is used by any parallel
+/// iterators inside it.
+///
+/// If `jobs` is None, `f` runs on the default pool, with one thread per core.
+pub fn in_thread_pool<T, F>(jobs: Option<usize>, f: F) -> Result<T>
+where
+    T: Send,
+    F: FnOnce() -> T + Send,
+{
+    match jobs {
+        None => Ok(f()),
+        Some(jobs) => Ok(rayon::ThreadPoolBuilder::new()
+            .num_threads(jobs)
+            .build()
+            .context(errors::ThreadPool)?
+            .install(f)),
+    }
+}
+
 /// True if `a` is zero.
 ///
 /// This trivial function exists as a predicate for serde.
@@ -32,3 +73,19 @@ pub(crate) fn zero_u32(a: &u32) -> bool {
 pub(crate) fn zero_u64(a: &u64) -> bool {
     *a == 0
 }
+
+/// Make a command that runs `command` in the platform's shell.
+#[cfg(unix)]
+pub(crate) fn shell_command(command: &str) -> Command {
+    let mut c = Command::new("sh");
+    c.arg("-c").arg(command);
+    c
+}
+
+/// Make a command that runs `command` in the platform's shell.
+#[cfg(windows)]
+pub(crate) fn shell_command(command: &str) -> Command {
+    let mut c = Command::new("cmd");
+    c.arg("/C").arg(command);
+    c
+}
@@ -0,0 +1,314 @@
+// Conserve backup system.
+// Copyright 2020 Martin Pool.
+
+//! Capture and restore NTFS security descriptors and named alternate data
+//! streams, so that backups of Windows servers keep file permissions and
+//! metadata such as `Zone.Identifier`.
+//!
+//! The security descriptor holds the owner, group and discretionary ACL, in
+//! self-relative binary form. Named streams are usually small, so they're
+//! stored inline in the index, and streams larger than `MAX_STREAM_SIZE` are
+//! skipped.
+
+use std::io;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+/// True if NTFS metadata can be read and written on this platform.
+pub(crate) const SUPPORTED: bool = cfg!(windows);
+
+/// Named streams larger than this aren't stored.
+pub const MAX_STREAM_SIZE: u64 = 64 << 10;
+
+/// NTFS metadata of a file or directory, as stored in the index.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct NtfsMetadata {
+    /// The security descriptor, as hex.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub security_descriptor: Option<String>,
+
+    /// Named alternate data streams.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub streams: Vec<NamedStream>,
+}
+
+/// An alternate data stream of a file or directory.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct NamedStream {
+    /// The stream name, such as `Zone.Identifier`.
+    pub name: String,
+    /// The stream content, as hex.
+    pub content: String,
+}
+
+/// Read the NTFS metadata of a file or directory.
+///
+/// Also returns the names of any streams that were too large to store.
+#[cfg(windows)]
+pub(crate) fn read(path: &Path) -> io::Result<(NtfsMetadata, Vec<String>)> {
+    let security_descriptor = Some(hex::encode(win::get_security(path)?));
+    let mut streams = Vec::new();
+    let mut too_large = Vec::new();
+    for (raw_name, size) in win::list_streams(path)? {
+        if let Some(name) = stream_name(&raw_name) {
+            if size > MAX_STREAM_SIZE {
+                too_large.push(name.to_owned());
+            } else {
+                streams.push(NamedStream {
+                    name: name.to_owned(),
+                    content: hex::encode(std::fs::read(stream_path(path, name))?),
+                });
+            }
+        }
+    }
+    Ok((
+        NtfsMetadata {
+            security_descriptor,
+            streams,
+        },
+        too_large,
+    ))
+}
+
+/// Read the NTFS metadata of a file or directory.
+///
+/// Also returns the names of any streams that were too large to store.
+#[cfg(not(windows))]
+pub(crate) fn read(_path: &Path) -> io::Result<(NtfsMetadata, Vec<String>)> {
+    Err(unsupported())
+}
+
+/// Write streams and then the security descriptor onto an existing file or
+/// directory.
+#[cfg(windows)]
+pub(crate) fn write(path: &Path, metadata: &NtfsMetadata) -> io::Result<()> {
+    let decode =
+        |s: &str| hex::decode(s).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e));
+    for stream in &metadata.streams {
+        std::fs::write(stream_path(path, &stream.name), decode(&stream.content)?)?;
+    }
+    // Last, because the ACL might not allow us to write the streams.
+    if let Some(security_descriptor) = &metadata.security_descriptor {
+        win::set_security(path, &decode(security_descriptor)?)?;
+    }
+    Ok(())
+}
+
+/// Write streams and then the security descriptor onto an existing file or
+/// directory.
+#[cfg(not(windows))]
+pub(crate) fn write(_path: &Path, _metadata: &NtfsMetadata) -> io::Result<()> {
+    Err(unsupported())
+}
+
+#[cfg(not(windows))]
+fn unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "NTFS metadata is only supported on Windows",
+    )
+}
+
+/// The path to open a named stream of a file.
+#[cfg(windows)]
+fn stream_path(path: &Path, name: &str) -> std::path::PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(":");
+    path.push(name);
+    path.into()
+}
+
+/// Find the name of a named data stream from the name Windows lists, like
+/// `:Zone.Identifier:$DATA`, or None for the default stream.
+#[cfg_attr(not(windows), allow(dead_code))]
+fn stream_name(raw_name: &str) -> Option<&str> {
+    raw_name
+        .strip_prefix(':')
+        .and_then(|s| s.strip_suffix(":$DATA"))
+        .filter(|name| !name.is_empty())
+}
+
+#[cfg(windows)]
+mod win {
+    use std::ffi::c_void;
+    use std::io;
+    use std::os::windows::ffi::OsStrExt;
+    use std::path::Path;
+
+    const OWNER_SECURITY_INFORMATION: u32 = 0x1;
+    const GROUP_SECURITY_INFORMATION: u32 = 0x2;
+    const DACL_SECURITY_INFORMATION: u32 = 0x4;
+    const SECURITY_INFORMATION: u32 =
+        OWNER_SECURITY_INFORMATION | GROUP_SECURITY_INFORMATION | DACL_SECURITY_INFORMATION;
+
+    const FIND_STREAM_INFO_STANDARD: i32 = 0;
+    const ERROR_HANDLE_EOF: i32 = 38;
+    const INVALID_HANDLE_VALUE: isize = -1;
+
+    /// `WIN32_FIND_STREAM_DATA`.
+    #[repr(C)]
+    struct FindStreamData {
+        stream_size: i64,
+        stream_name: [u16; 260 + 36],
+    }
+
+    #[link(name = "advapi32")]
+    extern "system" {
+        fn GetFileSecurityW(
+            file_name: *const u16,
+            requested_information: u32,
+            security_descriptor: *mut c_void,
+            length: u32,
+            length_needed: *mut u32,
+        ) -> i32;
+        fn SetFileSecurityW(
+            file_name: *const u16,
+            security_information: u32,
+            security_descriptor: *const c_void,
+        ) -> i32;
+    }
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn FindFirstStreamW(
+            file_name: *const u16,
+            info_level: i32,
+            find_stream_data: *mut FindStreamData,
+            flags: u32,
+        ) -> isize;
+        fn FindNextStreamW(find_stream: isize, find_stream_data: *mut FindStreamData) -> i32;
+        fn FindClose(find_file: isize) -> i32;
+    }
+
+    fn wide(path: &Path) -> Vec<u16> {
+        path.as_os_str().encode_wide().chain(Some(0)).collect()
+    }
+
+    pub(super) fn get_security(path: &Path) -> io::Result<Vec<u8>> {
+        let name = wide(path);
+        let mut needed = 0;
+        // Ask how big the buffer should be.
+        unsafe {
+            GetFileSecurityW(
+                name.as_ptr(),
+                SECURITY_INFORMATION,
+                std::ptr::null_mut(),
+                0,
+                &mut needed,
+            )
+        };
+        if needed == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut buf = vec![0u8; needed as usize];
+        let ok = unsafe {
+            GetFileSecurityW(
+                name.as_ptr(),
+                SECURITY_INFORMATION,
+                buf.as_mut_ptr() as *mut c_void,
+                buf.len() as u32,
+                &mut needed,
+            )
+        };
+        if ok == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(buf)
+    }
+
+    pub(super) fn set_security(path: &Path, security_descriptor: &[u8]) -> io::Result<()> {
+        let name = wide(path);
+        let ok = unsafe {
+            SetFileSecurityW(
+                name.as_ptr(),
+                SECURITY_INFORMATION,
+                security_descriptor.as_ptr() as *const c_void,
+            )
+        };
+        if ok == 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// List the raw names and sizes of all streams, including the default.
+    pub(super) fn list_streams(path: &Path) -> io::Result<Vec<(String, u64)>> {
+        let name = wide(path);
+        let mut data: FindStreamData = unsafe { std::mem::zeroed() };
+        let handle =
+            unsafe { FindFirstStreamW(name.as_ptr(), FIND_STREAM_INFO_STANDARD, &mut data, 0) };
+        if handle == INVALID_HANDLE_VALUE {
+            // Directories without streams have none at all.
+            let err = io::Error::last_os_error();
+            return match err.raw_os_error() {
+                Some(ERROR_HANDLE_EOF) => Ok(Vec::new()),
+                _ => Err(err),
+            };
+        }
+        let mut streams = Vec::new();
+        let result = loop {
+            let len = data
+                .stream_name
+                .iter()
+                .position(|&c| c == 0)
+                .unwrap_or(data.stream_name.len());
+            streams.push((
+                String::from_utf16_lossy(&data.stream_name[..len]),
+                data.stream_size as u64,
+            ));
+            if unsafe { FindNextStreamW(handle, &mut data) } == 0 {
+                let err = io::Error::last_os_error();
+                break match err.raw_os_error() {
+                    Some(ERROR_HANDLE_EOF) => Ok(streams),
+                    _ => Err(err),
+                };
+            }
+        };
+        unsafe { FindClose(handle) };
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stream_names() {
+        assert_eq!(
+            stream_name(":Zone.Identifier:$DATA"),
+            Some("Zone.Identifier")
+        );
+        assert_eq!(stream_name("::$DATA"), None);
+        assert_eq!(stream_name(":odd:name:$DATA"), Some("odd:name"));
+    }
+
+    #[test]
+    fn serialize_metadata() {
+        let metadata = NtfsMetadata {
+            security_descriptor: Some("0100".to_owned()),
+            streams: vec![NamedStream {
+                name: "Zone.Identifier".to_owned(),
+                content: "5b5d".to_owned(),
+            }],
+        };
+        let json = serde_json::to_string(&metadata).unwrap();
+        assert_eq!(
+            json,
+            r#"{"security_descriptor":"0100","streams":[{"name":"Zone.Identifier","content":"5b5d"}]}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&NtfsMetadata::default()).unwrap(),
+            "{}"
+        );
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn unsupported_elsewhere() {
+        assert!(read(Path::new(".")).is_err());
+        assert!(write(Path::new("."), &NtfsMetadata::default()).is_err());
+    }
+}
@@ -0,0 +1,58 @@
+// Conserve backup system.
+// Copyright 2020 Martin Pool.
+
+//! Callbacks from inside a backup, for programs that embed Conserve and want
+//! to audit, index or scan what's stored as it happens.
+
+use std::fmt;
+
+use crate::*;
+
+/// Receives events while a backup is written.
+///
+/// Every method does nothing by default, so implementations need only provide
+/// the ones they care about. Blocks are written on worker threads, so
+/// observers must be `Send` and `Sync`, and should return quickly.
+///
+/// ```no_run
+/// use std::sync::Arc;
+/// use conserve::{BackupOptions, IndexEntry, Observer};
+///
+/// struct Audit;
+///
+/// impl Observer for Audit {
+///     fn entry_stored(&self, entry: &IndexEntry) {
+///         println!("stored {}", entry.apath);
+///     }
+/// }
+///
+/// BackupOptions::new("/home/me", "/backup/archive")
+///     .observer(Arc::new(Audit))
+///     .run()
+///     .unwrap();
+/// ```
+pub trait Observer: Send + Sync {
+    /// A source entry is about to be copied into the backup.
+    fn entry_started(&self, _apath: &Apath, _kind: Kind) {}
+
+    /// An entry was written to the band's index, along with the addresses
+    /// of its content.
+    ///
+    /// In layered bands, entries for files that were deleted are written
+    /// with kind `Deleted`, and unchanged entries aren't written at all.
+    fn entry_stored(&self, _entry: &IndexEntry) {}
+
+    /// A new block was written, holding `len` bytes of content compressed
+    /// to `compressed_len` bytes. Blocks already in the archive aren't
+    /// reported.
+    fn block_written(&self, _hash: &str, _len: u64, _compressed_len: u64) {}
+
+    /// The band was completed.
+    fn band_closed(&self, _band_id: &BandId) {}
+}
+
+impl fmt::Debug for dyn Observer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Observer")
+    }
+}
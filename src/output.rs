@@ -6,9 +6,12 @@
 //! These are objects that accept iterators of different types of content, and write it to a
 //! file (typically stdout).
 
+use std::collections::HashMap;
+
 use super::*;
 
 use snafu::ResultExt;
+use tracing::error;
 
 use chrono::Local;
 
@@ -32,31 +35,47 @@ impl ShowArchive for ShortVersionList {
 #[derive(Debug, Default)]
 pub struct VerboseVersionList {
     show_sizes: bool,
+    show_changes: bool,
 }
 
 impl VerboseVersionList {
-    // Control whether to show the size of version disk usage.
+    // Control whether to show the size of each version, and the compressed
+    // size of the blocks it uses alone and shares with other versions.
     //
     // Setting this requires walking the band directories which takes some extra time.
     pub fn show_sizes(self, show_sizes: bool) -> VerboseVersionList {
-        VerboseVersionList { show_sizes }
+        VerboseVersionList { show_sizes, ..self }
+    }
+
+    // Control whether to show how many entries each version added, changed,
+    // and deleted, compared to the version before it.
+    pub fn show_changes(self, show_changes: bool) -> VerboseVersionList {
+        VerboseVersionList {
+            show_changes,
+            ..self
+        }
     }
 }
 
 impl ShowArchive for VerboseVersionList {
     fn show_archive(&self, archive: &Archive) -> Result<()> {
+        let band_space = if self.show_sizes {
+            archive.band_space()?
+        } else {
+            Default::default()
+        };
         for band_id in archive.list_bands()? {
             let band = match Band::open(&archive, &band_id) {
                 Ok(band) => band,
                 Err(e) => {
-                    ui::problem(&format!("Failed to open band {:?}: {:?}", band_id, e));
+                    error!("Failed to open band {:?}: {:?}", band_id, e);
                     continue;
                 }
             };
             let info = match band.get_info() {
                 Ok(info) => info,
                 Err(e) => {
-                    ui::problem(&format!("Failed to read band tail {:?}: {:?}", band_id, e));
+                    error!("Failed to read band tail {:?}: {:?}", band_id, e);
                     continue;
                 }
             };
@@ -74,22 +93,236 @@ impl ShowArchive for VerboseVersionList {
                 .and_then(|et| (et - info.start_time).to_std().ok())
                 .map(crate::ui::duration_to_hms)
                 .unwrap_or_default();
-            if self.show_sizes {
+            let mut line = if self.show_sizes {
                 let tree_mb = crate::misc::bytes_to_human_mb(
                     StoredTree::open_incomplete_version(archive, &band.id())?
                         .size()?
                         .file_bytes,
                 );
-                ui::println(&format!(
-                    "{:<20} {:<10} {} {:>8} {:>14}",
-                    band_id, is_complete_str, start_time_str, duration_str, tree_mb,
-                ));
+                let space = band_space.get(&band_id).copied().unwrap_or_default();
+                format!(
+                    "{:<20} {:<10} {} {:>8} {:>14} {:>14} {:>14}",
+                    band_id,
+                    is_complete_str,
+                    start_time_str,
+                    duration_str,
+                    tree_mb,
+                    crate::misc::bytes_to_human_mb(space.exclusive_bytes),
+                    crate::misc::bytes_to_human_mb(space.shared_bytes),
+                )
             } else {
-                ui::println(&format!(
+                format!(
                     "{:<20} {:<10} {} {:>8}",
                     band_id, is_complete_str, start_time_str, duration_str,
+                )
+            };
+            if self.show_changes {
+                let (mut added, mut changed, mut deleted) = (0, 0, 0);
+                for change in StoredTree::open_incomplete_version(archive, &band_id)?.changes()? {
+                    match change.kind {
+                        ChangeKind::Added => added += 1,
+                        ChangeKind::Changed => changed += 1,
+                        ChangeKind::Deleted => deleted += 1,
+                    }
+                }
+                line += &format!(
+                    " {:>10} {:>10} {:>10}",
+                    format!("+{}", added),
+                    format!("~{}", changed),
+                    format!("-{}", deleted),
+                );
+            }
+            ui::println(&line);
+        }
+        Ok(())
+    }
+}
+
+/// Show how an archive grew and changed over time.
+#[derive(Debug, Default)]
+pub struct ArchiveStats {
+    history: bool,
+    csv: bool,
+}
+
+impl ArchiveStats {
+    /// Show one line for each version, rather than only totals.
+    pub fn history(self, history: bool) -> ArchiveStats {
+        ArchiveStats { history, ..self }
+    }
+
+    /// Show the history as CSV, with exact numbers, for spreadsheets and
+    /// plotting.
+    pub fn csv(self, csv: bool) -> ArchiveStats {
+        ArchiveStats { csv, ..self }
+    }
+}
+
+impl ShowArchive for ArchiveStats {
+    fn show_archive(&self, archive: &Archive) -> Result<()> {
+        let history = archive.history()?;
+        ui::clear_progress();
+        let mb = crate::misc::bytes_to_human_mb;
+        if self.csv {
+            ui::println(
+                "version,start_time,complete,files,file_bytes,files_added,files_changed,\
+                 files_removed,new_block_bytes,total_block_bytes",
+            );
+            for h in &history {
+                ui::println(&format!(
+                    "{},{},{},{},{},{},{},{},{},{}",
+                    h.band_id,
+                    h.start_time.to_rfc3339(),
+                    h.is_closed,
+                    h.files,
+                    h.file_bytes,
+                    h.files_added,
+                    h.files_changed,
+                    h.files_removed,
+                    h.new_block_bytes,
+                    h.total_block_bytes
                 ));
             }
+        } else if self.history {
+            ui::println(&format!(
+                "{:<20} {:<19} {:>10} {:>12} {:>8} {:>8} {:>8} {:>12} {:>12}",
+                "version", "start", "files", "size", "added", "changed", "removed", "new", "total"
+            ));
+            for h in &history {
+                ui::println(&format!(
+                    "{:<20} {} {:>10} {:>12} {:>8} {:>8} {:>8} {:>12} {:>12}",
+                    h.band_id,
+                    h.start_time
+                        .with_timezone(&Local)
+                        .format(crate::TIMESTAMP_FORMAT),
+                    h.files,
+                    mb(h.file_bytes),
+                    h.files_added,
+                    h.files_changed,
+                    h.files_removed,
+                    mb(h.new_block_bytes),
+                    mb(h.total_block_bytes),
+                ));
+            }
+        } else {
+            let last = history.last();
+            let lines = [
+                ("Versions", history.len().to_string()),
+                (
+                    "Latest",
+                    last.map(|h| h.band_id.to_string())
+                        .unwrap_or_else(|| "none".to_owned()),
+                ),
+                ("Files", last.map(|h| h.files).unwrap_or(0).to_string()),
+                ("File size", mb(last.map(|h| h.file_bytes).unwrap_or(0))),
+                (
+                    "Block size",
+                    mb(last.map(|h| h.total_block_bytes).unwrap_or(0)),
+                ),
+            ];
+            for (label, value) in lines.iter() {
+                ui::println(&format!("{:<11} {}", format!("{}:", label), value));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Show the entries of a stored tree, or of a subtree within it, indented
+/// like `tree(1)`, with the size of each file and the total size of the files
+/// inside each directory.
+#[derive(Debug)]
+pub struct TreeListing<'a> {
+    tree: &'a StoredTree,
+    subtree: Apath,
+}
+
+/// One entry in a `TreeListing`, with the indexes of its children.
+struct TreeNode {
+    entry: IndexEntry,
+    bytes: u64,
+    children: Vec<usize>,
+}
+
+impl<'a> TreeListing<'a> {
+    pub fn new(tree: &'a StoredTree, subtree: Apath) -> Self {
+        Self { tree, subtree }
+    }
+
+    fn show_node(nodes: &[TreeNode], i: usize, prefix: &str, connector: &str) {
+        let node = &nodes[i];
+        let name = if connector.is_empty() {
+            node.entry.apath.to_string()
+        } else {
+            node.entry.apath.rsplit('/').next().unwrap().to_owned()
+        };
+        let (size, name) = match node.entry.kind {
+            Kind::Dir => (
+                bytes_to_human(node.bytes),
+                ui::paint(ui::Highlight::Directory, &name),
+            ),
+            Kind::Symlink => (
+                String::new(),
+                format!(
+                    "{} -> {}",
+                    ui::paint(ui::Highlight::Symlink, &name),
+                    node.entry.target.as_deref().unwrap_or_default()
+                ),
+            ),
+            _ => (bytes_to_human(node.bytes), name),
+        };
+        ui::println(&format!("{:>10}  {}{}{}", size, prefix, connector, name));
+        let child_prefix = match connector {
+            "├── " => format!("{}│   ", prefix),
+            "└── " => format!("{}    ", prefix),
+            _ => prefix.to_owned(),
+        };
+        for (j, &child) in node.children.iter().enumerate() {
+            let connector = if j + 1 == node.children.len() {
+                "└── "
+            } else {
+                "├── "
+            };
+            TreeListing::show_node(nodes, child, &child_prefix, connector);
+        }
+    }
+}
+
+impl<'a> ShowArchive for TreeListing<'a> {
+    fn show_archive(&self, _archive: &Archive) -> Result<()> {
+        // Entries come in apath order, so every directory is seen before its
+        // contents.
+        let mut nodes: Vec<TreeNode> = Vec::new();
+        let mut dirs: HashMap<String, usize> = HashMap::new();
+        let mut roots = Vec::new();
+        for entry in self.tree.iter_subtree(&self.subtree)? {
+            let i = nodes.len();
+            let parent = match entry.apath.rfind('/') {
+                Some(0) if &*entry.apath != "/" => dirs.get("/"),
+                Some(slash) if slash > 0 => dirs.get(&entry.apath[..slash]),
+                _ => None,
+            };
+            match parent {
+                Some(&parent) => nodes[parent].children.push(i),
+                None => roots.push(i),
+            }
+            if entry.kind == Kind::Dir {
+                dirs.insert(entry.apath.to_string(), i);
+            }
+            nodes.push(TreeNode {
+                bytes: entry.size().unwrap_or_default(),
+                entry,
+                children: Vec::new(),
+            });
+        }
+        // Children always come after their parents, so walking backwards
+        // totals each directory before it's added to its own parent.
+        for i in (0..nodes.len()).rev() {
+            let bytes: u64 = nodes[i].children.iter().map(|&c| nodes[c].bytes).sum();
+            nodes[i].bytes += bytes;
+        }
+        for root in roots {
+            TreeListing::show_node(&nodes, root, "", "");
         }
         Ok(())
     }
@@ -115,3 +348,74 @@ impl<'a> ShowArchive for IndexDump<'a> {
         Ok(())
     }
 }
+
+/// Show whether a band is complete, what it was based on, and a summary of
+/// its index.
+#[derive(Debug)]
+pub struct BandInfo<'a> {
+    band: &'a Band,
+}
+
+impl<'a> BandInfo<'a> {
+    pub fn new(band: &'a Band) -> Self {
+        Self { band }
+    }
+}
+
+impl<'a> ShowArchive for BandInfo<'a> {
+    fn show_archive(&self, _archive: &Archive) -> Result<()> {
+        let info = self.band.get_info()?;
+        let summary = self.band.index_summary()?;
+        let format_time = |t: chrono::DateTime<chrono::Utc>| {
+            t.with_timezone(&Local)
+                .format(crate::TIMESTAMP_FORMAT)
+                .to_string()
+        };
+        let lines = [
+            ("Band", info.id.to_string()),
+            (
+                "Complete",
+                if info.is_closed { "yes" } else { "no" }.to_owned(),
+            ),
+            ("Start time", format_time(info.start_time)),
+            (
+                "End time",
+                info.end_time.map(format_time).unwrap_or_default(),
+            ),
+            (
+                "Based on",
+                info.basis_band_id
+                    .map(|b| b.to_string())
+                    .unwrap_or_else(|| "none".to_owned()),
+            ),
+            (
+                "Source path",
+                info.source_path.unwrap_or_else(|| "unknown".to_owned()),
+            ),
+            (
+                "Layered on",
+                info.index_base_band_id
+                    .map(|b| b.to_string())
+                    .unwrap_or_else(|| "none".to_owned()),
+            ),
+            (
+                "Last apath",
+                summary
+                    .last_apath
+                    .map(String::from)
+                    .unwrap_or_else(|| "none".to_owned()),
+            ),
+            ("Index hunks", summary.index_hunks.to_string()),
+            ("Entries", summary.entries.to_string()),
+            ("Files", summary.files.to_string()),
+            ("Directories", summary.dirs.to_string()),
+            ("Symlinks", summary.symlinks.to_string()),
+            ("Deleted", summary.deleted.to_string()),
+            ("File bytes", summary.file_bytes.to_string()),
+        ];
+        for (label, value) in lines.iter() {
+            ui::println(&format!("{:<14} {}", format!("{}:", label), value));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,460 @@
+// Conserve backup system.
+// Copyright 2020 Martin Pool.
+
+//! Typed descriptions of non-fatal problems.
+//!
+//! Problems don't stop an operation, but they're collected into its stats so
+//! that programs can see which paths were affected and why, as well as being
+//! emitted as error events for people to read.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::io;
+use std::ops::{Add, AddAssign, Deref};
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+use snafu::ResultExt;
+use thousands::Separable;
+use tracing::{debug, error};
+
+use crate::*;
+
+/// A non-fatal problem encountered during an operation.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(tag = "kind")]
+pub enum Problem {
+    /// A source directory couldn't be listed, so its contents were skipped.
+    ListDirectory { path: PathBuf, message: String },
+
+    /// A filename isn't valid UTF-8 so can't be stored.
+    UndecodableName { dir: PathBuf, name: String },
+
+    /// A source file disappeared between listing its directory and reading it.
+    FileDisappeared { apath: String },
+
+    /// The metadata or file type of a source file couldn't be read.
+    MetadataError { apath: String, message: String },
+
+    /// The target of a symlink couldn't be read or decoded.
+    UnreadableSymlink { apath: String, message: String },
+
+    /// NTFS metadata of a source entry couldn't be read, or some of it
+    /// couldn't be stored.
+    NtfsMetadata { apath: String, message: String },
+
+    /// A source entry, or a whole directory, was skipped because the backup
+    /// isn't permitted to read it.
+    PermissionDenied { apath: String, entry_kind: Kind },
+
+    /// A symlink wasn't followed because it points to a directory that was
+    /// already visited, which would otherwise loop forever.
+    SymlinkLoop { apath: String },
+
+    /// An entry in a tar file couldn't be imported, for example because it's
+    /// of a type that can't be stored.
+    TarEntry { name: String, message: String },
+
+    /// An entry couldn't be copied, for example because the source file was
+    /// unreadable or the destination couldn't be written.
+    CopyEntry {
+        apath: Apath,
+        entry_kind: Kind,
+        message: String,
+    },
+}
+
+impl Problem {
+    /// Emit this problem as an error event, and then return it so that it can
+    /// be collected.
+    ///
+    /// The event has the problem's `kind`, and its `apath` or `source` path
+    /// where there is one, as fields, so that subscribers can filter on them.
+    ///
+    /// Permission problems are only logged at debug level, since there may be
+    /// very many of them; they're summarized at the end instead.
+    pub fn emit(self) -> Problem {
+        let kind = self.kind_name();
+        let apath = self.apath();
+        let source = self.source_path().map(|p| p.display().to_string());
+        if let Problem::PermissionDenied { .. } = self {
+            debug!(target: ui::PROBLEM_TARGET, kind, apath, source, "{}", self);
+        } else {
+            error!(target: ui::PROBLEM_TARGET, kind, apath, source, "{}", self);
+        }
+        self
+    }
+
+    /// The apath of the affected entry, if the problem is about one.
+    pub fn apath(&self) -> Option<&str> {
+        use Problem::*;
+        match self {
+            FileDisappeared { apath }
+            | MetadataError { apath, .. }
+            | UnreadableSymlink { apath, .. }
+            | NtfsMetadata { apath, .. }
+            | PermissionDenied { apath, .. }
+            | SymlinkLoop { apath } => Some(apath),
+            CopyEntry { apath, .. } => Some(apath),
+            ListDirectory { .. } | UndecodableName { .. } | TarEntry { .. } => None,
+        }
+    }
+
+    /// The source path affected, for problems found before there's an apath.
+    pub fn source_path(&self) -> Option<&Path> {
+        use Problem::*;
+        match self {
+            ListDirectory { path, .. } => Some(path),
+            UndecodableName { dir, .. } => Some(dir),
+            _ => None,
+        }
+    }
+
+    /// The name of this kind of problem, as in its serialized `kind`.
+    pub fn kind_name(&self) -> &'static str {
+        use Problem::*;
+        match self {
+            ListDirectory { .. } => "ListDirectory",
+            UndecodableName { .. } => "UndecodableName",
+            FileDisappeared { .. } => "FileDisappeared",
+            MetadataError { .. } => "MetadataError",
+            UnreadableSymlink { .. } => "UnreadableSymlink",
+            NtfsMetadata { .. } => "NtfsMetadata",
+            PermissionDenied { .. } => "PermissionDenied",
+            SymlinkLoop { .. } => "SymlinkLoop",
+            TarEntry { .. } => "TarEntry",
+            CopyEntry { .. } => "CopyEntry",
+        }
+    }
+
+    /// The directory where the problem occurred: a source path for
+    /// directories that couldn't be listed, and otherwise the parent of the
+    /// affected apath.
+    pub fn directory(&self) -> String {
+        use Problem::*;
+        match self {
+            ListDirectory { path, .. } => path.display().to_string(),
+            UndecodableName { dir, .. } => dir.display().to_string(),
+            FileDisappeared { apath }
+            | MetadataError { apath, .. }
+            | UnreadableSymlink { apath, .. }
+            | NtfsMetadata { apath, .. }
+            | PermissionDenied { apath, .. }
+            | SymlinkLoop { apath } => parent_apath(apath).to_owned(),
+            TarEntry { name, .. } => parent_apath(name).to_owned(),
+            CopyEntry { apath, .. } => parent_apath(apath).to_owned(),
+        }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Problem::*;
+        match self {
+            ListDirectory { path, message } => {
+                write!(f, "Error reading directory {:?}: {}", path, message)
+            }
+            UndecodableName { dir, name } => {
+                write!(f, "Can't decode filename {:?} in {:?}", name, dir)
+            }
+            FileDisappeared { apath } => {
+                write!(f, "File disappeared during iteration: {:?}", apath)
+            }
+            MetadataError { apath, message } => write!(
+                f,
+                "Failed to read source metadata from {:?}: {}",
+                apath, message
+            ),
+            UnreadableSymlink { apath, message } => {
+                write!(
+                    f,
+                    "Failed to read target of symlink {:?}: {}",
+                    apath, message
+                )
+            }
+            NtfsMetadata { apath, message } => write!(
+                f,
+                "Failed to read NTFS metadata from {:?}: {}",
+                apath, message
+            ),
+            PermissionDenied { apath, entry_kind } => write!(
+                f,
+                "Permission denied reading {} {:?}",
+                format!("{:?}", entry_kind).to_lowercase(),
+                apath
+            ),
+            SymlinkLoop { apath } => write!(
+                f,
+                "Not following symlink {:?} to a directory already visited",
+                apath
+            ),
+            TarEntry { name, message } => {
+                write!(f, "Skipped tar entry {:?}: {}", name, message)
+            }
+            CopyEntry {
+                apath,
+                entry_kind,
+                message,
+            } => write!(
+                f,
+                "Failed to copy {} {:?}: {}",
+                format!("{:?}", entry_kind).to_lowercase(),
+                apath.to_string(),
+                message
+            ),
+        }
+    }
+}
+
+/// The problems found during an operation, in the order they occurred.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct Problems(Vec<Problem>);
+
+impl Problems {
+    pub fn push(&mut self, problem: Problem) {
+        self.0.push(problem)
+    }
+
+    /// Write a summary of the problems for people to read: how many there
+    /// were of each kind, the first `first` problems themselves, and the
+    /// `top_dirs` directories with the most.
+    pub fn summarize(&self, w: &mut dyn io::Write, first: usize, top_dirs: usize) -> Result<()> {
+        let mut kinds = BTreeMap::<&str, usize>::new();
+        let mut dirs = BTreeMap::<String, usize>::new();
+        for problem in &self.0 {
+            *kinds.entry(problem.kind_name()).or_default() += 1;
+            *dirs.entry(problem.directory()).or_default() += 1;
+        }
+        let mut dirs: Vec<(String, usize)> = dirs.into_iter().collect();
+        // Most problems first, and then in order of the directory.
+        dirs.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        writeln!(
+            w,
+            "{:>12}      problems:",
+            self.0.len().separate_with_commas()
+        )
+        .context(errors::WriteStats)?;
+        for (kind, count) in &kinds {
+            writeln!(w, "{:>12}        {}", count.separate_with_commas(), kind)
+                .context(errors::WriteStats)?;
+        }
+        if first > 0 {
+            writeln!(w, "             first problems:").context(errors::WriteStats)?;
+            for problem in self.0.iter().take(first) {
+                // Keep each problem, with any causes, on one line.
+                let text = problem.to_string().replace("\n  caused by: ", ": ");
+                writeln!(w, "                    {}", text).context(errors::WriteStats)?;
+            }
+            if self.0.len() > first {
+                writeln!(
+                    w,
+                    "                    and {} more problems",
+                    (self.0.len() - first).separate_with_commas()
+                )
+                .context(errors::WriteStats)?;
+            }
+        }
+        if dirs.len() > 1 || dirs.iter().any(|(_, count)| *count > 1) {
+            writeln!(w, "             most problems in:").context(errors::WriteStats)?;
+            for (dir, count) in dirs.iter().take(top_dirs) {
+                writeln!(w, "{:>12}        {}", count.separate_with_commas(), dir)
+                    .context(errors::WriteStats)?;
+            }
+            if dirs.len() > top_dirs {
+                writeln!(
+                    w,
+                    "                    and {} more directories",
+                    dirs.len() - top_dirs
+                )
+                .context(errors::WriteStats)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Group the entries skipped because of their permissions by the
+    /// outermost unreadable directory containing them.
+    ///
+    /// Returns the apath of each such directory, or of the parent of an
+    /// unreadable file, with the number of skipped entries under it, in apath
+    /// order.
+    pub fn permission_denied_summary(&self) -> Vec<(String, usize)> {
+        let denied: Vec<(&str, Kind)> = self
+            .0
+            .iter()
+            .filter_map(|p| match p {
+                Problem::PermissionDenied { apath, entry_kind } => {
+                    Some((apath.as_str(), *entry_kind))
+                }
+                _ => None,
+            })
+            .collect();
+        let dirs: BTreeSet<&str> = denied
+            .iter()
+            .filter(|(_, kind)| *kind == Kind::Dir)
+            .map(|(apath, _)| *apath)
+            .collect();
+        let mut counts: BTreeMap<String, usize> = BTreeMap::new();
+        for (apath, kind) in denied {
+            let group = if kind == Kind::Dir {
+                apath
+            } else {
+                parent_apath(apath)
+            };
+            // Attribute the entry to the outermost unreadable directory above it.
+            let group = ancestors(group).find(|a| dirs.contains(a)).unwrap_or(group);
+            *counts.entry(group.to_owned()).or_default() += 1;
+        }
+        counts.into_iter().collect()
+    }
+}
+
+fn parent_apath(apath: &str) -> &str {
+    match apath.rfind('/') {
+        Some(0) | None => "/",
+        Some(i) => &apath[..i],
+    }
+}
+
+/// The apath and all its ancestors, outermost first.
+fn ancestors(apath: &str) -> impl Iterator<Item = &str> {
+    std::iter::once("/")
+        .chain(
+            apath
+                .match_indices('/')
+                .skip(1)
+                .map(move |(i, _)| &apath[..i]),
+        )
+        .chain(std::iter::once(apath).filter(|a| *a != "/"))
+}
+
+impl Deref for Problems {
+    type Target = [Problem];
+
+    fn deref(&self) -> &[Problem] {
+        &self.0
+    }
+}
+
+impl From<Vec<Problem>> for Problems {
+    fn from(problems: Vec<Problem>) -> Problems {
+        Problems(problems)
+    }
+}
+
+impl Add for Problems {
+    type Output = Problems;
+
+    fn add(mut self, other: Problems) -> Problems {
+        self += other;
+        self
+    }
+}
+
+impl AddAssign for Problems {
+    fn add_assign(&mut self, other: Problems) {
+        self.0.extend(other.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn denied(apath: &str, entry_kind: Kind) -> Problem {
+        Problem::PermissionDenied {
+            apath: apath.to_owned(),
+            entry_kind,
+        }
+    }
+
+    #[test]
+    fn summarize_by_kind_and_directory() {
+        let problems = Problems::from(vec![
+            denied("/a/b/one", Kind::File),
+            denied("/a/b/two", Kind::File),
+            Problem::FileDisappeared {
+                apath: "/a/b/three".to_owned(),
+            },
+            Problem::ListDirectory {
+                path: "/src/c".into(),
+                message: "oops".to_owned(),
+            },
+            Problem::FileDisappeared {
+                apath: "/d/four".to_owned(),
+            },
+        ]);
+        let mut out = Vec::new();
+        problems.summarize(&mut out, 2, 2).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "           5      problems:\n\
+             \x20          2        FileDisappeared\n\
+             \x20          1        ListDirectory\n\
+             \x20          2        PermissionDenied\n\
+             \x20            first problems:\n\
+             \x20                   Permission denied reading file \"/a/b/one\"\n\
+             \x20                   Permission denied reading file \"/a/b/two\"\n\
+             \x20                   and 3 more problems\n\
+             \x20            most problems in:\n\
+             \x20          3        /a/b\n\
+             \x20          1        /d\n\
+             \x20                   and 1 more directories\n"
+        );
+    }
+
+    #[test]
+    fn summarize_copy_problems_with_their_apaths() {
+        let problems = Problems::from(vec![Problem::CopyEntry {
+            apath: "/abs".into(),
+            entry_kind: Kind::Symlink,
+            message: "Failed to restore\n  caused by: escaping symlink".to_owned(),
+        }]);
+        let mut out = Vec::new();
+        problems.summarize(&mut out, 10, 10).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "           1      problems:\n\
+             \x20          1        CopyEntry\n\
+             \x20            first problems:\n\
+             \x20                   Failed to copy symlink \"/abs\": \
+             Failed to restore: escaping symlink\n"
+        );
+    }
+
+    #[test]
+    fn ancestors_outermost_first() {
+        assert_eq!(ancestors("/").collect::<Vec<_>>(), ["/"]);
+        assert_eq!(
+            ancestors("/var/lib/private").collect::<Vec<_>>(),
+            ["/", "/var", "/var/lib", "/var/lib/private"]
+        );
+    }
+
+    #[test]
+    fn summarize_permission_denied() {
+        let problems = Problems::from(vec![
+            denied("/var/lib/private", Kind::Dir),
+            denied("/var/lib/private/a", Kind::Dir),
+            denied("/var/lib/private/a/b", Kind::File),
+            Problem::FileDisappeared {
+                apath: "/tmp/x".to_owned(),
+            },
+            denied("/var/lib/private-other/c", Kind::File),
+            denied("/var/lib/private-other/d", Kind::File),
+            denied("/etc/shadow", Kind::File),
+        ]);
+        assert_eq!(
+            problems.permission_denied_summary(),
+            [
+                ("/etc".to_owned(), 1),
+                ("/var/lib/private".to_owned(), 3),
+                ("/var/lib/private-other".to_owned(), 2),
+            ]
+        );
+        assert_eq!(
+            denied("/etc/shadow", Kind::File).to_string(),
+            "Permission denied reading file \"/etc/shadow\""
+        );
+    }
+}
@@ -0,0 +1,965 @@
+// Conserve backup system.
+// Copyright 2020 Martin Pool.
+
+//! Push complete versions from a local archive to a `conserve serve` server,
+//! or to another local archive.
+//!
+//! Each band the destination doesn't have yet is sent as its head, then any
+//! blocks the destination is missing, then the index, and finally its tail, so
+//! the band only appears complete once everything it needs is there.
+//!
+//! If an earlier push was interrupted, the destination has an incomplete copy
+//! of the band. Destinations accept files they already have with the same
+//! content, so the band is simply sent again, and completed.
+//!
+//! Asking whether the destination has each block costs a round trip, so the
+//! blocks it's known to have can be remembered in a local block cache file,
+//! and not asked about again. The cache is kept only while the destination's
+//! block generation is the same, since it changes whenever blocks may have
+//! been removed.
+
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use rayon::prelude::*;
+use serde::Serialize;
+use snafu::ResultExt;
+use tracing::info;
+
+use crate::http::HttpClient;
+use crate::*;
+
+/// By default, send this many index files at once.
+const DEFAULT_BATCH_SIZE: usize = 16;
+
+/// Options for pushing an archive, and a way to run the push.
+///
+/// ```no_run
+/// let stats = conserve::PushOptions::new("/backup/archive", "http://backup-server:8080/")
+///     .token("secret")
+///     .run()
+///     .unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct PushOptions {
+    archive: PathBuf,
+    tree_name: Option<String>,
+    destination: String,
+    token: Option<Secret>,
+    bandwidth_limit: Option<u64>,
+    batch_size: usize,
+    block_cache: Option<PathBuf>,
+}
+
+impl PushOptions {
+    /// Push the local archive at `archive` to `destination`, which is either
+    /// the `http://` URL of a server, or the path of another archive.
+    pub fn new<P: AsRef<Path>>(archive: P, destination: &str) -> PushOptions {
+        PushOptions {
+            archive: archive.as_ref().to_path_buf(),
+            tree_name: None,
+            destination: destination.to_owned(),
+            token: None,
+            bandwidth_limit: None,
+            batch_size: DEFAULT_BATCH_SIZE,
+            block_cache: None,
+        }
+    }
+
+    /// Push from a named tree within the local archive, into the tree of
+    /// the same name in a destination archive.
+    pub fn tree(self, tree_name: &str) -> PushOptions {
+        PushOptions {
+            tree_name: Some(tree_name.to_owned()),
+            ..self
+        }
+    }
+
+    /// Authenticate to the server with this token.
+    pub fn token(self, token: &str) -> PushOptions {
+        PushOptions {
+            token: Some(Secret::new(token)),
+            ..self
+        }
+    }
+
+    /// Send at most this many bytes per second, on average.
+    pub fn bandwidth_limit(self, bytes_per_second: u64) -> PushOptions {
+        PushOptions {
+            bandwidth_limit: Some(bytes_per_second),
+            ..self
+        }
+    }
+
+    /// Send up to this many index files at once.
+    ///
+    /// Over a high-latency connection, sending several at once is much faster
+    /// than waiting for each in turn.
+    pub fn batch_size(self, batch_size: usize) -> PushOptions {
+        PushOptions {
+            batch_size: batch_size.max(1),
+            ..self
+        }
+    }
+
+    /// Remember in this local file which blocks the destination has, so
+    /// that later pushes to the same destination don't ask about them again.
+    ///
+    /// The file should only be used for one destination.
+    pub fn block_cache<P: AsRef<Path>>(self, path: P) -> PushOptions {
+        PushOptions {
+            block_cache: Some(path.as_ref().to_path_buf()),
+            ..self
+        }
+    }
+
+    /// Send every complete band that the destination doesn't yet have
+    /// complete.
+    pub fn run(&self) -> Result<PushStats> {
+        let archive = Archive::open_tree(&self.archive, self.tree_name.as_deref())?;
+        let destination: Box<dyn Destination> = if self.destination.starts_with("http://") {
+            Box::new(HttpClient::new(&self.destination, self.token.clone())?)
+        } else {
+            Box::new(Archive::open_tree(
+                &self.destination,
+                self.tree_name.as_deref(),
+            )?)
+        };
+        let block_cache = match &self.block_cache {
+            Some(path) => Some(BlockCache::open(path, &destination.block_generation()?)?),
+            None => None,
+        };
+        let pusher = Pusher {
+            archive: &archive,
+            destination: destination.as_ref(),
+            throttle: self.bandwidth_limit.map(Throttle::new),
+            batch_size: self.batch_size,
+            block_cache,
+        };
+        let remote_bands = destination.list_bands()?;
+        let mut stats = PushStats::default();
+        for band_id in archive.list_bands()? {
+            let band = Band::open(&archive, &band_id)?;
+            let resume = remote_bands.contains(&band_id);
+            if resume && destination.band_is_complete(&band_id)? {
+                stats.skipped_bands += 1;
+            } else if !band.is_closed()? {
+                info!("Not pushing incomplete band {}", band_id);
+                stats.skipped_bands += 1;
+            } else {
+                pusher.push_band(&band, &mut stats)?;
+                if resume {
+                    info!("Completed partly pushed band {}", band_id);
+                    stats.resumed_bands += 1;
+                } else {
+                    info!("Pushed band {}", band_id);
+                }
+                stats.bands += 1;
+            }
+        }
+        if let Some(block_cache) = &pusher.block_cache {
+            block_cache.flush()?;
+        }
+        Ok(stats)
+    }
+}
+
+/// Counts of what was sent by a push.
+#[derive(Debug, Default, Clone, Eq, PartialEq, Serialize)]
+pub struct PushStats {
+    /// Bands sent to the destination.
+    pub bands: usize,
+    /// Of those, bands that an earlier push left incomplete.
+    pub resumed_bands: usize,
+    /// Bands not sent because the destination had them already, or they're
+    /// incomplete.
+    pub skipped_bands: usize,
+    /// Blocks sent to the destination.
+    pub blocks: usize,
+    /// Uncompressed bytes of the blocks that were sent.
+    pub block_bytes: u64,
+    /// Blocks that the destination already had.
+    pub present_blocks: usize,
+    /// Of those, blocks that the block cache showed it had, without asking.
+    pub cached_blocks: usize,
+}
+
+impl PushStats {
+    /// Write a description of the stats for people to read.
+    pub fn summarize(&self, w: &mut dyn Write) -> Result<()> {
+        writeln!(
+            w,
+            "{:>12}      bands pushed\n\
+             {:>12}      bands resumed\n\
+             {:>12}      bands skipped\n\
+             {:>12}      blocks pushed\n\
+             {:>12} MB   in pushed blocks\n\
+             {:>12}      blocks already present\n\
+             {:>12}      blocks known from the block cache",
+            self.bands,
+            self.resumed_bands,
+            self.skipped_bands,
+            self.blocks,
+            bytes_to_human_mb(self.block_bytes),
+            self.present_blocks,
+            self.cached_blocks
+        )
+        .context(errors::WriteStats)
+    }
+}
+
+/// Somewhere that bands can be pushed.
+trait Destination: Sync {
+    fn list_bands(&self) -> Result<Vec<BandId>>;
+
+    /// True if the destination's copy of the band has its tail.
+    fn band_is_complete(&self, band_id: &BandId) -> Result<bool>;
+
+    fn has_block(&self, hash: &str) -> Result<bool>;
+
+    /// Identifies the generation of the destination's blocks, which changes
+    /// whenever blocks may have been removed.
+    fn block_generation(&self) -> Result<String>;
+
+    /// Store a block, given its uncompressed content.
+    fn put_block(&self, hash: &str, content: &[u8]) -> Result<()>;
+
+    /// Write a file in a band, such as `BANDHEAD` or `i/HUNKMAP`.
+    ///
+    /// Writing a file that's already there with the same content succeeds, so
+    /// that an interrupted push can be resumed.
+    fn put_band_file(&self, band_id: &BandId, file: &str, content: &[u8]) -> Result<()>;
+}
+
+impl Destination for HttpClient {
+    fn list_bands(&self) -> Result<Vec<BandId>> {
+        let (_, body) = self.request("GET", "/bands", None, &[])?;
+        let fail = |message: String| Error::Http {
+            url: "/bands".to_owned(),
+            message,
+        };
+        let names: Vec<String> = serde_json::from_slice(&body)
+            .map_err(|e| fail(format!("can't parse list of bands: {}", e)))?;
+        names
+            .iter()
+            .map(|name| BandId::from_string(name).map_err(|e| fail(e.to_string())))
+            .collect()
+    }
+
+    fn band_is_complete(&self, band_id: &BandId) -> Result<bool> {
+        let path = format!("/bands/{}", band_id);
+        let (_, body) = self.request("GET", &path, None, &[])?;
+        serde_json::from_slice::<serde_json::Value>(&body)
+            .ok()
+            .and_then(|info| info["is_closed"].as_bool())
+            .ok_or_else(|| self.error(&path, "can't parse band info".to_owned()))
+    }
+
+    fn has_block(&self, hash: &str) -> Result<bool> {
+        // Only a 404 means the block is missing: any other failure, such as a
+        // refused token or a server error, must stop the push.
+        let path = format!("/blocks/{}", hash);
+        match self.send("HEAD", &path, None, &[])? {
+            (404, _, _) => Ok(false),
+            (200..=299, _, _) => Ok(true),
+            (_, reason, _) => Err(self.error(&path, reason)),
+        }
+    }
+
+    fn block_generation(&self) -> Result<String> {
+        let (_, body) = self.request("GET", "/generation", None, &[])?;
+        serde_json::from_slice(&body)
+            .map_err(|e| self.error("/generation", format!("can't parse generation: {}", e)))
+    }
+
+    fn put_block(&self, hash: &str, content: &[u8]) -> Result<()> {
+        self.put(&format!("/blocks/{}", hash), content)
+    }
+
+    fn put_band_file(&self, band_id: &BandId, file: &str, content: &[u8]) -> Result<()> {
+        self.put(&format!("/bands/{}/{}", band_id, file), content)
+    }
+}
+
+impl Destination for Archive {
+    fn list_bands(&self) -> Result<Vec<BandId>> {
+        Archive::list_bands(self)
+    }
+
+    fn band_is_complete(&self, band_id: &BandId) -> Result<bool> {
+        Band::open(self, band_id)?.is_closed()
+    }
+
+    fn has_block(&self, hash: &str) -> Result<bool> {
+        self.block_dir().contains(hash)
+    }
+
+    fn block_generation(&self) -> Result<String> {
+        self.block_dir().generation()
+    }
+
+    fn put_block(&self, _hash: &str, content: &[u8]) -> Result<()> {
+        self.block_dir().store_block(content).map(|_| ())
+    }
+
+    fn put_band_file(&self, band_id: &BandId, file: &str, content: &[u8]) -> Result<()> {
+        let path = file
+            .split('/')
+            .fold(self.bands_path().join(band_id.to_string()), |p, f| {
+                p.join(f)
+            });
+        if path.is_file() {
+            if fs::read(&path).ok().as_deref() == Some(content) {
+                return Ok(());
+            } else if file == "BANDHEAD" {
+                return Err(Error::DestinationNotEmpty {
+                    path: path.parent().unwrap().to_path_buf(),
+                });
+            }
+        }
+        fs::create_dir_all(path.parent().unwrap())
+            .and_then(|()| AtomicFile::new(&path))
+            .and_then(|mut f| {
+                f.write_all(content)?;
+                f.close()
+            })
+            .context(errors::WriteBandFile { path })
+    }
+}
+
+/// Delays sending to keep the average rate under a limit.
+struct Throttle {
+    bytes_per_second: u64,
+    start: Instant,
+    sent_bytes: Mutex<u64>,
+}
+
+impl Throttle {
+    fn new(bytes_per_second: u64) -> Throttle {
+        Throttle {
+            bytes_per_second: bytes_per_second.max(1),
+            start: Instant::now(),
+            sent_bytes: Mutex::new(0),
+        }
+    }
+
+    /// Record that `bytes` were sent, and wait until the average rate is
+    /// back under the limit.
+    fn sent(&self, bytes: usize) {
+        let total = {
+            let mut sent_bytes = self.sent_bytes.lock().unwrap();
+            *sent_bytes += bytes as u64;
+            *sent_bytes
+        };
+        let due = Duration::from_secs_f64(total as f64 / self.bytes_per_second as f64);
+        let elapsed = self.start.elapsed();
+        if due > elapsed {
+            sleep(due - elapsed);
+        }
+    }
+}
+
+/// Blocks the destination is known to have, remembered in a local file.
+///
+/// The file holds the destination's block generation, then the hash of each
+/// block, one per line. If the generation has changed, the file is started
+/// again.
+struct BlockCache {
+    known: Mutex<HashSet<String>>,
+    file: Mutex<BufWriter<File>>,
+    path: PathBuf,
+}
+
+impl BlockCache {
+    fn open(path: &Path, generation: &str) -> Result<BlockCache> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e).context(errors::ReadMetadata { path }),
+        };
+        let mut lines = text.lines();
+        let mut known = HashSet::new();
+        let file = if lines.next() == Some(generation) {
+            // A line cut short by an interruption doesn't match any hash,
+            // and neither does the line appended after it.
+            known.extend(lines.map(str::to_owned));
+            OpenOptions::new().append(true).open(path)
+        } else {
+            File::create(path).and_then(|mut f| {
+                writeln!(f, "{}", generation)?;
+                Ok(f)
+            })
+        }
+        .context(errors::WriteMetadata { path })?;
+        Ok(BlockCache {
+            known: Mutex::new(known),
+            file: Mutex::new(BufWriter::new(file)),
+            path: path.to_owned(),
+        })
+    }
+
+    fn contains(&self, hash: &str) -> bool {
+        self.known.lock().unwrap().contains(hash)
+    }
+
+    /// Remember that the destination has this block.
+    fn insert(&self, hash: &str) -> Result<()> {
+        if self.known.lock().unwrap().insert(hash.to_owned()) {
+            writeln!(self.file.lock().unwrap(), "{}", hash)
+                .context(errors::WriteMetadata { path: &self.path })?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.file
+            .lock()
+            .unwrap()
+            .flush()
+            .context(errors::WriteMetadata { path: &self.path })
+    }
+}
+
+struct Pusher<'a> {
+    archive: &'a Archive,
+    destination: &'a dyn Destination,
+    throttle: Option<Throttle>,
+    batch_size: usize,
+    block_cache: Option<BlockCache>,
+}
+
+/// What was done about one block in a push.
+enum BlockOutcome {
+    /// Sent, with this many uncompressed bytes.
+    Sent(u64),
+    /// The destination said it already had it.
+    Present,
+    /// The block cache showed the destination already had it.
+    Cached,
+}
+
+impl Pusher<'_> {
+    fn throttle(&self, bytes: usize) {
+        if let Some(throttle) = &self.throttle {
+            throttle.sent(bytes)
+        }
+    }
+
+    fn put_band_file(&self, band: &Band, file: &str) -> Result<()> {
+        let content = read_file(
+            &file
+                .split('/')
+                .fold(band.path().to_path_buf(), |p, f| p.join(f)),
+        )?;
+        self.destination.put_band_file(band.id(), file, &content)?;
+        self.throttle(content.len());
+        Ok(())
+    }
+
+    /// Send a block, unless the destination has it already.
+    fn push_block(&self, hash: &str) -> Result<BlockOutcome> {
+        if let Some(block_cache) = &self.block_cache {
+            if block_cache.contains(hash) {
+                return Ok(BlockOutcome::Cached);
+            }
+        }
+        let outcome = if self.destination.has_block(hash)? {
+            BlockOutcome::Present
+        } else {
+            let (content, _sizes) = self.archive.block_dir().get_block_content(hash)?;
+            self.destination.put_block(hash, &content)?;
+            self.throttle(content.len());
+            BlockOutcome::Sent(content.len() as u64)
+        };
+        if let Some(block_cache) = &self.block_cache {
+            block_cache.insert(hash)?;
+        }
+        Ok(outcome)
+    }
+
+    fn push_band(&self, band: &Band, stats: &mut PushStats) -> Result<()> {
+        self.put_band_file(band, "BANDHEAD")?;
+
+        let mut hashes: Vec<String> = band
+            .iter_entries()?
+            .flat_map(|entry| entry.addrs.into_iter().map(|addr| addr.hash))
+            .collect();
+        hashes.sort_unstable();
+        hashes.dedup();
+        let pushed = threads::in_transport_pool(|| {
+            hashes
+                .par_iter()
+                .map(|hash| self.push_block(hash))
+                .collect::<Result<Vec<BlockOutcome>>>()
+        })?;
+        for outcome in pushed {
+            match outcome {
+                BlockOutcome::Sent(len) => {
+                    stats.blocks += 1;
+                    stats.block_bytes += len;
+                }
+                BlockOutcome::Present => stats.present_blocks += 1,
+                BlockOutcome::Cached => {
+                    stats.present_blocks += 1;
+                    stats.cached_blocks += 1;
+                }
+            }
+        }
+
+        let mut index_files: Vec<String> = walkdir::WalkDir::new(&band.index_dir_path)
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_type().is_file())
+            .map(|entry| {
+                let relative: Vec<String> = entry
+                    .path()
+                    .strip_prefix(band.path())
+                    .unwrap()
+                    .components()
+                    .map(|c| c.as_os_str().to_string_lossy().into_owned())
+                    .collect();
+                relative.join("/")
+            })
+            .collect();
+        index_files.sort();
+        for batch in index_files.chunks(self.batch_size) {
+            threads::in_transport_pool(|| {
+                batch
+                    .par_iter()
+                    .try_for_each(|file| self.put_band_file(band, file))
+            })?;
+        }
+
+        if band.is_signed() {
+            self.put_band_file(band, "BANDSIG")?;
+        }
+        // Only once every index file has arrived, complete the band.
+        self.put_band_file(band, "BANDTAIL")
+    }
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>> {
+    fs::read(path).context(errors::ReadMetadata {
+        path: path.to_path_buf(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::thread;
+
+    use super::*;
+    use crate::test_fixtures::{ScratchArchive, TreeFixture};
+
+    fn start_server(archive: &Archive, token: &str) -> SocketAddr {
+        let server = Server::bind(archive.clone(), "127.0.0.1:0")
+            .unwrap()
+            .with_push_token(token);
+        let address = server.local_addr().unwrap();
+        thread::spawn(move || server.run());
+        address
+    }
+
+    #[test]
+    fn push_bands() {
+        let local = ScratchArchive::new();
+        local.store_two_versions();
+        let remote = ScratchArchive::new();
+        let url = format!("http://{}/", start_server(&remote, "s3cret"));
+
+        let stats = PushOptions::new(local.path(), &url)
+            .token("s3cret")
+            .run()
+            .unwrap();
+        assert_eq!(stats.bands, 2);
+        assert_eq!(stats.skipped_bands, 0);
+        assert!(stats.blocks > 0);
+        assert_eq!(
+            remote.list_bands().unwrap(),
+            [BandId::new(&[0]), BandId::new(&[1])]
+        );
+        assert_eq!(
+            remote.referenced_blocks().unwrap(),
+            local.referenced_blocks().unwrap()
+        );
+        remote.validate().unwrap();
+        let local_entries: Vec<IndexEntry> = StoredTree::open_last(&local)
+            .unwrap()
+            .iter_entries()
+            .unwrap()
+            .collect();
+        let remote_entries: Vec<IndexEntry> = StoredTree::open_last(&remote)
+            .unwrap()
+            .iter_entries()
+            .unwrap()
+            .collect();
+        assert_eq!(local_entries, remote_entries);
+
+        // Pushing again sends nothing new.
+        let stats = PushOptions::new(local.path(), &url)
+            .token("s3cret")
+            .run()
+            .unwrap();
+        assert_eq!(stats.bands, 0);
+        assert_eq!(stats.skipped_bands, 2);
+    }
+
+    #[test]
+    fn push_to_local_archive() {
+        let local = ScratchArchive::new();
+        local.store_two_versions();
+        let mirror = ScratchArchive::new();
+
+        let stats = PushOptions::new(local.path(), mirror.path().to_str().unwrap())
+            .run()
+            .unwrap();
+        assert_eq!(stats.bands, 2);
+        assert_eq!(mirror.list_bands().unwrap(), local.list_bands().unwrap());
+        mirror.validate().unwrap();
+    }
+
+    #[test]
+    fn throttle_limits_rate() {
+        let throttle = Throttle::new(10_000);
+        throttle.sent(1000);
+        throttle.sent(1000);
+        assert!(throttle.start.elapsed() >= Duration::from_millis(200));
+    }
+
+    #[test]
+    fn resume_interrupted_push() {
+        let local = ScratchArchive::new();
+        local.store_two_versions();
+        let remote = ScratchArchive::new();
+        let url = format!("http://{}/", start_server(&remote, "s3cret"));
+        // A push that stopped after sending the head and part of the index.
+        let client = HttpClient::new(&url, Some(Secret::new("s3cret"))).unwrap();
+        let band = Band::open(&local, &BandId::new(&[0])).unwrap();
+        client
+            .put(
+                "/bands/b0000/BANDHEAD",
+                &fs::read(band.path().join("BANDHEAD")).unwrap(),
+            )
+            .unwrap();
+        client
+            .put(
+                "/bands/b0000/i/00000/000000000",
+                &fs::read(band.path().join("i/00000/000000000")).unwrap(),
+            )
+            .unwrap();
+        assert!(!Band::open(&remote, &BandId::new(&[0]))
+            .unwrap()
+            .is_closed()
+            .unwrap());
+
+        let stats = PushOptions::new(local.path(), &url)
+            .token("s3cret")
+            .run()
+            .unwrap();
+        assert_eq!(stats.bands, 2);
+        assert_eq!(stats.resumed_bands, 1);
+        remote.validate().unwrap();
+        assert!(Band::open(&remote, &BandId::new(&[0]))
+            .unwrap()
+            .is_closed()
+            .unwrap());
+    }
+
+    #[test]
+    fn resume_push_to_local_archive() {
+        let local = ScratchArchive::new();
+        local.store_two_versions();
+        let mirror = ScratchArchive::new();
+        let band = Band::open(&local, &BandId::new(&[0])).unwrap();
+        mirror
+            .put_band_file(
+                band.id(),
+                "BANDHEAD",
+                &fs::read(band.path().join("BANDHEAD")).unwrap(),
+            )
+            .unwrap();
+
+        let stats = PushOptions::new(local.path(), mirror.path().to_str().unwrap())
+            .run()
+            .unwrap();
+        assert_eq!(stats.bands, 2);
+        assert_eq!(stats.resumed_bands, 1);
+        mirror.validate().unwrap();
+    }
+
+    #[test]
+    fn block_cache_remembers_present_blocks() {
+        let local = ScratchArchive::new();
+        local.store_two_versions();
+        let remote = ScratchArchive::new();
+        let url = format!("http://{}/", start_server(&remote, "s3cret"));
+        let cache_path = local.path().with_extension("blocks");
+        let push = || {
+            PushOptions::new(local.path(), &url)
+                .token("s3cret")
+                .block_cache(&cache_path)
+                .run()
+                .unwrap()
+        };
+        assert!(push().blocks > 0);
+        assert!(cache_path.is_file());
+
+        // Send the last band again, as if it had been lost: the destination
+        // isn't asked about its blocks.
+        let remove_last_band = || fs::remove_dir_all(remote.path().join("b0001")).unwrap();
+        remove_last_band();
+        let stats = push();
+        assert_eq!(stats.bands, 1);
+        assert_eq!(stats.blocks, 0);
+        assert!(stats.cached_blocks > 0);
+        assert_eq!(stats.cached_blocks, stats.present_blocks);
+        remote.validate().unwrap();
+
+        // Once blocks may have been removed, the cache is forgotten.
+        remote.block_dir().new_generation().unwrap();
+        remove_last_band();
+        let stats = push();
+        assert_eq!(stats.cached_blocks, 0);
+        assert!(stats.present_blocks > 0);
+    }
+
+    /// Answer one request with the given status, and return the request.
+    fn answer_once(status: &'static str) -> (SocketAddr, thread::JoinHandle<String>) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            use std::io::{BufRead, BufReader};
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = String::new();
+            for line in BufReader::new(stream.try_clone().unwrap()).lines() {
+                let line = line.unwrap();
+                if line.is_empty() {
+                    break;
+                }
+                request.push_str(&line);
+                request.push('\n');
+            }
+            write!(
+                stream,
+                "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                status
+            )
+            .unwrap();
+            request
+        });
+        (address, handle)
+    }
+
+    #[test]
+    fn only_404_means_block_is_missing() {
+        let hash = "0".repeat(128);
+        let (address, handle) = answer_once("404 Not Found");
+        let client =
+            HttpClient::new(&format!("http://{}/", address), Some(Secret::new("s3cret"))).unwrap();
+        assert!(!client.has_block(&hash).unwrap());
+        handle.join().unwrap();
+
+        for status in &["401 Unauthorized", "500 Internal Server Error"] {
+            let (address, handle) = answer_once(status);
+            let client = HttpClient::new(&format!("http://{}/", address), None).unwrap();
+            let err = client.has_block(&hash).unwrap_err();
+            assert!(err.to_string().contains(status), "{}", err);
+            handle.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn reads_send_token() {
+        let (address, handle) = answer_once("200 OK");
+        let client =
+            HttpClient::new(&format!("http://{}/", address), Some(Secret::new("s3cret"))).unwrap();
+        let _ = client.list_bands();
+        let request = handle.join().unwrap();
+        assert!(request.starts_with("GET /bands "), "{}", request);
+        assert!(
+            request.contains("Authorization: Bearer s3cret\n"),
+            "{}",
+            request
+        );
+    }
+
+    #[test]
+    fn push_needs_token() {
+        let local = ScratchArchive::new();
+        local.store_two_versions();
+        let remote = ScratchArchive::new();
+        let url = format!("http://{}/", start_server(&remote, "s3cret"));
+
+        let err = PushOptions::new(local.path(), &url)
+            .token("guess")
+            .run()
+            .unwrap_err();
+        assert!(err.to_string().contains("401 Unauthorized"), "{}", err);
+        assert!(PushOptions::new(local.path(), &url).run().is_err());
+        assert_eq!(remote.list_bands().unwrap(), []);
+    }
+
+    #[test]
+    fn server_is_append_only() {
+        let remote = ScratchArchive::new();
+        let address = start_server(&remote, "s3cret");
+        let client =
+            HttpClient::new(&format!("http://{}", address), Some(Secret::new("s3cret"))).unwrap();
+        let head = br#"{"start_time":0,"band_format_version":"0.6.3"}"#;
+        client.put("/bands/b0000/BANDHEAD", head).unwrap();
+        // Sending the same head again is fine, but it can't be replaced, and
+        // files can't be written outside the band.
+        client.put("/bands/b0000/BANDHEAD", head).unwrap();
+        assert!(client
+            .put(
+                "/bands/b0000/BANDHEAD",
+                br#"{"start_time":1,"band_format_version":"0.6.3"}"#
+            )
+            .is_err());
+        assert!(client.put("/bands/b0000/../CONSERVE", b"{}").is_err());
+        assert!(client.put("/bands/b0000/i/junk", b"").is_err());
+
+        // A band can't be completed while its blocks are missing.
+        let block = b"hello world".to_vec();
+        let hash = blockdir::hash_bytes(&block).unwrap();
+        let entries = vec![IndexEntry {
+            apath: "/hello".into(),
+            kind: Kind::File,
+            mtime: 0,
+            mtime_nanos: 0,
+            addrs: vec![blockdir::Address {
+                hash: hash.clone(),
+                start: 0,
+                len: block.len() as u64,
+            }],
+            target: None,
+            ntfs: None,
+            statx: None,
+        }];
+        let mut hunk = Vec::new();
+        Snappy::compress_and_write(&serde_json::to_vec(&entries).unwrap(), &mut hunk).unwrap();
+        client.put("/bands/b0000/i/00000/000000000", &hunk).unwrap();
+        assert!(client
+            .put("/bands/b0000/BANDTAIL", br#"{"end_time":0}"#)
+            .is_err());
+        // Blocks must match their hash.
+        assert!(client.put(&format!("/blocks/{}", hash), b"other").is_err());
+        client.put(&format!("/blocks/{}", hash), &block).unwrap();
+        client
+            .put("/bands/b0000/BANDTAIL", br#"{"end_time":0}"#)
+            .unwrap();
+        // Once complete the band can't be changed.
+        assert!(client.put("/bands/b0000/i/HUNKMAP", b"").is_err());
+        remote.validate().unwrap();
+    }
+
+    #[test]
+    fn band_completes_only_after_all_hunks() {
+        let remote = ScratchArchive::new();
+        let address = start_server(&remote, "s3cret");
+        let client =
+            HttpClient::new(&format!("http://{}", address), Some(Secret::new("s3cret"))).unwrap();
+        let head = br#"{"start_time":0,"band_format_version":"0.6.3"}"#;
+        client.put("/bands/b0000/BANDHEAD", head).unwrap();
+        let hunk = |apath: &str| {
+            let entries = vec![IndexEntry {
+                apath: apath.into(),
+                kind: Kind::Dir,
+                mtime: 0,
+                mtime_nanos: 0,
+                addrs: Vec::new(),
+                target: None,
+                ntfs: None,
+                statx: None,
+            }];
+            let mut hunk = Vec::new();
+            Snappy::compress_and_write(&serde_json::to_vec(&entries).unwrap(), &mut hunk).unwrap();
+            hunk
+        };
+
+        // Hunks can arrive out of order, but the band can't be completed
+        // while there's a gap.
+        client
+            .put("/bands/b0000/i/00000/000000001", &hunk("/b"))
+            .unwrap();
+        assert!(client
+            .put("/bands/b0000/BANDTAIL", br#"{"end_time":0}"#)
+            .is_err());
+        client
+            .put("/bands/b0000/i/00000/000000000", &hunk("/"))
+            .unwrap();
+        client
+            .put("/bands/b0000/BANDTAIL", br#"{"end_time":0}"#)
+            .unwrap();
+        remote.validate().unwrap();
+    }
+
+    #[test]
+    fn server_refuses_corrupt_hunks() {
+        let remote = ScratchArchive::new();
+        let address = start_server(&remote, "s3cret");
+        let client =
+            HttpClient::new(&format!("http://{}", address), Some(Secret::new("s3cret"))).unwrap();
+        let head = br#"{"start_time":0,"band_format_version":"0.6.3"}"#;
+        client.put("/bands/b0000/BANDHEAD", head).unwrap();
+        let mut empty = Vec::new();
+        Snappy::compress_and_write(b"[]", &mut empty).unwrap();
+        for hunk in &[&b"garbage"[..], &[0xff; 64][..], &empty[..]] {
+            let (status, _, _) = client
+                .send("PUT", "/bands/b0000/i/00000/000000000", None, hunk)
+                .unwrap();
+            assert_eq!(status, 400);
+        }
+        assert!(!remote.bands_path().join("b0000/i/00000/000000000").exists());
+        // The server is still answering.
+        let (status, _, _) = client.send("GET", "/bands", None, b"").unwrap();
+        assert_eq!(status, 200);
+    }
+
+    #[test]
+    fn push_one_index_file_at_a_time() {
+        let local = ScratchArchive::new();
+        local.store_two_versions();
+        let remote = ScratchArchive::new();
+        let url = format!("http://{}/", start_server(&remote, "s3cret"));
+        let stats = PushOptions::new(local.path(), &url)
+            .token("s3cret")
+            .batch_size(1)
+            .run()
+            .unwrap();
+        assert_eq!(stats.bands, 2);
+        remote.validate().unwrap();
+    }
+
+    #[test]
+    fn server_enforces_quota() {
+        let local = ScratchArchive::new();
+        let src = TreeFixture::new();
+        src.create_file_with_contents("a", &[b'a'; 1000]);
+        BackupOptions::new(src.path(), local.path()).run().unwrap();
+        let remote = ScratchArchive::new();
+        let server = Server::bind((*remote).clone(), "127.0.0.1:0")
+            .unwrap()
+            .with_push_token("s3cret")
+            .with_quota(0)
+            .unwrap();
+        let url = format!("http://{}/", server.local_addr().unwrap());
+        thread::spawn(move || server.run());
+
+        let err = PushOptions::new(local.path(), &url)
+            .token("s3cret")
+            .run()
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("507 Insufficient Storage"),
+            "{}",
+            err
+        );
+    }
+}
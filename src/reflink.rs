@@ -0,0 +1,135 @@
+// Conserve backup system.
+// Copyright 2020 Martin Pool.
+
+//! Copy a restored file by sharing its extents, on filesystems that support
+//! it, such as btrfs and XFS on Linux, and APFS on macOS.
+//!
+//! On Linux this tries the `FICLONE` ioctl, and then `copy_file_range`, which
+//! the kernel may also implement by sharing extents, or at least without
+//! copying through user space. On macOS this is `clonefile`.
+
+use std::io;
+use std::path::Path;
+
+/// Files smaller than this are written normally, since sharing their
+/// extents saves little.
+pub(crate) const MIN_CLONE_SIZE: u64 = 64 << 10;
+
+/// Make a new file at `dest` with the same content as `source`, sharing its
+/// extents if possible.
+///
+/// `dest` must not already exist. On error, nothing is left at `dest`.
+#[cfg(target_os = "linux")]
+pub(crate) fn clone_file(source: &Path, dest: &Path) -> io::Result<()> {
+    use std::fs;
+    use std::os::unix::io::AsRawFd;
+
+    let source_file = fs::File::open(source)?;
+    let len = source_file.metadata()?.len();
+    let dest_file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(dest)?;
+    let cloned = unsafe {
+        libc::ioctl(
+            dest_file.as_raw_fd(),
+            libc::FICLONE,
+            source_file.as_raw_fd(),
+        )
+    } == 0;
+    let result = if cloned {
+        Ok(())
+    } else {
+        copy_file_range(&source_file, &dest_file, len)
+    };
+    if result.is_err() {
+        let _ = fs::remove_file(dest);
+    }
+    result
+}
+
+/// Copy `len` bytes from the start of `source` into `dest` within the kernel.
+#[cfg(target_os = "linux")]
+fn copy_file_range(source: &std::fs::File, dest: &std::fs::File, len: u64) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let mut remaining = len;
+    while remaining > 0 {
+        let copied = unsafe {
+            libc::copy_file_range(
+                source.as_raw_fd(),
+                std::ptr::null_mut(),
+                dest.as_raw_fd(),
+                std::ptr::null_mut(),
+                remaining as usize,
+                0,
+            )
+        };
+        match copied {
+            -1 => return Err(io::Error::last_os_error()),
+            0 => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "source file is shorter than expected",
+                ))
+            }
+            n => remaining -= n as u64,
+        }
+    }
+    Ok(())
+}
+
+/// Make a new file at `dest` with the same content as `source`, sharing its
+/// extents if possible.
+///
+/// `dest` must not already exist. On error, nothing is left at `dest`.
+#[cfg(target_os = "macos")]
+pub(crate) fn clone_file(source: &Path, dest: &Path) -> io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_string = |path: &Path| {
+        CString::new(path.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    };
+    let (source, dest) = (c_string(source)?, c_string(dest)?);
+    if unsafe { libc::clonefile(source.as_ptr(), dest.as_ptr(), 0) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Make a new file at `dest` with the same content as `source`, sharing its
+/// extents if possible.
+///
+/// `dest` must not already exist. On error, nothing is left at `dest`.
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub(crate) fn clone_file(_source: &Path, _dest: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "cloning files isn't supported on this platform",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::TreeFixture;
+
+    #[test]
+    fn clone_or_fail_cleanly() {
+        let tf = TreeFixture::new();
+        let content = "clone me\n".repeat(10_000);
+        let source = tf.create_file_with_contents("source", content.as_bytes());
+        let dest = tf.path().join("dest");
+        match clone_file(&source, &dest) {
+            Ok(()) => assert_eq!(std::fs::read_to_string(&dest).unwrap(), content),
+            Err(_) => assert!(!dest.exists()),
+        }
+        // The destination now exists, or the platform can't clone.
+        let source_dest = tf.path().join("source");
+        assert!(clone_file(&dest, &source_dest).is_err());
+        assert_eq!(std::fs::read_to_string(&source).unwrap(), content);
+    }
+}
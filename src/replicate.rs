@@ -0,0 +1,372 @@
+// Conserve backup system.
+// Copyright 2020 Martin Pool.
+
+//! Replicate an archive to several mirrors, each either a `conserve serve`
+//! server or another local archive.
+//!
+//! Mirrors are listed in a json config file:
+//!
+//! ```json
+//! {
+//!   "mirrors": [
+//!     {
+//!       "name": "offsite",
+//!       "destination": "http://backup.example.com:8080/",
+//!       "token": { "command": "pass show conserve/offsite" },
+//!       "priority": 10,
+//!       "interval_secs": 86400,
+//!       "bandwidth_limit": 1000000
+//!     },
+//!     { "name": "usb", "destination": "/mnt/usb/archive" }
+//!   ]
+//! }
+//! ```
+//!
+//! Mirrors with higher priority are replicated first. A mirror with an
+//! interval is replicated at most that often, and a bandwidth limit caps the
+//! average bytes per second sent to it.
+//!
+//! A state file records, for each mirror, which bands have been replicated
+//! and when, so that mirrors that are up to date or not due aren't contacted.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::jsonio::{read_json_metadata_file, write_json_metadata_file};
+use crate::*;
+
+/// The mirrors to replicate to.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReplicateConfig {
+    pub mirrors: Vec<MirrorConfig>,
+}
+
+impl ReplicateConfig {
+    /// Read a config file.
+    pub fn load(path: &Path) -> Result<ReplicateConfig> {
+        read_json_metadata_file(path)
+    }
+}
+
+/// One mirror of an archive.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct MirrorConfig {
+    /// Identifies the mirror in the state file and in messages.
+    pub name: String,
+    /// The `http://` URL of a server, or the path of another archive.
+    pub destination: String,
+    /// A file holding the token to authenticate to the server.
+    #[serde(default)]
+    pub token_file: Option<PathBuf>,
+    /// Where to get the token, if not from `token_file`: for example
+    /// `{"env": "OFFSITE_TOKEN"}` or `{"command": "pass show offsite"}`.
+    #[serde(default)]
+    pub token: Option<CredentialSource>,
+    /// Mirrors with a higher priority are replicated first.
+    #[serde(default)]
+    pub priority: i32,
+    /// Replicate at most this often.
+    #[serde(default)]
+    pub interval_secs: Option<u64>,
+    /// Send at most this many bytes per second.
+    #[serde(default)]
+    pub bandwidth_limit: Option<u64>,
+}
+
+/// What's been replicated to one mirror.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct MirrorState {
+    /// Bands known to be complete on the mirror.
+    pub bands: Vec<String>,
+    /// Unix time of the last attempt to replicate.
+    pub last_attempt: Option<i64>,
+    /// Unix time of the last successful replication.
+    pub last_success: Option<i64>,
+    /// Why the last attempt failed, if it did.
+    pub last_error: Option<String>,
+}
+
+/// The state of every mirror, by name.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+struct ReplicateState {
+    mirrors: BTreeMap<String, MirrorState>,
+}
+
+/// What happened to one mirror.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct MirrorResult {
+    pub name: String,
+    pub outcome: MirrorOutcome,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MirrorOutcome {
+    /// New bands or blocks were pushed, or the mirror already had them.
+    Pushed(PushStats),
+    /// The state file shows the mirror already has every complete band.
+    UpToDate,
+    /// The mirror was replicated less than its interval ago.
+    NotDue,
+    /// Replication failed with this error.
+    Failed(String),
+}
+
+/// Options for replicating an archive, and a way to run it.
+///
+/// ```no_run
+/// let config = conserve::ReplicateConfig::load("mirrors.json".as_ref()).unwrap();
+/// let results = conserve::ReplicateOptions::new("/backup/archive", config)
+///     .state_file("mirrors.json.state")
+///     .run()
+///     .unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct ReplicateOptions {
+    archive: PathBuf,
+    tree_name: Option<String>,
+    config: ReplicateConfig,
+    state_file: Option<PathBuf>,
+    force: bool,
+}
+
+impl ReplicateOptions {
+    pub fn new<P: AsRef<Path>>(archive: P, config: ReplicateConfig) -> ReplicateOptions {
+        ReplicateOptions {
+            archive: archive.as_ref().to_path_buf(),
+            tree_name: None,
+            config,
+            state_file: None,
+            force: false,
+        }
+    }
+
+    /// Replicate a named tree within the archive.
+    pub fn tree(self, tree_name: &str) -> ReplicateOptions {
+        ReplicateOptions {
+            tree_name: Some(tree_name.to_owned()),
+            ..self
+        }
+    }
+
+    /// Read and update the state of each mirror in this file.
+    ///
+    /// Without a state file, every mirror is contacted every time.
+    pub fn state_file<P: AsRef<Path>>(self, path: P) -> ReplicateOptions {
+        ReplicateOptions {
+            state_file: Some(path.as_ref().to_path_buf()),
+            ..self
+        }
+    }
+
+    /// Replicate to every mirror, even if it's not due or seems up to date.
+    pub fn force(self, force: bool) -> ReplicateOptions {
+        ReplicateOptions { force, ..self }
+    }
+
+    /// Replicate to each mirror in priority order.
+    ///
+    /// A mirror failing doesn't stop the others from being replicated: its
+    /// error is returned in its result.
+    pub fn run(&self) -> Result<Vec<MirrorResult>> {
+        let archive = Archive::open_tree(&self.archive, self.tree_name.as_deref())?;
+        let mut complete_bands = Vec::new();
+        for band_id in archive.list_bands()? {
+            if Band::open(&archive, &band_id)?.is_closed()? {
+                complete_bands.push(band_id.to_string());
+            }
+        }
+        let mut state: ReplicateState = match &self.state_file {
+            Some(path) if path.exists() => read_json_metadata_file(path)?,
+            _ => ReplicateState::default(),
+        };
+        let mut mirrors: Vec<&MirrorConfig> = self.config.mirrors.iter().collect();
+        mirrors.sort_by_key(|mirror| -mirror.priority);
+        let mut results = Vec::new();
+        for mirror in mirrors {
+            let mirror_state = state.mirrors.entry(mirror.name.clone()).or_default();
+            let now = chrono::Utc::now().timestamp();
+            let outcome = if !self.force
+                && complete_bands
+                    .iter()
+                    .all(|band| mirror_state.bands.contains(band))
+            {
+                MirrorOutcome::UpToDate
+            } else if !self.force
+                && matches!(
+                    (mirror.interval_secs, mirror_state.last_success),
+                    (Some(interval), Some(last)) if now - last < interval as i64
+                )
+            {
+                MirrorOutcome::NotDue
+            } else {
+                mirror_state.last_attempt = Some(now);
+                match self.push(mirror) {
+                    Ok(stats) => {
+                        info!("Replicated to {}", mirror.name);
+                        mirror_state.bands = complete_bands.clone();
+                        mirror_state.last_success = Some(now);
+                        mirror_state.last_error = None;
+                        MirrorOutcome::Pushed(stats)
+                    }
+                    Err(err) => {
+                        warn!("Failed to replicate to {}: {}", mirror.name, err);
+                        mirror_state.last_error = Some(err.to_string());
+                        MirrorOutcome::Failed(err.to_string())
+                    }
+                }
+            };
+            if let Some(path) = &self.state_file {
+                write_json_metadata_file(path, &state)?;
+            }
+            results.push(MirrorResult {
+                name: mirror.name.clone(),
+                outcome,
+            });
+        }
+        Ok(results)
+    }
+
+    fn push(&self, mirror: &MirrorConfig) -> Result<PushStats> {
+        let mut options = PushOptions::new(&self.archive, &mirror.destination);
+        if let Some(tree_name) = &self.tree_name {
+            options = options.tree(tree_name);
+        }
+        let token_source = mirror
+            .token_file
+            .clone()
+            .map(CredentialSource::File)
+            .or_else(|| mirror.token.clone());
+        if let Some(token_source) = token_source {
+            options = options.token(token_source.get()?.expose());
+        }
+        if let Some(bandwidth_limit) = mirror.bandwidth_limit {
+            options = options.bandwidth_limit(bandwidth_limit);
+        }
+        options.run()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{ScratchArchive, TreeFixture};
+
+    fn mirror(name: &str, destination: &Path) -> MirrorConfig {
+        MirrorConfig {
+            name: name.to_owned(),
+            destination: destination.to_str().unwrap().to_owned(),
+            token_file: None,
+            token: None,
+            priority: 0,
+            interval_secs: None,
+            bandwidth_limit: None,
+        }
+    }
+
+    #[test]
+    fn replicate_to_local_mirrors() {
+        let af = ScratchArchive::new();
+        af.store_two_versions();
+        let usb = ScratchArchive::new();
+        let nas = ScratchArchive::new();
+        let state_dir = TreeFixture::new();
+        let state_file = state_dir.path().join("state.json");
+        let config = ReplicateConfig {
+            mirrors: vec![
+                mirror("usb", usb.path()),
+                MirrorConfig {
+                    priority: 1,
+                    ..mirror("nas", nas.path())
+                },
+                mirror("gone", &state_dir.path().join("nonexistent")),
+            ],
+        };
+        let options = ReplicateOptions::new(af.path(), config).state_file(&state_file);
+
+        let results = options.run().unwrap();
+        let names: Vec<&str> = results.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, ["nas", "usb", "gone"]);
+        match &results[0].outcome {
+            MirrorOutcome::Pushed(stats) => assert_eq!(stats.bands, 2),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(matches!(results[2].outcome, MirrorOutcome::Failed(_)));
+        for mirror in &[&usb, &nas] {
+            assert_eq!(mirror.list_bands().unwrap(), af.list_bands().unwrap());
+            mirror.validate().unwrap();
+        }
+
+        let state: ReplicateState = read_json_metadata_file(&state_file).unwrap();
+        assert_eq!(state.mirrors["usb"].bands, ["b0000", "b0001"]);
+        assert!(state.mirrors["usb"].last_success.is_some());
+        assert!(state.mirrors["gone"].last_error.is_some());
+
+        // Mirrors that are up to date aren't contacted again, but failed
+        // mirrors are retried.
+        let results = options.run().unwrap();
+        assert_eq!(results[0].outcome, MirrorOutcome::UpToDate);
+        assert_eq!(results[1].outcome, MirrorOutcome::UpToDate);
+        assert!(matches!(results[2].outcome, MirrorOutcome::Failed(_)));
+
+        // Forcing it contacts them anyway, but finds nothing to send.
+        let results = options.clone().force(true).run().unwrap();
+        match &results[0].outcome {
+            MirrorOutcome::Pushed(stats) => {
+                assert_eq!(stats.bands, 0);
+                assert_eq!(stats.skipped_bands, 2);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn mirror_not_due() {
+        let af = ScratchArchive::new();
+        let src = TreeFixture::new();
+        src.create_file("hello");
+        BackupOptions::new(src.path(), af.path()).run().unwrap();
+        let usb = ScratchArchive::new();
+        let state_dir = TreeFixture::new();
+        let config = ReplicateConfig {
+            mirrors: vec![MirrorConfig {
+                interval_secs: Some(3600),
+                ..mirror("usb", usb.path())
+            }],
+        };
+        let options =
+            ReplicateOptions::new(af.path(), config).state_file(state_dir.path().join("state"));
+        assert!(matches!(
+            options.run().unwrap()[0].outcome,
+            MirrorOutcome::Pushed(_)
+        ));
+
+        // A new backup isn't replicated until the interval has passed.
+        BackupOptions::new(src.path(), af.path()).run().unwrap();
+        assert_eq!(options.run().unwrap()[0].outcome, MirrorOutcome::NotDue);
+        assert_eq!(usb.list_bands().unwrap().len(), 1);
+        options.clone().force(true).run().unwrap();
+        assert_eq!(usb.list_bands().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn parse_config() {
+        let config: ReplicateConfig = serde_json::from_str(
+            r#"{"mirrors": [
+                {"name": "usb", "destination": "/mnt/usb", "bandwidth_limit": 1000}
+            ]}"#,
+        )
+        .unwrap();
+        assert_eq!(config.mirrors[0].bandwidth_limit, Some(1000));
+        assert_eq!(config.mirrors[0].priority, 0);
+        assert!(serde_json::from_str::<ReplicateConfig>(
+            r#"{"mirrors": [{"name": "usb", "destination": "/mnt/usb", "speed": 1}]}"#
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,264 @@
+// Conserve backup system.
+// Copyright 2020 Martin Pool.
+
+//! Reports of the result of a command, sent to webhooks or local commands so
+//! that people can hear about backups that fail.
+
+use std::io::Write;
+use std::path::Path;
+use std::process::Stdio;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::http::HttpClient;
+use crate::misc::shell_command;
+use crate::*;
+
+/// Give up on a webhook that doesn't answer for this long, so that a broken
+/// one only delays the command briefly.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A summary of how a command finished, sent as JSON.
+///
+/// ```no_run
+/// let result = conserve::BackupOptions::new("/home/me/src", "/backup/archive").run();
+/// conserve::Report::new("backup", "/backup/archive", &result)
+///     .send_webhook("http://alerts.example.com/conserve")
+///     .unwrap();
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Report {
+    /// The command that ran, such as `backup` or `validate`.
+    pub command: String,
+    /// The archive it ran on.
+    pub archive: String,
+    /// True if the command succeeded, although there may still have been
+    /// some non-fatal errors counted in the stats.
+    pub succeeded: bool,
+    /// The error that stopped the command, if it failed.
+    pub error: Option<String>,
+    /// Stats from the command, if it succeeded.
+    pub stats: Option<serde_json::Value>,
+}
+
+impl Report {
+    /// Describe the result of `command` on `archive`.
+    pub fn new<S: Serialize>(command: &str, archive: &str, result: &Result<S>) -> Report {
+        let (error, stats) = match result {
+            Ok(stats) => (None, serde_json::to_value(stats).ok()),
+            Err(err) => (Some(err.to_string()), None),
+        };
+        Report {
+            command: command.to_owned(),
+            archive: archive.to_owned(),
+            succeeded: error.is_none(),
+            error,
+            stats,
+        }
+    }
+
+    /// The report as JSON.
+    pub fn to_json(&self) -> Vec<u8> {
+        serde_json::to_vec_pretty(self).expect("report can be serialized")
+    }
+
+    /// Post the report as JSON to an `http://` URL.
+    ///
+    /// This fails, rather than waiting, if the server doesn't answer within
+    /// 30 seconds.
+    pub fn send_webhook(&self, url: &str) -> Result<()> {
+        HttpClient::new(url, None)?
+            .timeout(WEBHOOK_TIMEOUT)
+            .post_json(&self.to_json())
+    }
+
+    /// Run a shell command with the report as JSON on its stdin.
+    ///
+    /// This can send the report by mail, for example with
+    /// `mail -s 'Conserve backup' me@example.com`, or to an https URL with
+    /// curl.
+    pub fn run_command(&self, command: &str) -> Result<()> {
+        let fail = |message: String| Error::NotifyCommand {
+            command: command.to_owned(),
+            message,
+        };
+        let mut child = shell_command(command)
+            .env(
+                "CONSERVE_RESULT",
+                if self.succeeded {
+                    "succeeded"
+                } else {
+                    "failed"
+                },
+            )
+            .stdin(Stdio::piped())
+            .spawn()
+            .map_err(|e| fail(e.to_string()))?;
+        // The command might not read its input, so ignore errors writing it.
+        let _ = child.stdin.take().unwrap().write_all(&self.to_json());
+        let status = child.wait().map_err(|e| fail(e.to_string()))?;
+        if status.success() {
+            Ok(())
+        } else {
+            Err(fail(status.to_string()))
+        }
+    }
+}
+
+/// The outcome of the latest run of a command, kept in a json state file so
+/// that monitoring can check that backups are recent without parsing logs.
+///
+/// The file is rewritten atomically at the end of every run, keeping the time
+/// of the last success across runs that fail.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RunState {
+    /// The command that ran, such as `backup` or `validate`.
+    pub command: String,
+    /// The archive it ran on.
+    pub archive: String,
+    /// When the last run finished, in seconds since the Unix epoch.
+    pub last_run_time: i64,
+    /// True if the last run succeeded.
+    pub last_run_succeeded: bool,
+    /// The error that stopped the last run, if it failed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+    /// When a run last succeeded, in seconds since the Unix epoch, if one
+    /// ever has.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_success_time: Option<i64>,
+    /// The latest complete version in the archive after the last run.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub band_id: Option<String>,
+    /// The number of non-fatal errors in the last run.
+    pub error_count: u64,
+}
+
+impl RunState {
+    /// Record the result in `report` in the state file at `path`, and return
+    /// the new state.
+    ///
+    /// If the existing file can't be read, it's replaced.
+    pub fn update(
+        path: &Path,
+        report: &Report,
+        band_id: Option<&BandId>,
+        error_count: u64,
+    ) -> Result<RunState> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs() as i64);
+        let previous: Option<RunState> = if path.exists() {
+            jsonio::read_json_metadata_file(path).ok()
+        } else {
+            None
+        };
+        let last_success_time = if report.succeeded {
+            Some(now)
+        } else {
+            previous.and_then(|p| p.last_success_time)
+        };
+        let state = RunState {
+            command: report.command.clone(),
+            archive: report.archive.clone(),
+            last_run_time: now,
+            last_run_succeeded: report.succeeded,
+            last_error: report.error.clone(),
+            last_success_time,
+            band_id: band_id.map(BandId::to_string),
+            error_count,
+        };
+        jsonio::write_json_metadata_file(path, &state)?;
+        Ok(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Read};
+    use std::net::TcpListener;
+    use std::thread;
+
+    use super::*;
+    use crate::stats::ValidateArchiveStats;
+
+    #[test]
+    fn report_failure() {
+        let result: Result<ValidateArchiveStats> = Err(Error::ArchiveEmpty);
+        let report = Report::new("validate", "/backup", &result);
+        assert!(!report.succeeded);
+        assert_eq!(report.error.as_deref(), Some("Archive has no bands"));
+        assert_eq!(report.stats, None);
+    }
+
+    #[test]
+    fn send_webhook() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hooks/conserve", listener.local_addr().unwrap());
+        let receiver = thread::spawn(move || {
+            let mut reader = BufReader::new(listener.accept().unwrap().0);
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line == "\r\n" {
+                    break;
+                } else if let Some(len) = line.strip_prefix("Content-Length: ") {
+                    content_length = len.trim().parse().unwrap();
+                }
+            }
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).unwrap();
+            reader
+                .get_mut()
+                .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+                .unwrap();
+            (request_line, body)
+        });
+
+        let result = Ok(ValidateArchiveStats::default());
+        let report = Report::new("validate", "/backup", &result);
+        report.send_webhook(&url).unwrap();
+        let (request_line, body) = receiver.join().unwrap();
+        assert_eq!(request_line, "POST /hooks/conserve HTTP/1.1\r\n");
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["command"], "validate");
+        assert_eq!(json["succeeded"], true);
+        assert_eq!(json["stats"]["block_dir_stats"]["block_error_count"], 0);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn command_failure_is_an_error() {
+        let report = Report::new("backup", "/backup", &Ok(()));
+        report.run_command("cat >/dev/null").unwrap();
+        let err = report.run_command("exit 3").unwrap_err();
+        assert!(err.to_string().contains("exit status: 3"), "{}", err);
+    }
+
+    #[test]
+    fn run_state_keeps_last_success() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("state.json");
+        let band_id = BandId::new(&[3]);
+
+        let report = Report::new("backup", "/backup", &Ok(()));
+        let state = RunState::update(&path, &report, Some(&band_id), 2).unwrap();
+        assert!(state.last_run_succeeded);
+        assert_eq!(state.last_success_time, Some(state.last_run_time));
+        assert_eq!(state.band_id.as_deref(), Some("b0003"));
+        assert_eq!(state.error_count, 2);
+
+        let result: Result<()> = Err(Error::ArchiveEmpty);
+        let report = Report::new("backup", "/backup", &result);
+        let failed = RunState::update(&path, &report, Some(&band_id), 0).unwrap();
+        assert!(!failed.last_run_succeeded);
+        assert_eq!(failed.last_error.as_deref(), Some("Archive has no bands"));
+        assert_eq!(failed.last_success_time, state.last_success_time);
+        let read: RunState = jsonio::read_json_metadata_file(&path).unwrap();
+        assert_eq!(read, failed);
+    }
+}
@@ -2,21 +2,283 @@
 
 //! Restore from the archive to the filesystem.
 
+use std::collections::HashMap;
 use std::fs;
-use std::io;
-use std::path::{Path, PathBuf};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
 
 use snafu::ResultExt;
+use tracing::{debug, error, info_span, warn, Span};
 
 use super::entry::Entry;
-use super::io::{directory_is_empty, ensure_dir_exists};
+use super::io::{apath_path, directory_is_empty, ensure_dir_exists, long_path};
 use super::stats::CopyStats;
+use super::unix_time::UnixTime;
 use super::*;
 
+/// Longest symlink target that's restored, in bytes: Linux's limit, less the
+/// terminating NUL.
+const MAX_SYMLINK_TARGET_LEN: usize = 4095;
+
+/// What to do with a symlink whose target is outside the restore destination,
+/// either as an absolute path or by climbing out with `..`.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum EscapingSymlinks {
+    /// Restore them like any other symlink.
+    #[default]
+    Allow,
+    /// Restore them, with a warning.
+    Warn,
+    /// Don't restore them, and count them as problems.
+    Refuse,
+}
+
+/// The span holding the events of a restore from `archive` into
+/// `destination`.
+///
+/// Programs that drive a `RestoreTree` themselves can enter it so their
+/// events look the same as those from `RestoreOptions::run`.
+pub fn restore_span(archive: &Path, destination: &Path) -> Span {
+    info_span!("restore", archive = %archive.display(), destination = %destination.display())
+}
+
+/// Options for restoring a version, for programs that embed Conserve.
+///
+/// ```no_run
+/// let stats = conserve::RestoreOptions::new("/backup/archive", "/tmp/restored")
+///     .exclude("/.cache")
+///     .run()
+///     .unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct RestoreOptions {
+    archive: PathBuf,
+    destination: PathBuf,
+    tree_name: Option<String>,
+    band_id: Option<BandId>,
+    incomplete: bool,
+    force_overwrite: bool,
+    excludes: Vec<String>,
+    filter: Option<Filter>,
+    print_filenames: bool,
+    path_maps: Vec<(String, String)>,
+    user_map: HashMap<u32, u32>,
+    group_map: HashMap<u32, u32>,
+    escaping_symlinks: EscapingSymlinks,
+}
+
+impl RestoreOptions {
+    /// Restore from the archive at `archive` into a new directory `destination`.
+    ///
+    /// By default the latest complete version is restored.
+    pub fn new<P: AsRef<Path>, Q: AsRef<Path>>(archive: P, destination: Q) -> RestoreOptions {
+        RestoreOptions {
+            archive: archive.as_ref().to_path_buf(),
+            destination: destination.as_ref().to_path_buf(),
+            tree_name: None,
+            band_id: None,
+            incomplete: false,
+            force_overwrite: false,
+            excludes: Vec::new(),
+            filter: None,
+            print_filenames: false,
+            path_maps: Vec::new(),
+            user_map: HashMap::new(),
+            group_map: HashMap::new(),
+            escaping_symlinks: EscapingSymlinks::default(),
+        }
+    }
+
+    /// Don't restore files matching a glob pattern.
+    pub fn exclude(mut self, pattern: &str) -> RestoreOptions {
+        self.excludes.push(pattern.to_owned());
+        self
+    }
+
+    /// Restore only directories and the entries matching a filter
+    /// expression.
+    pub fn filter(self, filter: Filter) -> RestoreOptions {
+        RestoreOptions {
+            filter: Some(filter),
+            ..self
+        }
+    }
+
+    /// Restore from a named tree within the archive.
+    pub fn tree(self, tree_name: &str) -> RestoreOptions {
+        RestoreOptions {
+            tree_name: Some(tree_name.to_owned()),
+            ..self
+        }
+    }
+
+    /// Restore a specific version, rather than the latest.
+    pub fn band_id(self, band_id: BandId) -> RestoreOptions {
+        RestoreOptions {
+            band_id: Some(band_id),
+            ..self
+        }
+    }
+
+    /// Allow restoring a version that was not completely written.
+    pub fn incomplete(self, incomplete: bool) -> RestoreOptions {
+        RestoreOptions { incomplete, ..self }
+    }
+
+    /// Restore even if the destination already exists and is not empty.
+    pub fn force_overwrite(self, force_overwrite: bool) -> RestoreOptions {
+        RestoreOptions {
+            force_overwrite,
+            ..self
+        }
+    }
+
+    /// Print the name of each file as it's restored.
+    pub fn print_filenames(self, print_filenames: bool) -> RestoreOptions {
+        RestoreOptions {
+            print_filenames,
+            ..self
+        }
+    }
+
+    /// Restore entries at or inside the apath `from` to `to` instead, like
+    /// `/home/alice` to `/home/bob`.
+    ///
+    /// If several maps match an entry, the one with the longest `from` is
+    /// used.
+    pub fn map_path(mut self, from: &str, to: &str) -> RestoreOptions {
+        self.path_maps.push((from.to_owned(), to.to_owned()));
+        self
+    }
+
+    /// Where entries were stored with the ID of the user owning them, give
+    /// those owned by `from` to `to` instead.
+    pub fn map_user(mut self, from: u32, to: u32) -> RestoreOptions {
+        self.user_map.insert(from, to);
+        self
+    }
+
+    /// Where entries were stored with the ID of the group owning them, give
+    /// those owned by `from` to `to` instead.
+    pub fn map_group(mut self, from: u32, to: u32) -> RestoreOptions {
+        self.group_map.insert(from, to);
+        self
+    }
+
+    /// Choose what to do with symlinks pointing outside the destination.
+    pub fn escaping_symlinks(self, escaping_symlinks: EscapingSymlinks) -> RestoreOptions {
+        RestoreOptions {
+            escaping_symlinks,
+            ..self
+        }
+    }
+
+    /// Restore the selected version into the destination.
+    pub fn run(&self) -> Result<CopyStats> {
+        let _span = restore_span(&self.archive, &self.destination).entered();
+        let archive = Archive::open_tree(&self.archive, self.tree_name.as_deref())?;
+        let st = match (&self.band_id, self.incomplete) {
+            (None, false) => StoredTree::open_last(&archive),
+            (None, true) => StoredTree::open_last_incomplete(&archive),
+            (Some(b), false) => StoredTree::open_version(&archive, b),
+            (Some(b), true) => StoredTree::open_incomplete_version(&archive, b),
+        }?
+        .with_excludes(excludes::from_strings(&self.excludes)?);
+        let mut path_maps = Vec::new();
+        for (from, to) in &self.path_maps {
+            if !Apath::is_valid(from) || !Apath::is_valid(to) {
+                return errors::InvalidPathMap {
+                    map: format!("{}={}", from, to),
+                }
+                .fail();
+            }
+            path_maps.push((Apath::from(from.as_str()), Apath::from(to.as_str())));
+        }
+        let rt = if self.force_overwrite {
+            RestoreTree::create_overwrite(&self.destination)
+        } else {
+            RestoreTree::create(&self.destination)
+        }?
+        .with_path_maps(path_maps)
+        .with_user_map(self.user_map.clone())
+        .with_group_map(self.group_map.clone())
+        .with_escaping_symlinks(self.escaping_symlinks);
+        copy_tree(
+            &st,
+            rt,
+            &CopyOptions {
+                print_filenames: self.print_filenames,
+                measure_first: true,
+                filter: self.filter.clone(),
+                ..CopyOptions::default()
+            },
+        )
+    }
+}
+
 /// A write-only tree on the filesystem, as a restore destination.
 #[derive(Debug)]
 pub struct RestoreTree {
     path: PathBuf,
+
+    /// Metadata of the directories restored so far, applied when the restore
+    /// finishes, so that writing their contents doesn't change their mtimes
+    /// and their owners and ACLs don't prevent it.
+    dirs: Vec<DirMetadata>,
+
+    /// Count of entries whose NTFS metadata can't be restored on this
+    /// platform.
+    ntfs_unsupported: usize,
+
+    /// The first file restored with each list of block addresses, from which
+    /// later files with the same content can be cloned.
+    restored_files: HashMap<Vec<blockdir::Address>, PathBuf>,
+
+    /// Prefixes of apaths to restore elsewhere, longest first.
+    path_maps: Vec<(Apath, Apath)>,
+
+    /// Stored user and group IDs to restore as different IDs.
+    user_map: HashMap<u32, u32>,
+    group_map: HashMap<u32, u32>,
+
+    /// Count of entries whose stored owner couldn't be restored, typically
+    /// because restoring ownership needs root.
+    owners_not_restored: usize,
+
+    /// What to do with symlinks pointing outside the destination.
+    escaping_symlinks: EscapingSymlinks,
+}
+
+/// The stored metadata of a restored directory.
+#[derive(Debug)]
+struct DirMetadata {
+    path: PathBuf,
+    mtime: UnixTime,
+    statx: Option<StatxMetadata>,
+    ntfs: Option<NtfsMetadata>,
+}
+
+/// Parse a path map like `/home/alice=/home/bob`, as given to
+/// `conserve restore --map`.
+pub fn parse_path_map(map: &str) -> Result<(Apath, Apath)> {
+    match map.split_once('=') {
+        Some((from, to)) if Apath::is_valid(from) && Apath::is_valid(to) => {
+            Ok((from.into(), to.into()))
+        }
+        _ => errors::InvalidPathMap { map }.fail(),
+    }
+}
+
+/// Parse a user or group ID map like `1000:1001`, as given to
+/// `conserve restore --map-user`.
+pub fn parse_id_map(map: &str) -> Result<(u32, u32)> {
+    let parsed = map
+        .split_once(':')
+        .and_then(|(from, to)| Some((from.parse().ok()?, to.parse().ok()?)));
+    match parsed {
+        Some(ids) => Ok(ids),
+        None => errors::InvalidIdMap { map }.fail(),
+    }
 }
 
 impl RestoreTree {
@@ -30,9 +292,7 @@ impl RestoreTree {
                 path: path.to_path_buf(),
             })?
         {
-            Ok(RestoreTree {
-                path: path.to_path_buf(),
-            })
+            Ok(RestoreTree::new(path))
         } else {
             errors::DestinationNotEmpty { path }.fail()
         }
@@ -40,30 +300,196 @@ impl RestoreTree {
 
     /// Create a RestoreTree, even if the destination directory is not empty.
     pub fn create_overwrite(path: &Path) -> Result<RestoreTree> {
-        Ok(RestoreTree {
-            path: path.to_path_buf(),
-        })
+        Ok(RestoreTree::new(path))
+    }
+
+    fn new(path: &Path) -> RestoreTree {
+        RestoreTree {
+            path: long_path(path),
+            dirs: Vec::new(),
+            ntfs_unsupported: 0,
+            restored_files: HashMap::new(),
+            path_maps: Vec::new(),
+            user_map: HashMap::new(),
+            group_map: HashMap::new(),
+            owners_not_restored: 0,
+            escaping_symlinks: EscapingSymlinks::default(),
+        }
+    }
+
+    /// Where entries were stored with the ID of the user owning them, give
+    /// those owned by the keys of `user_map` to its values instead.
+    pub fn with_user_map(self, user_map: HashMap<u32, u32>) -> RestoreTree {
+        RestoreTree { user_map, ..self }
     }
 
-    fn rooted_path(&self, apath: &Apath) -> PathBuf {
-        // Remove initial slash so that the apath is relative to the destination.
-        self.path.join(&apath[1..])
+    /// Where entries were stored with the ID of the group owning them, give
+    /// those owned by the keys of `group_map` to its values instead.
+    pub fn with_group_map(self, group_map: HashMap<u32, u32>) -> RestoreTree {
+        RestoreTree { group_map, ..self }
+    }
+
+    /// Choose what to do with symlinks pointing outside the destination.
+    pub fn with_escaping_symlinks(self, escaping_symlinks: EscapingSymlinks) -> RestoreTree {
+        RestoreTree {
+            escaping_symlinks,
+            ..self
+        }
+    }
+
+    /// Give a restored entry its stored owner and group, if they were
+    /// stored, after mapping them.
+    #[cfg(unix)]
+    fn restore_owner(&mut self, path: &Path, statx: Option<&StatxMetadata>) {
+        let statx = match statx {
+            Some(statx) if statx.uid.is_some() || statx.gid.is_some() => statx,
+            _ => return,
+        };
+        let map = |ids: &HashMap<u32, u32>, id: u32| ids.get(&id).copied().unwrap_or(id);
+        let uid = statx.uid.map(|uid| map(&self.user_map, uid));
+        let gid = statx.gid.map(|gid| map(&self.group_map, gid));
+        if let Err(e) = std::os::unix::fs::lchown(path, uid, gid) {
+            debug!("Failed to restore owner of {:?}: {}", path, e);
+            self.owners_not_restored += 1;
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn restore_owner(&mut self, _path: &Path, _statx: Option<&StatxMetadata>) {}
+
+    /// Restore entries at or inside the first apath of each pair to the
+    /// second instead.
+    ///
+    /// If several maps match an entry, the one with the longest first apath
+    /// is used.
+    pub fn with_path_maps(self, mut path_maps: Vec<(Apath, Apath)>) -> RestoreTree {
+        path_maps.sort_by_key(|(from, _)| std::cmp::Reverse(from.len()));
+        RestoreTree { path_maps, ..self }
+    }
+
+    /// Apply NTFS metadata to a restored file or directory, if it can be.
+    fn restore_ntfs_metadata(&mut self, path: &Path, metadata: &NtfsMetadata) {
+        if !ntfs::SUPPORTED {
+            self.ntfs_unsupported += 1;
+        } else if let Err(e) = ntfs::write(path, metadata) {
+            error!("Failed to restore NTFS metadata of {:?}: {}", path, e);
+        }
+    }
+
+    /// Remember a file whose content was just written, and restore its
+    /// metadata.
+    fn finish_file<E: Entry>(
+        &mut self,
+        path: &Path,
+        source_entry: &E,
+        clone_key: Option<&[blockdir::Address]>,
+    ) {
+        if let Some(addrs) = clone_key {
+            self.restored_files
+                .entry(addrs.to_vec())
+                .or_insert_with(|| path.to_owned());
+        }
+        self.restore_owner(path, source_entry.statx_metadata());
+        if let Some(metadata) = source_entry.ntfs_metadata() {
+            self.restore_ntfs_metadata(path, metadata);
+        }
+    }
+
+    /// Check a stored symlink target can be restored at `path`, and whether
+    /// it's allowed to point outside the destination.
+    #[cfg_attr(not(unix), allow(dead_code))]
+    fn check_symlink(&self, path: &Path, target: &str) -> Result<()> {
+        let reason = if target.is_empty() {
+            "target is empty".to_owned()
+        } else if target.contains('\0') {
+            "target contains a NUL character".to_owned()
+        } else if target.len() > MAX_SYMLINK_TARGET_LEN {
+            format!("target is {} bytes long", target.len())
+        } else if self.escaping_symlinks != EscapingSymlinks::Allow
+            && escapes_root(&self.path, path, Path::new(target))
+        {
+            if self.escaping_symlinks == EscapingSymlinks::Warn {
+                warn!(
+                    "Restored symlink {:?} points outside the destination, to {:?}",
+                    path, target
+                );
+                return Ok(());
+            }
+            format!("target {:?} is outside the destination", target)
+        } else {
+            return Ok(());
+        };
+        errors::InvalidSymlinkTarget { path, reason }.fail()
+    }
+
+    /// Find where to restore an entry, creating the directories above it if
+    /// it's moved by a path map, since they may not be in the tree.
+    fn rooted_path(&self, apath: &Apath) -> Result<PathBuf> {
+        let mapped = self
+            .path_maps
+            .iter()
+            .find_map(|(from, to)| apath.replace_prefix(from, to));
+        match mapped {
+            Some(mapped) => {
+                let path = apath_path(&self.path, &mapped);
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent).context(errors::Restore { path: parent })?;
+                }
+                Ok(path)
+            }
+            None => Ok(apath_path(&self.path, apath)),
+        }
     }
 }
 
 impl tree::WriteTree for RestoreTree {
-    fn finish(self) -> Result<CopyStats> {
-        // Live tree doesn't need to be finished.
+    /// Apply the metadata of the directories restored so far.
+    fn checkpoint(self) -> Result<CopyStats> {
+        self.finish()
+    }
+
+    fn finish(mut self) -> Result<CopyStats> {
+        // Children before their parents, in case a directory's owner or ACL
+        // prevents changing its contents. The mtime is set last, once
+        // nothing more will be written inside the directory.
+        for dir in std::mem::take(&mut self.dirs).iter().rev() {
+            self.restore_owner(&dir.path, dir.statx.as_ref());
+            if let Some(metadata) = &dir.ntfs {
+                self.restore_ntfs_metadata(&dir.path, metadata);
+            }
+            if let Err(e) = utime::set_file_times(&dir.path, dir.mtime.secs, dir.mtime.secs) {
+                warn!("Failed to restore mtime of {:?}: {}", dir.path, e);
+            }
+        }
+        if self.ntfs_unsupported > 0 {
+            warn!(
+                "NTFS metadata of {} entries can't be restored on this platform",
+                self.ntfs_unsupported
+            );
+        }
+        if self.owners_not_restored > 0 {
+            warn!(
+                "Owner of {} entries couldn't be restored: this usually needs root",
+                self.owners_not_restored
+            );
+        }
         Ok(CopyStats::default())
     }
 
     fn copy_dir<E: Entry>(&mut self, entry: &E) -> Result<()> {
-        let path = self.rooted_path(entry.apath());
+        let path = self.rooted_path(entry.apath())?;
         match fs::create_dir(&path) {
-            Ok(()) => Ok(()),
-            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(()),
-            e => e.context(errors::Restore { path }),
+            Ok(()) => (),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => (),
+            Err(source) => return Err(Error::Restore { path, source }),
         }
+        self.dirs.push(DirMetadata {
+            path,
+            mtime: entry.mtime(),
+            statx: entry.statx_metadata().cloned(),
+            ntfs: entry.ntfs_metadata().cloned(),
+        });
+        Ok(())
     }
 
     /// Copy in the contents of a file from another tree.
@@ -76,13 +502,51 @@ impl tree::WriteTree for RestoreTree {
         // TODO: Reset mtime: can probably use https://docs.rs/utime/0.2.2/utime/
         // TODO: For restore, maybe not necessary to rename into place, and
         // we could just write directly.
-        let path = self.rooted_path(source_entry.apath());
+        let path = self.rooted_path(source_entry.apath())?;
+        // Files of deduplicated content are cloned from the first copy, which
+        // is faster and, on filesystems that share extents, smaller.
+        let clone_key = source_entry
+            .stored_addrs()
+            .filter(|_| source_entry.size().unwrap_or(0) >= reflink::MIN_CLONE_SIZE);
+        if let Some(original) = clone_key.and_then(|addrs| self.restored_files.get(addrs)) {
+            match reflink::clone_file(original, &path) {
+                Ok(()) => {
+                    self.restore_owner(&path, source_entry.statx_metadata());
+                    if let Some(metadata) = source_entry.ntfs_metadata() {
+                        self.restore_ntfs_metadata(&path, metadata);
+                    }
+                    ui::increment_bytes_done(source_entry.size().unwrap_or(0));
+                    return Ok(CopyStats {
+                        uncompressed_bytes: source_entry.size().unwrap_or(0),
+                        cloned_files: 1,
+                        ..CopyStats::default()
+                    });
+                }
+                Err(e) => debug!("Failed to clone {:?} to {:?}: {}", original, path, e),
+            }
+        }
+        // A file left by an earlier restore is updated in place, so that only
+        // the regions that differ are rewritten.
+        if let (Some(addrs), Some(block_dir)) = (source_entry.stored_addrs(), from_tree.block_dir())
+        {
+            if is_updatable_file(&path) {
+                let bytes_rewritten = update_in_place(&path, addrs, block_dir)?;
+                self.finish_file(&path, source_entry, clone_key);
+                return Ok(CopyStats {
+                    uncompressed_bytes: bytes_rewritten,
+                    updated_files: 1,
+                    ..CopyStats::default()
+                });
+            }
+        }
         let ctx = || errors::Restore { path: path.clone() };
         let mut af = AtomicFile::new(&path).with_context(ctx)?;
         // TODO: Read one block at a time: don't pull all the contents into memory.
         let content = &mut from_tree.file_contents(&source_entry)?;
-        let bytes_copied = std::io::copy(content, &mut af).with_context(ctx)?;
-        af.close().context(errors::Restore { path })?;
+        let bytes_copied =
+            std::io::copy(content, &mut ProgressWriter(&mut af)).with_context(ctx)?;
+        af.close().context(errors::Restore { path: path.clone() })?;
+        self.finish_file(&path, source_entry, clone_key);
         // TODO: Accumulate stats.
         Ok(CopyStats {
             uncompressed_bytes: bytes_copied,
@@ -90,15 +554,21 @@ impl tree::WriteTree for RestoreTree {
         })
     }
 
+    fn reports_file_progress(&self) -> bool {
+        true
+    }
+
     #[cfg(unix)]
     fn copy_symlink<E: Entry>(&mut self, entry: &E) -> Result<()> {
         use std::os::unix::fs as unix_fs;
         if let Some(ref target) = entry.symlink_target() {
-            let path = self.rooted_path(entry.apath());
-            unix_fs::symlink(target, &path).context(errors::Restore { path })?;
+            let path = self.rooted_path(entry.apath())?;
+            self.check_symlink(&path, target)?;
+            unix_fs::symlink(target, &path).context(errors::Restore { path: &path })?;
+            self.restore_owner(&path, entry.statx_metadata());
         } else {
             // TODO: Treat as an error.
-            ui::problem(&format!("No target in symlink entry {}", entry.apath()));
+            error!("No target in symlink entry {}", entry.apath());
         }
         Ok(())
     }
@@ -107,21 +577,116 @@ impl tree::WriteTree for RestoreTree {
     fn copy_symlink<E: Entry>(&mut self, entry: &E) -> Result<()> {
         // TODO: Add a test with a canned index containing a symlink, and expect
         // it cannot be restored on Windows and can be on Unix.
-        ui::problem(&format!(
-            "Can't restore symlinks on non-Unix: {}",
-            entry.apath()
-        ));
+        error!("Can't restore symlinks on non-Unix: {}", entry.apath());
         Ok(())
     }
 }
 
+/// True if a symlink at `link`, inside the directory `root`, points outside
+/// it.
+///
+/// Only the paths are compared: symlinks that the target passes through
+/// aren't followed.
+#[cfg_attr(not(unix), allow(dead_code))]
+fn escapes_root(root: &Path, link: &Path, target: &Path) -> bool {
+    // Compare in the canonical form of the root, as absolute targets would
+    // be resolved.
+    let canonical_root = fs::canonicalize(root).unwrap_or_else(|_| root.to_owned());
+    let link = canonical_root.join(link.strip_prefix(root).unwrap_or(link));
+    let mut resolved = PathBuf::new();
+    for component in link.parent().unwrap_or(&link).join(target).components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir => {
+                if let Some(Component::Normal(_)) = resolved.components().next_back() {
+                    resolved.pop();
+                }
+            }
+            c => resolved.push(c),
+        }
+    }
+    !resolved.starts_with(&canonical_root)
+}
+
+/// Counts bytes into the progress bar as they're written.
+struct ProgressWriter<W: io::Write>(W);
+
+/// True if `path` is a regular file that can be updated in place: not a
+/// symlink, and not linked from elsewhere, which would see the change.
+fn is_updatable_file(path: &Path) -> bool {
+    match fs::symlink_metadata(path) {
+        #[cfg(unix)]
+        Ok(metadata) => {
+            use std::os::unix::fs::MetadataExt;
+            metadata.is_file() && metadata.nlink() == 1
+        }
+        #[cfg(not(unix))]
+        Ok(metadata) => metadata.is_file(),
+        Err(_) => false,
+    }
+}
+
+/// Make the existing file at `path` hold the content of `addrs`, rewriting
+/// only the parts that differ.
+///
+/// Each part of the file is hashed and compared to the block it should
+/// come from, so blocks are only read from the archive where the content
+/// differs. Parts taken from within a block, rather than a whole block,
+/// can't be compared this way and are always rewritten.
+///
+/// Returns the number of bytes rewritten.
+fn update_in_place(path: &Path, addrs: &[blockdir::Address], block_dir: &BlockDir) -> Result<u64> {
+    let ctx = || errors::Restore {
+        path: path.to_owned(),
+    };
+    let mut file = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .with_context(ctx)?;
+    let mut pos: u64 = 0;
+    let mut bytes_rewritten: u64 = 0;
+    let mut buf = Vec::new();
+    for addr in addrs {
+        buf.clear();
+        (&mut file)
+            .take(addr.len)
+            .read_to_end(&mut buf)
+            .with_context(ctx)?;
+        if buf.len() as u64 != addr.len || blockdir::hash_bytes(&buf)? != addr.hash {
+            let (content, _sizes) = block_dir.get(addr)?;
+            file.seek(SeekFrom::Start(pos)).with_context(ctx)?;
+            file.write_all(&content).with_context(ctx)?;
+            bytes_rewritten += addr.len;
+        }
+        pos += addr.len;
+        ui::increment_bytes_done(addr.len);
+    }
+    file.set_len(pos).with_context(ctx)?;
+    Ok(bytes_rewritten)
+}
+
+impl<W: io::Write> io::Write for ProgressWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.0.write(buf)?;
+        ui::increment_bytes_done(len as u64);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::fs;
+    use std::path::Path;
 
     use spectral::prelude::*;
 
     use super::super::*;
+    use crate::io::{apath_path, long_path};
     use crate::test_fixtures::{ScratchArchive, TreeFixture};
 
     #[test]
@@ -165,6 +730,93 @@ mod tests {
         assert_eq!(stats.files, 2);
     }
 
+    #[test]
+    fn restore_long_paths() {
+        // Deeper than the 260 character limit on unprefixed Windows paths.
+        let srcdir = TreeFixture::new();
+        let name = "d".repeat(60);
+        let mut apath = String::new();
+        for _ in 0..6 {
+            apath.push('/');
+            apath.push_str(&name);
+            srcdir.create_dir(&apath[1..]);
+        }
+        apath.push_str("/file");
+        srcdir.create_file_with_contents(&apath[1..], b"deep");
+        let af = ScratchArchive::new();
+        let bw = BackupWriter::begin(&af).unwrap();
+        copy_tree(&srcdir.live_tree(), bw, &COPY_DEFAULT).unwrap();
+
+        let destdir = TreeFixture::new();
+        let st = StoredTree::open_last(&af).unwrap();
+        let rt = RestoreTree::create(destdir.path()).unwrap();
+        let stats = copy_tree(&st, rt, &CopyOptions::default()).unwrap();
+        assert_eq!(stats.files, 1);
+        let path = apath_path(&long_path(destdir.path()), &apath);
+        assert!(path.as_os_str().len() > 300);
+        assert_eq!(fs::read(path).unwrap(), b"deep");
+    }
+
+    #[test]
+    fn restore_directory_mtimes() {
+        let srcdir = TreeFixture::new();
+        srcdir.create_dir("empty");
+        srcdir.create_dir("subdir");
+        srcdir.create_file("subdir/subfile");
+        let mtime = 1_500_000_000;
+        for dir in &["", "empty", "subdir"] {
+            utime::set_file_times(srcdir.path().join(dir), mtime, mtime).unwrap();
+        }
+        let af = ScratchArchive::new();
+        let bw = BackupWriter::begin(&af).unwrap();
+        copy_tree(&srcdir.live_tree(), bw, &COPY_DEFAULT).unwrap();
+
+        let destdir = TreeFixture::new();
+        let st = StoredTree::open_last(&af).unwrap();
+        let rt = RestoreTree::create(destdir.path()).unwrap();
+        copy_tree(&st, rt, &CopyOptions::default()).unwrap();
+        // The root and directories with children keep their stored mtimes,
+        // even though their contents were written after they were made.
+        for dir in &["", "empty", "subdir"] {
+            let restored = fs::metadata(destdir.path().join(dir))
+                .unwrap()
+                .modified()
+                .unwrap();
+            assert_eq!(
+                restored,
+                std::time::UNIX_EPOCH + std::time::Duration::from_secs(mtime as u64),
+                "mtime of {:?}",
+                dir
+            );
+        }
+    }
+
+    #[test]
+    fn clone_identical_files() {
+        let srcdir = TreeFixture::new();
+        let big = "duplicated\n".repeat(20_000);
+        srcdir.create_file_with_contents("a", big.as_bytes());
+        srcdir.create_file_with_contents("b", big.as_bytes());
+        srcdir.create_file_with_contents("small1", b"small");
+        srcdir.create_file_with_contents("small2", b"small");
+        let af = ScratchArchive::new();
+        let bw = BackupWriter::begin(&af).unwrap();
+        copy_tree(&srcdir.live_tree(), bw, &COPY_DEFAULT).unwrap();
+
+        let destdir = TreeFixture::new();
+        let st = StoredTree::open_last(&af).unwrap();
+        let rt = RestoreTree::create(destdir.path()).unwrap();
+        let stats = copy_tree(&st, rt, &CopyOptions::default()).unwrap();
+        assert_eq!(stats.files, 4);
+        if cfg!(target_os = "linux") {
+            assert_eq!(stats.cloned_files, 1);
+        }
+        for name in &["a", "b"] {
+            assert_eq!(fs::read_to_string(destdir.path().join(name)).unwrap(), big);
+        }
+        assert_eq!(fs::read(destdir.path().join("small2")).unwrap(), b"small");
+    }
+
     #[test]
     pub fn decline_to_overwrite() {
         let af = ScratchArchive::new();
@@ -194,6 +846,46 @@ mod tests {
         assert_that(&dest.join("existing").as_path()).is_a_file();
     }
 
+    #[test]
+    pub fn update_existing_files_in_place() {
+        let af = ScratchArchive::new();
+        let srcdir = TreeFixture::new();
+        let content: Vec<u8> = (0..40_000u32).map(|i| (i % 251) as u8).collect();
+        srcdir.create_file_with_contents("big", &content);
+        srcdir.create_file_with_contents("short", &content[..10_000]);
+        BackupOptions::new(srcdir.path(), af.path())
+            .tuning(Tuning {
+                block_size: 4096,
+                small_file_size: 0,
+                ..Tuning::default()
+            })
+            .run()
+            .unwrap();
+        let destdir = tempfile::TempDir::new().unwrap();
+        RestoreOptions::new(af.path(), destdir.path())
+            .run()
+            .unwrap();
+
+        // Damage one block's worth of one file, and extend the other.
+        let big_path = destdir.path().join("big");
+        let mut damaged = content.clone();
+        damaged[5000..5100].copy_from_slice(&[0; 100]);
+        fs::write(&big_path, &damaged).unwrap();
+        let short_path = destdir.path().join("short");
+        fs::write(&short_path, &content[..12_345]).unwrap();
+
+        let stats = RestoreOptions::new(af.path(), destdir.path())
+            .force_overwrite(true)
+            .run()
+            .unwrap();
+        assert_eq!(fs::read(&big_path).unwrap(), content);
+        assert_eq!(fs::read(&short_path).unwrap(), &content[..10_000]);
+        assert_eq!(stats.updated_files, 2);
+        // Only the damaged block of /big was rewritten, and nothing of
+        // /short, which just needed truncating.
+        assert_eq!(stats.uncompressed_bytes, 4096);
+    }
+
     #[test]
     pub fn exclude_files() {
         let af = ScratchArchive::new();
@@ -212,4 +904,150 @@ mod tests {
         assert_that(&dest.join("subdir").as_path()).is_a_directory();
         assert_eq!(stats.files, 2);
     }
+
+    #[test]
+    pub fn map_paths() {
+        let af = ScratchArchive::new();
+        af.store_two_versions();
+        let destdir = TreeFixture::new();
+        RestoreOptions::new(af.path(), destdir.path())
+            .map_path("/subdir", "/moved/here")
+            .map_path("/hello", "/greeting")
+            .map_path("/subdir/subfile", "/moved/there/file")
+            .run()
+            .unwrap();
+
+        let dest = &destdir.path();
+        assert_that(&dest.join("greeting")).is_a_file();
+        assert_that(&dest.join("hello2")).is_a_file();
+        assert_that(&dest.join("moved").join("here")).is_a_directory();
+        assert_that(&dest.join("moved").join("there").join("file")).is_a_file();
+        assert!(!dest.join("hello").exists());
+        assert!(!dest.join("subdir").exists());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    pub fn map_owners() {
+        use std::os::unix::fs::MetadataExt;
+
+        let af = ScratchArchive::new();
+        let srcdir = TreeFixture::new();
+        let file = srcdir.create_file("hello");
+        BackupOptions::new(srcdir.path(), af.path())
+            .statx_metadata(true)
+            .run()
+            .unwrap();
+        let source = fs::metadata(&file).unwrap();
+        let destdir = TreeFixture::new();
+        RestoreOptions::new(af.path(), destdir.path())
+            .force_overwrite(true)
+            .map_user(source.uid(), 12345)
+            .map_group(source.gid(), 23456)
+            .run()
+            .unwrap();
+
+        // Only root can give files to other users.
+        if unsafe { libc::geteuid() } == 0 {
+            let restored = fs::metadata(destdir.path().join("hello")).unwrap();
+            assert_eq!(restored.uid(), 12345);
+            assert_eq!(restored.gid(), 23456);
+        }
+        assert_eq!(parse_id_map("1000:1001").unwrap(), (1000, 1001));
+        for bad in &["1000", "a:1", "1:-1", ":"] {
+            assert!(
+                matches!(parse_id_map(bad), Err(Error::InvalidIdMap { .. })),
+                "{}",
+                bad
+            );
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_symlink_targets() {
+        let destdir = TreeFixture::new();
+        let rt = RestoreTree::create_overwrite(destdir.path())
+            .unwrap()
+            .with_escaping_symlinks(EscapingSymlinks::Refuse);
+        let link = destdir.path().join("sub").join("link");
+        rt.check_symlink(&link, "../file").unwrap();
+        rt.check_symlink(&link, "a/./../b").unwrap();
+        let too_long = "a/".repeat(2048);
+        for bad in &[
+            "",
+            "a\0b",
+            &too_long,
+            "../../file",
+            "sub/../../../x",
+            "/etc",
+        ] {
+            assert!(
+                matches!(
+                    rt.check_symlink(&link, bad),
+                    Err(Error::InvalidSymlinkTarget { .. })
+                ),
+                "{:?}",
+                bad
+            );
+        }
+        let inside = destdir.path().canonicalize().unwrap().join("x");
+        rt.check_symlink(&link, inside.to_str().unwrap()).unwrap();
+
+        let rt = rt.with_escaping_symlinks(EscapingSymlinks::Warn);
+        rt.check_symlink(&link, "/etc").unwrap();
+        assert!(rt.check_symlink(&link, "a\0b").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn refuse_escaping_symlinks() {
+        let af = ScratchArchive::new();
+        let srcdir = TreeFixture::new();
+        srcdir.create_file("hello");
+        srcdir.create_dir("sub");
+        srcdir.create_symlink("abs", "/etc/passwd");
+        srcdir.create_symlink("up", "../outside");
+        srcdir.create_symlink("sub/back", "../hello");
+        BackupOptions::new(srcdir.path(), af.path()).run().unwrap();
+
+        let destdir = TreeFixture::new();
+        let stats = RestoreOptions::new(af.path(), destdir.path())
+            .force_overwrite(true)
+            .escaping_symlinks(EscapingSymlinks::Refuse)
+            .run()
+            .unwrap();
+        assert_eq!(stats.errors, 2);
+        let dest = destdir.path();
+        assert!(fs::symlink_metadata(dest.join("abs")).is_err());
+        assert!(fs::symlink_metadata(dest.join("up")).is_err());
+        assert_eq!(
+            fs::read_link(dest.join("sub").join("back")).unwrap(),
+            Path::new("../hello")
+        );
+    }
+
+    #[test]
+    pub fn reject_bad_path_maps() {
+        assert_eq!(
+            parse_path_map("/a=/b/c").unwrap(),
+            ("/a".into(), "/b/c".into())
+        );
+        for bad in &["/a", "a=/b", "/a=b/", "/a=/b/../c"] {
+            assert!(
+                matches!(parse_path_map(bad), Err(Error::InvalidPathMap { .. })),
+                "{}",
+                bad
+            );
+        }
+        let af = ScratchArchive::new();
+        af.store_two_versions();
+        let destdir = TreeFixture::new();
+        assert!(matches!(
+            RestoreOptions::new(af.path(), destdir.path())
+                .map_path("/a", "b")
+                .run(),
+            Err(Error::InvalidPathMap { .. })
+        ));
+    }
 }
@@ -0,0 +1,240 @@
+// Conserve backup system.
+// Copyright 2020 Martin Pool.
+
+//! Remember the entries of the last backup of a source, so that the next
+//! backup can tell which files are unchanged without reading the previous
+//! version's index, which may be slow if the archive is remote.
+//!
+//! Like tar's `--listed-incremental` file, the cache is a local file, not part
+//! of the archive: losing it only means the next backup reads the index. It's
+//! only used if it was written for the version the new backup is based on,
+//! and it's replaced after every complete backup.
+//!
+//! The first line is a json header naming the archive and version. Each
+//! following line is a json index entry, in apath order, plus the inode of
+//! source files where it's known, so that a file replaced by another with
+//! the same size and mtime is still seen as changed.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+use tracing::{info, warn};
+
+use crate::io::AtomicFile;
+use crate::*;
+
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
+struct Header {
+    /// The canonical path of the archive.
+    archive: String,
+    /// The version whose entries follow.
+    band_id: String,
+}
+
+impl Header {
+    fn new(archive_path: &Path, band_id: &BandId) -> Header {
+        Header {
+            archive: archive_path
+                .canonicalize()
+                .unwrap_or_else(|_| archive_path.to_owned())
+                .to_string_lossy()
+                .into_owned(),
+            band_id: band_id.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CachedEntry {
+    #[serde(flatten)]
+    entry: IndexEntry,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    inode: Option<u64>,
+}
+
+/// The entries of a version, read from a scan cache.
+#[derive(Debug)]
+pub(crate) struct ScanCache {
+    /// All the entries of the version's tree, in apath order.
+    pub entries: Vec<IndexEntry>,
+    /// The inodes of source files, where they were known.
+    pub inodes: BTreeMap<Apath, u64>,
+}
+
+impl ScanCache {
+    /// Read the cache at `path`, if it exists and holds the entries of
+    /// `band_id` in the archive at `archive_path`.
+    ///
+    /// A cache that's unreadable json is ignored, with a warning, so that the
+    /// backup reads the index instead.
+    pub fn read(path: &Path, archive_path: &Path, band_id: &BandId) -> Result<Option<ScanCache>> {
+        let ctx = || errors::ReadScanCache {
+            path: path.to_owned(),
+        };
+        let mut lines = match fs::File::open(path) {
+            Ok(f) => BufReader::new(f).lines(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(ctx),
+        };
+        let header: Header = match lines.next().transpose().with_context(ctx)? {
+            Some(line) => match serde_json::from_str(&line) {
+                Ok(header) => header,
+                Err(e) => {
+                    warn!("Ignoring unreadable scan cache {:?}: {}", path, e);
+                    return Ok(None);
+                }
+            },
+            None => return Ok(None),
+        };
+        if header != Header::new(archive_path, band_id) {
+            info!(
+                "Scan cache {:?} is for {} in {:?}, not {}: reading the index",
+                path, header.band_id, header.archive, band_id
+            );
+            return Ok(None);
+        }
+        let mut cache = ScanCache {
+            entries: Vec::new(),
+            inodes: BTreeMap::new(),
+        };
+        for line in lines {
+            let cached: CachedEntry = match serde_json::from_str(&line.with_context(ctx)?) {
+                Ok(cached) => cached,
+                Err(e) => {
+                    warn!("Ignoring unreadable scan cache {:?}: {}", path, e);
+                    return Ok(None);
+                }
+            };
+            if let Some(inode) = cached.inode {
+                cache.inodes.insert(cached.entry.apath.clone(), inode);
+            }
+            cache.entries.push(cached.entry);
+        }
+        Ok(Some(cache))
+    }
+}
+
+/// Collects the entries of a new version as it's written, to be saved as a
+/// scan cache once the version is complete.
+#[derive(Debug)]
+pub(crate) struct ScanCacheWriter {
+    path: PathBuf,
+    header: Header,
+    entries: Vec<CachedEntry>,
+    /// Inodes of source files whose entries aren't yet pushed.
+    inodes: BTreeMap<Apath, u64>,
+}
+
+impl ScanCacheWriter {
+    pub fn new(path: &Path, archive_path: &Path, band_id: &BandId) -> ScanCacheWriter {
+        ScanCacheWriter {
+            path: path.to_owned(),
+            header: Header::new(archive_path, band_id),
+            entries: Vec::new(),
+            inodes: BTreeMap::new(),
+        }
+    }
+
+    /// Remember the inode of a source file, for when its entry is pushed.
+    pub fn source_inode(&mut self, apath: &Apath, inode: Option<u64>) {
+        if let Some(inode) = inode {
+            self.inodes.insert(apath.clone(), inode);
+        }
+    }
+
+    /// Add an entry of the new version's tree, in any order.
+    pub fn push(&mut self, entry: IndexEntry) {
+        let inode = self.inodes.remove(&entry.apath);
+        self.entries.push(CachedEntry { entry, inode });
+    }
+
+    /// Replace the cache file with the entries pushed so far.
+    pub fn write(mut self) -> Result<()> {
+        self.entries
+            .sort_by(|a, b| a.entry.apath.cmp(&b.entry.apath));
+        let ctx = || errors::WriteScanCache {
+            path: self.path.clone(),
+        };
+        let mut af = AtomicFile::new(&self.path).with_context(ctx)?;
+        {
+            let mut w = BufWriter::new(&mut af);
+            serde_json::to_writer(&mut w, &self.header).context(errors::SerializeJson {
+                path: self.path.clone(),
+            })?;
+            w.write_all(b"\n").with_context(ctx)?;
+            for cached in &self.entries {
+                serde_json::to_writer(&mut w, cached).context(errors::SerializeJson {
+                    path: self.path.clone(),
+                })?;
+                w.write_all(b"\n").with_context(ctx)?;
+            }
+            w.flush().with_context(ctx)?;
+        }
+        af.close().with_context(ctx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::TreeFixture;
+
+    fn entry(apath: &str) -> IndexEntry {
+        IndexEntry {
+            apath: apath.into(),
+            kind: Kind::File,
+            mtime: 1_592_266_523,
+            mtime_nanos: 0,
+            addrs: vec![],
+            target: None,
+            ntfs: None,
+            statx: None,
+        }
+    }
+
+    #[test]
+    fn write_and_read() {
+        let tf = TreeFixture::new();
+        let path = tf.path().join("scan");
+        let band_id = BandId::new(&[3]);
+        let mut writer = ScanCacheWriter::new(&path, tf.path(), &band_id);
+        writer.source_inode(&"/b".into(), Some(42));
+        writer.push(entry("/b"));
+        writer.push(entry("/a"));
+        writer.write().unwrap();
+
+        let cache = ScanCache::read(&path, tf.path(), &band_id)
+            .unwrap()
+            .unwrap();
+        assert_eq!(cache.entries, [entry("/a"), entry("/b")]);
+        assert_eq!(cache.inodes.len(), 1);
+        assert_eq!(cache.inodes[&"/b".into()], 42);
+
+        // It's not used for other versions or archives.
+        assert!(ScanCache::read(&path, tf.path(), &BandId::new(&[4]))
+            .unwrap()
+            .is_none());
+        let other = TreeFixture::new();
+        assert!(ScanCache::read(&path, other.path(), &band_id)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn missing_or_damaged_cache_is_ignored() {
+        let tf = TreeFixture::new();
+        let path = tf.path().join("scan");
+        let band_id = BandId::zero();
+        assert!(ScanCache::read(&path, tf.path(), &band_id)
+            .unwrap()
+            .is_none());
+        fs::write(&path, "not json\n").unwrap();
+        assert!(ScanCache::read(&path, tf.path(), &band_id)
+            .unwrap()
+            .is_none());
+    }
+}
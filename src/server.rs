@@ -0,0 +1,795 @@
+// Conserve backup system.
+// Copyright 2020 Martin Pool.
+
+//! Serve an archive over a simple HTTP API, so that other machines can browse
+//! or restore from it without mounting its storage, and optionally push new
+//! versions into it.
+//!
+//! Reads are `GET` (or `HEAD`):
+//!
+//! * `/bands`: a json list of band ids.
+//! * `/bands/{id}`: a json dict describing the band.
+//! * `/bands/{id}/index`: a json list of the band's index entries, in apath
+//!   order. For a band with a layered index, these are merged with the
+//!   entries of the bands below, so they're the whole tree.
+//! * `/blocks/{hash}`: the uncompressed content of a block. A
+//!   `Range: bytes=start-end` header returns just part of it.
+//! * `/generation`: a json string identifying the generation of the block
+//!   directory, which changes whenever blocks may have been removed.
+//!
+//! If the server has a push token, every request must present it in an
+//! `Authorization: Bearer TOKEN` header, and clients can then add to the
+//! archive with `PUT`:
+//!
+//! * `/blocks/{hash}`: store a block, given its uncompressed content, which
+//!   must match the hash.
+//! * `/bands/{id}/{file}`: write `BANDHEAD`, which creates a new band, then
+//!   its index files under `i/` in any order, each of which must decode to
+//!   some entries, then `BANDSIG` if the band is signed, and finally `BANDTAIL`, which is only accepted once the index has
+//!   no missing hunks and every block it refers to is present.
+//!
+//! The archive is append-only to clients: they can't replace or delete any
+//! file, or change a band once it's complete. Sending a file that's already
+//! there with the same content succeeds, so that an interrupted push can be
+//! resumed. With a quota, new blocks are
+//! refused once the block directory holds that many compressed bytes.
+//!
+//! Each connection serves one request and is then closed. A fixed pool of
+//! threads serves connections, and gives up on clients that stop sending or
+//! receiving, or send overlong lines.
+
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::Path;
+use std::sync::mpsc::sync_channel;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use snafu::ResultExt;
+use tracing::{debug, info, warn};
+
+use crate::hunk_map::HunkMap;
+use crate::index::check_hunk;
+use crate::*;
+
+/// Don't read more than this many header lines from one request.
+const MAX_HEADER_LINES: usize = 100;
+
+/// Refuse requests with a request line or header longer than this.
+const MAX_LINE_LENGTH: usize = 8 << 10;
+
+/// Serve this many connections at once; more wait to be accepted.
+const WORKER_THREADS: usize = 16;
+
+/// Drop connections that don't send or receive anything for this long.
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Don't accept request bodies larger than this.
+const MAX_BODY_SIZE: usize = 64 << 20;
+
+/// A listening HTTP server for one archive.
+#[derive(Debug)]
+pub struct Server {
+    state: Arc<State>,
+    listener: TcpListener,
+}
+
+/// Everything shared between the threads serving requests.
+#[derive(Debug)]
+struct State {
+    archive: Archive,
+
+    /// If set, clients presenting this token can push.
+    push_token: Option<Secret>,
+
+    /// If set, refuse new blocks once this many compressed bytes are stored.
+    quota: Option<u64>,
+
+    /// Compressed bytes in the block directory, if there's a quota.
+    used_bytes: Mutex<u64>,
+}
+
+impl Server {
+    /// Listen on an address, such as `127.0.0.1:8080`.
+    pub fn bind<A: ToSocketAddrs + fmt::Debug>(archive: Archive, address: A) -> Result<Server> {
+        let listener = TcpListener::bind(&address).context(errors::Listen {
+            address: format!("{:?}", address),
+        })?;
+        Ok(Server {
+            state: Arc::new(State {
+                archive,
+                push_token: None,
+                quota: None,
+                used_bytes: Mutex::new(0),
+            }),
+            listener,
+        })
+    }
+
+    /// Accept pushes from clients presenting this token, and refuse every
+    /// request from those that don't.
+    pub fn with_push_token(self, token: &str) -> Server {
+        self.map_state(|state| State {
+            push_token: Some(Secret::new(token)),
+            ..state
+        })
+    }
+
+    /// Refuse new blocks once the block directory holds this many compressed
+    /// bytes.
+    ///
+    /// This measures the existing blocks, which may take a while.
+    pub fn with_quota(self, quota: u64) -> Result<Server> {
+        let used_bytes = self.state.archive.block_dir().stats()?.sizes.compressed;
+        Ok(self.map_state(|state| State {
+            quota: Some(quota),
+            used_bytes: Mutex::new(used_bytes),
+            ..state
+        }))
+    }
+
+    fn map_state<F: FnOnce(State) -> State>(self, f: F) -> Server {
+        let state = Arc::try_unwrap(self.state).expect("server state is not yet shared");
+        Server {
+            state: Arc::new(f(state)),
+            listener: self.listener,
+        }
+    }
+
+    /// Return the address the server is listening on.
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.listener.local_addr().context(errors::Listen {
+            address: "(unknown)".to_owned(),
+        })
+    }
+
+    /// Serve requests until the process is stopped, on a pool of threads.
+    pub fn run(self) -> Result<()> {
+        let (sender, receiver) = sync_channel::<TcpStream>(WORKER_THREADS);
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..WORKER_THREADS {
+            let state = self.state.clone();
+            let receiver = receiver.clone();
+            thread::spawn(move || loop {
+                let stream = receiver.lock().unwrap().recv();
+                match stream.map(|stream| serve_connection(&state, stream)) {
+                    Err(_) => return,
+                    Ok(Err(err)) if err.kind() == io::ErrorKind::UnexpectedEof => (),
+                    Ok(Err(err)) => warn!("Failed to serve request: {}", err),
+                    Ok(Ok(())) => (),
+                }
+            });
+        }
+        for stream in self.listener.incoming() {
+            match stream {
+                // Blocks while all the workers are busy and the queue is full.
+                Ok(stream) => sender.send(stream).expect("server threads stopped"),
+                Err(err) => warn!("Failed to accept connection: {}", err),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A request read from a connection.
+struct Request {
+    method: String,
+    path: String,
+    range: Option<String>,
+    authorization: Option<String>,
+    body: Vec<u8>,
+}
+
+/// A response to one request.
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    headers: Vec<String>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn json(value: &serde_json::Value) -> Response {
+        Response {
+            status: "200 OK",
+            content_type: "application/json",
+            headers: Vec::new(),
+            body: value.to_string().into_bytes(),
+        }
+    }
+
+    fn error(status: &'static str) -> Response {
+        Response {
+            status,
+            content_type: "text/plain",
+            headers: Vec::new(),
+            body: format!("{}\n", status).into_bytes(),
+        }
+    }
+
+    fn created() -> Response {
+        Response::error("201 Created")
+    }
+
+    fn unauthorized() -> Response {
+        Response {
+            headers: vec!["WWW-Authenticate: Bearer".to_owned()],
+            ..Response::error("401 Unauthorized")
+        }
+    }
+}
+
+/// Serve one connection, answering with an error if serving it panics, so
+/// that no request can stop a worker thread.
+fn serve_connection(state: &State, stream: TcpStream) -> io::Result<()> {
+    let error_stream = stream.try_clone()?;
+    match catch_unwind(AssertUnwindSafe(|| handle_connection(state, stream))) {
+        Ok(result) => result,
+        Err(_) => {
+            warn!("Panicked while serving a request");
+            write_response(
+                error_stream,
+                &Response::error("500 Internal Server Error"),
+                false,
+            )
+        }
+    }
+}
+
+fn handle_connection(state: &State, stream: TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let response = match read_request(&stream)? {
+        Some(request) => {
+            let response = respond(state, &request);
+            debug!(
+                "HTTP {} {} -> {}",
+                request.method, request.path, response.status
+            );
+            write_response(stream, &response, request.method == "HEAD")?;
+            return Ok(());
+        }
+        None => Response::error("400 Bad Request"),
+    };
+    write_response(stream, &response, false)
+}
+
+/// Read one line, or return None if it's longer than `MAX_LINE_LENGTH`.
+///
+/// At the end of the stream, returns an empty string.
+fn read_line<R: BufRead>(reader: &mut R) -> io::Result<Option<String>> {
+    let mut line = String::new();
+    reader.take(MAX_LINE_LENGTH as u64).read_line(&mut line)?;
+    if line.len() == MAX_LINE_LENGTH && !line.ends_with('\n') {
+        Ok(None)
+    } else {
+        Ok(Some(line))
+    }
+}
+
+/// Read a request, or return None if it's malformed or too large.
+fn read_request(stream: &TcpStream) -> io::Result<Option<Request>> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let request_line = match read_line(&mut reader)? {
+        Some(line) if line.is_empty() => {
+            // Closed without sending anything, perhaps just checking the port.
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    let mut words = request_line.split_whitespace();
+    let (method, path) = match (words.next(), words.next()) {
+        (Some(method), Some(path)) => (method.to_owned(), path.to_owned()),
+        _ => return Ok(None),
+    };
+    let mut range = None;
+    let mut authorization = None;
+    let mut content_length = 0;
+    for _ in 0..MAX_HEADER_LINES {
+        let line = match read_line(&mut reader)? {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+        if line.trim_end().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            let value = value.trim().to_owned();
+            match name.trim().to_ascii_lowercase().as_str() {
+                "range" => range = Some(value),
+                "authorization" => authorization = Some(value),
+                "content-length" => match value.parse() {
+                    Ok(len) if len <= MAX_BODY_SIZE => content_length = len,
+                    _ => return Ok(None),
+                },
+                _ => (),
+            }
+        }
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+    Ok(Some(Request {
+        method,
+        path,
+        range,
+        authorization,
+        body,
+    }))
+}
+
+fn write_response(mut stream: TcpStream, response: &Response, head_only: bool) -> io::Result<()> {
+    let mut header = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+        response.status,
+        response.content_type,
+        response.body.len()
+    );
+    for h in &response.headers {
+        header.push_str(h);
+        header.push_str("\r\n");
+    }
+    header.push_str("\r\n");
+    stream.write_all(header.as_bytes())?;
+    if !head_only {
+        stream.write_all(&response.body)?;
+    }
+    stream.flush()
+}
+
+fn respond(state: &State, request: &Request) -> Response {
+    let archive = &state.archive;
+    let parts: Vec<&str> = request
+        .path
+        .trim_start_matches('/')
+        .split('/')
+        .filter(|p| !p.is_empty())
+        .collect();
+    let result = match (request.method.as_str(), parts.as_slice()) {
+        _ if state.push_token.is_some() && !is_authorized(state, request) => {
+            return Response::unauthorized()
+        }
+        ("GET", _) | ("HEAD", _) => match parts.as_slice() {
+            ["bands"] => list_bands(archive),
+            ["bands", band_id] => band_info(archive, band_id),
+            ["bands", band_id, "index"] => band_index(archive, band_id),
+            ["blocks", hash] => block_content(archive, hash, request.range.as_deref()),
+            ["generation"] => archive
+                .block_dir()
+                .generation()
+                .map(|generation| Response::json(&serde_json::json!(generation))),
+            _ => return Response::error("404 Not Found"),
+        },
+        ("PUT", _) if state.push_token.is_none() => return Response::error("403 Forbidden"),
+        ("PUT", ["blocks", hash]) => put_block(state, hash, &request.body),
+        ("PUT", ["bands", band_id, file @ ..]) => {
+            put_band_file(archive, band_id, file, &request.body)
+        }
+        ("PUT", _) => return Response::error("404 Not Found"),
+        _ => return Response::error("405 Method Not Allowed"),
+    };
+    result.unwrap_or_else(|err| {
+        warn!(
+            "Failed to serve {} {:?}: {}",
+            request.method, request.path, err
+        );
+        Response::error("500 Internal Server Error")
+    })
+}
+
+fn is_authorized(state: &State, request: &Request) -> bool {
+    match (&state.push_token, &request.authorization) {
+        (Some(token), Some(authorization)) => match authorization.strip_prefix("Bearer ") {
+            // Compare every byte, so the time taken doesn't reveal how much
+            // of the token was right.
+            Some(given) => {
+                let token = token.expose();
+                given.len() == token.len()
+                    && given
+                        .bytes()
+                        .zip(token.bytes())
+                        .fold(0, |acc, (a, b)| acc | (a ^ b))
+                        == 0
+            }
+            None => false,
+        },
+        _ => false,
+    }
+}
+
+fn list_bands(archive: &Archive) -> Result<Response> {
+    let band_ids: Vec<String> = archive
+        .list_bands()?
+        .iter()
+        .map(BandId::to_string)
+        .collect();
+    Ok(Response::json(&serde_json::json!(band_ids)))
+}
+
+/// Open a band named in a request, or return None if there's no such band.
+fn open_band(archive: &Archive, band_id: &str) -> Result<Option<Band>> {
+    let band_id = match BandId::from_string(band_id) {
+        Ok(band_id) => band_id,
+        Err(_) => return Ok(None),
+    };
+    if !archive.list_bands()?.contains(&band_id) {
+        return Ok(None);
+    }
+    Band::open(archive, &band_id).map(Some)
+}
+
+fn band_info(archive: &Archive, band_id: &str) -> Result<Response> {
+    let band = match open_band(archive, band_id)? {
+        Some(band) => band,
+        None => return Ok(Response::error("404 Not Found")),
+    };
+    let info = band.get_info()?;
+    Ok(Response::json(&serde_json::json!({
+        "id": info.id.to_string(),
+        "is_closed": info.is_closed,
+        "start_time": info.start_time.to_rfc3339(),
+        "end_time": info.end_time.map(|t| t.to_rfc3339()),
+        "basis_band_id": info.basis_band_id.map(|b| b.to_string()),
+        "source_path": info.source_path,
+    })))
+}
+
+fn band_index(archive: &Archive, band_id: &str) -> Result<Response> {
+    let band = match open_band(archive, band_id)? {
+        Some(band) => band,
+        None => return Ok(Response::error("404 Not Found")),
+    };
+    let entries: Vec<IndexEntry> = StoredTree::new(archive, band)?.iter_entries()?.collect();
+    Ok(Response::json(&serde_json::json!(entries)))
+}
+
+/// True if this looks like a block hash, and so is safe to use as a filename.
+fn is_valid_hash(hash: &str) -> bool {
+    hash.len() == blockdir::BLAKE_HASH_SIZE_BYTES * 2
+        && hash
+            .bytes()
+            .all(|b| b.is_ascii_hexdigit() && !b.is_ascii_uppercase())
+}
+
+fn put_block(state: &State, hash: &str, content: &[u8]) -> Result<Response> {
+    let block_dir = state.archive.block_dir();
+    if !is_valid_hash(hash) || blockdir::hash_bytes(content)? != hash {
+        return Ok(Response::error("400 Bad Request"));
+    }
+    if block_dir.contains(hash)? {
+        return Ok(Response::error("200 OK"));
+    }
+    if let Some(quota) = state.quota {
+        if *state.used_bytes.lock().unwrap() >= quota {
+            warn!("Refused block {} because the archive is over quota", hash);
+            return Ok(Response::error("507 Insufficient Storage"));
+        }
+    }
+    let (_addrs, stats) = block_dir.store_blocks(&[content.to_vec()])?;
+    *state.used_bytes.lock().unwrap() += stats.compressed_bytes;
+    Ok(Response::created())
+}
+
+/// True if this is the name of a file that can be pushed into a band.
+fn is_band_file(file: &[&str]) -> bool {
+    let digits = |s: &str, len| s.len() == len && s.bytes().all(|b| b.is_ascii_digit());
+    match file {
+        ["BANDHEAD"] | ["BANDTAIL"] | ["BANDSIG"] | ["i", "HUNKMAP"] => true,
+        ["i", subdir, hunk] => digits(subdir, 5) && digits(hunk, 9),
+        _ => false,
+    }
+}
+
+fn put_band_file(
+    archive: &Archive,
+    band_id: &str,
+    file: &[&str],
+    content: &[u8],
+) -> Result<Response> {
+    let band_id = match BandId::from_string(band_id) {
+        Ok(band_id) if is_band_file(file) => band_id,
+        _ => return Ok(Response::error("404 Not Found")),
+    };
+    let band_path = archive.bands_path().join(band_id.to_string());
+    let path = file.iter().fold(band_path.clone(), |p, f| p.join(f));
+    if fs::read(&path).ok().as_deref() == Some(content) {
+        return Ok(Response::error("200 OK"));
+    }
+    if file == ["BANDHEAD"] {
+        fs::create_dir_all(band_path.join("i")).context(errors::CreateBand)?;
+    } else if !band_path.join("BANDHEAD").is_file() || band_path.join("BANDTAIL").exists() {
+        return Ok(Response::error("409 Conflict"));
+    }
+    if let ["i", _, _] = file {
+        if let Err(message) = check_hunk(content) {
+            warn!("Refused corrupt index hunk {:?}: {}", path, message);
+            return Ok(Response::error("400 Bad Request"));
+        }
+    }
+    if file == ["BANDTAIL"] {
+        let band = Band::open(archive, &band_id)?;
+        if !index_is_complete(&band)? {
+            warn!("Refused to complete {}: index hunks are missing", band_id);
+            return Ok(Response::error("409 Conflict"));
+        }
+        if let Some(base_band_id) = band.index_base_band_id()? {
+            if !archive.list_bands()?.contains(&base_band_id) {
+                warn!(
+                    "Refused to complete {}: it's layered on missing band {}",
+                    band_id, base_band_id
+                );
+                return Ok(Response::error("409 Conflict"));
+            }
+        }
+        for entry in band.iter_entries()? {
+            for addr in &entry.addrs {
+                if !archive.block_dir().contains(&addr.hash)? {
+                    warn!(
+                        "Refused to complete {}: block {} is missing",
+                        band_id, addr.hash
+                    );
+                    return Ok(Response::error("409 Conflict"));
+                }
+            }
+        }
+    }
+    match write_new_file(&path, content) {
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+            // Another request wrote it first: fine if it's the same.
+            return Ok(if fs::read(&path).ok().as_deref() == Some(content) {
+                Response::error("200 OK")
+            } else {
+                Response::error("409 Conflict")
+            });
+        }
+        result => result.context(errors::WriteBandFile { path: path.clone() })?,
+    }
+    if file == ["BANDTAIL"] {
+        info!("Received complete band {}", band_id);
+    }
+    Ok(Response::created())
+}
+
+/// True if the band's index hunks are numbered without gaps, and agree with
+/// its hunk map if there is one.
+///
+/// Clients may send index files in any order, so this checks that none are
+/// still missing before the band can be completed.
+fn index_is_complete(band: &Band) -> Result<bool> {
+    let hunk_count = band.index().count_hunks()?;
+    let stored_hunks = walkdir::WalkDir::new(&band.index_dir_path)
+        .min_depth(2)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file())
+        .count();
+    if stored_hunks != hunk_count as usize {
+        return Ok(false);
+    }
+    Ok(match HunkMap::read(&band.index_dir_path)? {
+        Some(hunk_map) => hunk_map.hunks.len() == hunk_count as usize,
+        None => true,
+    })
+}
+
+fn write_new_file(path: &Path, content: &[u8]) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = AtomicFile::new(path)?;
+    file.write_all(content)?;
+    file.close_noclobber()
+}
+
+fn block_content(archive: &Archive, hash: &str, range: Option<&str>) -> Result<Response> {
+    // Only well-formed hashes are looked up, so that requests can't name any
+    // other file.
+    if !is_valid_hash(hash) || !archive.block_dir().contains(hash)? {
+        return Ok(Response::error("404 Not Found"));
+    }
+    let (content, _sizes) = archive.block_dir().get_block_content(hash)?;
+    let range = match range {
+        None => {
+            return Ok(Response {
+                status: "200 OK",
+                content_type: "application/octet-stream",
+                headers: vec!["Accept-Ranges: bytes".to_owned()],
+                body: content,
+            })
+        }
+        Some(range) => parse_range(range, content.len()),
+    };
+    Ok(match range {
+        Some((start, end)) => Response {
+            status: "206 Partial Content",
+            content_type: "application/octet-stream",
+            headers: vec![format!(
+                "Content-Range: bytes {}-{}/{}",
+                start,
+                end - 1,
+                content.len()
+            )],
+            body: content[start..end].to_vec(),
+        },
+        None => Response {
+            headers: vec![format!("Content-Range: bytes */{}", content.len())],
+            ..Response::error("416 Range Not Satisfiable")
+        },
+    })
+}
+
+/// Parse a `bytes=start-end` range, with an inclusive end, into a half-open
+/// range within a body of length `len`.
+fn parse_range(range: &str, len: usize) -> Option<(usize, usize)> {
+    let (start, end) = range.strip_prefix("bytes=")?.split_once('-')?;
+    let start: usize = start.trim().parse().ok()?;
+    let end = match end.trim() {
+        "" => len,
+        end => end.parse::<usize>().ok()?.saturating_add(1).min(len),
+    };
+    if start < end {
+        Some((start, end))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+    use crate::test_fixtures::ScratchArchive;
+
+    /// Start a server in the background, and return its address.
+    fn start_server(archive: &Archive) -> SocketAddr {
+        let server = Server::bind(archive.clone(), "127.0.0.1:0").unwrap();
+        let address = server.local_addr().unwrap();
+        thread::spawn(move || server.run());
+        address
+    }
+
+    /// Make a request and return the response headers and body.
+    fn get(address: SocketAddr, request: &str) -> (String, Vec<u8>) {
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+        let split = response
+            .windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .expect("end of headers");
+        (
+            String::from_utf8(response[..split + 2].to_vec()).unwrap(),
+            response[split + 4..].to_vec(),
+        )
+    }
+
+    #[test]
+    fn serve_bands_and_index() {
+        let af = ScratchArchive::new();
+        af.store_two_versions();
+        let address = start_server(&af);
+
+        let (headers, body) = get(address, "GET /bands HTTP/1.1\r\n\r\n");
+        assert!(headers.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert_eq!(body, br#"["b0000","b0001"]"#);
+
+        let (headers, body) = get(address, "GET /bands/b0001 HTTP/1.1\r\n\r\n");
+        assert!(headers.starts_with("HTTP/1.1 200 OK\r\n"));
+        let info: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(info["id"], "b0001");
+        assert_eq!(info["is_closed"], true);
+        assert_eq!(info["basis_band_id"], "b0000");
+
+        let (_, body) = get(address, "GET /bands/b0000/index HTTP/1.1\r\n\r\n");
+        let entries: Vec<IndexEntry> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(entries[1].apath, Apath::from("/hello"));
+
+        for path in &["/bands/b0009", "/bands/junk/index", "/nothing", "/"] {
+            let (headers, _) = get(address, &format!("GET {} HTTP/1.1\r\n\r\n", path));
+            assert!(
+                headers.starts_with("HTTP/1.1 404 Not Found\r\n"),
+                "{}",
+                path
+            );
+        }
+        let (headers, _) = get(address, "DELETE /bands/b0000 HTTP/1.1\r\n\r\n");
+        assert!(headers.starts_with("HTTP/1.1 405 "));
+    }
+
+    #[test]
+    fn reads_need_token_if_set() {
+        let af = ScratchArchive::new();
+        af.store_two_versions();
+        let server = Server::bind(af.clone(), "127.0.0.1:0")
+            .unwrap()
+            .with_push_token("s3cret");
+        let address = server.local_addr().unwrap();
+        thread::spawn(move || server.run());
+
+        for auth in &["", "Authorization: Bearer guess\r\n"] {
+            let (headers, _) = get(address, &format!("GET /bands HTTP/1.1\r\n{}\r\n", auth));
+            assert!(headers.starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+        }
+        let (headers, body) = get(
+            address,
+            "GET /bands HTTP/1.1\r\nAuthorization: Bearer s3cret\r\n\r\n",
+        );
+        assert!(headers.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert_eq!(body, br#"["b0000","b0001"]"#);
+    }
+
+    #[test]
+    fn refuse_long_lines() {
+        let long = format!("GET /{} HTTP/1.1\r\n", "x".repeat(MAX_LINE_LENGTH));
+        let mut reader = io::Cursor::new(long.as_bytes());
+        assert_eq!(read_line(&mut reader).unwrap(), None);
+
+        let mut reader = io::Cursor::new(&b"GET / HTTP/1.1\r\n"[..]);
+        assert_eq!(
+            read_line(&mut reader).unwrap().as_deref(),
+            Some("GET / HTTP/1.1\r\n")
+        );
+        assert_eq!(read_line(&mut reader).unwrap().as_deref(), Some(""));
+    }
+
+    #[test]
+    fn serve_block_ranges() {
+        let af = ScratchArchive::new();
+        let hash = af.block_dir().store_block(b"0123456789").unwrap().hash;
+        let address = start_server(&af);
+
+        let (headers, body) = get(address, &format!("GET /blocks/{} HTTP/1.1\r\n\r\n", hash));
+        assert!(headers.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(headers.contains("Content-Length: 10\r\n"));
+        assert_eq!(body, b"0123456789");
+
+        let (headers, body) = get(
+            address,
+            &format!("GET /blocks/{} HTTP/1.1\r\nRange: bytes=2-4\r\n\r\n", hash),
+        );
+        assert!(headers.starts_with("HTTP/1.1 206 Partial Content\r\n"));
+        assert!(headers.contains("Content-Range: bytes 2-4/10\r\n"));
+        assert_eq!(body, b"234");
+
+        let (_, body) = get(
+            address,
+            &format!("GET /blocks/{} HTTP/1.1\r\nrange: bytes=7-\r\n\r\n", hash),
+        );
+        assert_eq!(body, b"789");
+
+        let (headers, _) = get(
+            address,
+            &format!("GET /blocks/{} HTTP/1.1\r\nRange: bytes=20-\r\n\r\n", hash),
+        );
+        assert!(headers.starts_with("HTTP/1.1 416 "));
+
+        let (headers, body) = get(address, &format!("HEAD /blocks/{} HTTP/1.1\r\n\r\n", hash));
+        assert!(headers.contains("Content-Length: 10\r\n"));
+        assert!(body.is_empty());
+
+        let (headers, _) = get(address, "GET /blocks/..%2FCONSERVE HTTP/1.1\r\n\r\n");
+        assert!(headers.starts_with("HTTP/1.1 404 "));
+        let (headers, _) = get(
+            address,
+            &format!("GET /blocks/{} HTTP/1.1\r\n\r\n", "0".repeat(128)),
+        );
+        assert!(headers.starts_with("HTTP/1.1 404 "));
+    }
+
+    #[test]
+    fn parse_ranges() {
+        assert_eq!(parse_range("bytes=0-0", 10), Some((0, 1)));
+        assert_eq!(parse_range("bytes=3-100", 10), Some((3, 10)));
+        assert_eq!(parse_range("bytes=9-", 10), Some((9, 10)));
+        assert_eq!(parse_range("bytes=10-", 10), None);
+        assert_eq!(parse_range("bytes=5-4", 10), None);
+        assert_eq!(parse_range("bytes=-5", 10), None);
+        assert_eq!(parse_range("items=1-2", 10), None);
+    }
+}
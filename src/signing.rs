@@ -0,0 +1,395 @@
+// Conserve backup system.
+// Copyright 2020 Martin Pool.
+
+//! Sign the archive header and complete bands with a secret key, so that
+//! anyone without the key, such as a storage provider, can't rewrite the
+//! history of an archive without it being noticed.
+//!
+//! Signatures are Ed25519 signatures over a manifest listing the BLAKE2b hash
+//! of each signed file. The header's manifest is in `CONSERVE.sig`, and each
+//! band's is in `BANDSIG`, covering its head, tail and every index file. Since
+//! the index holds the hash of every block, this covers the content too.
+//!
+//! Each band's manifest also names the signed band before it, so removing a
+//! band from the middle of the history is noticed. Removing the newest bands
+//! can't be noticed this way, since what's left is a history that really
+//! existed.
+//!
+//! Signatures are checked with the public key, which can be given to anyone
+//! who reads the archive, while the secret key is only needed to write it.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use blake2_rfc::blake2b::Blake2b;
+use ed25519_dalek::Signer;
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+
+use crate::blockdir::hash_bytes;
+use crate::jsonio::{read_json_metadata_file, write_json_metadata_file};
+use crate::*;
+
+/// Bytes in a key, secret or public.
+const KEY_LEN: usize = 32;
+
+const ALGORITHM: &str = "ed25519";
+
+/// A secret key for signing archives.
+pub struct SigningKey {
+    key: ed25519_dalek::SigningKey,
+}
+
+/// The public half of a `SigningKey`, which can check signatures but not
+/// make them.
+#[derive(Clone)]
+pub struct PublicKey {
+    key: ed25519_dalek::VerifyingKey,
+}
+
+impl fmt::Debug for SigningKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Don't log the key itself.
+        write!(f, "SigningKey({})", self.key_id())
+    }
+}
+
+impl fmt::Debug for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PublicKey({})", self.key_id())
+    }
+}
+
+/// A list of files and their hashes, signed with a key.
+#[derive(Debug, Serialize, Deserialize)]
+struct Manifest {
+    algorithm: String,
+    key_id: String,
+    /// For a band, the id of the signed band before it, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    previous: Option<String>,
+    files: BTreeMap<String, String>,
+    signature: String,
+}
+
+impl Manifest {
+    /// The bytes that are signed: everything in the manifest but the
+    /// signature, each field preceded by a zero byte.
+    fn signed_bytes(&self) -> Vec<u8> {
+        let mut fields = vec![
+            self.algorithm.as_str(),
+            self.key_id.as_str(),
+            self.previous.as_deref().unwrap_or_default(),
+        ];
+        for (name, hash) in &self.files {
+            fields.push(name);
+            fields.push(hash);
+        }
+        let mut bytes = Vec::new();
+        for field in fields {
+            bytes.push(0);
+            bytes.extend_from_slice(field.as_bytes());
+        }
+        bytes
+    }
+}
+
+impl SigningKey {
+    /// Make a new random key.
+    pub fn generate() -> Result<SigningKey> {
+        let mut key = [0; KEY_LEN];
+        getrandom::getrandom(&mut key).map_err(|e| Error::GenerateKey {
+            message: e.to_string(),
+        })?;
+        Ok(SigningKey {
+            key: ed25519_dalek::SigningKey::from_bytes(&key),
+        })
+    }
+
+    /// Read a key written by `save`.
+    pub fn load(path: &Path) -> Result<SigningKey> {
+        Ok(SigningKey {
+            key: ed25519_dalek::SigningKey::from_bytes(&read_key_file(path)?),
+        })
+    }
+
+    /// Write the key as hex to a new file, readable only by its owner.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        write_key_file(path, &self.key.to_bytes(), 0o600)
+    }
+
+    /// The public key that checks this key's signatures.
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey {
+            key: self.key.verifying_key(),
+        }
+    }
+
+    /// A short public identifier for the key, the same as its public key's.
+    pub fn key_id(&self) -> String {
+        self.public_key().key_id()
+    }
+
+    /// Sign files relative to `dir`, writing the manifest to `sig_path`.
+    ///
+    /// Files not yet written can be included by giving their content. For
+    /// a band, `previous` is the id of the signed band before it.
+    pub(crate) fn sign(
+        &self,
+        dir: &Path,
+        names: &[String],
+        pending: &[(&str, &[u8])],
+        previous: Option<&str>,
+        sig_path: &Path,
+    ) -> Result<()> {
+        let mut files = BTreeMap::new();
+        for name in names {
+            files.insert(name.clone(), hash_bytes(&read_signed_file(dir, name)?)?);
+        }
+        for (name, content) in pending {
+            files.insert((*name).to_owned(), hash_bytes(content)?);
+        }
+        let mut manifest = Manifest {
+            algorithm: ALGORITHM.to_owned(),
+            key_id: self.key_id(),
+            previous: previous.map(str::to_owned),
+            files,
+            signature: String::new(),
+        };
+        manifest.signature = hex::encode(self.key.sign(&manifest.signed_bytes()).to_bytes());
+        write_json_metadata_file(sig_path, &manifest)
+    }
+}
+
+impl PublicKey {
+    /// Read a public key written by `save`.
+    pub fn load(path: &Path) -> Result<PublicKey> {
+        let key = ed25519_dalek::VerifyingKey::from_bytes(&read_key_file(path)?).map_err(|_| {
+            Error::InvalidKey {
+                path: path.to_path_buf(),
+            }
+        })?;
+        Ok(PublicKey { key })
+    }
+
+    /// Write the key as hex to a new file.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        write_key_file(path, self.key.as_bytes(), 0o644)
+    }
+
+    /// A short identifier for the key.
+    pub fn key_id(&self) -> String {
+        let mut hasher = Blake2b::new(8);
+        hasher.update(b"conserve key id");
+        hasher.update(self.key.as_bytes());
+        hex::encode(hasher.finalize().as_bytes())
+    }
+
+    /// Check that the manifest in `sig_path` was signed by this key, names
+    /// `previous` as the band before, and lists exactly the files `names`
+    /// with their current content.
+    pub(crate) fn verify(
+        &self,
+        dir: &Path,
+        names: &[String],
+        previous: Option<&str>,
+        sig_path: &Path,
+    ) -> Result<()> {
+        let bad = |message: String| Error::BadSignature {
+            path: dir.to_path_buf(),
+            message,
+        };
+        if !sig_path.is_file() {
+            return Err(Error::SignatureMissing {
+                path: dir.to_path_buf(),
+            });
+        }
+        let manifest: Manifest = read_json_metadata_file(sig_path)?;
+        if manifest.algorithm != ALGORITHM {
+            return Err(bad(format!("unknown algorithm {:?}", manifest.algorithm)));
+        }
+        if manifest.key_id != self.key_id() {
+            return Err(bad(format!("signed by another key {}", manifest.key_id)));
+        }
+        let verified = hex::decode(&manifest.signature)
+            .ok()
+            .and_then(|bytes| ed25519_dalek::Signature::from_slice(&bytes).ok())
+            .and_then(|signature| {
+                self.key
+                    .verify_strict(&manifest.signed_bytes(), &signature)
+                    .ok()
+            });
+        if verified.is_none() {
+            return Err(bad("manifest doesn't match its signature".to_owned()));
+        }
+        if manifest.previous.as_deref() != previous {
+            let describe = |previous: Option<&str>| match previous {
+                Some(band_id) => format!("version {}", band_id),
+                None => "no signed version".to_owned(),
+            };
+            return Err(bad(format!(
+                "signed as following {}, but follows {}",
+                describe(manifest.previous.as_deref()),
+                describe(previous)
+            )));
+        }
+        let listed: Vec<&String> = manifest.files.keys().collect();
+        let mut names: Vec<&String> = names.iter().collect();
+        names.sort();
+        if listed != names {
+            return Err(bad("files were added or removed".to_owned()));
+        }
+        for (name, hash) in &manifest.files {
+            if hash_bytes(&read_signed_file(dir, name)?)? != *hash {
+                return Err(bad(format!("{} was changed", name)));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn read_signed_file(dir: &Path, name: &str) -> Result<Vec<u8>> {
+    let path: PathBuf = name.split('/').fold(dir.to_path_buf(), |p, f| p.join(f));
+    fs::read(&path).context(errors::ReadMetadata { path })
+}
+
+fn read_key_file(path: &Path) -> Result<[u8; KEY_LEN]> {
+    let text = fs::read_to_string(path).context(errors::ReadKey { path })?;
+    match hex::decode(text.trim()) {
+        Ok(bytes) if bytes.len() == KEY_LEN => {
+            let mut key = [0; KEY_LEN];
+            key.copy_from_slice(&bytes);
+            Ok(key)
+        }
+        _ => Err(Error::InvalidKey {
+            path: path.to_path_buf(),
+        }),
+    }
+}
+
+/// Write a key as hex to a new file, with the given Unix permissions.
+fn write_key_file(path: &Path, key: &[u8], _mode: u32) -> Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(_mode);
+    }
+    options
+        .open(path)
+        .and_then(|mut f| writeln!(f, "{}", hex::encode(key)))
+        .context(errors::WriteKey { path })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::TreeFixture;
+
+    #[test]
+    fn save_and_load_key() {
+        let tf = TreeFixture::new();
+        let path = tf.path().join("key");
+        let key = SigningKey::generate().unwrap();
+        key.save(&path).unwrap();
+        assert!(key.save(&path).is_err(), "key isn't overwritten");
+        let loaded = SigningKey::load(&path).unwrap();
+        assert_eq!(loaded.key.to_bytes(), key.key.to_bytes());
+        assert_eq!(loaded.key_id().len(), 16);
+        assert!(!format!("{:?}", key).contains(&hex::encode(key.key.to_bytes())));
+
+        let public_path = tf.path().join("key.pub");
+        key.public_key().save(&public_path).unwrap();
+        let public_key = PublicKey::load(&public_path).unwrap();
+        assert_eq!(public_key.key, key.key.verifying_key());
+        assert_eq!(public_key.key_id(), key.key_id());
+
+        tf.create_file_with_contents("short", b"1234\n");
+        assert!(matches!(
+            SigningKey::load(&tf.path().join("short")),
+            Err(Error::InvalidKey { .. })
+        ));
+        assert!(matches!(
+            PublicKey::load(&tf.path().join("short")),
+            Err(Error::InvalidKey { .. })
+        ));
+    }
+
+    #[test]
+    fn verify_files() {
+        let tf = TreeFixture::new();
+        tf.create_file_with_contents("a", b"alpha");
+        tf.create_dir("sub");
+        tf.create_file_with_contents("sub/b", b"beta");
+        let sig = tf.path().join("SIG");
+        let names = vec!["a".to_owned(), "sub/b".to_owned()];
+        let key = SigningKey::generate().unwrap();
+        key.sign(
+            tf.path(),
+            &names[..1],
+            &[("sub/b", b"beta")],
+            Some("b0001"),
+            &sig,
+        )
+        .unwrap();
+        let public_key = key.public_key();
+        public_key
+            .verify(tf.path(), &names, Some("b0001"), &sig)
+            .unwrap();
+
+        let other_key = SigningKey::generate().unwrap().public_key();
+        assert!(matches!(
+            other_key.verify(tf.path(), &names, Some("b0001"), &sig),
+            Err(Error::BadSignature { .. })
+        ));
+        let err = public_key
+            .verify(tf.path(), &names, Some("b0000"), &sig)
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("signed as following version b0001, but follows version b0000"),
+            "{}",
+            err
+        );
+        let err = public_key
+            .verify(tf.path(), &names, None, &sig)
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("follows no signed version"),
+            "{}",
+            err
+        );
+        assert!(public_key
+            .verify(tf.path(), &names[..1], Some("b0001"), &sig)
+            .is_err());
+
+        // The manifest can't be edited to match changed files.
+        let mut manifest: Manifest = read_json_metadata_file(&sig).unwrap();
+        manifest.previous = None;
+        write_json_metadata_file(&sig, &manifest).unwrap();
+        let err = public_key
+            .verify(tf.path(), &names, None, &sig)
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("doesn't match its signature"),
+            "{}",
+            err
+        );
+        key.sign(tf.path(), &names, &[], None, &sig).unwrap();
+
+        tf.create_file_with_contents("sub/b", b"gamma");
+        let err = public_key
+            .verify(tf.path(), &names, None, &sig)
+            .unwrap_err();
+        assert!(err.to_string().contains("sub/b was changed"), "{}", err);
+        fs::remove_file(&sig).unwrap();
+        assert!(matches!(
+            public_key.verify(tf.path(), &names, None, &sig),
+            Err(Error::SignatureMissing { .. })
+        ));
+    }
+}
@@ -0,0 +1,300 @@
+// Conserve backup system.
+// Copyright 2020 Martin Pool.
+
+//! Back up from a snapshot of the source volume, so that the backup is
+//! consistent, as if taken at a single point in time, without stopping
+//! applications.
+//!
+//! On Windows, this is a Volume Shadow Copy (VSS) snapshot, which also lets
+//! files held open and locked by other programs, such as Outlook PST files or
+//! registry hives, be read. Snapshots are made and removed through WMI by
+//! PowerShell, which needs the backup to run as an administrator.
+//!
+//! On macOS, this is a local APFS snapshot made by `tmutil`, mounted
+//! read-only in a temporary directory.
+//!
+//! The snapshot is removed when the `Snapshot` is dropped.
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use tempfile::TempDir;
+use tracing::{debug, warn};
+
+use crate::*;
+
+/// A snapshot of the volume holding a source directory.
+#[derive(Debug)]
+pub struct Snapshot {
+    /// The source directory, within the snapshot.
+    path: PathBuf,
+    kind: SnapshotKind,
+}
+
+#[derive(Debug)]
+enum SnapshotKind {
+    /// A Windows shadow copy, with an ID like `{8F0A...}`.
+    ShadowCopy { id: String },
+    /// A macOS local snapshot, named by its date, mounted on a temporary
+    /// directory.
+    Apfs { date: String, mount_dir: TempDir },
+}
+
+impl Snapshot {
+    /// Make a snapshot of the volume holding `source`.
+    ///
+    /// This is only supported on Windows and macOS.
+    pub fn create(source: &Path) -> Result<Snapshot> {
+        if cfg!(windows) {
+            Snapshot::create_shadow_copy(source)
+        } else if cfg!(target_os = "macos") {
+            Snapshot::create_apfs(source)
+        } else {
+            Err(snapshot_error(
+                "snapshots are only supported on Windows and macOS".to_owned(),
+            ))
+        }
+    }
+
+    fn create_shadow_copy(source: &Path) -> Result<Snapshot> {
+        let source = source
+            .canonicalize()
+            .map_err(|e| snapshot_error(format!("{}: {}", source.display(), e)))?;
+        let source = source.to_string_lossy();
+        let (volume, relative) = split_volume(&source)
+            .ok_or_else(|| snapshot_error(format!("{} is not on a local volume", source)))?;
+        let output = powershell(&format!(
+            "$r = (Get-WmiObject -List Win32_ShadowCopy).Create('{}', 'ClientAccessible'); \
+             if ($r.ReturnValue -ne 0) {{ exit $r.ReturnValue }}; \
+             $s = Get-WmiObject Win32_ShadowCopy | Where-Object {{ $_.ID -eq $r.ShadowID }}; \
+             Write-Output $s.ID; Write-Output $s.DeviceObject",
+            volume
+        ))?;
+        let (id, device) = parse_create_output(&output).ok_or_else(|| {
+            snapshot_error(format!("unexpected output from PowerShell: {:?}", output))
+        })?;
+        let path = PathBuf::from(format!("{}\\{}", device, relative));
+        debug!(?id, ?path, "Created shadow copy");
+        Ok(Snapshot {
+            path,
+            kind: SnapshotKind::ShadowCopy { id },
+        })
+    }
+
+    fn create_apfs(source: &Path) -> Result<Snapshot> {
+        let source = source
+            .canonicalize()
+            .map_err(|e| snapshot_error(format!("{}: {}", source.display(), e)))?;
+        let df = run("df", &["-P".as_ref(), source.as_os_str()])?;
+        let volume = df
+            .lines()
+            .nth(1)
+            .and_then(|line| line.split_whitespace().last())
+            .ok_or_else(|| snapshot_error(format!("unexpected output from df: {:?}", df)))?;
+        let relative = relative_to_volume(&source, Path::new(volume));
+        let output = run("tmutil", &["localsnapshot".as_ref()])?;
+        let date = parse_tmutil_output(&output).ok_or_else(|| {
+            snapshot_error(format!("unexpected output from tmutil: {:?}", output))
+        })?;
+        let mount_dir = TempDir::new().map_err(|e| snapshot_error(e.to_string()))?;
+        let mount_path = mount_dir.path().to_path_buf();
+        // Constructed now so that the snapshot is deleted if mounting fails.
+        let snapshot = Snapshot {
+            path: mount_path.join(relative),
+            kind: SnapshotKind::Apfs {
+                date: date.to_owned(),
+                mount_dir,
+            },
+        };
+        run(
+            "mount_apfs",
+            &[
+                "-o".as_ref(),
+                "ro,nobrowse".as_ref(),
+                "-s".as_ref(),
+                format!("com.apple.TimeMachine.{}.local", date).as_ref(),
+                volume.as_ref(),
+                mount_path.as_os_str(),
+            ],
+        )?;
+        debug!(?date, path = ?snapshot.path, "Mounted APFS snapshot");
+        Ok(snapshot)
+    }
+
+    /// The path of the source directory within the snapshot.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        let (name, deleted) = match &self.kind {
+            SnapshotKind::ShadowCopy { id } => (
+                id,
+                powershell(&format!(
+                    "Get-WmiObject Win32_ShadowCopy | Where-Object {{ $_.ID -eq '{}' }} \
+                     | ForEach-Object {{ $_.Delete() }}",
+                    id
+                )),
+            ),
+            SnapshotKind::Apfs { date, mount_dir } => {
+                // The mount might have failed, so ignore errors unmounting.
+                let _ = run("umount", &[mount_dir.path().as_os_str()]);
+                (
+                    date,
+                    run("tmutil", &["deletelocalsnapshots".as_ref(), date.as_ref()]),
+                )
+            }
+        };
+        if let Err(e) = deleted {
+            warn!("Failed to remove snapshot {}: {}", name, e);
+        }
+    }
+}
+
+fn snapshot_error(message: String) -> Error {
+    Error::Snapshot { message }
+}
+
+/// Run a PowerShell command and return its output.
+fn powershell(command: &str) -> Result<String> {
+    run(
+        "powershell",
+        &[
+            "-NoProfile".as_ref(),
+            "-NonInteractive".as_ref(),
+            "-Command".as_ref(),
+            command.as_ref(),
+        ],
+    )
+}
+
+/// Run a program and return its output.
+fn run(program: &str, args: &[&std::ffi::OsStr]) -> Result<String> {
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .map_err(|e| snapshot_error(format!("failed to run {}: {}", program, e)))?;
+    if !output.status.success() {
+        return Err(snapshot_error(format!(
+            "{} failed with {}: {}",
+            program,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// The path of `source` within the APFS volume mounted at `volume`.
+///
+/// On macOS 10.15 and later, user files are on the data volume, mounted at
+/// `/System/Volumes/Data`, but appear at the same path under `/`.
+fn relative_to_volume(source: &Path, volume: &Path) -> PathBuf {
+    source
+        .strip_prefix(volume)
+        .or_else(|_| source.strip_prefix("/"))
+        .unwrap_or(source)
+        .to_path_buf()
+}
+
+/// Find the snapshot date in output from `tmutil localsnapshot`.
+fn parse_tmutil_output(output: &str) -> Option<&str> {
+    output
+        .lines()
+        .find_map(|line| {
+            line.trim()
+                .strip_prefix("Created local snapshot with date: ")
+        })
+        .filter(|date| !date.is_empty())
+}
+
+/// Split a canonical Windows path like `\\?\C:\Users\me` into the volume,
+/// `C:\`, and the path within it, `Users\me`.
+fn split_volume(path: &str) -> Option<(String, &str)> {
+    let path = path.strip_prefix(r"\\?\").unwrap_or(path);
+    let mut chars = path.chars();
+    match (chars.next(), chars.next(), chars.next()) {
+        (Some(drive), Some(':'), Some('\\')) if drive.is_ascii_alphabetic() => {
+            Some((format!("{}:\\", drive), path[3..].trim_end_matches('\\')))
+        }
+        _ => None,
+    }
+}
+
+/// Find the shadow copy ID and device path in the output of the script that
+/// creates it.
+fn parse_create_output(output: &str) -> Option<(String, String)> {
+    let mut lines = output.lines().map(str::trim).filter(|l| !l.is_empty());
+    match (lines.next(), lines.next()) {
+        (Some(id), Some(device)) if id.starts_with('{') && device.starts_with(r"\\?\") => {
+            Some((id.to_owned(), device.trim_end_matches('\\').to_owned()))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_volumes() {
+        assert_eq!(
+            split_volume(r"\\?\C:\Users\me\"),
+            Some((r"C:\".to_owned(), r"Users\me"))
+        );
+        assert_eq!(split_volume(r"D:\"), Some((r"D:\".to_owned(), "")));
+        assert_eq!(split_volume(r"\\?\UNC\server\share"), None);
+        assert_eq!(split_volume("/home/me"), None);
+    }
+
+    #[test]
+    fn parse_output() {
+        assert_eq!(
+            parse_create_output(
+                "{8F0A71C2-1E5B-4C3D-9E6F-0A1B2C3D4E5F}\r\n\
+                 \\\\?\\GLOBALROOT\\Device\\HarddiskVolumeShadowCopy3\r\n"
+            ),
+            Some((
+                "{8F0A71C2-1E5B-4C3D-9E6F-0A1B2C3D4E5F}".to_owned(),
+                r"\\?\GLOBALROOT\Device\HarddiskVolumeShadowCopy3".to_owned()
+            ))
+        );
+        assert_eq!(parse_create_output(""), None);
+    }
+
+    #[test]
+    fn parse_tmutil() {
+        assert_eq!(
+            parse_tmutil_output(
+                "NOTE: local snapshots are considered purgeable\n\
+                 Created local snapshot with date: 2020-06-19-123456\n"
+            ),
+            Some("2020-06-19-123456")
+        );
+        assert_eq!(parse_tmutil_output("Error: not permitted\n"), None);
+    }
+
+    #[test]
+    fn relative_to_data_volume() {
+        assert_eq!(
+            relative_to_volume(Path::new("/Users/me"), Path::new("/System/Volumes/Data")),
+            Path::new("Users/me")
+        );
+        assert_eq!(
+            relative_to_volume(Path::new("/Volumes/Work/src"), Path::new("/Volumes/Work")),
+            Path::new("src")
+        );
+    }
+
+    #[cfg(not(any(windows, target_os = "macos")))]
+    #[test]
+    fn unsupported_elsewhere() {
+        assert!(matches!(
+            Snapshot::create(Path::new(".")),
+            Err(Error::Snapshot { .. })
+        ));
+    }
+}
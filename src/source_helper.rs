@@ -0,0 +1,272 @@
+// Conserve backup system.
+// Copyright 2020 Martin Pool.
+
+//! Read source directories and files that the backup isn't permitted to read
+//! itself, through a helper process with more privileges.
+//!
+//! The helper is typically `sudo -n conserve source helper`, so only the
+//! unreadable paths are read as root. It's run once per directory to list it,
+//! writing one json `HelperEntry` per line, and once per file to write its
+//! content.
+
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io::{self, BufRead, Read, Write};
+use std::path::Path;
+use std::process::{Child, ChildStdout, Command, Stdio};
+
+use serde::{Deserialize, Serialize};
+
+use crate::unix_time::UnixTime;
+use crate::*;
+
+/// An entry of a directory, as listed by the helper.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct HelperEntry {
+    pub name: String,
+    pub kind: Kind,
+    pub mtime: i64,
+    #[serde(default, skip_serializing_if = "crate::misc::zero_u32")]
+    pub mtime_nanos: u32,
+    /// For files only, the size in bytes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    /// For symlinks only, the target.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+}
+
+impl HelperEntry {
+    pub(crate) fn mtime(&self) -> UnixTime {
+        UnixTime {
+            secs: self.mtime,
+            nanosecs: self.mtime_nanos,
+        }
+    }
+}
+
+/// A command that reads source paths with more privileges.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct SourceHelper {
+    /// The program and its leading arguments, to which an operation and a
+    /// path are added.
+    argv: Vec<OsString>,
+}
+
+impl SourceHelper {
+    /// Make a helper from a program and its leading arguments, such as
+    /// `["sudo", "-n", "/usr/bin/conserve", "source", "helper"]`.
+    pub fn new<I: IntoIterator<Item = S>, S: AsRef<OsStr>>(argv: I) -> SourceHelper {
+        let argv: Vec<OsString> = argv.into_iter().map(|a| a.as_ref().to_owned()).collect();
+        assert!(!argv.is_empty(), "helper command is empty");
+        SourceHelper { argv }
+    }
+
+    fn command(&self, operation: &str, path: &Path) -> Command {
+        let mut command = Command::new(&self.argv[0]);
+        command
+            .args(&self.argv[1..])
+            .arg(operation)
+            .arg(path)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit());
+        command
+    }
+
+    /// List a directory.
+    pub(crate) fn list_dir(&self, path: &Path) -> io::Result<Vec<HelperEntry>> {
+        let output = self.command("list", path).output()?;
+        if !output.status.success() {
+            return Err(helper_failed(output.status));
+        }
+        output
+            .stdout
+            .lines()
+            .map(|line| {
+                serde_json::from_str(&line?)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            })
+            .collect()
+    }
+
+    /// Start reading a file.
+    pub(crate) fn read_file(&self, path: &Path) -> io::Result<HelperFile> {
+        let mut child = self.command("cat", path).spawn()?;
+        let stdout = child.stdout.take().expect("helper stdout is piped");
+        Ok(HelperFile { child, stdout })
+    }
+}
+
+fn helper_failed(status: std::process::ExitStatus) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        format!("source helper failed: {}", status),
+    )
+}
+
+/// The content of a file, as written by the helper.
+///
+/// Reading fails at the end if the helper didn't succeed, so that a partial
+/// file isn't taken as complete.
+#[derive(Debug)]
+pub struct HelperFile {
+    child: Child,
+    stdout: ChildStdout,
+}
+
+impl Read for HelperFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.stdout.read(buf)?;
+        if len == 0 && !buf.is_empty() {
+            let status = self.child.wait()?;
+            if !status.success() {
+                return Err(helper_failed(status));
+            }
+        }
+        Ok(len)
+    }
+}
+
+impl Drop for HelperFile {
+    fn drop(&mut self) {
+        // Don't leave a zombie if the file wasn't read to the end.
+        if let Ok(None) = self.child.try_wait() {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
+    }
+}
+
+/// As the helper, list a directory, writing one json entry per line.
+///
+/// Entries that can't be read or whose names aren't UTF-8 are reported on
+/// stderr and skipped.
+pub fn serve_list(path: &Path, out: &mut dyn Write) -> io::Result<()> {
+    for dir_entry in fs::read_dir(path)? {
+        let dir_entry = dir_entry?;
+        let name = match dir_entry.file_name().into_string() {
+            Ok(name) => name,
+            Err(name) => {
+                eprintln!("conserve source helper: skipping undecodable {:?}", name);
+                continue;
+            }
+        };
+        let child_path = dir_entry.path();
+        let metadata = match statx::read(&child_path) {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                eprintln!("conserve source helper: {:?}: {}", child_path, e);
+                continue;
+            }
+        };
+        let target = if metadata.kind == Kind::Symlink {
+            match fs::read_link(&child_path)?.into_os_string().into_string() {
+                Ok(target) => Some(target),
+                Err(_) => {
+                    eprintln!(
+                        "conserve source helper: can't decode target of {:?}",
+                        child_path
+                    );
+                    continue;
+                }
+            }
+        } else {
+            None
+        };
+        let entry = HelperEntry {
+            name,
+            kind: metadata.kind,
+            mtime: metadata.mtime.secs,
+            mtime_nanos: metadata.mtime.nanosecs,
+            size: metadata.size,
+            target,
+        };
+        serde_json::to_writer(&mut *out, &entry)?;
+        writeln!(out)?;
+    }
+    out.flush()
+}
+
+/// As the helper, write the content of a file.
+pub fn serve_cat(path: &Path, out: &mut dyn Write) -> io::Result<()> {
+    io::copy(&mut fs::File::open(path)?, out)?;
+    out.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::TreeFixture;
+
+    #[test]
+    fn serve_and_parse_list() {
+        let tf = TreeFixture::new();
+        tf.create_file("hello");
+        tf.create_dir("subdir");
+        tf.create_symlink("link", "target");
+        let mut out = Vec::new();
+        serve_list(tf.path(), &mut out).unwrap();
+        let mut entries: Vec<HelperEntry> = out
+            .lines()
+            .map(|line| serde_json::from_str(&line.unwrap()).unwrap())
+            .collect();
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        let names: Vec<(&str, Kind)> = entries.iter().map(|e| (e.name.as_str(), e.kind)).collect();
+        if SYMLINKS_SUPPORTED {
+            assert_eq!(
+                names,
+                [
+                    ("hello", Kind::File),
+                    ("link", Kind::Symlink),
+                    ("subdir", Kind::Dir)
+                ]
+            );
+            assert_eq!(entries[1].target.as_deref(), Some("target"));
+        }
+        assert_eq!(entries[0].size, Some(8));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn run_helper() {
+        let tf = TreeFixture::new();
+        let path = tf.create_file("hello");
+        // A stand-in for conserve, run by sh as `helper OPERATION PATH`.
+        let helper = SourceHelper::new(&[
+            "sh",
+            "-c",
+            r#"case "$1" in
+                 cat) cat "$2" ;;
+                 list) echo '{"name":"x","kind":"File","mtime":10,"size":3}' ;;
+                 *) exit 1 ;;
+               esac"#,
+            "helper",
+        ]);
+        let mut content = String::new();
+        helper
+            .read_file(&path)
+            .unwrap()
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!(content, "contents");
+        assert_eq!(
+            helper.list_dir(tf.path()).unwrap(),
+            [HelperEntry {
+                name: "x".to_owned(),
+                kind: Kind::File,
+                mtime: 10,
+                mtime_nanos: 0,
+                size: Some(3),
+                target: None,
+            }]
+        );
+
+        let err = helper
+            .read_file(&tf.path().join("missing"))
+            .unwrap()
+            .read_to_end(&mut Vec::new())
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+    }
+}
@@ -0,0 +1,223 @@
+// Conserve backup system.
+// Copyright 2020 Martin Pool.
+
+//! Keep Conserve's biggest buffers within a memory limit, so that it can
+//! back up large trees on a machine with little memory.
+//!
+//! Half the limit is for the entries of one source directory, which are
+//! sorted before they're returned. Past that, sorted runs of entries are
+//! written to temporary files, in the directory given by `TMPDIR`, and merged
+//! back as they're read. The other half is for blocks queued to be stored,
+//! which are stored sooner rather than letting the queue grow.
+//!
+//! The limit doesn't count memory that's proportional to the number of
+//! threads, such as blocks being compressed, or the few entries of each
+//! directory that's still to be visited.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use snafu::ResultExt;
+
+use crate::*;
+
+/// The memory limit in bytes, or 0 if there's none.
+static MEMORY_LIMIT: AtomicU64 = AtomicU64::new(0);
+
+/// Limit the memory used by buffers started from now on to about `limit`
+/// bytes, or remove the limit if it's `None`.
+pub fn set_memory_limit(limit: Option<u64>) {
+    MEMORY_LIMIT.store(limit.unwrap_or(0), Ordering::Relaxed);
+}
+
+/// The limit set by `set_memory_limit`.
+pub fn memory_limit() -> Option<u64> {
+    match MEMORY_LIMIT.load(Ordering::Relaxed) {
+        0 => None,
+        limit => Some(limit),
+    }
+}
+
+/// The most bytes of entries to hold from one directory.
+pub(crate) fn directory_budget() -> Option<u64> {
+    memory_limit().map(|limit| limit / 2)
+}
+
+/// The most bytes of blocks to queue before storing them.
+pub(crate) fn block_queue_budget() -> Option<u64> {
+    memory_limit().map(|limit| limit / 2)
+}
+
+/// Items to be sorted by a string key, which are written out to temporary
+/// files when they're bigger than the budget.
+pub(crate) struct SortBuffer<T> {
+    budget: Option<u64>,
+    items: Vec<(String, T)>,
+    /// Estimated size of `items`.
+    bytes: u64,
+    /// Files each holding a sorted run of items, as lines of JSON.
+    runs: Vec<File>,
+}
+
+/// Items read back from one sorted run.
+type Source<T> = Box<dyn Iterator<Item = Result<(String, T)>>>;
+
+/// Items from a `SortBuffer` in key order.
+pub(crate) enum Sorted<T> {
+    /// All the items fit in memory.
+    Memory(Vec<(String, T)>),
+    /// Some items were spilled, and are merged as they're read.
+    Spilled(Merge<T>),
+}
+
+impl<T: Serialize + DeserializeOwned + 'static> SortBuffer<T> {
+    /// Make a buffer that spills when it holds more than `budget` bytes.
+    pub fn new(budget: Option<u64>) -> SortBuffer<T> {
+        SortBuffer {
+            budget,
+            items: Vec::new(),
+            bytes: 0,
+            runs: Vec::new(),
+        }
+    }
+
+    /// Add an item estimated to take `size` bytes.
+    pub fn push(&mut self, key: String, value: T, size: u64) -> Result<()> {
+        self.items.push((key, value));
+        self.bytes += size;
+        match self.budget {
+            Some(budget) if self.bytes > budget => self.spill(),
+            _ => Ok(()),
+        }
+    }
+
+    fn spill(&mut self) -> Result<()> {
+        self.items.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        let mut file = tempfile::tempfile().context(errors::WriteSpill)?;
+        let mut writer = BufWriter::new(&mut file);
+        for item in self.items.drain(..) {
+            serde_json::to_writer(&mut writer, &item)
+                .map_err(io::Error::from)
+                .context(errors::WriteSpill)?;
+            writer.write_all(b"\n").context(errors::WriteSpill)?;
+        }
+        writer.flush().context(errors::WriteSpill)?;
+        drop(writer);
+        file.seek(SeekFrom::Start(0)).context(errors::WriteSpill)?;
+        self.runs.push(file);
+        self.bytes = 0;
+        Ok(())
+    }
+
+    /// Return all the items in key order.
+    pub fn into_sorted(mut self) -> Result<Sorted<T>> {
+        self.items.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        if self.runs.is_empty() {
+            return Ok(Sorted::Memory(self.items));
+        }
+        let mut sources: Vec<Source<T>> = self
+            .runs
+            .into_iter()
+            .map(|file| {
+                Box::new(
+                    serde_json::Deserializer::from_reader(BufReader::new(file))
+                        .into_iter()
+                        .map(|item| item.context(errors::ReadSpill)),
+                ) as Source<T>
+            })
+            .collect();
+        sources.push(Box::new(self.items.into_iter().map(Ok)));
+        let heads = sources
+            .iter_mut()
+            .map(|source| source.next().transpose())
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Sorted::Spilled(Merge { sources, heads }))
+    }
+}
+
+/// Merges sorted runs of items.
+pub(crate) struct Merge<T> {
+    sources: Vec<Source<T>>,
+    /// The next item from each source, if it's not finished.
+    heads: Vec<Option<(String, T)>>,
+}
+
+impl<T> fmt::Debug for Merge<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Merge")
+            .field("sources", &self.sources.len())
+            .finish()
+    }
+}
+
+impl<T> Iterator for Merge<T> {
+    type Item = Result<(String, T)>;
+
+    fn next(&mut self) -> Option<Result<(String, T)>> {
+        let i = self
+            .heads
+            .iter()
+            .enumerate()
+            .filter_map(|(i, head)| head.as_ref().map(|(key, _)| (i, key)))
+            .min_by(|a, b| a.1.cmp(b.1))?
+            .0;
+        match self.sources[i].next().transpose() {
+            Ok(next) => Some(Ok(std::mem::replace(&mut self.heads[i], next).unwrap())),
+            Err(e) => {
+                self.heads[i] = None;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sorted_keys(buf: SortBuffer<u32>) -> (bool, Vec<String>) {
+        match buf.into_sorted().unwrap() {
+            Sorted::Memory(items) => (false, items.into_iter().map(|(k, _)| k).collect()),
+            Sorted::Spilled(merge) => (true, merge.map(|item| item.unwrap().0).collect()),
+        }
+    }
+
+    #[test]
+    fn sort_in_memory() {
+        let mut buf = SortBuffer::new(None);
+        for (i, key) in ["b", "c", "a"].iter().enumerate() {
+            buf.push(key.to_string(), i as u32, 1000).unwrap();
+        }
+        assert_eq!(
+            sorted_keys(buf),
+            (false, vec!["a".into(), "b".into(), "c".into()])
+        );
+    }
+
+    #[test]
+    fn spill_and_merge() {
+        let mut buf = SortBuffer::new(Some(25));
+        let keys: Vec<String> = (0..100).map(|i| format!("{:03}", (i * 37) % 100)).collect();
+        for (i, key) in keys.iter().enumerate() {
+            buf.push(key.clone(), i as u32, 10).unwrap();
+        }
+        assert_eq!(buf.runs.len(), 33);
+        let mut expected = keys;
+        expected.sort();
+        assert_eq!(sorted_keys(buf), (true, expected));
+    }
+
+    #[test]
+    fn limit() {
+        set_memory_limit(Some(1 << 20));
+        assert_eq!(memory_limit(), Some(1 << 20));
+        assert_eq!(directory_budget(), Some(1 << 19));
+        set_memory_limit(None);
+        assert_eq!(memory_limit(), None);
+        assert_eq!(block_queue_budget(), None);
+    }
+}
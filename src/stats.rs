@@ -1,17 +1,36 @@
 // Conserve backup system.
 // Copyright 2015, 2016, 2017, 2018, 2019, 2020 Martin Pool.
 
+//! Counters describing the work done by each operation.
+//!
+//! Stats from parts of an operation can be merged with `+=`, and all stats
+//! can be serialized, for example as JSON.
+
+use std::collections::BTreeMap;
 use std::io;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+use chrono::{DateTime, Utc};
 use derive_more::{Add, AddAssign};
+use serde::{Serialize, Serializer};
+use snafu::ResultExt;
 use thousands::Separable;
 
-use crate::Result;
+use crate::{errors, Apath, BandId, Kind, Problems, Result};
 
 pub fn mb_string(s: u64) -> String {
     (s / 1_000_000).separate_with_commas()
 }
 
+/// Serialize a duration as a number of seconds.
+fn serialize_secs<S: Serializer>(
+    duration: &Duration,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64())
+}
+
 /// Describe the compression ratio: higher is better.
 fn ratio(uncompressed: u64, compressed: u64) -> f64 {
     if compressed > 0 {
@@ -23,15 +42,19 @@ fn ratio(uncompressed: u64, compressed: u64) -> f64 {
 
 /// Describes sizes of data read or written, with both the
 /// compressed and uncompressed size.
-#[derive(Add, AddAssign, Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[derive(Add, AddAssign, Clone, Copy, Debug, Default, Eq, PartialEq, Serialize)]
 pub struct Sizes {
     pub compressed: u64,
     pub uncompressed: u64,
 }
 
-#[derive(Debug, Default, Clone, PartialEq, Eq)]
+#[derive(Add, AddAssign, Debug, Default, Clone, PartialEq, Eq, Serialize)]
 pub struct ValidateArchiveStats {
     pub block_dir_stats: ValidateBlockDirStats,
+    /// Number of apaths found out of order, or duplicated, in band indexes.
+    pub index_order_errors: u64,
+    /// Number of band indexes rewritten in order.
+    pub indexes_rewritten: u64,
 }
 
 impl ValidateArchiveStats {
@@ -50,37 +73,209 @@ impl ValidateArchiveStats {
     }
 }
 
-#[derive(Clone, Default, Debug, Eq, PartialEq)]
+#[derive(Add, AddAssign, Clone, Default, Debug, Eq, PartialEq, Serialize)]
 pub struct ValidateBlockDirStats {
     /// Number of blocks read.
     pub block_read_count: u64,
     /// Number of blocks that failed to read back.
     pub block_error_count: u64,
+    /// Number of blocks not read because they were verified recently.
+    pub block_skipped_count: u64,
+}
+
+/// Statistics about the blocks stored in a block directory, and any
+/// anomalies found while listing them.
+#[derive(Default, Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct BlockDirStats {
+    pub block_count: u64,
+    pub sizes: Sizes,
+
+    /// Number of blocks by compressed size, keyed by the power of two at or
+    /// above their size.
+    pub size_histogram: BTreeMap<u64, u64>,
+
+    /// Blocks and their sizes in each prefix subdirectory.
+    pub subdirs: BTreeMap<String, SubdirStats>,
+
+    /// Block files in a subdirectory that doesn't match their name.
+    pub misplaced_blocks: Vec<PathBuf>,
+
+    /// Hashes stored more than once, in different subdirectories.
+    pub duplicate_blocks: Vec<String>,
+
+    /// Blocks whose length can't be read.
+    pub unreadable_blocks: Vec<PathBuf>,
+
+    /// Leftover temporary files from interrupted writes.
+    pub temp_files: u64,
+
+    /// Files and directories that shouldn't be in a block directory.
+    pub unexpected_files: Vec<PathBuf>,
+}
+
+#[derive(Add, AddAssign, Default, Debug, Clone, Copy, Eq, PartialEq, Serialize)]
+pub struct SubdirStats {
+    pub block_count: u64,
+    pub sizes: Sizes,
+}
+
+/// The size of one band, and how it changed from the band before it.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct BandHistory {
+    pub band_id: BandId,
+    pub start_time: DateTime<Utc>,
+    pub is_closed: bool,
+    pub files: u64,
+    /// Total uncompressed size of all files.
+    pub file_bytes: u64,
+    /// Files not present in the previous band.
+    pub files_added: u64,
+    /// Files whose content differs from the previous band.
+    pub files_changed: u64,
+    /// Files in the previous band but not this one.
+    pub files_removed: u64,
+    /// Compressed bytes of blocks first used by this band.
+    pub new_block_bytes: u64,
+    /// Compressed bytes of blocks used by this band and all earlier bands.
+    pub total_block_bytes: u64,
+}
+
+/// What was done by `Archive::squash`.
+#[derive(Debug, Default, Clone, Eq, PartialEq, Serialize)]
+pub struct SquashStats {
+    /// Number of versions merged into the last version of the range, and
+    /// removed.
+    pub bands_removed: usize,
+    /// The whole index written for the last version, if it was layered.
+    pub index_builder_stats: IndexBuilderStats,
+}
+
+/// The compressed block space used by one band.
+#[derive(Default, Debug, Clone, Copy, Eq, PartialEq, Serialize)]
+pub struct BandSpace {
+    /// Bytes of blocks referenced by no other band, which would be freed by
+    /// deleting this band.
+    pub exclusive_bytes: u64,
+    /// Bytes of blocks also referenced by other bands.
+    pub shared_bytes: u64,
+}
+
+impl BlockDirStats {
+    /// True if anything unexpected was found.
+    pub fn has_anomalies(&self) -> bool {
+        !(self.misplaced_blocks.is_empty()
+            && self.duplicate_blocks.is_empty()
+            && self.unreadable_blocks.is_empty()
+            && self.unexpected_files.is_empty())
+    }
+
+    /// Write a description of the stats for people to read, optionally
+    /// including each prefix subdirectory.
+    pub fn summarize(&self, w: &mut dyn io::Write, show_subdirs: bool) -> Result<()> {
+        writeln!(
+            w,
+            "{:>12}      blocks",
+            self.block_count.separate_with_commas()
+        )
+        .context(errors::WriteStats)?;
+        writeln!(
+            w,
+            "{:>12} MB     uncompressed",
+            mb_string(self.sizes.uncompressed)
+        )
+        .context(errors::WriteStats)?;
+        writeln!(
+            w,
+            "{:>12} MB     after {:.1}x compression",
+            mb_string(self.sizes.compressed),
+            ratio(self.sizes.uncompressed, self.sizes.compressed)
+        )
+        .context(errors::WriteStats)?;
+        writeln!(w).context(errors::WriteStats)?;
+        writeln!(w, "Compressed block sizes:").context(errors::WriteStats)?;
+        for (upper, count) in &self.size_histogram {
+            writeln!(
+                w,
+                "{:>12}      up to {} bytes",
+                count.separate_with_commas(),
+                upper.separate_with_commas()
+            )
+            .context(errors::WriteStats)?;
+        }
+        if show_subdirs {
+            writeln!(w).context(errors::WriteStats)?;
+            writeln!(w, "Subdirectories:").context(errors::WriteStats)?;
+            for (name, subdir) in &self.subdirs {
+                writeln!(
+                    w,
+                    "{:>12}      {:>10} blocks {:>10} MB {:>6.1}x",
+                    name,
+                    subdir.block_count.separate_with_commas(),
+                    mb_string(subdir.sizes.compressed),
+                    ratio(subdir.sizes.uncompressed, subdir.sizes.compressed)
+                )
+                .context(errors::WriteStats)?;
+            }
+        }
+        writeln!(w).context(errors::WriteStats)?;
+        writeln!(w, "{:>12}      temporary files", self.temp_files).context(errors::WriteStats)?;
+        for (description, paths) in &[
+            ("misplaced blocks", &self.misplaced_blocks),
+            ("unreadable blocks", &self.unreadable_blocks),
+            ("unexpected files", &self.unexpected_files),
+        ] {
+            writeln!(w, "{:>12}      {}", paths.len(), description).context(errors::WriteStats)?;
+            for path in paths.iter() {
+                writeln!(w, "                  {}", path.display()).context(errors::WriteStats)?;
+            }
+        }
+        writeln!(
+            w,
+            "{:>12}      duplicated blocks",
+            self.duplicate_blocks.len()
+        )
+        .context(errors::WriteStats)?;
+        for hash in &self.duplicate_blocks {
+            writeln!(w, "                  {}", hash).context(errors::WriteStats)?;
+        }
+        Ok(())
+    }
 }
 
-#[derive(Default, Debug, Clone, Eq, PartialEq)]
+#[derive(Add, AddAssign, Default, Debug, Clone, Eq, PartialEq, Serialize)]
 pub struct IndexEntryIterStats {
     pub index_hunks: u64,
     pub uncompressed_index_bytes: u64,
     pub compressed_index_bytes: u64,
 }
 
-#[derive(Add, AddAssign, Clone, Debug, Default, Eq, PartialEq)]
+#[derive(Add, AddAssign, Clone, Debug, Default, Eq, PartialEq, Serialize)]
 pub struct IndexBuilderStats {
     pub index_hunks: u64,
     pub uncompressed_index_bytes: u64,
     pub compressed_index_bytes: u64,
 }
 
-#[derive(Debug, Default, Clone, Eq, PartialEq)]
+#[derive(Add, AddAssign, Debug, Default, Clone, Eq, PartialEq, Serialize)]
 pub struct LiveTreeIterStats {
     pub directories_visited: usize,
     pub exclusions: usize,
     pub metadata_error: usize,
     pub entries_returned: usize,
+    /// Directories whose files were taken from the trusted basis.
+    pub unchanged_dirs: usize,
+    /// Symlinks not followed because they point to a directory already
+    /// visited.
+    pub symlink_loops: usize,
+    /// Directories with too many entries to sort in memory, which were
+    /// spilled to temporary files.
+    pub spilled_dirs: usize,
 }
 
-#[derive(Add, AddAssign, Debug, Default, Eq, PartialEq, Clone)]
+/// Statistics from making a backup, as returned by `BackupOptions::run`.
+pub type BackupStats = CopyStats;
+
+#[derive(Add, AddAssign, Debug, Default, Eq, PartialEq, Clone, Serialize)]
 pub struct CopyStats {
     // TODO: Have separate more-specific stats for backup and restore, and then
     // each can have a single Display method.
@@ -105,9 +300,36 @@ pub struct CopyStats {
     pub single_block_files: usize,
     pub multi_block_files: usize,
 
+    /// Small files stored in blocks shared with other files.
+    pub combined_files: usize,
+
+    /// Restored files cloned from an identical file restored earlier,
+    /// sharing its extents where the filesystem supports it.
+    pub cloned_files: usize,
+
+    /// Files already present in the restore destination that were updated
+    /// in place, rewriting only the parts that differed.
+    pub updated_files: usize,
+
     pub errors: usize,
 
+    /// Non-fatal problems from reading the source and writing the destination.
+    pub problems: Problems,
+
     pub index_builder_stats: IndexBuilderStats,
+
+    /// Time spent reading the content of new and changed files, and
+    /// splitting it into blocks.
+    #[serde(serialize_with = "serialize_secs")]
+    pub read_time: Duration,
+
+    /// Time spent hashing, compressing, and writing blocks.
+    #[serde(serialize_with = "serialize_secs")]
+    pub store_time: Duration,
+
+    /// The slowest entries, if copied with `CopyOptions::profile_entries`.
+    #[serde(skip_serializing_if = "EntryProfile::is_empty")]
+    pub entry_profile: EntryProfile,
     // TODO: Include elapsed time.
 }
 
@@ -194,6 +416,12 @@ impl CopyStats {
             self.written_blocks.separate_with_commas(),
         )
         .unwrap();
+        writeln!(
+            w,
+            "{:>12}        small files combined",
+            self.combined_files.separate_with_commas(),
+        )
+        .unwrap();
         writeln!(
             w,
             "{:>12} MB     uncompressed",
@@ -261,3 +489,321 @@ impl CopyStats {
         // )
     }
 }
+
+/// The time taken to find and copy one entry.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct EntryTime {
+    pub apath: Apath,
+    pub kind: Kind,
+    /// The whole time, including listing the source directory if this is
+    /// the first entry read from it.
+    #[serde(serialize_with = "serialize_secs")]
+    pub elapsed: Duration,
+    /// Time spent reading the file's content, and splitting it into blocks.
+    #[serde(serialize_with = "serialize_secs")]
+    pub read_time: Duration,
+    /// Time spent storing blocks while copying this file, which may include
+    /// blocks of small files copied just before it.
+    #[serde(serialize_with = "serialize_secs")]
+    pub store_time: Duration,
+}
+
+/// The slowest files of a copy, and the directories whose entries took
+/// longest, to find what's slowing down a backup, such as a slow network
+/// mount or a huge file.
+#[derive(Debug, Default, Clone, Eq, PartialEq, Serialize)]
+pub struct EntryProfile {
+    /// How many files and directories to keep.
+    #[serde(skip)]
+    limit: usize,
+    /// The slowest files, slowest first.
+    files: Vec<EntryTime>,
+    /// The total time of the entries directly in each directory.
+    #[serde(skip)]
+    dir_times: BTreeMap<Apath, Duration>,
+}
+
+impl EntryProfile {
+    /// Make a profile that keeps the `limit` slowest files and directories.
+    pub fn new(limit: usize) -> EntryProfile {
+        EntryProfile {
+            limit,
+            ..EntryProfile::default()
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty() && self.dir_times.is_empty()
+    }
+
+    /// Count the time of an entry against its directory, and keep it if it's
+    /// one of the slowest files.
+    pub fn record(&mut self, entry_time: EntryTime) {
+        let apath: &str = &entry_time.apath;
+        if let Some(slash) = apath.rfind('/').filter(|_| apath != "/") {
+            let parent = Apath::from(if slash == 0 { "/" } else { &apath[..slash] });
+            *self.dir_times.entry(parent).or_default() += entry_time.elapsed;
+        }
+        if entry_time.kind == Kind::File {
+            let pos = self
+                .files
+                .iter()
+                .position(|f| f.elapsed < entry_time.elapsed)
+                .unwrap_or(self.files.len());
+            if pos < self.limit {
+                self.files.insert(pos, entry_time);
+                self.files.truncate(self.limit);
+            }
+        }
+    }
+
+    /// The slowest files, slowest first.
+    pub fn slowest_files(&self) -> &[EntryTime] {
+        &self.files
+    }
+
+    /// The directories whose entries took longest in total, slowest first.
+    pub fn slowest_dirs(&self) -> Vec<(&Apath, Duration)> {
+        let mut dirs: Vec<(&Apath, Duration)> =
+            self.dir_times.iter().map(|(a, d)| (a, *d)).collect();
+        dirs.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+        dirs.truncate(self.limit);
+        dirs
+    }
+
+    /// Write the slowest files and directories for people to read.
+    pub fn summarize(&self, w: &mut dyn io::Write) -> Result<()> {
+        let secs = |d: Duration| format!("{:.3}s", d.as_secs_f64());
+        writeln!(w, "             slowest files:").context(errors::WriteStats)?;
+        for file in &self.files {
+            writeln!(
+                w,
+                "{:>12}        {} (read {}, store {})",
+                secs(file.elapsed),
+                file.apath,
+                secs(file.read_time),
+                secs(file.store_time)
+            )
+            .context(errors::WriteStats)?;
+        }
+        writeln!(w, "             slowest directories:").context(errors::WriteStats)?;
+        for (apath, elapsed) in self.slowest_dirs() {
+            writeln!(w, "{:>12}        {}", secs(elapsed), apath).context(errors::WriteStats)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::ops::Add for EntryProfile {
+    type Output = EntryProfile;
+
+    fn add(mut self, other: EntryProfile) -> EntryProfile {
+        self += other;
+        self
+    }
+}
+
+impl std::ops::AddAssign for EntryProfile {
+    fn add_assign(&mut self, other: EntryProfile) {
+        self.limit = self.limit.max(other.limit);
+        for (apath, elapsed) in other.dir_times {
+            *self.dir_times.entry(apath).or_default() += elapsed;
+        }
+        self.files.extend(other.files);
+        self.files.sort_by_key(|f| std::cmp::Reverse(f.elapsed));
+        self.files.truncate(self.limit);
+    }
+}
+
+/// Write backup stats in the Prometheus textfile format, for example to be
+/// read by the node_exporter textfile collector.
+///
+/// `finish_time` is recorded as the time of the last successful backup, so
+/// this should only be called when the backup succeeded.
+pub fn write_backup_metrics(
+    stats: &CopyStats,
+    duration: Duration,
+    finish_time: SystemTime,
+    w: &mut dyn io::Write,
+) -> io::Result<()> {
+    let finish_secs = finish_time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64();
+    let metrics: &[(&str, &str, f64)] = &[
+        ("files", "Files in the source tree", stats.files as f64),
+        (
+            "new_files",
+            "Files not present in the previous backup",
+            stats.new_files as f64,
+        ),
+        (
+            "modified_files",
+            "Files changed since the previous backup",
+            stats.modified_files as f64,
+        ),
+        (
+            "unmodified_files",
+            "Files unchanged since the previous backup",
+            stats.unmodified_files as f64,
+        ),
+        (
+            "directories",
+            "Directories in the source tree",
+            stats.directories as f64,
+        ),
+        (
+            "symlinks",
+            "Symlinks in the source tree",
+            stats.symlinks as f64,
+        ),
+        (
+            "errors",
+            "Non-fatal errors during the backup",
+            stats.errors as f64,
+        ),
+        (
+            "uncompressed_bytes",
+            "Bytes of file content read from new or changed files",
+            stats.uncompressed_bytes as f64,
+        ),
+        (
+            "deduplicated_bytes",
+            "Bytes of file content already present in the archive",
+            stats.deduplicated_bytes as f64,
+        ),
+        (
+            "compressed_bytes",
+            "Bytes of compressed blocks written to the archive",
+            stats.compressed_bytes as f64,
+        ),
+        (
+            "written_blocks",
+            "Blocks written to the archive",
+            stats.written_blocks as f64,
+        ),
+        (
+            "duration_seconds",
+            "Elapsed time of the backup",
+            duration.as_secs_f64(),
+        ),
+        (
+            "last_success_timestamp_seconds",
+            "Unix time when a backup last completed successfully",
+            finish_secs,
+        ),
+    ];
+    for (name, help, value) in metrics {
+        writeln!(w, "# HELP conserve_backup_{} {}.", name, help)?;
+        writeln!(w, "# TYPE conserve_backup_{} gauge", name)?;
+        writeln!(w, "conserve_backup_{} {}", name, value)?;
+    }
+    Ok(())
+}
+
+/// Write stats as pretty-printed JSON, followed by a newline.
+pub fn write_json<S: Serialize>(stats: &S, w: &mut dyn io::Write) -> Result<()> {
+    serde_json::to_writer_pretty(&mut *w, stats)
+        .map_err(io::Error::from)
+        .and_then(|()| writeln!(w))
+        .context(errors::WriteStats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merge_and_serialize_stats() {
+        let mut stats = CopyStats {
+            files: 2,
+            written_blocks: 1,
+            ..CopyStats::default()
+        };
+        stats += CopyStats {
+            files: 3,
+            ..CopyStats::default()
+        };
+        assert_eq!(stats.files, 5);
+        assert_eq!(stats.written_blocks, 1);
+
+        let mut buf = Vec::new();
+        write_json(&stats, &mut buf).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&buf).unwrap();
+        assert_eq!(json["files"], 5);
+        assert_eq!(json["index_builder_stats"]["index_hunks"], 0);
+        assert_eq!(json["read_time"], 0.0);
+        assert!(json.get("entry_profile").is_none());
+        assert!(buf.ends_with(b"}\n"));
+    }
+
+    #[test]
+    fn entry_profile() {
+        let entry_time = |apath: &str, kind, millis| EntryTime {
+            apath: apath.into(),
+            kind,
+            elapsed: Duration::from_millis(millis),
+            read_time: Duration::from_millis(millis / 2),
+            store_time: Duration::default(),
+        };
+        let mut profile = EntryProfile::new(2);
+        profile.record(entry_time("/", Kind::Dir, 1));
+        profile.record(entry_time("/a", Kind::File, 10));
+        profile.record(entry_time("/nfs", Kind::Dir, 1));
+        profile.record(entry_time("/nfs/b", Kind::File, 300));
+        profile.record(entry_time("/nfs/c", Kind::File, 200));
+        let mut other = EntryProfile::new(2);
+        other.record(entry_time("/d", Kind::File, 250));
+        profile += other;
+
+        let files: Vec<&str> = profile
+            .slowest_files()
+            .iter()
+            .map(|f| f.apath.as_ref())
+            .collect();
+        assert_eq!(files, ["/nfs/b", "/d"]);
+        let dirs: Vec<(&str, u128)> = profile
+            .slowest_dirs()
+            .into_iter()
+            .map(|(apath, d)| (apath.as_ref(), d.as_millis()))
+            .collect();
+        assert_eq!(dirs, [("/nfs", 500), ("/", 261)]);
+
+        let mut buf = Vec::new();
+        profile.summarize(&mut buf).unwrap();
+        let text = String::from_utf8(buf).unwrap();
+        assert!(text.starts_with(
+            "             slowest files:\n      \
+             0.300s        /nfs/b (read 0.150s, store 0.000s)\n"
+        ));
+        assert!(text.contains("             slowest directories:\n      0.500s        /nfs\n"));
+    }
+
+    #[test]
+    fn backup_metrics() {
+        let stats = CopyStats {
+            files: 10,
+            errors: 2,
+            compressed_bytes: 1234,
+            ..CopyStats::default()
+        };
+        let mut buf = Vec::new();
+        write_backup_metrics(
+            &stats,
+            Duration::from_millis(2500),
+            UNIX_EPOCH + Duration::from_secs(1_600_000_000),
+            &mut buf,
+        )
+        .unwrap();
+        let text = String::from_utf8(buf).unwrap();
+        assert!(text.starts_with(
+            "# HELP conserve_backup_files Files in the source tree.\n\
+             # TYPE conserve_backup_files gauge\n\
+             conserve_backup_files 10\n"
+        ));
+        assert!(text.contains("\nconserve_backup_errors 2\n"));
+        assert!(text.contains("\nconserve_backup_compressed_bytes 1234\n"));
+        assert!(text.contains("\nconserve_backup_duration_seconds 2.5\n"));
+        assert!(text.ends_with("\nconserve_backup_last_success_timestamp_seconds 1600000000\n"));
+    }
+}
@@ -0,0 +1,242 @@
+// Conserve backup system.
+// Copyright 2020 Martin Pool.
+
+//! Read the metadata of source files.
+//!
+//! On Linux this is one `statx` call per entry, which also gives the birth
+//! time, mount ID, and attributes such as immutable or append-only, that
+//! `stat` doesn't. Elsewhere, or if the kernel doesn't support `statx`, it's
+//! `lstat`.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::unix_time::UnixTime;
+use crate::*;
+
+/// True if `statx` metadata can be read on this platform.
+pub(crate) const SUPPORTED: bool = cfg!(target_os = "linux");
+
+/// Extra metadata from `statx`, as stored in the index.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct StatxMetadata {
+    /// Birth time in whole seconds past the Unix epoch, if the filesystem
+    /// records it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub btime: Option<i64>,
+
+    /// Fractional nanoseconds of the birth time.
+    #[serde(default, skip_serializing_if = "crate::misc::zero_u32")]
+    pub btime_nanos: u32,
+
+    /// The ID of the mount holding the entry.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mnt_id: Option<u64>,
+
+    /// `STATX_ATTR_*` flags.
+    #[serde(default, skip_serializing_if = "crate::misc::zero_u64")]
+    pub attributes: u64,
+
+    /// The numeric ID of the user owning the entry.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uid: Option<u32>,
+
+    /// The numeric ID of the group owning the entry.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gid: Option<u32>,
+}
+
+/// The metadata of a source entry, not following symlinks.
+#[derive(Debug)]
+pub(crate) struct SourceMetadata {
+    pub kind: Kind,
+    pub mtime: UnixTime,
+    /// For files only, the size in bytes.
+    pub size: Option<u64>,
+    /// On Linux, extra metadata from `statx`.
+    pub statx: Option<StatxMetadata>,
+    /// On Unix, the inode number.
+    pub inode: Option<u64>,
+}
+
+impl From<&fs::Metadata> for SourceMetadata {
+    fn from(metadata: &fs::Metadata) -> SourceMetadata {
+        let kind = if metadata.is_file() {
+            Kind::File
+        } else if metadata.is_dir() {
+            Kind::Dir
+        } else if metadata.file_type().is_symlink() {
+            Kind::Symlink
+        } else {
+            Kind::Unknown
+        };
+        SourceMetadata {
+            kind,
+            mtime: metadata
+                .modified()
+                .expect("Failed to get file mtime")
+                .into(),
+            size: if metadata.is_file() {
+                Some(metadata.len())
+            } else {
+                None
+            },
+            statx: None,
+            inode: inode(metadata),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn inode(metadata: &fs::Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Some(metadata.ino())
+}
+
+#[cfg(not(unix))]
+fn inode(_metadata: &fs::Metadata) -> Option<u64> {
+    None
+}
+
+/// Read the metadata of a source entry, not following symlinks.
+#[cfg(target_os = "linux")]
+pub(crate) fn read(path: &Path) -> io::Result<SourceMetadata> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut buf: libc::statx = unsafe { std::mem::zeroed() };
+    let ret = unsafe {
+        libc::statx(
+            libc::AT_FDCWD,
+            c_path.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW | libc::AT_STATX_SYNC_AS_STAT,
+            libc::STATX_BASIC_STATS | libc::STATX_BTIME | libc::STATX_MNT_ID,
+            &mut buf,
+        )
+    };
+    if ret != 0 {
+        let err = io::Error::last_os_error();
+        // Old kernels don't have statx, and some sandboxes forbid it.
+        return match err.raw_os_error() {
+            Some(libc::ENOSYS) | Some(libc::EPERM) => {
+                fs::symlink_metadata(path).map(|m| SourceMetadata::from(&m))
+            }
+            _ => Err(err),
+        };
+    }
+    let kind = match u32::from(buf.stx_mode) & libc::S_IFMT {
+        libc::S_IFREG => Kind::File,
+        libc::S_IFDIR => Kind::Dir,
+        libc::S_IFLNK => Kind::Symlink,
+        _ => Kind::Unknown,
+    };
+    let has = |mask: u32| buf.stx_mask & mask != 0;
+    let (btime, btime_nanos) = if has(libc::STATX_BTIME) {
+        (Some(buf.stx_btime.tv_sec), buf.stx_btime.tv_nsec)
+    } else {
+        (None, 0)
+    };
+    Ok(SourceMetadata {
+        kind,
+        mtime: UnixTime {
+            secs: buf.stx_mtime.tv_sec,
+            nanosecs: buf.stx_mtime.tv_nsec,
+        },
+        size: if kind == Kind::File {
+            Some(buf.stx_size)
+        } else {
+            None
+        },
+        statx: Some(StatxMetadata {
+            btime,
+            btime_nanos,
+            mnt_id: if has(libc::STATX_MNT_ID) {
+                Some(buf.stx_mnt_id)
+            } else {
+                None
+            },
+            attributes: buf.stx_attributes & buf.stx_attributes_mask,
+            uid: if has(libc::STATX_UID) {
+                Some(buf.stx_uid)
+            } else {
+                None
+            },
+            gid: if has(libc::STATX_GID) {
+                Some(buf.stx_gid)
+            } else {
+                None
+            },
+        }),
+        inode: Some(buf.stx_ino),
+    })
+}
+
+/// Read the metadata of a source entry, not following symlinks.
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn read(path: &Path) -> io::Result<SourceMetadata> {
+    fs::symlink_metadata(path).map(|m| SourceMetadata::from(&m))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::TreeFixture;
+
+    #[test]
+    fn read_metadata() {
+        let tf = TreeFixture::new();
+        let path = tf.create_file("hello");
+        let metadata = read(&path).unwrap();
+        assert_eq!(metadata.kind, Kind::File);
+        assert_eq!(metadata.size, Some(8));
+        let fs_metadata = fs::symlink_metadata(&path).unwrap();
+        assert_eq!(
+            metadata.mtime,
+            UnixTime::from(fs_metadata.modified().unwrap())
+        );
+        assert_eq!(read(tf.path()).unwrap().kind, Kind::Dir);
+        assert_eq!(read(tf.path()).unwrap().size, None);
+        assert_eq!(
+            read(&tf.path().join("nothing")).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn statx_fields() {
+        let tf = TreeFixture::new();
+        let path = tf.create_file("hello");
+        let statx = read(&path).unwrap().statx.unwrap();
+        // Not every filesystem records the birth time, but it can't be after
+        // the file was written.
+        if let Some(btime) = statx.btime {
+            assert!(btime <= read(&path).unwrap().mtime.secs);
+        }
+        assert_eq!(statx.attributes, 0);
+        let fs_metadata = fs::symlink_metadata(&path).unwrap();
+        use std::os::unix::fs::MetadataExt;
+        assert_eq!(statx.uid, Some(fs_metadata.uid()));
+        assert_eq!(statx.gid, Some(fs_metadata.gid()));
+    }
+
+    #[test]
+    fn serialize_statx() {
+        let statx = StatxMetadata {
+            btime: Some(1_592_266_523),
+            btime_nanos: 0,
+            mnt_id: Some(29),
+            attributes: 0x10,
+            ..StatxMetadata::default()
+        };
+        assert_eq!(
+            serde_json::to_string(&statx).unwrap(),
+            r#"{"btime":1592266523,"mnt_id":29,"attributes":16}"#
+        );
+    }
+}
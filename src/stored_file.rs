@@ -1,6 +1,9 @@
 // Copyright 2017, 2018, 2019 Martin Pool.
 
 ///! Access a file stored in the archive.
+use std::collections::HashSet;
+use std::io::{self, Read, Seek, SeekFrom};
+
 use rayon::prelude::*;
 
 use crate::stats::Sizes;
@@ -25,32 +28,62 @@ impl StoredFile {
     }
 
     /// Validate the stored file hash is as expected.
-    pub(crate) fn validate(&self) -> Result<()> {
+    ///
+    /// If every block it uses is in `known_good`, the content isn't read
+    /// again.
+    pub(crate) fn validate(&self, known_good: Option<&HashSet<String>>) -> Result<()> {
+        if let Some(known_good) = known_good {
+            if self.addrs.iter().all(|a| known_good.contains(&a.hash)) {
+                ui::increment_bytes_done(self.len());
+                return Ok(());
+            }
+        }
         // TODO: Perhaps the file should know its apath and hold its entry.
         // TODO: Give a more specific message including the band and apath, if
         // the content can't be loaded.
         // TODO: Arguably we don't need to actually load the chunks here; it's
         // enough to remember that all the blocks were loaded before.
         // TODO: Give warnings and remember if there are any errors, but don't stop early.
-        self.block_range()
-            .unwrap()
-            .into_par_iter()
-            .map(|i| {
-                let (_content, sizes) = self.read_block(i)?;
-                ui::increment_bytes_done(sizes.uncompressed);
-                Ok(())
-            })
-            .find_any(Result::is_err)
-            .unwrap_or(Ok(()))
+        threads::in_block_pool(|| {
+            self.block_range()
+                .unwrap()
+                .into_par_iter()
+                .map(|i| {
+                    let (_content, sizes) = self.read_block(i)?;
+                    ui::increment_bytes_done(sizes.uncompressed);
+                    Ok(())
+                })
+                .find_any(Result::is_err)
+        })
+        .unwrap_or(Ok(()))
         // TODO: Return sum of sizes.
     }
 
-    /// Open a cursor on this file that implements `std::io::Read`.
-    pub(crate) fn into_read(self) -> ReadStoredFile {
+    /// The length of the file in bytes.
+    pub fn len(&self) -> u64 {
+        self.addrs.iter().map(|a| a.len).sum()
+    }
+
+    /// True if the file has no content.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Open a cursor on this file that implements `std::io::Read` and
+    /// `std::io::Seek`.
+    pub fn into_read(self) -> ReadStoredFile {
+        let mut block_starts = Vec::with_capacity(self.addrs.len() + 1);
+        let mut pos = 0;
+        block_starts.push(pos);
+        for addr in &self.addrs {
+            pos += addr.len;
+            block_starts.push(pos);
+        }
         ReadStoredFile {
-            remaining_addrs: self.addrs.into_iter(),
-            buf: Vec::<u8>::new(),
-            buf_cursor: 0,
+            addrs: self.addrs,
+            block_starts,
+            pos: 0,
+            buf: None,
             block_dir: self.block_dir,
         }
     }
@@ -66,47 +99,152 @@ impl ReadBlocks for StoredFile {
     }
 }
 
-/// Adapt a StoredFile to `std::io::Read`, which requires keeping a cursor position.
+/// Adapt a StoredFile to `std::io::Read` and `std::io::Seek`, which requires
+/// keeping a cursor position.
+///
+/// Only the block containing the current position is held in memory, so
+/// seeking to read part of a file only reads the blocks that are needed.
 #[derive(Debug)]
 pub struct ReadStoredFile {
-    /// Block addresses remaining to be read.
-    remaining_addrs: std::vec::IntoIter<blockdir::Address>,
+    /// All addresses for this file.
+    addrs: Vec<blockdir::Address>,
 
-    // TODO: buf, buf_cursor, remaining_addrs all really belong in some kind of `Read` adapter, not
-    // the StoredFile itself.
-    /// Already-read but not yet returned data.
-    buf: Vec<u8>,
+    /// The position in the file where each block starts, followed by the
+    /// length of the file.
+    block_starts: Vec<u64>,
 
-    /// How far through buf has been returned?
-    buf_cursor: usize,
+    /// The current position in the file.
+    pos: u64,
+
+    /// The index and content of the most recently read block.
+    buf: Option<(usize, Vec<u8>)>,
 
     block_dir: BlockDir,
 }
 
-impl std::io::Read for ReadStoredFile {
-    fn read(&mut self, out: &mut [u8]) -> std::io::Result<usize> {
+impl ReadStoredFile {
+    fn len(&self) -> u64 {
+        *self.block_starts.last().unwrap()
+    }
+}
+
+impl Read for ReadStoredFile {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
         // TODO: Readahead n_cpus blocks into memory, using futures-cpupool or similar.
-        loop {
-            // If there's already buffered data, return as much of that as will fit.
-            let avail = self.buf.len() - self.buf_cursor;
-            if avail > 0 {
-                let s = std::cmp::min(out.len(), avail);
-                let r = &self.buf[self.buf_cursor..self.buf_cursor + s];
-                out[..s].copy_from_slice(r);
-                self.buf_cursor += s;
-                return Ok(s);
-            } else if let Some(addr) = self.remaining_addrs.next() {
-                // TODO: Handle errors nicely, but they need to convert to std::io::Error.
+        if self.pos >= self.len() || out.is_empty() {
+            return Ok(0);
+        }
+        // The last block starting at or before the current position: since
+        // that's before the end of the file, it's not empty and contains the
+        // position.
+        let i = self.block_starts.partition_point(|&s| s <= self.pos) - 1;
+        let content = match &self.buf {
+            Some((buf_i, content)) if *buf_i == i => content,
+            _ => {
                 // TODO: Remember the sizes somewhere, maybe by changing this not to be
                 // std::io::Read.
-                self.buf = self.block_dir.get(&addr).unwrap().0;
-                self.buf_cursor = 0;
-            // TODO: Read directly into the caller's buffer, if it will fit. Requires changing
-            // BlockDir::get to take a caller-provided buffer.
-            } else {
-                // No data buffered and no more to read, end of file.
-                return Ok(0);
+                // TODO: Read directly into the caller's buffer, if it will fit. Requires changing
+                // BlockDir::get to take a caller-provided buffer.
+                let (content, _sizes) = self
+                    .block_dir
+                    .get(&self.addrs[i])
+                    .map_err(io::Error::other)?;
+                &self.buf.insert((i, content)).1
             }
+        };
+        let offset = (self.pos - self.block_starts[i]) as usize;
+        let s = std::cmp::min(out.len(), content.len() - offset);
+        out[..s].copy_from_slice(&content[offset..offset + s]);
+        self.pos += s as u64;
+        Ok(s)
+    }
+}
+
+impl Seek for ReadStoredFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(n) => {
+                self.pos = n;
+                return Ok(n);
+            }
+            SeekFrom::End(offset) => (self.len(), offset),
+            SeekFrom::Current(offset) => (self.pos, offset),
+        };
+        // As for files, seeking past the end is allowed, but not before the start.
+        match base.checked_add_signed(offset) {
+            Some(n) => {
+                self.pos = n;
+                Ok(n)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockdir::Address;
+    use crate::test_fixtures::ScratchArchive;
+
+    fn stored_file(af: &ScratchArchive) -> StoredFile {
+        let block_dir = af.block_dir().clone();
+        let a = block_dir.store_block(b"0123").unwrap();
+        let b = block_dir.store_block(b"xx4567xx").unwrap();
+        StoredFile::open(
+            block_dir,
+            vec![
+                a.clone(),
+                Address {
+                    hash: b.hash,
+                    start: 2,
+                    len: 4,
+                },
+                Address {
+                    len: 0,
+                    ..a.clone()
+                },
+                a,
+            ],
+        )
+    }
+
+    #[test]
+    fn read_whole_file() {
+        let af = ScratchArchive::new();
+        let f = stored_file(&af);
+        assert_eq!(f.len(), 12);
+        let mut content = String::new();
+        f.into_read().read_to_string(&mut content).unwrap();
+        assert_eq!(content, "012345670123");
+    }
+
+    #[test]
+    fn seek_and_read() {
+        let af = ScratchArchive::new();
+        let mut r = stored_file(&af).into_read();
+        let mut buf = [0u8; 3];
+
+        assert_eq!(r.seek(SeekFrom::Start(3)).unwrap(), 3);
+        r.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"345");
+
+        assert_eq!(r.seek(SeekFrom::Current(1)).unwrap(), 7);
+        r.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"701");
+
+        assert_eq!(r.seek(SeekFrom::End(-2)).unwrap(), 10);
+        let mut rest = Vec::new();
+        r.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, b"23");
+
+        // Seeking past the end reads nothing; seeking before the start fails.
+        assert_eq!(r.seek(SeekFrom::End(5)).unwrap(), 17);
+        assert_eq!(r.read(&mut buf).unwrap(), 0);
+        assert!(r.seek(SeekFrom::Current(-20)).is_err());
+        assert_eq!(r.seek(SeekFrom::Current(0)).unwrap(), 17);
+    }
+}
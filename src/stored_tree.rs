@@ -8,6 +8,8 @@
 //! across incremental backups, hiding from the caller that data may be distributed across
 //! multiple index files, bands, and blocks.
 
+use std::collections::HashSet;
+
 use rayon::iter::ParallelBridge;
 use rayon::prelude::*;
 
@@ -20,21 +22,64 @@ pub struct StoredTree {
     archive: Archive,
     band: Band,
     excludes: GlobSet,
+
+    /// If true, directories that differ only in their mtime aren't counted as
+    /// changed.
+    ignore_dir_mtimes: bool,
+
+    /// If set, entries more than this many directories deep are skipped.
+    max_depth: Option<usize>,
+
+    /// The band's index, kept so that lookups share its hunk cache.
+    index: ReadIndex,
+
+    /// If the band's index is a layer, the tree of the band below it.
+    base: Option<Box<StoredTree>>,
 }
 
 impl StoredTree {
-    /// Open the last complete version in the archive.
-    pub fn open_last(archive: &Archive) -> Result<StoredTree> {
-        let band = archive
-            .last_complete_band()?
-            .ok_or(errors::Error::ArchiveEmpty)?;
+    /// Open the tree of a band, and of the bands below it if its index is
+    /// layered, without checking whether it's complete or signed.
+    pub(crate) fn new(archive: &Archive, band: Band) -> Result<StoredTree> {
+        let base = match band.index_base_band_id()? {
+            Some(base_band_id) => {
+                let base_band = Band::open(archive, &base_band_id)?;
+                if base_band.is_closed()? {
+                    archive.verify_band(&base_band)?;
+                }
+                Some(Box::new(StoredTree::new(archive, base_band)?))
+            }
+            None => None,
+        };
         Ok(StoredTree {
             archive: archive.clone(),
+            index: band.index(),
             band,
             excludes: excludes::excludes_nothing(),
+            ignore_dir_mtimes: false,
+            max_depth: None,
+            base,
         })
     }
 
+    /// Open the last complete version in the archive.
+    pub fn open_last(archive: &Archive) -> Result<StoredTree> {
+        let band = archive
+            .last_complete_band()?
+            .ok_or(errors::Error::ArchiveEmpty)?;
+        archive.verify_band(&band)?;
+        StoredTree::new(archive, band)
+    }
+
+    /// Open the last version in the archive, even if it is incomplete.
+    ///
+    /// This is useful to recover files from a backup that was interrupted; the
+    /// tree may contain only a prefix of the source tree.
+    pub fn open_last_incomplete(archive: &Archive) -> Result<StoredTree> {
+        let band_id = archive.last_band_id()?.ok_or(errors::Error::ArchiveEmpty)?;
+        StoredTree::open_incomplete_version(archive, &band_id)
+    }
+
     /// Open a specified version.
     ///
     /// It's an error if it's not complete.
@@ -45,30 +90,44 @@ impl StoredTree {
                 band_id: band_id.clone(),
             });
         }
-        Ok(StoredTree {
-            archive: archive.clone(),
-            band,
-            excludes: excludes::excludes_nothing(),
-        })
+        archive.verify_band(&band)?;
+        StoredTree::new(archive, band)
     }
 
     /// Open a specified version.
     ///
     /// This function allows opening incomplete versions, which might contain only a partial copy
     /// of the source tree, or maybe nothing at all.
+    ///
+    /// If the archive has a signing key, complete versions must be correctly
+    /// signed, but incomplete versions can't be checked.
     pub fn open_incomplete_version(archive: &Archive, band_id: &BandId) -> Result<StoredTree> {
         let band = Band::open(archive, band_id)?;
-        Ok(StoredTree {
-            archive: archive.clone(),
-            band,
-            excludes: excludes::excludes_nothing(),
-        })
+        if band.is_closed()? {
+            archive.verify_band(&band)?;
+        }
+        StoredTree::new(archive, band)
     }
 
     pub fn with_excludes(self, excludes: GlobSet) -> StoredTree {
         StoredTree { excludes, ..self }
     }
 
+    /// Don't count directories as changed when only their mtime differs, as
+    /// it does whenever an entry is added to or removed from them.
+    pub fn ignore_dir_mtimes(self, ignore_dir_mtimes: bool) -> StoredTree {
+        StoredTree {
+            ignore_dir_mtimes,
+            ..self
+        }
+    }
+
+    /// Only return entries at most `max_depth` directories below the root, or
+    /// below the subtree being iterated.
+    pub fn with_max_depth(self, max_depth: Option<usize>) -> StoredTree {
+        StoredTree { max_depth, ..self }
+    }
+
     pub fn band(&self) -> &Band {
         &self.band
     }
@@ -77,32 +136,179 @@ impl StoredTree {
         &self.archive
     }
 
+    /// The number of bands below this one whose indexes are read to make
+    /// its tree: 0 unless its index is layered.
+    pub fn layers(&self) -> usize {
+        self.base.as_ref().map_or(0, |base| 1 + base.layers())
+    }
+
+    /// Return the index entry for an apath, if it's present in this tree and
+    /// neither it nor any directory containing it is excluded.
+    ///
+    /// This reads only a few index hunks, so it's much faster than iterating
+    /// the tree to find one file.
+    pub fn entry(&self, apath: &Apath) -> Result<Option<IndexEntry>> {
+        let path: &str = apath;
+        let mut ancestors = path
+            .match_indices('/')
+            .skip(1)
+            .map(|(i, _)| &path[..i])
+            .chain(std::iter::once(path));
+        if ancestors.any(|a| self.excludes.is_match(a)) {
+            return Ok(None);
+        }
+        match (self.index.find_entry(apath)?, &self.base) {
+            (Some(entry), _) if entry.is_tombstone() => Ok(None),
+            (Some(entry), _) => Ok(Some(entry)),
+            (None, Some(base)) => base.entry(apath),
+            (None, None) => Ok(None),
+        }
+    }
+
+    /// Iterate `apath`, if it's present, and everything inside it.
+    ///
+    /// This skips reading index hunks entirely outside the subtree, so it's
+    /// much faster than iterating the whole tree to list one directory.
+    pub fn iter_subtree(&self, apath: &Apath) -> Result<index::IndexEntryIter> {
+        let mut iter = self.index.iter_subtree(apath)?;
+        if let Some(base) = &self.base {
+            iter = iter.with_base(base.iter_subtree(apath)?);
+        }
+        Ok(iter
+            .with_excludes(self.excludes.clone())
+            .with_max_depth(self.max_depth))
+    }
+
     pub fn is_closed(&self) -> Result<bool> {
         self.band.is_closed()
     }
 
+    /// Open the version this one was written over: the band its index is
+    /// layered on, or else the basis of the backup, or else the band just
+    /// before it. Returns None for the first version in the archive.
+    pub fn open_previous(&self) -> Result<Option<StoredTree>> {
+        let previous_id = match self.band.index_base_band_id()? {
+            Some(base_band_id) => Some(base_band_id),
+            None => {
+                let band_ids = self.archive.list_bands()?;
+                match self.band.get_info()?.basis_band_id {
+                    Some(basis) if band_ids.contains(&basis) => Some(basis),
+                    _ => band_ids
+                        .into_iter()
+                        .take_while(|band_id| band_id < self.band.id())
+                        .last(),
+                }
+            }
+        };
+        match previous_id {
+            Some(band_id) => Ok(Some(
+                StoredTree::open_incomplete_version(&self.archive, &band_id)?
+                    .with_excludes(self.excludes.clone()),
+            )),
+            None => Ok(None),
+        }
+    }
+
+    /// List the entries that were added, changed, or deleted in this version,
+    /// compared to the version it was written over, in apath order.
+    ///
+    /// For a layered index this reads only the layer and looks up its
+    /// entries in the base, and deletions come from its tombstones.
+    pub fn changes(&self) -> Result<Vec<Change>> {
+        let previous = match self.open_previous()? {
+            Some(previous) => previous,
+            None => {
+                return Ok(self
+                    .iter_entries()?
+                    .map(|entry| Change::new(entry.apath, ChangeKind::Added))
+                    .collect())
+            }
+        };
+        let mut changes = Vec::new();
+        if self.base.is_some() {
+            for entry in self.index.iter()? {
+                if self.excludes.is_match(&entry.apath) {
+                    continue;
+                }
+                let kind = match (entry.kind, previous.entry(&entry.apath)?) {
+                    (Kind::Deleted, Some(_)) => ChangeKind::Deleted,
+                    (Kind::Deleted, None) => continue,
+                    (_, None) => ChangeKind::Added,
+                    (_, Some(old)) if !self.is_changed(&old, &entry) => continue,
+                    (_, Some(_)) => ChangeKind::Changed,
+                };
+                changes.push(Change::new(entry.apath, kind));
+            }
+            return Ok(changes);
+        }
+        for merged in merge_trees(previous.iter_entries()?, self.iter_entries()?) {
+            match merged {
+                MergedEntry::Left(old) => changes.push(Change::new(old.apath, ChangeKind::Deleted)),
+                MergedEntry::Right(entry) => {
+                    changes.push(Change::new(entry.apath, ChangeKind::Added))
+                }
+                MergedEntry::Both(old, entry) if self.is_changed(&old, &entry) => {
+                    changes.push(Change::new(entry.apath, ChangeKind::Changed))
+                }
+                MergedEntry::Both(..) => (),
+            }
+        }
+        Ok(changes)
+    }
+
+    /// True if `new` should be listed as changed from `old`, an entry with
+    /// the same apath in the previous version.
+    fn is_changed(&self, old: &IndexEntry, new: &IndexEntry) -> bool {
+        if self.ignore_dir_mtimes && old.kind == Kind::Dir && new.kind == Kind::Dir {
+            IndexEntry {
+                mtime: new.mtime,
+                mtime_nanos: new.mtime_nanos,
+                ..old.clone()
+            } != *new
+        } else {
+            old != new
+        }
+    }
+
     pub fn validate(&self) -> Result<()> {
         ui::set_progress_phase(&format!("Check tree {}", self.band().id()));
-        self.iter_entries()?
-            .filter(|e| e.kind() == Kind::File)
-            .par_bridge()
-            .map(|e| self.validate_one_entry(&e))
-            .inspect(|e| {
-                if let Err(e) = e {
-                    ui::show_error(e);
-                }
-            })
-            .find_any(Result::is_err)
-            .unwrap_or(Ok(()))
+        self.validate_entries(None)
+    }
+
+    /// Check the content of stored files, counting their bytes towards the
+    /// current progress phase.
+    ///
+    /// Entries are read from the index on one thread, while the files are
+    /// checked across the pool for blocks. Files whose blocks are all in
+    /// `known_good` are not read again.
+    pub(crate) fn validate_entries(&self, known_good: Option<&HashSet<String>>) -> Result<()> {
+        let entries = self.iter_entries()?;
+        threads::in_block_pool(|| {
+            entries
+                .filter(|e| e.kind() == Kind::File)
+                .par_bridge()
+                .map(|e| self.validate_one_entry(&e, known_good))
+                .inspect(|e| {
+                    if let Err(e) = e {
+                        ui::show_error(e);
+                    }
+                })
+                .find_any(Result::is_err)
+                .unwrap_or(Ok(()))
+        })
     }
 
-    fn validate_one_entry(&self, e: &IndexEntry) -> Result<()> {
+    fn validate_one_entry(
+        &self,
+        e: &IndexEntry,
+        known_good: Option<&HashSet<String>>,
+    ) -> Result<()> {
         ui::set_progress_file(e.apath());
-        self.open_stored_file(&e)?.validate()
+        self.open_stored_file(e)?.validate(known_good)
     }
 
     /// Open a file stored within this tree.
-    fn open_stored_file(&self, entry: &IndexEntry) -> Result<StoredFile> {
+    pub fn open_stored_file(&self, entry: &IndexEntry) -> Result<StoredFile> {
         Ok(StoredFile::open(
             self.archive.block_dir().clone(),
             entry.addrs.clone(),
@@ -110,6 +316,37 @@ impl StoredTree {
     }
 }
 
+/// How an entry differs between a version and the one before it.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ChangeKind {
+    Added,
+    Changed,
+    Deleted,
+}
+
+impl ChangeKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            ChangeKind::Added => "added",
+            ChangeKind::Changed => "changed",
+            ChangeKind::Deleted => "deleted",
+        }
+    }
+}
+
+/// One entry that differs between a version and the one before it.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Change {
+    pub apath: Apath,
+    pub kind: ChangeKind,
+}
+
+impl Change {
+    fn new(apath: Apath, kind: ChangeKind) -> Change {
+        Change { apath, kind }
+    }
+}
+
 impl ReadTree for StoredTree {
     type I = index::IndexEntryIter;
     type R = ReadStoredFile;
@@ -117,18 +354,29 @@ impl ReadTree for StoredTree {
 
     /// Return an iter of index entries in this stored tree.
     fn iter_entries(&self) -> Result<index::IndexEntryIter> {
-        Ok(self
-            .band
-            .iter_entries()?
-            .with_excludes(self.excludes.clone()))
+        let mut iter = self.index.iter()?;
+        if let Some(base) = &self.base {
+            iter = iter.with_base(base.iter_entries()?);
+        }
+        Ok(iter
+            .with_excludes(self.excludes.clone())
+            .with_max_depth(self.max_depth))
     }
 
     fn file_contents(&self, entry: &Self::Entry) -> Result<Self::R> {
         Ok(self.open_stored_file(entry)?.into_read())
     }
 
+    fn block_dir(&self) -> Option<&BlockDir> {
+        Some(self.archive.block_dir())
+    }
+
     fn estimate_count(&self) -> Result<u64> {
-        self.band.index().estimate_entry_count()
+        let count = self.index.estimate_entry_count()?;
+        match &self.base {
+            Some(base) => Ok(count.max(base.estimate_count()?)),
+            None => Ok(count),
+        }
     }
 }
 
@@ -147,11 +395,7 @@ mod test {
 
         assert_eq!(*st.band().id(), last_band_id);
 
-        let names: Vec<String> = st
-            .iter_entries()
-            .unwrap()
-            .map(|e| e.apath.into())
-            .collect();
+        let names: Vec<String> = st.iter_entries().unwrap().map(|e| e.apath.into()).collect();
         let expected = if SYMLINKS_SUPPORTED {
             vec![
                 "/",
@@ -167,9 +411,96 @@ mod test {
         assert_eq!(expected, names);
     }
 
+    #[test]
+    pub fn entry_by_apath() {
+        let af = ScratchArchive::new();
+        af.store_two_versions();
+        let st = StoredTree::open_last(&af).unwrap();
+
+        let entry = st.entry(&"/subdir/subfile".into()).unwrap().unwrap();
+        assert_eq!(entry.kind(), Kind::File);
+        assert!(st.entry(&"/nothing".into()).unwrap().is_none());
+
+        let st = st.with_excludes(excludes::from_strings(&["/subdir"]).unwrap());
+        assert!(st.entry(&"/subdir".into()).unwrap().is_none());
+        assert!(st.entry(&"/subdir/subfile".into()).unwrap().is_none());
+        assert!(st.entry(&"/hello".into()).unwrap().is_some());
+    }
+
+    #[test]
+    pub fn iter_subtree() {
+        let af = ScratchArchive::new();
+        af.store_two_versions();
+        let st = StoredTree::open_last(&af).unwrap();
+
+        let names: Vec<String> = st
+            .iter_subtree(&"/subdir".into())
+            .unwrap()
+            .map(|e| e.apath.into())
+            .collect();
+        assert_eq!(names, ["/subdir", "/subdir/subfile"]);
+    }
+
+    #[test]
+    pub fn max_depth() {
+        let af = ScratchArchive::new();
+        af.store_two_versions();
+        let st = StoredTree::open_last(&af).unwrap().with_max_depth(Some(0));
+        let names: Vec<String> = st.iter_entries().unwrap().map(|e| e.apath.into()).collect();
+        assert_eq!(names, ["/"]);
+
+        let st = st.with_max_depth(Some(1));
+        assert!(st
+            .iter_entries()
+            .unwrap()
+            .all(|e| e.apath != "/subdir/subfile"));
+        assert!(st.iter_entries().unwrap().any(|e| e.apath == "/subdir"));
+
+        // Depth is counted from the subtree.
+        let names: Vec<String> = st
+            .with_max_depth(Some(0))
+            .iter_subtree(&"/subdir".into())
+            .unwrap()
+            .map(|e| e.apath.into())
+            .collect();
+        assert_eq!(names, ["/subdir"]);
+    }
+
+    #[test]
+    pub fn changes() {
+        let af = ScratchArchive::new();
+        af.store_two_versions();
+
+        let first = StoredTree::open_version(&af, &BandId::zero()).unwrap();
+        let changes = first.changes().unwrap();
+        assert_eq!(changes.len(), first.iter_entries().unwrap().count());
+        assert!(changes.iter().all(|c| c.kind == ChangeKind::Added));
+
+        let changes = StoredTree::open_last(&af).unwrap().changes().unwrap();
+        assert!(changes.contains(&Change::new("/hello2".into(), ChangeKind::Added)));
+        assert!(!changes.iter().any(|c| c.apath == "/hello"));
+        assert!(!changes.iter().any(|c| c.kind == ChangeKind::Deleted));
+    }
+
     #[test]
     pub fn cant_open_no_versions() {
         let af = ScratchArchive::new();
         assert!(StoredTree::open_last(&af).is_err());
+        assert!(StoredTree::open_last_incomplete(&af).is_err());
+    }
+
+    #[test]
+    pub fn open_last_incomplete() {
+        let af = ScratchArchive::new();
+        af.store_two_versions();
+        af.setup_incomplete_empty_band();
+
+        let st = StoredTree::open_last(&af).unwrap();
+        assert_eq!(*st.band().id(), BandId::new(&[1]));
+
+        let st = StoredTree::open_last_incomplete(&af).unwrap();
+        assert_eq!(*st.band().id(), BandId::new(&[2]));
+        assert!(!st.is_closed().unwrap());
+        assert_eq!(st.iter_entries().unwrap().count(), 0);
     }
 }
@@ -0,0 +1,428 @@
+// Conserve backup system.
+// Copyright 2020 Martin Pool.
+
+//! Read a tar file as a tree, so that it can be imported into an archive.
+//!
+//! Entries in a tar file can be in any order, but trees must be read in apath
+//! order. So, opening a `TarTree` scans all the headers in the file and sorts
+//! them, and file contents are later read by seeking back into the tar file.
+//! As a result only uncompressed tar files, not streams such as pipes, can be
+//! read.
+//!
+//! Hard links are imported as files with the same content as the earlier
+//! entry they link to.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{Read, Seek, SeekFrom, Take};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::vec;
+
+use snafu::ResultExt;
+use tar::EntryType;
+
+use crate::unix_time::UnixTime;
+use crate::*;
+
+/// A tar file, read as a tree.
+#[derive(Debug)]
+pub struct TarTree {
+    path: PathBuf,
+    entries: Vec<TarEntry>,
+    excludes: GlobSet,
+
+    /// Entries that couldn't be imported, not yet taken by a copy.
+    problems: Mutex<Vec<Problem>>,
+}
+
+/// An entry read from a tar file header.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct TarEntry {
+    apath: Apath,
+    kind: Kind,
+    mtime: UnixTime,
+    size: Option<u64>,
+    symlink_target: Option<String>,
+
+    /// Position of the file contents within the tar file.
+    data_position: u64,
+}
+
+impl TarTree {
+    /// Open a tar file and read all its headers.
+    ///
+    /// Entries that can't be represented in an archive are reported as problems
+    /// and skipped.
+    ///
+    /// The tar file must be a regular file, since its contents are read by
+    /// seeking back into it.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<TarTree> {
+        let path = path.as_ref();
+        let ctx = || errors::ReadTar {
+            path: path.to_path_buf(),
+        };
+        let file = fs::File::open(path).with_context(ctx)?;
+        if !file.metadata().with_context(ctx)?.is_file() {
+            return Err(Error::TarNotSeekable {
+                path: path.to_path_buf(),
+            });
+        }
+        let mut tar = tar::Archive::new(file);
+        // Later entries for the same path replace earlier ones, as they would when
+        // extracting the tar file.
+        let mut by_apath = BTreeMap::<Apath, TarEntry>::new();
+        let mut problems = Vec::new();
+        for tar_entry in tar.entries_with_seek().with_context(ctx)? {
+            let tar_entry = tar_entry.with_context(ctx)?;
+            match TarEntry::from_tar(&tar_entry, &by_apath) {
+                Ok(entry) => {
+                    by_apath.insert(entry.apath.clone(), entry);
+                }
+                Err(problem) => problems.push(problem.emit()),
+            }
+        }
+        // Tar files need not contain entries for all parent directories, but
+        // the archive should, so that the tree can be restored.
+        let mut parents = vec![Apath::from("/")];
+        for apath in by_apath.keys() {
+            let mut p: &str = apath;
+            while let Some(slash) = p.rfind('/') {
+                if slash == 0 {
+                    break;
+                }
+                p = &p[..slash];
+                parents.push(Apath::from(p));
+            }
+        }
+        for parent in parents {
+            by_apath
+                .entry(parent.clone())
+                .or_insert_with(|| TarEntry::synthetic_dir(parent));
+        }
+        Ok(TarTree {
+            path: path.to_path_buf(),
+            entries: by_apath.into_values().collect(),
+            excludes: excludes::excludes_nothing(),
+            problems: Mutex::new(problems),
+        })
+    }
+
+    /// Return a new TarTree which when listed will ignore certain files.
+    pub fn with_excludes(self, excludes: GlobSet) -> TarTree {
+        TarTree { excludes, ..self }
+    }
+}
+
+impl ReadTree for TarTree {
+    type Entry = TarEntry;
+    type I = vec::IntoIter<TarEntry>;
+    type R = Take<fs::File>;
+
+    fn iter_entries(&self) -> Result<Self::I> {
+        // As in a live tree, excluding a directory also excludes everything
+        // inside it.
+        let mut excluded_dirs = Vec::<String>::new();
+        let mut entries = Vec::with_capacity(self.entries.len());
+        for e in &self.entries {
+            if excluded_dirs
+                .iter()
+                .any(|d| e.apath.starts_with(d.as_str()))
+            {
+                continue;
+            } else if self.excludes.is_match(&e.apath) {
+                if e.kind == Kind::Dir {
+                    excluded_dirs.push(format!("{}/", e.apath));
+                }
+            } else {
+                entries.push(e.clone());
+            }
+        }
+        Ok(entries.into_iter())
+    }
+
+    fn file_contents(&self, entry: &TarEntry) -> Result<Self::R> {
+        assert_eq!(entry.kind, Kind::File);
+        let ctx = || errors::ReadTar {
+            path: self.path.clone(),
+        };
+        let mut file = fs::File::open(&self.path).with_context(ctx)?;
+        file.seek(SeekFrom::Start(entry.data_position))
+            .with_context(ctx)?;
+        Ok(file.take(entry.size.unwrap_or(0)))
+    }
+
+    fn estimate_count(&self) -> Result<u64> {
+        Ok(self.entries.len() as u64)
+    }
+
+    /// Problems are found when the tar file is opened, so they're all
+    /// returned to the first caller.
+    fn take_problems(&self, _entries: &mut Self::I) -> Problems {
+        std::mem::take(&mut *self.problems.lock().unwrap()).into()
+    }
+}
+
+impl Entry for TarEntry {
+    fn apath(&self) -> &Apath {
+        &self.apath
+    }
+
+    fn kind(&self) -> Kind {
+        self.kind
+    }
+
+    fn mtime(&self) -> UnixTime {
+        self.mtime
+    }
+
+    fn size(&self) -> Option<u64> {
+        self.size
+    }
+
+    fn symlink_target(&self) -> &Option<String> {
+        &self.symlink_target
+    }
+}
+
+impl TarEntry {
+    /// Convert a tar header, or describe why it can't be stored.
+    ///
+    /// Hard links are resolved to the `earlier` entry they link to.
+    fn from_tar<R: Read>(
+        tar_entry: &tar::Entry<R>,
+        earlier: &BTreeMap<Apath, TarEntry>,
+    ) -> std::result::Result<TarEntry, Problem> {
+        let path_bytes = tar_entry.path_bytes();
+        let name = match std::str::from_utf8(&path_bytes) {
+            Ok(name) => name,
+            Err(_) => {
+                return Err(Problem::TarEntry {
+                    name: String::from_utf8_lossy(&path_bytes).into_owned(),
+                    message: "Can't decode name".to_owned(),
+                })
+            }
+        };
+        let problem = |message: String| Problem::TarEntry {
+            name: name.to_owned(),
+            message,
+        };
+        let apath =
+            apath_from_tar_name(name).ok_or_else(|| problem("Can't store this name".to_owned()))?;
+        let header = tar_entry.header();
+        let mtime = UnixTime {
+            secs: header.mtime().unwrap_or(0) as i64,
+            nanosecs: 0,
+        };
+        let mut symlink_target = None;
+        let mut size = None;
+        let kind = match header.entry_type() {
+            EntryType::Regular | EntryType::Continuous => {
+                size = Some(tar_entry.size());
+                Kind::File
+            }
+            EntryType::Directory => Kind::Dir,
+            EntryType::Symlink => {
+                match tar_entry.link_name() {
+                    Ok(Some(target)) => match target.to_str() {
+                        Some(t) => symlink_target = Some(t.to_owned()),
+                        None => return Err(problem("Can't decode symlink target".to_owned())),
+                    },
+                    _ => return Err(problem("Failed to read symlink target".to_owned())),
+                }
+                Kind::Symlink
+            }
+            EntryType::Link => {
+                let target = tar_entry
+                    .link_name()
+                    .ok()
+                    .flatten()
+                    .and_then(|target| target.to_str().and_then(apath_from_tar_name))
+                    .ok_or_else(|| problem("Failed to read hard link target".to_owned()))?;
+                return match earlier.get(&target) {
+                    Some(linked) if linked.kind == Kind::File => Ok(TarEntry {
+                        apath,
+                        ..linked.clone()
+                    }),
+                    _ => Err(problem(format!(
+                        "Hard link target {:?} is not an earlier file",
+                        target
+                    ))),
+                };
+            }
+            other => return Err(problem(format!("Unsupported entry type {:?}", other))),
+        };
+        Ok(TarEntry {
+            apath,
+            kind,
+            mtime,
+            size,
+            symlink_target,
+            data_position: tar_entry.raw_file_position(),
+        })
+    }
+
+    fn synthetic_dir(apath: Apath) -> TarEntry {
+        TarEntry {
+            apath,
+            kind: Kind::Dir,
+            mtime: UnixTime {
+                secs: 0,
+                nanosecs: 0,
+            },
+            size: None,
+            symlink_target: None,
+            data_position: 0,
+        }
+    }
+}
+
+/// Convert a name from a tar header, like `./a/b/` or `a/b`, to an apath.
+///
+/// Returns None if the name can't be represented, for example because it
+/// contains `..`.
+fn apath_from_tar_name(name: &str) -> Option<Apath> {
+    let parts: Vec<&str> = name
+        .split('/')
+        .filter(|p| !p.is_empty() && *p != ".")
+        .collect();
+    let apath = format!("/{}", parts.join("/"));
+    if Apath::is_valid(&apath) {
+        Some(Apath::from(apath))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use tempfile::NamedTempFile;
+
+    use super::*;
+
+    fn append_file(builder: &mut tar::Builder<fs::File>, name: &str, content: &[u8]) {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(1_500_000_000);
+        header.set_entry_type(EntryType::Regular);
+        builder.append_data(&mut header, name, content).unwrap();
+    }
+
+    fn make_tar() -> NamedTempFile {
+        let tf = NamedTempFile::new().unwrap();
+        let mut builder = tar::Builder::new(tf.reopen().unwrap());
+        append_file(&mut builder, "./zzz", b"last");
+        append_file(&mut builder, "./sub/dir/deep", b"deep contents");
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(EntryType::Directory);
+        header.set_mode(0o755);
+        header.set_size(0);
+        builder
+            .append_data(&mut header, "./sub/", std::io::empty())
+            .unwrap();
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(EntryType::Symlink);
+        header.set_size(0);
+        builder.append_link(&mut header, "./link", "zzz").unwrap();
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(EntryType::Link);
+        header.set_size(0);
+        builder
+            .append_link(&mut header, "./sub/hardlink", "sub/dir/deep")
+            .unwrap();
+        builder
+            .append_link(&mut header, "./dangling", "nothing")
+            .unwrap();
+        // The tar crate refuses to write names containing `..`, so poke it directly
+        // into the header.
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(EntryType::Regular);
+        header.set_size(4);
+        header.as_gnu_mut().unwrap().name[..9].copy_from_slice(b"../escape");
+        header.set_cksum();
+        builder.append(&header, &b"nope"[..]).unwrap();
+        append_file(&mut builder, "aaa", b"first");
+        builder.finish().unwrap();
+        tf
+    }
+
+    #[test]
+    fn tar_names_to_apaths() {
+        assert_eq!(apath_from_tar_name("./"), Some(Apath::from("/")));
+        assert_eq!(apath_from_tar_name("a/b/"), Some(Apath::from("/a/b")));
+        assert_eq!(apath_from_tar_name("/a//b"), Some(Apath::from("/a/b")));
+        assert_eq!(apath_from_tar_name("./a/./b"), Some(Apath::from("/a/b")));
+        assert_eq!(apath_from_tar_name("a/../b"), None);
+    }
+
+    #[test]
+    fn read_tar_in_apath_order() {
+        let tf = make_tar();
+        let tree = TarTree::open(tf.path()).unwrap();
+        let entries: Vec<TarEntry> = tree.iter_entries().unwrap().collect();
+        let names: Vec<&str> = entries.iter().map(|e| e.apath.as_ref()).collect();
+        assert_eq!(
+            names,
+            [
+                "/",
+                "/aaa",
+                "/link",
+                "/sub",
+                "/zzz",
+                "/sub/dir",
+                "/sub/hardlink",
+                "/sub/dir/deep"
+            ]
+        );
+        assert_eq!(entries[1].kind, Kind::File);
+        assert_eq!(entries[1].size, Some(5));
+        assert_eq!(entries[1].mtime.secs, 1_500_000_000);
+        assert_eq!(entries[2].kind, Kind::Symlink);
+        assert_eq!(entries[2].symlink_target.as_deref(), Some("zzz"));
+        assert_eq!(entries[3].kind, Kind::Dir);
+        // Synthesized parent directory
+        assert_eq!(entries[5].kind, Kind::Dir);
+
+        for entry in &entries[6..] {
+            let mut content = String::new();
+            tree.file_contents(entry)
+                .unwrap()
+                .read_to_string(&mut content)
+                .unwrap();
+            assert_eq!(content, "deep contents");
+        }
+
+        let problems = tree.take_problems(&mut tree.iter_entries().unwrap());
+        let names: Vec<String> = problems
+            .iter()
+            .map(|problem| match problem {
+                Problem::TarEntry { name, .. } => name.clone(),
+                other => panic!("unexpected {:?}", other),
+            })
+            .collect();
+        assert_eq!(names, ["dangling", "../escape"]);
+    }
+
+    #[test]
+    fn pipes_are_refused() {
+        let err = TarTree::open("/dev/null").unwrap_err();
+        assert!(matches!(err, Error::TarNotSeekable { .. }), "{}", err);
+    }
+
+    #[test]
+    fn tar_with_excludes() {
+        let tf = make_tar();
+        let tree = TarTree::open(tf.path())
+            .unwrap()
+            .with_excludes(excludes::from_strings(&["/sub"]).unwrap());
+        let names: Vec<String> = tree
+            .iter_entries()
+            .unwrap()
+            .map(|e| e.apath.into())
+            .collect();
+        assert_eq!(names, ["/", "/aaa", "/link", "/zzz"]);
+    }
+}
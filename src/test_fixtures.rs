@@ -1,10 +1,11 @@
 // Conserve backup system.
-// Copyright 2016, 2017, 2018, 2019 Martin Pool.
+// Copyright 2016, 2017, 2018, 2019, 2020 Martin Pool.
 
 /// Utilities to set up test environments.
 ///
 /// Fixtures that create directories will be automatically deleted when the object
 /// is deleted.
+use std::collections::HashSet;
 use std::fs;
 use std::io::Write;
 use std::ops::Deref;
@@ -117,14 +118,20 @@ impl TreeFixture {
 
     /// Make a file in the tree, with given contents. Returns the full path.
     pub fn create_file_with_contents(&self, relative_path: &str, contents: &[u8]) -> PathBuf {
-        let full_path = self.root.join(relative_path);
+        let full_path = self.long_path(relative_path);
         let mut f = fs::File::create(&full_path).unwrap();
         f.write_all(contents).unwrap();
         full_path
     }
 
     pub fn create_dir(&self, relative_path: &str) {
-        fs::create_dir(self.root.join(relative_path)).unwrap();
+        fs::create_dir(self.long_path(relative_path)).unwrap();
+    }
+
+    /// The path of a file in the tree, in a form that works even if it's
+    /// very long.
+    fn long_path(&self, relative_path: &str) -> PathBuf {
+        crate::io::apath_path(&crate::io::long_path(&self.root), relative_path)
     }
 
     #[cfg(unix)]
@@ -160,3 +167,284 @@ impl Default for TreeFixture {
         Self::new()
     }
 }
+
+/// Styles of names for generated files and directories.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum NameStyle {
+    /// Short names of lowercase letters and digits.
+    Ascii,
+    /// Names of any length up to 40 characters, with upper case, spaces,
+    /// punctuation, and non-ASCII characters.
+    Mixed,
+    /// Names of 100 to 200 characters.
+    Long,
+}
+
+/// Makes a tree of files, directories, and symlinks in a `TreeFixture`, at a
+/// scale and shape set by its options.
+///
+/// The same seed and options always make the same tree.
+///
+/// ```
+/// use conserve::test_fixtures::{TreeFixture, TreeGenerator};
+///
+/// let tf = TreeFixture::new();
+/// let generated = TreeGenerator::new(1).files(50).dirs(5).generate(&tf);
+/// assert_eq!(generated.files, 50);
+/// ```
+#[derive(Debug, Clone)]
+pub struct TreeGenerator {
+    seed: u64,
+    files: usize,
+    dirs: usize,
+    max_depth: usize,
+    max_file_size: u64,
+    symlinks: usize,
+    names: NameStyle,
+}
+
+/// What a `TreeGenerator` made.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct GeneratedTree {
+    pub files: usize,
+    pub dirs: usize,
+    pub symlinks: usize,
+    /// The total size of the files.
+    pub file_bytes: u64,
+}
+
+impl TreeGenerator {
+    pub fn new(seed: u64) -> TreeGenerator {
+        TreeGenerator {
+            seed,
+            files: 100,
+            dirs: 10,
+            max_depth: 3,
+            max_file_size: 64 << 10,
+            symlinks: 0,
+            names: NameStyle::Ascii,
+        }
+    }
+
+    /// Make this many files.
+    pub fn files(self, files: usize) -> TreeGenerator {
+        TreeGenerator { files, ..self }
+    }
+
+    /// Make this many directories, below the root.
+    pub fn dirs(self, dirs: usize) -> TreeGenerator {
+        TreeGenerator { dirs, ..self }
+    }
+
+    /// Nest directories at most this deep below the root.
+    pub fn max_depth(self, max_depth: usize) -> TreeGenerator {
+        TreeGenerator { max_depth, ..self }
+    }
+
+    /// Make files at most this large.
+    ///
+    /// Sizes are spread evenly over their order of magnitude, so most files
+    /// are small, as in real trees.
+    pub fn max_file_size(self, max_file_size: u64) -> TreeGenerator {
+        TreeGenerator {
+            max_file_size,
+            ..self
+        }
+    }
+
+    /// Make this many symlinks, mostly to generated files, and some
+    /// dangling. They're skipped where symlinks aren't supported.
+    pub fn symlinks(self, symlinks: usize) -> TreeGenerator {
+        TreeGenerator { symlinks, ..self }
+    }
+
+    /// Name files and directories in this style.
+    pub fn names(self, names: NameStyle) -> TreeGenerator {
+        TreeGenerator { names, ..self }
+    }
+
+    /// Make the tree inside `tf`.
+    pub fn generate(&self, tf: &TreeFixture) -> GeneratedTree {
+        let mut rng = SplitMix64(self.seed);
+        let mut generated = GeneratedTree::default();
+        let mut used = HashSet::new();
+        // Relative paths of directories, and their depth.
+        let mut dirs: Vec<(String, usize)> = vec![(String::new(), 0)];
+        for _ in 0..self.dirs {
+            let parents: Vec<&(String, usize)> = dirs
+                .iter()
+                .filter(|(_, depth)| *depth < self.max_depth)
+                .collect();
+            if parents.is_empty() {
+                break;
+            }
+            let (parent, depth) = parents[rng.below(parents.len() as u64) as usize].clone();
+            let path = self.unused_path(&mut rng, &mut used, &parent);
+            tf.create_dir(&path);
+            dirs.push((path, depth + 1));
+            generated.dirs += 1;
+        }
+        let mut files = Vec::with_capacity(self.files);
+        let mut content = Vec::new();
+        for _ in 0..self.files {
+            let parent = &dirs[rng.below(dirs.len() as u64) as usize].0;
+            let path = self.unused_path(&mut rng, &mut used, parent);
+            let size = rng.size_up_to(self.max_file_size);
+            content.clear();
+            content.extend((0..size).map(|_| rng.next_u64() as u8));
+            tf.create_file_with_contents(&path, &content);
+            files.push(path);
+            generated.files += 1;
+            generated.file_bytes += size;
+        }
+        if SYMLINKS_SUPPORTED {
+            for _ in 0..self.symlinks {
+                let parent = dirs[rng.below(dirs.len() as u64) as usize].0.clone();
+                let path = self.unused_path(&mut rng, &mut used, &parent);
+                let target = if files.is_empty() || rng.below(10) == 0 {
+                    "dangling".to_owned()
+                } else {
+                    // Absolute within the fixture, so it resolves from any
+                    // directory.
+                    let file = &files[rng.below(files.len() as u64) as usize];
+                    tf.path().join(file).to_string_lossy().into_owned()
+                };
+                tf.create_symlink(&path, &target);
+                generated.symlinks += 1;
+            }
+        }
+        generated
+    }
+
+    /// Make a new name within `parent`, and return its relative path.
+    fn unused_path(
+        &self,
+        rng: &mut SplitMix64,
+        used: &mut HashSet<String>,
+        parent: &str,
+    ) -> String {
+        loop {
+            let name = self.name(rng);
+            let path = if parent.is_empty() {
+                name
+            } else {
+                format!("{}/{}", parent, name)
+            };
+            // Compare case-insensitively, so that trees can be made on
+            // filesystems that ignore case.
+            if used.insert(path.to_lowercase()) {
+                return path;
+            }
+        }
+    }
+
+    fn name(&self, rng: &mut SplitMix64) -> String {
+        const ASCII: &[char] = &[
+            'a', 'b', 'c', 'd', 'e', 'f', 'g', 'h', 'i', 'j', 'k', 'l', 'm', 'n', 'o', 'p', 'q',
+            'r', 's', 't', 'u', 'v', 'w', 'x', 'y', 'z', '0', '1', '2', '3', '4', '5', '6', '7',
+            '8', '9',
+        ];
+        const MIXED: &[char] = &[
+            'A', 'Z', 'a', 'e', 'o', 'z', '0', '9', ' ', '-', '_', '.', '(', ')', '#', '\'', '~',
+            '+', '=', '@', 'é', 'ß', 'ø', 'Ω', 'Ж', 'ש', '日', '本', '🦀',
+        ];
+        let (chars, min_len, max_len) = match self.names {
+            NameStyle::Ascii => (ASCII, 1, 12),
+            NameStyle::Mixed => (MIXED, 1, 40),
+            NameStyle::Long => (ASCII, 100, 200),
+        };
+        let len = min_len + rng.below(max_len - min_len + 1);
+        let name: String = (0..len)
+            .map(|_| chars[rng.below(chars.len() as u64) as usize])
+            .collect();
+        if name.starts_with('.') || name.ends_with('.') || name.ends_with(' ') {
+            // Avoid names that are special or that Windows would change.
+            format!("x{}x", name)
+        } else {
+            name
+        }
+    }
+}
+
+/// A small, fast, deterministic pseudo-random generator.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// A number less than `n`, which must be positive.
+    fn below(&mut self, n: u64) -> u64 {
+        self.next_u64() % n
+    }
+
+    /// A size up to `max`, spread evenly over its order of magnitude.
+    fn size_up_to(&mut self, max: u64) -> u64 {
+        let bits = 64 - max.leading_zeros() as u64;
+        let limit = 1u64
+            .checked_shl(self.below(bits + 1) as u32)
+            .unwrap_or(u64::MAX);
+        self.below(limit).min(max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn list(tf: &TreeFixture) -> Vec<(Apath, Kind, Option<u64>)> {
+        tf.live_tree()
+            .iter_entries()
+            .unwrap()
+            .map(|e| (e.apath().clone(), e.kind(), e.size()))
+            .collect()
+    }
+
+    #[test]
+    fn generated_trees_are_repeatable() {
+        for names in &[NameStyle::Ascii, NameStyle::Mixed, NameStyle::Long] {
+            let generator = TreeGenerator::new(7)
+                .files(200)
+                .dirs(20)
+                .max_depth(4)
+                .symlinks(10)
+                .names(*names);
+            let a = TreeFixture::new();
+            let b = TreeFixture::new();
+            let generated = generator.generate(&a);
+            assert_eq!(generator.generate(&b), generated);
+            assert_eq!(list(&a), list(&b));
+
+            let entries = list(&a);
+            assert_eq!(generated.files, 200);
+            assert_eq!(generated.dirs, 20);
+            assert_eq!(
+                entries.iter().filter(|e| e.1 == Kind::File).count(),
+                generated.files
+            );
+            assert_eq!(
+                entries.iter().filter(|e| e.1 == Kind::Dir).count(),
+                generated.dirs + 1
+            );
+            assert!(entries.iter().all(|e| e.0.split('/').count() <= 4 + 1 + 1));
+            assert_eq!(
+                entries.iter().filter_map(|e| e.2).sum::<u64>(),
+                generated.file_bytes
+            );
+            if SYMLINKS_SUPPORTED {
+                assert_eq!(entries.iter().filter(|e| e.1 == Kind::Symlink).count(), 10);
+            }
+        }
+
+        let a = TreeFixture::new();
+        let b = TreeFixture::new();
+        TreeGenerator::new(1).generate(&a);
+        TreeGenerator::new(2).generate(&b);
+        assert_ne!(list(&a), list(&b));
+    }
+}
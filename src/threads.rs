@@ -0,0 +1,132 @@
+// Conserve backup system.
+// Copyright 2020 Martin Pool.
+
+//! Limit how many threads Conserve uses, so that a program embedding it can
+//! bound its use of the machine.
+//!
+//! Work on blocks (hashing, compressing, and writing them while backing up,
+//! and reading them back to validate or restore) runs on one pool, and
+//! sending blocks and index files to another archive runs on another. Each
+//! block is hashed and written by the same thread, so there's no separate
+//! limit on writers. Source trees and indexes are walked on the calling
+//! thread.
+//!
+//! Work started from a thread that's already in a rayon pool, such as one
+//! made by `in_thread_pool` or by the embedding program, stays in that pool.
+
+use std::sync::{Arc, Mutex};
+
+use lazy_static::lazy_static;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use snafu::ResultExt;
+
+use crate::*;
+
+/// How many threads to use for each kind of work.
+///
+/// `None` uses rayon's global pool, which has one thread per core.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Threads {
+    /// Threads hashing, compressing, writing, and reading blocks.
+    pub blocks: Option<usize>,
+
+    /// Threads sending blocks and index files when pushing to another
+    /// archive.
+    pub transport: Option<usize>,
+}
+
+impl Threads {
+    /// Use at most `n` threads for each kind of work.
+    pub fn all(n: usize) -> Threads {
+        Threads {
+            blocks: Some(n),
+            transport: Some(n),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Pools {
+    threads: Threads,
+    blocks: Option<Arc<ThreadPool>>,
+    transport: Option<Arc<ThreadPool>>,
+}
+
+lazy_static! {
+    static ref POOLS: Mutex<Pools> = Mutex::default();
+}
+
+/// Set how many threads are used from now on, for work not already started.
+pub fn set_threads(threads: Threads) -> Result<()> {
+    let build = |n: Option<usize>| -> Result<Option<Arc<ThreadPool>>> {
+        n.map(|n| {
+            ThreadPoolBuilder::new()
+                .num_threads(n)
+                .build()
+                .map(Arc::new)
+                .context(errors::ThreadPool)
+        })
+        .transpose()
+    };
+    let pools = Pools {
+        threads,
+        blocks: build(threads.blocks)?,
+        transport: build(threads.transport)?,
+    };
+    *POOLS.lock().unwrap() = pools;
+    Ok(())
+}
+
+/// The number of threads set by `set_threads`.
+pub fn threads() -> Threads {
+    POOLS.lock().unwrap().threads
+}
+
+/// Run `f`, and any parallel iterators inside it, on the pool for blocks.
+pub(crate) fn in_block_pool<T: Send, F: FnOnce() -> T + Send>(f: F) -> T {
+    let pool = POOLS.lock().unwrap().blocks.clone();
+    install(pool, f)
+}
+
+/// Run `f`, and any parallel iterators inside it, on the pool for sending
+/// files to another archive.
+pub(crate) fn in_transport_pool<T: Send, F: FnOnce() -> T + Send>(f: F) -> T {
+    let pool = POOLS.lock().unwrap().transport.clone();
+    install(pool, f)
+}
+
+fn install<T: Send, F: FnOnce() -> T + Send>(pool: Option<Arc<ThreadPool>>, f: F) -> T {
+    match pool {
+        Some(pool) if rayon::current_thread_index().is_none() => pool.install(f),
+        _ => f(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limit_threads() {
+        assert_eq!(Threads::all(2).blocks, Some(2));
+
+        set_threads(Threads {
+            blocks: Some(2),
+            transport: None,
+        })
+        .unwrap();
+        assert_eq!(threads().blocks, Some(2));
+        assert_eq!(in_block_pool(rayon::current_num_threads), 2);
+        // Work already in a pool stays there.
+        assert_eq!(
+            in_thread_pool(Some(3), || in_block_pool(rayon::current_num_threads)).unwrap(),
+            3
+        );
+
+        set_threads(Threads::default()).unwrap();
+        assert_eq!(
+            in_block_pool(rayon::current_num_threads),
+            rayon::current_num_threads()
+        );
+    }
+}
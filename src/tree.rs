@@ -1,14 +1,21 @@
 // Conserve backup system.
 // Copyright 2017, 2018, 2019, 2020 Martin Pool.
 
-//! Abstract Tree trait.
+//! Abstract Tree traits.
+//!
+//! Every readable tree, whether live on the filesystem (`LiveTree`), stored in
+//! an archive (`StoredTree`), or in a tar file (`TarTree`), implements
+//! `ReadTree`, and every writable tree implements `WriteTree`. So generic
+//! operations such as `copy_tree` and `iter_merged_entries` work on any
+//! combination of trees.
 
 use std::ops::Range;
 
 use crate::stats::{CopyStats, Sizes};
 use crate::*;
 
-/// Abstract Tree that may be either on the real filesystem or stored in an archive.
+/// A tree that can be read, whether on the real filesystem, stored in an
+/// archive, or in a tar file.
 pub trait ReadTree {
     type Entry: Entry;
     type I: Iterator<Item = Self::Entry>;
@@ -24,23 +31,43 @@ pub trait ReadTree {
     /// Read file contents as a `std::io::Read`.
     fn file_contents(&self, entry: &Self::Entry) -> Result<Self::R>;
 
+    /// The block directory holding the content of stored files, for trees in
+    /// an archive, so that parts of files can be read without the rest.
+    fn block_dir(&self) -> Option<&BlockDir> {
+        None
+    }
+
     /// Estimate the number of entries in the tree.
     /// This might do somewhat expensive IO, so isn't the Iter's `size_hint`.
     fn estimate_count(&self) -> Result<u64>;
 
+    /// Return and forget the non-fatal problems found so far by an iterator
+    /// from `iter_entries`.
+    ///
+    /// Each iterator has its own problems, so walking the tree again doesn't
+    /// repeat them.
+    fn take_problems(&self, _entries: &mut Self::I) -> Problems {
+        Problems::default()
+    }
+
     /// Measure the tree size.
     ///
     /// This typically requires walking all entries, which may take a while.
     fn size(&self) -> Result<TreeSize> {
-        let mut tot = 0u64;
-        for e in self.iter_entries()? {
-            // While just measuring size, ignore directories/files we can't stat.
-            let s = e.size().unwrap_or(0);
-            tot += s;
-            ui::increment_bytes_done(s);
-        }
-        Ok(TreeSize { file_bytes: tot })
+        Ok(measure_entries(self.iter_entries()?))
+    }
+}
+
+/// Add up the size of some entries, showing progress.
+pub(crate) fn measure_entries<E: Entry>(entries: impl Iterator<Item = E>) -> TreeSize {
+    let mut tot = 0u64;
+    for e in entries {
+        // While just measuring size, ignore directories/files we can't stat.
+        let s = e.size().unwrap_or(0);
+        tot += s;
+        ui::increment_bytes_done(s);
     }
+    TreeSize { file_bytes: tot }
 }
 
 /// A tree open for writing, either local or an an archive.
@@ -52,6 +79,15 @@ pub trait ReadTree {
 pub trait WriteTree {
     fn finish(self) -> Result<CopyStats>;
 
+    /// Write out what's been copied so far, when copying stops early,
+    /// without finishing the tree.
+    fn checkpoint(self) -> Result<CopyStats>
+    where
+        Self: Sized,
+    {
+        Ok(CopyStats::default())
+    }
+
     /// Copy a directory entry from a source tree to this tree.
     fn copy_dir<E: Entry>(&mut self, entry: &E) -> Result<()>;
 
@@ -64,6 +100,12 @@ pub trait WriteTree {
     // TODO: Use some better interface than IO::Read, that permits getting sizes
     // from the source file when restoring.
     fn copy_file<R: ReadTree>(&mut self, entry: &R::Entry, from_tree: &R) -> Result<CopyStats>;
+
+    /// True if `copy_file` reports bytes to the progress bar as it writes
+    /// them, so the caller shouldn't count each whole file when it's done.
+    fn reports_file_progress(&self) -> bool {
+        false
+    }
 }
 
 /// Read a file as a series of blocks of bytes.
@@ -0,0 +1,145 @@
+// Conserve backup system.
+// Copyright 2020 Martin Pool.
+
+//! Settings that trade memory and file counts against throughput when
+//! writing backups.
+//!
+//! The defaults suit most trees, but an archive of a few huge files may be
+//! faster with bigger blocks, and one of millions of tiny files with bigger
+//! index hunks. They're stored in the archive header so that every backup
+//! into the archive uses the same settings, and none of them affect how
+//! existing bands are read.
+
+use serde::{Deserialize, Serialize};
+use snafu::ensure;
+
+use crate::*;
+
+/// Smallest allowed block size.
+const MIN_BLOCK_SIZE: usize = 4 << 10;
+
+/// Largest allowed block size, small enough that blocks can still be
+/// transferred by the server.
+const LARGEST_BLOCK_SIZE: usize = 16 << 20;
+
+/// Largest allowed number of entries per index hunk.
+const MAX_HUNK_ENTRIES: usize = 100_000;
+
+/// Thresholds for writing blocks and index hunks.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Tuning {
+    /// Break file content into blocks of this many uncompressed bytes.
+    pub block_size: usize,
+
+    /// Files no bigger than this are combined into shared blocks, or none
+    /// are if it's 0.
+    pub small_file_size: u64,
+
+    /// Write an index hunk once it has this many entries.
+    pub index_hunk_entries: usize,
+
+    /// Store queued blocks once there are this many bytes of them.
+    ///
+    /// Bigger batches are compressed with more parallelism, but use more
+    /// memory.
+    pub flush_bytes: usize,
+}
+
+impl Default for Tuning {
+    fn default() -> Tuning {
+        Tuning {
+            block_size: MAX_BLOCK_SIZE,
+            small_file_size: DEFAULT_SMALL_FILE_SIZE,
+            index_hunk_entries: index::MAX_ENTRIES_PER_HUNK,
+            flush_bytes: 16 * MAX_BLOCK_SIZE,
+        }
+    }
+}
+
+impl Tuning {
+    pub(crate) fn is_default(&self) -> bool {
+        *self == Tuning::default()
+    }
+
+    /// Check the settings are within the range Conserve can use.
+    pub fn validate(&self) -> Result<()> {
+        let fail = |message: String| errors::InvalidTuning { message };
+        ensure!(
+            (MIN_BLOCK_SIZE..=LARGEST_BLOCK_SIZE).contains(&self.block_size),
+            fail(format!(
+                "block size must be between {} and {} bytes",
+                MIN_BLOCK_SIZE, LARGEST_BLOCK_SIZE
+            ))
+        );
+        ensure!(
+            self.small_file_size <= self.block_size as u64,
+            fail("small file size can't be more than the block size".to_owned())
+        );
+        ensure!(
+            (1..=MAX_HUNK_ENTRIES).contains(&self.index_hunk_entries),
+            fail(format!(
+                "index hunk entries must be between 1 and {}",
+                MAX_HUNK_ENTRIES
+            ))
+        );
+        ensure!(
+            self.flush_bytes >= self.block_size,
+            fail("flush bytes can't be less than the block size".to_owned())
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_is_valid() {
+        Tuning::default().validate().unwrap();
+        assert!(Tuning::default().is_default());
+    }
+
+    #[test]
+    fn reject_out_of_range() {
+        let bad = [
+            Tuning {
+                block_size: 100,
+                small_file_size: 0,
+                ..Tuning::default()
+            },
+            Tuning {
+                small_file_size: 2 << 20,
+                ..Tuning::default()
+            },
+            Tuning {
+                index_hunk_entries: 0,
+                ..Tuning::default()
+            },
+            Tuning {
+                flush_bytes: 1000,
+                ..Tuning::default()
+            },
+        ];
+        for tuning in &bad {
+            assert!(
+                matches!(tuning.validate(), Err(Error::InvalidTuning { .. })),
+                "{:?}",
+                tuning
+            );
+        }
+    }
+
+    #[test]
+    fn missing_fields_are_default() {
+        let tuning: Tuning = serde_json::from_str(r#"{"block_size": 65536}"#).unwrap();
+        assert_eq!(
+            tuning,
+            Tuning {
+                block_size: 65536,
+                ..Tuning::default()
+            }
+        );
+    }
+}
@@ -2,19 +2,33 @@
 // Copyright 2015, 2016, 2018, 2019, 2020 Martin Pool.
 
 //! Abstract user interface trait.
+//!
+//! The library reports problems and notable events through `tracing`, so that
+//! programs embedding Conserve can subscribe to, filter, and ship them.
+//! `install_tracing_subscriber` renders those events as text, interleaved
+//! with progress bars, for the command line.
 
+use std::cell::RefCell;
 use std::fmt::Write;
 use std::io;
 use std::io::Write as IoWrite;
-use std::sync::Mutex;
+use std::rc::Rc;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
 use std::time::Duration;
 use std::time::Instant;
 
 use crossterm::{cursor, queue, style, terminal};
 use lazy_static::lazy_static;
+use serde::Serialize;
 use thousands::Separable;
 use unicode_segmentation::UnicodeSegmentation;
 
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+
+use crate::log_file::LogFileLayer;
 use crate::stats::Sizes;
 
 const PROGRESS_RATE_LIMIT_MS: u32 = 200;
@@ -40,14 +54,150 @@ struct UIState {
     progress_enabled: bool,
 
     progress_state: ProgressState,
+
+    /// Called with the progress state whenever it would be drawn, whether or
+    /// not a progress bar is enabled, unless the thread making progress has
+    /// its own callback.
+    progress_callback: Option<SharedProgressCallback>,
+
+    verbosity: Verbosity,
+
+    /// Should messages and listings be colored?
+    color_enabled: bool,
+
+    /// Should problems be left out of the terminal, to be summarized later?
+    defer_problems: bool,
+}
+
+/// How much the terminal layer shows, besides output that was specifically
+/// requested such as listings.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum Verbosity {
+    /// Only errors and warnings.
+    Quiet,
+    /// Also informational messages.
+    Normal,
+    /// As Normal; callers may also show filenames.
+    Verbose,
+    /// Also debug messages.
+    Debug,
 }
 
+/// When to color terminal output.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ColorChoice {
+    /// Color when stdout is a terminal, unless the `NO_COLOR` environment
+    /// variable is set.
+    Auto,
+    Always,
+    Never,
+}
+
+/// The role of some text, which determines its color.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Highlight {
+    /// Something present only in the newer or source tree.
+    Added,
+    /// Something present only in the older or stored tree.
+    Removed,
+    Directory,
+    Symlink,
+    Error,
+    Warning,
+}
+
+impl Highlight {
+    fn style(self) -> style::ContentStyle {
+        use style::{Attribute, Color, ContentStyle};
+        match self {
+            Highlight::Added => ContentStyle::new().foreground(Color::Green),
+            Highlight::Removed => ContentStyle::new().foreground(Color::Red),
+            Highlight::Directory => ContentStyle::new().foreground(Color::Blue),
+            Highlight::Symlink => ContentStyle::new().foreground(Color::Cyan),
+            Highlight::Error => ContentStyle::new()
+                .foreground(Color::Red)
+                .attribute(Attribute::Bold),
+            Highlight::Warning => ContentStyle::new()
+                .foreground(Color::Yellow)
+                .attribute(Attribute::Bold),
+        }
+    }
+
+    fn paint_if(self, enabled: bool, s: &str) -> String {
+        if enabled {
+            self.style().apply(s).to_string()
+        } else {
+            s.to_owned()
+        }
+    }
+}
+
+/// A function to be told about progress, for example by a GUI.
+pub type ProgressCallback = Box<dyn Fn(&ProgressState) + Send + Sync>;
+
+/// A function to be told about progress made on one thread.
+pub type ThreadProgressCallback = Box<dyn Fn(&ProgressState)>;
+
+/// A callback that can be called without holding the UI lock.
+type SharedProgressCallback = Arc<dyn Fn(&ProgressState) + Send + Sync>;
+
+/// A callback that's called on the thread making progress.
+type LocalProgressCallback = Rc<dyn Fn(&ProgressState)>;
+
+#[derive(Clone)]
 pub struct ProgressState {
     pub phase: String,
     start: Instant,
     pub bytes_done: u64,
     pub bytes_total: u64,
     pub filename: String,
+
+    /// Number of files completed in this phase.
+    pub files_done: u64,
+
+    /// Bytes that were already present in the archive, for showing the
+    /// deduplication ratio.
+    pub bytes_deduplicated: u64,
+
+    /// Exponentially-smoothed transfer rate in bytes per second, used to
+    /// estimate the time remaining.
+    smoothed_rate: Option<f64>,
+
+    /// Time and bytes_done when the rate was last sampled.
+    last_sample: Option<(Instant, u64)>,
+
+    /// When any progress was last made, for detecting stalls.
+    last_progress: Instant,
+
+    /// True if the current stall was already reported.
+    stall_reported: bool,
+
+    /// True if the stall watchdog asked for the current entry to be
+    /// abandoned.
+    abandon_entry: bool,
+}
+
+/// Weight given to each new rate sample: lower values smooth more.
+const RATE_SMOOTHING: f64 = 0.2;
+
+thread_local! {
+    static THREAD_PROGRESS_CALLBACK: RefCell<Option<LocalProgressCallback>> =
+        RefCell::new(None);
+}
+
+/// Progress to be passed to a callback once the UI state is unlocked, so that
+/// the callback can itself use the UI.
+struct ProgressReport {
+    callback: LocalProgressCallback,
+    state: ProgressState,
+}
+
+impl ProgressReport {
+    fn send(report: Option<ProgressReport>) {
+        if let Some(report) = report {
+            (report.callback)(&report.state)
+        }
+    }
 }
 
 lazy_static! {
@@ -59,36 +209,167 @@ pub fn println(s: &str) {
     UI_STATE.lock().unwrap().println(s);
 }
 
+/// Print a line to stderr, so it's kept apart from listings on stdout.
+pub fn eprintln(s: &str) {
+    UI_STATE.lock().unwrap().eprintln(s);
+}
+
 // TODO: Rather than a directly-called function, hook this into logging.
 pub fn problem<S: AsRef<str>>(s: &S) {
     UI_STATE.lock().unwrap().problem(s.as_ref())
 }
 
-/// Report that a non-fatal error occurred.
+/// Report that a non-fatal error occurred, as an error event.
 ///
 /// The program will continue.
 pub fn show_error(e: &dyn std::error::Error) {
-    // TODO: Convert to logging.
+    tracing::error!("{}", format_error(e));
+}
+
+/// Describe an error and all its causes.
+pub fn format_error(e: &dyn std::error::Error) -> String {
     let mut buf = e.to_string();
     let mut cause = e;
     while let Some(c) = cause.source() {
         write!(&mut buf, "\n  caused by: {}", c).expect("Failed to format error cause");
         cause = c;
     }
-    problem(&buf);
+    buf
+}
+
+/// Set how much is shown on the terminal.
+pub fn set_verbosity(verbosity: Verbosity) {
+    UI_STATE.lock().unwrap().verbosity = verbosity;
+}
+
+pub fn verbosity() -> Verbosity {
+    UI_STATE.lock().unwrap().verbosity
+}
+
+/// Set whether to color output.
+///
+/// Output is not colored by default.
+pub fn set_color(choice: ColorChoice) {
+    use crossterm::tty::IsTty;
+    let enabled = match choice {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        ColorChoice::Auto => {
+            io::stdout().is_tty() && std::env::var_os("NO_COLOR").unwrap_or_default().is_empty()
+        }
+    };
+    UI_STATE.lock().unwrap().color_enabled = enabled;
+}
+
+/// Return `s` colored for its role, if color is enabled.
+pub fn paint(highlight: Highlight, s: &str) -> String {
+    let enabled = UI_STATE.lock().unwrap().color_enabled;
+    highlight.paint_if(enabled, s)
+}
+
+/// Renders tracing events as text: errors and warnings as problems, and other
+/// events as plain messages, depending on the verbosity.
+///
+/// Info events are hidden in quiet mode, and debug events are shown only in
+/// debug mode. Trace events are never shown.
+#[derive(Debug, Default)]
+pub struct TerminalLayer {}
+
+impl<S: Subscriber> Layer<S> for TerminalLayer {
+    fn on_event(&self, event: &Event, _ctx: Context<S>) {
+        let level = *event.metadata().level();
+        if event.metadata().target() == LOG_ONLY_TARGET {
+            return;
+        }
+        let mut ui = UI_STATE.lock().unwrap();
+        if ui.defer_problems && event.metadata().target() == PROBLEM_TARGET {
+            return;
+        }
+        let max_level = match ui.verbosity {
+            Verbosity::Quiet => Level::WARN,
+            Verbosity::Normal | Verbosity::Verbose => Level::INFO,
+            Verbosity::Debug => Level::DEBUG,
+        };
+        if level > max_level {
+            return;
+        }
+        let mut visitor = MessageVisitor::default();
+        event.record(&mut visitor);
+        if level == Level::ERROR {
+            ui.problem(&visitor.message);
+        } else if level == Level::WARN {
+            let prefix = Highlight::Warning.paint_if(ui.color_enabled, "conserve warning:");
+            ui.println(&format!("{} {}", prefix, visitor.message));
+        } else {
+            ui.println(&visitor.message);
+        }
+    }
+}
+
+/// Extracts the formatted message, and any other fields, from an event.
+#[derive(Default)]
+pub(crate) struct MessageVisitor {
+    pub(crate) message: String,
+
+    /// Fields other than the message, as ` name=value` for each.
+    pub(crate) fields: String,
+}
+
+impl Visit for MessageVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            write!(self.message, "{:?}", value).unwrap();
+        } else {
+            write!(self.fields, " {}={:?}", field.name(), value).unwrap();
+        }
+    }
+}
+
+/// Events with this target are written to the log file, if any, at any level,
+/// but not shown on the terminal.
+pub const LOG_ONLY_TARGET: &str = "conserve::log";
+
+/// Tracing target for problems, which the terminal can hold back to be
+/// summarized at the end, with `defer_problems`.
+pub const PROBLEM_TARGET: &str = "conserve::problem";
+
+/// Install a global tracing subscriber that renders events through this module,
+/// and optionally also writes them to a log file.
+///
+/// This should be called once, early, by programs such as the command line
+/// that want Conserve's messages shown on the terminal.
+pub fn install_tracing_subscriber(log_file: Option<LogFileLayer>) {
+    let subscriber = tracing_subscriber::registry()
+        .with(TerminalLayer::default())
+        .with(log_file);
+    tracing::subscriber::set_global_default(subscriber)
+        .expect("Failed to install tracing subscriber");
 }
 
 pub fn set_progress_phase(s: &str) {
     let mut ui = UI_STATE.lock().unwrap();
+    ui.progress_state.note_progress();
     ui.progress_state.phase = s.to_string();
     ui.progress_state.bytes_done = 0;
-    ui.show_progress();
+    ui.progress_state.files_done = 0;
+    ui.progress_state.bytes_deduplicated = 0;
+    ui.progress_state.smoothed_rate = None;
+    ui.progress_state.last_sample = None;
+    // Always report the start of a phase, however recent the last update.
+    ui.last_update = None;
+    let report = ui.show_progress();
+    drop(ui);
+    ProgressReport::send(report);
 }
 
 pub fn set_progress_file(s: &str) {
     let mut ui = UI_STATE.lock().unwrap();
+    ui.progress_state.note_progress();
+    ui.progress_state.abandon_entry = false;
     ui.progress_state.filename = s.into();
-    ui.show_progress();
+    let report = ui.show_progress();
+    drop(ui);
+    ProgressReport::send(report);
 }
 
 pub fn set_bytes_total(bytes_total: u64) {
@@ -97,13 +378,115 @@ pub fn set_bytes_total(bytes_total: u64) {
 
 pub fn increment_bytes_done(b: u64) {
     let mut ui = UI_STATE.lock().unwrap();
+    ui.progress_state.note_progress();
     ui.progress_state.bytes_done += b;
-    ui.show_progress();
+    let report = ui.show_progress();
+    drop(ui);
+    ProgressReport::send(report);
+}
+
+/// Count one more file completed in this phase.
+pub fn increment_files_done() {
+    let mut ui = UI_STATE.lock().unwrap();
+    ui.progress_state.note_progress();
+    ui.progress_state.files_done += 1;
+}
+
+/// Note that the current entry is still making progress, for example because
+/// some of a large file was read, without changing any counters.
+pub fn heartbeat() {
+    UI_STATE.lock().unwrap().progress_state.note_progress();
+}
+
+/// True if the stall watchdog gave up on the current entry, so that whatever
+/// is reading it should fail rather than continue.
+pub fn should_abandon_entry() -> bool {
+    UI_STATE.lock().unwrap().progress_state.abandon_entry
+}
+
+/// Watches for operations that stop making progress, for example because
+/// of a hung network filesystem or a dying disk.
+///
+/// Stops watching when dropped.
+pub struct StallWatchdog {
+    stop: Option<mpsc::Sender<()>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+/// Start warning whenever no progress is made for `timeout`, naming the file
+/// that's stuck.
+///
+/// If `abandon_stalled` is true, the stuck entry is also abandoned, by
+/// `should_abandon_entry` returning true until the next file starts. An entry
+/// blocked in the operating system is only abandoned once that call returns.
+pub fn start_stall_watchdog(timeout: Duration, abandon_stalled: bool) -> StallWatchdog {
+    let (stop, stop_rx) = mpsc::channel();
+    let check_interval = (timeout / 4).min(Duration::from_secs(1));
+    let thread = thread::spawn(move || {
+        while let Err(mpsc::RecvTimeoutError::Timeout) = stop_rx.recv_timeout(check_interval) {
+            let stall = UI_STATE.lock().unwrap().progress_state.check_stall(
+                Instant::now(),
+                timeout,
+                abandon_stalled,
+            );
+            // Warn after releasing the lock, since the terminal layer takes it too.
+            if let Some(message) = stall {
+                tracing::warn!("{}", message);
+            }
+        }
+    });
+    StallWatchdog {
+        stop: Some(stop),
+        thread: Some(thread),
+    }
+}
+
+impl Drop for StallWatchdog {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
 }
 
+/// Count bytes that didn't need to be stored because they were already present.
+pub fn increment_bytes_deduplicated(b: u64) {
+    UI_STATE.lock().unwrap().progress_state.bytes_deduplicated += b;
+}
+
+/// Remove the progress bar, at the end of a phase, and report the final
+/// state to the progress callback.
 pub fn clear_progress() {
     let mut ui = UI_STATE.lock().unwrap();
+    let report = ui.progress_report();
     ui.clear_progress();
+    drop(ui);
+    ProgressReport::send(report);
+}
+
+/// Set or clear a function to be called as progress is made on any thread.
+///
+/// The callback is rate-limited in the same way as drawing progress bars.
+pub fn set_progress_callback(callback: Option<ProgressCallback>) {
+    UI_STATE.lock().unwrap().progress_callback = callback.map(Arc::from);
+}
+
+/// Set or clear a function to be called, instead of any set by
+/// `set_progress_callback`, as progress is made on the calling thread.
+///
+/// This lets operations running at the same time on different threads each
+/// report to their own callback, although they share the progress state.
+/// Progress made on other threads, such as while validating blocks in
+/// parallel, isn't reported to it.
+pub fn set_thread_progress_callback(callback: Option<ThreadProgressCallback>) {
+    THREAD_PROGRESS_CALLBACK.with(|c| *c.borrow_mut() = callback.map(Rc::from));
+}
+
+/// Leave problems out of the terminal, so that the command can summarize
+/// them when it's done. They're still written to the log file.
+pub fn defer_problems(defer: bool) {
+    UI_STATE.lock().unwrap().defer_problems = defer;
 }
 
 /// Enable drawing progress bars, only if stdout is a tty.
@@ -122,6 +505,10 @@ impl Default for UIState {
             progress_present: false,
             progress_enabled: false,
             progress_state: ProgressState::default(),
+            progress_callback: None,
+            verbosity: Verbosity::Normal,
+            color_enabled: false,
+            defer_problems: false,
         }
     }
 }
@@ -134,6 +521,13 @@ impl Default for ProgressState {
             filename: String::new(),
             bytes_done: 0,
             bytes_total: 0,
+            files_done: 0,
+            bytes_deduplicated: 0,
+            smoothed_rate: None,
+            last_sample: None,
+            last_progress: Instant::now(),
+            stall_reported: false,
+            abandon_entry: false,
         }
     }
 }
@@ -178,6 +572,149 @@ pub fn compression_ratio(s: &Sizes) -> f64 {
     }
 }
 
+impl ProgressState {
+    fn note_progress(&mut self) {
+        self.last_progress = Instant::now();
+        self.stall_reported = false;
+    }
+
+    /// If there's been no progress for `timeout`, and this stall wasn't
+    /// reported yet, return a message describing it.
+    fn check_stall(
+        &mut self,
+        now: Instant,
+        timeout: Duration,
+        abandon_stalled: bool,
+    ) -> Option<String> {
+        let stalled_for = now.saturating_duration_since(self.last_progress);
+        if self.stall_reported || stalled_for < timeout {
+            return None;
+        }
+        self.stall_reported = true;
+        let mut message = format!(
+            "No progress for {}s while {}",
+            stalled_for.as_secs(),
+            if self.phase.is_empty() {
+                "working"
+            } else {
+                &self.phase
+            }
+        );
+        if !self.filename.is_empty() {
+            write!(message, " {:?}", self.filename).unwrap();
+            if abandon_stalled {
+                self.abandon_entry = true;
+                message.push_str(": abandoning it");
+            }
+        }
+        Some(message)
+    }
+
+    /// Update the smoothed rate from the bytes done since the last sample.
+    fn sample_rate(&mut self, now: Instant) {
+        if let Some((last_time, last_bytes)) = self.last_sample {
+            let secs = now.duration_since(last_time).as_secs_f64();
+            if secs <= 0.0 {
+                return;
+            }
+            let sample = self.bytes_done.saturating_sub(last_bytes) as f64 / secs;
+            self.smoothed_rate = Some(match self.smoothed_rate {
+                Some(rate) => RATE_SMOOTHING * sample + (1.0 - RATE_SMOOTHING) * rate,
+                None => sample,
+            });
+        }
+        self.last_sample = Some((now, self.bytes_done));
+    }
+
+    /// Estimate the time to finish, if the total and rate are known.
+    pub fn eta(&self) -> Option<Duration> {
+        match self.smoothed_rate {
+            Some(rate) if rate > 0.0 && self.bytes_total > 0 => Some(Duration::from_secs_f64(
+                self.bytes_total.saturating_sub(self.bytes_done) as f64 / rate,
+            )),
+            _ => None,
+        }
+    }
+
+    /// Write the state as one line of JSON, for programs that show their own
+    /// progress.
+    pub fn write_json(&self, w: &mut dyn io::Write) -> io::Result<()> {
+        #[derive(Serialize)]
+        struct ProgressEvent<'a> {
+            phase: &'a str,
+            apath: &'a str,
+            bytes_done: u64,
+            bytes_total: u64,
+            files_done: u64,
+            elapsed_secs: f64,
+            eta_secs: Option<f64>,
+        }
+        let event = ProgressEvent {
+            phase: &self.phase,
+            apath: &self.filename,
+            bytes_done: self.bytes_done,
+            bytes_total: self.bytes_total,
+            files_done: self.files_done,
+            elapsed_secs: self.start.elapsed().as_secs_f64(),
+            eta_secs: self.eta().map(|eta| eta.as_secs_f64()),
+        };
+        let mut line = serde_json::to_vec(&event)?;
+        line.push(b'\n');
+        w.write_all(&line)
+    }
+
+    /// Format the progress bar as a prefix of numbers and a message, fitting
+    /// in `width` columns.
+    fn render(&self, width: usize) -> (String, String) {
+        const SHOW_PERCENT: bool = true;
+
+        let mut prefix = String::with_capacity(50);
+        let mut message = String::with_capacity(200);
+        let elapsed = self.start.elapsed();
+        let rate = mbps_rate(self.bytes_done, elapsed);
+        if SHOW_PERCENT && self.bytes_total > 0 {
+            write!(prefix, "{:>3}% ", 100 * self.bytes_done / self.bytes_total).unwrap();
+        }
+        write!(prefix, "{} ", duration_to_hms(elapsed)).unwrap();
+        if let Some(eta) = self.eta() {
+            write!(prefix, "eta {} ", duration_to_hms(eta).trim_start()).unwrap();
+        }
+        write!(
+            prefix,
+            "{:>15} ",
+            crate::misc::bytes_to_human_mb(self.bytes_done),
+        )
+        .unwrap();
+        write!(prefix, "{:>8} MB/s ", (rate as u64).separate_with_commas()).unwrap();
+        let elapsed_secs = elapsed.as_secs_f64();
+        if self.files_done > 0 && elapsed_secs > 0.0 {
+            write!(
+                prefix,
+                "{:>6} files/s ",
+                ((self.files_done as f64 / elapsed_secs) as u64).separate_with_commas()
+            )
+            .unwrap();
+        }
+        if self.bytes_deduplicated > 0 && self.bytes_done > 0 {
+            write!(
+                prefix,
+                "{:>3}% dedup ",
+                100 * self.bytes_deduplicated.min(self.bytes_done) / self.bytes_done
+            )
+            .unwrap();
+        }
+        write!(message, "{} {}", self.phase, self.filename).unwrap();
+        let prefix: String = UnicodeSegmentation::graphemes(prefix.as_str(), true)
+            .take(width)
+            .collect();
+        let message_limit = width.saturating_sub(prefix.len());
+        let truncated_message = UnicodeSegmentation::graphemes(message.as_str(), true)
+            .take(message_limit)
+            .collect::<String>();
+        (prefix, truncated_message)
+    }
+}
+
 impl UIState {
     /// Return false if it's too soon after the progress bar was last drawn.
     fn can_update_yet(&mut self) -> bool {
@@ -209,48 +746,48 @@ impl UIState {
         self.last_update = Some(Instant::now());
     }
 
-    fn show_progress(&mut self) {
-        if !self.progress_enabled || !self.can_update_yet() {
-            return;
+    /// The current progress, for this thread's callback if it has one, or
+    /// else the process's.
+    fn progress_report(&self) -> Option<ProgressReport> {
+        let callback = THREAD_PROGRESS_CALLBACK
+            .with(|c| c.borrow().clone())
+            .or_else(|| {
+                self.progress_callback.clone().map(|callback| {
+                    Rc::new(move |state: &ProgressState| callback(state)) as LocalProgressCallback
+                })
+            })?;
+        Some(ProgressReport {
+            callback,
+            state: self.progress_state.clone(),
+        })
+    }
+
+    /// Draw the progress bar, if it's enabled and not drawn too recently, and
+    /// return the progress to report to any callback.
+    #[must_use]
+    fn show_progress(&mut self) -> Option<ProgressReport> {
+        if !self.can_update_yet() {
+            return None;
+        }
+        self.progress_state.sample_rate(Instant::now());
+        let report = self.progress_report();
+        if report.is_some() {
+            self.set_update_timestamp();
         }
+        if self.progress_enabled {
+            self.draw_progress();
+        }
+        report
+    }
+
+    fn draw_progress(&mut self) {
+        // Check the width every time, so that resizing the terminal is handled.
         let w = if let Ok((w, _)) = terminal::size() {
             w as usize
         } else {
             return;
         };
-
-        const SHOW_PERCENT: bool = true;
-
-        let mut prefix = String::with_capacity(50);
-        let mut message = String::with_capacity(200);
-        let state = &self.progress_state;
-        let elapsed = state.start.elapsed();
-        let rate = mbps_rate(state.bytes_done, elapsed);
-        if SHOW_PERCENT && state.bytes_total > 0 {
-            write!(
-                prefix,
-                "{:>3}% ",
-                100 * state.bytes_done / state.bytes_total
-            )
-            .unwrap();
-        }
-        write!(prefix, "{} ", duration_to_hms(elapsed)).unwrap();
-        write!(
-            prefix,
-            "{:>15} ",
-            crate::misc::bytes_to_human_mb(state.bytes_done),
-        )
-        .unwrap();
-        write!(prefix, "{:>8} MB/s ", (rate as u64).separate_with_commas()).unwrap();
-        write!(message, "{} {}", state.phase, state.filename).unwrap();
-        let message_limit = w - prefix.len();
-        let truncated_message = if message.len() < message_limit {
-            message
-        } else {
-            UnicodeSegmentation::graphemes(message.as_str(), true)
-                .take(message_limit)
-                .collect::<String>()
-        };
+        let (prefix, truncated_message) = self.progress_state.render(w);
         let mut stdout = io::stdout();
         queue!(
             stdout,
@@ -274,22 +811,15 @@ impl UIState {
         println!("{}", s);
     }
 
-    fn problem(&mut self, s: &str) {
+    fn eprintln(&mut self, s: &str) {
         self.clear_progress();
-        println!("conserve error: {}", s);
-        // Drawing this way makes messages leak from tests, for unclear reasons.
+        eprintln!("{}", s);
+    }
 
-        // queue!(
-        //     stdout,
-        //     style::SetForegroundColor(style::Color::Red),
-        //     style::SetAttribute(style::Attribute::Bold),
-        //     style::Print("conserve error: "),
-        //     style::SetAttribute(style::Attribute::Reset),
-        //     style::Print(s),
-        //     style::Print("\n"),
-        //     style::ResetColor,
-        // )
-        // .unwrap();
+    fn problem(&mut self, s: &str) {
+        self.clear_progress();
+        let prefix = Highlight::Error.paint_if(self.color_enabled, "conserve error:");
+        println!("{} {}", prefix, s);
     }
 }
 
@@ -305,4 +835,146 @@ mod tests {
         });
         assert_eq!(format!("{:3.1}x", ratio), "2.0x");
     }
+
+    #[test]
+    pub fn progress_eta_from_smoothed_rate() {
+        let mut state = ProgressState {
+            bytes_total: 1000,
+            ..ProgressState::default()
+        };
+        assert_eq!(state.eta(), None);
+        let t0 = Instant::now();
+        state.sample_rate(t0);
+        state.bytes_done = 100;
+        state.sample_rate(t0 + Duration::from_secs(1));
+        assert_eq!(state.eta(), Some(Duration::from_secs(9)));
+        // A burst moves the rate only partway.
+        state.bytes_done = 1100 - 500;
+        state.sample_rate(t0 + Duration::from_secs(2));
+        assert_eq!(state.smoothed_rate, Some(0.2 * 500.0 + 0.8 * 100.0));
+    }
+
+    #[test]
+    pub fn progress_as_json() {
+        let mut state = ProgressState {
+            phase: "Copying".to_owned(),
+            filename: "/hello".to_owned(),
+            bytes_total: 1000,
+            ..ProgressState::default()
+        };
+        let mut buf = Vec::new();
+        state.write_json(&mut buf).unwrap();
+        let t0 = Instant::now();
+        state.sample_rate(t0);
+        state.bytes_done = 100;
+        state.sample_rate(t0 + Duration::from_secs(1));
+        state.write_json(&mut buf).unwrap();
+
+        let lines: Vec<serde_json::Value> = buf
+            .split(|&b| b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["phase"], "Copying");
+        assert_eq!(lines[0]["apath"], "/hello");
+        assert_eq!(lines[0]["bytes_total"], 1000);
+        assert!(lines[0]["eta_secs"].is_null());
+        assert_eq!(lines[1]["bytes_done"], 100);
+        assert_eq!(lines[1]["eta_secs"], 9.0);
+    }
+
+    #[test]
+    pub fn progress_fits_narrow_terminal() {
+        let state = ProgressState {
+            phase: "Copying".to_owned(),
+            filename: "/a/long/filename/\u{1F600}/here".to_owned(),
+            bytes_done: 50,
+            bytes_total: 100,
+            files_done: 3,
+            bytes_deduplicated: 25,
+            ..ProgressState::default()
+        };
+        let (prefix, message) = state.render(200);
+        assert!(prefix.starts_with(" 50% "));
+        assert!(prefix.contains(" 50% dedup "));
+        assert_eq!(message, "Copying /a/long/filename/\u{1F600}/here");
+        for width in 0..60 {
+            let (prefix, message) = state.render(width);
+            assert!(prefix.len() + message.graphemes(true).count() <= width);
+        }
+    }
+
+    #[test]
+    pub fn report_each_stall_once() {
+        let mut state = ProgressState {
+            phase: "Copying".to_owned(),
+            filename: "/big".to_owned(),
+            ..ProgressState::default()
+        };
+        let timeout = Duration::from_secs(30);
+        let t0 = state.last_progress;
+        assert_eq!(
+            state.check_stall(t0 + Duration::from_secs(29), timeout, false),
+            None
+        );
+        assert_eq!(
+            state.check_stall(t0 + Duration::from_secs(31), timeout, false),
+            Some("No progress for 31s while Copying \"/big\"".to_owned())
+        );
+        assert!(!state.abandon_entry);
+        assert_eq!(
+            state.check_stall(t0 + Duration::from_secs(90), timeout, false),
+            None
+        );
+
+        // After progress resumes, a new stall is reported and can abandon the entry.
+        state.note_progress();
+        let t1 = state.last_progress;
+        assert_eq!(
+            state.check_stall(t1 + Duration::from_secs(40), timeout, true),
+            Some("No progress for 40s while Copying \"/big\": abandoning it".to_owned())
+        );
+        assert!(state.abandon_entry);
+    }
+
+    #[test]
+    pub fn paint_only_when_enabled() {
+        assert_eq!(Highlight::Added.paint_if(false, "/new"), "/new");
+        let painted = Highlight::Added.paint_if(true, "/new");
+        assert!(painted.starts_with('\x1b'));
+        assert!(painted.contains("/new"));
+        assert_ne!(painted, Highlight::Removed.paint_if(true, "/new"));
+    }
+
+    /// Collects error messages, as an embedding program might.
+    #[derive(Clone, Default)]
+    struct CollectErrors {
+        messages: std::sync::Arc<Mutex<Vec<String>>>,
+    }
+
+    impl<S: Subscriber> Layer<S> for CollectErrors {
+        fn on_event(&self, event: &Event, _ctx: Context<S>) {
+            if *event.metadata().level() == Level::ERROR {
+                let mut visitor = MessageVisitor::default();
+                event.record(&mut visitor);
+                self.messages.lock().unwrap().push(visitor.message);
+            }
+        }
+    }
+
+    #[test]
+    pub fn show_error_emits_event_with_causes() {
+        let collect = CollectErrors::default();
+        let subscriber = tracing_subscriber::registry().with(collect.clone());
+        let err = crate::Error::ReadMetadata {
+            path: "/nonexistent".into(),
+            source: io::Error::new(io::ErrorKind::NotFound, "not found"),
+        };
+        tracing::subscriber::with_default(subscriber, || show_error(&err));
+        assert_eq!(
+            *collect.messages.lock().unwrap(),
+            ["Failed to read metadata file \"/nonexistent\"\n  caused by: not found"]
+        );
+    }
 }
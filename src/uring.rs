@@ -0,0 +1,637 @@
+// Conserve backup system.
+// Copyright 2020 Martin Pool.
+
+//! Read small source files in batches through io_uring on Linux, with the
+//! `io_uring` feature.
+//!
+//! Backing up many small files is otherwise dominated by a synchronous
+//! `open`, `read` and `close` for each one. Instead, as each source directory
+//! is listed its small files are remembered, and when the first of them is
+//! read, it and the following ones are opened, read, and closed together,
+//! with one `io_uring_enter` call for each step of the batch.
+//!
+//! If the kernel doesn't support io_uring, or it's forbidden, or any file
+//! can't be read this way, files are read normally instead. Files are opened
+//! with `O_NOATIME`, so those that don't permit it are also read normally.
+//! If the ring itself fails, it's not used again, and the rest of the tree is
+//! read normally.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::path::PathBuf;
+
+use tracing::debug;
+
+use crate::*;
+
+/// Files larger than this are read normally.
+pub(crate) const MAX_PREFETCH_SIZE: u64 = 256 << 10;
+
+/// Read at most this many files in each batch.
+const BATCH: usize = 64;
+
+/// Small files of the current source directory, and the contents of those
+/// already read.
+pub(crate) struct Prefetch {
+    /// None once the ring has failed.
+    ring: Option<ring::Ring>,
+    /// Files not yet read, in apath order.
+    pending: VecDeque<(Apath, PathBuf, u64)>,
+    /// Contents read but not yet taken.
+    ready: BTreeMap<Apath, Vec<u8>>,
+}
+
+impl Prefetch {
+    /// Set up a ring, or return None if io_uring isn't available.
+    pub(crate) fn new() -> Option<Prefetch> {
+        match ring::Ring::new(BATCH as u32) {
+            Ok(ring) => Some(Prefetch {
+                ring: Some(ring),
+                pending: VecDeque::new(),
+                ready: BTreeMap::new(),
+            }),
+            Err(e) => {
+                debug!("io_uring is not available: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Remember the small files of a newly listed directory, forgetting any
+    /// from the previous directory.
+    ///
+    /// The tree is read in order, so by the time the next directory is
+    /// listed, all the files of the previous one have been read or skipped.
+    pub(crate) fn start_directory(&mut self, files: Vec<(Apath, PathBuf, u64)>) {
+        self.pending = files.into();
+        self.ready.clear();
+    }
+
+    /// Take the contents of a file, reading it and the following files if
+    /// it hasn't been read yet. Returns None if the file should be read
+    /// normally.
+    pub(crate) fn take(&mut self, apath: &Apath) -> Option<Vec<u8>> {
+        if let Some(content) = self.ready.remove(apath) {
+            return Some(content);
+        }
+        // Files before this one were skipped, probably because they're
+        // unchanged since the last backup.
+        while let Some((a, _, _)) = self.pending.front() {
+            if a >= apath {
+                break;
+            }
+            self.pending.pop_front();
+        }
+        match self.pending.front() {
+            Some((a, _, _)) if a == apath => (),
+            _ => return None,
+        }
+        self.ready.clear();
+        let batch: Vec<(Apath, PathBuf, u64)> = self
+            .pending
+            .drain(..BATCH.min(self.pending.len()))
+            .collect();
+        let requests: Vec<(PathBuf, u64)> = batch
+            .iter()
+            .map(|(_, path, size)| (path.clone(), *size))
+            .collect();
+        let ring = self.ring.as_mut()?;
+        match ring.read_files(&requests) {
+            Ok(contents) => {
+                for ((apath, _, _), content) in batch.into_iter().zip(contents) {
+                    if let Some(content) = content {
+                        self.ready.insert(apath, content);
+                    }
+                }
+            }
+            Err(e) => {
+                debug!("io_uring batch failed, so it won't be used again: {}", e);
+                self.ring = None;
+            }
+        }
+        self.ready.remove(apath)
+    }
+}
+
+impl std::fmt::Debug for Prefetch {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Prefetch")
+            .field("pending", &self.pending.len())
+            .field("ready", &self.ready.len())
+            .finish()
+    }
+}
+
+#[cfg(not(all(feature = "io_uring", target_os = "linux")))]
+mod ring {
+    use std::io;
+    use std::path::PathBuf;
+
+    pub(super) struct Ring;
+
+    impl Ring {
+        pub(super) fn new(_entries: u32) -> io::Result<Ring> {
+            Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "built without the io_uring feature",
+            ))
+        }
+
+        pub(super) fn read_files(
+            &mut self,
+            _files: &[(PathBuf, u64)],
+        ) -> io::Result<Vec<Option<Vec<u8>>>> {
+            unreachable!()
+        }
+    }
+}
+
+#[cfg(all(feature = "io_uring", target_os = "linux"))]
+mod ring {
+    //! A minimal io_uring, driven by raw system calls, as described in
+    //! `io_uring_setup(2)` and `io_uring_enter(2)`.
+
+    use std::ffi::CString;
+    use std::io;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::PathBuf;
+    use std::ptr;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    const IORING_OFF_SQ_RING: i64 = 0;
+    const IORING_OFF_CQ_RING: i64 = 0x800_0000;
+    const IORING_OFF_SQES: i64 = 0x1000_0000;
+    const IORING_ENTER_GETEVENTS: u32 = 1;
+
+    const IORING_OP_OPENAT: u8 = 18;
+    const IORING_OP_CLOSE: u8 = 19;
+    const IORING_OP_READ: u8 = 22;
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct SqringOffsets {
+        head: u32,
+        tail: u32,
+        ring_mask: u32,
+        ring_entries: u32,
+        flags: u32,
+        dropped: u32,
+        array: u32,
+        resv1: u32,
+        resv2: u64,
+    }
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct CqringOffsets {
+        head: u32,
+        tail: u32,
+        ring_mask: u32,
+        ring_entries: u32,
+        overflow: u32,
+        cqes: u32,
+        flags: u32,
+        resv1: u32,
+        resv2: u64,
+    }
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct Params {
+        sq_entries: u32,
+        cq_entries: u32,
+        flags: u32,
+        sq_thread_cpu: u32,
+        sq_thread_idle: u32,
+        features: u32,
+        wq_fd: u32,
+        resv: [u32; 3],
+        sq_off: SqringOffsets,
+        cq_off: CqringOffsets,
+    }
+
+    /// A submission queue entry.
+    #[repr(C)]
+    #[derive(Default)]
+    struct Sqe {
+        opcode: u8,
+        flags: u8,
+        ioprio: u16,
+        fd: i32,
+        off: u64,
+        addr: u64,
+        len: u32,
+        op_flags: u32,
+        user_data: u64,
+        buf_index: u16,
+        personality: u16,
+        splice_fd_in: i32,
+        addr3: u64,
+        pad: u64,
+    }
+
+    /// A completion queue entry.
+    #[repr(C)]
+    struct Cqe {
+        user_data: u64,
+        res: i32,
+        flags: u32,
+    }
+
+    /// A memory-mapped region shared with the kernel.
+    struct Mmap {
+        ptr: *mut u8,
+        len: usize,
+    }
+
+    impl Mmap {
+        fn new(fd: i32, len: usize, offset: i64) -> io::Result<Mmap> {
+            let ptr = unsafe {
+                libc::mmap(
+                    ptr::null_mut(),
+                    len,
+                    libc::PROT_READ | libc::PROT_WRITE,
+                    libc::MAP_SHARED | libc::MAP_POPULATE,
+                    fd,
+                    offset,
+                )
+            };
+            if ptr == libc::MAP_FAILED {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(Mmap {
+                    ptr: ptr as *mut u8,
+                    len,
+                })
+            }
+        }
+
+        /// A pointer `offset` bytes into the region.
+        fn at<T>(&self, offset: u32) -> *mut T {
+            assert!(offset as usize + std::mem::size_of::<T>() <= self.len);
+            unsafe { self.ptr.add(offset as usize) as *mut T }
+        }
+
+        fn atomic(&self, offset: u32) -> &AtomicU32 {
+            unsafe { &*self.at::<AtomicU32>(offset) }
+        }
+    }
+
+    impl Drop for Mmap {
+        fn drop(&mut self) {
+            unsafe { libc::munmap(self.ptr as *mut libc::c_void, self.len) };
+        }
+    }
+
+    pub(super) struct Ring {
+        fd: i32,
+        entries: u32,
+        sq: Mmap,
+        cq: Mmap,
+        sqes: Mmap,
+        sq_off: SqringOffsets,
+        cq_off: CqringOffsets,
+
+        /// Set when `io_uring_enter` failed, after which some operations
+        /// may still be queued or in flight, so the ring isn't used again.
+        broken: bool,
+
+        /// Paths and buffers of operations that may still be in flight on a
+        /// broken ring. The kernel may still read or write them, so they're
+        /// never freed.
+        lent_paths: Vec<Option<CString>>,
+        lent_bufs: Vec<Vec<u8>>,
+    }
+
+    // The ring is only used through `&mut self`, so it can move between
+    // threads.
+    unsafe impl Send for Ring {}
+
+    impl Ring {
+        pub(super) fn new(entries: u32) -> io::Result<Ring> {
+            let mut params = Params::default();
+            let fd = unsafe {
+                libc::syscall(
+                    libc::SYS_io_uring_setup,
+                    entries,
+                    &mut params as *mut Params,
+                )
+            } as i32;
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let map = |len: usize, offset: i64| {
+                Mmap::new(fd, len, offset).inspect_err(|_| {
+                    unsafe { libc::close(fd) };
+                })
+            };
+            let sq = map(
+                params.sq_off.array as usize + params.sq_entries as usize * 4,
+                IORING_OFF_SQ_RING,
+            )?;
+            let cq = map(
+                params.cq_off.cqes as usize
+                    + params.cq_entries as usize * std::mem::size_of::<Cqe>(),
+                IORING_OFF_CQ_RING,
+            )?;
+            let sqes = map(
+                params.sq_entries as usize * std::mem::size_of::<Sqe>(),
+                IORING_OFF_SQES,
+            )?;
+            Ok(Ring {
+                fd,
+                entries: params.sq_entries,
+                sq,
+                cq,
+                sqes,
+                sq_off: params.sq_off,
+                cq_off: params.cq_off,
+                broken: false,
+                lent_paths: Vec::new(),
+                lent_bufs: Vec::new(),
+            })
+        }
+
+        /// Swap the ring's descriptor, to make it fail in tests.
+        #[cfg(test)]
+        pub(super) fn replace_fd(&mut self, fd: i32) -> i32 {
+            std::mem::replace(&mut self.fd, fd)
+        }
+
+        /// Keep memory that operations on the broken ring may still use.
+        fn lend(&mut self, paths: Vec<Option<CString>>, bufs: Vec<Vec<u8>>) {
+            debug_assert!(self.broken);
+            self.lent_paths.extend(paths);
+            self.lent_bufs.extend(bufs);
+        }
+
+        /// Open, read, and close files expected to be the given sizes.
+        ///
+        /// Returns the contents of each file, or None if it couldn't be read
+        /// or isn't the expected size.
+        ///
+        /// After an error the ring is broken: every later call fails, and
+        /// files that might still be open are left open, rather than risk
+        /// closing a descriptor that's been reused.
+        pub(super) fn read_files(
+            &mut self,
+            files: &[(PathBuf, u64)],
+        ) -> io::Result<Vec<Option<Vec<u8>>>> {
+            assert!(files.len() <= self.entries as usize);
+            if self.broken {
+                // "File descriptor in bad state".
+                return Err(io::Error::from_raw_os_error(libc::EBADFD));
+            }
+            let paths = files
+                .iter()
+                .map(|(path, _)| CString::new(path.as_os_str().as_bytes()).ok())
+                .collect::<Vec<_>>();
+            let opens: Vec<(usize, Sqe)> = paths
+                .iter()
+                .enumerate()
+                .filter_map(|(i, path)| {
+                    path.as_ref().map(|path| {
+                        (
+                            i,
+                            Sqe {
+                                opcode: IORING_OP_OPENAT,
+                                fd: libc::AT_FDCWD,
+                                addr: path.as_ptr() as u64,
+                                op_flags: (libc::O_RDONLY | libc::O_CLOEXEC | libc::O_NOATIME)
+                                    as u32,
+                                ..Sqe::default()
+                            },
+                        )
+                    })
+                })
+                .collect();
+            let fds = match self.run(opens) {
+                Ok(fds) => fds,
+                Err(e) => {
+                    self.lend(paths, Vec::new());
+                    return Err(e);
+                }
+            };
+
+            // One more byte than expected, to notice files that grew.
+            let mut bufs: Vec<Vec<u8>> = files
+                .iter()
+                .map(|(_, size)| vec![0; *size as usize + 1])
+                .collect();
+            let reads: Vec<(usize, Sqe)> = fds
+                .iter()
+                .filter(|(_, fd)| *fd >= 0)
+                .map(|&(i, fd)| {
+                    (
+                        i,
+                        Sqe {
+                            opcode: IORING_OP_READ,
+                            fd,
+                            addr: bufs[i].as_mut_ptr() as u64,
+                            len: bufs[i].len() as u32,
+                            ..Sqe::default()
+                        },
+                    )
+                })
+                .collect();
+            let read_lens = match self.run(reads) {
+                Ok(read_lens) => read_lens,
+                Err(e) => {
+                    // Reads in flight hold their own references to the
+                    // files, so the descriptors can be closed now.
+                    for &(_, fd) in &fds {
+                        if fd >= 0 {
+                            unsafe { libc::close(fd) };
+                        }
+                    }
+                    self.lend(paths, bufs);
+                    return Err(e);
+                }
+            };
+
+            let closes: Vec<(usize, Sqe)> = fds
+                .iter()
+                .filter(|(_, fd)| *fd >= 0)
+                .map(|&(i, fd)| {
+                    (
+                        i,
+                        Sqe {
+                            opcode: IORING_OP_CLOSE,
+                            fd,
+                            ..Sqe::default()
+                        },
+                    )
+                })
+                .collect();
+            if let Err(e) = self.run(closes) {
+                // The reads are complete, so the buffers are free again, but
+                // the closes may not be.
+                self.lend(paths, Vec::new());
+                return Err(e);
+            }
+
+            let mut contents: Vec<Option<Vec<u8>>> = vec![None; files.len()];
+            for (i, len) in read_lens {
+                if len >= 0 && len as u64 == files[i].1 {
+                    let mut buf = std::mem::take(&mut bufs[i]);
+                    buf.truncate(len as usize);
+                    contents[i] = Some(buf);
+                }
+            }
+            Ok(contents)
+        }
+
+        /// Submit some operations, and wait for them all to complete.
+        ///
+        /// Returns the index given with each operation, and its result.
+        ///
+        /// If it fails, some of the operations may not have been submitted,
+        /// or may not have completed, and the ring is marked broken. The
+        /// caller must then keep the memory they use with `lend`.
+        fn run(&mut self, ops: Vec<(usize, Sqe)>) -> io::Result<Vec<(usize, i32)>> {
+            let count = ops.len() as u32;
+            if count == 0 {
+                return Ok(Vec::new());
+            }
+            let mask = unsafe { *self.sq.at::<u32>(self.sq_off.ring_mask) };
+            let sq_tail = self.sq.atomic(self.sq_off.tail);
+            let mut tail = sq_tail.load(Ordering::Acquire);
+            for (i, mut sqe) in ops {
+                let slot = tail & mask;
+                sqe.user_data = i as u64;
+                unsafe {
+                    ptr::write(self.sqes.at::<Sqe>(slot * 64), sqe);
+                    ptr::write(self.sq.at::<u32>(self.sq_off.array + slot * 4), slot);
+                }
+                tail = tail.wrapping_add(1);
+            }
+            sq_tail.store(tail, Ordering::Release);
+
+            let mut results = Vec::with_capacity(count as usize);
+            let mut submitted = 0;
+            while (results.len() as u32) < count {
+                let wanted = count - results.len() as u32;
+                let ret = unsafe {
+                    libc::syscall(
+                        libc::SYS_io_uring_enter,
+                        self.fd,
+                        count - submitted,
+                        wanted,
+                        IORING_ENTER_GETEVENTS,
+                        ptr::null::<libc::sigset_t>(),
+                        0usize,
+                    )
+                };
+                if ret < 0 {
+                    let err = io::Error::last_os_error();
+                    let outstanding = submitted > results.len() as u32;
+                    match err.raw_os_error() {
+                        Some(libc::EINTR) => continue,
+                        // Short of resources until completions are reaped,
+                        // which can only help if some are still to come.
+                        Some(libc::EAGAIN) | Some(libc::EBUSY) if outstanding => (),
+                        _ => {
+                            self.broken = true;
+                            return Err(err);
+                        }
+                    }
+                } else {
+                    submitted += ret as u32;
+                }
+                let cq_mask = unsafe { *self.cq.at::<u32>(self.cq_off.ring_mask) };
+                let cq_head = self.cq.atomic(self.cq_off.head);
+                let cq_tail = self.cq.atomic(self.cq_off.tail);
+                let mut head = cq_head.load(Ordering::Acquire);
+                let tail = cq_tail.load(Ordering::Acquire);
+                while head != tail {
+                    let cqe = unsafe {
+                        ptr::read(self.cq.at::<Cqe>(
+                            self.cq_off.cqes + (head & cq_mask) * std::mem::size_of::<Cqe>() as u32,
+                        ))
+                    };
+                    results.push((cqe.user_data as usize, cqe.res));
+                    head = head.wrapping_add(1);
+                }
+                cq_head.store(head, Ordering::Release);
+            }
+            Ok(results)
+        }
+    }
+
+    impl Drop for Ring {
+        fn drop(&mut self) {
+            // The kernel cancels outstanding operations once the ring is
+            // closed, but may still be finishing them afterwards.
+            std::mem::forget(std::mem::take(&mut self.lent_paths));
+            std::mem::forget(std::mem::take(&mut self.lent_bufs));
+            unsafe { libc::close(self.fd) };
+        }
+    }
+}
+
+#[cfg(all(test, feature = "io_uring", target_os = "linux"))]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::TreeFixture;
+
+    #[test]
+    fn read_batches() {
+        let mut prefetch = match Prefetch::new() {
+            Some(prefetch) => prefetch,
+            None => return, // Forbidden in some sandboxes.
+        };
+        let tf = TreeFixture::new();
+        let mut files = Vec::new();
+        for i in 0..(BATCH + 10) {
+            let name = format!("f{:03}", i);
+            let content = name.repeat(i);
+            let path = tf.create_file_with_contents(&name, content.as_bytes());
+            files.push((
+                Apath::from(format!("/{}", name)),
+                path,
+                content.len() as u64,
+            ));
+        }
+        // The size changed since it was listed, so it's read normally.
+        files[3].2 += 1;
+        let missing = tf.path().join("missing");
+        files.push(("/missing".into(), missing, 0));
+        prefetch.start_directory(files.clone());
+
+        // The first file is skipped, as if it was unchanged.
+        for (i, (apath, _, _)) in files.iter().enumerate().skip(1) {
+            let content = prefetch.take(apath);
+            if i == 3 || apath == "/missing" {
+                assert_eq!(content, None);
+            } else {
+                let name = &apath[1..];
+                assert_eq!(content.unwrap(), name.repeat(i).as_bytes());
+            }
+        }
+        assert_eq!(prefetch.take(&files[0].0), None);
+    }
+
+    #[test]
+    fn broken_ring_is_not_used_again() {
+        let mut ring = match ring::Ring::new(4) {
+            Ok(ring) => ring,
+            Err(_) => return,
+        };
+        let tf = TreeFixture::new();
+        let path = tf.create_file_with_contents("hello", b"hello");
+        // Make io_uring_enter fail after the operations are queued.
+        let fd = ring.replace_fd(-1);
+        assert!(ring.read_files(&[(path.clone(), 5)]).is_err());
+        ring.replace_fd(fd);
+        assert!(ring.read_files(&[(path, 5)]).is_err());
+    }
+
+    #[test]
+    fn ring_errors() {
+        if let Ok(mut ring) = ring::Ring::new(4) {
+            let results = ring
+                .read_files(&[(PathBuf::from("/nonexistent/conserve"), 0)])
+                .unwrap();
+            assert_eq!(results, [None]);
+        }
+    }
+}
@@ -0,0 +1,199 @@
+// Conserve backup system.
+// Copyright 2020 Martin Pool.
+
+//! Remember when each block was last found to be intact, so that routine
+//! validation can skip blocks that were checked recently.
+//!
+//! The cache is a local file beside the archive, not part of the archive
+//! itself: it records what this machine has checked, and losing it only
+//! means the next validation rereads everything.
+//!
+//! Each line holds a block hash and the Unix time in seconds when it was
+//! last verified, separated by a space.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use snafu::ResultExt;
+
+use crate::io::AtomicFile;
+use crate::*;
+
+/// The times when blocks were last verified, read from and saved to a
+/// local file.
+#[derive(Debug, Clone)]
+pub struct ValidationCache {
+    path: PathBuf,
+    verified: HashMap<String, u64>,
+}
+
+impl ValidationCache {
+    /// The default cache file for an archive: a sidecar file named after
+    /// the archive directory, like `/backup/archive.validated`.
+    pub fn sidecar_path(archive_path: &Path) -> PathBuf {
+        let mut name = archive_path
+            .file_name()
+            .map(|n| n.to_os_string())
+            .unwrap_or_else(|| "archive".into());
+        name.push(".validated");
+        archive_path.with_file_name(name)
+    }
+
+    /// Read the cache at `path`, or start an empty one if it doesn't exist.
+    pub fn open(path: &Path) -> Result<ValidationCache> {
+        let mut verified = HashMap::new();
+        let ctx = || errors::ReadValidationCache {
+            path: path.to_owned(),
+        };
+        match fs::File::open(path) {
+            Ok(f) => {
+                for line in BufReader::new(f).lines() {
+                    let line = line.with_context(ctx)?;
+                    // Unparseable lines are forgotten, so those blocks are
+                    // just checked again.
+                    if let Some((hash, time)) = line.split_once(' ') {
+                        if let Ok(time) = time.parse() {
+                            verified.insert(hash.to_owned(), time);
+                        }
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e).with_context(ctx),
+        }
+        Ok(ValidationCache {
+            path: path.to_owned(),
+            verified,
+        })
+    }
+
+    /// The number of blocks with a recorded verification time.
+    pub fn len(&self) -> usize {
+        self.verified.len()
+    }
+
+    /// True if no blocks have been recorded.
+    pub fn is_empty(&self) -> bool {
+        self.verified.is_empty()
+    }
+
+    /// True if the block was verified after `cutoff`.
+    pub fn verified_since(&self, hash: &str, cutoff: SystemTime) -> bool {
+        let cutoff = unix_seconds(cutoff);
+        matches!(self.verified.get(hash), Some(&t) if t > cutoff)
+    }
+
+    /// The Unix time when the block was last verified, or 0 if it never was.
+    pub(crate) fn last_verified(&self, hash: &str) -> u64 {
+        self.verified.get(hash).copied().unwrap_or_default()
+    }
+
+    /// Record that these blocks were verified at `when`.
+    pub fn record<'a>(&mut self, hashes: impl IntoIterator<Item = &'a String>, when: SystemTime) {
+        let when = unix_seconds(when);
+        for hash in hashes {
+            self.verified.insert(hash.clone(), when);
+        }
+    }
+
+    /// Forget blocks that aren't in `present`, such as those removed by gc.
+    pub(crate) fn retain_present(&mut self, present: &[(String, u64)]) {
+        let present: std::collections::HashSet<&str> =
+            present.iter().map(|(hash, _)| hash.as_str()).collect();
+        self.verified
+            .retain(|hash, _| present.contains(hash.as_str()));
+    }
+
+    /// Write the cache back to its file.
+    pub fn save(&self) -> Result<()> {
+        let ctx = || errors::WriteValidationCache {
+            path: self.path.clone(),
+        };
+        let mut af = AtomicFile::new(&self.path).with_context(ctx)?;
+        {
+            let mut w = BufWriter::new(&mut af);
+            for (hash, time) in &self.verified {
+                writeln!(w, "{} {}", hash, time).with_context(ctx)?;
+            }
+            w.flush().with_context(ctx)?;
+        }
+        af.close().with_context(ctx)
+    }
+}
+
+fn unix_seconds(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+/// Parse an age like "30d", "12h", "90m", "2w", or a number of seconds.
+pub fn parse_age(age: &str) -> Result<Duration> {
+    let (number, unit) = match age.find(|c: char| !c.is_ascii_digit()) {
+        Some(pos) => age.split_at(pos),
+        None => (age, "s"),
+    };
+    let scale = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return errors::InvalidAge { age }.fail(),
+    };
+    match number.parse::<u64>() {
+        Ok(n) => Ok(Duration::from_secs(n * scale)),
+        Err(_) => errors::InvalidAge { age }.fail(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_ages() {
+        assert_eq!(parse_age("30d").unwrap(), Duration::from_secs(30 * 86400));
+        assert_eq!(parse_age("2w").unwrap(), Duration::from_secs(14 * 86400));
+        assert_eq!(parse_age("12h").unwrap(), Duration::from_secs(12 * 3600));
+        assert_eq!(parse_age("90m").unwrap(), Duration::from_secs(90 * 60));
+        assert_eq!(parse_age("45").unwrap(), Duration::from_secs(45));
+        assert!(parse_age("").is_err());
+        assert!(parse_age("d").is_err());
+        assert!(parse_age("3y").is_err());
+        assert!(parse_age("-3d").is_err());
+    }
+
+    #[test]
+    fn sidecar_beside_archive() {
+        assert_eq!(
+            ValidationCache::sidecar_path(Path::new("/backup/archive")),
+            Path::new("/backup/archive.validated")
+        );
+    }
+
+    #[test]
+    fn save_and_reopen() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("cache");
+        let mut cache = ValidationCache::open(&path).unwrap();
+        assert!(cache.is_empty());
+
+        let then = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        let hashes = vec!["aaaa".to_owned(), "bbbb".to_owned()];
+        cache.record(&hashes, then);
+        cache.save().unwrap();
+
+        let mut cache = ValidationCache::open(&path).unwrap();
+        assert_eq!(cache.len(), 2);
+        let before = then - Duration::from_secs(1);
+        assert!(cache.verified_since("aaaa", before));
+        assert!(!cache.verified_since("aaaa", then));
+        assert!(!cache.verified_since("cccc", UNIX_EPOCH));
+
+        cache.retain_present(&[("bbbb".to_owned(), 10)]);
+        assert!(!cache.verified_since("aaaa", before));
+        assert!(cache.verified_since("bbbb", before));
+    }
+}
@@ -0,0 +1,400 @@
+// Conserve backup system.
+// Copyright 2020 Martin Pool.
+
+//! Watch a source tree and back it up soon after it changes.
+//!
+//! Changes are found by rescanning the tree's metadata, which is much cheaper
+//! than a backup, and sees exactly what the backup would. On Linux, inotify
+//! wakes the watcher to rescan as soon as something changes; elsewhere, or if
+//! inotify can't be used, the tree is rescanned every poll interval. Once
+//! changes have been seen, a backup starts after the tree has been quiet for a
+//! while, or after a maximum delay if it never goes quiet.
+
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use tracing::{debug, info};
+
+use crate::stats::BackupStats;
+use crate::unix_time::UnixTime;
+use crate::*;
+
+use self::notify::Notifier;
+
+/// What's compared between scans to notice a change.
+type Snapshot = BTreeMap<Apath, (Kind, UnixTime, Option<u64>)>;
+
+/// Options for watching a tree, and a way to run the watch.
+///
+/// ```no_run
+/// let backup = conserve::BackupOptions::new("/home/me/src", "/backup/archive");
+/// conserve::WatchOptions::new(backup)
+///     .quiet_period(std::time::Duration::from_secs(60))
+///     .run(|result| println!("{:?}", result))
+///     .unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct WatchOptions {
+    backup: BackupOptions,
+    poll_interval: Duration,
+    quiet_period: Duration,
+    max_delay: Option<Duration>,
+    max_backups: Option<usize>,
+}
+
+impl WatchOptions {
+    /// Watch the source of `backup`, and run it when the source changes.
+    pub fn new(backup: BackupOptions) -> WatchOptions {
+        WatchOptions {
+            backup,
+            poll_interval: Duration::from_secs(10),
+            quiet_period: Duration::from_secs(30),
+            max_delay: None,
+            max_backups: None,
+        }
+    }
+
+    /// Scan the tree for changes at least this often, even if no change was
+    /// notified.
+    pub fn poll_interval(self, poll_interval: Duration) -> WatchOptions {
+        WatchOptions {
+            poll_interval,
+            ..self
+        }
+    }
+
+    /// Wait until the tree has been unchanged for this long before backing
+    /// up.
+    pub fn quiet_period(self, quiet_period: Duration) -> WatchOptions {
+        WatchOptions {
+            quiet_period,
+            ..self
+        }
+    }
+
+    /// Back up at most this long after the first change, even if the tree
+    /// hasn't gone quiet.
+    pub fn max_delay(self, max_delay: Duration) -> WatchOptions {
+        WatchOptions {
+            max_delay: Some(max_delay),
+            ..self
+        }
+    }
+
+    /// Stop after this many backups, including the first one.
+    pub fn max_backups(self, max_backups: usize) -> WatchOptions {
+        WatchOptions {
+            max_backups: Some(max_backups),
+            ..self
+        }
+    }
+
+    /// Back up once, then again after each batch of changes, calling
+    /// `after_backup` with the result of each backup.
+    ///
+    /// A failed backup doesn't stop watching, but failing to scan the
+    /// source does.
+    pub fn run<F: FnMut(Result<BackupStats>)>(&self, mut after_backup: F) -> Result<()> {
+        let live_tree = self.backup.live_tree()?;
+        let mut notifier = Notifier::new();
+        let mut snapshot = scan(&live_tree)?;
+        watch_dirs(&mut notifier, &live_tree, &snapshot);
+        let mut backups = 0;
+        loop {
+            after_backup(self.backup.run());
+            backups += 1;
+            if matches!(self.max_backups, Some(max) if backups >= max) {
+                return Ok(());
+            }
+            self.wait_for_changes(&live_tree, &mut notifier, &mut snapshot)?;
+        }
+    }
+
+    /// Scan until changes are seen and then the tree is quiet, or the maximum
+    /// delay has passed.
+    fn wait_for_changes(
+        &self,
+        live_tree: &LiveTree,
+        notifier: &mut Notifier,
+        snapshot: &mut Snapshot,
+    ) -> Result<()> {
+        let mut first_change: Option<Instant> = None;
+        let mut last_change = Instant::now();
+        loop {
+            let timeout = match first_change {
+                // Wake up when the tree should have gone quiet, to notice it.
+                Some(_) => (last_change + self.quiet_period)
+                    .saturating_duration_since(Instant::now())
+                    .min(self.poll_interval),
+                None => self.poll_interval,
+            };
+            notifier.wait(timeout);
+            let new_snapshot = scan(live_tree)?;
+            let changes = count_changes(snapshot, &new_snapshot);
+            let now = Instant::now();
+            if changes > 0 {
+                info!("{} paths changed", changes);
+                *snapshot = new_snapshot;
+                watch_dirs(notifier, live_tree, snapshot);
+                last_change = now;
+                first_change.get_or_insert(now);
+            }
+            if let Some(first_change) = first_change {
+                if now - last_change >= self.quiet_period {
+                    debug!("Source is quiet; starting backup");
+                    return Ok(());
+                } else if matches!(self.max_delay, Some(max) if now - first_change >= max) {
+                    info!("Source is still changing; starting backup anyway");
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+fn scan(live_tree: &LiveTree) -> Result<Snapshot> {
+    // Problems are reported by the backup itself, not by every scan.
+    let snapshot = live_tree
+        .iter_entries()?
+        .quiet()
+        .map(|entry| {
+            (
+                entry.apath().clone(),
+                (entry.kind(), entry.mtime(), entry.size()),
+            )
+        })
+        .collect();
+    Ok(snapshot)
+}
+
+/// Ask to be notified of changes in every directory in the snapshot.
+///
+/// Directories that are already watched are unaffected.
+fn watch_dirs(notifier: &mut Notifier, live_tree: &LiveTree, snapshot: &Snapshot) {
+    for (apath, (kind, _, _)) in snapshot {
+        if *kind == Kind::Dir {
+            if let Some(path) = live_tree.relative_path(apath) {
+                notifier.watch(&path);
+            }
+        }
+    }
+}
+
+/// Count the paths that were added, removed, or changed between two scans.
+fn count_changes(before: &Snapshot, after: &Snapshot) -> usize {
+    let changed = after
+        .iter()
+        .filter(|(apath, metadata)| before.get(apath) != Some(metadata))
+        .count();
+    let removed = before
+        .keys()
+        .filter(|apath| !after.contains_key(apath))
+        .count();
+    changed + removed
+}
+
+#[cfg(target_os = "linux")]
+mod notify {
+    use std::ffi::CString;
+    use std::io;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    use tracing::debug;
+
+    const EVENTS: u32 = libc::IN_ATTRIB
+        | libc::IN_CLOSE_WRITE
+        | libc::IN_CREATE
+        | libc::IN_DELETE
+        | libc::IN_DELETE_SELF
+        | libc::IN_MODIFY
+        | libc::IN_MOVE_SELF
+        | libc::IN_MOVED_FROM
+        | libc::IN_MOVED_TO
+        | libc::IN_ONLYDIR;
+
+    /// Give a burst of changes this long to finish before rescanning.
+    const SETTLE: Duration = Duration::from_millis(100);
+
+    /// Wakes up the watcher when inotify reports a change in a watched
+    /// directory.
+    pub(super) struct Notifier {
+        /// The inotify descriptor, or None if inotify isn't available.
+        fd: Option<libc::c_int>,
+    }
+
+    impl Notifier {
+        pub fn new() -> Notifier {
+            let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+            if fd < 0 {
+                debug!(
+                    "Can't use inotify, falling back to polling: {}",
+                    io::Error::last_os_error()
+                );
+                return Notifier { fd: None };
+            }
+            Notifier { fd: Some(fd) }
+        }
+
+        pub fn watch(&mut self, dir: &Path) {
+            let fd = match self.fd {
+                Some(fd) => fd,
+                None => return,
+            };
+            let path = match CString::new(dir.as_os_str().as_bytes()) {
+                Ok(path) => path,
+                Err(_) => return,
+            };
+            if unsafe { libc::inotify_add_watch(fd, path.as_ptr(), EVENTS) } < 0 {
+                // For example, too many watches: changes here are still found
+                // by the periodic rescan.
+                debug!(
+                    "Can't watch {:?} for changes: {}",
+                    dir,
+                    io::Error::last_os_error()
+                );
+            }
+        }
+
+        /// Wait until a change is notified or the timeout passes.
+        pub fn wait(&mut self, timeout: Duration) {
+            let fd = match self.fd {
+                Some(fd) => fd,
+                None => return sleep(timeout),
+            };
+            let mut pollfd = libc::pollfd {
+                fd,
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            let timeout_ms = timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+            if unsafe { libc::poll(&mut pollfd, 1, timeout_ms) } > 0 {
+                sleep(SETTLE);
+            }
+            // The events themselves don't matter, because the whole tree is
+            // rescanned.
+            let mut buf = [0u8; 4096];
+            loop {
+                let len =
+                    unsafe { libc::read(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+                if len <= 0 {
+                    break;
+                }
+            }
+        }
+    }
+
+    impl Drop for Notifier {
+        fn drop(&mut self) {
+            if let Some(fd) = self.fd {
+                unsafe { libc::close(fd) };
+            }
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod notify {
+    use std::path::Path;
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    /// Waits for the poll interval, where change notifications aren't
+    /// supported.
+    pub(super) struct Notifier;
+
+    impl Notifier {
+        pub fn new() -> Notifier {
+            Notifier
+        }
+
+        pub fn watch(&mut self, _dir: &Path) {}
+
+        pub fn wait(&mut self, timeout: Duration) {
+            sleep(timeout)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::test_fixtures::{ScratchArchive, TreeFixture};
+
+    #[test]
+    fn count_changes_between_scans() {
+        let tf = TreeFixture::new();
+        tf.create_file("hello");
+        tf.create_file("gone");
+        let lt = LiveTree::open(tf.path()).unwrap();
+        let before = scan(&lt).unwrap();
+        assert_eq!(count_changes(&before, &scan(&lt).unwrap()), 0);
+
+        std::fs::remove_file(tf.path().join("gone")).unwrap();
+        tf.create_file("new");
+        tf.create_file_with_contents("hello", b"longer contents");
+        // The root directory's mtime changes too.
+        assert_eq!(count_changes(&before, &scan(&lt).unwrap()), 4);
+    }
+
+    #[test]
+    fn back_up_after_changes() {
+        let af = ScratchArchive::new();
+        let tf = TreeFixture::new();
+        tf.create_file("hello");
+        let new_file = tf.path().join("new");
+        let writer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            std::fs::write(new_file, b"new").unwrap();
+        });
+
+        let mut results = Vec::new();
+        WatchOptions::new(BackupOptions::new(tf.path(), af.path()))
+            .poll_interval(Duration::from_millis(10))
+            .quiet_period(Duration::from_millis(50))
+            .max_backups(2)
+            .run(|result| results.push(result.unwrap()))
+            .unwrap();
+        writer.join().unwrap();
+
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[1].files, 2);
+        let apaths: Vec<String> = StoredTree::open_last(&af)
+            .unwrap()
+            .iter_entries()
+            .unwrap()
+            .map(|e| e.apath.into())
+            .collect();
+        assert_eq!(apaths, ["/", "/hello", "/new"]);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn notified_changes_are_seen_before_the_poll_interval() {
+        let af = ScratchArchive::new();
+        let tf = TreeFixture::new();
+        tf.create_dir("subdir");
+        let new_file = tf.path().join("subdir").join("new");
+        let writer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            std::fs::write(new_file, b"new").unwrap();
+        });
+
+        let start = Instant::now();
+        let mut results = Vec::new();
+        WatchOptions::new(BackupOptions::new(tf.path(), af.path()))
+            .poll_interval(Duration::from_secs(600))
+            .quiet_period(Duration::from_millis(50))
+            .max_backups(2)
+            .run(|result| results.push(result.unwrap()))
+            .unwrap();
+        writer.join().unwrap();
+
+        assert!(start.elapsed() < Duration::from_secs(60));
+        assert_eq!(results[1].files, 1);
+    }
+}
@@ -184,8 +184,10 @@ both     /subdir
         .success()
         .stderr(is_empty())
         .stdout(
-            is_match(r"^b0000 *complete   20\d\d-\d\d-\d\d \d\d:\d\d:\d\d +0:\d+ *0 MB\n$")
-                .unwrap(),
+            is_match(
+                r"^b0000 *complete   20\d\d-\d\d-\d\d \d\d:\d\d:\d\d +0:\d+ +0 MB +0 MB +0 MB\n$",
+            )
+            .unwrap(),
         );
 
     main_binary()
@@ -255,6 +257,13 @@ both     /subdir
         .success()
         .stderr(is_empty())
         .stdout(contains("Archive is OK.\n"));
+    main_binary()
+        .args(&["validate", "--jobs", "2"])
+        .arg(&arch_dir)
+        .assert()
+        .success()
+        .stderr(is_empty())
+        .stdout(contains("Archive is OK.\n"));
 
     // TODO: Compare vs source tree.
 }
@@ -322,6 +331,2236 @@ fn incomplete_version() {
         .arg(af.path())
         .assert()
         .success()
+        .stdout(is_empty())
+        .stderr("Version b0000 is incomplete: index is empty\n");
+
+    // ls --incomplete without a band id opens the latest band
+    main_binary()
+        .args(&["ls", "--incomplete"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout(is_empty())
+        .stderr("Version b0000 is incomplete: index is empty\n");
+}
+
+/// An interrupted backup can be listed, and the output shows where it stops.
+#[test]
+fn ls_incomplete_after_complete_version() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+    af.setup_incomplete_empty_band();
+
+    // Without --incomplete, the last complete version is listed.
+    main_binary()
+        .arg("ls")
+        .arg(af.path())
+        .assert()
+        .success()
+        .stderr(is_empty())
+        .stdout(contains("/hello2\n"))
+        .stdout(contains("incomplete").not());
+
+    main_binary()
+        .args(&["ls", "--incomplete"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout(is_empty())
+        .stderr("Version b0002 is incomplete: index is empty\n");
+}
+
+#[test]
+fn band_info() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+    af.setup_incomplete_empty_band();
+
+    main_binary()
+        .args(&["band-info", "-b", "b1"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stderr(is_empty())
+        .stdout(contains("Band:          b0001\n"))
+        .stdout(contains("Complete:      yes\n"))
+        .stdout(contains("Based on:      b0000\n"))
+        .stdout(contains("Last apath:    /subdir/subfile\n"))
+        .stdout(contains("Files:         3\n"))
+        .stdout(contains("File bytes:    24\n"));
+
+    // By default, shows the last band, even if incomplete.
+    main_binary()
+        .arg("band-info")
+        .arg(af.path())
+        .assert()
+        .success()
+        .stderr(is_empty())
+        .stdout(contains("Band:          b0002\n"))
+        .stdout(contains("Complete:      no\n"))
+        .stdout(contains("Last apath:    none\n"))
+        .stdout(contains("Entries:       0\n"));
+}
+
+#[test]
+fn named_trees() {
+    let af = ScratchArchive::new();
+    let src = TreeFixture::new();
+    src.create_file("hello");
+
+    main_binary()
+        .args(&["backup", "--tree", "home"])
+        .arg(af.path())
+        .arg(src.path())
+        .assert()
+        .success();
+
+    main_binary()
+        .arg("trees")
+        .arg(af.path())
+        .assert()
+        .success()
         .stderr(is_empty())
+        .stdout("home\n");
+
+    main_binary()
+        .args(&["versions", "--short", "--tree", "home"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout("b0000\n");
+
+    // The default tree is still empty.
+    main_binary()
+        .args(&["versions", "--short"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    main_binary()
+        .args(&["ls", "-t", "home"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout("/\n/hello\n");
+
+    main_binary()
+        .args(&["ls", "--tree", "etc"])
+        .arg(af.path())
+        .assert()
+        .failure()
+        .stdout(contains("Archive has no bands"));
+
+    main_binary()
+        .args(&["ls", "--tree", "../escape"])
+        .arg(af.path())
+        .assert()
+        .failure()
+        .stdout(contains("Invalid tree name"));
+
+    main_binary()
+        .args(&["validate", "--tree", "home"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout(contains("Archive is OK.\n"));
+}
+
+#[test]
+fn validate_checks_named_trees() {
+    let af = ScratchArchive::new();
+    let src = TreeFixture::new();
+    src.create_file("hello");
+    main_binary()
+        .args(&["backup", "--tree", "home"])
+        .arg(af.path())
+        .arg(src.path())
+        .assert()
+        .success();
+    let hunk_path = af.path().join("trees/home/b0000/i/00000/000000000");
+    std::fs::write(&hunk_path, b"garbage").unwrap();
+    std::fs::write(af.path().join("trees/home/notes.txt"), b"").unwrap();
+
+    main_binary()
+        .arg("validate")
+        .arg(af.path())
+        .assert()
+        .stdout(contains("Failed to read index hunk"))
+        .stdout(contains("trees/home/b0000"))
+        .stdout(contains("Unexpected files in tree directory"))
+        .stdout(contains("notes.txt"));
+}
+
+#[test]
+fn rsync_slash() {
+    let af = ScratchArchive::new();
+    let src = TreeFixture::new();
+    src.create_file("hello");
+    let src_name = src
+        .path()
+        .canonicalize()
+        .unwrap()
+        .file_name()
+        .unwrap()
+        .to_string_lossy()
+        .into_owned();
+
+    main_binary()
+        .args(&["backup", "--rsync-slash"])
+        .arg(af.path())
+        .arg(src.path())
+        .assert()
+        .success();
+    main_binary()
+        .args(&["ls", "-b", "b0000"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout(format!("/\n/{0}\n/{0}/hello\n", src_name));
+
+    // With a trailing slash, the contents are stored at the root.
+    main_binary()
+        .args(&["backup", "--rsync-slash"])
+        .arg(af.path())
+        .arg(format!("{}/", src.path().display()))
+        .assert()
+        .success();
+    main_binary()
+        .args(&["ls", "-b", "b0001"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout("/\n/hello\n");
+
+    // Restoring without a destination suggests the original source.
+    main_binary()
+        .arg("restore")
+        .arg(af.path())
+        .assert()
+        .failure()
+        .stdout(contains("No restore destination given"))
+        .stdout(contains(src_name));
+}
+
+#[test]
+fn cp() {
+    let src = TreeFixture::new();
+    src.create_file("hello");
+    src.create_file("junk");
+    let dest = TempDir::new().unwrap();
+
+    main_binary()
+        .args(&["cp", "--exclude", "/junk"])
+        .arg(src.path())
+        .arg(dest.path())
+        .assert()
+        .success()
+        .stdout(starts_with("Copy complete.\n"));
+    dest.child("hello").assert("contents");
+    dest.child("junk").assert(predicate::path::missing());
+
+    // Refuses to overwrite a non-empty destination.
+    main_binary()
+        .arg("cp")
+        .arg(src.path())
+        .arg(dest.path())
+        .assert()
+        .failure()
+        .stdout(contains("Destination directory not empty"));
+}
+
+#[test]
+fn stats_json() {
+    let af = ScratchArchive::new();
+    let src = TreeFixture::new();
+    src.create_file("hello");
+    let stats_dir = TempDir::new().unwrap();
+    let stats_path = stats_dir.path().join("stats.json");
+
+    main_binary()
+        .arg("backup")
+        .arg(af.path())
+        .arg(src.path())
+        .arg("--stats-json")
+        .arg(&stats_path)
+        .assert()
+        .success()
+        .stdout(starts_with("Backup complete.\n"));
+    let json: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&stats_path).unwrap()).unwrap();
+    assert_eq!(json["files"], 1);
+    assert_eq!(json["new_files"], 1);
+    assert_eq!(json["errors"], 0);
+
+    main_binary()
+        .arg("validate")
+        .arg(af.path())
+        .arg("--stats-json")
+        .arg(&stats_path)
+        .assert()
+        .success();
+    let json: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&stats_path).unwrap()).unwrap();
+    assert_eq!(json["block_dir_stats"]["block_error_count"], 0);
+}
+
+#[test]
+fn metrics_textfile() {
+    let af = ScratchArchive::new();
+    let src = TreeFixture::new();
+    src.create_file("hello");
+    let metrics_dir = TempDir::new().unwrap();
+    let metrics_path = metrics_dir.path().join("conserve.prom");
+
+    main_binary()
+        .arg("backup")
+        .arg(af.path())
+        .arg(src.path())
+        .arg("--metrics-textfile")
+        .arg(&metrics_path)
+        .assert()
+        .success();
+    let metrics = std::fs::read_to_string(&metrics_path).unwrap();
+    assert!(metrics.contains("\nconserve_backup_files 1\n"));
+    assert!(metrics.contains("\nconserve_backup_errors 0\n"));
+    assert!(metrics.contains("# TYPE conserve_backup_last_success_timestamp_seconds gauge\n"));
+}
+
+#[test]
+fn quiet() {
+    let af = ScratchArchive::new();
+    let src = TreeFixture::new();
+    src.create_file("hello");
+
+    main_binary()
+        .arg("-q")
+        .arg("backup")
+        .arg(af.path())
+        .arg(src.path())
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    main_binary()
+        .arg("validate")
+        .arg(af.path())
+        .arg("--quiet")
+        .assert()
+        .success()
         .stdout(is_empty());
+
+    // Requested output is still shown.
+    main_binary()
+        .args(&["ls", "-q", "--no-progress"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout("/\n/hello\n");
+}
+
+#[test]
+fn log_file() {
+    let af = ScratchArchive::new();
+    let src = TreeFixture::new();
+    src.create_file("hello");
+    let log_dir = TempDir::new().unwrap();
+    let log_path = log_dir.path().join("conserve.log");
+
+    for _ in 0..2 {
+        main_binary()
+            .args(&["-q", "--log-file"])
+            .arg(&log_path)
+            .arg("backup")
+            .arg(af.path())
+            .arg(src.path())
+            .assert()
+            .success()
+            .stdout(is_empty());
+    }
+    let log = std::fs::read_to_string(&log_path).unwrap();
+    // Both runs are appended.
+    assert_eq!(log.matches("Start conserve").count(), 2);
+    assert_eq!(log.matches("backup: Backup complete.").count(), 2);
+    assert!(log.contains("Stats: {"));
+    assert!(log.ends_with("Finished successfully\n"));
+}
+
+#[test]
+fn color() {
+    let af = ScratchArchive::new();
+    let src = TreeFixture::new();
+    src.create_file("old");
+    main_binary()
+        .arg("backup")
+        .arg(af.path())
+        .arg(src.path())
+        .assert()
+        .success();
+    src.create_file("new");
+
+    let output = main_binary()
+        .args(&["diff", "--color", "always"])
+        .arg(af.path())
+        .arg(src.path())
+        .env_remove("NO_COLOR")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("\x1b["));
+    assert!(stdout.contains("right    /new"));
+
+    // By default, output that isn't a terminal isn't colored.
+    main_binary()
+        .arg("diff")
+        .arg(af.path())
+        .arg(src.path())
+        .assert()
+        .success()
+        .stdout("both     /\nright    /new\nboth     /old\n");
+    main_binary()
+        .args(&["ls", "--color=never"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout("/\n/old\n");
+
+    // The older --ui option also sets colors, unless --color is given.
+    let output = main_binary()
+        .args(&["--ui", "color", "ls"])
+        .arg(af.path())
+        .output()
+        .unwrap();
+    assert!(String::from_utf8(output.stdout).unwrap().contains("\x1b["));
+    main_binary()
+        .args(&["--ui", "color", "ls", "--color=never"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout("/\n/old\n");
+}
+
+/// Without the `notify` feature, `--notify` is accepted but only warns.
+#[cfg(not(feature = "notify"))]
+#[test]
+fn notify_unsupported() {
+    let af = ScratchArchive::new();
+    let src = TreeFixture::new();
+    src.create_file("hello");
+
+    main_binary()
+        .args(&["backup", "--notify"])
+        .arg(af.path())
+        .arg(src.path())
+        .assert()
+        .success()
+        .stdout(contains(
+            "conserve warning: This build of Conserve can't show desktop notifications",
+        ));
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+#[test]
+fn backup_checks_free_space() {
+    let af = ScratchArchive::new();
+    let src = TreeFixture::new();
+    src.create_file("hello");
+
+    main_binary()
+        .args(&["backup", "--min-free-space", &u64::MAX.to_string()])
+        .arg(af.path())
+        .arg(src.path())
+        .assert()
+        .failure()
+        .stdout(contains("less than the 18446744073709551615 required"));
+    main_binary()
+        .args(&["backup", "--warn-free-space", &u64::MAX.to_string()])
+        .arg(af.path())
+        .arg(src.path())
+        .assert()
+        .success()
+        .stdout(contains("conserve warning: Only "));
+    main_binary()
+        .args(&["versions", "--short"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout("b0000\n");
+}
+
+#[test]
+fn backup_trusting_dir_mtimes() {
+    let af = ScratchArchive::new();
+    let src = TreeFixture::new();
+    src.create_file("hello");
+    src.create_dir("subdir");
+
+    main_binary()
+        .arg("backup")
+        .arg(af.path())
+        .arg(src.path())
+        .assert()
+        .success();
+    src.create_file("subdir/added");
+    main_binary()
+        .args(&["backup", "--trust-dir-mtimes"])
+        .arg(af.path())
+        .arg(src.path())
+        .assert()
+        .success();
+    main_binary()
+        .arg("ls")
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout("/\n/hello\n/subdir\n/subdir/added\n");
+}
+
+#[test]
+fn ls_subtree() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+
+    main_binary()
+        .arg("ls")
+        .arg(af.path())
+        .arg("/subdir")
+        .assert()
+        .success()
+        .stdout("/subdir\n/subdir/subfile\n");
+
+    main_binary()
+        .arg("ls")
+        .arg(af.path())
+        .arg("subdir")
+        .assert()
+        .failure()
+        .stdout(contains("Invalid apath \"subdir\""));
+}
+
+#[test]
+fn init_with_binary_index() {
+    let tmp = TempDir::new().unwrap();
+    let adir = tmp.path().join("a");
+    let src = TreeFixture::new();
+    src.create_file("hello");
+
+    main_binary()
+        .args(&["init", "--index-format", "binary"])
+        .arg(&adir)
+        .assert()
+        .success();
+    assert!(std::fs::read_to_string(adir.join("CONSERVE"))
+        .unwrap()
+        .contains(r#"{"conserve_archive_version":"0.6.3","index_format":"binary"}"#));
+    main_binary()
+        .arg("backup")
+        .arg(&adir)
+        .arg(src.path())
+        .assert()
+        .success();
+    main_binary()
+        .arg("ls")
+        .arg(&adir)
+        .assert()
+        .success()
+        .stdout("/\n/hello\n");
+}
+
+#[test]
+fn init_with_tuning() {
+    let tmp = TempDir::new().unwrap();
+    let adir = tmp.path().join("a");
+    let src = TreeFixture::new();
+    src.create_file_with_contents("big", &[7u8; 10_000]);
+
+    main_binary()
+        .args(&["init", "--block-size", "10"])
+        .arg(&adir)
+        .assert()
+        .failure()
+        .stdout(contains("Invalid tuning: block size must be between"));
+    main_binary()
+        .args(&[
+            "init",
+            "--block-size",
+            "4096",
+            "--small-file-size",
+            "1000",
+            "--index-hunk-entries",
+            "100",
+        ])
+        .arg(&adir)
+        .assert()
+        .success();
+    assert!(std::fs::read_to_string(adir.join("CONSERVE"))
+        .unwrap()
+        .contains(r#""block_size":4096"#));
+    main_binary()
+        .arg("backup")
+        .arg(&adir)
+        .arg(src.path())
+        .assert()
+        .success()
+        .stdout(contains(" 2      new data blocks:"));
+}
+
+#[test]
+fn small_file_size() {
+    let af = ScratchArchive::new();
+    let src = TreeFixture::new();
+    src.create_file("hello");
+
+    main_binary()
+        .args(&["backup", "--small-file-size", "lots"])
+        .arg(af.path())
+        .arg(src.path())
+        .assert()
+        .failure();
+    main_binary()
+        .args(&["backup", "--small-file-size", "0"])
+        .arg(af.path())
+        .arg(src.path())
+        .assert()
+        .success()
+        .stdout(contains("0        small files combined"));
+}
+
+#[test]
+fn paranoid_backup() {
+    let af = ScratchArchive::new();
+    let src = TreeFixture::new();
+    src.create_file("hello");
+
+    main_binary()
+        .args(&["backup", "--paranoid"])
+        .arg(af.path())
+        .arg(src.path())
+        .assert()
+        .success();
+    main_binary()
+        .arg("validate")
+        .arg(af.path())
+        .assert()
+        .success();
+}
+
+#[test]
+fn layered_backup() {
+    let af = ScratchArchive::new();
+    let src = TreeFixture::new();
+    src.create_file("hello");
+    src.create_file("world");
+
+    for _ in 0..2 {
+        main_binary()
+            .args(&["backup", "--layered"])
+            .arg(af.path())
+            .arg(src.path())
+            .assert()
+            .success();
+    }
+    main_binary()
+        .arg("band-info")
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout(contains("Layered on:    b0000\n"))
+        .stdout(contains("Entries:       0\n"));
+    main_binary()
+        .arg("ls")
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout("/\n/hello\n/world\n");
+}
+
+#[test]
+fn select_versions_back_from_latest() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+
+    main_binary()
+        .args(&["ls", "-b", "latest~1"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout(contains("/hello\n"))
+        .stdout(contains("/hello2").not());
+    main_binary()
+        .args(&["ls", "--backup", "latest"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout(contains("/hello2\n"));
+    main_binary()
+        .args(&["diff", "-b", "latest"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout(contains("added    /hello2\n"));
+    main_binary()
+        .args(&["ls", "-b", "latest~2"])
+        .arg(af.path())
+        .assert()
+        .failure()
+        .stdout(contains(
+            "No version \"latest~2\": the archive has 2 complete versions",
+        ));
+}
+
+#[test]
+fn diff_and_versions_show_deletions() {
+    let af = ScratchArchive::new();
+    let src = TreeFixture::new();
+    src.create_file("hello");
+    src.create_file("world");
+
+    let backup = || {
+        main_binary()
+            .args(&["backup", "--layered"])
+            .arg(af.path())
+            .arg(src.path())
+            .assert()
+            .success();
+    };
+    backup();
+    std::fs::remove_file(src.path().join("world")).unwrap();
+    src.create_file("new");
+    backup();
+
+    main_binary()
+        .args(&["diff", "-b", "b0001"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout(contains("added    /new\n"))
+        .stdout(contains("deleted  /world\n"))
+        .stdout(contains("/hello").not());
+    main_binary()
+        .args(&["diff", "-b", "b0001"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout(contains("changed  /\n"));
+    main_binary()
+        .args(&["diff", "--ignore-dir-mtimes", "-b", "b0001"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout("added    /new\ndeleted  /world\n");
+    main_binary()
+        .args(&["versions", "--changes"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout(contains("+3         ~0         -0\n"))
+        .stdout(contains("-1\n"));
+}
+
+#[test]
+fn squash_layered_versions() {
+    let af = ScratchArchive::new();
+    let src = TreeFixture::new();
+    src.create_file("hello");
+
+    for name in &["world", "again"] {
+        src.create_file(name);
+        main_binary()
+            .args(&["backup", "--layered"])
+            .arg(af.path())
+            .arg(src.path())
+            .assert()
+            .success();
+    }
+    main_binary()
+        .args(&["squash", "-b", "b0000..b0001"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout("Squashed 1 versions into b0001\n");
+    main_binary()
+        .args(&["versions", "--short"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout("b0001\n");
+    main_binary()
+        .arg("ls")
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout("/\n/again\n/hello\n/world\n");
+
+    // The squashed version is in the trash until it's emptied.
+    main_binary()
+        .args(&["trash", "ls"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout("b0000\n");
+    main_binary()
+        .args(&["undelete", "-b", "b0000"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout("Undeleted b0000\n");
+    main_binary()
+        .args(&["versions", "--short"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout("b0000\nb0001\n");
+    main_binary()
+        .args(&["squash", "-b", "b0000..b0001"])
+        .arg(af.path())
+        .assert()
+        .success();
+    main_binary()
+        .args(&["trash", "empty"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout("Removed 1 versions from the trash\n");
+    main_binary()
+        .args(&["undelete", "-b", "b0000"])
+        .arg(af.path())
+        .assert()
+        .failure()
+        .stdout(contains("Band b0000 is not in the trash"));
+    main_binary()
+        .args(&["validate"])
+        .arg(af.path())
+        .assert()
+        .success();
+
+    main_binary()
+        .args(&["squash", "-b", "b0001"])
+        .arg(af.path())
+        .assert()
+        .failure()
+        .stdout(contains("expected START..END"));
+}
+
+#[test]
+fn squash_needs_deletion_secret() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+    main_binary()
+        .args(&["guard-deletion", "--new-secret-env", "DELETION_SECRET"])
+        .arg(af.path())
+        .env("DELETION_SECRET", "second person")
+        .assert()
+        .success()
+        .stdout("Removing versions now needs the deletion secret\n");
+
+    main_binary()
+        .args(&["squash", "-b", "b0000..b0001"])
+        .arg(af.path())
+        .assert()
+        .failure()
+        .stdout(contains("guarded against deletion"));
+    main_binary()
+        .args(&["squash", "-b", "b0000..b0001"])
+        .args(&["--deletion-secret-env", "DELETION_SECRET"])
+        .arg(af.path())
+        .env("DELETION_SECRET", "first person")
+        .assert()
+        .failure()
+        .stdout(contains("Wrong deletion secret"));
+    main_binary()
+        .args(&["squash", "-b", "b0000..b0001"])
+        .args(&["--deletion-secret-env", "DELETION_SECRET"])
+        .arg(af.path())
+        .env("DELETION_SECRET", "second person")
+        .assert()
+        .success()
+        .stdout("Squashed 1 versions into b0001\n");
+
+    main_binary()
+        .args(&["guard-deletion", "--remove"])
+        .arg(af.path())
+        .assert()
+        .failure();
+    main_binary()
+        .args(&["guard-deletion", "--remove"])
+        .args(&["--deletion-secret-env", "DELETION_SECRET"])
+        .arg(af.path())
+        .env("DELETION_SECRET", "second person")
+        .assert()
+        .success();
+    main_binary()
+        .args(&["validate"])
+        .arg(af.path())
+        .assert()
+        .success();
+}
+
+#[test]
+fn ls_tree_view() {
+    let af = ScratchArchive::new();
+    let src = TreeFixture::new();
+    src.create_file_with_contents("hello", b"hi");
+    src.create_dir("subdir");
+    src.create_file_with_contents("subdir/big", &[0; 2500]);
+    src.create_file_with_contents("subdir/small", b"abc");
+    main_binary()
+        .arg("backup")
+        .arg(af.path())
+        .arg(src.path())
+        .assert()
+        .success();
+
+    main_binary()
+        .args(&["ls", "--tree-view"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout(concat!(
+            "    2.5 KB  /\n",
+            "       2 B  ├── hello\n",
+            "    2.5 KB  └── subdir\n",
+            "    2.5 KB      ├── big\n",
+            "       3 B      └── small\n",
+        ));
+    main_binary()
+        .args(&["ls", "--tree-view"])
+        .arg(af.path())
+        .arg("/subdir")
+        .assert()
+        .success()
+        .stdout("    2.5 KB  /subdir\n    2.5 KB  ├── big\n       3 B  └── small\n");
+}
+
+#[test]
+fn filter_backup_ls_and_restore() {
+    let af = ScratchArchive::new();
+    let src = TreeFixture::new();
+    src.create_file_with_contents("small.log", b"hi");
+    src.create_dir("subdir");
+    src.create_file_with_contents("subdir/big.log", &[0; 2500]);
+    src.create_file_with_contents("subdir/big.dat", &[1; 2500]);
+    src.create_file_with_contents("subdir/notes", b"abc");
+    main_binary()
+        .args(&["backup", "--filter", "name != notes"])
+        .arg(af.path())
+        .arg(src.path())
+        .assert()
+        .success();
+
+    main_binary()
+        .arg("ls")
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout("/\n/small.log\n/subdir\n/subdir/big.dat\n/subdir/big.log\n");
+    main_binary()
+        .args(&["ls", "--filter", r#"size > 1KB and path ~ "*.log""#])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout("/subdir/big.log\n");
+
+    let dest = TempDir::new().unwrap();
+    main_binary()
+        .args(&["restore", "--filter", "kind = file and size < 1KiB"])
+        .arg(af.path())
+        .arg(dest.path())
+        .assert()
+        .success();
+    dest.child("small.log").assert("hi");
+    dest.child("subdir").assert(is_dir());
+    dest.child("subdir/big.log")
+        .assert(predicate::path::missing());
+
+    main_binary()
+        .args(&["ls", "--filter", "size >> 1"])
+        .arg(af.path())
+        .assert()
+        .failure()
+        .stdout(contains("Invalid filter \"size >> 1\": expected a value"));
+}
+
+#[test]
+fn du_top() {
+    let af = ScratchArchive::new();
+    let src = TreeFixture::new();
+    src.create_file_with_contents("hello", b"hi");
+    src.create_dir("subdir");
+    src.create_file_with_contents("subdir/big", &[0; 2500]);
+    src.create_file_with_contents("subdir/small", b"abc");
+    main_binary()
+        .arg("backup")
+        .arg(af.path())
+        .arg(src.path())
+        .assert()
+        .success();
+
+    main_binary()
+        .args(&["du", "-b", "b0000", "--top", "2"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout(concat!(
+            "Largest files:\n",
+            "    2.5 KB  /subdir/big\n",
+            "       3 B  /subdir/small\n",
+            "Largest directories:\n",
+            "    2.5 KB  /\n",
+            "    2.5 KB  /subdir\n",
+        ));
+}
+
+#[test]
+fn restore_with_path_map() {
+    let af = ScratchArchive::new();
+    let src = TreeFixture::new();
+    src.create_dir("alice");
+    src.create_file("alice/notes");
+    main_binary()
+        .arg("backup")
+        .arg(af.path())
+        .arg(src.path())
+        .assert()
+        .success();
+
+    let dest = TempDir::new().unwrap();
+    main_binary()
+        .args(&["restore", "--map", "/alice=/home/bob"])
+        .arg(af.path())
+        .arg(dest.path())
+        .assert()
+        .success();
+    dest.child("home/bob/notes").assert(is_file());
+    dest.child("alice").assert(predicate::path::missing());
+
+    main_binary()
+        .args(&["restore", "--map-user", "1000"])
+        .arg(af.path())
+        .arg(TempDir::new().unwrap().path())
+        .assert()
+        .failure()
+        .stdout(contains("Invalid ID map \"1000\""));
+    main_binary()
+        .args(&["restore", "--map", "alice"])
+        .arg(af.path())
+        .arg(TempDir::new().unwrap().path())
+        .assert()
+        .failure()
+        .stdout(contains("Invalid path map \"alice\""));
+}
+
+#[test]
+fn deterministic_backup() {
+    let af = ScratchArchive::new();
+    let src = TreeFixture::new();
+    src.create_file("hello");
+
+    main_binary()
+        .args(&["backup", "--deterministic"])
+        .arg(af.path())
+        .arg(src.path())
+        .assert()
+        .success();
+    let head = std::fs::read_to_string(af.path().join("b0000").join("BANDHEAD")).unwrap();
+    assert!(head.contains(r#""start_time":0"#), "{}", head);
+    let tail = std::fs::read_to_string(af.path().join("b0000").join("BANDTAIL")).unwrap();
+    assert_eq!(tail, "{\"end_time\":0}\n");
+}
+
+#[test]
+fn blockdir_stats() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+
+    main_binary()
+        .args(&["blockdir-stats", "--subdirs"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout(contains("      blocks\n"))
+        .stdout(contains("Subdirectories:"))
+        .stdout(contains("0      misplaced blocks").and(contains("warning").not()));
+
+    std::fs::write(af.path().join("d").join("junk"), b"").unwrap();
+    main_binary()
+        .arg("blockdir-stats")
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout(contains("1      unexpected files"))
+        .stdout(contains(
+            "conserve warning: The block directory contains unexpected files",
+        ));
+}
+
+#[test]
+fn skip_archive_inside_source() {
+    let src = TreeFixture::new();
+    src.create_file("hello");
+    let archive_dir = src.path().join("archive");
+
+    main_binary()
+        .arg("init")
+        .arg(&archive_dir)
+        .assert()
+        .success();
+    main_binary()
+        .arg("backup")
+        .arg(&archive_dir)
+        .arg(src.path())
+        .assert()
+        .success()
+        .stdout(contains("conserve warning: Skipping Conserve archive"));
+    main_binary()
+        .arg("ls")
+        .arg(&archive_dir)
+        .assert()
+        .success()
+        .stdout("/\n/hello\n");
+
+    main_binary()
+        .args(&["source", "ls", "--include-archives"])
+        .arg(src.path())
+        .assert()
+        .success()
+        .stdout(contains("/archive\n"));
+}
+
+#[test]
+fn diff_and_validate_with_exclude() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+    let src = TreeFixture::new();
+    src.create_file("hello");
+    src.create_file("junk");
+
+    main_binary()
+        .args(&["diff", "--exclude", "/junk", "--exclude", "/subdir"])
+        .args(&["--exclude", "/hello2", "--exclude", "/link"])
+        .arg(af.path())
+        .arg(src.path())
+        .assert()
+        .success()
+        .stdout("both     /\nboth     /hello\n");
+
+    main_binary()
+        .args(&["validate", "--backup", "b0000", "--exclude", "/hello"])
+        .arg(af.path())
+        .assert()
+        .success();
+}
+
+#[test]
+fn validate_since_uses_sidecar_cache() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+    let cache_path = af.path().with_file_name("archive.validated");
+
+    for _ in 0..2 {
+        main_binary()
+            .args(&["validate", "--since", "30d"])
+            .arg(af.path())
+            .assert()
+            .success()
+            .stdout(contains("Archive is OK.\n"));
+        assert!(cache_path.is_file());
+    }
+
+    main_binary()
+        .args(&["validate", "--scrub-budget", "1h"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout(contains("Archive is OK.\n"));
+
+    main_binary()
+        .args(&["validate", "--since", "soon"])
+        .arg(af.path())
+        .assert()
+        .failure()
+        .stdout(contains("Invalid age \"soon\""));
+}
+
+#[test]
+fn validate_fix_index_order() {
+    use conserve::compress::snappy::Snappy;
+    use conserve::compress::Compression;
+    use conserve::{Band, BandId, IndexEntry};
+
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+    let band = Band::open(&af, &BandId::zero()).unwrap();
+    let mut entries: Vec<IndexEntry> = band.iter_entries().unwrap().collect();
+    entries.swap(1, 2);
+    let mut hunk =
+        std::fs::File::create(band.index_dir_path.join("00000").join("000000000")).unwrap();
+    Snappy::compress_and_write(&serde_json::to_vec(&entries).unwrap(), &mut hunk).unwrap();
+
+    main_binary()
+        .arg("validate")
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout(contains("out of order"))
+        .stdout(contains("Archive is OK.").not());
+
+    main_binary()
+        .args(&["validate", "--fix-index-order"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout(contains("Rewrote index of b0000 in order"));
+
+    main_binary()
+        .arg("validate")
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout(contains("Archive is OK.\n"));
+}
+
+#[test]
+fn doctor() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+    let src = TreeFixture::new();
+
+    main_binary()
+        .args(&["doctor", "--warn-free-space", "0"])
+        .arg(af.path())
+        .arg(src.path())
+        .assert()
+        .success()
+        .stdout(is_match(r"^ok +archive +Archive .* is readable, in format 0\.6\n").unwrap())
+        .stdout(contains("All 2 versions are readable\n"))
+        .stdout(contains("source "))
+        .stdout(contains("warning").not());
+
+    main_binary()
+        .args(&["doctor", "--json"])
+        .arg(af.path())
+        .arg(src.path().join("missing"))
+        .assert()
+        .failure()
+        .stdout(contains(r#""severity":"Problem","check":"source""#))
+        .stdout(contains("Found 1 problems"));
+
+    main_binary()
+        .arg("doctor")
+        .arg(src.path())
+        .assert()
+        .failure()
+        .stdout(contains("problem archive"))
+        .stdout(contains("conserve init"));
+}
+
+#[test]
+fn backup_scan_cache() {
+    let af = ScratchArchive::new();
+    let src = TreeFixture::new();
+    src.create_file("hello");
+    let cache_path = af.path().with_file_name("scan");
+
+    for band_id in &["b0000", "b0001"] {
+        main_binary()
+            .args(&["backup", "--scan-cache"])
+            .arg(&cache_path)
+            .arg(af.path())
+            .arg(src.path())
+            .assert()
+            .success();
+        let cache = std::fs::read_to_string(&cache_path).unwrap();
+        assert!(cache.lines().next().unwrap().contains(band_id), "{}", cache);
+        assert!(cache.contains(r#""apath":"/hello""#));
+    }
+    main_binary()
+        .arg("validate")
+        .arg(af.path())
+        .assert()
+        .success();
+}
+
+#[cfg(unix)]
+#[test]
+fn backup_dereference_skips_symlink_loops() {
+    let af = ScratchArchive::new();
+    let src = TreeFixture::new();
+    src.create_dir("sub");
+    src.create_file("sub/hello");
+    src.create_symlink("sub/up", "..");
+    src.create_symlink("link", "sub/hello");
+
+    main_binary()
+        .args(&["backup", "--dereference"])
+        .arg(af.path())
+        .arg(src.path())
+        .assert()
+        .success()
+        .stdout(contains("SymlinkLoop"));
+    main_binary()
+        .arg("ls")
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout("/\n/link\n/sub\n/sub/hello\n");
+}
+
+#[test]
+fn max_depth() {
+    let af = ScratchArchive::new();
+    let src = TreeFixture::new();
+    src.create_file("hello");
+    src.create_dir("subdir");
+    src.create_file("subdir/subfile");
+
+    main_binary()
+        .args(&["backup", "--max-depth", "1"])
+        .arg(af.path())
+        .arg(src.path())
+        .assert()
+        .success();
+    main_binary()
+        .arg("ls")
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout("/\n/hello\n/subdir\n");
+
+    main_binary()
+        .arg("backup")
+        .arg(af.path())
+        .arg(src.path())
+        .assert()
+        .success();
+    main_binary()
+        .args(&["ls", "--max-depth", "1"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout("/\n/hello\n/subdir\n");
+
+    let dest = TempDir::new().unwrap();
+    main_binary()
+        .args(&["restore", "--max-depth", "1"])
+        .arg(af.path())
+        .arg(dest.path())
+        .assert()
+        .success();
+    dest.child("hello").assert(is_file());
+    dest.child("subdir").assert(is_dir());
+    dest.child("subdir/subfile")
+        .assert(predicate::path::missing());
+}
+
+#[test]
+fn backup_profile_entries() {
+    let af = ScratchArchive::new();
+    let src = TreeFixture::new();
+    src.create_dir("subdir");
+    src.create_file("subdir/subfile");
+
+    main_binary()
+        .args(&["backup", "--profile-entries", "5"])
+        .arg(af.path())
+        .arg(src.path())
+        .assert()
+        .success()
+        .stdout(contains("slowest files:"))
+        .stdout(is_match(r"\d\.\d{3}s +/subdir/subfile \(read ").unwrap())
+        .stdout(is_match(r"slowest directories:\n +\d\.\d{3}s +/").unwrap());
+}
+
+#[cfg(unix)]
+#[test]
+fn backup_progress_json() {
+    let af = ScratchArchive::new();
+    let src = TreeFixture::new();
+    src.create_file("hello");
+
+    let output = main_binary()
+        .args(&["--progress-json", "1", "backup"])
+        .arg(af.path())
+        .arg(src.path())
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let events: Vec<serde_json::Value> = String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .filter(|line| line.starts_with('{'))
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert!(
+        events.iter().any(|event| event["phase"] == "Copying"),
+        "{:?}",
+        events
+    );
+    for event in &events {
+        assert!(event["bytes_done"].is_u64());
+        assert!(event["bytes_total"].is_u64());
+        assert!(event["apath"].is_string());
+    }
+}
+
+#[test]
+fn backup_state_file() {
+    let af = ScratchArchive::new();
+    let src = TreeFixture::new();
+    src.create_file("hello");
+    let state_path = af.path().with_file_name("state.json");
+    let read_state = || -> serde_json::Value {
+        serde_json::from_slice(&std::fs::read(&state_path).unwrap()).unwrap()
+    };
+
+    main_binary()
+        .args(&["backup", "--state-file"])
+        .arg(&state_path)
+        .arg(af.path())
+        .arg(src.path())
+        .assert()
+        .success();
+    let state = read_state();
+    assert_eq!(state["command"], "backup");
+    assert_eq!(state["last_run_succeeded"], true);
+    assert_eq!(state["band_id"], "b0000");
+    assert_eq!(state["error_count"], 0);
+    let last_success_time = state["last_success_time"].as_i64().unwrap();
+    assert!(last_success_time > 0);
+    assert_eq!(state["last_run_time"], last_success_time);
+
+    // A failed run is recorded, keeping the time of the last success.
+    main_binary()
+        .args(&["backup", "--state-file"])
+        .arg(&state_path)
+        .arg(af.path())
+        .arg(src.path().join("missing"))
+        .assert()
+        .failure();
+    let state = read_state();
+    assert_eq!(state["last_run_succeeded"], false);
+    assert!(state["last_error"].as_str().is_some());
+    assert_eq!(state["last_success_time"], last_success_time);
+    assert_eq!(state["band_id"], "b0000");
+
+    main_binary()
+        .args(&["validate", "--state-file"])
+        .arg(&state_path)
+        .arg(af.path())
+        .assert()
+        .success();
+    let state = read_state();
+    assert_eq!(state["command"], "validate");
+    assert_eq!(state["last_run_succeeded"], true);
+}
+
+#[test]
+fn explain_excludes() {
+    let src = TreeFixture::new();
+    src.create_file("hello");
+    src.create_dir("cache");
+    src.create_file("cache/data");
+    src.create_file("notes.tmp");
+    main_binary()
+        .arg("init")
+        .arg(src.path().join("archive"))
+        .assert()
+        .success();
+
+    main_binary()
+        .args(&["explain-excludes", "--exclude", "/cache", "-e", "*.tmp"])
+        .arg(src.path())
+        .assert()
+        .success()
+        .stdout(
+            "/archive                                 Conserve archive\n\
+             /cache                                   excluded by /cache\n\
+             /notes.tmp                               excluded by *.tmp\n",
+        );
+}
+
+#[test]
+fn exclude_preset() {
+    let src = TreeFixture::new();
+    src.create_file("hello");
+    src.create_file(".DS_Store");
+    src.create_dir("app");
+    src.create_dir("app/node_modules");
+    src.create_file("app/node_modules/left-pad.js");
+
+    main_binary()
+        .args(&["source", "ls", "--exclude-preset", "os-caches,node_modules"])
+        .arg(src.path())
+        .assert()
+        .success()
+        .stdout("/\n/app\n/hello\n");
+
+    main_binary()
+        .args(&["explain-excludes", "--exclude-preset", "node_modules"])
+        .arg(src.path())
+        .assert()
+        .success()
+        .stdout(contains("/app/node_modules").and(contains("excluded by /**/node_modules")));
+
+    main_binary()
+        .args(&["source", "ls", "--exclude-preset", "junk"])
+        .arg(src.path())
+        .assert()
+        .failure()
+        .stdout(contains("Unknown exclude preset \"junk\""));
+}
+
+#[test]
+fn exclude_if_present() {
+    let af = ScratchArchive::new();
+    let src = TreeFixture::new();
+    src.create_file("hello");
+    src.create_dir("scratch");
+    src.create_file("scratch/.nobackup");
+    src.create_file("scratch/big");
+
+    main_binary()
+        .args(&["backup", "--exclude-if-present", ".nobackup"])
+        .arg(af.path())
+        .arg(src.path())
+        .assert()
+        .success();
+    main_binary()
+        .arg("ls")
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout("/\n/hello\n");
+
+    main_binary()
+        .args(&["explain-excludes", "--exclude-if-present", ".nobackup"])
+        .arg(src.path())
+        .assert()
+        .success()
+        .stdout(contains("/scratch").and(contains("contains .nobackup")));
+}
+
+#[test]
+fn serve_bad_address() {
+    let af = ScratchArchive::new();
+    main_binary()
+        .args(&["serve", "--listen", "not an address"])
+        .arg(af.path())
+        .assert()
+        .failure()
+        .stdout(contains("Failed to listen on \"not an address\""));
+}
+
+#[test]
+fn serve_and_push() {
+    let local = ScratchArchive::new();
+    local.store_two_versions();
+    let remote = ScratchArchive::new();
+    let token_dir = TempDir::new().unwrap();
+    token_dir.child("token").write_str("s3cret\n").unwrap();
+    let token_path = token_dir.path().join("token");
+
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let address = format!("127.0.0.1:{}", port);
+    let mut server = main_binary()
+        .args(&["serve", "--listen", &address, "--push-token-file"])
+        .arg(&token_path)
+        .arg(remote.path())
+        .spawn()
+        .unwrap();
+    for _ in 0..100 {
+        if std::net::TcpStream::connect(&address).is_ok() {
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(50));
+    }
+
+    let result = main_binary()
+        .args(&["push", "--token-file"])
+        .arg(&token_path)
+        .arg(local.path())
+        .arg(format!("http://{}/", address))
+        .assert();
+    server.kill().unwrap();
+    server.wait().unwrap();
+    result
+        .success()
+        .stdout(contains("           2      bands pushed\n"));
+
+    main_binary()
+        .args(&["ls"])
+        .arg(remote.path())
+        .assert()
+        .success()
+        .stdout(contains("/hello2\n"));
+}
+
+#[cfg(unix)]
+#[test]
+fn push_with_token_from_env_and_command() {
+    let local = ScratchArchive::new();
+    local.store_two_versions();
+    let remote = ScratchArchive::new();
+
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let address = format!("127.0.0.1:{}", port);
+    let mut server = main_binary()
+        .args(&[
+            "serve",
+            "--listen",
+            &address,
+            "--push-token-env",
+            "PUSH_TOKEN",
+        ])
+        .env("PUSH_TOKEN", "s3cret")
+        .arg(remote.path())
+        .spawn()
+        .unwrap();
+    for _ in 0..100 {
+        if std::net::TcpStream::connect(&address).is_ok() {
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(50));
+    }
+
+    let failed = main_binary()
+        .args(&["push", "--token-command", "exit 2"])
+        .arg(local.path())
+        .arg(format!("http://{}/", address))
+        .assert();
+    let result = main_binary()
+        .args(&["push", "--password-command", "echo s3cret"])
+        .arg(local.path())
+        .arg(format!("http://{}/", address))
+        .assert();
+    server.kill().unwrap();
+    server.wait().unwrap();
+    failed
+        .failure()
+        .stdout(contains("Failed to get credential from command \"exit 2\""));
+    result
+        .success()
+        .stdout(contains("           2      bands pushed\n"));
+}
+
+#[cfg(not(any(windows, target_os = "macos")))]
+#[test]
+fn snapshot_needs_windows_or_macos() {
+    let af = ScratchArchive::new();
+    let src = TreeFixture::new();
+    main_binary()
+        .args(&["backup", "--snapshot"])
+        .arg(af.path())
+        .arg(src.path())
+        .assert()
+        .failure()
+        .stdout(contains(
+            "snapshots are only supported on Windows and macOS",
+        ));
+}
+
+#[cfg(not(windows))]
+#[test]
+fn ntfs_metadata_needs_windows() {
+    let af = ScratchArchive::new();
+    let src = TreeFixture::new();
+    src.create_file("hello");
+    main_binary()
+        .args(&["backup", "--ntfs-metadata"])
+        .arg(af.path())
+        .arg(src.path())
+        .assert()
+        .success()
+        .stdout(contains("NTFS metadata can only be captured on Windows"));
+}
+
+#[test]
+fn watch_max_backups() {
+    let af = ScratchArchive::new();
+    let src = TreeFixture::new();
+    src.create_file("hello");
+    main_binary()
+        .args(&["watch", "--max-backups", "1", "--poll-interval", "0.1"])
+        .arg(af.path())
+        .arg(src.path())
+        .assert()
+        .success()
+        .stdout(contains("Backup complete."));
+    main_binary()
+        .arg("ls")
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout("/\n/hello\n");
+}
+
+#[cfg(unix)]
+#[test]
+fn notify_command_gets_report() {
+    let af = ScratchArchive::new();
+    let src = TreeFixture::new();
+    src.create_file("hello");
+    let report_dir = TempDir::new().unwrap();
+    let report_path = report_dir.path().join("report.json");
+    main_binary()
+        .arg("backup")
+        .arg(af.path())
+        .arg(src.path())
+        .arg("--notify-command")
+        .arg(format!("cat >{}", report_path.display()))
+        .assert()
+        .success();
+    let report: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(&report_path).unwrap()).unwrap();
+    assert_eq!(report["command"], "backup");
+    assert_eq!(report["succeeded"], true);
+    assert_eq!(report["stats"]["files"], 1);
+
+    // A failed validation is reported too, and a failing notification is
+    // only a warning.
+    let empty = ScratchArchive::new();
+    main_binary()
+        .arg("validate")
+        .arg(empty.path())
+        .arg("--backup=b0123")
+        .arg("--notify-command")
+        .arg(format!(
+            "test \"$CONSERVE_RESULT\" = failed && cat >{}",
+            report_path.display()
+        ))
+        .arg("--webhook=http://127.0.0.1:1/")
+        .assert()
+        .failure()
+        .stdout(contains("Failed to send report"));
+    let report: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(&report_path).unwrap()).unwrap();
+    assert_eq!(report["command"], "validate");
+    assert_eq!(report["succeeded"], false);
+}
+
+#[test]
+fn backup_and_replicate() {
+    let af = ScratchArchive::new();
+    let mirror = ScratchArchive::new();
+    let src = TreeFixture::new();
+    src.create_file("hello");
+    let config_dir = TempDir::new().unwrap();
+    let config = config_dir.child("mirrors.json");
+    config
+        .write_str(&format!(
+            r#"{{"mirrors": [{{"name": "usb", "destination": {:?}}}]}}"#,
+            mirror.path()
+        ))
+        .unwrap();
+    main_binary()
+        .arg("backup")
+        .arg(af.path())
+        .arg(src.path())
+        .arg("--replicate")
+        .arg(config.path())
+        .assert()
+        .success()
+        .stdout(contains("usb                  pushed 1 bands"));
+    config_dir.child("mirrors.json.state").assert(is_file());
+    main_binary()
+        .arg("ls")
+        .arg(mirror.path())
+        .assert()
+        .success()
+        .stdout("/\n/hello\n");
+
+    main_binary()
+        .arg("replicate")
+        .arg(af.path())
+        .arg(config.path())
+        .assert()
+        .success()
+        .stdout("usb                  up to date\n");
+}
+
+#[test]
+fn backup_with_state_file_and_replicate() {
+    let af = ScratchArchive::new();
+    let mirror = ScratchArchive::new();
+    let src = TreeFixture::new();
+    src.create_file("hello");
+    let config_dir = TempDir::new().unwrap();
+    let config = config_dir.child("mirrors.json");
+    config
+        .write_str(&format!(
+            r#"{{"mirrors": [{{"name": "usb", "destination": {:?}}}]}}"#,
+            mirror.path()
+        ))
+        .unwrap();
+    let state = config_dir.child("mon.json");
+    main_binary()
+        .arg("backup")
+        .arg(af.path())
+        .arg(src.path())
+        .arg("--state-file")
+        .arg(state.path())
+        .arg("--replicate")
+        .arg(config.path())
+        .assert()
+        .success()
+        .stdout(contains("usb                  pushed 1 bands"))
+        .stdout(contains("Failed to replicate").not());
+    let state: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(state.path()).unwrap()).unwrap();
+    assert_eq!(state["command"], "backup");
+    config_dir.child("mirrors.json.state").assert(is_file());
+    main_binary()
+        .arg("ls")
+        .arg(mirror.path())
+        .assert()
+        .success()
+        .stdout("/\n/hello\n");
+}
+
+#[test]
+fn signed_archive() {
+    let testdir = TempDir::new().unwrap();
+    let key = testdir.child("key");
+    let arch = testdir.child("a");
+    let src = TreeFixture::new();
+    src.create_file("hello");
+    main_binary()
+        .arg("keygen")
+        .arg(key.path())
+        .assert()
+        .success()
+        .stdout(starts_with("Created key "));
+    let public_key = testdir.child("key.pub");
+    public_key.assert(is_file());
+    main_binary()
+        .arg("init")
+        .arg(arch.path())
+        .arg("--key")
+        .arg(key.path())
+        .assert()
+        .success();
+    arch.child("CONSERVE.sig").assert(is_file());
+    main_binary()
+        .arg("backup")
+        .arg(arch.path())
+        .arg(src.path())
+        .arg("--key")
+        .arg(key.path())
+        .assert()
+        .success();
+    arch.child("b0000").child("BANDSIG").assert(is_file());
+    main_binary()
+        .arg("validate")
+        .arg(arch.path())
+        .arg("--key")
+        .arg(key.path())
+        .assert()
+        .success();
+    main_binary()
+        .arg("ls")
+        .arg(arch.path())
+        .arg("--public-key")
+        .arg(public_key.path())
+        .assert()
+        .success()
+        .stdout(contains("/hello"));
+
+    // Changing the stored version is detected when it's read with the key.
+    std::fs::write(
+        arch.child("b0000").child("BANDTAIL").path(),
+        "{\"end_time\":0}\n",
+    )
+    .unwrap();
+    main_binary()
+        .arg("ls")
+        .arg(arch.path())
+        .arg("--public-key")
+        .arg(public_key.path())
+        .assert()
+        .failure()
+        .stdout(contains("Bad signature"));
+    main_binary()
+        .arg("validate")
+        .arg(arch.path())
+        .arg("--key")
+        .arg(key.path())
+        .assert()
+        .failure();
+}
+
+#[test]
+fn source_helper() {
+    let src = TreeFixture::new();
+    src.create_file("hello");
+    src.create_dir("subdir");
+
+    main_binary()
+        .args(&["source", "helper", "cat"])
+        .arg(src.path().join("hello"))
+        .assert()
+        .success()
+        .stdout("contents");
+    main_binary()
+        .args(&["source", "helper", "list"])
+        .arg(src.path())
+        .assert()
+        .success()
+        .stdout(
+            contains(r#"{"name":"hello","kind":"File","mtime":"#)
+                .and(contains(r#"{"name":"subdir","kind":"Dir","mtime":"#)),
+        );
+    main_binary()
+        .args(&["source", "helper", "cat"])
+        .arg(src.path().join("missing"))
+        .assert()
+        .failure();
+
+    // The helper is hidden from help.
+    main_binary()
+        .args(&["source", "--help"])
+        .assert()
+        .success()
+        .stdout(contains("helper").not());
+
+    // Running the helper under a command that changes nothing still backs up.
+    let af = ScratchArchive::new();
+    main_binary()
+        .args(&["backup", "--escalate-command", "env"])
+        .arg(af.path())
+        .arg(src.path())
+        .assert()
+        .success();
+}
+
+#[test]
+fn stall_timeout() {
+    let af = ScratchArchive::new();
+    let src = TreeFixture::new();
+    src.create_file("hello");
+
+    main_binary()
+        .args(&["backup", "--abandon-stalled"])
+        .arg(af.path())
+        .arg(src.path())
+        .assert()
+        .failure();
+    main_binary()
+        .args(&["backup", "--stall-timeout", "60", "--abandon-stalled"])
+        .arg(af.path())
+        .arg(src.path())
+        .assert()
+        .success()
+        .stdout(contains("No progress").not());
+}
+
+#[test]
+fn stats_history() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+
+    main_binary()
+        .arg("stats")
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout(contains("Versions:   2\n").and(contains("Latest:     b0001\n")));
+    main_binary()
+        .args(&["stats", "--history"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout(
+            is_match(concat!(
+                r"^version +start +files +size +added +changed +removed +new +total\n",
+                r"b0000 +20\d\d-\d\d-\d\d \d\d:\d\d:\d\d +2 +0 MB +2 +0 +0 ",
+            ))
+            .unwrap(),
+        );
+    main_binary()
+        .args(&["stats", "--csv"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout(
+            is_match(concat!(
+                r"^version,start_time,complete,files,file_bytes,files_added,files_changed,",
+                r"files_removed,new_block_bytes,total_block_bytes\n",
+                r"b0000,20[-\d:T+.]+,true,2,16,2,0,0,\d+,\d+\n",
+                r"b0001,20[-\d:T+.]+,true,3,24,1,0,0,\d+,\d+\n$",
+            ))
+            .unwrap(),
+        );
+}
+
+#[cfg(unix)]
+#[test]
+fn problems_summarized_at_end() {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    let af = ScratchArchive::new();
+    let src = TreeFixture::new();
+    src.create_file("hello");
+    src.create_dir("subdir");
+    for name in [&b"bad\xffone"[..], b"subdir/bad\xfftwo"] {
+        std::fs::File::create(src.path().join(OsStr::from_bytes(name))).unwrap();
+    }
+    let log_dir = TempDir::new().unwrap();
+    let log_path = log_dir.path().join("conserve.log");
+
+    let output = main_binary()
+        .arg("--log-file")
+        .arg(&log_path)
+        .arg("backup")
+        .arg(af.path())
+        .arg(src.path())
+        .assert()
+        .success()
+        .stdout(
+            contains("           2      problems:\n")
+                .and(contains("           2        UndecodableName\n"))
+                .and(contains("first problems:"))
+                .and(contains("most problems in:"))
+                .and(contains("Every problem is listed in the log file")),
+        )
+        .get_output()
+        .stdout
+        .clone();
+    // Each problem is shown once, in the summary, not as it happened.
+    let stdout = String::from_utf8(output).unwrap();
+    assert_eq!(stdout.matches("Can't decode filename").count(), 2);
+    assert!(stdout.find("Can't decode filename") > stdout.find("problems:"));
+    let log = std::fs::read_to_string(&log_path).unwrap();
+    assert_eq!(log.matches("Can't decode filename").count(), 2);
+}
+
+#[test]
+fn compare_archives_and_replica() {
+    let original = ScratchArchive::new();
+    original.store_two_versions();
+    let replica = ScratchArchive::new();
+    main_binary()
+        .arg("push")
+        .arg(original.path())
+        .arg(replica.path())
+        .assert()
+        .success();
+
+    main_binary()
+        .arg("compare-archives")
+        .arg(original.path())
+        .arg(replica.path())
+        .assert()
+        .success()
+        .stdout(contains("Archives match: 2 versions and "));
+
+    let src = TreeFixture::new();
+    src.create_file_with_contents("new", b"new content");
+    main_binary()
+        .arg("backup")
+        .arg(original.path())
+        .arg(src.path())
+        .assert()
+        .success();
+    main_binary()
+        .arg("compare-archives")
+        .arg(original.path())
+        .arg(replica.path())
+        .assert()
+        .failure()
+        .stdout(contains("left       band b0002\n"))
+        .stdout(contains("left       block "))
+        .stdout(contains("Archives differ in 2 bands or blocks"));
+}
+
+#[test]
+fn find_by_content_hash() {
+    let af = ScratchArchive::new();
+    let src = TreeFixture::new();
+    src.create_file("hello");
+    // The BLAKE2b-512 of "contents", as printed by b2sum.
+    let hash = "9063990e5c5b2184877f92adace7c801a549b00c39cd7549877f06d5dd0d3a6c\
+                a6eee42d5896bdac64831c8114c55cee664078bd105dc691270c92644ccb2ce7";
+
+    main_binary()
+        .arg("backup")
+        .arg(af.path())
+        .arg(src.path())
+        .assert()
+        .success();
+    // The unchanged file's hash is found from its stored content.
+    main_binary()
+        .args(&["backup", "--content-index"])
+        .arg(af.path())
+        .arg(src.path())
+        .assert()
+        .success();
+    main_binary()
+        .args(&["find", "--hash", hash])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout(starts_with("b0001 /hello\n").and(contains(
+            "1 versions have no content index and weren't searched",
+        )));
+
+    main_binary()
+        .arg("content-index")
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout("Indexed 1 files in b0000\n");
+    main_binary()
+        .args(&["find", "--hash", &hash[..16]])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout("b0000 /hello\nb0001 /hello\n");
+    main_binary()
+        .args(&["find", "--hash", "not-hex"])
+        .arg(af.path())
+        .assert()
+        .failure();
+    main_binary()
+        .arg("validate")
+        .arg(af.path())
+        .assert()
+        .success();
+}
+
+#[test]
+fn list_duplicate_files() {
+    let af = ScratchArchive::new();
+    let src = TreeFixture::new();
+    src.create_file("hello");
+    src.create_file("hello2");
+    src.create_file_with_contents("other", b"other");
+
+    main_binary()
+        .arg("backup")
+        .arg(af.path())
+        .arg(src.path())
+        .assert()
+        .success();
+    main_binary()
+        .arg("dupes")
+        .arg(af.path())
+        .assert()
+        .failure()
+        .stdout(contains("has no content index"));
+
+    main_binary()
+        .arg("content-index")
+        .arg(af.path())
+        .assert()
+        .success();
+    main_binary()
+        .args(&["dupes", "-b", "b0"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout(
+            "2 files of 8 B each, 8 B redundant: 9063990e5c5b2184877f92adace7c801\
+             a549b00c39cd7549877f06d5dd0d3a6ca6eee42d5896bdac64831c8114c55cee66\
+             4078bd105dc691270c92644ccb2ce7\n    /hello\n    /hello2\n\
+             1 sets of identical files, 8 B redundant\n",
+        );
+}
+
+#[test]
+fn limit_threads() {
+    let af = ScratchArchive::new();
+    let src = TreeFixture::new();
+    src.create_file("hello");
+    src.create_dir("subdir");
+    src.create_file("subdir/subfile");
+
+    main_binary()
+        .args(&["--threads", "1", "backup"])
+        .arg(af.path())
+        .arg(src.path())
+        .assert()
+        .success();
+    main_binary()
+        .args(&["validate", "--threads", "1"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout(contains("Archive is OK.\n"));
+
+    let dest = TempDir::new().unwrap();
+    main_binary()
+        .args(&["restore", "--threads", "1"])
+        .arg(af.path())
+        .arg(dest.path())
+        .assert()
+        .success();
+    dest.child("subdir/subfile").assert(is_file());
+}
+
+#[test]
+fn memory_limit() {
+    let af = ScratchArchive::new();
+    let src = TreeFixture::new();
+    let mut expected = "/\n".to_owned();
+    for i in 0..100 {
+        src.create_file(&format!("file{:03}", i));
+        expected.push_str(&format!("/file{:03}\n", i));
+    }
+    src.create_dir("subdir");
+    src.create_file("subdir/subfile");
+    expected.push_str("/subdir\n/subdir/subfile\n");
+
+    main_binary()
+        .args(&["--memory-limit", "10000", "backup"])
+        .arg(af.path())
+        .arg(src.path())
+        .assert()
+        .success();
+    main_binary()
+        .arg("ls")
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout(expected);
+}
+
+#[cfg(unix)]
+#[test]
+fn restore_refuse_escaping_symlinks() {
+    let af = ScratchArchive::new();
+    let src = TreeFixture::new();
+    src.create_file("hello");
+    src.create_symlink("abs", "/etc/passwd");
+    src.create_symlink("link", "hello");
+    main_binary()
+        .arg("backup")
+        .arg(af.path())
+        .arg(src.path())
+        .assert()
+        .success();
+
+    let dest = TempDir::new().unwrap();
+    main_binary()
+        .args(&["restore", "--escaping-symlinks", "refuse"])
+        .arg(af.path())
+        .arg(dest.path())
+        .assert()
+        .success()
+        .stdout(contains("CopyEntry"))
+        .stdout(contains("Failed to copy symlink \"/abs\""));
+    dest.child("abs").assert(predicate::path::missing());
+    assert_eq!(
+        std::fs::read_link(dest.path().join("link")).unwrap(),
+        std::path::Path::new("hello")
+    );
 }
@@ -3,11 +3,13 @@
 /// Test Conserve through its public API.
 use std::fs::File;
 use std::io::prelude::*;
+use std::time::Duration;
 
 use tempfile::TempDir;
 
 use conserve::test_fixtures::ScratchArchive;
 use conserve::test_fixtures::TreeFixture;
+use conserve::test_fixtures::{NameStyle, TreeGenerator};
 use conserve::*;
 
 const HELLO_HASH: &str =
@@ -183,3 +185,868 @@ fn mtime_before_epoch() {
     let bw = BackupWriter::begin(&af).unwrap();
     let _copy_stats = copy_tree(&lt, bw, &COPY_DEFAULT).unwrap();
 }
+
+/// A tar file can be imported into a new version, and restored.
+#[test]
+fn import_tar() {
+    let tar_file = tempfile::NamedTempFile::new().unwrap();
+    let mut builder = tar::Builder::new(tar_file.reopen().unwrap());
+    for (name, content) in &[("b/two", "second file"), ("a", "first file")] {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        builder
+            .append_data(&mut header, name, content.as_bytes())
+            .unwrap();
+    }
+    builder.finish().unwrap();
+
+    let af = ScratchArchive::new();
+    let tar_tree = TarTree::open(tar_file.path()).unwrap();
+    let stats = copy_tree(&tar_tree, BackupWriter::begin(&af).unwrap(), &COPY_DEFAULT).unwrap();
+    assert_eq!(stats.files, 2);
+    assert_eq!(stats.directories, 2);
+    assert_eq!(stats.errors, 0);
+
+    let rd = TempDir::new().unwrap();
+    let st = StoredTree::open_last(&af).unwrap();
+    copy_tree(&st, RestoreTree::create(rd.path()).unwrap(), &COPY_DEFAULT).unwrap();
+    assert_eq!(
+        std::fs::read_to_string(rd.path().join("a")).unwrap(),
+        "first file"
+    );
+    assert_eq!(
+        std::fs::read_to_string(rd.path().join("b").join("two")).unwrap(),
+        "second file"
+    );
+}
+
+#[test]
+fn copy_live_tree_to_restore_tree() {
+    let srcdir = TreeFixture::new();
+    srcdir.create_file("hello");
+    srcdir.create_dir("subdir");
+    srcdir.create_file("subdir/excluded");
+    let lt = srcdir
+        .live_tree()
+        .with_excludes(excludes::from_strings(&["/subdir/excluded"]).unwrap());
+    let dest = TempDir::new().unwrap();
+    let stats = copy_tree(
+        &lt,
+        RestoreTree::create(dest.path()).unwrap(),
+        &COPY_DEFAULT,
+    )
+    .unwrap();
+    assert_eq!(stats.files, 1);
+    assert_eq!(stats.errors, 0);
+    assert_eq!(
+        std::fs::read_to_string(dest.path().join("hello")).unwrap(),
+        "contents"
+    );
+    assert!(dest.path().join("subdir").is_dir());
+    assert!(!dest.path().join("subdir").join("excluded").exists());
+}
+
+#[test]
+fn library_options_backup_restore_validate() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file("hello");
+    srcdir.create_file("junk");
+
+    let stats = BackupOptions::new(srcdir.path(), af.path())
+        .exclude("/junk")
+        .run()
+        .unwrap();
+    assert_eq!(stats.files, 1);
+
+    let dest = TempDir::new().unwrap();
+    let stats = RestoreOptions::new(af.path(), dest.path())
+        .band_id(BandId::new(&[0]))
+        .run()
+        .unwrap();
+    assert_eq!(stats.files, 1);
+    assert!(dest.path().join("hello").is_file());
+    assert!(!dest.path().join("junk").exists());
+
+    // The destination is now not empty.
+    assert!(RestoreOptions::new(af.path(), dest.path()).run().is_err());
+    RestoreOptions::new(af.path(), dest.path())
+        .force_overwrite(true)
+        .run()
+        .unwrap();
+
+    ValidateOptions::new(af.path()).run().unwrap();
+}
+
+#[test]
+fn backup_and_restore_generated_tree() {
+    let srcdir = TreeFixture::new();
+    let generated = TreeGenerator::new(20)
+        .files(500)
+        .dirs(40)
+        .max_depth(5)
+        .max_file_size(100_000)
+        .symlinks(20)
+        .names(NameStyle::Mixed)
+        .generate(&srcdir);
+    let af = ScratchArchive::new();
+    // Each file in a block of combined small files currently reads the
+    // whole block, so keep them separate to keep this quick.
+    let stats = BackupOptions::new(srcdir.path(), af.path())
+        .small_file_size(0)
+        .run()
+        .unwrap();
+    assert_eq!(stats.files, generated.files);
+    assert_eq!(stats.directories, generated.dirs + 1);
+    assert_eq!(stats.symlinks, generated.symlinks);
+    ValidateOptions::new(af.path()).run().unwrap();
+
+    let dest = TempDir::new().unwrap();
+    RestoreOptions::new(af.path(), dest.path()).run().unwrap();
+    let list = |lt: LiveTree| -> Vec<(Apath, Kind, Option<u64>, Option<String>)> {
+        lt.iter_entries()
+            .unwrap()
+            .map(|e| {
+                (
+                    e.apath().clone(),
+                    e.kind(),
+                    e.size(),
+                    e.symlink_target().clone(),
+                )
+            })
+            .collect()
+    };
+    let restored = list(LiveTree::open(dest.path()).unwrap());
+    assert_eq!(restored, list(srcdir.live_tree()));
+    assert_eq!(
+        restored.iter().filter_map(|e| e.2).sum::<u64>(),
+        generated.file_bytes
+    );
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn statx_metadata_is_stored() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file("hello");
+    let backup = BackupOptions::new(srcdir.path(), af.path()).statx_metadata(true);
+    backup.run().unwrap();
+    // The second backup reuses the unchanged entry, and keeps its metadata.
+    backup.run().unwrap();
+
+    let st = StoredTree::open_last(&af).unwrap();
+    let entries = st.iter_entries().unwrap().collect::<Vec<_>>();
+    assert_eq!(entries.len(), 2);
+    for entry in &entries {
+        assert!(entry.statx_metadata().unwrap().mnt_id.is_some());
+    }
+
+    BackupOptions::new(srcdir.path(), af.path()).run().unwrap();
+    let st = StoredTree::open_last(&af).unwrap();
+    assert!(st
+        .iter_entries()
+        .unwrap()
+        .all(|entry| entry.statx_metadata().is_none()));
+}
+
+#[cfg(unix)]
+#[test]
+fn problems_are_collected_in_stats() {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file("hello");
+    File::create(srcdir.path().join(OsStr::from_bytes(b"bad\xffname"))).unwrap();
+
+    let stats = BackupOptions::new(srcdir.path(), af.path()).run().unwrap();
+    assert_eq!(stats.files, 1);
+    assert_eq!(stats.errors, 1);
+    assert_eq!(
+        *stats.problems,
+        [Problem::UndecodableName {
+            dir: srcdir.path().to_path_buf(),
+            name: "bad\u{fffd}name".to_owned(),
+        }]
+    );
+}
+
+/// Live, stored and restored trees can be copied to and compared with each
+/// other in any combination.
+#[test]
+fn trees_are_interchangeable() {
+    let srcdir = TreeFixture::new();
+    srcdir.create_file("hello");
+    srcdir.create_dir("subdir");
+    srcdir.create_file_with_contents("subdir/a", b"contents of a");
+    let live = srcdir.live_tree();
+
+    let af = ScratchArchive::new();
+    copy_tree(&live, BackupWriter::begin(&af).unwrap(), &COPY_DEFAULT).unwrap();
+    let stored = StoredTree::open_last(&af).unwrap();
+
+    // Copy a stored tree straight into another archive.
+    let af2 = ScratchArchive::new();
+    let copy_stats = copy_tree(&stored, BackupWriter::begin(&af2).unwrap(), &COPY_DEFAULT).unwrap();
+    assert_eq!(copy_stats.files, 2);
+    let stored2 = StoredTree::open_last(&af2).unwrap();
+
+    let restore_dir = TempDir::new().unwrap();
+    let restore_path = restore_dir.path().join("r");
+    copy_tree(
+        &stored2,
+        RestoreTree::create(&restore_path).unwrap(),
+        &COPY_DEFAULT,
+    )
+    .unwrap();
+    let restored = LiveTree::open(&restore_path).unwrap();
+
+    fn all_both<A: ReadTree, B: ReadTree>(a: &A, b: &B) {
+        let kinds: Vec<MergedEntryKind> = iter_merged_entries(a, b)
+            .unwrap()
+            .map(|e| e.kind())
+            .collect();
+        assert_eq!(kinds.len(), 4);
+        assert!(kinds.iter().all(|k| *k == MergedEntryKind::Both));
+    }
+    all_both(&live, &stored);
+    all_both(&stored, &live);
+    all_both(&stored, &stored2);
+    all_both(&stored2, &restored);
+
+    let size = live.size().unwrap().file_bytes;
+    assert_eq!(size, 21);
+    assert_eq!(stored.size().unwrap().file_bytes, size);
+    assert_eq!(restored.size().unwrap().file_bytes, size);
+}
+
+#[test]
+fn binary_index_backup_and_restore() {
+    let tmp = TempDir::new().unwrap();
+    let archive_path = tmp.path().join("archive");
+    Archive::create_with_index_format(&archive_path, IndexFormat::Binary).unwrap();
+    let af = Archive::open(&archive_path).unwrap();
+    assert_eq!(af.index_format(), IndexFormat::Binary);
+    assert_eq!(af.archive_version(), "0.6.3");
+
+    let srcdir = TreeFixture::new();
+    srcdir.create_file("hello");
+    srcdir.create_dir("subdir");
+    srcdir.create_file_with_contents("subdir/a", b"contents of a");
+    let stats = BackupOptions::new(srcdir.path(), &archive_path)
+        .run()
+        .unwrap();
+    assert_eq!(stats.files, 2);
+
+    let stored = StoredTree::open_last(&af).unwrap();
+    let entry = stored.entry(&"/subdir/a".into()).unwrap().unwrap();
+    assert_eq!(entry.size(), Some(13));
+
+    let dest = TempDir::new().unwrap();
+    RestoreOptions::new(&archive_path, dest.path())
+        .run()
+        .unwrap();
+    assert_eq!(
+        std::fs::read_to_string(dest.path().join("subdir").join("a")).unwrap(),
+        "contents of a"
+    );
+    ValidateOptions::new(&archive_path).run().unwrap();
+}
+
+#[test]
+fn tuned_archive_backup_and_restore() {
+    let tmp = TempDir::new().unwrap();
+    let archive_path = tmp.path().join("archive");
+    let tuning = Tuning {
+        block_size: 4096,
+        small_file_size: 0,
+        index_hunk_entries: 2,
+        flush_bytes: 4096,
+    };
+    Archive::create_with_tuning(&archive_path, IndexFormat::default(), tuning).unwrap();
+    let af = Archive::open(&archive_path).unwrap();
+    assert_eq!(af.tuning(), tuning);
+
+    let srcdir = TreeFixture::new();
+    let big: Vec<u8> = (0..10_000u32).map(|i| (i / 100) as u8).collect();
+    srcdir.create_file_with_contents("big", &big);
+    srcdir.create_file("hello");
+    srcdir.create_file("world");
+    let stats = BackupOptions::new(srcdir.path(), &archive_path)
+        .run()
+        .unwrap();
+    assert_eq!(stats.files, 3);
+    assert_eq!(stats.combined_files, 0);
+    assert_eq!(stats.written_blocks, 4);
+
+    let band = Band::open(&af, &BandId::zero()).unwrap();
+    assert_eq!(band.index().count_hunks().unwrap(), 2);
+
+    let dest = TempDir::new().unwrap();
+    RestoreOptions::new(&archive_path, dest.path())
+        .run()
+        .unwrap();
+    assert_eq!(std::fs::read(dest.path().join("big")).unwrap(), big);
+    ValidateOptions::new(&archive_path).run().unwrap();
+}
+
+#[test]
+fn layered_index_backups() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_dir("subdir");
+    for name in &["subdir/a", "subdir/b", "subdir/c", "unchanged"] {
+        srcdir.create_file(name);
+    }
+    let backup = || {
+        BackupOptions::new(srcdir.path(), af.path())
+            .layered_indexes(true)
+            .run()
+            .unwrap()
+    };
+    backup();
+    srcdir.create_file_with_contents("subdir/a", b"new contents of a");
+    std::fs::remove_file(srcdir.path().join("subdir").join("b")).unwrap();
+    srcdir.create_file("subdir/d");
+    let stats = backup();
+    assert_eq!(stats.unmodified_files, 2);
+
+    let band = Band::open(&af, &BandId::new(&[1])).unwrap();
+    assert_eq!(band.index_base_band_id().unwrap(), Some(BandId::zero()));
+    let layer: Vec<(String, Kind)> = band
+        .iter_entries()
+        .unwrap()
+        .map(|e| (e.apath.into(), e.kind))
+        .collect();
+    assert!(layer.contains(&("/subdir/a".to_owned(), Kind::File)));
+    assert!(layer.contains(&("/subdir/b".to_owned(), Kind::Deleted)));
+    assert!(layer.contains(&("/subdir/d".to_owned(), Kind::File)));
+    assert!(!layer.iter().any(|(apath, _)| apath == "/unchanged"));
+
+    let st = StoredTree::open_last(&af).unwrap();
+    assert_eq!(st.layers(), 1);
+    let apaths: Vec<String> = st.iter_entries().unwrap().map(|e| e.apath.into()).collect();
+    assert_eq!(
+        apaths,
+        [
+            "/",
+            "/subdir",
+            "/unchanged",
+            "/subdir/a",
+            "/subdir/c",
+            "/subdir/d"
+        ]
+    );
+    assert_eq!(st.entry(&"/subdir/b".into()).unwrap(), None);
+    assert!(st.entry(&"/unchanged".into()).unwrap().is_some());
+    let subtree: Vec<String> = st
+        .iter_subtree(&"/subdir".into())
+        .unwrap()
+        .map(|e| e.apath.into())
+        .collect();
+    assert_eq!(subtree, ["/subdir", "/subdir/a", "/subdir/c", "/subdir/d"]);
+
+    let dest = TempDir::new().unwrap();
+    RestoreOptions::new(af.path(), dest.path()).run().unwrap();
+    assert_eq!(
+        std::fs::read_to_string(dest.path().join("subdir").join("a")).unwrap(),
+        "new contents of a"
+    );
+    assert!(!dest.path().join("subdir").join("b").exists());
+    assert!(dest.path().join("unchanged").is_file());
+    ValidateOptions::new(af.path()).run().unwrap();
+
+    // After enough layers, a whole index is written again.
+    for _ in 0..MAX_INDEX_LAYERS {
+        backup();
+    }
+    let st = StoredTree::open_last(&af).unwrap();
+    assert_eq!(st.layers(), 0);
+    assert_eq!(st.iter_entries().unwrap().count(), 6);
+}
+
+#[test]
+fn changes_reported_with_and_without_layers() {
+    for &layered in &[false, true] {
+        let af = ScratchArchive::new();
+        let srcdir = TreeFixture::new();
+        srcdir.create_dir("subdir");
+        for name in &["subdir/a", "subdir/b", "unchanged"] {
+            srcdir.create_file(name);
+        }
+        let backup = || {
+            BackupOptions::new(srcdir.path(), af.path())
+                .layered_indexes(layered)
+                .run()
+                .unwrap()
+        };
+        backup();
+        srcdir.create_file_with_contents("subdir/a", b"new contents of a");
+        std::fs::remove_file(srcdir.path().join("subdir").join("b")).unwrap();
+        srcdir.create_file("subdir/c");
+        backup();
+
+        let st = StoredTree::open_last(&af).unwrap();
+        assert_eq!(st.layers(), layered as usize);
+        let changes: Vec<(String, ChangeKind)> = st
+            .changes()
+            .unwrap()
+            .into_iter()
+            .filter(|c| !["/", "/subdir"].contains(&c.apath.to_string().as_str()))
+            .map(|c| (c.apath.into(), c.kind))
+            .collect();
+        assert_eq!(
+            changes,
+            [
+                ("/subdir/a".to_owned(), ChangeKind::Changed),
+                ("/subdir/b".to_owned(), ChangeKind::Deleted),
+                ("/subdir/c".to_owned(), ChangeKind::Added),
+            ],
+            "layered={}",
+            layered
+        );
+
+        // The directories' mtimes changed because their contents did, which
+        // can be ignored.
+        let changes: Vec<String> = st
+            .ignore_dir_mtimes(true)
+            .changes()
+            .unwrap()
+            .into_iter()
+            .map(|c| c.apath.into())
+            .collect();
+        assert_eq!(changes, ["/subdir/a", "/subdir/b", "/subdir/c"]);
+    }
+}
+
+/// The content of every file in a band directory, by relative path.
+fn band_files(band_path: &std::path::Path) -> Vec<(std::path::PathBuf, Vec<u8>)> {
+    let mut files = Vec::new();
+    for entry in walkdir::WalkDir::new(band_path).sort_by(|a, b| a.file_name().cmp(b.file_name())) {
+        let entry = entry.unwrap();
+        if entry.file_type().is_file() {
+            files.push((
+                entry.path().strip_prefix(band_path).unwrap().to_owned(),
+                std::fs::read(entry.path()).unwrap(),
+            ));
+        }
+    }
+    files
+}
+
+#[test]
+fn deterministic_backups_are_identical() {
+    let srcdir = TreeFixture::new();
+    srcdir.create_file("hello");
+    srcdir.create_dir("subdir");
+    srcdir.create_file_with_contents("subdir/a", b"contents of a");
+    let af1 = ScratchArchive::new();
+    let af2 = ScratchArchive::new();
+    for af in &[&af1, &af1, &af2] {
+        BackupOptions::new(srcdir.path(), af.path())
+            .deterministic(true)
+            .run()
+            .unwrap();
+    }
+    let first = band_files(&af1.path().join("b0000"));
+    assert!(first.iter().any(|(path, _)| path.ends_with("BANDTAIL")));
+    assert_eq!(band_files(&af1.path().join("b0001")), first);
+    assert_eq!(band_files(&af2.path().join("b0000")), first);
+
+    let band = Band::open(&af1, &BandId::new(&[1])).unwrap();
+    let info = band.get_info().unwrap();
+    assert_eq!(info.start_time.timestamp(), 0);
+    assert_eq!(info.basis_band_id, None);
+}
+
+#[test]
+fn small_files_share_blocks() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    for i in 0..20 {
+        srcdir
+            .create_file_with_contents(&format!("small{:02}", i), format!("file {}", i).as_bytes());
+    }
+    let stats = BackupOptions::new(srcdir.path(), af.path()).run().unwrap();
+    assert_eq!(stats.files, 20);
+    assert_eq!(stats.combined_files, 20);
+    assert_eq!(stats.written_blocks, 1);
+
+    let dest = TempDir::new().unwrap();
+    RestoreOptions::new(af.path(), dest.path()).run().unwrap();
+    for i in 0..20 {
+        assert_eq!(
+            std::fs::read_to_string(dest.path().join(format!("small{:02}", i))).unwrap(),
+            format!("file {}", i)
+        );
+    }
+    af.validate().unwrap();
+
+    let af = ScratchArchive::new();
+    let stats = BackupOptions::new(srcdir.path(), af.path())
+        .small_file_size(0)
+        .run()
+        .unwrap();
+    assert_eq!(stats.combined_files, 0);
+    assert_eq!(stats.written_blocks, 20);
+}
+
+#[test]
+fn validate_skips_excluded_files() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file("hello");
+    srcdir.create_file_with_contents("junk", b"junk contents");
+    BackupOptions::new(srcdir.path(), af.path())
+        .small_file_size(0)
+        .run()
+        .unwrap();
+
+    // Remove the block holding /junk, so only that file is damaged.
+    let st = StoredTree::open_last(&af).unwrap();
+    let junk = st
+        .iter_entries()
+        .unwrap()
+        .find(|e| e.apath == Apath::from("/junk"))
+        .unwrap();
+    let hash = &junk.addrs[0].hash;
+    std::fs::remove_file(af.path().join("d").join(&hash[..3]).join(hash)).unwrap();
+
+    assert!(ValidateOptions::new(af.path()).run().is_err());
+    assert!(ValidateOptions::new(af.path())
+        .band_id(BandId::new(&[0]))
+        .run()
+        .is_err());
+    ValidateOptions::new(af.path())
+        .exclude("/junk")
+        .run()
+        .unwrap();
+    ValidateOptions::new(af.path())
+        .band_id(BandId::new(&[0]))
+        .exclude("/junk")
+        .run()
+        .unwrap();
+}
+
+#[test]
+fn validate_on_worker_threads() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    for i in 0..20 {
+        srcdir.create_file_with_contents(&format!("file{:02}", i), format!("{}", i).as_bytes());
+    }
+    BackupOptions::new(srcdir.path(), af.path())
+        .small_file_size(0)
+        .run()
+        .unwrap();
+
+    for jobs in &[1, 3] {
+        let stats = ValidateOptions::new(af.path()).jobs(*jobs).run().unwrap();
+        assert_eq!(stats.block_dir_stats.block_read_count, 20);
+        assert_eq!(stats.block_dir_stats.block_error_count, 0);
+    }
+
+    // Damage one block; it's found whichever thread checks it.
+    let hash = af.block_dir().block_names().unwrap().next().unwrap();
+    std::fs::remove_file(af.path().join("d").join(&hash[..3]).join(&hash)).unwrap();
+    assert!(ValidateOptions::new(af.path()).jobs(2).run().is_err());
+}
+
+#[test]
+fn validate_since_skips_recently_verified_blocks() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    for i in 0..5 {
+        srcdir.create_file_with_contents(&format!("file{}", i), format!("{}", i).as_bytes());
+    }
+    BackupOptions::new(srcdir.path(), af.path())
+        .small_file_size(0)
+        .run()
+        .unwrap();
+    let cache_path = ValidationCache::sidecar_path(af.path());
+    assert!(!cache_path.exists());
+
+    // The first run reads everything, and remembers it.
+    let month = parse_age("30d").unwrap();
+    let stats = ValidateOptions::new(af.path()).since(month).run().unwrap();
+    assert_eq!(stats.block_dir_stats.block_read_count, 5);
+    assert_eq!(stats.block_dir_stats.block_skipped_count, 0);
+    assert_eq!(ValidationCache::open(&cache_path).unwrap().len(), 5);
+
+    // Within the month, those blocks are skipped, even if they've since
+    // been damaged.
+    let block_path = |hash: &str| af.path().join("d").join(&hash[..3]).join(hash);
+    let hashes: Vec<String> = af.block_dir().block_names().unwrap().collect();
+    std::fs::copy(block_path(&hashes[0]), block_path(&hashes[1])).unwrap();
+    let stats = ValidateOptions::new(af.path()).since(month).run().unwrap();
+    assert_eq!(stats.block_dir_stats.block_read_count, 0);
+    assert_eq!(stats.block_dir_stats.block_skipped_count, 5);
+
+    // A zero age rereads them all, and finds the damage.
+    assert!(ValidateOptions::new(af.path())
+        .since(Duration::from_secs(0))
+        .run()
+        .is_err());
+}
+
+#[test]
+fn validate_scrub_budget() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    for i in 0..5 {
+        srcdir.create_file_with_contents(&format!("file{}", i), format!("{}", i).as_bytes());
+    }
+    BackupOptions::new(srcdir.path(), af.path())
+        .small_file_size(0)
+        .run()
+        .unwrap();
+    let cache_path = ValidationCache::sidecar_path(af.path());
+
+    // With no time to spare, no blocks are read, and none are recorded.
+    let stats = ValidateOptions::new(af.path())
+        .scrub_budget(Duration::from_secs(0))
+        .run()
+        .unwrap();
+    assert_eq!(stats.block_dir_stats.block_read_count, 0);
+    assert_eq!(stats.block_dir_stats.block_skipped_count, 5);
+    assert!(ValidationCache::open(&cache_path).unwrap().is_empty());
+
+    // With enough time, they're all read.
+    let hour = parse_age("1h").unwrap();
+    let stats = ValidateOptions::new(af.path())
+        .scrub_budget(hour)
+        .run()
+        .unwrap();
+    assert_eq!(stats.block_dir_stats.block_read_count, 5);
+    assert_eq!(ValidationCache::open(&cache_path).unwrap().len(), 5);
+
+    // A missing block is noticed even when there's no time to read any.
+    let block_path = |hash: &str| af.path().join("d").join(&hash[..3]).join(hash);
+    let hashes: Vec<String> = af.block_dir().block_names().unwrap().collect();
+    std::fs::remove_file(block_path(&hashes[0])).unwrap();
+    assert!(ValidateOptions::new(af.path())
+        .scrub_budget(Duration::from_secs(0))
+        .run()
+        .is_err());
+}
+
+#[test]
+fn filter_backup_and_restore() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file_with_contents("keep.txt", b"keep");
+    srcdir.create_file_with_contents("drop.tmp", b"drop");
+    srcdir.create_dir("subdir");
+    srcdir.create_file_with_contents("subdir/big.txt", &[0; 2000]);
+    BackupOptions::new(srcdir.path(), af.path())
+        .filter(Filter::parse(r#"name !~ "*.tmp""#).unwrap())
+        .run()
+        .unwrap();
+    let apaths: Vec<String> = StoredTree::open_last(&af)
+        .unwrap()
+        .iter_entries()
+        .unwrap()
+        .map(|e| e.apath.to_string())
+        .collect();
+    assert_eq!(apaths, ["/", "/keep.txt", "/subdir", "/subdir/big.txt"]);
+
+    let destdir = TempDir::new().unwrap();
+    RestoreOptions::new(af.path(), destdir.path())
+        .filter(Filter::parse("size > 1KB").unwrap())
+        .run()
+        .unwrap();
+    assert!(!destdir.path().join("keep.txt").exists());
+    assert!(destdir.path().join("subdir/big.txt").is_file());
+}
+
+#[derive(Default)]
+struct RecordingObserver {
+    events: std::sync::Mutex<Vec<String>>,
+}
+
+impl Observer for RecordingObserver {
+    fn entry_started(&self, apath: &Apath, kind: Kind) {
+        self.events
+            .lock()
+            .unwrap()
+            .push(format!("start {} {:?}", apath, kind));
+    }
+
+    fn entry_stored(&self, entry: &IndexEntry) {
+        self.events
+            .lock()
+            .unwrap()
+            .push(format!("stored {} {}", entry.apath, entry.addrs.len()));
+    }
+
+    fn block_written(&self, hash: &str, len: u64, _compressed_len: u64) {
+        self.events
+            .lock()
+            .unwrap()
+            .push(format!("block {} {}", &hash[..8], len));
+    }
+
+    fn band_closed(&self, band_id: &BandId) {
+        self.events
+            .lock()
+            .unwrap()
+            .push(format!("closed {}", band_id));
+    }
+}
+
+#[test]
+fn observe_backup() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file("hello");
+    let observer = std::sync::Arc::new(RecordingObserver::default());
+    BackupOptions::new(srcdir.path(), af.path())
+        .observer(observer.clone())
+        .run()
+        .unwrap();
+    assert_eq!(
+        *observer.events.lock().unwrap(),
+        [
+            "start / Dir",
+            "stored / 0",
+            "start /hello File",
+            &format!("block {} 8", &HELLO_HASH[..8]),
+            "stored /hello 1",
+            "closed b0000",
+        ]
+    );
+
+    // Blocks that are already stored aren't reported again.
+    observer.events.lock().unwrap().clear();
+    srcdir.create_file("again");
+    BackupOptions::new(srcdir.path(), af.path())
+        .small_file_size(0)
+        .observer(observer.clone())
+        .run()
+        .unwrap();
+    assert!(observer
+        .events
+        .lock()
+        .unwrap()
+        .contains(&"stored /again 1".to_owned()));
+    assert!(!observer
+        .events
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|e| e.starts_with("block")));
+}
+
+#[test]
+fn find_content_in_backups() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file("hello");
+    BackupOptions::new(srcdir.path(), af.path())
+        .content_index(true)
+        .run()
+        .unwrap();
+    srcdir.create_file_with_contents("renamed", b"contents");
+    srcdir.create_file_with_contents("new", b"new content");
+    BackupOptions::new(srcdir.path(), af.path())
+        .layered_indexes(true)
+        .content_index(true)
+        .run()
+        .unwrap();
+
+    // HELLO_HASH is the hash of "contents", and it's found in both versions,
+    // including where it's unchanged in the second.
+    let found = find_content(&af, HELLO_HASH).unwrap();
+    assert!(found.unindexed_bands.is_empty());
+    let matches: Vec<String> = found
+        .matches
+        .iter()
+        .map(|(band_id, entry)| format!("{} {} {}", band_id, entry.apath, entry.size))
+        .collect();
+    assert_eq!(
+        matches,
+        ["b0000 /hello 8", "b0001 /hello 8", "b0001 /renamed 8"]
+    );
+    assert!(find_content(&af, &HELLO_HASH[..10]).unwrap().matches.len() == 3);
+    af.validate().unwrap();
+}
+
+#[test]
+fn interrupted_by_write_failures() {
+    let srcdir = TreeFixture::new();
+    srcdir.create_file("hello");
+    srcdir.create_dir("subdir");
+    srcdir.create_file_with_contents("subdir/a", b"contents of a");
+
+    // Count the writes in a whole backup.
+    let af = ScratchArchive::new();
+    let guard = faults::FaultPlan::new(af.path()).install();
+    BackupOptions::new(srcdir.path(), af.path()).run().unwrap();
+    let writes = guard.writes();
+    drop(guard);
+    assert!(writes > 3);
+
+    // Fail each write in turn. Either the backup fails, or the file being
+    // stored is reported as an error. Either way, the archive is still valid
+    // and the next backup is complete.
+    for n in 1..=writes {
+        let af = ScratchArchive::new();
+        let guard = faults::FaultPlan::new(af.path()).fail_write(n).install();
+        match BackupOptions::new(srcdir.path(), af.path()).run() {
+            Ok(stats) => assert_eq!(stats.errors, 1, "write {}", n),
+            Err(_) => (),
+        }
+        drop(guard);
+        let stats = ValidateOptions::new(af.path()).run().unwrap();
+        assert_eq!(stats.block_dir_stats.block_error_count, 0);
+
+        let stats = BackupOptions::new(srcdir.path(), af.path()).run().unwrap();
+        assert_eq!(stats.errors, 0);
+        let stored = StoredTree::open_last(&af).unwrap();
+        assert_eq!(stored.size().unwrap().file_bytes, 21, "write {}", n);
+        ValidateOptions::new(af.path()).run().unwrap();
+    }
+}
+
+#[test]
+fn short_and_delayed_reads() {
+    let srcdir = TreeFixture::new();
+    TreeGenerator::new(3).files(20).generate(&srcdir);
+    let af = ScratchArchive::new();
+    BackupOptions::new(srcdir.path(), af.path()).run().unwrap();
+
+    // Short reads from the source make exactly the same blocks.
+    let af2 = ScratchArchive::new();
+    let guard = faults::FaultPlan::new(srcdir.path())
+        .short_reads(7)
+        .delay(Duration::from_millis(2))
+        .install();
+    let start = std::time::Instant::now();
+    BackupOptions::new(srcdir.path(), af2.path()).run().unwrap();
+    assert_eq!(guard.reads(), 20);
+    assert!(start.elapsed() >= Duration::from_millis(40));
+    drop(guard);
+    let block_names = |af: &ScratchArchive| -> Vec<String> {
+        let mut names: Vec<String> = af.block_dir().block_names().unwrap().collect();
+        names.sort();
+        names
+    };
+    assert_eq!(block_names(&af), block_names(&af2));
+}
+
+#[test]
+fn read_failure_reported_by_validate() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+    let guard = faults::FaultPlan::new(af.path().join("d"))
+        .fail_read(1)
+        .install();
+    let stats = ValidateOptions::new(af.path()).run().unwrap();
+    assert_eq!(stats.block_dir_stats.block_error_count, 1);
+    drop(guard);
+    let stats = ValidateOptions::new(af.path()).run().unwrap();
+    assert_eq!(stats.block_dir_stats.block_error_count, 0);
+}